@@ -0,0 +1,60 @@
+/*
+ * C interface to lowkey, for the iOS XCFramework and the Android shared library.
+ *
+ * Every function returns LOWKEY_OK or LOWKEY_ERROR. After an error,
+ * lowkey_last_error() describes what went wrong. Buffers filled in by lowkey
+ * must be released with lowkey_buffer_free().
+ */
+
+#ifndef LOWKEY_H
+#define LOWKEY_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LOWKEY_OK 0
+#define LOWKEY_ERROR -1
+
+typedef struct LowkeyBuffer {
+    uint8_t *data;
+    size_t len;
+} LowkeyBuffer;
+
+/* Hide `message` in the cover image and store the resulting PNG in `out`. */
+int32_t lowkey_encode(const uint8_t *cover, size_t cover_len,
+                      const uint8_t *message, size_t message_len,
+                      const char *key, LowkeyBuffer *out);
+
+/* Recover the message hidden in the image and store it in `out`. */
+int32_t lowkey_decode(const uint8_t *image, size_t image_len,
+                      const char *key, LowkeyBuffer *out);
+
+/* Store the largest message size, in bytes, that fits into the cover image in `out`. */
+int32_t lowkey_capacity(const uint8_t *cover, size_t cover_len, size_t *out);
+
+/*
+ * File descriptor variants of lowkey_encode and lowkey_decode. The descriptors
+ * stay owned by the caller and are not closed.
+ */
+int32_t lowkey_encode_fd(int32_t cover_fd, int32_t message_fd, int32_t output_fd,
+                         const char *key);
+int32_t lowkey_decode_fd(int32_t image_fd, int32_t output_fd, const char *key);
+
+/* Release a buffer filled in by lowkey. Passing an empty buffer is a no-op. */
+void lowkey_buffer_free(LowkeyBuffer buffer);
+
+/*
+ * Message describing the last error on this thread, or NULL if there was none.
+ * Valid until the next lowkey call on the same thread.
+ */
+const char *lowkey_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LOWKEY_H */
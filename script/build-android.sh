@@ -0,0 +1,14 @@
+#!/usr/bin/env bash
+# Build liblowkey.so for Android ABIs into target/android/jniLibs.
+# Requires the Android NDK (ANDROID_NDK_HOME) and cargo-ndk (`cargo install cargo-ndk`).
+set -e
+
+cd "$(dirname "${BASH_SOURCE[0]}")/.."
+
+rustup target add aarch64-linux-android armv7-linux-androideabi x86_64-linux-android
+cargo ndk \
+    -t arm64-v8a -t armeabi-v7a -t x86_64 \
+    -o target/android/jniLibs \
+    build --release --lib
+
+echo "Shared libraries located at: target/android/jniLibs"
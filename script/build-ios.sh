@@ -0,0 +1,28 @@
+#!/usr/bin/env bash
+# Build lowkey.xcframework (static library + include/lowkey.h) for iOS devices and simulators.
+# Requires macOS with Xcode.
+set -e
+
+cd "$(dirname "${BASH_SOURCE[0]}")/.."
+
+targets=(aarch64-apple-ios aarch64-apple-ios-sim x86_64-apple-ios)
+
+rustup target add "${targets[@]}"
+for target in "${targets[@]}"; do
+    cargo build --release --lib --target "${target}"
+done
+
+# Both simulator architectures go into one fat library
+mkdir -p target/ios-sim/release
+lipo -create \
+    target/aarch64-apple-ios-sim/release/liblowkey.a \
+    target/x86_64-apple-ios/release/liblowkey.a \
+    -output target/ios-sim/release/liblowkey.a
+
+rm -rf target/lowkey.xcframework
+xcodebuild -create-xcframework \
+    -library target/aarch64-apple-ios/release/liblowkey.a -headers include \
+    -library target/ios-sim/release/liblowkey.a -headers include \
+    -output target/lowkey.xcframework
+
+echo "XCFramework located at: target/lowkey.xcframework"
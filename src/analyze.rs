@@ -0,0 +1,410 @@
+use serde_json::{Value, json};
+use std::path::Path;
+
+use crate::img::analysis::{
+    ChannelComparison, ChiSquare, Quality, RsAnalysis, SamplePairs, bit_plane, bit_plane_grid,
+    chi_square, compare_histograms, quality, rs_analysis, sample_pairs,
+};
+use crate::img::io::read_image;
+use lowkey::error::LowkeyError;
+
+pub const CHANNEL_NAMES: [&str; 4] = ["r", "g", "b", "a"];
+
+/// How many of the most suspicious images a report run lists on stdout
+const RANKING_LENGTH: usize = 10;
+
+pub struct AnalyzeOptions<'a> {
+    /// Original cover of the (single) image, to compare histograms with
+    pub cover: Option<&'a str>,
+    /// Prefix for bit plane images
+    pub export_planes: Option<&'a str>,
+    /// Print JSON instead of text
+    pub json: bool,
+    /// Write every result, ranked by suspicion, to this JSON file and only
+    /// print the ranking
+    pub report: Option<&'a str>,
+}
+
+/// Detector results for one image
+struct Analysis {
+    image: String,
+    chi_square: ChiSquare,
+    rs: RsAnalysis,
+    spa: SamplePairs,
+    cover_comparison: Option<CoverComparison>,
+}
+
+struct CoverComparison {
+    cover: String,
+    quality: Quality,
+    channels: [ChannelComparison; 4],
+}
+
+/// Run the steganalysis detectors on each image and print a report to stdout.
+///
+/// With a cover, the image's histograms are also compared with the cover's.
+/// With `export_planes`, the two lowest bit planes of every channel are also
+/// written as black and white images named `<prefix>-<channel>-lsb.png` and
+/// `<prefix>-<channel>-lsb2.png` (`<prefix>-<image name>-...` for several images).
+pub fn analyze(images: &[String], options: &AnalyzeOptions) -> Result<String, LowkeyError> {
+    if options.cover.is_some() && images.len() != 1 {
+        return Err(LowkeyError::InvalidInput(
+            "--cover can only be used with a single image".into(),
+        ));
+    }
+
+    let mut analyses = Vec::new();
+    let mut failed = Vec::new();
+    for image in images {
+        let analysis = match analyze_image(image, options.cover) {
+            Ok(analysis) => analysis,
+            // One unreadable file should not stop a scan of many
+            Err(e) if images.len() > 1 => {
+                eprintln!("Failed to analyze '{}': {}", image, e);
+                failed.push((image.as_str(), e.to_string()));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if !options.json && options.report.is_none() {
+            println!("{}", text_report(&analysis));
+        }
+        analyses.push(analysis);
+
+        if let Some(prefix) = options.export_planes {
+            let prefix = if images.len() == 1 {
+                prefix.to_string()
+            } else {
+                let stem = Path::new(image)
+                    .file_stem()
+                    .map_or_else(|| "image".into(), |stem| stem.to_string_lossy());
+                format!("{}-{}", prefix, stem)
+            };
+            export_bit_planes(image, &prefix)?;
+        }
+    }
+
+    if let Some(path) = options.report {
+        write_report(path, &analyses, &failed)?;
+        if !options.json {
+            print_ranking(&analyses);
+        }
+    }
+
+    if !failed.is_empty() {
+        let names: Vec<_> = failed.iter().map(|(image, _)| *image).collect();
+        return Err(LowkeyError::Other(format!(
+            "Failed to analyze {} of {} images: {}",
+            failed.len(),
+            images.len(),
+            names.join(", ")
+        )));
+    }
+
+    if options.json {
+        let report: Vec<Value> = analyses.iter().map(json_report).collect();
+        println!("{}", Value::Array(report));
+        // Keep stdout valid JSON
+        return Ok(String::new());
+    }
+
+    match options.report {
+        Some(path) => Ok(format!(
+            "Analyzed {} images, report written to {}",
+            images.len(),
+            path
+        )),
+        None => Ok(format!("Analyzed {} images", images.len())),
+    }
+}
+
+/// How likely an image is to carry a payload, from 0.0 to 1.0: the highest
+/// share of the image any detector finds embedded.
+fn suspicion(analysis: &Analysis) -> f64 {
+    [
+        analysis.chi_square.embedded_fraction,
+        analysis.rs.embedding_rate,
+        analysis.spa.embedding_rate,
+    ]
+    .into_iter()
+    .fold(0.0, f64::max)
+}
+
+/// Most suspicious first
+fn ranked(analyses: &[Analysis]) -> Vec<&Analysis> {
+    let mut ranked: Vec<_> = analyses.iter().collect();
+    ranked.sort_by(|a, b| suspicion(b).total_cmp(&suspicion(a)));
+    ranked
+}
+
+fn write_report(
+    path: &str,
+    analyses: &[Analysis],
+    failed: &[(&str, String)],
+) -> Result<(), LowkeyError> {
+    let images: Vec<Value> = ranked(analyses).into_iter().map(json_report).collect();
+    let failed: Vec<Value> = failed
+        .iter()
+        .map(|(image, error)| json!({ "image": image, "error": error }))
+        .collect();
+    let report = json!({ "images": images, "failed": failed });
+
+    let json = serde_json::to_vec_pretty(&report)
+        .map_err(|e| LowkeyError::Other(format!("Failed to serialize report: {}", e)))?;
+    std::fs::write(path, json)
+        .map_err(|e| LowkeyError::Io(format!("Failed to write report '{}': {}", path, e)))
+}
+
+fn print_ranking(analyses: &[Analysis]) {
+    println!("Most suspicious images:");
+    for (rank, analysis) in ranked(analyses)
+        .into_iter()
+        .take(RANKING_LENGTH)
+        .enumerate()
+    {
+        println!(
+            "{:>4}. {:>5.1}%  {}",
+            rank + 1,
+            suspicion(analysis) * 100.0,
+            analysis.image
+        );
+    }
+    if analyses.len() > RANKING_LENGTH {
+        println!("  ... and {} more", analyses.len() - RANKING_LENGTH);
+    }
+}
+
+/// Detector results for one image, in plain language.
+pub fn report(image: &str) -> Result<String, LowkeyError> {
+    analyze_image(image, None).map(|analysis| text_report(&analysis))
+}
+
+fn analyze_image(image: &str, cover: Option<&str>) -> Result<Analysis, LowkeyError> {
+    let img = read_image(image)?;
+    let cover_comparison = match cover {
+        Some(cover) => {
+            let cover_img = read_image(cover)?;
+            Some(CoverComparison {
+                cover: cover.to_string(),
+                quality: quality(&cover_img, &img)?,
+                channels: compare_histograms(&cover_img, &img)?,
+            })
+        }
+        None => None,
+    };
+
+    Ok(Analysis {
+        image: image.to_string(),
+        chi_square: chi_square(&img),
+        rs: rs_analysis(&img),
+        spa: sample_pairs(&img),
+        cover_comparison,
+    })
+}
+
+fn text_report(analysis: &Analysis) -> String {
+    let Analysis {
+        image,
+        chi_square,
+        rs,
+        spa,
+        cover_comparison,
+    } = analysis;
+
+    let mut report = format!(
+        "{}:\n  chi-square attack: statistic {:.2}, p-value {:.4}, looks embedded in the first {:.0}%\n    {}\n  \
+         RS analysis: estimated embedding rate {:.1}% (R {:.1}%, G {:.1}%, B {:.1}%), about {} bytes\n    {}\n  \
+         sample-pairs analysis: estimated embedding rate {:.1}% (R {:.1}%, G {:.1}%, B {:.1}%, A {:.1}%), about {} bytes\n    {}",
+        image,
+        chi_square.statistic,
+        chi_square.p_value,
+        chi_square.embedded_fraction * 100.0,
+        chi_square.interpretation(),
+        rs.embedding_rate * 100.0,
+        rs.channel_rates[0] * 100.0,
+        rs.channel_rates[1] * 100.0,
+        rs.channel_rates[2] * 100.0,
+        rs.estimated_bytes,
+        rs.interpretation(),
+        spa.embedding_rate * 100.0,
+        spa.channel_rates[0] * 100.0,
+        spa.channel_rates[1] * 100.0,
+        spa.channel_rates[2] * 100.0,
+        spa.channel_rates[3] * 100.0,
+        spa.estimated_bytes,
+        spa.interpretation()
+    );
+
+    if let Some(CoverComparison {
+        cover,
+        quality,
+        channels,
+    }) = cover_comparison
+    {
+        report.push_str(&format!(
+            "\n  compared with cover {}: {}",
+            cover,
+            quality.summary()
+        ));
+        for (name, channel) in CHANNEL_NAMES.iter().zip(channels) {
+            report.push_str(&format!(
+                "\n    {}: {:.2}% of values changed, KL divergence {:.6} bits, pair asymmetry {:.4} -> {:.4}",
+                name.to_uppercase(),
+                channel.changed * 100.0,
+                channel.kl_divergence,
+                channel.cover_pair_asymmetry,
+                channel.stego_pair_asymmetry
+            ));
+        }
+    }
+
+    report
+}
+
+fn json_report(analysis: &Analysis) -> Value {
+    let Analysis {
+        image,
+        chi_square,
+        rs,
+        spa,
+        cover_comparison,
+    } = analysis;
+
+    let mut report = json!({
+        "image": image,
+        "suspicion": suspicion(analysis),
+        "chi_square": {
+            "statistic": chi_square.statistic,
+            "p_value": chi_square.p_value,
+            "embedded_fraction": chi_square.embedded_fraction,
+            "interpretation": chi_square.interpretation(),
+        },
+        "rs": {
+            "embedding_rate": rs.embedding_rate,
+            "channels": channel_object(&rs.channel_rates),
+            "estimated_bytes": rs.estimated_bytes,
+            "interpretation": rs.interpretation(),
+        },
+        "sample_pairs": {
+            "embedding_rate": spa.embedding_rate,
+            "channels": channel_object(&spa.channel_rates),
+            "estimated_bytes": spa.estimated_bytes,
+            "interpretation": spa.interpretation(),
+        },
+    });
+
+    if let Some(CoverComparison {
+        cover,
+        quality,
+        channels,
+    }) = cover_comparison
+    {
+        let channels: serde_json::Map<String, Value> = CHANNEL_NAMES
+            .iter()
+            .zip(channels)
+            .map(|(name, channel)| {
+                let comparison = json!({
+                    "changed": channel.changed,
+                    "kl_divergence": channel.kl_divergence,
+                    "cover_pair_asymmetry": channel.cover_pair_asymmetry,
+                    "stego_pair_asymmetry": channel.stego_pair_asymmetry,
+                });
+                (name.to_string(), comparison)
+            })
+            .collect();
+        report["cover_comparison"] = json!({
+            "cover": cover,
+            "psnr": quality.psnr,
+            "ssim": quality.ssim,
+            "channels": channels,
+        });
+    }
+
+    report
+}
+
+/// `{"r": ..., "g": ..., ...}` for as many channels as there are rates
+fn channel_object(rates: &[f64]) -> Value {
+    CHANNEL_NAMES
+        .iter()
+        .zip(rates)
+        .map(|(name, rate)| (name.to_string(), json!(rate)))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Write bit `plane` (0 for the LSB) of `channel` of `image` to `output` as a
+/// black and white image, white where the bit is set, or of all four
+/// channels in a grid (see `bit_plane_grid`) without a channel.
+pub fn render_bit_plane(
+    image: &str,
+    plane: u8,
+    channel: Option<&str>,
+    output: &str,
+) -> Result<(), LowkeyError> {
+    if plane > 7 {
+        return Err(LowkeyError::InvalidInput(format!(
+            "Bit plane must be 0 to 7, got {}",
+            plane
+        )));
+    }
+    let img = read_image(image)?;
+    let rendered = match channel {
+        Some(name) => {
+            let index = CHANNEL_NAMES
+                .iter()
+                .position(|&channel| channel == name.to_lowercase())
+                .ok_or_else(|| {
+                    LowkeyError::InvalidInput(format!(
+                        "Invalid channel '{}': expected r, g, b or a",
+                        name
+                    ))
+                })?;
+            bit_plane(&img, index, plane)
+        }
+        None => bit_plane_grid(&img, plane),
+    };
+
+    if let Some(parent) = Path::new(output).parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            LowkeyError::Io(format!(
+                "Failed to create directory '{}': {}",
+                parent.display(),
+                e
+            ))
+        })?;
+    }
+    rendered
+        .save(output)
+        .map_err(|e| LowkeyError::Io(format!("Failed to write '{}': {}", output, e)))
+}
+
+fn export_bit_planes(image: &str, prefix: &str) -> Result<(), LowkeyError> {
+    let img = read_image(image)?;
+
+    if let Some(parent) = Path::new(prefix).parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            LowkeyError::Io(format!(
+                "Failed to create directory '{}': {}",
+                parent.display(),
+                e
+            ))
+        })?;
+    }
+
+    for (channel, name) in CHANNEL_NAMES.iter().enumerate() {
+        for (bit, suffix) in [(0, "lsb"), (1, "lsb2")] {
+            let path = format!("{}-{}-{}.png", prefix, name, suffix);
+            bit_plane(&img, channel, bit)
+                .save(&path)
+                .map_err(|e| LowkeyError::Io(format!("Failed to write '{}': {}", path, e)))?;
+        }
+    }
+
+    eprintln!("Saved bit planes of {} to {}-*.png", image, prefix);
+    Ok(())
+}
@@ -0,0 +1,366 @@
+// Entry points for Rust programs that embed lowkey, re-exported at the crate
+// root. They wrap `img::codec`, which stays available for finer control.
+
+use std::path::Path;
+
+use crate::crypto::{Cipher, KdfParams};
+use crate::error::LowkeyError;
+use crate::file_info::FileInfo;
+use crate::img::codec::{
+    Decoy, Distribute, EncodeReport, FillLimit, Format, Layout, PadTo, PayloadOptions, Redundancy,
+    SequenceChunk, SetOptions, decode_from_files, decode_from_memory, encode_chunk_file,
+    encode_from_file, encode_from_files, encode_jpeg_file, encode_to_memory,
+    message_capacity_from_files,
+};
+use crate::img::io::PngEncoding;
+use crate::img::naming::OutputTemplate;
+use crate::img::pixel::{Channels, Method};
+use crate::img::region::Region;
+
+/// Key used when none is given, as with the CLI. It only obscures the
+/// message; pass your own key to protect it.
+pub const DEFAULT_KEY: &str = "lowkey is a steganography tool";
+
+/// Error returned by the public API, with a message meant for users and its
+/// kind.
+pub type Error = LowkeyError;
+
+/// How to encode, mirroring the `lowkey encode` options.
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    /// Encryption key (any length, stretched with Argon2id)
+    pub key: String,
+    /// Shrink the covers to about the size the message needs (see `--auto-resize`)
+    pub auto_resize: bool,
+    /// Leave out covers too small to hold even the headers, with
+    /// `encode_many` (see `--skip-small`)
+    pub skip_small: bool,
+    /// Put a complete copy of the message in every cover, or erasure-code it
+    /// over them, instead of splitting it, with `encode_many` (see `--redundancy`)
+    pub redundancy: Redundancy,
+    /// Spread the message so that every cover is about equally full instead
+    /// of filling each in turn, with `encode_many` (see `--distribute`)
+    pub distribute: Distribute,
+    /// Spread the message over the noisy areas of a single cover (see `--match-noise`)
+    pub match_noise: bool,
+    /// Place the message in a keyed random order in a single cover (see `--scatter`)
+    pub scatter: bool,
+    /// Scatter the message over the textured areas of a single cover only
+    /// (see `--adaptive`)
+    pub adaptive: bool,
+    /// Message bits per channel value, 1 to 4 (see `--bits`); must be 1 with `match_noise`
+    pub bits: u8,
+    /// Channels that carry the message (see `--channels`); must be all four with `match_noise`
+    pub channels: Channels,
+    /// Argon2id cost of deriving the encryption key from `key`
+    pub kdf: KdfParams,
+    /// AEAD cipher the message is encrypted with (see `--cipher`)
+    pub cipher: Cipher,
+    /// Add Reed-Solomon parity so that damaged values can be corrected (see `--ecc`)
+    pub ecc: bool,
+    /// Metadata of the file the message came from, restored by `decode --output-dir`
+    pub file: Option<FileInfo>,
+    /// The message was made by `archive::pack`, for `decode --output-dir` to unpack
+    pub archive: bool,
+    /// Embed the message without encrypting it, for payloads already
+    /// encrypted with another tool (see `--no-encrypt`)
+    pub plain: bool,
+    /// `age1...` public keys to encrypt the message to instead of with
+    /// `key` (see `--recipient`)
+    pub recipients: Vec<String>,
+    /// Pad the message to a fixed size so that its length does not show
+    /// (see `--pad-to`)
+    pub pad_to: Option<PadTo>,
+    /// Harmless message with a key of its own, embedded in front of the
+    /// message to give up instead of it; needs `scatter` or `adaptive` (see `--decoy`)
+    pub decoy: Option<Decoy>,
+    /// Set the low bits the message leaves unused at random (see `--no-noise-fill`)
+    pub noise_fill: bool,
+    /// How values are changed to carry the bits (see `--method`)
+    pub method: Method,
+    /// Leave transparent pixels alone, with a single cover (see `--skip-transparent`)
+    pub skip_transparent: bool,
+    /// Keep the message to part of a single cover, which decode finds with
+    /// the key (see `--region`)
+    pub region: Option<Region>,
+    /// Embed in the pixels, in the DCT coefficients of a JPEG cover, which
+    /// `encode` then writes as a JPEG, or in a PNG chunk (see `--format`)
+    pub format: Format,
+    /// How `encode_many` names its outputs instead of after the covers,
+    /// e.g. "{stem}_{index:03}.png" (see `--output-template`)
+    pub output_template: Option<String>,
+    /// Deflate the message first when that makes it smaller (see `--no-compress`)
+    pub compress: bool,
+    /// Most of each cover the message may fill, with the pixels format (see `--max-fill`)
+    pub max_fill: Option<FillLimit>,
+    /// Apply each cover's ICC profile to its pixels and write plain sRGB (see `--convert-srgb`)
+    pub convert_srgb: bool,
+    /// Give the outputs none of the covers' metadata (see `--strip-metadata`)
+    pub strip_metadata: bool,
+    /// What `encode_many`'s outputs record of their order in their metadata
+    /// (see `--no-sequence-chunk` and `--encrypt-sequence-chunk`)
+    pub sequence_chunk: SequenceChunk,
+    /// Compression level and row filter of PNG outputs (see
+    /// `--png-compression` and `--png-filter`)
+    pub png_encoding: PngEncoding,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            key: DEFAULT_KEY.to_string(),
+            auto_resize: false,
+            skip_small: false,
+            redundancy: Redundancy::None,
+            distribute: Distribute::Fill,
+            match_noise: false,
+            scatter: false,
+            adaptive: false,
+            bits: 1,
+            channels: Channels::ALL,
+            kdf: KdfParams::default(),
+            cipher: Cipher::default(),
+            ecc: false,
+            file: None,
+            archive: false,
+            plain: false,
+            recipients: Vec::new(),
+            pad_to: None,
+            decoy: None,
+            noise_fill: true,
+            method: Method::default(),
+            skip_transparent: false,
+            region: None,
+            format: Format::default(),
+            output_template: None,
+            compress: true,
+            max_fill: None,
+            convert_srgb: false,
+            strip_metadata: false,
+            sequence_chunk: SequenceChunk::default(),
+            png_encoding: PngEncoding::default(),
+        }
+    }
+}
+
+impl EncodeOptions {
+    /// Default options with the given key.
+    pub fn with_key(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            ..Self::default()
+        }
+    }
+
+    fn payload(&self) -> PayloadOptions {
+        PayloadOptions {
+            kdf: self.kdf,
+            ecc: self.ecc,
+            file: self.file.clone(),
+            archive: self.archive,
+            plain: self.plain,
+            cipher: self.cipher,
+            recipients: self.recipients.clone(),
+            pad_to: self.pad_to,
+            decoy: self.decoy.clone(),
+            noise_fill: self.noise_fill,
+            method: self.method,
+            skip_transparent: self.skip_transparent,
+            region: self.region.clone(),
+            compress: self.compress,
+            max_fill: self.max_fill,
+            convert_srgb: self.convert_srgb,
+            strip_metadata: self.strip_metadata,
+            sequence_chunk: self.sequence_chunk,
+            png_encoding: self.png_encoding,
+        }
+    }
+
+    fn layout(&self) -> Result<Layout, Error> {
+        let (depth, channels) = (self.bits, self.channels);
+        match (self.match_noise, self.scatter, self.adaptive) {
+            (false, false, false) => Ok(Layout::Sequential { depth, channels }),
+            (false, true, false) => Ok(Layout::Scattered { depth, channels }),
+            (false, false, true) => Ok(Layout::Adaptive { depth, channels }),
+            (true, false, false) if depth == 1 && channels == Channels::ALL => {
+                Ok(Layout::NoiseMatched)
+            }
+            (true, false, false) => Err(Error::InvalidInput(
+                "match_noise only supports one bit per value in all four channels".to_string(),
+            )),
+            _ => Err(Error::InvalidInput(
+                "Only one of match_noise, scatter and adaptive can be used".to_string(),
+            )),
+        }
+    }
+}
+
+/// Hide `message` in `cover` and write the PNG to `output`, or the JPEG
+/// with `Format::Jpeg`. With `Format::Chunk` the message goes in a PNG
+/// chunk instead of the pixels.
+pub fn encode(
+    cover: impl AsRef<Path>,
+    message: &[u8],
+    output: impl AsRef<Path>,
+    options: &EncodeOptions,
+) -> Result<EncodeReport, Error> {
+    check_key(&options.key)?;
+    let (cover, output) = (path_str(cover.as_ref())?, path_str(output.as_ref())?);
+    if options.format != Format::Pixels {
+        if options.layout()? != Layout::default()
+            || options.auto_resize
+            || options.max_fill.is_some()
+        {
+            return Err(Error::InvalidInput(format!(
+                "The {} format only supports the default layout, without auto_resize or max_fill",
+                options.format
+            )));
+        }
+        let encode_file = match options.format {
+            Format::Jpeg => encode_jpeg_file,
+            _ => encode_chunk_file,
+        };
+        return encode_file(cover, message, output, &options.key, &options.payload());
+    }
+    encode_from_file(
+        cover,
+        message,
+        output,
+        options.auto_resize,
+        options.layout()?,
+        &options.key,
+        &options.payload(),
+    )
+}
+
+/// Spread `message` over several covers and write the PNGs, named after the
+/// covers or by `options.output_template`, to `output_dir`. Decode them together, in any order.
+pub fn encode_many(
+    covers: &[impl AsRef<Path>],
+    message: &[u8],
+    output_dir: impl AsRef<Path>,
+    options: &EncodeOptions,
+) -> Result<EncodeReport, Error> {
+    check_key(&options.key)?;
+    if options.match_noise || options.scatter || options.adaptive {
+        return Err(Error::InvalidInput(
+            "match_noise, scatter and adaptive are only supported with a single cover".to_string(),
+        ));
+    }
+    if options.format != Format::Pixels {
+        return Err(Error::InvalidInput(format!(
+            "The {} format is only supported with a single cover",
+            options.format
+        )));
+    }
+    let set = SetOptions {
+        depth: options.bits,
+        channels: options.channels,
+        auto_resize: options.auto_resize,
+        skip_small: options.skip_small,
+        redundancy: options.redundancy,
+        distribute: options.distribute,
+        output_template: options
+            .output_template
+            .as_deref()
+            .map(str::parse::<OutputTemplate>)
+            .transpose()?,
+    };
+    encode_from_files(
+        &path_strings(covers)?,
+        message,
+        path_str(output_dir.as_ref())?,
+        &set,
+        &options.key,
+        &options.payload(),
+    )
+}
+
+/// Recover the message hidden in one image, or in all images of a sequence.
+pub fn decode(images: &[impl AsRef<Path>], key: &str) -> Result<Vec<u8>, Error> {
+    check_key(key)?;
+    decode_from_files(&path_strings(images)?, key)
+}
+
+/// Like `encode`, with the cover and the resulting PNG held in memory.
+pub fn encode_bytes(cover: &[u8], message: &[u8], key: &str) -> Result<Vec<u8>, Error> {
+    check_key(key)?;
+    encode_to_memory(cover, message, key)
+}
+
+/// Like `decode`, for a single PNG held in memory.
+pub fn decode_bytes(image: &[u8], key: &str) -> Result<Vec<u8>, Error> {
+    check_key(key)?;
+    decode_from_memory(image, key)
+}
+
+/// Largest message, in bytes, that fits into the given images.
+pub fn capacity(images: &[impl AsRef<Path>]) -> Result<usize, Error> {
+    message_capacity_from_files(&path_strings(images)?)
+}
+
+fn check_key(key: &str) -> Result<(), Error> {
+    if key.is_empty() {
+        return Err(Error::InvalidInput(
+            "Encryption key cannot be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn path_str(path: &Path) -> Result<&str, Error> {
+    path.to_str()
+        .ok_or_else(|| Error::InvalidInput(format!("Path is not valid UTF-8: {}", path.display())))
+}
+
+fn path_strings(paths: &[impl AsRef<Path>]) -> Result<Vec<String>, Error> {
+    paths
+        .iter()
+        .map(|path| path_str(path.as_ref()).map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbaImage};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let dir = std::env::temp_dir().join(format!("lowkey-api-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png");
+        let output = dir.join("stego.png");
+        RgbaImage::from_pixel(64, 64, image::Rgba([10, 200, 90, 255]))
+            .save(&cover)
+            .unwrap();
+
+        let options = EncodeOptions::with_key("api key");
+        let report = encode(&cover, b"from another program", &output, &options).unwrap();
+        assert_eq!(report.images.len(), 1);
+        assert_eq!(
+            decode(&[&output], "api key").unwrap(),
+            b"from another program"
+        );
+        assert!(decode(&[&output], "wrong key").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bytes_round_trip_and_errors() {
+        let mut cover = Vec::new();
+        RgbaImage::from_pixel(32, 32, image::Rgba([1, 2, 3, 255]))
+            .write_to(&mut Cursor::new(&mut cover), ImageFormat::Png)
+            .unwrap();
+
+        let stego = encode_bytes(&cover, b"in memory", DEFAULT_KEY).unwrap();
+        assert_eq!(decode_bytes(&stego, DEFAULT_KEY).unwrap(), b"in memory");
+
+        let error = encode_bytes(&cover, b"x", "").unwrap_err();
+        assert_eq!(error.message(), "Encryption key cannot be empty");
+        let boxed: Box<dyn std::error::Error> = Box::new(error);
+        assert!(!boxed.to_string().is_empty());
+    }
+}
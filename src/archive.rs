@@ -0,0 +1,208 @@
+// Several files bundled into one message, so that a whole folder can be
+// hidden at once: [4 bytes file count] + for each file [2 bytes path length] +
+// [path] + [8 bytes data length] + [data], all big-endian. Paths are relative
+// and use '/' between components.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::LowkeyError;
+use crate::file_info::check_name;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Relative path, '/'-separated
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+pub fn pack(entries: &[Entry]) -> Result<Vec<u8>, LowkeyError> {
+    let count = u32::try_from(entries.len()).map_err(|_| {
+        LowkeyError::InvalidInput(format!("Too many files for one archive: {}", entries.len()))
+    })?;
+
+    let mut bytes = count.to_be_bytes().to_vec();
+    for entry in entries {
+        check_path(&entry.path).map_err(|e| LowkeyError::InvalidInput(e.message().to_string()))?;
+        let path_len = u16::try_from(entry.path.len())
+            .map_err(|_| LowkeyError::InvalidInput(format!("Path is too long: {}", entry.path)))?;
+        bytes.extend_from_slice(&path_len.to_be_bytes());
+        bytes.extend_from_slice(entry.path.as_bytes());
+        bytes.extend_from_slice(&(entry.data.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&entry.data);
+    }
+    Ok(bytes)
+}
+
+pub fn unpack(bytes: &[u8]) -> Result<Vec<Entry>, LowkeyError> {
+    let (count, mut rest) = take(bytes, 4)?;
+    let count = u32::from_be_bytes(count.try_into().unwrap());
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let (path_len, after) = take(rest, 2)?;
+        let (path, after) = take(
+            after,
+            u16::from_be_bytes([path_len[0], path_len[1]]) as usize,
+        )?;
+        let (data_len, after) = take(after, 8)?;
+        let data_len = usize::try_from(u64::from_be_bytes(data_len.try_into().unwrap()))
+            .map_err(|_| LowkeyError::Format("File in the archive is too large".to_string()))?;
+        let (data, after) = take(after, data_len)?;
+        rest = after;
+
+        let path = String::from_utf8(path.to_vec())
+            .map_err(|_| LowkeyError::Format("Path in the archive is not UTF-8".to_string()))?;
+        check_path(&path)?;
+        entries.push(Entry {
+            path,
+            data: data.to_vec(),
+        });
+    }
+
+    if !rest.is_empty() {
+        return Err(LowkeyError::Format(format!(
+            "{} stray bytes after the archive",
+            rest.len()
+        )));
+    }
+    Ok(entries)
+}
+
+/// Every file under `dir`, with paths relative to it, in sorted order.
+/// Symbolic links to directories are not followed.
+pub fn read_dir(dir: &str) -> Result<Vec<Entry>, LowkeyError> {
+    let root = Path::new(dir);
+    if !root.is_dir() {
+        return Err(LowkeyError::InvalidInput(format!(
+            "'{}' is not a directory",
+            dir
+        )));
+    }
+
+    let mut entries = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let listing = fs::read_dir(&current).map_err(|e| {
+            LowkeyError::Io(format!(
+                "Failed to read directory '{}': {}",
+                current.display(),
+                e
+            ))
+        })?;
+        for item in listing {
+            let item = item
+                .map_err(|e| LowkeyError::Io(format!("Failed to read directory entry: {}", e)))?;
+            let path = item.path();
+            let file_type = item
+                .file_type()
+                .map_err(|e| LowkeyError::Io(format!("Failed to read directory entry: {}", e)))?;
+
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if path.is_file() {
+                let relative = path
+                    .strip_prefix(root)
+                    .unwrap()
+                    .components()
+                    .map(|component| component.as_os_str().to_str())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| {
+                        LowkeyError::InvalidInput(format!(
+                            "Invalid file name in '{}'",
+                            path.display()
+                        ))
+                    })?
+                    .join("/");
+                let data = fs::read(&path).map_err(|e| {
+                    LowkeyError::Io(format!(
+                        "Failed to read message file '{}': {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+                entries.push(Entry {
+                    path: relative,
+                    data,
+                });
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Write `entries` under `dir`, creating subdirectories, and return the paths written.
+pub fn write_dir(entries: &[Entry], dir: &str) -> Result<Vec<PathBuf>, LowkeyError> {
+    let mut written = Vec::with_capacity(entries.len());
+    for entry in entries {
+        check_path(&entry.path)?;
+        let path = entry
+            .path
+            .split('/')
+            .fold(PathBuf::from(dir), |path, component| path.join(component));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                LowkeyError::Io(format!("Failed to create output directory: {}", e))
+            })?;
+        }
+        fs::write(&path, &entry.data)
+            .map_err(|e| LowkeyError::Io(format!("Failed to write '{}': {}", path.display(), e)))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// The paths come from the message, so none may lead out of the output directory.
+fn check_path(path: &str) -> Result<(), LowkeyError> {
+    path.split('/')
+        .try_for_each(check_name)
+        .map_err(|_| LowkeyError::Format(format!("Unsafe path '{}' in the archive", path)))
+}
+
+/// The first `len` bytes and the rest.
+fn take(bytes: &[u8], len: usize) -> Result<(&[u8], &[u8]), LowkeyError> {
+    bytes
+        .split_at_checked(len)
+        .ok_or_else(|| LowkeyError::Format("Archive in the message is truncated".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, data: &[u8]) -> Entry {
+        Entry {
+            path: path.to_string(),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_pack_unpack() {
+        let entries = vec![
+            entry("a.txt", b"first"),
+            entry("docs/empty", b""),
+            entry("docs/deep/b.bin", &[0, 1, 2, 255]),
+        ];
+        let bytes = pack(&entries).unwrap();
+        assert_eq!(unpack(&bytes).unwrap(), entries);
+
+        assert!(unpack(&bytes[..bytes.len() - 1]).is_err());
+        assert!(unpack(&[bytes.as_slice(), b"x"].concat()).is_err());
+        assert_eq!(unpack(&pack(&[]).unwrap()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_unsafe_paths() {
+        for path in ["", "/etc/passwd", "../x", "a/../../x", "a//b", "a/", "a\\b"] {
+            let mut bytes = 1u32.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&(path.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(path.as_bytes());
+            bytes.extend_from_slice(&0u64.to_be_bytes());
+            assert!(unpack(&bytes).is_err(), "{:?}", path);
+            assert!(pack(&[entry(path, b"")]).is_err(), "{:?}", path);
+        }
+    }
+}
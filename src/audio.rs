@@ -0,0 +1,179 @@
+// Audio covers: the message goes in the lowest bit of 16-bit PCM samples,
+// one bit per sample, in the order they are stored (channels interleaved).
+// A change of one in 32768 is far below anything audible. WAV files are read
+// and written as they are; FLAC files are decoded and written back as WAV,
+// since the samples must be kept exactly.
+
+use std::fs;
+use std::io::Cursor;
+
+use crate::carrier::Carrier;
+use crate::error::LowkeyError;
+
+/// Whether `bytes` start like a WAV or FLAC file.
+pub fn is_audio(bytes: &[u8]) -> bool {
+    is_wav(bytes) || bytes.starts_with(b"fLaC")
+}
+
+fn is_wav(bytes: &[u8]) -> bool {
+    bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WAVE"
+}
+
+/// Whether the file at `path` is a WAV or FLAC file, by its first bytes.
+pub fn is_audio_file(path: &str) -> Result<bool, LowkeyError> {
+    use std::io::Read;
+    let mut start = Vec::with_capacity(12);
+    fs::File::open(path)
+        .and_then(|file| file.take(12).read_to_end(&mut start))
+        .map_err(|e| LowkeyError::Io(format!("Failed to read '{}': {}", path, e)))?;
+    Ok(is_audio(&start))
+}
+
+/// 16-bit PCM samples with the format to write them back in.
+pub struct Audio {
+    spec: hound::WavSpec,
+    samples: Vec<i16>,
+}
+
+impl Audio {
+    /// Read the WAV or FLAC file at `path`.
+    pub fn read(path: &str) -> Result<Self, LowkeyError> {
+        let bytes = fs::read(path)
+            .map_err(|e| LowkeyError::Io(format!("Failed to read '{}': {}", path, e)))?;
+        Self::parse(&bytes).map_err(|e| e.map_message(|e| format!("{} ('{}')", e, path)))
+    }
+
+    /// Decode a WAV or FLAC file in memory.
+    pub fn parse(bytes: &[u8]) -> Result<Self, LowkeyError> {
+        if is_wav(bytes) {
+            let reader = hound::WavReader::new(Cursor::new(bytes))
+                .map_err(|e| LowkeyError::Format(format!("Invalid WAV file: {}", e)))?;
+            let spec = reader.spec();
+            if spec.sample_format != hound::SampleFormat::Int || spec.bits_per_sample != 16 {
+                return Err(LowkeyError::Format(
+                    "Only 16-bit PCM WAV files are supported".to_string(),
+                ));
+            }
+            let samples = reader
+                .into_samples::<i16>()
+                .collect::<Result<_, _>>()
+                .map_err(|e| LowkeyError::Format(format!("Invalid WAV file: {}", e)))?;
+            Ok(Self { spec, samples })
+        } else if bytes.starts_with(b"fLaC") {
+            let mut reader = claxon::FlacReader::new(Cursor::new(bytes))
+                .map_err(|e| LowkeyError::Format(format!("Invalid FLAC file: {}", e)))?;
+            let info = reader.streaminfo();
+            if info.bits_per_sample != 16 {
+                return Err(LowkeyError::Format(format!(
+                    "Only 16-bit FLAC files are supported, not {}-bit ones",
+                    info.bits_per_sample
+                )));
+            }
+            let spec = hound::WavSpec {
+                channels: info.channels as u16,
+                sample_rate: info.sample_rate,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let samples = reader
+                .samples()
+                .map(|sample| sample.map(|sample| sample as i16))
+                .collect::<Result<_, _>>()
+                .map_err(|e| LowkeyError::Format(format!("Invalid FLAC file: {}", e)))?;
+            Ok(Self { spec, samples })
+        } else {
+            Err(LowkeyError::Format("Not a WAV or FLAC file".to_string()))
+        }
+    }
+
+    /// The samples as a 16-bit PCM WAV file.
+    pub fn to_wav(&self) -> Result<Vec<u8>, LowkeyError> {
+        let mut bytes = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut bytes, self.spec)
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
+        let mut samples = writer.get_i16_writer(self.samples.len() as u32);
+        for &sample in &self.samples {
+            samples.write_sample(sample);
+        }
+        samples
+            .flush()
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
+        writer
+            .finalize()
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
+        Ok(bytes.into_inner())
+    }
+
+    /// Every sample, channels interleaved.
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+}
+
+impl Carrier for Audio {
+    fn value_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn values(&self) -> impl Iterator<Item = u8> + '_ {
+        self.samples.iter().map(|&sample| sample as u8 & 1)
+    }
+
+    fn set_value(&mut self, index: usize, bit: bool) {
+        let sample = &mut self.samples[index];
+        *sample = (*sample & !1) | bit as i16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        Audio {
+            spec,
+            samples: samples.to_vec(),
+        }
+        .to_wav()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = wav(&[0, -1, 1000, -32768, 32767, 5]);
+        assert!(is_audio(&bytes));
+        let mut audio = Audio::parse(&bytes).unwrap();
+        assert_eq!(audio.value_count(), 6);
+        assert_eq!(audio.values().collect::<Vec<_>>(), vec![0, 1, 0, 0, 1, 1]);
+
+        for index in 0..6 {
+            audio.set_value(index, index % 2 == 0);
+        }
+        let audio = Audio::parse(&audio.to_wav().unwrap()).unwrap();
+        assert_eq!(audio.samples(), &[1, -2, 1001, -32768, 32767, 4]);
+        assert_eq!(audio.spec.channels, 2);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 8,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut bytes = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        writer.write_sample(3i8).unwrap();
+        writer.finalize().unwrap();
+        assert!(Audio::parse(bytes.get_ref()).is_err());
+        assert!(Audio::parse(b"not audio").is_err());
+        assert!(!is_audio(b"RIFF\0\0\0\0WEBPVP8L"));
+    }
+}
@@ -0,0 +1,17 @@
+// Covers whose message bits don't go in the pixels of an image: the
+// coefficients of a JPEG, the samples of an audio file. The header,
+// encryption and capacity checks are the same for all of them; only where
+// the bits are differs.
+
+/// A cover with a fixed sequence of values, each of which can carry a
+/// message bit in its lowest bit.
+pub trait Carrier {
+    /// Values that can carry a bit each.
+    fn value_count(&self) -> usize;
+
+    /// The bit each value carries now, as 0 or 1, in order.
+    fn values(&self) -> impl Iterator<Item = u8> + '_;
+
+    /// Set the bit the `index`th value carries.
+    fn set_value(&mut self, index: usize, bit: bool);
+}
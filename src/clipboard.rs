@@ -0,0 +1,42 @@
+use arboard::{Clipboard, ImageData};
+use std::borrow::Cow;
+
+/// Read the current clipboard text as message bytes.
+pub fn read_text() -> Result<Vec<u8>, String> {
+    let mut clipboard =
+        Clipboard::new().map_err(|e| format!("Failed to access clipboard: {}", e))?;
+    let text = clipboard
+        .get_text()
+        .map_err(|e| format!("Failed to read text from clipboard: {}", e))?;
+
+    Ok(text.into_bytes())
+}
+
+/// Place decoded message bytes onto the clipboard.
+///
+/// UTF-8 messages are copied as text. Anything else is tried as an image, so a
+/// hidden screenshot can be pasted straight into another application. Other
+/// binary data is rejected because the clipboard has no portable format for it.
+pub fn write(bytes: &[u8]) -> Result<(), String> {
+    let mut clipboard =
+        Clipboard::new().map_err(|e| format!("Failed to access clipboard: {}", e))?;
+
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return clipboard
+            .set_text(text)
+            .map_err(|e| format!("Failed to write text to clipboard: {}", e));
+    }
+
+    let img = image::load_from_memory(bytes)
+        .map_err(|_| "Decoded message is neither text nor an image and cannot be placed on the clipboard (use --output instead)".to_string())?
+        .to_rgba8();
+    let (width, height) = img.dimensions();
+
+    clipboard
+        .set_image(ImageData {
+            width: width as usize,
+            height: height as usize,
+            bytes: Cow::Owned(img.into_raw()),
+        })
+        .map_err(|e| format!("Failed to write image to clipboard: {}", e))
+}
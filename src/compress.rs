@@ -0,0 +1,61 @@
+// Deflate compression of messages before they are encrypted, so that more
+// fits in a cover and fewer values change.
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{Read, Write};
+
+use crate::error::LowkeyError;
+
+/// Largest message a compressed payload may inflate to
+const MAX_DECOMPRESSED_BYTES: u64 = 1 << 30;
+
+/// The compressed message, or `None` if compressing does not make it smaller.
+pub(crate) fn compress(message_bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(message_bytes).ok()?;
+    let compressed = encoder.finish().ok()?;
+
+    (compressed.len() < message_bytes.len()).then_some(compressed)
+}
+
+/// The message `compress` deflated; one that doesn't inflate is damaged.
+pub(crate) fn decompress(compressed: &[u8]) -> Result<Vec<u8>, LowkeyError> {
+    let mut message_bytes = Vec::new();
+    DeflateDecoder::new(compressed)
+        .take(MAX_DECOMPRESSED_BYTES + 1)
+        .read_to_end(&mut message_bytes)
+        .map_err(|e| LowkeyError::Format(format!("Failed to decompress message: {}", e)))?;
+
+    if message_bytes.len() as u64 > MAX_DECOMPRESSED_BYTES {
+        return Err(LowkeyError::Format(format!(
+            "Failed to decompress message: larger than {} bytes",
+            MAX_DECOMPRESSED_BYTES
+        )));
+    }
+    Ok(message_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let text = "the quick brown fox jumps over the lazy dog\n".repeat(100);
+        let compressed = compress(text.as_bytes()).unwrap();
+        assert!(compressed.len() * 5 < text.len());
+        assert_eq!(decompress(&compressed).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn test_incompressible() {
+        assert!(compress(b"").is_none());
+        assert!(compress(&[0x5a]).is_none());
+        assert!(matches!(
+            decompress(b"\xff\xff"),
+            Err(LowkeyError::Format(_))
+        ));
+    }
+}
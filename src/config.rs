@@ -0,0 +1,187 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Settings from `~/.config/lowkey/config.toml` (or `$LOWKEY_CONFIG`).
+/// Command-line options take precedence.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub hooks: Hooks,
+    pub defaults: Defaults,
+    /// Named sets of defaults, picked with --profile
+    pub profiles: BTreeMap<String, Defaults>,
+}
+
+impl Config {
+    /// The `[defaults]`, with those of `[profiles.<profile>]` on top.
+    pub fn defaults(&self, profile: Option<&str>) -> Result<Defaults, String> {
+        let Some(name) = profile else {
+            return Ok(self.defaults.clone());
+        };
+        let profile = self.profiles.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            format!(
+                "Unknown profile '{}' (the config file defines: {})",
+                name,
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            )
+        })?;
+        Ok(profile.clone().or(self.defaults.clone()))
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    /// Same as --pre-encode-cmd
+    pub pre_encode: Option<String>,
+    /// Same as --post-encode-cmd
+    pub post_encode: Option<String>,
+}
+
+/// Encode and decode settings used where the command line leaves them open.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    /// Same as --key-file; a leading `~/` is the home directory
+    pub key_file: Option<String>,
+    /// Same as --bits
+    pub bits: Option<u8>,
+    /// Same as --channels
+    pub channels: Option<String>,
+    /// Where `encode --image` writes without --output, with `{stem}` for the
+    /// cover's file name without its extension, e.g. "{stem}-lk.png"
+    pub output: Option<String>,
+    /// `false` is the same as --no-compress
+    pub compress: Option<bool>,
+}
+
+impl Defaults {
+    /// These settings, falling back to `other` for those left unset.
+    fn or(self, other: Defaults) -> Defaults {
+        Defaults {
+            key_file: self.key_file.or(other.key_file),
+            bits: self.bits.or(other.bits),
+            channels: self.channels.or(other.channels),
+            output: self.output.or(other.output),
+            compress: self.compress.or(other.compress),
+        }
+    }
+
+    /// The key file with `~/` expanded.
+    pub fn key_file(&self) -> Option<String> {
+        let path = self.key_file.as_ref()?;
+        Some(match (path.strip_prefix("~/"), env("HOME")) {
+            (Some(rest), Some(home)) => PathBuf::from(home).join(rest).display().to_string(),
+            _ => path.clone(),
+        })
+    }
+
+    /// The output path for `cover` from the naming template, if there is one.
+    pub fn output_for(&self, cover: &str) -> Option<String> {
+        let stem = Path::new(cover)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        let name = self.output.as_ref()?.replace("{stem}", &stem);
+        // Next to the cover, unless the template says where
+        Some(match Path::new(cover).parent() {
+            Some(dir) if !Path::new(&name).is_absolute() && !cover.contains("://") => {
+                dir.join(name).display().to_string()
+            }
+            _ => name,
+        })
+    }
+}
+
+/// Load the config file. A missing file is the same as an empty one.
+pub fn load() -> Result<Config, String> {
+    let Some(path) = config_path() else {
+        return Ok(Config::default());
+    };
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => {
+            return Err(format!(
+                "Failed to read config file '{}': {}",
+                path.display(),
+                e
+            ));
+        }
+    };
+
+    toml::from_str(&text)
+        .map_err(|e| format!("Failed to parse config file '{}': {}", path.display(), e))
+}
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env("LOWKEY_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+
+    let config_dir = env("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| env("APPDATA").map(PathBuf::from))?;
+    Some(config_dir.join("lowkey").join("config.toml"))
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hooks() {
+        let config: Config = toml::from_str(
+            "[hooks]\npre_encode = \"optimize \\\"$LOWKEY_IMAGES\\\"\"\npost_encode = \"upload\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.hooks.pre_encode.as_deref(),
+            Some("optimize \"$LOWKEY_IMAGES\"")
+        );
+        assert_eq!(config.hooks.post_encode.as_deref(), Some("upload"));
+
+        assert!(toml::from_str::<Config>("[hooks]\nunknown = \"x\"\n").is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config: Config = toml::from_str(
+            "[defaults]\nbits = 2\noutput = \"{stem}-lk.png\"\n\n\
+             [profiles.work]\nkey_file = \"/keys/work\"\nbits = 1\ncompress = false\n",
+        )
+        .unwrap();
+        assert_eq!(config.defaults(None).unwrap().bits, Some(2));
+
+        let work = config.defaults(Some("work")).unwrap();
+        assert_eq!(work.bits, Some(1));
+        assert_eq!(work.key_file().as_deref(), Some("/keys/work"));
+        assert_eq!(work.compress, Some(false));
+        assert_eq!(
+            work.output_for("photos/cat.jpg").as_deref(),
+            Some("photos/cat-lk.png")
+        );
+        assert_eq!(work.output_for("cat.jpg").as_deref(), Some("cat-lk.png"));
+
+        let error = config.defaults(Some("home")).unwrap_err();
+        assert!(
+            error.contains("home") && error.contains("work"),
+            "{}",
+            error
+        );
+        assert!(toml::from_str::<Config>("[profiles.work]\nbitz = 1\n").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::rpc;
+use crate::service::{self, Listener};
+
+/// Frames larger than this are refused instead of allocated
+const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+/// Serve encode/decode requests from other local processes on a Unix domain
+/// socket at `path`, using `key` for every request.
+///
+/// Clients never see or send the key; only this process holds it. Each frame
+/// is a 4-byte big-endian length followed by that many bytes of a JSON-RPC 2.0
+/// message, with the same methods as `lowkey rpc`. Responses use the same
+/// framing. The socket is only accessible to the user running lowkey and is
+/// removed on shutdown. Requests may only name files in `root`, or in the
+/// working directory without one.
+#[cfg(unix)]
+pub fn serve(path: &str, key: String, root: Option<&str>) -> Result<(), String> {
+    let policy = Arc::new(rpc::Policy {
+        key: Some(key),
+        token: None,
+        root: Some(rpc::resolve_root(root.map(Into::into))?),
+    });
+    let shutdown = service::install_shutdown_handler()?;
+    let listener = Listener::bind_unix(path)?;
+    eprintln!("Listening on {}", path);
+
+    let connection_shutdown = Arc::clone(&shutdown);
+    let result = listener.serve(&shutdown, move |stream| {
+        if let Err(e) = serve_connection(stream, &policy, &connection_shutdown) {
+            eprintln!("Connection failed: {}", e);
+        }
+    });
+
+    let _ = std::fs::remove_file(path);
+    result
+}
+
+/// Named pipes are not implemented, so there is no control server on Windows.
+#[cfg(not(unix))]
+pub fn serve(_path: &str, _key: String, _root: Option<&str>) -> Result<(), String> {
+    Err(
+        "The control socket is only supported on Unix; Windows named pipes are not implemented"
+            .into(),
+    )
+}
+
+/// Answer framed requests on `stream` until EOF or shutdown.
+fn serve_connection<S: Read + Write>(
+    mut stream: S,
+    policy: &rpc::Policy,
+    shutdown: &AtomicBool,
+) -> io::Result<()> {
+    while let Some(frame) = read_frame(&mut stream)? {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let _request = service::begin_request();
+        let message = String::from_utf8_lossy(&frame);
+        if let Some(response) = rpc::handle_message(&message, policy) {
+            write_frame(&mut stream, response.to_string().as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read one frame, or `None` if the peer closed the connection between frames.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes exceeds the {} byte limit",
+                len, MAX_FRAME_LEN
+            ),
+        ));
+    }
+
+    let mut frame = vec![0u8; len];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(frame.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame too large"))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(frame)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, b"{}").unwrap();
+        write_frame(&mut buffer, b"[1]").unwrap();
+        assert_eq!(&buffer[..4], &[0, 0, 0, 2]);
+
+        let mut reader = buffer.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"{}");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"[1]");
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_oversized_frame_is_refused() {
+        let mut reader: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+        assert!(read_frame(&mut reader).is_err());
+    }
+}
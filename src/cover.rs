@@ -0,0 +1,64 @@
+// `lowkey generate-cover`: a synthetic cover image, sized for a message if
+// asked, for when there is no photo at hand.
+
+use std::path::Path;
+
+use lowkey::crypto;
+use lowkey::img::codec::message_capacity_for_size;
+use lowkey::img::common::check_image_png;
+use lowkey::img::generate::{CoverStyle, check_fits, generate_cover, parse_size, size_for_message};
+
+pub struct CoverOptions<'a> {
+    /// `WxH`; without it, the smallest 4:3 size that fits `message_size`
+    pub size: Option<&'a str>,
+    /// "noise", "gradient" or "photo-like"; photo-like if not given
+    pub style: Option<&'a str>,
+    /// Bytes of message the cover must carry with the default settings
+    pub message_size: Option<usize>,
+    /// Seed of the image; random if not given
+    pub seed: Option<u64>,
+}
+
+/// Write a cover generated as `options` say to `output`.
+pub fn generate(output: &str, options: &CoverOptions) -> Result<String, String> {
+    check_image_png(output)?;
+    let style: CoverStyle = options
+        .style
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let (width, height) = match (options.size, options.message_size) {
+        (Some(size), message_size) => {
+            let (width, height) = parse_size(size)?;
+            if let Some(message_size) = message_size {
+                check_fits(width, height, message_size)?;
+            }
+            (width, height)
+        }
+        (None, Some(message_size)) => size_for_message(message_size)?,
+        (None, None) => return Err("Must specify --size or --message-size".into()),
+    };
+    let seed = options.seed.unwrap_or_else(|| {
+        u64::from_le_bytes(crypto::random_bytes(8).try_into().expect("8 bytes"))
+    });
+
+    let cover = generate_cover(width, height, style, seed);
+    if let Some(parent) = Path::new(output).parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create directory '{}': {}", parent.display(), e))?;
+    }
+    cover
+        .save(output)
+        .map_err(|e| format!("Failed to write '{}': {}", output, e))?;
+
+    Ok(format!(
+        "Generated a {}x{} cover with room for {} bytes at {} (seed {})",
+        width,
+        height,
+        message_capacity_for_size(width, height),
+        output,
+        seed
+    ))
+}
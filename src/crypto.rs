@@ -1,8 +1,199 @@
+use aes_gcm::Aes256Gcm;
+use argon2::{Algorithm, Argon2, Params, Version};
 use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
-    ChaCha20Poly1305, Nonce,
+    ChaCha20Poly1305, Nonce, XChaCha20Poly1305,
+    aead::{self, Aead, AeadCore, KeyInit, OsRng, rand_core::RngCore},
 };
+use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::error::LowkeyError;
+
+/// Size of the random salt stored in front of passphrase-derived ciphertexts
+pub const SALT_BYTES: usize = 16;
+
+/// Salt and Argon2id parameters stored in front of the nonce
+pub const KDF_HEADER_BYTES: usize = SALT_BYTES + 12;
+
+/// Key check value stored after the KDF parameters by `encrypt_with_key_check`
+pub const KEY_CHECK_BYTES: usize = 8;
+
+/// Plaintext bytes per chunk of `encrypt_chunked` output
+pub const CHUNK_BYTES: usize = 1 << 20;
+
+/// Authentication tag at the end of every ciphertext or chunk, as long for
+/// every cipher
+pub const TAG_BYTES: usize = 16;
+
+/// AEAD cipher of `encrypt_chunked` output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Cipher {
+    #[default]
+    ChaCha20Poly1305,
+    /// With 24-byte nonces, so a key can encrypt more messages than random
+    /// 12-byte nonces would safely allow
+    XChaCha20Poly1305,
+    /// Fast on CPUs with AES instructions, and what some policies require
+    Aes256Gcm,
+}
+
+impl Cipher {
+    /// Value of the cipher byte in the message header
+    pub fn id(self) -> u8 {
+        match self {
+            Self::ChaCha20Poly1305 => 0,
+            Self::XChaCha20Poly1305 => 1,
+            Self::Aes256Gcm => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, LowkeyError> {
+        match id {
+            0 => Ok(Self::ChaCha20Poly1305),
+            1 => Ok(Self::XChaCha20Poly1305),
+            2 => Ok(Self::Aes256Gcm),
+            _ => Err(LowkeyError::Format(format!("Unknown cipher {}", id))),
+        }
+    }
+
+    /// Random start of every chunk nonce, which goes on with a 4-byte chunk
+    /// counter and a byte that is 1 for the last chunk only
+    const fn nonce_prefix_bytes(self) -> usize {
+        match self {
+            Self::XChaCha20Poly1305 => 24 - 5,
+            Self::ChaCha20Poly1305 | Self::Aes256Gcm => 12 - 5,
+        }
+    }
+
+    /// Everything `encrypt_chunked` puts before the first chunk
+    pub const fn chunked_header_bytes(self) -> usize {
+        KDF_HEADER_BYTES + KEY_CHECK_BYTES + self.nonce_prefix_bytes()
+    }
+
+    fn backend(self, key_bytes: &[u8; 32]) -> Box<dyn ChunkCipher> {
+        match self {
+            Self::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305::new(key_bytes.into())),
+            Self::XChaCha20Poly1305 => Box::new(XChaCha20Poly1305::new(key_bytes.into())),
+            Self::Aes256Gcm => Box::new(Aes256Gcm::new(key_bytes.into())),
+        }
+    }
+}
+
+impl std::str::FromStr for Cipher {
+    type Err = LowkeyError;
+
+    /// `chacha20-poly1305`, `xchacha20-poly1305` or `aes-256-gcm`.
+    fn from_str(name: &str) -> Result<Self, LowkeyError> {
+        match name {
+            "chacha20-poly1305" => Ok(Self::ChaCha20Poly1305),
+            "xchacha20-poly1305" => Ok(Self::XChaCha20Poly1305),
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown cipher '{}' (expected chacha20-poly1305, xchacha20-poly1305 or aes-256-gcm)",
+                name
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+            Self::XChaCha20Poly1305 => "XChaCha20-Poly1305",
+            Self::Aes256Gcm => "AES-256-GCM",
+        })
+    }
+}
+
+/// The AEAD backends behind `Cipher`, taking nonces of their own length as
+/// plain bytes.
+trait ChunkCipher {
+    fn seal(&self, nonce: &[u8], plaintext: &[u8]) -> aead::Result<Vec<u8>>;
+    fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> aead::Result<Vec<u8>>;
+}
+
+impl<C: Aead> ChunkCipher for C {
+    fn seal(&self, nonce: &[u8], plaintext: &[u8]) -> aead::Result<Vec<u8>> {
+        self.encrypt(aead::Nonce::<C>::from_slice(nonce), plaintext)
+    }
+
+    fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> aead::Result<Vec<u8>> {
+        self.decrypt(aead::Nonce::<C>::from_slice(nonce), ciphertext)
+    }
+}
+
+/// Upper bounds on Argon2id parameters, so a crafted image cannot make
+/// decoding allocate or compute without limit
+pub const MAX_KDF_MEMORY_KIB: u32 = 1024 * 1024;
+pub const MAX_KDF_ITERATIONS: u32 = 64;
+pub const MAX_KDF_PARALLELISM: u32 = 16;
+
+/// Default memory bound of the RPC and control servers, where one request
+/// for an image claiming `MAX_KDF_MEMORY_KIB` would tie up a GiB per request
+pub const SERVER_MAX_KDF_MEMORY_KIB: u32 = 64 * 1024;
+
+/// Memory bound in force for this process, `MAX_KDF_MEMORY_KIB` until
+/// `limit_kdf_memory` lowers it
+static KDF_MEMORY_LIMIT_KIB: AtomicU32 = AtomicU32::new(MAX_KDF_MEMORY_KIB);
+
+/// Refuse Argon2id parameters above `memory_kib` from now on, for encoding
+/// and decoding alike.
+pub fn limit_kdf_memory(memory_kib: u32) {
+    KDF_MEMORY_LIMIT_KIB.store(memory_kib.min(MAX_KDF_MEMORY_KIB), Ordering::Relaxed);
+}
+
+/// Cost of deriving the encryption key from the passphrase with Argon2id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub memory_kib: u32,
+    /// Number of passes over the memory
+    pub iterations: u32,
+    /// Number of lanes (always computed on one thread)
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The OWASP recommendation for Argon2id: 19 MiB, 2 passes, 1 lane
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    pub fn validate(&self) -> Result<(), LowkeyError> {
+        if !(1..=MAX_KDF_PARALLELISM).contains(&self.parallelism) {
+            return Err(LowkeyError::InvalidInput(format!(
+                "Invalid KDF parallelism {} (must be 1 to {})",
+                self.parallelism, MAX_KDF_PARALLELISM
+            )));
+        }
+        let min_memory_kib = 8 * self.parallelism;
+        let max_memory_kib = KDF_MEMORY_LIMIT_KIB.load(Ordering::Relaxed);
+        if !(min_memory_kib..=max_memory_kib).contains(&self.memory_kib) {
+            return Err(LowkeyError::InvalidInput(format!(
+                "Invalid KDF memory {} KiB (must be {} to {} KiB)",
+                self.memory_kib, min_memory_kib, max_memory_kib
+            )));
+        }
+        if !(1..=MAX_KDF_ITERATIONS).contains(&self.iterations) {
+            return Err(LowkeyError::InvalidInput(format!(
+                "Invalid KDF iterations {} (must be 1 to {})",
+                self.iterations, MAX_KDF_ITERATIONS
+            )));
+        }
+        Ok(())
+    }
+}
 
 fn get_key_bytes(key: &str) -> [u8; 32] {
     // Hash the key using SHA256 to get a fixed 32-byte key
@@ -15,53 +206,648 @@ fn get_key_bytes(key: &str) -> [u8; 32] {
     key_array
 }
 
-/// Encrypts plaintext using ChaCha20-Poly1305
+fn derive_key_bytes(key: &str, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], LowkeyError> {
+    params.validate()?;
+    argon2id(key, salt, params)
+}
+
+fn argon2id(key: &str, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], LowkeyError> {
+    let argon2_params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(32),
+    )
+    .map_err(|e| LowkeyError::Crypto(format!("Key derivation failed: {}", e)))?;
+    let mut key_bytes = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+        .hash_password_into(key.as_bytes(), salt, &mut key_bytes)
+        .map_err(|e| LowkeyError::Crypto(format!("Key derivation failed: {}", e)))?;
+    Ok(key_bytes)
+}
+
+/// Encrypts plaintext using ChaCha20-Poly1305 with a key derived from `key`
+/// by SHA256. Kept for reading and writing version 0 messages; new messages
+/// use `encrypt_with_kdf`.
 /// Returns: nonce (12 bytes) + ciphertext + tag (16 bytes)
 ///
 /// # Arguments
 /// * `plaintext` - The data to encrypt
 /// * `key` - Encryption key.
-pub fn encrypt(plaintext: &[u8], key: &str) -> Result<Vec<u8>, String> {
-    let key_bytes = get_key_bytes(key);
-    let cipher = ChaCha20Poly1305::new((&key_bytes).into());
+pub fn encrypt(plaintext: &[u8], key: &str) -> Result<Vec<u8>, LowkeyError> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    encrypt_with_nonce(plaintext, &get_key_bytes(key), &nonce)
+}
+
+/// Encrypts plaintext using ChaCha20-Poly1305 with a key derived from `key`
+/// by Argon2id with a random salt.
+/// Returns: salt (16 bytes) + memory KiB, iterations, parallelism (4 bytes
+/// each, big endian) + nonce (12 bytes) + ciphertext + tag (16 bytes)
+///
+/// # Arguments
+/// * `plaintext` - The data to encrypt
+/// * `key` - Encryption key.
+/// * `params` - Argon2id cost
+pub fn encrypt_with_kdf(
+    plaintext: &[u8],
+    key: &str,
+    params: &KdfParams,
+) -> Result<Vec<u8>, LowkeyError> {
+    let mut salt = [0u8; SALT_BYTES];
+    OsRng.fill_bytes(&mut salt);
+
+    let key_bytes = derive_key_bytes(key, &salt, params)?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let encrypted = encrypt_with_nonce(plaintext, &key_bytes, &nonce)?;
+    Ok(with_kdf_header(&salt, params, &encrypted))
+}
+
+/// Like `encrypt_with_kdf`, with a key check value between the Argon2id
+/// parameters and the nonce, so that `decrypt_with_key_check` can tell a
+/// wrong key from damaged data.
+/// Returns: salt (16 bytes) + memory KiB, iterations, parallelism (4 bytes
+/// each, big endian) + key check (8 bytes) + nonce (12 bytes) + ciphertext +
+/// tag (16 bytes)
+pub fn encrypt_with_key_check(
+    plaintext: &[u8],
+    key: &str,
+    params: &KdfParams,
+) -> Result<Vec<u8>, LowkeyError> {
+    let mut salt = [0u8; SALT_BYTES];
+    OsRng.fill_bytes(&mut salt);
+
+    let key_bytes = derive_key_bytes(key, &salt, params)?;
     let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut checked = key_check(&key_bytes).finalize().into_bytes()[..KEY_CHECK_BYTES].to_vec();
+    checked.extend(encrypt_with_nonce(plaintext, &key_bytes, &nonce)?);
+    Ok(with_kdf_header(&salt, params, &checked))
+}
+
+/// Encrypts plaintext in chunks of `CHUNK_BYTES`, each with its own tag, so
+/// that it can be decrypted a chunk at a time (see `ChunkDecryptor`). The
+/// nonces count the chunks and mark the last one, so chunks can be neither
+/// reordered nor dropped (the STREAM construction).
+/// Returns: salt (16 bytes) + memory KiB, iterations, parallelism (4 bytes
+/// each, big endian) + key check (8 bytes) + nonce prefix (7 bytes, 19 for
+/// XChaCha20-Poly1305) + per chunk: ciphertext + tag (16 bytes)
+pub fn encrypt_chunked(
+    plaintext: &[u8],
+    key: &str,
+    params: &KdfParams,
+    cipher: Cipher,
+) -> Result<Vec<u8>, LowkeyError> {
+    let mut salt = [0u8; SALT_BYTES];
+    OsRng.fill_bytes(&mut salt);
+    let mut prefix = vec![0u8; cipher.nonce_prefix_bytes()];
+    OsRng.fill_bytes(&mut prefix);
+
+    let key_bytes = derive_key_bytes(key, &salt, params)?;
+    let backend = cipher.backend(&key_bytes);
+    let mut result = with_kdf_header(
+        &salt,
+        params,
+        &[
+            &key_check(&key_bytes).finalize().into_bytes()[..KEY_CHECK_BYTES],
+            &prefix[..],
+        ]
+        .concat(),
+    );
+    result.reserve(chunked_len(plaintext.len(), cipher) - result.len());
+
+    let count = chunk_count(plaintext.len());
+    // An empty message is still one (empty) last chunk
+    let mut chunks = plaintext.chunks(CHUNK_BYTES);
+    for index in 0..count {
+        let chunk = chunks.next().unwrap_or_default();
+        let ciphertext = backend
+            .seal(&chunk_nonce(&prefix, index, index + 1 == count), chunk)
+            .map_err(|e| LowkeyError::Crypto(format!("Encryption failed: {}", e)))?;
+        result.extend_from_slice(&ciphertext);
+    }
+    Ok(result)
+}
+
+/// Number of chunks `encrypt_chunked` cuts `plaintext_len` bytes into.
+fn chunk_count(plaintext_len: usize) -> usize {
+    plaintext_len.div_ceil(CHUNK_BYTES).max(1)
+}
+
+/// Size of `encrypt_chunked` output for `plaintext_len` bytes.
+pub fn chunked_len(plaintext_len: usize, cipher: Cipher) -> usize {
+    cipher.chunked_header_bytes() + plaintext_len + chunk_count(plaintext_len) * TAG_BYTES
+}
+
+/// Most plaintext bytes that `encrypted_len` bytes of `encrypt_chunked`
+/// output can carry.
+pub fn max_chunked_plaintext_len(encrypted_len: usize, cipher: Cipher) -> usize {
+    let chunks = encrypted_len.saturating_sub(cipher.chunked_header_bytes());
+    let full = chunks / (CHUNK_BYTES + TAG_BYTES);
+    let partial = chunks % (CHUNK_BYTES + TAG_BYTES);
+    full * CHUNK_BYTES + partial.saturating_sub(TAG_BYTES)
+}
+
+fn chunk_nonce(prefix: &[u8], index: usize, last: bool) -> Vec<u8> {
+    let mut nonce = prefix.to_vec();
+    nonce.extend_from_slice(&(index as u32).to_be_bytes());
+    nonce.push(last as u8);
+    nonce
+}
+
+/// Decrypts `encrypt_chunked` output a chunk at a time, so that a long
+/// message never has to be held encrypted and decrypted at once.
+pub struct ChunkDecryptor {
+    backend: Box<dyn ChunkCipher>,
+    prefix: Vec<u8>,
+    index: usize,
+}
+
+impl ChunkDecryptor {
+    /// Derive the key from the first `cipher.chunked_header_bytes()` of the
+    /// encrypted data and check it.
+    pub fn new(header: &[u8], key: &str, cipher: Cipher) -> Result<Self, LowkeyError> {
+        if header.len() != cipher.chunked_header_bytes() {
+            return Err(LowkeyError::Crypto(format!(
+                "Encrypted data too short: {} bytes (minimum is {} bytes)",
+                header.len(),
+                cipher.chunked_header_bytes() + TAG_BYTES
+            )));
+        }
+        let (salt, params, rest) = split_kdf_header(header);
+        let key_bytes = derive_key_bytes(key, salt, &params)?;
+        let (check, prefix) = rest.split_at(KEY_CHECK_BYTES);
+        if key_check(&key_bytes).verify_truncated_left(check).is_err() {
+            return Err(LowkeyError::Crypto(
+                "Wrong key: the message was hidden with a different key".to_string(),
+            ));
+        }
+        Ok(Self {
+            backend: cipher.backend(&key_bytes),
+            prefix: prefix.to_vec(),
+            index: 0,
+        })
+    }
+
+    /// Decrypt the next chunk, of `CHUNK_BYTES + TAG_BYTES` bytes unless it
+    /// is the `last`.
+    pub fn decrypt(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, LowkeyError> {
+        let nonce = chunk_nonce(&self.prefix, self.index, last);
+        let plaintext = self.backend.open(&nonce, chunk).map_err(|_| {
+            LowkeyError::Crypto(format!(
+                "The message is damaged: the key is right, but chunk {} does not check out",
+                self.index + 1
+            ))
+        })?;
+        self.index += 1;
+        Ok(plaintext)
+    }
+}
+
+/// Decrypts all of `encrypt_chunked` output at once.
+pub fn decrypt_chunked(
+    encrypted_data: &[u8],
+    key: &str,
+    cipher: Cipher,
+) -> Result<Vec<u8>, LowkeyError> {
+    let header_bytes = cipher.chunked_header_bytes();
+    if encrypted_data.len() < header_bytes + TAG_BYTES {
+        return Err(LowkeyError::Crypto(format!(
+            "Encrypted data too short: {} bytes (minimum is {} bytes)",
+            encrypted_data.len(),
+            header_bytes + TAG_BYTES
+        )));
+    }
+    let (header, chunks) = encrypted_data.split_at(header_bytes);
+    let mut decryptor = ChunkDecryptor::new(header, key, cipher)?;
+    let count = chunks.len().div_ceil(CHUNK_BYTES + TAG_BYTES);
+    let mut plaintext = Vec::with_capacity(max_chunked_plaintext_len(encrypted_data.len(), cipher));
+    for (index, chunk) in chunks.chunks(CHUNK_BYTES + TAG_BYTES).enumerate() {
+        plaintext.extend(decryptor.decrypt(chunk, index + 1 == count)?);
+    }
+    Ok(plaintext)
+}
+
+/// MAC over a fixed label, whose start is the key check value. It tells
+/// nothing the authentication tag doesn't already, but is checked first.
+fn key_check(key_bytes: &[u8; 32]) -> Hmac<Sha256> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key_bytes).expect("HMAC accepts keys of any length");
+    mac.update(b"lowkey key check");
+    mac
+}
+
+/// Encrypts plaintext using ChaCha20-Poly1305 with a nonce derived from the
+/// key and plaintext, so the same input always gives the same output.
+/// Returns the same format as `encrypt`.
+///
+/// This reveals whether two ciphertexts hold the same plaintext, which is the
+/// price for stable output in places like git filters.
+///
+/// # Arguments
+/// * `plaintext` - The data to encrypt
+/// * `key` - Encryption key.
+pub fn encrypt_deterministic(plaintext: &[u8], key: &str) -> Result<Vec<u8>, LowkeyError> {
+    let key_bytes = get_key_bytes(key);
+    encrypt_with_nonce(
+        plaintext,
+        &key_bytes,
+        &deterministic_nonce(plaintext, &key_bytes),
+    )
+}
+
+/// Like `encrypt_with_kdf`, but with the salt and nonce derived from the key
+/// and plaintext, so the same input always gives the same output.
+///
+/// The salt is keyed with Argon2id of the key under a fixed salt, not with a
+/// fast hash of the key, since it is stored in the clear: checking a guessed
+/// key against it then costs as much as against the ciphertext.
+///
+/// # Arguments
+/// * `plaintext` - The data to encrypt
+/// * `key` - Encryption key.
+/// * `params` - Argon2id cost
+pub fn encrypt_deterministic_with_kdf(
+    plaintext: &[u8],
+    key: &str,
+    params: &KdfParams,
+) -> Result<Vec<u8>, LowkeyError> {
+    let salt_key = derive_key_bytes(key, b"lowkey deterministic salt key", params)?;
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(&salt_key).expect("HMAC accepts keys of any length");
+    mac.update(b"lowkey deterministic salt");
+    mac.update(plaintext);
+    let digest = mac.finalize().into_bytes();
+    let salt = &digest[..SALT_BYTES];
+
+    let key_bytes = derive_key_bytes(key, salt, params)?;
+    let nonce = deterministic_nonce(plaintext, &key_bytes);
+    let encrypted = encrypt_with_nonce(plaintext, &key_bytes, &nonce)?;
+    Ok(with_kdf_header(salt, params, &encrypted))
+}
+
+/// Nonce for `plaintext`, from a subkey of `key_bytes` so the AEAD key is
+/// never used for anything but the AEAD.
+fn deterministic_nonce(plaintext: &[u8], key_bytes: &[u8; 32]) -> Nonce {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key_bytes).expect("HMAC accepts keys of any length");
+    mac.update(b"lowkey nonce key");
+    let nonce_key = mac.finalize().into_bytes();
+
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(&nonce_key).expect("HMAC accepts keys of any length");
+    mac.update(b"lowkey deterministic nonce");
+    mac.update(plaintext);
+    let digest = mac.finalize().into_bytes();
+    *Nonce::from_slice(&digest[..12])
+}
+
+fn with_kdf_header(salt: &[u8], params: &KdfParams, encrypted: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(KDF_HEADER_BYTES + encrypted.len());
+    result.extend_from_slice(salt);
+    result.extend_from_slice(&params.memory_kib.to_be_bytes());
+    result.extend_from_slice(&params.iterations.to_be_bytes());
+    result.extend_from_slice(&params.parallelism.to_be_bytes());
+    result.extend_from_slice(encrypted);
+    result
+}
+
+/// Keyed SHA256 digest of `data`, for recognising unchanged data without
+/// storing it or the key. Keyed with `stretched_key`, since a known or
+/// guessed `data` would otherwise test guessed keys at SHA256 speed.
+pub fn fingerprint(data: &[u8], key: &str) -> [u8; 32] {
+    let key_bytes = stretched_key(key, b"lowkey fingerprint key");
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(&key_bytes).expect("HMAC accepts keys of any length");
+    mac.update(b"lowkey fingerprint");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// A result of `stretched_key`, found again by the SHA256 of the key
+struct StretchedKey {
+    id: [u8; 32],
+    domain: &'static [u8],
+    key_bytes: [u8; 32],
+}
+
+/// Most recent results of `stretched_key`
+static STRETCHED_KEYS: Mutex<Vec<StretchedKey>> = Mutex::new(Vec::new());
+
+/// How many results `STRETCHED_KEYS` keeps
+const STRETCHED_KEYS_KEPT: usize = 16;
+
+/// Argon2id of `key` at the default cost under the fixed salt `domain`, for
+/// keying what can't carry a random salt of its own. Kept for the process,
+/// so each image or file using the key doesn't derive it again.
+fn stretched_key(key: &str, domain: &'static [u8]) -> [u8; 32] {
+    let id = get_key_bytes(key);
+    let mut kept = STRETCHED_KEYS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if let Some(stretched) = kept
+        .iter()
+        .find(|stretched| stretched.id == id && stretched.domain == domain)
+    {
+        return stretched.key_bytes;
+    }
+    // The default cost is fixed rather than validated against the memory
+    // limit: it has to be the same for every reader.
+    let key_bytes =
+        argon2id(key, domain, &KdfParams::default()).expect("the default parameters are valid");
+    if kept.len() == STRETCHED_KEYS_KEPT {
+        kept.remove(0);
+    }
+    kept.push(StretchedKey {
+        id,
+        domain,
+        key_bytes,
+    });
+    key_bytes
+}
+
+/// Random identifier as long as a UUID, such as the one tying the images of
+/// a sequence together.
+pub fn random_id() -> [u8; 16] {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
+/// `len` random bytes, such as the noise filling the low bits a message
+/// left unused.
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Random 32-byte key, hex-encoded so that it can be given as `--key` or
+/// stored for `--key-file`.
+pub fn generate_key() -> String {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// New age X25519 identity: the `AGE-SECRET-KEY-1...` secret for
+/// `decrypt_with_identities` and the `age1...` public key for
+/// `encrypt_to_recipients`.
+pub fn generate_identity() -> (String, String) {
+    use age::secrecy::ExposeSecret;
+
+    let identity = age::x25519::Identity::generate();
+    (
+        identity.to_string().expose_secret().to_string(),
+        identity.to_public().to_string(),
+    )
+}
+
+/// Keyed seed for placing message bits, so only holders of the key know
+/// where they are. The header sits at the first positions and is easy to
+/// recognise, so the seed comes from `stretched_key`: otherwise finding it
+/// would test a guessed key faster than the ciphertext can.
+pub fn layout_seed(key: &str) -> u64 {
+    let key_bytes = stretched_key(key, b"lowkey layout key");
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(&key_bytes).expect("HMAC accepts keys of any length");
+    mac.update(b"lowkey layout");
+    let digest = mac.finalize().into_bytes();
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+fn encrypt_with_nonce(
+    plaintext: &[u8],
+    key_bytes: &[u8; 32],
+    nonce: &Nonce,
+) -> Result<Vec<u8>, LowkeyError> {
+    let cipher = ChaCha20Poly1305::new(key_bytes.into());
 
     let ciphertext = cipher
-        .encrypt(&nonce, plaintext)
-        .map_err(|e| format!("Encryption failed: {}", e))?;
+        .encrypt(nonce, plaintext)
+        .map_err(|e| LowkeyError::Crypto(format!("Encryption failed: {}", e)))?;
 
     let mut result = Vec::with_capacity(12 + ciphertext.len());
-    result.extend_from_slice(&nonce);
+    result.extend_from_slice(nonce);
     result.extend_from_slice(&ciphertext);
 
     Ok(result)
 }
 
-/// Decrypts ciphertext using ChaCha20-Poly1305
+/// Decrypts ciphertext from `encrypt` using ChaCha20-Poly1305
 /// Input format: nonce (12 bytes) + ciphertext + tag (16 bytes)
 ///
 /// # Arguments
 /// * `encrypted_data` - The data to decrypt (nonce + ciphertext + tag)
 /// * `key` - Decryption key.
-pub fn decrypt(encrypted_data: &[u8], key: &str) -> Result<Vec<u8>, String> {
+pub fn decrypt(encrypted_data: &[u8], key: &str) -> Result<Vec<u8>, LowkeyError> {
+    decrypt_with_key_bytes(encrypted_data, &get_key_bytes(key))
+}
+
+/// Decrypts ciphertext from `encrypt_with_kdf`, deriving the key with the
+/// salt and Argon2id parameters stored in front of it
+///
+/// # Arguments
+/// * `encrypted_data` - The data to decrypt (salt + parameters + nonce + ciphertext + tag)
+/// * `key` - Decryption key.
+pub fn decrypt_with_kdf(encrypted_data: &[u8], key: &str) -> Result<Vec<u8>, LowkeyError> {
+    if encrypted_data.len() < KDF_HEADER_BYTES {
+        return Err(LowkeyError::Crypto(format!(
+            "Encrypted data too short: {} bytes (minimum is {} bytes)",
+            encrypted_data.len(),
+            KDF_HEADER_BYTES + 12 + 16
+        )));
+    }
+
+    let (salt, params, encrypted) = split_kdf_header(encrypted_data);
+    let key_bytes = derive_key_bytes(key, salt, &params)?;
+    decrypt_with_key_bytes(encrypted, &key_bytes)
+}
+
+/// Decrypts ciphertext from `encrypt_with_key_check`, saying whether the key
+/// is wrong or the data damaged if it can't.
+///
+/// # Arguments
+/// * `encrypted_data` - The data to decrypt (salt + parameters + key check + nonce + ciphertext + tag)
+/// * `key` - Decryption key.
+pub fn decrypt_with_key_check(encrypted_data: &[u8], key: &str) -> Result<Vec<u8>, LowkeyError> {
+    if encrypted_data.len() < KDF_HEADER_BYTES + KEY_CHECK_BYTES {
+        return Err(LowkeyError::Crypto(format!(
+            "Encrypted data too short: {} bytes (minimum is {} bytes)",
+            encrypted_data.len(),
+            KDF_HEADER_BYTES + KEY_CHECK_BYTES + 12 + 16
+        )));
+    }
+
+    let (salt, params, checked) = split_kdf_header(encrypted_data);
+    let key_bytes = derive_key_bytes(key, salt, &params)?;
+    let (check, encrypted) = checked.split_at(KEY_CHECK_BYTES);
+    if key_check(&key_bytes).verify_truncated_left(check).is_err() {
+        return Err(LowkeyError::Crypto(
+            "Wrong key: the message was hidden with a different key".to_string(),
+        ));
+    }
+    decrypt_with_key_bytes(encrypted, &key_bytes).map_err(|_| {
+        LowkeyError::Crypto(
+            "The message is damaged: the key is right, but the data does not check out".to_string(),
+        )
+    })
+}
+
+/// Key derivation parameters from the front of `encrypt_with_kdf` output,
+/// which are stored in the clear and need no key to read.
+pub fn read_kdf_params(encrypted_data: &[u8]) -> Result<KdfParams, LowkeyError> {
+    if encrypted_data.len() < KDF_HEADER_BYTES {
+        return Err(LowkeyError::Crypto(format!(
+            "Encrypted data too short for KDF parameters: {} bytes (minimum is {} bytes)",
+            encrypted_data.len(),
+            KDF_HEADER_BYTES
+        )));
+    }
+
+    let (_, params, _) = split_kdf_header(encrypted_data);
+    params.validate()?;
+    Ok(params)
+}
+
+/// Salt and parameters from the front of `encrypt_with_kdf` output, and the rest
+fn split_kdf_header(encrypted_data: &[u8]) -> (&[u8], KdfParams, &[u8]) {
+    let (header, rest) = encrypted_data.split_at(KDF_HEADER_BYTES);
+    let word = |i: usize| {
+        let start = SALT_BYTES + i * 4;
+        u32::from_be_bytes(header[start..start + 4].try_into().unwrap())
+    };
+    let params = KdfParams {
+        memory_kib: word(0),
+        iterations: word(1),
+        parallelism: word(2),
+    };
+    (&header[..SALT_BYTES], params, rest)
+}
+
+fn decrypt_with_key_bytes(
+    encrypted_data: &[u8],
+    key_bytes: &[u8; 32],
+) -> Result<Vec<u8>, LowkeyError> {
     if encrypted_data.len() < 12 + 16 {
-        return Err(format!(
+        return Err(LowkeyError::Crypto(format!(
             "Encrypted data too short: {} bytes (minimum is 28 bytes)",
             encrypted_data.len()
-        ));
+        )));
     }
 
-    let key_bytes = get_key_bytes(key);
-    let cipher = ChaCha20Poly1305::new((&key_bytes).into());
+    let cipher = ChaCha20Poly1305::new(key_bytes.into());
     let nonce = Nonce::from_slice(&encrypted_data[..12]);
     let ciphertext = &encrypted_data[12..];
-    let plaintext = cipher
-        .decrypt(nonce, ciphertext)
-        .map_err(|e| format!("Decryption failed: {}", e))?;
+    let plaintext = cipher.decrypt(nonce, ciphertext).map_err(|_| {
+        LowkeyError::Crypto("Decryption failed: wrong key or damaged message".to_string())
+    })?;
 
     Ok(plaintext)
 }
 
+/// Encrypts plaintext to every recipient with age: a random file key
+/// encrypts the data, and the age header holds that key wrapped for each
+/// recipient by X25519.
+/// Returns: an age file (binary format)
+///
+/// # Arguments
+/// * `plaintext` - The data to encrypt
+/// * `recipients` - `age1...` public keys
+pub fn encrypt_to_recipients(
+    plaintext: &[u8],
+    recipients: &[String],
+) -> Result<Vec<u8>, LowkeyError> {
+    let recipients = recipients
+        .iter()
+        .map(|recipient| parse_recipient(recipient))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let encryptor = age::Encryptor::with_recipients(
+        recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient),
+    )
+    .map_err(|e| LowkeyError::Crypto(format!("Failed to encrypt to recipients: {}", e)))?;
+
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut ciphertext)
+        .map_err(|e| LowkeyError::Crypto(format!("Failed to encrypt to recipients: {}", e)))?;
+    writer
+        .write_all(plaintext)
+        .and_then(|_| writer.finish())
+        .map_err(|e| LowkeyError::Crypto(format!("Failed to encrypt to recipients: {}", e)))?;
+
+    Ok(ciphertext)
+}
+
+/// Decrypts an age file produced by `encrypt_to_recipients`
+///
+/// # Arguments
+/// * `ciphertext` - The age file
+/// * `identity_files` - age identity files (`AGE-SECRET-KEY-1...` lines)
+pub fn decrypt_with_identities(
+    ciphertext: &[u8],
+    identity_files: &[String],
+) -> Result<Vec<u8>, LowkeyError> {
+    let mut identities = Vec::new();
+    for path in identity_files {
+        let identity_file = age::IdentityFile::from_file(path.clone()).map_err(|e| {
+            LowkeyError::Io(format!("Failed to read identity file '{}': {}", path, e))
+        })?;
+        identities.extend(identity_file.into_identities().map_err(|e| {
+            LowkeyError::Io(format!("Failed to read identity file '{}': {}", path, e))
+        })?);
+    }
+
+    let decryptor = age::Decryptor::new_buffered(ciphertext)
+        .map_err(|e| LowkeyError::Crypto(format!("Failed to decrypt with identities: {}", e)))?;
+    let mut reader = decryptor
+        .decrypt(
+            identities
+                .iter()
+                .map(|identity| identity.as_ref() as &dyn age::Identity),
+        )
+        .map_err(|e| LowkeyError::Crypto(format!("Failed to decrypt with identities: {}", e)))?;
+
+    let mut plaintext = Vec::new();
+    reader
+        .read_to_end(&mut plaintext)
+        .map_err(|e| LowkeyError::Crypto(format!("Failed to decrypt with identities: {}", e)))?;
+
+    Ok(plaintext)
+}
+
+/// Recipients listed in an age recipients file: one `age1...` public key per
+/// line, blank lines and `#` comments ignored.
+pub fn read_recipients_file(path: &str) -> Result<Vec<String>, LowkeyError> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        LowkeyError::Io(format!("Failed to read recipients file '{}': {}", path, e))
+    })?;
+
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            parse_recipient(line)
+                .map(|_| line.to_string())
+                .map_err(|e| {
+                    e.map_message(|e| format!("{} on line {} of '{}'", e, line_number, path))
+                })
+        })
+        .collect()
+}
+
+fn parse_recipient(recipient: &str) -> Result<age::x25519::Recipient, LowkeyError> {
+    recipient.parse().map_err(|e| {
+        LowkeyError::InvalidInput(format!(
+            "Invalid recipient '{}': {} (only age1... X25519 recipients are supported)",
+            recipient, e
+        ))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -93,9 +879,260 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_encrypt_deterministic() {
+        let message = b"Hello, World!";
+        let encrypted1 = encrypt_deterministic(message, "default-key").unwrap();
+        let encrypted2 = encrypt_deterministic(message, "default-key").unwrap();
+        assert_eq!(encrypted1, encrypted2);
+        assert_ne!(
+            encrypted1,
+            encrypt_deterministic(b"Hello, World?", "default-key").unwrap()
+        );
+
+        let decrypted = decrypt(&encrypted1, "default-key").unwrap();
+        assert_eq!(message, &decrypted[..]);
+    }
+
+    const CHEAP_KDF: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn test_derive_key_bytes() {
+        // Keys of images written before lowkey used the argon2 crate
+        let params = KdfParams {
+            memory_kib: 64,
+            iterations: 2,
+            parallelism: 2,
+        };
+        let key = derive_key_bytes("password", b"lowkey salt 16b!", &params).unwrap();
+        assert_eq!(
+            key.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "3ad2c2b598d1b8df6be14c580e462ea90037b6a04d9033e09a24e433033a482e"
+        );
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_with_kdf() {
+        let message = b"Hello, World!";
+        let encrypted = encrypt_with_kdf(message, "my-custom-password", &CHEAP_KDF).unwrap();
+        assert_eq!(encrypted.len(), KDF_HEADER_BYTES + 12 + message.len() + 16);
+        assert_eq!(
+            split_kdf_header(&encrypted).1,
+            CHEAP_KDF,
+            "parameters are stored with the ciphertext"
+        );
+
+        let decrypted = decrypt_with_kdf(&encrypted, "my-custom-password").unwrap();
+        assert_eq!(message, &decrypted[..]);
+        assert!(decrypt_with_kdf(&encrypted, "wrong-password").is_err());
+
+        // Salts are random
+        let again = encrypt_with_kdf(message, "my-custom-password", &CHEAP_KDF).unwrap();
+        assert_ne!(encrypted[..SALT_BYTES], again[..SALT_BYTES]);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_with_key_check() {
+        let message = b"Hello, World!";
+        let mut encrypted = encrypt_with_key_check(message, "default-key", &CHEAP_KDF).unwrap();
+        assert_eq!(
+            encrypted.len(),
+            KDF_HEADER_BYTES + KEY_CHECK_BYTES + 12 + message.len() + 16
+        );
+        assert_eq!(read_kdf_params(&encrypted).unwrap(), CHEAP_KDF);
+        assert_eq!(
+            decrypt_with_key_check(&encrypted, "default-key").unwrap(),
+            message
+        );
+
+        let error = decrypt_with_key_check(&encrypted, "wrong-password").unwrap_err();
+        assert!(error.message().starts_with("Wrong key"), "{}", error);
+
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        let error = decrypt_with_key_check(&encrypted, "default-key").unwrap_err();
+        assert!(
+            error.message().starts_with("The message is damaged"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_chunked() {
+        let cipher = Cipher::default();
+        for len in [0, 100, CHUNK_BYTES, CHUNK_BYTES + 1] {
+            let message: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt_chunked(&message, "default-key", &CHEAP_KDF, cipher).unwrap();
+            assert_eq!(encrypted.len(), chunked_len(len, cipher));
+            assert_eq!(max_chunked_plaintext_len(encrypted.len(), cipher), len);
+            assert_eq!(read_kdf_params(&encrypted).unwrap(), CHEAP_KDF);
+            assert_eq!(
+                decrypt_chunked(&encrypted, "default-key", cipher).unwrap(),
+                message
+            );
+        }
+
+        let message = vec![7u8; CHUNK_BYTES + 10];
+        let encrypted = encrypt_chunked(&message, "default-key", &CHEAP_KDF, cipher).unwrap();
+        let error = decrypt_chunked(&encrypted, "wrong-password", cipher).unwrap_err();
+        assert!(error.message().starts_with("Wrong key"), "{}", error);
+
+        // Dropping the last chunk leaves a full chunk that isn't marked last
+        let truncated = &encrypted[..cipher.chunked_header_bytes() + CHUNK_BYTES + TAG_BYTES];
+        let error = decrypt_chunked(truncated, "default-key", cipher).unwrap_err();
+        assert!(
+            error.message().contains("chunk 1 does not check out"),
+            "{}",
+            error
+        );
+
+        let mut damaged = encrypted.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        let error = decrypt_chunked(&damaged, "default-key", cipher).unwrap_err();
+        assert!(
+            error.message().contains("chunk 2 does not check out"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_ciphers() {
+        let message = b"Hello, World!";
+        for name in ["chacha20-poly1305", "xchacha20-poly1305", "aes-256-gcm"] {
+            let cipher: Cipher = name.parse().unwrap();
+            assert_eq!(Cipher::from_id(cipher.id()).unwrap(), cipher);
+            let encrypted = encrypt_chunked(message, "default-key", &CHEAP_KDF, cipher).unwrap();
+            assert_eq!(encrypted.len(), chunked_len(message.len(), cipher));
+            assert_eq!(
+                decrypt_chunked(&encrypted, "default-key", cipher).unwrap(),
+                message
+            );
+            // The wrong cipher fails rather than returning garbage
+            let other = Cipher::from_id((cipher.id() + 2) % 3).unwrap();
+            assert!(decrypt_chunked(&encrypted, "default-key", other).is_err());
+        }
+        assert!("aes-128-gcm".parse::<Cipher>().is_err());
+        assert!(Cipher::from_id(3).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_deterministic_with_kdf() {
+        let message = b"Hello, World!";
+        let encrypted1 =
+            encrypt_deterministic_with_kdf(message, "default-key", &CHEAP_KDF).unwrap();
+        let encrypted2 =
+            encrypt_deterministic_with_kdf(message, "default-key", &CHEAP_KDF).unwrap();
+        assert_eq!(encrypted1, encrypted2);
+
+        let decrypted = decrypt_with_kdf(&encrypted1, "default-key").unwrap();
+        assert_eq!(message, &decrypted[..]);
+    }
+
+    #[test]
+    fn test_decrypt_with_kdf_rejects_excessive_parameters() {
+        let mut encrypted = encrypt_with_kdf(b"Hello", "default-key", &CHEAP_KDF).unwrap();
+        encrypted[SALT_BYTES..SALT_BYTES + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let error = decrypt_with_kdf(&encrypted, "default-key").unwrap_err();
+        assert!(error.message().contains("Invalid KDF memory"), "{}", error);
+
+        // A lowered limit applies to what images claim; no other test comes
+        // near it, so lowering it here cannot fail them
+        limit_kdf_memory(MAX_KDF_MEMORY_KIB - 1);
+        encrypted[SALT_BYTES..SALT_BYTES + 4].copy_from_slice(&MAX_KDF_MEMORY_KIB.to_be_bytes());
+        let error = decrypt_with_kdf(&encrypted, "default-key").unwrap_err();
+        limit_kdf_memory(MAX_KDF_MEMORY_KIB);
+        assert!(error.message().contains("Invalid KDF memory"), "{}", error);
+    }
+
+    #[test]
+    fn test_layout_seed() {
+        // Keyed with Argon2id of the key, not a fast hash of it
+        let key_bytes = argon2id("key", b"lowkey layout key", &KdfParams::default()).unwrap();
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key_bytes).unwrap();
+        mac.update(b"lowkey layout");
+        let digest = mac.finalize().into_bytes();
+        assert_eq!(
+            layout_seed("key"),
+            u64::from_le_bytes(digest[..8].try_into().unwrap())
+        );
+        assert_eq!(layout_seed("key"), layout_seed("key"));
+        assert_ne!(layout_seed("key"), layout_seed("other key"));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let key_bytes = argon2id("key", b"lowkey fingerprint key", &KdfParams::default()).unwrap();
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key_bytes).unwrap();
+        mac.update(b"lowkey fingerprint");
+        mac.update(b"message");
+        let digest: [u8; 32] = mac.finalize().into_bytes().into();
+        assert_eq!(fingerprint(b"message", "key"), digest);
+        assert_ne!(fingerprint(b"message", "other key"), digest);
+    }
+
     #[test]
     fn test_decrypt_invalid_data() {
         let result = decrypt(&[0u8; 10], "any-key");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_generate_key_and_identity() {
+        let key = generate_key();
+        assert_eq!(key.len(), 64);
+        assert!(key.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_ne!(key, generate_key());
+
+        let (secret, public) = generate_identity();
+        let identity: age::x25519::Identity = secret.parse().unwrap();
+        assert_eq!(identity.to_public().to_string(), public);
+        parse_recipient(&public).unwrap();
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_with_age_files() {
+        use age::secrecy::ExposeSecret;
+
+        let dir = std::env::temp_dir().join(format!("lowkey-age-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let identity = age::x25519::Identity::generate();
+        let identity_path = dir.join("key.txt").to_string_lossy().to_string();
+        let recipients_path = dir.join("recipients.txt").to_string_lossy().to_string();
+        fs::write(
+            &identity_path,
+            format!("# test key\n{}\n", identity.to_string().expose_secret()),
+        )
+        .unwrap();
+        fs::write(
+            &recipients_path,
+            format!("# team\n\n{}\n", identity.to_public()),
+        )
+        .unwrap();
+
+        let message = b"Hello, World!";
+        let recipients = read_recipients_file(&recipients_path).unwrap();
+        assert_eq!(recipients, [identity.to_public().to_string()]);
+        let encrypted = encrypt_to_recipients(message, &recipients).unwrap();
+        let decrypted = decrypt_with_identities(&encrypted, &[identity_path]).unwrap();
+        assert_eq!(message, &decrypted[..]);
+
+        let other_path = dir.join("other.txt").to_string_lossy().to_string();
+        let other = age::x25519::Identity::generate();
+        fs::write(&other_path, other.to_string().expose_secret()).unwrap();
+        assert!(decrypt_with_identities(&encrypted, &[other_path]).is_err());
+
+        fs::write(&recipients_path, "age1notakey\n").unwrap();
+        let error = read_recipients_file(&recipients_path).unwrap_err();
+        assert!(error.message().contains("on line 1 of"), "{}", error);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,99 @@
+// `lowkey diff`: how much an encode changed its cover, in numbers and, if
+// asked, as an image of the differences.
+
+use serde_json::json;
+use std::path::Path;
+
+use crate::analyze::CHANNEL_NAMES;
+use lowkey::img::analysis::{changes, difference_image, quality};
+use lowkey::img::io::read_image;
+
+pub struct DiffOptions<'a> {
+    /// Write the amplified differences to this PNG
+    pub output: Option<&'a str>,
+    /// Factor each change is multiplied by in the difference image
+    pub amplify: u8,
+    /// Print JSON instead of text
+    pub json: bool,
+}
+
+/// Compare `stego` with its `original` cover and print the changed values,
+/// PSNR and SSIM to stdout.
+pub fn diff(original: &str, stego: &str, options: &DiffOptions) -> Result<String, String> {
+    let cover = read_image(original)?;
+    let img = read_image(stego)?;
+    let changed = changes(&cover, &img)?;
+    let quality = quality(&cover, &img)?;
+
+    if let Some(output) = options.output {
+        if let Some(parent) = Path::new(output).parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create directory '{}': {}", parent.display(), e))?;
+        }
+        difference_image(&cover, &img, options.amplify)?
+            .save(output)
+            .map_err(|e| format!("Failed to write '{}': {}", output, e))?;
+    }
+
+    let pixels = cover.width() as u64 * cover.height() as u64;
+    let share = |count: u64, total: u64| count as f64 / total.max(1) as f64 * 100.0;
+    if options.json {
+        let channels: serde_json::Map<String, serde_json::Value> = CHANNEL_NAMES
+            .iter()
+            .zip(changed.channels)
+            .map(|(name, count)| (name.to_string(), json!(count)))
+            .collect();
+        let report = json!({
+            "original": original,
+            "stego": stego,
+            "changed_values": changed.values(),
+            "total_values": pixels * 4,
+            "channels": channels,
+            "changed_pixels": changed.pixels,
+            "total_pixels": pixels,
+            "psnr": quality.psnr,
+            "ssim": quality.ssim,
+            "difference_image": options.output,
+        });
+        println!("{}", report);
+        // Keep stdout valid JSON
+        return Ok(String::new());
+    }
+
+    println!(
+        "Changed values: {} of {} ({:.2}%)",
+        changed.values(),
+        pixels * 4,
+        share(changed.values(), pixels * 4)
+    );
+    let channels: Vec<String> = CHANNEL_NAMES
+        .iter()
+        .zip(changed.channels)
+        .map(|(name, count)| {
+            format!(
+                "{}: {} ({:.2}%)",
+                name.to_uppercase(),
+                count,
+                share(count, pixels)
+            )
+        })
+        .collect();
+    println!("  {}", channels.join(", "));
+    println!(
+        "Changed pixels: {} of {} ({:.2}%)",
+        changed.pixels,
+        pixels,
+        share(changed.pixels, pixels)
+    );
+    println!("{}", quality.summary());
+
+    Ok(match options.output {
+        Some(output) => format!(
+            "Compared {} with {}, differences written to {}",
+            stego, original, output
+        ),
+        None => format!("Compared {} with {}", stego, original),
+    })
+}
@@ -0,0 +1,294 @@
+// Reed-Solomon error correction over GF(256), so that a message survives a
+// few damaged channel values. The payload is cut into RS(255, 223) blocks,
+// the last one shortened, and their bytes are interleaved so that a burst of
+// damage is spread over all blocks instead of overwhelming one.
+
+/// Bytes of a full block
+const BLOCK_BYTES: usize = 255;
+
+/// Parity bytes per block; up to half as many damaged bytes can be corrected
+const PARITY_BYTES: usize = 32;
+
+/// Data bytes of a full block
+const DATA_BYTES: usize = BLOCK_BYTES - PARITY_BYTES;
+
+/// Field polynomial x^8 + x^4 + x^3 + x^2 + 1
+const FIELD_POLY: u16 = 0x11d;
+
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+const TABLES: Tables = tables();
+
+const fn tables() -> Tables {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= FIELD_POLY;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    Tables { exp, log }
+}
+
+pub(crate) fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+    }
+}
+
+pub(crate) fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        0
+    } else {
+        TABLES.exp[TABLES.log[a as usize] as usize + 255 - TABLES.log[b as usize] as usize]
+    }
+}
+
+/// α^power
+pub(crate) fn alpha(power: usize) -> u8 {
+    TABLES.exp[power % 255]
+}
+
+/// Value at `x` of the polynomial with `coefficients` from the constant term up.
+fn eval(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0, |acc, &coefficient| mul(acc, x) ^ coefficient)
+}
+
+/// (x - α^0)(x - α^1)...(x - α^31), from the highest degree down.
+fn generator() -> [u8; PARITY_BYTES + 1] {
+    let mut poly = [0u8; PARITY_BYTES + 1];
+    poly[0] = 1;
+    for i in 0..PARITY_BYTES {
+        for j in (1..=i + 1).rev() {
+            poly[j] ^= mul(poly[j - 1], alpha(i));
+        }
+    }
+    poly
+}
+
+/// Number of bytes `encode` turns `data_len` bytes into.
+pub(crate) fn encoded_len(data_len: usize) -> usize {
+    data_len + data_len.div_ceil(DATA_BYTES) * PARITY_BYTES
+}
+
+/// Most data bytes that `encoded_len` bytes can carry.
+pub(crate) fn max_data_len(encoded_len: usize) -> usize {
+    let partial = encoded_len % BLOCK_BYTES;
+    encoded_len / BLOCK_BYTES * DATA_BYTES + partial.saturating_sub(PARITY_BYTES)
+}
+
+/// `data` in interleaved blocks with parity.
+pub(crate) fn encode(data: &[u8]) -> Vec<u8> {
+    let generator = generator();
+    let blocks: Vec<Vec<u8>> = data
+        .chunks(DATA_BYTES)
+        .map(|chunk| {
+            let mut parity = [0u8; PARITY_BYTES];
+            for &byte in chunk {
+                let factor = byte ^ parity[0];
+                parity.rotate_left(1);
+                parity[PARITY_BYTES - 1] = 0;
+                for (p, &g) in parity.iter_mut().zip(&generator[1..]) {
+                    *p ^= mul(g, factor);
+                }
+            }
+            let mut block = chunk.to_vec();
+            block.extend_from_slice(&parity);
+            block
+        })
+        .collect();
+
+    let mut encoded = Vec::with_capacity(encoded_len(data.len()));
+    for i in 0..BLOCK_BYTES {
+        encoded.extend(blocks.iter().filter_map(|block| block.get(i)));
+    }
+    encoded
+}
+
+/// The data in `encoded`, corrected, and how many bytes had to be corrected.
+pub(crate) fn decode(encoded: &[u8]) -> Result<(Vec<u8>, usize), String> {
+    let partial = encoded.len() % BLOCK_BYTES;
+    if (1..=PARITY_BYTES).contains(&partial) {
+        return Err(format!(
+            "Invalid error-corrected payload length {}",
+            encoded.len()
+        ));
+    }
+
+    let count = encoded.len().div_ceil(BLOCK_BYTES);
+    let full = encoded.len() / BLOCK_BYTES;
+    let mut blocks: Vec<Vec<u8>> = vec![Vec::new(); count];
+    let mut bytes = encoded.iter();
+    for i in 0..BLOCK_BYTES {
+        for (j, block) in blocks.iter_mut().enumerate() {
+            if j < full || i < partial {
+                block.extend(bytes.next());
+            }
+        }
+    }
+
+    let mut data = Vec::with_capacity(max_data_len(encoded.len()));
+    let mut corrected = 0;
+    for (i, block) in blocks.iter_mut().enumerate() {
+        corrected += correct(block)
+            .map_err(|e| format!("Failed to correct block {} of {}: {}", i + 1, count, e))?;
+        data.extend_from_slice(&block[..block.len() - PARITY_BYTES]);
+    }
+    Ok((data, corrected))
+}
+
+/// Syndromes of `block`, all zero if it is a codeword.
+fn compute_syndromes(block: &[u8]) -> [u8; PARITY_BYTES] {
+    let mut syndromes = [0u8; PARITY_BYTES];
+    for (j, syndrome) in syndromes.iter_mut().enumerate() {
+        *syndrome = block.iter().fold(0, |acc, &byte| mul(acc, alpha(j)) ^ byte);
+    }
+    syndromes
+}
+
+/// Correct `block` in place (Berlekamp-Massey, Chien search, Forney) and
+/// return the number of bytes corrected.
+fn correct(block: &mut [u8]) -> Result<usize, String> {
+    let syndromes = compute_syndromes(block);
+    if syndromes.iter().all(|&s| s == 0) {
+        return Ok(0);
+    }
+
+    // Error locator, from the constant term up
+    let mut locator = vec![1u8];
+    let mut previous = vec![1u8];
+    let mut errors = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1u8;
+    for n in 0..PARITY_BYTES {
+        let discrepancy = (1..=errors).fold(syndromes[n], |acc, i| {
+            acc ^ mul(*locator.get(i).unwrap_or(&0), syndromes[n - i])
+        });
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+
+        let scale = div(discrepancy, previous_discrepancy);
+        let mut next = locator.clone();
+        next.resize(next.len().max(previous.len() + shift), 0);
+        for (i, &coefficient) in previous.iter().enumerate() {
+            next[i + shift] ^= mul(scale, coefficient);
+        }
+        if 2 * errors <= n {
+            previous = std::mem::replace(&mut locator, next);
+            errors = n + 1 - errors;
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            locator = next;
+            shift += 1;
+        }
+    }
+    locator.truncate(errors + 1);
+    if errors > PARITY_BYTES / 2 {
+        return Err("too many damaged bytes".to_string());
+    }
+
+    // Error evaluator: syndromes times locator, mod x^PARITY_BYTES
+    let mut evaluator = [0u8; PARITY_BYTES];
+    for (i, &l) in locator.iter().enumerate() {
+        for (j, &s) in syndromes.iter().enumerate().take(PARITY_BYTES - i) {
+            evaluator[i + j] ^= mul(l, s);
+        }
+    }
+    // Formal derivative: only the odd terms survive in characteristic 2
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, &l)| if i % 2 == 1 { l } else { 0 })
+        .collect();
+
+    let len = block.len();
+    let mut corrected = 0;
+    for (position, byte) in block.iter_mut().enumerate() {
+        let power = len - 1 - position;
+        let x_inverse = alpha(255 - power % 255);
+        if eval(&locator, x_inverse) != 0 {
+            continue;
+        }
+        let magnitude = div(
+            mul(alpha(power), eval(&evaluator, x_inverse)),
+            eval(&derivative, x_inverse),
+        );
+        *byte ^= magnitude;
+        corrected += 1;
+    }
+
+    if corrected != errors || compute_syndromes(block).iter().any(|&s| s != 0) {
+        return Err("too many damaged bytes".to_string());
+    }
+    Ok(corrected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for len in [0, 1, 100, DATA_BYTES, DATA_BYTES + 1, 1000] {
+            let encoded = encode(&data(len));
+            assert_eq!(encoded.len(), encoded_len(len));
+            assert_eq!(max_data_len(encoded.len()), len);
+            assert_eq!(decode(&encoded).unwrap(), (data(len), 0));
+        }
+    }
+
+    #[test]
+    fn test_corrects_errors() {
+        // Two full blocks and a shortened one, with a burst across all of them
+        let mut encoded = encode(&data(500));
+        for byte in &mut encoded[100..140] {
+            *byte ^= 0x5a;
+        }
+        encoded[0] ^= 1;
+        let last = encoded.len() - 1;
+        encoded[last] ^= 0xff;
+        assert_eq!(decode(&encoded).unwrap(), (data(500), 42));
+    }
+
+    #[test]
+    fn test_too_many_errors() {
+        let mut encoded = encode(&data(100));
+        for byte in &mut encoded[..20] {
+            *byte ^= 0xa5;
+        }
+        assert!(decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_max_data_len() {
+        assert_eq!(max_data_len(BLOCK_BYTES + PARITY_BYTES), DATA_BYTES);
+        assert_eq!(max_data_len(BLOCK_BYTES + PARITY_BYTES + 5), DATA_BYTES + 5);
+        assert_eq!(max_data_len(10), 0);
+    }
+}
@@ -0,0 +1,103 @@
+// Errors of the library by kind, so that callers can tell a message that
+// doesn't fit from a wrong key or a missing file. Each carries the message
+// shown to users, which is all that `Display` prints.
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum LowkeyError {
+    /// Options or arguments that can't be used, alone or together
+    #[error("{0}")]
+    InvalidInput(String),
+    /// A file that couldn't be read or written
+    #[error("{0}")]
+    Io(String),
+    /// A cover or stego file lowkey can't read: an unsupported format, or
+    /// a damaged file
+    #[error("{0}")]
+    Format(String),
+    /// The message doesn't fit in the covers
+    #[error("{0}")]
+    Capacity(String),
+    /// Nothing was hidden with lowkey, or not in a protocol this version
+    /// reads
+    #[error("{0}")]
+    NoMessage(String),
+    /// The key or identities don't decrypt the message, or it is damaged
+    #[error("{0}")]
+    Crypto(String),
+    /// Anything else
+    #[error("{0}")]
+    Other(String),
+}
+
+impl LowkeyError {
+    /// The message shown to users.
+    pub fn message(&self) -> &str {
+        match self {
+            Self::InvalidInput(message)
+            | Self::Io(message)
+            | Self::Format(message)
+            | Self::Capacity(message)
+            | Self::NoMessage(message)
+            | Self::Crypto(message)
+            | Self::Other(message) => message,
+        }
+    }
+
+    /// The same kind of error with its message rewritten by `f`.
+    pub fn map_message(self, f: impl FnOnce(&str) -> String) -> Self {
+        let message = f(self.message());
+        match self {
+            Self::InvalidInput(_) => Self::InvalidInput(message),
+            Self::Io(_) => Self::Io(message),
+            Self::Format(_) => Self::Format(message),
+            Self::Capacity(_) => Self::Capacity(message),
+            Self::NoMessage(_) => Self::NoMessage(message),
+            Self::Crypto(_) => Self::Crypto(message),
+            Self::Other(_) => Self::Other(message),
+        }
+    }
+
+    /// Exit code of the CLI for this kind of error: 1 for other errors, then
+    /// 2 to 7 in the order of the variants.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Other(_) => 1,
+            Self::InvalidInput(_) => 2,
+            Self::Io(_) => 3,
+            Self::Format(_) => 4,
+            Self::Capacity(_) => 5,
+            Self::NoMessage(_) => 6,
+            Self::Crypto(_) => 7,
+        }
+    }
+}
+
+/// For callers that still report errors as plain messages
+impl From<LowkeyError> for String {
+    fn from(error: LowkeyError) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_message_keeps_kind() {
+        let error = LowkeyError::Capacity("Message is too long".to_string())
+            .map_message(|message| format!("Failed to encode message: {}", message));
+        assert_eq!(
+            error,
+            LowkeyError::Capacity("Failed to encode message: Message is too long".to_string())
+        );
+        assert_eq!(error.exit_code(), 5);
+        assert_eq!(
+            String::from(error),
+            "Failed to encode message: Message is too long"
+        );
+        assert_eq!(LowkeyError::Other("x".to_string()).exit_code(), 1);
+    }
+}
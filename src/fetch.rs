@@ -0,0 +1,226 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{crypto, storage};
+
+/// Largest cover image that will be downloaded (50 MiB)
+const MAX_DOWNLOAD_BYTES: u64 = 50 * 1024 * 1024;
+
+pub fn is_url(path: &str) -> bool {
+    let path_lower = path.to_lowercase();
+    path_lower.starts_with("https://") || path_lower.starts_with("http://")
+}
+
+/// Temporary directory holding downloaded cover images or outputs waiting to be uploaded.
+///
+/// The directory and everything in it is removed when this value is dropped,
+/// so it must outlive any use of the local paths inside it.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Create a new directory only this user can enter, under an
+    /// unguessable name.
+    ///
+    /// The temporary directory is shared, so a directory that already exists
+    /// (planted by someone else, or a link) is an error rather than reused.
+    pub fn create(label: &str) -> Result<Self, String> {
+        let suffix: String = crypto::random_bytes(8)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let path = std::env::temp_dir().join(format!("lowkey-{}-{}", label, suffix));
+
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(&path)
+            .map_err(|e| format!("Failed to create temporary directory: {}", e))?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Replace every URL or object-storage URI in `paths` with the path of a
+/// downloaded local copy.
+///
+/// Local paths are passed through unchanged. Returns `None` for the download
+/// directory when nothing had to be downloaded.
+pub fn fetch_images(paths: Vec<String>) -> Result<(Vec<String>, Option<ScratchDir>), String> {
+    if !paths
+        .iter()
+        .any(|path| is_url(path) || storage::is_object_uri(path))
+    {
+        return Ok((paths, None));
+    }
+
+    let download_dir = ScratchDir::create("download")?;
+    let local_paths = paths
+        .into_iter()
+        .enumerate()
+        .map(|(i, path)| {
+            // Each download gets its own subdirectory so files keep their
+            // original names (used to name multi-image outputs) without colliding.
+            let dir = download_dir.path.join(i.to_string());
+            if is_url(&path) {
+                download_image(&path, &dir)
+            } else if storage::is_object_uri(&path) {
+                download_object(&path, &dir)
+            } else {
+                Ok(path)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((local_paths, Some(download_dir)))
+}
+
+fn download_image(url: &str, dir: &Path) -> Result<String, String> {
+    if !url.to_lowercase().starts_with("https://") {
+        return Err(format!(
+            "Refusing to download '{}': only HTTPS URLs are supported",
+            url
+        ));
+    }
+
+    eprintln!("Downloading cover image {}", url);
+
+    // A redirect to plain HTTP would undo the check above
+    let mut response = ureq::get(url)
+        .config()
+        .https_only(true)
+        .build()
+        .call()
+        .map_err(|e| format!("Failed to download '{}': {}", url, e))?;
+
+    let content_type = response
+        .body()
+        .mime_type()
+        .unwrap_or_default()
+        .to_lowercase();
+    if !content_type.starts_with("image/") {
+        return Err(format!(
+            "Refusing to use '{}' as a cover image: content type is '{}', expected image/*",
+            url, content_type
+        ));
+    }
+
+    if let Some(length) = response.body().content_length()
+        && length > MAX_DOWNLOAD_BYTES
+    {
+        return Err(format!(
+            "Refusing to download '{}': {} bytes exceeds the {} byte limit",
+            url, length, MAX_DOWNLOAD_BYTES
+        ));
+    }
+
+    let bytes = response
+        .body_mut()
+        .with_config()
+        .limit(MAX_DOWNLOAD_BYTES)
+        .read_to_vec()
+        .map_err(|e| format!("Failed to download '{}': {}", url, e))?;
+
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create download directory: {}", e))?;
+    let local_path = dir.join(file_name_from_url(url, &content_type));
+    fs::write(&local_path, bytes)
+        .map_err(|e| format!("Failed to save downloaded image '{}': {}", url, e))?;
+
+    Ok(local_path.to_string_lossy().to_string())
+}
+
+fn download_object(uri: &str, dir: &Path) -> Result<String, String> {
+    eprintln!("Downloading cover image {}", uri);
+
+    let bytes = storage::get(uri)?;
+
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create download directory: {}", e))?;
+    let local_path = dir.join(storage::object_file_name(uri)?);
+    fs::write(&local_path, bytes)
+        .map_err(|e| format!("Failed to save downloaded image '{}': {}", uri, e))?;
+
+    Ok(local_path.to_string_lossy().to_string())
+}
+
+/// Pick a local file name for a downloaded image.
+///
+/// The last path segment of the URL is used when it looks like a file name,
+/// otherwise a name is made up from the content type.
+fn file_name_from_url(url: &str, content_type: &str) -> String {
+    let path = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    let segment = path.rsplit('/').next().unwrap_or_default();
+
+    if segment.contains('.') && path.matches('/').count() > 2 {
+        return segment.to_string();
+    }
+
+    let ext = match content_type {
+        "image/jpeg" => "jpg",
+        "image/bmp" => "bmp",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/tiff" => "tiff",
+        _ => "png",
+    };
+    format!("image.{}", ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_url() {
+        assert!(is_url("https://cdn.example.com/cover.png"));
+        assert!(is_url("HTTP://cdn.example.com/cover.png"));
+        assert!(!is_url("images/cover.png"));
+    }
+
+    #[test]
+    fn test_scratch_dir() {
+        let first = ScratchDir::create("test").unwrap();
+        let second = ScratchDir::create("test").unwrap();
+        assert_ne!(first.path(), second.path());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(first.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_file_name_from_url() {
+        assert_eq!(
+            file_name_from_url("https://cdn.example.com/a/cover.jpg?w=800", "image/jpeg"),
+            "cover.jpg"
+        );
+        assert_eq!(
+            file_name_from_url("https://cdn.example.com/render/", "image/jpeg"),
+            "image.jpg"
+        );
+        assert_eq!(
+            file_name_from_url("https://cdn.example.com", "image/png"),
+            "image.png"
+        );
+    }
+}
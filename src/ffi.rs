@@ -0,0 +1,339 @@
+// C ABI for mobile apps, declared in include/lowkey.h. Images and messages are
+// passed as byte buffers or file descriptors, so photos never have to be
+// copied to a path lowkey can open.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+
+use crate::img::codec::{decode_from_memory, encode_to_memory, message_capacity_from_memory};
+
+/// Status codes returned by every function; see `lowkey_last_error` after an error
+pub const LOWKEY_OK: i32 = 0;
+pub const LOWKEY_ERROR: i32 = -1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Bytes allocated by lowkey. Must be released with `lowkey_buffer_free`.
+#[repr(C)]
+pub struct LowkeyBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl LowkeyBuffer {
+    fn from_vec(bytes: Vec<u8>) -> Self {
+        let mut bytes = bytes.into_boxed_slice();
+        let buffer = Self {
+            data: bytes.as_mut_ptr(),
+            len: bytes.len(),
+        };
+        std::mem::forget(bytes);
+        buffer
+    }
+}
+
+/// Hide `message` in the cover image and store the resulting PNG in `out`.
+///
+/// # Safety
+/// `cover` and `message` must point to `cover_len` and `message_len` readable
+/// bytes, `key` must be a NUL-terminated UTF-8 string and `out` must be valid
+/// for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lowkey_encode(
+    cover: *const u8,
+    cover_len: usize,
+    message: *const u8,
+    message_len: usize,
+    key: *const c_char,
+    out: *mut LowkeyBuffer,
+) -> i32 {
+    run(|| {
+        // SAFETY: guaranteed by the caller
+        let (cover, message, key) = unsafe {
+            (
+                bytes(cover, cover_len)?,
+                bytes(message, message_len)?,
+                key_str(key)?,
+            )
+        };
+        let png = encode_to_memory(cover, message, key)?;
+        // SAFETY: guaranteed by the caller
+        unsafe { write_out(out, LowkeyBuffer::from_vec(png)) }
+    })
+}
+
+/// Recover the message hidden in the image and store it in `out`.
+///
+/// # Safety
+/// `image` must point to `image_len` readable bytes, `key` must be a
+/// NUL-terminated UTF-8 string and `out` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lowkey_decode(
+    image: *const u8,
+    image_len: usize,
+    key: *const c_char,
+    out: *mut LowkeyBuffer,
+) -> i32 {
+    run(|| {
+        // SAFETY: guaranteed by the caller
+        let (image, key) = unsafe { (bytes(image, image_len)?, key_str(key)?) };
+        let message_bytes = decode_from_memory(image, key)?;
+        // SAFETY: guaranteed by the caller
+        unsafe { write_out(out, LowkeyBuffer::from_vec(message_bytes)) }
+    })
+}
+
+/// Store the largest message size, in bytes, that fits into the cover image in `out`.
+///
+/// # Safety
+/// `cover` must point to `cover_len` readable bytes and `out` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lowkey_capacity(
+    cover: *const u8,
+    cover_len: usize,
+    out: *mut usize,
+) -> i32 {
+    run(|| {
+        // SAFETY: guaranteed by the caller
+        let cover = unsafe { bytes(cover, cover_len)? };
+        let capacity = message_capacity_from_memory(cover)?;
+        // SAFETY: guaranteed by the caller
+        unsafe { write_out(out, capacity) }
+    })
+}
+
+/// Like `lowkey_encode`, but reads the cover and message from file descriptors
+/// and writes the PNG to `output_fd`.
+///
+/// The descriptors stay owned by the caller and are not closed.
+///
+/// # Safety
+/// The descriptors must be open for the duration of the call and `key` must
+/// be a NUL-terminated UTF-8 string.
+#[cfg(unix)]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lowkey_encode_fd(
+    cover_fd: i32,
+    message_fd: i32,
+    output_fd: i32,
+    key: *const c_char,
+) -> i32 {
+    run(|| {
+        // SAFETY: guaranteed by the caller
+        let (cover, message, key) = unsafe {
+            (
+                fd::read_all(cover_fd)?,
+                fd::read_all(message_fd)?,
+                key_str(key)?,
+            )
+        };
+        let png = encode_to_memory(&cover, &message, key)?;
+        // SAFETY: guaranteed by the caller
+        unsafe { fd::write_all(output_fd, &png) }
+    })
+}
+
+/// Like `lowkey_decode`, but reads the image from `image_fd` and writes the
+/// message to `output_fd`.
+///
+/// The descriptors stay owned by the caller and are not closed.
+///
+/// # Safety
+/// The descriptors must be open for the duration of the call and `key` must
+/// be a NUL-terminated UTF-8 string.
+#[cfg(unix)]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lowkey_decode_fd(
+    image_fd: i32,
+    output_fd: i32,
+    key: *const c_char,
+) -> i32 {
+    run(|| {
+        // SAFETY: guaranteed by the caller
+        let (image, key) = unsafe { (fd::read_all(image_fd)?, key_str(key)?) };
+        let message_bytes = decode_from_memory(&image, key)?;
+        // SAFETY: guaranteed by the caller
+        unsafe { fd::write_all(output_fd, &message_bytes) }
+    })
+}
+
+/// Release a buffer returned by lowkey. Passing an empty buffer is a no-op.
+///
+/// # Safety
+/// `buffer` must have been returned by lowkey and not freed before.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lowkey_buffer_free(buffer: LowkeyBuffer) {
+    if buffer.data.is_null() {
+        return;
+    }
+    // SAFETY: the buffer was created by `LowkeyBuffer::from_vec` from a boxed slice
+    drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(buffer.data, buffer.len)) });
+}
+
+/// Message describing the last error on this thread, or NULL if there was none.
+///
+/// The string is valid until the next lowkey call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn lowkey_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Run `f`, translating its result into a status code and recording any error.
+fn run(f: impl FnOnce() -> Result<(), String>) -> i32 {
+    // Panics must not unwind across the C boundary
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err("Internal error".to_string()));
+
+    let (status, error) = match result {
+        Ok(()) => (LOWKEY_OK, None),
+        Err(e) => (
+            LOWKEY_ERROR,
+            Some(CString::new(e.replace('\0', "")).unwrap_or_default()),
+        ),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+    status
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Result<&'a [u8], String> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if data.is_null() {
+        return Err("Buffer pointer is NULL".to_string());
+    }
+    // SAFETY: guaranteed by the caller
+    Ok(unsafe { std::slice::from_raw_parts(data, len) })
+}
+
+unsafe fn key_str<'a>(key: *const c_char) -> Result<&'a str, String> {
+    if key.is_null() {
+        return Err("Key is NULL".to_string());
+    }
+    // SAFETY: guaranteed by the caller
+    let key = unsafe { CStr::from_ptr(key) }
+        .to_str()
+        .map_err(|_| "Key is not valid UTF-8".to_string())?;
+    if key.is_empty() {
+        return Err("Encryption key cannot be empty".to_string());
+    }
+    Ok(key)
+}
+
+unsafe fn write_out<T>(out: *mut T, value: T) -> Result<(), String> {
+    if out.is_null() {
+        return Err("Output pointer is NULL".to_string());
+    }
+    // SAFETY: guaranteed by the caller
+    unsafe { out.write(value) };
+    Ok(())
+}
+
+#[cfg(unix)]
+mod fd {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd;
+
+    /// Borrow `fd` as a `File` without taking ownership of it.
+    unsafe fn borrow(fd: i32) -> Result<ManuallyDrop<File>, String> {
+        if fd < 0 {
+            return Err(format!("Invalid file descriptor {}", fd));
+        }
+        // SAFETY: guaranteed by the caller; ManuallyDrop keeps the fd open
+        Ok(ManuallyDrop::new(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    pub unsafe fn read_all(fd: i32) -> Result<Vec<u8>, String> {
+        // SAFETY: guaranteed by the caller
+        let mut file = unsafe { borrow(fd)? };
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .map_err(|e| format!("Failed to read file descriptor {}: {}", fd, e))?;
+        Ok(bytes)
+    }
+
+    pub unsafe fn write_all(fd: i32, bytes: &[u8]) -> Result<(), String> {
+        // SAFETY: guaranteed by the caller
+        let mut file = unsafe { borrow(fd)? };
+        file.write_all(bytes)
+            .and_then(|_| file.flush())
+            .map_err(|e| format!("Failed to write file descriptor {}: {}", fd, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbaImage};
+    use std::io::Cursor;
+
+    fn cover_png() -> Vec<u8> {
+        let mut png = Vec::new();
+        RgbaImage::from_pixel(32, 32, image::Rgba([120, 80, 200, 255]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let cover = cover_png();
+        let message = b"hidden on a phone";
+        let key = c"ffi key";
+
+        let mut stego = LowkeyBuffer {
+            data: std::ptr::null_mut(),
+            len: 0,
+        };
+        let mut decoded = LowkeyBuffer {
+            data: std::ptr::null_mut(),
+            len: 0,
+        };
+        unsafe {
+            assert_eq!(
+                lowkey_encode(
+                    cover.as_ptr(),
+                    cover.len(),
+                    message.as_ptr(),
+                    message.len(),
+                    key.as_ptr(),
+                    &mut stego,
+                ),
+                LOWKEY_OK
+            );
+            assert_eq!(
+                lowkey_decode(stego.data, stego.len, key.as_ptr(), &mut decoded),
+                LOWKEY_OK
+            );
+            assert_eq!(
+                std::slice::from_raw_parts(decoded.data, decoded.len),
+                message
+            );
+            lowkey_buffer_free(stego);
+            lowkey_buffer_free(decoded);
+        }
+    }
+
+    #[test]
+    fn test_error_is_reported() {
+        let mut capacity = 0;
+        let status = unsafe { lowkey_capacity(b"not an image".as_ptr(), 12, &mut capacity) };
+
+        assert_eq!(status, LOWKEY_ERROR);
+        let message = unsafe { CStr::from_ptr(lowkey_last_error()) };
+        assert!(
+            message
+                .to_str()
+                .unwrap()
+                .starts_with("Failed to read image")
+        );
+    }
+}
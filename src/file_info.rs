@@ -0,0 +1,255 @@
+// Name, MIME type and modification time of a hidden file, carried in front of
+// its contents inside the encrypted message so that decode can restore it.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::error::LowkeyError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    /// File name without any directories
+    pub name: String,
+    pub mime: String,
+    /// Modification time in seconds since the Unix epoch, if known
+    pub modified: Option<u64>,
+}
+
+impl FileInfo {
+    /// Name, MIME type (guessed from the extension) and modification time of
+    /// the file at `path`.
+    pub fn from_path(path: &str) -> Result<Self, LowkeyError> {
+        let name = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| LowkeyError::InvalidInput(format!("Invalid file name in '{}'", path)))?
+            .to_string();
+        let metadata = fs::metadata(path)
+            .map_err(|e| LowkeyError::Io(format!("Failed to read file metadata: {}", e)))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs());
+        let mime = mime_guess::from_path(&name)
+            .first_or_octet_stream()
+            .to_string();
+
+        Ok(Self {
+            name,
+            mime,
+            modified,
+        })
+    }
+
+    /// `content` with the file info in front: [2 bytes name length] + [name] +
+    /// [1 byte MIME type length] + [MIME type] + [8 bytes modification time,
+    /// 0 if unknown], all big-endian.
+    pub(crate) fn prepend(&self, content: &[u8]) -> Result<Vec<u8>, LowkeyError> {
+        let name_len = u16::try_from(self.name.len()).map_err(|_| {
+            LowkeyError::InvalidInput(format!("File name is too long: {} bytes", self.name.len()))
+        })?;
+        let mime_len = u8::try_from(self.mime.len()).map_err(|_| {
+            LowkeyError::InvalidInput(format!("MIME type is too long: {} bytes", self.mime.len()))
+        })?;
+
+        let mut bytes = Vec::with_capacity(11 + self.name.len() + self.mime.len() + content.len());
+        bytes.extend_from_slice(&name_len.to_be_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.push(mime_len);
+        bytes.extend_from_slice(self.mime.as_bytes());
+        bytes.extend_from_slice(&self.modified.unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(content);
+        Ok(bytes)
+    }
+
+    /// The file info from the front of `bytes`, and the content after it.
+    pub(crate) fn split(bytes: &[u8]) -> Result<(Self, &[u8]), LowkeyError> {
+        let text = |bytes: &[u8]| {
+            String::from_utf8(bytes.to_vec()).map_err(|_| {
+                LowkeyError::Format("File info in the message is not UTF-8".to_string())
+            })
+        };
+
+        let (name_len, rest) = take(bytes, 2)?;
+        let (name, rest) = take(
+            rest,
+            u16::from_be_bytes([name_len[0], name_len[1]]) as usize,
+        )?;
+        let (mime_len, rest) = take(rest, 1)?;
+        let (mime, rest) = take(rest, mime_len[0] as usize)?;
+        let (modified, content) = take(rest, 8)?;
+
+        let name = text(name)?;
+        check_name(&name)?;
+        let modified = u64::from_be_bytes(modified.try_into().unwrap());
+        Ok((
+            Self {
+                name,
+                mime: text(mime)?,
+                modified: (modified != 0).then_some(modified),
+            },
+            content,
+        ))
+    }
+
+    /// Write `content` to `dir` under the file's name, with its modification
+    /// time, and return the path written. A file of that name already there
+    /// is dealt with as `on_conflict` says.
+    pub fn restore(
+        &self,
+        dir: &str,
+        content: &[u8],
+        on_conflict: OnConflict,
+    ) -> Result<PathBuf, LowkeyError> {
+        check_name(&self.name)?;
+        fs::create_dir_all(dir)
+            .map_err(|e| LowkeyError::Io(format!("Failed to create output directory: {}", e)))?;
+
+        let path = Path::new(dir).join(&self.name);
+        let path = match on_conflict {
+            _ if !path.exists() => path,
+            OnConflict::Overwrite => path,
+            OnConflict::Rename => free_path(&path),
+            OnConflict::Fail => {
+                return Err(LowkeyError::Io(format!(
+                    "'{}' already exists (use --on-conflict rename or overwrite)",
+                    path.display()
+                )));
+            }
+        };
+        fs::write(&path, content)
+            .map_err(|e| LowkeyError::Io(format!("Failed to write file: {}", e)))?;
+        if let Some(modified) = self.modified {
+            File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_secs(modified)))
+                .map_err(|e| LowkeyError::Io(format!("Failed to set modification time: {}", e)))?;
+        }
+        Ok(path)
+    }
+}
+
+/// What `restore` does when the output directory already has a file of the
+/// restored name
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Write "name (1).ext", or the first such name that is free
+    #[default]
+    Rename,
+    /// Replace the existing file
+    Overwrite,
+    /// Leave the existing file and fail
+    Fail,
+}
+
+impl std::str::FromStr for OnConflict {
+    type Err = LowkeyError;
+
+    fn from_str(name: &str) -> Result<Self, LowkeyError> {
+        match name {
+            "rename" => Ok(Self::Rename),
+            "overwrite" => Ok(Self::Overwrite),
+            "fail" => Ok(Self::Fail),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown conflict mode '{}' (expected rename, overwrite or fail)",
+                name
+            ))),
+        }
+    }
+}
+
+/// `path` with " (1)", " (2)", ... added to its stem, whichever is first free.
+fn free_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// The first `len` bytes and the rest.
+fn take(bytes: &[u8], len: usize) -> Result<(&[u8], &[u8]), LowkeyError> {
+    bytes
+        .split_at_checked(len)
+        .ok_or_else(|| LowkeyError::Format("File info in the message is truncated".to_string()))
+}
+
+/// The name comes from the message, so it must not lead out of the output directory.
+pub(crate) fn check_name(name: &str) -> Result<(), LowkeyError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(LowkeyError::Format(format!(
+            "Unsafe file name '{}' in the message",
+            name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepend_split() {
+        let info = FileInfo {
+            name: "report.pdf".to_string(),
+            mime: "application/pdf".to_string(),
+            modified: Some(1_700_000_000),
+        };
+        let bytes = info.prepend(b"%PDF-1.7").unwrap();
+        assert_eq!(FileInfo::split(&bytes).unwrap(), (info, &b"%PDF-1.7"[..]));
+
+        assert!(FileInfo::split(&bytes[..5]).is_err());
+    }
+
+    #[test]
+    fn test_unsafe_names() {
+        for name in ["", "..", "../etc/passwd", "dir/file", "c:\\file"] {
+            let info = FileInfo {
+                name: name.to_string(),
+                mime: "text/plain".to_string(),
+                modified: None,
+            };
+            let bytes = info.prepend(b"").unwrap();
+            assert!(FileInfo::split(&bytes).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_restore_conflicts() {
+        let dir = std::env::temp_dir().join(format!("lowkey-restore-{}", std::process::id()));
+        let dir_name = dir.to_str().unwrap();
+        let info = FileInfo {
+            name: "notes.txt".to_string(),
+            mime: "text/plain".to_string(),
+            modified: None,
+        };
+        let restore = |content: &[u8], on_conflict| info.restore(dir_name, content, on_conflict);
+
+        assert_eq!(
+            restore(b"one", OnConflict::Rename).unwrap(),
+            dir.join("notes.txt")
+        );
+        assert_eq!(
+            restore(b"two", OnConflict::Rename).unwrap(),
+            dir.join("notes (1).txt")
+        );
+        assert_eq!(
+            restore(b"three", OnConflict::Rename).unwrap(),
+            dir.join("notes (2).txt")
+        );
+        assert!(restore(b"four", OnConflict::Fail).is_err());
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), b"one");
+        restore(b"five", OnConflict::Overwrite).unwrap();
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), b"five");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+use std::io::{Read, Write};
+
+use crate::img::codec::{decode_from_memory, encode_deterministic};
+use lowkey::error::LowkeyError;
+
+/// Git clean filter: hide the working-tree file read from stdin in `cover`
+/// and write the stego PNG to stdout.
+///
+/// Output is deterministic, so git does not see unchanged files as modified.
+/// Input that already decodes with `key` is passed through unchanged, which
+/// keeps the filter idempotent.
+pub fn clean(cover: &str, key: &str) -> Result<(), LowkeyError> {
+    let input = read_stdin()?;
+
+    if decode_from_memory(&input, key).is_ok() {
+        return write_stdout(&input);
+    }
+
+    let mut output = Vec::new();
+    encode_deterministic(cover, &input, key, &mut output)?;
+    write_stdout(&output)
+}
+
+/// Git smudge filter: recover the file hidden in the stego PNG read from stdin
+/// and write it to stdout.
+///
+/// Blobs that are not lowkey images (e.g. committed before the filter was
+/// configured) or that do not decode with `key` are passed through unchanged,
+/// so a checkout without the key still succeeds.
+pub fn smudge(key: &str) -> Result<(), LowkeyError> {
+    let input = read_stdin()?;
+
+    match decode_from_memory(&input, key) {
+        Ok(message_bytes) => write_stdout(&message_bytes),
+        Err(e) => {
+            eprintln!("lowkey: leaving blob as is: {}", e);
+            write_stdout(&input)
+        }
+    }
+}
+
+fn read_stdin() -> Result<Vec<u8>, LowkeyError> {
+    let mut input = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut input)
+        .map_err(|e| LowkeyError::Io(format!("Failed to read stdin: {}", e)))?;
+    Ok(input)
+}
+
+fn write_stdout(bytes: &[u8]) -> Result<(), LowkeyError> {
+    let mut stdout = std::io::stdout();
+    stdout
+        .write_all(bytes)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| LowkeyError::Io(format!("Failed to write stdout: {}", e)))
+}
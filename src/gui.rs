@@ -0,0 +1,237 @@
+use eframe::egui;
+
+use crate::img::codec::{
+    Layout, PayloadOptions, SetOptions, decode_from_files, encode_from_file, encode_from_files,
+    message_capacity_from_files,
+};
+use crate::img::io::write_message_file;
+use crate::{DEFAULT_KEY, check_key};
+
+/// Open the desktop window and block until it is closed.
+pub fn run() -> Result<(), String> {
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_title("lowkey")
+            .with_inner_size([560.0, 520.0])
+            .with_drag_and_drop(true),
+        ..Default::default()
+    };
+
+    eframe::run_native(
+        "lowkey",
+        options,
+        Box::new(|_cc| Ok(Box::new(App::default()))),
+    )
+    .map_err(|e| format!("Failed to start GUI: {}", e))
+}
+
+struct App {
+    covers: Vec<String>,
+    /// Capacity of `covers` in message bytes, refreshed whenever the list changes
+    capacity: Option<Result<usize, String>>,
+    key: String,
+    message: String,
+    /// Output PNG for a single cover, output directory for several. Optional for decode.
+    output: String,
+    auto_resize: bool,
+    status: Result<String, String>,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self {
+            covers: Vec::new(),
+            capacity: None,
+            key: DEFAULT_KEY.to_string(),
+            message: String::new(),
+            output: String::new(),
+            auto_resize: false,
+            status: Ok("Drop cover images onto the window".to_string()),
+        }
+    }
+}
+
+impl App {
+    fn add_dropped_files(&mut self, ctx: &egui::Context) {
+        let dropped: Vec<String> = ctx.input(|i| {
+            i.raw
+                .dropped_files
+                .iter()
+                .map(|file| file.path().to_string_lossy().to_string())
+                .filter(|path| !path.is_empty())
+                .collect()
+        });
+
+        if !dropped.is_empty() {
+            self.covers.extend(dropped);
+            self.capacity = None;
+        }
+    }
+
+    fn capacity(&mut self) -> &Result<usize, String> {
+        let covers = &self.covers;
+        self.capacity
+            .get_or_insert_with(|| message_capacity_from_files(covers).map_err(String::from))
+    }
+
+    fn encode(&self) -> Result<String, String> {
+        check_key(&self.key)?;
+        let output = self.output.trim();
+        if output.is_empty() {
+            return Err("Choose an output path first".to_string());
+        }
+
+        match self.covers.as_slice() {
+            [] => Err("Drop at least one cover image first".to_string()),
+            [cover] => {
+                encode_from_file(
+                    cover,
+                    self.message.as_bytes(),
+                    output,
+                    self.auto_resize,
+                    Layout::default(),
+                    &self.key,
+                    &PayloadOptions::default(),
+                )?;
+                Ok(format!("Encoded message into {}", output))
+            }
+            covers => {
+                encode_from_files(
+                    covers,
+                    self.message.as_bytes(),
+                    output,
+                    &SetOptions {
+                        auto_resize: self.auto_resize,
+                        ..SetOptions::default()
+                    },
+                    &self.key,
+                    &PayloadOptions::default(),
+                )?;
+                Ok(format!("Encoded message into output directory {}", output))
+            }
+        }
+    }
+
+    fn decode(&mut self) -> Result<String, String> {
+        check_key(&self.key)?;
+        if self.covers.is_empty() {
+            return Err("Drop at least one cover image first".to_string());
+        }
+
+        let message_bytes = decode_from_files(&self.covers, &self.key)?;
+
+        let output = self.output.trim();
+        if !output.is_empty() {
+            write_message_file(output, &message_bytes)?;
+            return Ok(format!("Successfully decoded message to {}", output));
+        }
+
+        self.message = String::from_utf8(message_bytes).map_err(|_| {
+            "Decoded message is not UTF-8 text (set an output path to save it to a file)"
+                .to_string()
+        })?;
+        Ok("Successfully decoded message".to_string())
+    }
+
+    fn covers_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.heading("Cover images");
+            if ui.button("Clear").clicked() {
+                self.covers.clear();
+                self.capacity = None;
+            }
+        });
+
+        if self.covers.is_empty() {
+            ui.weak("Drag and drop PNG images here. Order matters when hiding across several.");
+        }
+
+        let mut removed = None;
+        for (i, cover) in self.covers.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.small_button("✖").clicked() {
+                    removed = Some(i);
+                }
+                ui.label(cover);
+            });
+        }
+        if let Some(i) = removed {
+            self.covers.remove(i);
+            self.capacity = None;
+        }
+    }
+
+    fn capacity_ui(&mut self, ui: &mut egui::Ui) {
+        if self.covers.is_empty() {
+            return;
+        }
+
+        let message_len = self.message.len();
+        match self.capacity() {
+            Ok(capacity) => {
+                let capacity = *capacity;
+                let fill = if capacity == 0 {
+                    1.0
+                } else {
+                    message_len as f32 / capacity as f32
+                };
+                let mut bar = egui::ProgressBar::new(fill.min(1.0))
+                    .text(format!("{} / {} bytes", message_len, capacity));
+                if message_len > capacity {
+                    bar = bar.fill(ui.visuals().error_fg_color);
+                }
+                ui.add(bar);
+            }
+            Err(e) => {
+                let e = e.clone();
+                ui.colored_label(ui.visuals().error_fg_color, e);
+            }
+        }
+    }
+}
+
+impl eframe::App for App {
+    fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+        self.add_dropped_files(ui.ctx());
+
+        egui::CentralPanel::default().show(ui, |ui| {
+            self.covers_ui(ui);
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                ui.label("Passphrase");
+                ui.add(egui::TextEdit::singleline(&mut self.key).password(true));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Output");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.output)
+                        .hint_text("PNG file, or directory for several covers"),
+                );
+            });
+            ui.checkbox(&mut self.auto_resize, "Auto-resize the covers to fit");
+
+            ui.label("Message");
+            ui.add(
+                egui::TextEdit::multiline(&mut self.message)
+                    .desired_rows(8)
+                    .desired_width(f32::INFINITY),
+            );
+            self.capacity_ui(ui);
+
+            ui.horizontal(|ui| {
+                if ui.button("Encode").clicked() {
+                    self.status = self.encode();
+                }
+                if ui.button("Decode").clicked() {
+                    self.status = self.decode();
+                }
+            });
+
+            match &self.status {
+                Ok(status) => ui.label(status),
+                Err(e) => ui.colored_label(ui.visuals().error_fg_color, e),
+            };
+        });
+    }
+}
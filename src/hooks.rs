@@ -0,0 +1,34 @@
+use std::process::{Command, Stdio};
+
+/// Run a user-configured hook command through the shell.
+///
+/// `vars` are added to the command's environment, so hooks can refer to
+/// e.g. `$LOWKEY_OUTPUT` instead of lowkey splicing paths into the command.
+/// The hook inherits stdout and stderr; a non-zero exit status is an error.
+pub fn run(name: &str, command: &str, vars: &[(&str, String)]) -> Result<(), String> {
+    let status = shell(command)
+        .envs(vars.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| format!("Failed to run {} command: {}", name, e))?;
+
+    if !status.success() {
+        return Err(format!("{} command failed ({})", name, status));
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
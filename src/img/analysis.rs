@@ -0,0 +1,1187 @@
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+use serde::Serialize;
+
+use super::codec::Layout;
+use super::pixel::Method;
+use crate::error::LowkeyError;
+
+/// A prefix is considered embedded when its chi-square p-value exceeds this
+const DETECTION_P_VALUE: f64 = 0.95;
+
+/// Prefixes with fewer channel values than this are too small to judge
+const MIN_SAMPLES: usize = 1024;
+
+/// Value pairs expected less often than this are left out of the statistic
+const MIN_EXPECTED: f64 = 5.0;
+
+/// Embedding rates RS analysis reports below this are within its usual error
+const RS_DETECTION_RATE: f64 = 0.05;
+
+/// Flipping mask applied to each group of pixels in RS analysis
+const RS_MASK: [i16; 4] = [0, 1, 1, 0];
+
+/// Embedding rates sample-pairs analysis reports below this are within its usual error
+const SPA_DETECTION_RATE: f64 = 0.05;
+
+/// Detectability scores below this are reported as low, below
+/// `HIGH_DETECTABILITY` as moderate
+const MODERATE_DETECTABILITY: f64 = 25.0;
+const HIGH_DETECTABILITY: f64 = 50.0;
+
+/// Fill ratios above this get a recommendation to spread the message out
+const HIGH_FILL_RATIO: f64 = 0.25;
+
+/// LSB planes this much more random than the plane above them stand out
+const HIGH_PLANE_CONTRAST: f64 = 0.1;
+
+/// Result of the chi-square attack (Westfeld & Pfitzmann) on LSB replacement.
+///
+/// Replacing LSBs with message bits evens out the counts of each pair of
+/// values `2k` and `2k + 1`. The p-value is the probability that counts at
+/// least this even arise from LSB replacement, so values close to 1 mean
+/// the image looks embedded.
+pub struct ChiSquare {
+    /// Statistic over the whole image
+    pub statistic: f64,
+    /// p-value over the whole image
+    pub p_value: f64,
+    /// p-values over the first 1%, 2%, ..., 100% of channel values, in the
+    /// order they are embedded in
+    pub prefix_p_values: Vec<f64>,
+    /// Share of the image, from the start, that looks embedded (0.0 to 1.0)
+    pub embedded_fraction: f64,
+}
+
+impl ChiSquare {
+    /// Plain-language reading of the result.
+    pub fn interpretation(&self) -> String {
+        if self.embedded_fraction == 0.0 {
+            "No sign of LSB embedding; a chi-square attack would not flag this image.".to_string()
+        } else if self.embedded_fraction >= 0.99 {
+            "Strong sign of LSB embedding across the whole image; a chi-square attack would flag it."
+                .to_string()
+        } else {
+            format!(
+                "Strong sign of LSB embedding in the first {:.0}% of the image; a chi-square attack would flag it. \
+                 A smaller message shrinks the flagged region but does not remove it.",
+                self.embedded_fraction * 100.0
+            )
+        }
+    }
+}
+
+/// Run the chi-square attack on an image.
+///
+/// Messages are embedded from the first channel value onwards, so the test is
+/// repeated on growing prefixes to estimate how much of the image is used.
+pub fn chi_square(img: &RgbaImage) -> ChiSquare {
+    let values = img.as_raw();
+    let mut histogram = [0u64; 256];
+    let mut prefix_p_values = Vec::with_capacity(100);
+    let mut counted = 0;
+
+    for percent in 1..=100 {
+        let end = values.len() * percent / 100;
+        for &value in &values[counted..end] {
+            histogram[value as usize] += 1;
+        }
+        counted = end;
+
+        let p_value = if end < MIN_SAMPLES {
+            0.0
+        } else {
+            pairs_test(&histogram).1
+        };
+        prefix_p_values.push(p_value);
+    }
+
+    // Embedding starts at the beginning, so only the leading run of detected
+    // prefixes counts; later chance hits are noise
+    let embedded_percent = prefix_p_values
+        .iter()
+        .enumerate()
+        .skip_while(|&(i, _)| values.len() * (i + 1) / 100 < MIN_SAMPLES)
+        .take_while(|&(_, &p)| p > DETECTION_P_VALUE)
+        .last()
+        .map_or(0, |(i, _)| i + 1);
+
+    let (statistic, p_value) = pairs_test(&histogram);
+
+    ChiSquare {
+        statistic,
+        p_value,
+        prefix_p_values,
+        embedded_fraction: embedded_percent as f64 / 100.0,
+    }
+}
+
+/// Result of RS (regular/singular groups) analysis (Fridrich, Goljan & Du).
+///
+/// LSB replacement makes flipping LSBs less likely to roughen groups of
+/// neighbouring pixels than flipping them the other way round. Comparing both
+/// on the image and on its LSB-inverted copy gives an estimate of the share of
+/// channel values that carry message bits.
+pub struct RsAnalysis {
+    /// Estimated embedding rate of the red, green and blue channels (0.0 to 1.0)
+    pub channel_rates: [f64; 3],
+    /// Mean of the channel rates
+    pub embedding_rate: f64,
+    /// Estimated length of the embedded data at one bit per value, in bytes
+    pub estimated_bytes: u64,
+}
+
+impl RsAnalysis {
+    /// Plain-language reading of the result, with a recommendation.
+    pub fn interpretation(&self) -> String {
+        if self.embedding_rate < RS_DETECTION_RATE {
+            "No measurable LSB replacement; RS analysis would not flag this image.".to_string()
+        } else {
+            format!(
+                "About {:.0}% of the image carries data according to RS analysis, which reliably measures LSB replacement. \
+                 Consider LSB matching (±1 embedding), which RS analysis cannot measure, or a lower fill ratio (a larger cover or more images).",
+                self.embedding_rate * 100.0
+            )
+        }
+    }
+}
+
+/// Run RS analysis on the red, green and blue channels of an image.
+///
+/// The alpha channel is left out: it is usually flat, which gives RS
+/// analysis nothing to measure.
+pub fn rs_analysis(img: &RgbaImage) -> RsAnalysis {
+    let channel_rates = std::array::from_fn(|channel| rs_rate(rs_counts(img, channel)));
+    RsAnalysis {
+        channel_rates,
+        embedding_rate: channel_rates.iter().sum::<f64>() / 3.0,
+        estimated_bytes: estimated_bytes(img, &channel_rates),
+    }
+}
+
+/// Solve the RS equation for the embedding rate, given the group shares of
+/// the image and of its LSB-inverted copy.
+fn rs_rate(counts: [(f64, f64, f64, f64); 2]) -> f64 {
+    let [
+        (r_m, s_m, r_neg, s_neg),
+        (r_m_inv, s_m_inv, r_neg_inv, s_neg_inv),
+    ] = counts;
+
+    let d0 = r_m - s_m;
+    let d1 = r_m_inv - s_m_inv;
+    let d_neg0 = r_neg - s_neg;
+    let d_neg1 = r_neg_inv - s_neg_inv;
+
+    // 2(d1 + d0)z^2 + (d-0 - d-1 - d1 - 3d0)z + d0 - d-0 = 0
+    let a = 2.0 * (d1 + d0);
+    let b = d_neg0 - d_neg1 - d1 - 3.0 * d0;
+    let c = d0 - d_neg0;
+
+    let rate = |z: f64| z / (z - 0.5);
+    if a.abs() < f64::EPSILON {
+        if b.abs() < f64::EPSILON {
+            return 0.0;
+        }
+        return rate(-c / b).clamp(0.0, 1.0);
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    let (near, far) = if discriminant < 0.0 {
+        // Noise gave complex roots: use their modulus with the sign of their
+        // real part. That is the vertex close to a double root, and tends to
+        // infinity (a rate of 1) when the image is fully embedded.
+        let z = (c / a).sqrt().copysign(-b / a);
+        (z, z)
+    } else {
+        let root1 = (-b + discriminant.sqrt()) / (2.0 * a);
+        let root2 = (-b - discriminant.sqrt()) / (2.0 * a);
+        if root1.abs() < root2.abs() {
+            (root1, root2)
+        } else {
+            (root2, root1)
+        }
+    };
+
+    // The root closer to zero is the estimate, unless it gives no valid rate.
+    // That happens close to full embedding, where the roots grow large.
+    let valid = |rate: f64| (-0.05..=1.05).contains(&rate);
+    if !valid(rate(near)) && valid(rate(far)) {
+        rate(far).clamp(0.0, 1.0)
+    } else {
+        rate(near).clamp(0.0, 1.0)
+    }
+}
+
+/// Shares of regular and singular groups under the mask and its negation,
+/// as (R_M, S_M, R_-M, S_-M), in the image and in its LSB-inverted copy.
+fn rs_counts(img: &RgbaImage, channel: usize) -> [(f64, f64, f64, f64); 2] {
+    [false, true].map(|invert| rs_counts_inverted(img, channel, invert))
+}
+
+fn rs_counts_inverted(img: &RgbaImage, channel: usize, invert: bool) -> (f64, f64, f64, f64) {
+    let (width, height) = img.dimensions();
+    let (mut r_m, mut s_m, mut r_neg, mut s_neg) = (0usize, 0usize, 0usize, 0usize);
+    let mut groups = 0usize;
+
+    for y in 0..height {
+        for x0 in (0..width.saturating_sub(3)).step_by(4) {
+            let group: [i16; 4] = std::array::from_fn(|i| {
+                let value = img.get_pixel(x0 + i as u32, y)[channel] as i16;
+                if invert { value ^ 1 } else { value }
+            });
+            let smoothness = rs_smoothness(&group);
+
+            let flipped = std::array::from_fn(|i| match RS_MASK[i] {
+                0 => group[i],
+                // F1: 0 <-> 1, 2 <-> 3, ...
+                _ => group[i] ^ 1,
+            });
+            let negated = std::array::from_fn(|i| match RS_MASK[i] {
+                0 => group[i],
+                // F-1: -1 <-> 0, 1 <-> 2, ...
+                _ => ((group[i] + 1) ^ 1) - 1,
+            });
+
+            match rs_smoothness(&flipped).cmp(&smoothness) {
+                std::cmp::Ordering::Greater => r_m += 1,
+                std::cmp::Ordering::Less => s_m += 1,
+                std::cmp::Ordering::Equal => {}
+            }
+            match rs_smoothness(&negated).cmp(&smoothness) {
+                std::cmp::Ordering::Greater => r_neg += 1,
+                std::cmp::Ordering::Less => s_neg += 1,
+                std::cmp::Ordering::Equal => {}
+            }
+            groups += 1;
+        }
+    }
+
+    if groups == 0 {
+        return (0.0, 0.0, 0.0, 0.0);
+    }
+    let share = |count: usize| count as f64 / groups as f64;
+    (share(r_m), share(s_m), share(r_neg), share(s_neg))
+}
+
+/// Discrimination function: how rough a group of neighbouring values is
+fn rs_smoothness(group: &[i16; 4]) -> i16 {
+    group.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum()
+}
+
+/// Result of sample-pairs analysis (Dumitrescu, Wu & Wang).
+///
+/// Looks at pairs of horizontally adjacent values and how LSB replacement
+/// moves them between sets defined by their order and parity, which gives
+/// an estimate of the share of values carrying message bits per channel.
+pub struct SamplePairs {
+    /// Estimated embedding rate of the red, green, blue and alpha channels (0.0 to 1.0)
+    pub channel_rates: [f64; 4],
+    /// Mean of the red, green and blue rates, as for RS analysis: an opaque
+    /// alpha channel has nothing to measure and would pull it down
+    pub embedding_rate: f64,
+    /// Estimated length of the embedded data at one bit per value, in bytes
+    pub estimated_bytes: u64,
+}
+
+impl SamplePairs {
+    /// Plain-language reading of the result.
+    pub fn interpretation(&self) -> String {
+        if self.embedding_rate < SPA_DETECTION_RATE {
+            "No measurable LSB replacement; sample-pairs analysis would not flag this image."
+                .to_string()
+        } else {
+            format!(
+                "About {:.0}% of the image carries data according to sample-pairs analysis.",
+                self.embedding_rate * 100.0
+            )
+        }
+    }
+}
+
+/// Run sample-pairs analysis on each channel of an image.
+pub fn sample_pairs(img: &RgbaImage) -> SamplePairs {
+    let channel_rates = std::array::from_fn(|channel| sample_pairs_rate(img, channel));
+    SamplePairs {
+        channel_rates,
+        embedding_rate: channel_rates[..3].iter().sum::<f64>() / 3.0,
+        estimated_bytes: estimated_bytes(img, &channel_rates),
+    }
+}
+
+/// Bytes that the share `channel_rates` of the values of each channel
+/// would hold at one bit per value.
+fn estimated_bytes(img: &RgbaImage, channel_rates: &[f64]) -> u64 {
+    let pixels = img.width() as f64 * img.height() as f64;
+    (channel_rates.iter().sum::<f64>() * pixels / 8.0).round() as u64
+}
+
+fn sample_pairs_rate(img: &RgbaImage, channel: usize) -> f64 {
+    let (width, height) = img.dimensions();
+    // X and Y: pairs ordered with / against the parity of the second value.
+    // Z and W: pairs that are equal / differ in the LSB only.
+    let (mut x, mut y, mut z_w, mut pairs) = (0usize, 0usize, 0usize, 0usize);
+
+    for row in 0..height {
+        for col in 0..width.saturating_sub(1) {
+            let u = img.get_pixel(col, row)[channel];
+            let v = img.get_pixel(col + 1, row)[channel];
+            let v_even = v.is_multiple_of(2);
+            if (v_even && u < v) || (!v_even && u > v) {
+                x += 1;
+            }
+            if (v_even && u > v) || (!v_even && u < v) {
+                y += 1;
+            }
+            if u / 2 == v / 2 {
+                z_w += 1;
+            }
+            pairs += 1;
+        }
+    }
+
+    if pairs == 0 {
+        return 0.0;
+    }
+
+    // (|W| + |Z|) / 2 * p^2 + (2|X| - |P|) * p + |Y| - |X| = 0, smaller root
+    let share = |count: usize| count as f64 / pairs as f64;
+    let a = share(z_w) / 2.0;
+    let b = 2.0 * share(x) - 1.0;
+    let c = share(y) - share(x);
+
+    let p = if a.abs() < f64::EPSILON {
+        if b.abs() < f64::EPSILON {
+            return 0.0;
+        }
+        -c / b
+    } else {
+        // Noise can push the discriminant slightly below zero near the
+        // double root; the vertex is the best estimate then
+        let discriminant = (b * b - 4.0 * a * c).max(0.0);
+        let root1 = (-b + discriminant.sqrt()) / (2.0 * a);
+        let root2 = (-b - discriminant.sqrt()) / (2.0 * a);
+        root1.min(root2)
+    };
+
+    p.clamp(0.0, 1.0)
+}
+
+/// How a stego image's statistics differ from its original cover, per channel.
+pub struct ChannelComparison {
+    /// Share of values that differ from the cover (0.0 to 1.0)
+    pub changed: f64,
+    /// Kullback-Leibler divergence of the stego histogram from the cover
+    /// histogram, in bits
+    pub kl_divergence: f64,
+    /// Pair asymmetry of the cover: sum |h(2k) - h(2k+1)| / sum (h(2k) + h(2k+1)).
+    /// LSB replacement pulls it towards 0.
+    pub cover_pair_asymmetry: f64,
+    /// Pair asymmetry of the stego image
+    pub stego_pair_asymmetry: f64,
+}
+
+/// Compare the histograms of a stego image with its original cover, for the
+/// red, green, blue and alpha channels.
+pub fn compare_histograms(
+    cover: &RgbaImage,
+    stego: &RgbaImage,
+) -> Result<[ChannelComparison; 4], LowkeyError> {
+    check_same_size(cover, stego)?;
+
+    Ok(std::array::from_fn(|channel| {
+        let mut cover_histogram = [0u64; 256];
+        let mut stego_histogram = [0u64; 256];
+        let mut changed = 0usize;
+        for (cover_pixel, stego_pixel) in cover.pixels().zip(stego.pixels()) {
+            let (c, s) = (cover_pixel[channel], stego_pixel[channel]);
+            cover_histogram[c as usize] += 1;
+            stego_histogram[s as usize] += 1;
+            if c != s {
+                changed += 1;
+            }
+        }
+
+        let values = (cover.width() as usize * cover.height() as usize).max(1);
+        ChannelComparison {
+            changed: changed as f64 / values as f64,
+            kl_divergence: kl_divergence(&stego_histogram, &cover_histogram),
+            cover_pair_asymmetry: pair_asymmetry(&cover_histogram),
+            stego_pair_asymmetry: pair_asymmetry(&stego_histogram),
+        }
+    }))
+}
+
+/// Values a stego image changed in its original cover.
+pub struct Changes {
+    /// Changed values of the red, green, blue and alpha channels
+    pub channels: [u64; 4],
+    /// Pixels with at least one changed value
+    pub pixels: u64,
+}
+
+impl Changes {
+    pub fn values(&self) -> u64 {
+        self.channels.iter().sum()
+    }
+}
+
+/// Count the values and pixels of `stego` that differ from `cover`.
+pub fn changes(cover: &RgbaImage, stego: &RgbaImage) -> Result<Changes, LowkeyError> {
+    check_same_size(cover, stego)?;
+    let mut changes = Changes {
+        channels: [0; 4],
+        pixels: 0,
+    };
+    for (cover_pixel, stego_pixel) in cover.pixels().zip(stego.pixels()) {
+        for channel in 0..4 {
+            if cover_pixel[channel] != stego_pixel[channel] {
+                changes.channels[channel] += 1;
+            }
+        }
+        if cover_pixel != stego_pixel {
+            changes.pixels += 1;
+        }
+    }
+    Ok(changes)
+}
+
+/// The differences between `cover` and `stego` as an opaque image: each of
+/// red, green and blue is the change in that channel times `amplify`, and a
+/// change in alpha adds to all three, so that changes of one show at a glance.
+pub fn difference_image(
+    cover: &RgbaImage,
+    stego: &RgbaImage,
+    amplify: u8,
+) -> Result<RgbaImage, LowkeyError> {
+    check_same_size(cover, stego)?;
+    Ok(RgbaImage::from_fn(cover.width(), cover.height(), |x, y| {
+        let (c, s) = (cover.get_pixel(x, y), stego.get_pixel(x, y));
+        let change = |channel: usize| c[channel].abs_diff(s[channel]) as u32 * amplify as u32;
+        let alpha = change(3);
+        let value = |channel| (change(channel) + alpha).min(255) as u8;
+        Rgba([value(0), value(1), value(2), 255])
+    }))
+}
+
+fn check_same_size(cover: &RgbaImage, stego: &RgbaImage) -> Result<(), LowkeyError> {
+    if cover.dimensions() != stego.dimensions() {
+        return Err(LowkeyError::InvalidInput(format!(
+            "Cover is {}x{} but the stego image is {}x{}",
+            cover.width(),
+            cover.height(),
+            stego.width(),
+            stego.height()
+        )));
+    }
+    Ok(())
+}
+
+/// Visual difference between a cover and a stego image.
+#[derive(Serialize)]
+pub struct Quality {
+    /// Peak signal-to-noise ratio over all channel values, in dB (infinite
+    /// for identical images)
+    pub psnr: f64,
+    /// Mean structural similarity of the red, green and blue channels, from
+    /// 1.0 (identical) down
+    pub ssim: f64,
+}
+
+impl Quality {
+    pub fn summary(&self) -> String {
+        if self.psnr.is_infinite() {
+            format!("PSNR inf dB, SSIM {:.6}", self.ssim)
+        } else {
+            format!("PSNR {:.2} dB, SSIM {:.6}", self.psnr, self.ssim)
+        }
+    }
+
+    /// Fail if the PSNR is below `min_psnr` or the SSIM below `min_ssim`.
+    pub fn check(&self, min_psnr: Option<f64>, min_ssim: Option<f64>) -> Result<(), LowkeyError> {
+        if let Some(min_psnr) = min_psnr
+            && self.psnr < min_psnr
+        {
+            return Err(LowkeyError::Other(format!(
+                "PSNR {:.2} dB is below the minimum of {:.2} dB",
+                self.psnr, min_psnr
+            )));
+        }
+        if let Some(min_ssim) = min_ssim
+            && self.ssim < min_ssim
+        {
+            return Err(LowkeyError::Other(format!(
+                "SSIM {:.6} is below the minimum of {:.6}",
+                self.ssim, min_ssim
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Side of the windows SSIM is computed over, and the step between them
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STEP: u32 = 4;
+
+/// Measure how much a stego image differs visually from its cover.
+pub fn quality(cover: &RgbaImage, stego: &RgbaImage) -> Result<Quality, LowkeyError> {
+    check_same_size(cover, stego)?;
+
+    let squared_error: f64 = cover
+        .iter()
+        .zip(stego.iter())
+        .map(|(&c, &s)| (c as f64 - s as f64).powi(2))
+        .sum();
+    let mse = squared_error / cover.len().max(1) as f64;
+    let psnr = if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    };
+
+    let ssim = (0..3)
+        .map(|channel| ssim(cover, stego, channel))
+        .sum::<f64>()
+        / 3.0;
+
+    Ok(Quality { psnr, ssim })
+}
+
+/// Mean SSIM (Wang et al.) of one channel over overlapping square windows.
+fn ssim(cover: &RgbaImage, stego: &RgbaImage, channel: usize) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = cover.dimensions();
+    let window = SSIM_WINDOW.min(width).min(height);
+    if window == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=height - window).step_by(SSIM_STEP as usize) {
+        for x in (0..=width - window).step_by(SSIM_STEP as usize) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..window {
+                for dx in 0..window {
+                    let a = cover.get_pixel(x + dx, y + dy)[channel] as f64;
+                    let b = stego.get_pixel(x + dx, y + dy)[channel] as f64;
+                    sum_a += a;
+                    sum_b += b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                    sum_ab += a * b;
+                }
+            }
+
+            let n = (window * window) as f64;
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let variance_a = sum_aa / n - mean_a * mean_a;
+            let variance_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2));
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+/// KL divergence D(p || q) in bits, with both histograms smoothed so empty
+/// bins do not make it infinite.
+fn kl_divergence(p: &[u64; 256], q: &[u64; 256]) -> f64 {
+    const SMOOTHING: f64 = 0.5;
+    let p_total = p.iter().sum::<u64>() as f64 + 256.0 * SMOOTHING;
+    let q_total = q.iter().sum::<u64>() as f64 + 256.0 * SMOOTHING;
+
+    p.iter()
+        .zip(q)
+        .map(|(&p, &q)| {
+            let p = (p as f64 + SMOOTHING) / p_total;
+            let q = (q as f64 + SMOOTHING) / q_total;
+            p * (p / q).log2()
+        })
+        .sum()
+}
+
+fn pair_asymmetry(histogram: &[u64; 256]) -> f64 {
+    let (difference, total) =
+        histogram
+            .chunks_exact(2)
+            .fold((0u64, 0u64), |(difference, total), pair| {
+                (
+                    difference + pair[0].abs_diff(pair[1]),
+                    total + pair[0] + pair[1],
+                )
+            });
+    if total == 0 {
+        0.0
+    } else {
+        difference as f64 / total as f64
+    }
+}
+
+/// How easily an encoded image would be caught, from 0 (not at all) to 100.
+#[derive(Serialize)]
+pub struct Detectability {
+    pub score: f64,
+    /// "low", "moderate" or "high"
+    pub level: &'static str,
+    /// Share of the image's capacity the message uses (0.0 to 1.0)
+    pub fill_ratio: f64,
+    /// Share of the image the chi-square attack flags (0.0 to 1.0)
+    pub chi_square_fraction: f64,
+    /// Entropy of the LSB plane of each channel, per bit (0.0 to 1.0)
+    pub plane_entropy: [f64; 4],
+    /// Largest amount by which a channel's LSB plane is more random than its
+    /// second LSB plane. Embedding into smooth areas raises it.
+    pub plane_contrast: f64,
+    pub recommendations: Vec<String>,
+}
+
+impl Detectability {
+    /// One-line summary, followed by the recommendations on their own lines.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "detectability {:.0}/100 ({}): fill ratio {:.1}%, chi-square flags {:.0}%, LSB plane entropy {:.2}",
+            self.score,
+            self.level,
+            self.fill_ratio * 100.0,
+            self.chi_square_fraction * 100.0,
+            self.plane_entropy.iter().sum::<f64>() / 4.0
+        );
+        for recommendation in &self.recommendations {
+            summary.push_str("\n  - ");
+            summary.push_str(recommendation);
+        }
+        summary
+    }
+}
+
+/// Score how detectable an encoded image is, from the share of its capacity
+/// the message uses, the chi-square attack and the entropy of its low bit
+/// planes. The recommendations leave out what the encode's `layout` and
+/// `method` already do.
+pub fn detectability(
+    img: &RgbaImage,
+    fill_ratio: f64,
+    layout: Layout,
+    method: Method,
+) -> Detectability {
+    let fill_ratio = fill_ratio.clamp(0.0, 1.0);
+    let chi_square_fraction = chi_square(img).embedded_fraction;
+    let lsb_entropy: [f64; 4] = std::array::from_fn(|channel| plane_entropy(img, channel, 0));
+    let plane_contrast = (0..4)
+        .map(|channel| lsb_entropy[channel] - plane_entropy(img, channel, 1))
+        .fold(0.0, f64::max);
+
+    let score = (100.0 * (0.4 * fill_ratio + 0.4 * chi_square_fraction + 0.2 * plane_contrast))
+        .clamp(0.0, 100.0);
+    let level = if score < MODERATE_DETECTABILITY {
+        "low"
+    } else if score < HIGH_DETECTABILITY {
+        "moderate"
+    } else {
+        "high"
+    };
+
+    let mut recommendations = Vec::new();
+    if fill_ratio > HIGH_FILL_RATIO {
+        recommendations.push(format!(
+            "The message fills {:.0}% of the image; use more carriers (--image-list or --image-dir) or a larger cover",
+            fill_ratio * 100.0
+        ));
+    }
+    // Noise matching already spreads the message, and LSB matching can't be
+    // combined with it
+    let noise_matched = layout == Layout::NoiseMatched;
+    if chi_square_fraction > 0.0 && !noise_matched && method == Method::LsbReplace {
+        recommendations.push(format!(
+            "A chi-square attack flags the first {:.0}% of the image; --match-noise spreads the message over the whole image instead",
+            chi_square_fraction * 100.0
+        ));
+    }
+    if plane_contrast > HIGH_PLANE_CONTRAST && noise_matched {
+        recommendations.push(
+            "The embedded bits stand out against smooth or flat areas; use a photo with more texture"
+                .to_string(),
+        );
+    } else if plane_contrast > HIGH_PLANE_CONTRAST {
+        recommendations.push(
+            "The embedded bits stand out against smooth or flat areas (e.g. an opaque alpha channel); use --match-noise or a photo with more texture"
+                .to_string(),
+        );
+    }
+
+    Detectability {
+        score,
+        level,
+        fill_ratio,
+        chi_square_fraction,
+        plane_entropy: lsb_entropy,
+        plane_contrast,
+        recommendations,
+    }
+}
+
+/// Shannon entropy of the 2x2 blocks of one bit plane of a channel, per bit.
+/// Random bits give 1.0, a constant plane 0.0.
+fn plane_entropy(img: &RgbaImage, channel: usize, bit: u8) -> f64 {
+    let mut counts = [0u64; 16];
+    for y in (0..img.height().saturating_sub(1)).step_by(2) {
+        for x in (0..img.width().saturating_sub(1)).step_by(2) {
+            let pattern =
+                [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .iter()
+                    .fold(0usize, |pattern, &(dx, dy)| {
+                        let value = img.get_pixel(x + dx, y + dy)[channel];
+                        (pattern << 1) | ((value >> bit) & 1) as usize
+                    });
+            counts[pattern] += 1;
+        }
+    }
+
+    let total = counts.iter().sum::<u64>() as f64;
+    if total == 0.0 {
+        return 0.0;
+    }
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum();
+    entropy / 4.0
+}
+
+/// One bit plane of a channel as a black and white image: white where the
+/// bit is set. Bit 0 is the LSB.
+pub fn bit_plane(img: &RgbaImage, channel: usize, bit: u8) -> GrayImage {
+    GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let set = (img.get_pixel(x, y)[channel] >> bit) & 1 == 1;
+        Luma([if set { 255 } else { 0 }])
+    })
+}
+
+/// The same bit plane of all four channels in one image twice the size of
+/// `img`: red top left, green top right, blue bottom left, alpha bottom right.
+pub fn bit_plane_grid(img: &RgbaImage, bit: u8) -> GrayImage {
+    let (width, height) = img.dimensions();
+    let mut grid = GrayImage::new(width * 2, height * 2);
+    for channel in 0..4 {
+        let (x, y) = ((channel as u32 % 2) * width, (channel as u32 / 2) * height);
+        image::imageops::replace(&mut grid, &bit_plane(img, channel, bit), x as i64, y as i64);
+    }
+    grid
+}
+
+/// Chi-square statistic over the pairs of values, and its p-value.
+fn pairs_test(histogram: &[u64; 256]) -> (f64, f64) {
+    let mut statistic = 0.0;
+    let mut categories = 0;
+    for pair in histogram.chunks_exact(2) {
+        let expected = (pair[0] + pair[1]) as f64 / 2.0;
+        if expected < MIN_EXPECTED {
+            continue;
+        }
+        statistic += (pair[0] as f64 - expected).powi(2) / expected;
+        categories += 1;
+    }
+
+    if categories < 2 {
+        return (statistic, 0.0);
+    }
+
+    let degrees_of_freedom = (categories - 1) as f64;
+    let p_value = 1.0 - regularized_gamma_p(degrees_of_freedom / 2.0, statistic / 2.0);
+    (statistic, p_value.clamp(0.0, 1.0))
+}
+
+/// Regularized lower incomplete gamma function P(a, x), which gives the
+/// chi-square CDF as P(k / 2, x / 2).
+fn regularized_gamma_p(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    if x < a + 1.0 {
+        // Series expansion
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        let mut n = a;
+        for _ in 0..1000 {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * 1e-15 {
+                break;
+            }
+        }
+        (sum.ln() - x + a * x.ln() - ln_gamma(a)).exp()
+    } else {
+        // Continued fraction for Q(a, x), evaluated with Lentz's method
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..1000 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+        1.0 - (-x + a * x.ln() - ln_gamma(a)).exp() * h
+    }
+}
+
+/// Natural log of the gamma function (Lanczos approximation, g = 7).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        // Reflection formula
+        return (std::f64::consts::PI / (std::f64::consts::PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    /// Smooth gradient, so neighbouring values are not evenly used
+    fn cover() -> RgbaImage {
+        RgbaImage::from_fn(128, 128, |x, y| {
+            Rgba([
+                (x / 3) as u8 * 2,
+                (y / 5) as u8 * 2,
+                ((x + y) / 7) as u8 * 2,
+                255,
+            ])
+        })
+    }
+
+    /// Replace the LSBs of the first `fraction` of channel values with pseudo-random bits
+    fn embed(img: &mut RgbaImage, fraction: f64) {
+        let count = (img.len() as f64 * fraction) as usize;
+        let mut state = 0x2545_f491_u32;
+        for value in img.iter_mut().take(count) {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *value = (*value & 0xFE) | (state & 1) as u8;
+        }
+    }
+
+    /// Smooth shading with mild sensor-like noise, as RS analysis expects
+    fn photo() -> RgbaImage {
+        RgbaImage::from_fn(256, 256, |x, y| {
+            let base = 120.0 + 40.0 * (x as f64 / 17.0).sin() + 30.0 * (y as f64 / 23.0).cos();
+            let noise = |c: u32| {
+                let h = (x.wrapping_mul(73_856_093)
+                    ^ y.wrapping_mul(19_349_663)
+                    ^ c.wrapping_mul(83_492_791))
+                .wrapping_mul(2_654_435_761);
+                (h >> 29) as f64 - 3.5
+            };
+            Rgba([
+                (base + noise(0)) as u8,
+                (base + 10.0 + noise(1)) as u8,
+                (base + 20.0 + noise(2)) as u8,
+                255,
+            ])
+        })
+    }
+
+    #[test]
+    fn test_rs_analysis_estimates_embedding_rate() {
+        let mut img = photo();
+        assert!(rs_analysis(&img).embedding_rate < RS_DETECTION_RATE);
+
+        embed(&mut img, 0.5);
+        let rate = rs_analysis(&img).embedding_rate;
+        assert!((0.35..=0.65).contains(&rate), "embedding rate {}", rate);
+
+        embed(&mut img, 1.0);
+        let result = rs_analysis(&img);
+        assert!(
+            result.embedding_rate > 0.85,
+            "embedding rate {}",
+            result.embedding_rate
+        );
+        // Three channels of 256x256 values at one bit each hold 24576 bytes
+        assert!(
+            (20_000..=26_000).contains(&result.estimated_bytes),
+            "estimated {} bytes",
+            result.estimated_bytes
+        );
+    }
+
+    #[test]
+    fn test_sample_pairs_estimates_embedding_rate() {
+        let mut img = photo();
+        let result = sample_pairs(&img);
+        assert!(result.embedding_rate < SPA_DETECTION_RATE);
+        // A flat alpha channel has nothing embedded
+        assert_eq!(result.channel_rates[3], 0.0);
+
+        embed(&mut img, 0.5);
+        let rate = sample_pairs(&img).embedding_rate;
+        assert!((0.35..=0.65).contains(&rate), "embedding rate {}", rate);
+
+        embed(&mut img, 1.0);
+        let result = sample_pairs(&img);
+        assert!(
+            result.embedding_rate > 0.85,
+            "embedding rate {}",
+            result.embedding_rate
+        );
+        assert!(result.channel_rates[3] > 0.9);
+    }
+
+    #[test]
+    fn test_sample_pairs_ignores_opaque_alpha() {
+        let mut img = photo();
+        let mut state = 0x2545_f491_u32;
+        for pixel in img.pixels_mut() {
+            for value in &mut pixel.0[..3] {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                *value = (*value & 0xFE) | (state & 1) as u8;
+            }
+        }
+
+        let result = sample_pairs(&img);
+        assert_eq!(result.channel_rates[3], 0.0);
+        assert!(
+            result.embedding_rate > 0.9,
+            "embedding rate {}",
+            result.embedding_rate
+        );
+    }
+
+    #[test]
+    fn test_compare_histograms() {
+        let cover = photo();
+        let same = compare_histograms(&cover, &cover).unwrap();
+        assert_eq!(same[0].changed, 0.0);
+        assert!(same[0].kl_divergence.abs() < 1e-12);
+
+        let mut stego = cover.clone();
+        embed(&mut stego, 1.0);
+        let comparison = compare_histograms(&cover, &stego).unwrap();
+        // Random bits change about half of the LSBs
+        assert!((0.4..=0.6).contains(&comparison[0].changed));
+        assert!(comparison[0].kl_divergence > 0.0);
+        // A flat alpha channel becomes perfectly balanced between 254 and 255
+        assert_eq!(comparison[3].cover_pair_asymmetry, 1.0);
+        assert!(comparison[3].stego_pair_asymmetry < 0.05);
+
+        assert!(compare_histograms(&cover, &RgbaImage::new(1, 1)).is_err());
+    }
+
+    #[test]
+    fn test_changes() {
+        let cover = photo();
+        let mut stego = cover.clone();
+        stego.get_pixel_mut(3, 4)[0] ^= 1;
+        stego.get_pixel_mut(3, 4)[3] ^= 1;
+        stego.get_pixel_mut(10, 0)[2] ^= 2;
+        let changed = changes(&cover, &stego).unwrap();
+        assert_eq!(changed.channels, [1, 0, 1, 1]);
+        assert_eq!((changed.values(), changed.pixels), (3, 2));
+
+        let difference = difference_image(&cover, &stego, 100).unwrap();
+        assert_eq!(difference.get_pixel(3, 4), &Rgba([200, 100, 100, 255]));
+        assert_eq!(difference.get_pixel(10, 0), &Rgba([0, 0, 200, 255]));
+        assert_eq!(difference.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+        assert!(changes(&cover, &RgbaImage::new(1, 1)).is_err());
+    }
+
+    #[test]
+    fn test_detectability() {
+        let mut img = photo();
+        let clean = detectability(&img, 0.0, Layout::default(), Method::LsbReplace);
+        assert_eq!(clean.level, "low");
+        assert!(clean.recommendations.is_empty());
+
+        embed(&mut img, 1.0);
+        let full = detectability(&img, 1.0, Layout::default(), Method::LsbReplace);
+        assert_eq!(full.level, "high");
+        assert!(full.score > clean.score);
+        // The flat alpha channel turns random
+        assert!(full.plane_entropy[3] > 0.9);
+        assert!(full.plane_contrast > HIGH_PLANE_CONTRAST);
+        assert_eq!(full.recommendations.len(), 3);
+        assert!(
+            full.recommendations
+                .iter()
+                .any(|recommendation| recommendation.contains("--match-noise"))
+        );
+
+        // Advice the encode already follows is left out
+        let noise_matched = detectability(&img, 1.0, Layout::NoiseMatched, Method::LsbReplace);
+        assert_eq!(noise_matched.score, full.score);
+        assert_eq!(noise_matched.recommendations.len(), 2);
+        assert!(
+            noise_matched
+                .recommendations
+                .iter()
+                .all(|recommendation| !recommendation.contains("--match-noise"))
+        );
+    }
+
+    #[test]
+    fn test_noise_matched_embedding() {
+        use super::super::pixel::noise_matched_positions;
+
+        let img = photo();
+        let positions = noise_matched_positions(&img, "key", img.len() / 4);
+        // The order only depends on the upper bits, and each prefix is stable
+        assert_eq!(positions[..100], noise_matched_positions(&img, "key", 100));
+        let mut flipped = img.clone();
+        flipped.iter_mut().for_each(|value| *value ^= 1);
+        assert_eq!(
+            positions,
+            noise_matched_positions(&flipped, "key", img.len() / 4)
+        );
+        assert_ne!(
+            positions[..100],
+            noise_matched_positions(&img, "other", 100)
+        );
+
+        // The flat alpha channel is mostly left alone, and nothing looks embedded from the start
+        let alpha = positions
+            .iter()
+            .filter(|&&position| position % 4 == 3)
+            .count();
+        assert!(alpha < positions.len() / 100, "{} alpha values used", alpha);
+        let mut stego = img.clone();
+        let mut state = 0x2545_f491_u32;
+        for position in positions {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            stego.as_mut()[position] = (stego.as_raw()[position] & 0xFE) | (state & 1) as u8;
+        }
+        let result = detectability(&stego, 0.25, Layout::NoiseMatched, Method::LsbReplace);
+        assert_eq!(result.chi_square_fraction, 0.0);
+        assert!(result.plane_contrast < HIGH_PLANE_CONTRAST);
+    }
+
+    #[test]
+    fn test_quality() {
+        let cover = photo();
+        let same = quality(&cover, &cover).unwrap();
+        assert!(same.psnr.is_infinite());
+        assert!((same.ssim - 1.0).abs() < 1e-12);
+
+        let mut stego = cover.clone();
+        embed(&mut stego, 1.0);
+        let result = quality(&cover, &stego).unwrap();
+        // Flipping half of the LSBs gives an MSE of 0.5, i.e. about 51 dB
+        assert!((50.0..52.5).contains(&result.psnr), "PSNR {}", result.psnr);
+        assert!((0.95..1.0).contains(&result.ssim), "SSIM {}", result.ssim);
+        assert!(result.check(Some(50.0), Some(0.95)).is_ok());
+        assert!(result.check(Some(55.0), None).is_err());
+        assert!(result.check(None, Some(1.0)).is_err());
+        assert!(same.check(Some(100.0), Some(1.0)).is_ok());
+
+        assert!(quality(&cover, &RgbaImage::new(1, 1)).is_err());
+    }
+
+    #[test]
+    fn test_bit_plane() {
+        let img = RgbaImage::from_pixel(2, 1, Rgba([0b01, 0b10, 0b11, 0]));
+        assert_eq!(bit_plane(&img, 0, 0).get_pixel(1, 0)[0], 255);
+        assert_eq!(bit_plane(&img, 0, 1).get_pixel(1, 0)[0], 0);
+        assert_eq!(bit_plane(&img, 1, 0).get_pixel(0, 0)[0], 0);
+        assert_eq!(bit_plane(&img, 1, 1).get_pixel(0, 0)[0], 255);
+        assert_eq!(bit_plane(&img, 3, 0).get_pixel(0, 0)[0], 0);
+
+        let grid = bit_plane_grid(&img, 1);
+        assert_eq!(grid.dimensions(), (4, 2));
+        let row = |y| (0..4).map(|x| grid.get_pixel(x, y)[0]).collect::<Vec<_>>();
+        // Red, green; blue, alpha
+        assert_eq!(row(0), [0, 0, 255, 255]);
+        assert_eq!(row(1), [255, 255, 0, 0]);
+    }
+
+    #[test]
+    fn test_ln_gamma() {
+        assert!((ln_gamma(1.0)).abs() < 1e-10);
+        assert!((ln_gamma(5.0) - 24f64.ln()).abs() < 1e-10);
+        assert!((ln_gamma(0.5) - std::f64::consts::PI.sqrt().ln()).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_chi_square_cdf() {
+        // Chi-square with 2 degrees of freedom has CDF 1 - exp(-x / 2)
+        for x in [0.5, 2.0, 10.0] {
+            let expected = 1.0 - (-x / 2.0f64).exp();
+            assert!((regularized_gamma_p(1.0, x / 2.0) - expected).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_clean_cover_is_not_flagged() {
+        let result = chi_square(&cover());
+        assert_eq!(result.embedded_fraction, 0.0);
+        assert!(result.p_value < 0.05);
+    }
+
+    #[test]
+    fn test_embedded_prefix_is_flagged() {
+        let mut img = cover();
+        embed(&mut img, 0.4);
+        let result = chi_square(&img);
+        assert!(
+            (0.3..=0.5).contains(&result.embedded_fraction),
+            "embedded fraction {}",
+            result.embedded_fraction
+        );
+
+        embed(&mut img, 1.0);
+        let result = chi_square(&img);
+        assert!(result.p_value > DETECTION_P_VALUE);
+        assert_eq!(result.embedded_fraction, 1.0);
+    }
+}
@@ -1,249 +1,6321 @@
-use bitvec::prelude::{BitVec, Lsb0};
+use bitvec::prelude::{BitSlice, BitVec, Lsb0};
 use image::RgbaImage;
-use std::fs::{self, File};
-use std::io::Read;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io::Write;
+use std::iter;
 use std::path::Path;
+use std::slice;
 
-use super::common::{check_capacity_images, check_image_png, convert_bytes_to_bits};
-use super::io::{read_image, read_sequence_info, save_rgba_with_metadata};
-use super::pixel::{get_bits_reader_images, read_bits, set_bits_image};
-use super::resize::resize_image;
-use crate::crypto;
+use super::analysis::{Detectability, Quality, detectability, quality};
+use super::common::{
+    check_capacity_images, check_capacity_values, check_image_lossless, check_image_png,
+    convert_bytes_to_bits,
+};
+use super::io::{
+    ImageHeader, KeepMetadata, OutputFormat, PngEncoding, Rgba16Image, SequenceInfo, carrier_from,
+    is_lossy_webp, low_bytes, payload_chunk_from, probe_image, probe_image_from_memory,
+    read_carrier, read_cover, read_image, read_payload_chunk, read_sealed_sequence_info,
+    read_sequence_info, save_rgba_with_metadata, save_rgba_with_payload, save_rgba16_with_metadata,
+    save_rgba16_with_payload, set_low_bytes, write_atomically, write_rgba_with_metadata,
+    write_rgba_with_metadata_from,
+};
+use super::jpeg::{Jpeg, is_jpeg, is_jpeg_file};
+use super::naming::{NameVars, OutputTemplate};
+use super::pixel::{
+    Channels, LazyImagesReader, MAX_DEPTH, Method, TRANSPARENT_ALPHA, adaptive_capacity,
+    adaptive_positions, fill_noise_at, fill_noise_positions, get_bits_reader_adaptive,
+    get_bits_reader_images, get_bits_reader_noise_matched, get_bits_reader_scattered,
+    match_changes, opaque_pixels, put_pixels, read_bits, read_bits_at_depth,
+    region_record_positions, scattered_positions, set_bits_image_at, set_bits_image_at_tracked,
+    set_bits_image_noise_matched, set_bits_positions, take_pixels, textured_values, wipe_low_bits,
+};
+use super::pool::WritePool;
+use super::region::{Region, RegionRecord};
+use super::resize::{MIN_DIMENSION, resize_image, resize_to, scaled_dimensions};
+use crate::audio::{Audio, is_audio, is_audio_file};
+use crate::carrier::Carrier;
+use crate::crypto::{
+    self, CHUNK_BYTES, ChunkDecryptor, Cipher, KDF_HEADER_BYTES, KEY_CHECK_BYTES, KdfParams,
+    TAG_BYTES,
+};
+use crate::error::LowkeyError;
+use crate::file_info::FileInfo;
+use crate::progress::Progress;
+use crate::{compress, ecc, metrics, shard};
 
 /// Protocol version for the steganography format
-/// Version 0: [1 byte version] + [4 bytes message length] + [encrypted message data]
-const PROTOCOL_VERSION: u8 = 0;
+/// Version 0: [1 byte version] + [4 bytes message length] + [encrypted message data],
+///            key derived with SHA256
+/// Version 1: [1 byte version] + [4 bytes message length] + [16 bytes salt] +
+///            [12 bytes Argon2id parameters] + [encrypted message data],
+///            key derived with Argon2id
+/// Version 2: [1 byte version] + [4 bytes message length] + [1 byte depth] +
+///            [encrypted message data as in version 1], the header at one bit per
+///            channel value and the data at `depth` bits per value
+/// Version 3: [1 byte version] + [4 bytes message length] + [1 byte depth] +
+///            [1 byte channel mask] + [encrypted message data as in version 1],
+///            header and data in the masked channels only
+/// Version 4: [1 byte version] + [4 bytes message length] + [1 byte depth] +
+///            [1 byte channel mask] + [1 byte layout] +
+///            [encrypted message data as in version 1]
+/// Version 5: [1 byte version] + [4 bytes message length] + [1 byte depth] +
+///            [1 byte channel mask] + [1 byte layout] + [1 byte flags] +
+///            [encrypted message data as in version 1], the message
+///            deflate-compressed before encryption if `COMPRESSED_FLAG` is set and
+///            the encrypted data in Reed-Solomon blocks if `ECC_FLAG` is set; with
+///            `FILE_FLAG` the message starts with the hidden file's name, MIME
+///            type and modification time (see `FileInfo::prepend`), and with
+///            `ARCHIVE_FLAG` the message is a bundle of files (see `archive::pack`);
+///            with `KEY_CHECK_FLAG` a key check value follows the Argon2id
+///            parameters (see `crypto::encrypt_with_key_check`)
+/// Version 6: [1 byte version] + [8 bytes message length] + [1 byte depth] +
+///            [1 byte channel mask] + [1 byte layout] + [1 byte flags] +
+///            [encrypted message data as in version 5], or with `CHUNKED_FLAG`
+///            in chunks with a tag each (see `crypto::encrypt_chunked`); with
+///            `PLAIN_FLAG` the message as it is, not encrypted
+/// Version 7: [1 byte version] + [8 bytes message length] + [1 byte depth] +
+///            [1 byte channel mask] + [1 byte layout] + [1 byte flags] +
+///            [1 byte cipher] + [encrypted message data as in version 6], the
+///            chunks encrypted with the cipher (see `Cipher::id`); with
+///            `RECIPIENTS_FLAG` an age file whose header wraps the file key
+///            for each recipient (see `crypto::encrypt_to_recipients`)
+/// Version 8: [header as in version 7] + [1 byte more flags] +
+///            [encrypted message data as in version 7]; with `PADDED_FLAG`
+///            the message is encrypted with its length in front and zeros
+///            after it up to a fixed size (see `pad_message`), and with
+///            `LSB_MATCH_FLAG` the bits embedded by LSB matching (see
+///            `match_changes`); with `TRANSPARENT_SKIPPED_FLAG` the message
+///            is in the pixels that are not transparent only, as if they
+///            were the whole image in a row (see `take_pixels`), and with
+///            `REGION_FLAG` in those of a region only, which a record
+///            embedded with the key tells (see `RegionRecord::to_bytes`)
+const PROTOCOL_VERSION: u8 = 8;
 
-pub fn encode_from_file(
-    input_image: &str,
-    message_file: &str,
-    output_image: &str,
-    auto_resize: bool,
-    key: &str,
-) -> Result<(), String> {
-    check_image_png(output_image)?;
+/// Still decoded, never written: SHA256 instead of Argon2id
+const LEGACY_PROTOCOL_VERSION: u8 = 0;
 
-    let mut img = read_image(input_image)?;
+/// First version with the depth byte
+const DEPTH_PROTOCOL_VERSION: u8 = 2;
 
-    let mut message_file_handle = File::open(message_file)
-        .map_err(|e| format!("Failed to open message file '{}': {}", message_file, e))?;
-    let mut message_bytes = Vec::new();
-    message_file_handle
-        .read_to_end(&mut message_bytes)
-        .map_err(|e| format!("Failed to read message file: {}", e))?;
+/// First version with the channel mask byte
+const CHANNELS_PROTOCOL_VERSION: u8 = 3;
 
-    if auto_resize {
-        img = resize_image(&mut img, message_bytes.len(), 600)?;
+/// First version with the layout byte
+const LAYOUT_PROTOCOL_VERSION: u8 = 4;
+
+/// First version with the flags byte
+const FLAGS_PROTOCOL_VERSION: u8 = 5;
+
+/// First version with the 8-byte length
+const WIDE_LENGTH_PROTOCOL_VERSION: u8 = 6;
+
+/// First version with the cipher byte
+const CIPHER_PROTOCOL_VERSION: u8 = 7;
+
+/// First version with the second flags byte, after the cipher byte, which
+/// holds the high byte of the flags
+const MORE_FLAGS_PROTOCOL_VERSION: u8 = 8;
+
+/// Flag set when the message was compressed before encryption
+const COMPRESSED_FLAG: u16 = 0b0000_0001;
+
+/// Flag set when the encrypted message is wrapped in error correction
+const ECC_FLAG: u16 = 0b0000_0010;
+
+/// Flag set when the message starts with the hidden file's metadata
+const FILE_FLAG: u16 = 0b0000_0100;
+
+/// Flag set when the message is an archive of several files
+const ARCHIVE_FLAG: u16 = 0b0000_1000;
+
+/// Flag set when the encrypted message carries a key check value, so that a
+/// wrong key can be told from damaged data
+const KEY_CHECK_FLAG: u16 = 0b0001_0000;
+
+/// Flag set when the message is encrypted in chunks, so that it can be
+/// decrypted as it is read
+const CHUNKED_FLAG: u16 = 0b0010_0000;
+
+/// Flag set when the message is embedded without encryption, for payloads
+/// encrypted before they reach lowkey
+const PLAIN_FLAG: u16 = 0b0100_0000;
+
+/// Flag set when the message is encrypted to age recipients instead of
+/// with the key
+const RECIPIENTS_FLAG: u16 = 0b1000_0000;
+
+/// Flag set when the message was padded to a fixed size before encryption,
+/// so that the length in the header does not give its size away
+const PADDED_FLAG: u16 = 0b1_0000_0000;
+
+/// Flag set when the bits were embedded by LSB matching instead of
+/// replacement; reading them back is the same either way
+const LSB_MATCH_FLAG: u16 = 0b10_0000_0000;
+
+/// Flag set when transparent pixels were left out, so that the message is
+/// only read from the others
+const TRANSPARENT_SKIPPED_FLAG: u16 = 0b100_0000_0000;
+
+/// Flag set when the message was kept to a region of the image
+const REGION_FLAG: u16 = 0b1000_0000_0000;
+
+/// Values of the layout byte
+const LAYOUT_SEQUENTIAL: u8 = 0;
+const LAYOUT_NOISE_MATCHED: u8 = 1;
+const LAYOUT_SCATTERED: u8 = 2;
+const LAYOUT_ADAPTIVE: u8 = 3;
+
+/// Size of the start of the header every version shares: version byte + 4-byte length
+const BASE_HEADER_BYTES: usize = 5;
+
+/// Size of the message header: version byte + 8-byte length + depth byte +
+/// channel mask byte + layout byte + flags byte + cipher byte + second
+/// flags byte
+const HEADER_BYTES: usize = BASE_HEADER_BYTES + 10;
+
+/// Bytes added to a message of up to a chunk on top of the plaintext:
+/// header, KDF salt and parameters, key check, nonce prefix and auth tag,
+/// with the default cipher
+const MESSAGE_OVERHEAD_BYTES: usize =
+    HEADER_BYTES + Cipher::ChaCha20Poly1305.chunked_header_bytes() + TAG_BYTES;
+
+/// Where message bits go in a single cover.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Values of `channels` from the start of the image, `depth` low bits
+    /// each (the header always takes one bit per value)
+    Sequential { depth: u8, channels: Channels },
+    /// Keyed positions in the noisy areas, one bit each (see `noise_matched_positions`)
+    NoiseMatched,
+    /// Values of `channels` in a keyed random order, `depth` low bits each
+    /// (see `scattered_positions`)
+    Scattered { depth: u8, channels: Channels },
+    /// Textured values of `channels` in a keyed random order, `depth` low
+    /// bits each, leaving flat areas alone (see `adaptive_positions`)
+    Adaptive { depth: u8, channels: Channels },
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Sequential {
+            depth: 1,
+            channels: Channels::ALL,
+        }
     }
+}
 
-    let bits = get_message_bits(&message_bytes, key)?;
-    set_bits_image(&mut img, &bits)?;
+impl Layout {
+    fn depth(self) -> u8 {
+        match self {
+            Layout::Sequential { depth, .. }
+            | Layout::Scattered { depth, .. }
+            | Layout::Adaptive { depth, .. } => depth,
+            Layout::NoiseMatched => 1,
+        }
+    }
 
-    if let Some(parent) = Path::new(output_image).parent() {
-        fs::create_dir_all(parent)
-            .map_err(|e| format!("Failed to create output directory: {}", e))?;
+    fn channels(self) -> Channels {
+        match self {
+            Layout::Sequential { channels, .. }
+            | Layout::Scattered { channels, .. }
+            | Layout::Adaptive { channels, .. } => channels,
+            Layout::NoiseMatched => Channels::ALL,
+        }
     }
 
-    save_rgba_with_metadata(&img, output_image, input_image, None)?;
+    /// Value of the header's layout byte
+    fn id(self) -> u8 {
+        match self {
+            Layout::Sequential { .. } => LAYOUT_SEQUENTIAL,
+            Layout::NoiseMatched => LAYOUT_NOISE_MATCHED,
+            Layout::Scattered { .. } => LAYOUT_SCATTERED,
+            Layout::Adaptive { .. } => LAYOUT_ADAPTIVE,
+        }
+    }
 
-    Ok(())
+    /// Whether bits are placed by the upper bits of the values, which LSB
+    /// matching would change
+    fn by_texture(self) -> bool {
+        matches!(self, Layout::NoiseMatched | Layout::Adaptive { .. })
+    }
 }
 
-pub fn encode_from_files(
-    input_images: &[String],
-    message_file: &str,
-    output_dir: &str,
-    key: &str,
-) -> Result<(), String> {
-    if input_images.is_empty() {
-        return Err("No input images provided".to_string());
+/// Part of a single cover the message goes in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Channel values of the pixels, written to a lossless image
+    #[default]
+    Pixels,
+    /// Quantized DCT coefficients of a baseline JPEG, written back without
+    /// compressing it again (see `jpeg`)
+    Jpeg,
+    /// A private ancillary chunk of a PNG, with the pixels left as they are.
+    /// Not covert, but it survives changes to the pixels and has no limit
+    /// on the size of the message.
+    Chunk,
+}
+
+impl std::str::FromStr for Format {
+    type Err = LowkeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pixels" => Ok(Format::Pixels),
+            "jpeg" | "jpg" => Ok(Format::Jpeg),
+            "chunk" => Ok(Format::Chunk),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown format '{}' (expected pixels, jpeg or chunk)",
+                s
+            ))),
+        }
     }
+}
 
-    let mut message_file_handle = File::open(message_file)
-        .map_err(|e| format!("Failed to open message file '{}': {}", message_file, e))?;
-    let mut message_bytes = Vec::new();
-    message_file_handle
-        .read_to_end(&mut message_bytes)
-        .map_err(|e| format!("Failed to read message file: {}", e))?;
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Format::Pixels => "pixels",
+            Format::Jpeg => "jpeg",
+            Format::Chunk => "chunk",
+        })
+    }
+}
 
-    let mut images: Vec<(String, RgbaImage)> = input_images
-        .iter()
-        .map(|image_path| read_image(image_path).map(|img| (image_path.clone(), img)))
-        .collect::<Result<Vec<_>, _>>()?;
+/// How the message is protected before and while it is embedded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PayloadOptions {
+    /// Argon2id cost of deriving the encryption key
+    pub kdf: KdfParams,
+    /// Wrap the encrypted message in Reed-Solomon parity, so that damaged
+    /// channel values can be corrected
+    pub ecc: bool,
+    /// Name, MIME type and modification time of the hidden file, carried
+    /// with the message so that decode can restore it
+    pub file: Option<FileInfo>,
+    /// The message was made by `archive::pack`, for decode to unpack
+    pub archive: bool,
+    /// Embed the message as it is, for payloads already encrypted with
+    /// another tool; the key then only places the bits
+    pub plain: bool,
+    /// AEAD cipher the message is encrypted with
+    pub cipher: Cipher,
+    /// `age1...` public keys to encrypt the message to instead of with the
+    /// key, which then only places the bits
+    pub recipients: Vec<String>,
+    /// Pad the message to a fixed size before encrypting it, so that the
+    /// length in the header does not give its size away
+    pub pad_to: Option<PadTo>,
+    /// Message embedded where an unscattered one would be, under a key of
+    /// its own, with the message scattered behind it (see `Decoy`)
+    pub decoy: Option<Decoy>,
+    /// Set the low bits the message leaves unused at random, so that there
+    /// is no edge where it ends; not with `Layout::NoiseMatched`, whose
+    /// point is to leave smooth areas alone
+    pub noise_fill: bool,
+    /// How values are changed to carry the bits
+    pub method: Method,
+    /// Leave transparent pixels alone, whose colour is often undefined or
+    /// zeroed and would only show the bits; with a single cover and not
+    /// with the layouts that pick values by texture
+    pub skip_transparent: bool,
+    /// Keep the message to part of the image, with a single cover and not
+    /// with the layouts that pick values by texture
+    pub region: Option<Region>,
+    /// Deflate the message before encrypting it, when that makes it smaller
+    pub compress: bool,
+    /// Most of each image the message may fill; several covers are filled
+    /// only up to it, so that the message reaches more of them
+    pub max_fill: Option<FillLimit>,
+    /// Apply the cover's ICC profile to its pixels before embedding, and
+    /// write the output as plain sRGB without it
+    pub convert_srgb: bool,
+    /// Give the output none of the cover's metadata
+    pub strip_metadata: bool,
+    /// How the outputs of a multi-image encode record their order in their
+    /// metadata
+    pub sequence_chunk: SequenceChunk,
+    /// How a PNG output is compressed
+    pub png_encoding: PngEncoding,
+}
 
-    fs::create_dir_all(output_dir)
-        .map_err(|e| format!("Failed to create output directory: {}", e))?;
+impl Default for PayloadOptions {
+    fn default() -> Self {
+        Self {
+            kdf: KdfParams::default(),
+            ecc: false,
+            file: None,
+            archive: false,
+            plain: false,
+            cipher: Cipher::default(),
+            recipients: Vec::new(),
+            pad_to: None,
+            decoy: None,
+            noise_fill: true,
+            method: Method::default(),
+            skip_transparent: false,
+            region: None,
+            compress: true,
+            max_fill: None,
+            convert_srgb: false,
+            strip_metadata: false,
+            sequence_chunk: SequenceChunk::default(),
+            png_encoding: PngEncoding::default(),
+        }
+    }
+}
+
+impl PayloadOptions {
+    /// Which of the cover's metadata the output is given.
+    pub fn keep_metadata(&self) -> KeepMetadata {
+        if self.strip_metadata {
+            KeepMetadata::Nothing
+        } else if self.convert_srgb {
+            KeepMetadata::AllButColorProfile
+        } else {
+            KeepMetadata::All
+        }
+    }
+}
 
-    let bits = get_message_bits(&message_bytes, key)?;
+/// A harmless message to give up instead of the real one. Decoding with its
+/// key finds it as an ordinary sequential message; decoding with the real
+/// key finds the scattered message in the values after it. Neither shows
+/// that the other is there.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Decoy {
+    pub message: Vec<u8>,
+    pub key: String,
+}
 
-    check_capacity_images(
-        &images.iter().map(|(_, img)| img).collect::<Vec<_>>(),
-        &bits,
-    )?;
+/// Size a message is padded to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PadTo {
+    /// An embedded payload of this many bytes, encryption and parity included
+    Bytes(usize),
+    /// As much as the cover holds
+    Full,
+    /// The smallest power of two bytes, from `MIN_POWER_OF_TWO_PAD`, that
+    /// the message fits in as embedded, so that only its rough size shows
+    PowerOfTwo,
+}
 
-    let total_bits = bits.len();
-    let images_count = images.len();
-    let mut cursor = 0usize;
+/// Smallest size `PadTo::PowerOfTwo` pads to
+const MIN_POWER_OF_TWO_PAD: usize = 1024;
 
-    for (i, (image_path, img)) in images.iter_mut().enumerate() {
-        if cursor >= total_bits {
-            break;
+impl std::str::FromStr for PadTo {
+    type Err = LowkeyError;
+
+    /// A number of bytes, `full` or `pow2`.
+    fn from_str(size: &str) -> Result<Self, LowkeyError> {
+        match size {
+            "full" => Ok(Self::Full),
+            "pow2" => Ok(Self::PowerOfTwo),
+            _ => size.parse().map(Self::Bytes).map_err(|_| {
+                LowkeyError::InvalidInput(format!(
+                    "Invalid padding size '{}' (expected a number of bytes, full or pow2)",
+                    size
+                ))
+            }),
         }
+    }
+}
 
-        let (width, height) = img.dimensions();
-        let image_capacity_bits = (width * height * 4) as usize;
+/// How a message is spread over several covers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Redundancy {
+    /// Split over the covers, each carrying the part after the previous one's
+    #[default]
+    None,
+    /// A complete copy in every cover large enough for one, so that any one
+    /// of them is enough to decode
+    Full,
+    /// Erasure-coded into `total` shards, one per cover, of which any
+    /// `needed` rebuild the message
+    Shards { needed: u8, total: u8 },
+}
 
-        let bits_to_encode = std::cmp::min(image_capacity_bits, total_bits - cursor);
-        let next_cursor = cursor + bits_to_encode;
+impl std::str::FromStr for Redundancy {
+    type Err = LowkeyError;
 
-        set_bits_image(img, &bits[cursor..next_cursor])?;
-        cursor = next_cursor;
+    /// `none`, `full` or `K-of-N`.
+    fn from_str(name: &str) -> Result<Self, LowkeyError> {
+        match name {
+            "none" => return Ok(Self::None),
+            "full" => return Ok(Self::Full),
+            _ => {}
+        }
+        let unknown = || {
+            LowkeyError::InvalidInput(format!(
+                "Unknown redundancy '{}' (expected none, full or K-of-N, such as 3-of-5)",
+                name
+            ))
+        };
+        let (needed, total) = name.split_once("-of-").ok_or_else(unknown)?;
+        let needed: usize = needed.parse().map_err(|_| unknown())?;
+        let total: usize = total.parse().map_err(|_| unknown())?;
+        if needed < 2 || needed >= total || total > shard::MAX_SHARDS {
+            return Err(LowkeyError::InvalidInput(format!(
+                "Invalid redundancy '{}' (K-of-N needs 2 <= K < N <= {}; for K = 1 use full)",
+                name,
+                shard::MAX_SHARDS
+            )));
+        }
+        Ok(Self::Shards {
+            needed: needed as u8,
+            total: total as u8,
+        })
+    }
+}
 
-        let filename = Path::new(image_path)
-            .file_name()
-            .ok_or_else(|| format!("Invalid input path: {}", image_path))?;
+/// How a message split over several covers is shared out between them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Distribute {
+    /// Each cover filled before the next, leaving the last ones out if the
+    /// message ends before them
+    #[default]
+    Fill,
+    /// Every cover about equally full, each saying how much of it the
+    /// message takes (see `SpreadHeader`)
+    Even,
+}
 
-        let mut output_filename = filename.to_string_lossy().to_string();
-        if output_filename.to_lowercase().ends_with(".jpg")
-            || output_filename.to_lowercase().ends_with(".jpeg")
-        {
-            if let Some(pos) = output_filename.rfind('.') {
-                output_filename = format!("{}.png", &output_filename[..pos]);
-            }
+/// How a multi-image encode lays the message out over its covers and names
+/// the outputs.
+#[derive(Clone, Debug)]
+pub struct SetOptions {
+    /// Low bits of each channel value the message takes
+    pub depth: u8,
+    /// Channels the message goes in
+    pub channels: Channels,
+    /// Shrink all covers by the same factor to what the message needs (see
+    /// `resize_covers`), or each to what a copy needs with `Redundancy::Full`
+    pub auto_resize: bool,
+    /// Leave out covers too small to hold even the headers instead of
+    /// failing the encode
+    pub skip_small: bool,
+    /// Whether the covers share the message, or each can rebuild it
+    pub redundancy: Redundancy,
+    /// How a shared message is spread over the covers; only without
+    /// redundancy
+    pub distribute: Distribute,
+    /// Names of the outputs; the covers' own, as PNG, without one
+    pub output_template: Option<OutputTemplate>,
+}
+
+impl Default for SetOptions {
+    fn default() -> Self {
+        Self {
+            depth: 1,
+            channels: Channels::ALL,
+            auto_resize: false,
+            skip_small: false,
+            redundancy: Redundancy::default(),
+            distribute: Distribute::default(),
+            output_template: None,
         }
+    }
+}
 
-        let output_path = Path::new(output_dir).join(&output_filename);
-        let output_path_str = output_path.to_string_lossy().to_string();
+impl std::str::FromStr for Distribute {
+    type Err = LowkeyError;
 
-        let sequence_info = Some((i as u32, images_count as u32));
-        save_rgba_with_metadata(&img, &output_path_str, image_path, sequence_info)?;
-        println!(
-            "Saved encoded image {}/{}: {}",
-            i + 1,
-            images_count,
-            output_path_str
+    fn from_str(name: &str) -> Result<Self, LowkeyError> {
+        match name {
+            "fill" => Ok(Self::Fill),
+            "even" => Ok(Self::Even),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown distribution '{}' (expected fill or even)",
+                name
+            ))),
+        }
+    }
+}
+
+/// What the outputs of a multi-image encode record of their order in their
+/// metadata, besides the in-band sequence headers decode can always go by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SequenceChunk {
+    /// Index and image count in the clear, in an lKsq chunk or TIFF tag,
+    /// which any tool listing chunks shows
+    #[default]
+    Plain,
+    /// Index and image count encrypted under the key, so that the chunk
+    /// looks like random data under a generic name
+    Encrypted,
+    /// Nothing; decode orders the images by their in-band headers
+    Off,
+}
+
+/// Most of an image's capacity a message may fill, as the fill ratio of the
+/// detectability report, since fuller images are easier to flag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FillLimit {
+    /// Largest fill ratio, above 0 and at most 1
+    pub ratio: f64,
+    /// Only warn about an image filled above `ratio` instead of failing
+    pub warn_only: bool,
+}
+
+impl FillLimit {
+    /// Fail, or with `warn_only` warn, if `fill` of the image at `path` is
+    /// above the limit.
+    fn check(self, path: &str, fill: f64) -> Result<(), LowkeyError> {
+        if fill <= self.ratio {
+            return Ok(());
+        }
+        let problem = format!(
+            "The message would fill {:.1}% of {}, more than the {:.1}% allowed",
+            fill * 100.0,
+            path,
+            self.ratio * 100.0
         );
+        if self.warn_only {
+            eprintln!("Warning: {}", problem);
+            return Ok(());
+        }
+        Err(LowkeyError::Capacity(problem))
+    }
+
+    /// Most values of an image of `values` the message may take.
+    fn values(self, values: usize) -> usize {
+        (values as f64 * self.ratio) as usize
+    }
+}
+
+/// A decoded message, with the metadata of the file it came from if that was
+/// embedded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedMessage {
+    pub bytes: Vec<u8>,
+    pub file: Option<FileInfo>,
+    /// `bytes` is an archive to unpack with `archive::unpack`
+    pub archive: bool,
+    /// Set while `bytes` is still the age file of a message encrypted to
+    /// recipients, which `open` decrypts
+    pub sealed: Option<Sealed>,
+}
+
+/// What is left to undo of a message encrypted to age recipients once it is
+/// decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sealed {
+    compressed: bool,
+    file: bool,
+}
+
+impl DecodedMessage {
+    /// Decrypt a message encrypted to age recipients with the identities in
+    /// `identity_files` and unpack it. Other messages are returned as they are.
+    pub fn open(self, identity_files: &[String]) -> Result<Self, LowkeyError> {
+        let Some(sealed) = self.sealed else {
+            return Ok(self);
+        };
+        if identity_files.is_empty() {
+            return Err(LowkeyError::Crypto(
+                "The message is encrypted to age recipients, decode it with an identity file"
+                    .to_string(),
+            ));
+        }
+        let message_bytes = metrics::time_stage("decrypt", || {
+            crypto::decrypt_with_identities(&self.bytes, identity_files)
+        })?;
+        unpack_message(message_bytes, sealed, self.archive)
     }
+}
 
+/// Check that `depth` low bits per channel value can be used.
+pub fn check_depth(depth: u8) -> Result<(), LowkeyError> {
+    if !(1..=MAX_DEPTH).contains(&depth) {
+        return Err(LowkeyError::InvalidInput(format!(
+            "Invalid bit depth {} (must be 1 to {})",
+            depth, MAX_DEPTH
+        )));
+    }
     Ok(())
 }
 
-pub fn decode_from_files(
-    image_paths: &[String],
-    output_file: &str,
-    key: &str,
-) -> Result<(), String> {
-    if image_paths.is_empty() {
-        return Err("No input images provided".to_string());
+/// Fixed part of a message, read before the encrypted data.
+struct MessageHeader {
+    version: u8,
+    /// Encrypted payload length
+    length: u64,
+    /// Message bits per channel value after the header
+    depth: u8,
+    /// Channels that carry the header and the message
+    channels: Channels,
+    /// Layout byte, `None` before version 4
+    layout: Option<u8>,
+    /// Whether the message was compressed before encryption
+    compressed: bool,
+    /// Whether the encrypted message is wrapped in error correction
+    ecc: bool,
+    /// Whether the message starts with file metadata
+    file: bool,
+    /// Whether the message is an archive of several files
+    archive: bool,
+    /// Whether the encrypted message carries a key check value
+    key_check: bool,
+    /// Whether the message is encrypted in chunks
+    chunked: bool,
+    /// Whether the message is not encrypted at all
+    plain: bool,
+    /// Cipher of a chunked message, ChaCha20-Poly1305 before version 7
+    cipher: Cipher,
+    /// Whether the message is encrypted to age recipients
+    recipients: bool,
+    /// Whether the message was padded before encryption
+    padded: bool,
+    /// How the values were changed, replaced before version 8
+    method: Method,
+    /// Whether the message is in the pixels that are not transparent only
+    transparent_skipped: bool,
+    /// Whether the message is kept to a region of the image
+    region: bool,
+}
+
+/// Which pixels of an image a message is in, as its header says.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Selection {
+    transparent_skipped: bool,
+    region: bool,
+}
+
+impl Selection {
+    const WHOLE: Selection = Selection {
+        transparent_skipped: false,
+        region: false,
+    };
+    const OPAQUE: Selection = Selection {
+        transparent_skipped: true,
+        region: false,
+    };
+}
+
+impl MessageHeader {
+    fn selection(&self) -> Selection {
+        Selection {
+            transparent_skipped: self.transparent_skipped,
+            region: self.region,
+        }
     }
 
-    let mut paths_with_sequence: Vec<(String, Option<(u32, u32)>)> = image_paths
-        .iter()
-        .map(|path| {
-            let seq_info = read_sequence_info(path).unwrap_or(None);
-            (path.clone(), seq_info)
+    /// Whether the header may belong to a message with `layout`. Before the
+    /// layout byte, only where the header was found tells.
+    fn allows_layout(&self, layout: u8) -> bool {
+        self.layout.is_none_or(|id| id == layout)
+    }
+
+    /// Shortest encrypted payload of this version: KDF salt and parameters,
+    /// key check, nonce and auth tag
+    fn min_length(&self) -> usize {
+        if self.plain || self.recipients {
+            return 0;
+        }
+        if self.chunked {
+            return self.cipher.chunked_header_bytes() + TAG_BYTES;
+        }
+        let kdf_bytes = if self.version == LEGACY_PROTOCOL_VERSION {
+            0
+        } else {
+            KDF_HEADER_BYTES
+        };
+        let check_bytes = if self.key_check { KEY_CHECK_BYTES } else { 0 };
+        kdf_bytes + check_bytes + 12 + 16
+    }
+
+    fn check_length(&self) -> Result<(), LowkeyError> {
+        if self.length < self.min_length() as u64 {
+            return Err(LowkeyError::NoMessage(format!(
+                "Payload of {} bytes is too short to be encrypted",
+                self.length
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Marks the in-band sequence header
+const SEQUENCE_MAGIC: &[u8; 4] = b"lKsq";
+
+/// Size of the in-band sequence header: magic + 4-byte index + 4-byte image
+/// count + 16-byte set ID
+const SEQUENCE_HEADER_BYTES: usize = 28;
+
+/// Channel values the in-band sequence header takes, at one bit each
+const SEQUENCE_HEADER_VALUES: usize = SEQUENCE_HEADER_BYTES * 8;
+
+/// Header at the start of every image of a multi-image message, before the
+/// message header in the first. Unlike the sequence metadata, it survives
+/// tools that strip metadata, so the images can still be put in order and
+/// checked to belong together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SequenceHeader {
+    index: u32,
+    total: u32,
+    /// Random, and the same in all images of one encode
+    set_id: [u8; 16],
+}
+
+impl SequenceHeader {
+    fn to_bits(self) -> BitVec<u8, Lsb0> {
+        let mut bytes = SEQUENCE_MAGIC.to_vec();
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.total.to_be_bytes());
+        bytes.extend_from_slice(&self.set_id);
+        convert_bytes_to_bits(&bytes)
+    }
+
+    /// Read a header from the next values of `reader`, if they hold one.
+    fn read(reader: &mut impl Iterator<Item = u8>) -> Option<Self> {
+        let bytes = read_bits(reader, SEQUENCE_HEADER_BYTES * 8)
+            .ok()?
+            .into_vec();
+        if &bytes[..4] != SEQUENCE_MAGIC {
+            return None;
+        }
+        let header = Self {
+            index: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            total: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            set_id: bytes[12..28].try_into().unwrap(),
+        };
+        (header.index < header.total).then_some(header)
+    }
+
+    /// The header at the start of `img`, and the channels it is in.
+    fn find(img: &RgbaImage) -> Option<(Self, Channels)> {
+        Channels::candidates().find_map(|channels| {
+            let mut reader = get_bits_reader_images(std::slice::from_ref(img), channels);
+            Self::read(&mut reader).map(|header| (header, channels))
         })
-        .collect();
+    }
+}
 
-    let all_have_sequence = paths_with_sequence
-        .iter()
-        .all(|(_, seq_info)| seq_info.is_some());
+/// Marks the spread header
+const SPREAD_MAGIC: &[u8; 4] = b"lKsp";
 
-    if all_have_sequence {
-        paths_with_sequence.sort_by_key(|(_, seq_info)| seq_info.unwrap().0);
-        println!("Detected sequence information in PNG metadata, using automatic ordering");
+/// Size of the spread header: magic + 4-byte value count
+const SPREAD_HEADER_BYTES: usize = 8;
+
+/// Channel values the spread header takes, at one bit each
+const SPREAD_HEADER_VALUES: usize = SPREAD_HEADER_BYTES * 8;
+
+/// Header after the sequence header of every image of an evenly spread set
+/// (see `Distribute::Even`), in the same channels: how many values after it
+/// carry the message, the message header's included. Without it, the
+/// message takes every value of an image before going on to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SpreadHeader {
+    values: u32,
+}
+
+impl SpreadHeader {
+    fn to_bits(self) -> BitVec<u8, Lsb0> {
+        let mut bytes = SPREAD_MAGIC.to_vec();
+        bytes.extend_from_slice(&self.values.to_be_bytes());
+        convert_bytes_to_bits(&bytes)
     }
 
-    let sorted_paths: Vec<String> = paths_with_sequence
-        .into_iter()
-        .map(|(path, _)| path)
-        .collect();
+    /// Read a header from the next values of `reader`, if they hold one.
+    fn read(reader: &mut impl Iterator<Item = u8>) -> Option<Self> {
+        let bytes = read_bits(reader, SPREAD_HEADER_BYTES * 8).ok()?.into_vec();
+        if &bytes[..4] != SPREAD_MAGIC {
+            return None;
+        }
+        Some(Self {
+            values: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+        })
+    }
 
-    let mut images: Vec<RgbaImage> = Vec::new();
-    for image_path in &sorted_paths {
-        check_image_png(image_path)?;
-        let img = image::open(image_path)
-            .map_err(|e| format!("Failed to open image '{}': {}", image_path, e))?
-            .to_rgba8();
-        images.push(img);
+    /// The header after the sequence header at the start of `img`, which is
+    /// in `channels`.
+    fn find(img: &RgbaImage, channels: Channels) -> Option<Self> {
+        let mut reader = get_bits_reader_images(std::slice::from_ref(img), channels);
+        SequenceHeader::read(&mut reader)?;
+        Self::read(&mut reader)
     }
+}
 
-    let mut reader = get_bits_reader_images(&images);
+/// Marks the shard header
+const SHARD_MAGIC: &[u8; 4] = b"lKsh";
 
-    let message_count = {
-        let header_count = 5;
-        let bits = read_bits(&mut reader, header_count * 8)?;
-        let header_bytes: [u8; 5] = std::array::from_fn(|i| {
-            (0..8).fold(0u8, |acc, j| {
-                acc | ((*bits.get(i * 8 + j).unwrap() as u8) << j)
-            })
-        });
+/// Size of the shard header: magic + shards needed + depth + 4-byte shard
+/// length + 8-byte checksum
+const SHARD_HEADER_BYTES: usize = 18;
 
-        let version = header_bytes[0];
-        let len_bytes: [u8; 4] = header_bytes[1..5].try_into().unwrap();
-        let count = u32::from_be_bytes(len_bytes);
+/// Channel values the shard header takes, at one bit each
+const SHARD_HEADER_VALUES: usize = SHARD_HEADER_BYTES * 8;
 
-        if version != PROTOCOL_VERSION {
-            return Err(format!(
-                "Unsupported protocol version {}. Expected version {}",
-                version, PROTOCOL_VERSION
-            ));
+/// Header after the sequence header of every image of an erasure-coded set
+/// (see `Redundancy::Shards`), in the same channels. The shard follows at
+/// `depth` bits per value; the message header is inside the shards, so an
+/// image can't be decoded on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ShardHeader {
+    /// Shards that rebuild the message
+    needed: u8,
+    depth: u8,
+    /// Shard length in bytes
+    length: u32,
+    /// Start of the SHA-256 of the shard, to pass over damaged ones
+    checksum: [u8; 8],
+}
+
+impl ShardHeader {
+    fn new(needed: u8, depth: u8, shard: &[u8]) -> Self {
+        Self {
+            needed,
+            depth,
+            length: shard.len() as u32,
+            checksum: shard_checksum(shard),
         }
+    }
 
-        count
-    };
+    fn to_bits(self) -> BitVec<u8, Lsb0> {
+        let mut bytes = SHARD_MAGIC.to_vec();
+        bytes.push(self.needed);
+        bytes.push(self.depth);
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes.extend_from_slice(&self.checksum);
+        convert_bytes_to_bits(&bytes)
+    }
 
-    let encrypted_bytes: Vec<_> = {
-        let bits = read_bits(&mut reader, message_count as usize * 8)?;
-        bits.chunks(8)
-            .map(|chunk| {
-                chunk
-                    .iter()
-                    .enumerate()
-                    .fold(0u8, |acc, (i, bit)| acc | ((*bit as u8) << i))
-            })
-            .collect()
-    };
+    /// Read a header from the next values of `reader`, if they hold one.
+    fn read(reader: &mut impl Iterator<Item = u8>) -> Option<Self> {
+        let bytes = read_bits(reader, SHARD_HEADER_BYTES * 8).ok()?.into_vec();
+        if &bytes[..4] != SHARD_MAGIC {
+            return None;
+        }
+        let header = Self {
+            needed: bytes[4],
+            depth: bytes[5],
+            length: u32::from_be_bytes(bytes[6..10].try_into().unwrap()),
+            checksum: bytes[10..18].try_into().unwrap(),
+        };
+        (header.needed > 0 && check_depth(header.depth).is_ok()).then_some(header)
+    }
+
+    /// The header after the sequence header at the start of `img`, which is
+    /// in `channels`, and a reader at the shard after it.
+    fn find(img: &RgbaImage, channels: Channels) -> Option<(Self, impl Iterator<Item = u8> + '_)> {
+        let mut reader = get_bits_reader_images(std::slice::from_ref(img), channels);
+        SequenceHeader::read(&mut reader)?;
+        Self::read(&mut reader).map(|header| (header, reader))
+    }
+}
+
+fn shard_checksum(shard: &[u8]) -> [u8; 8] {
+    Sha256::digest(shard)[..8].try_into().unwrap()
+}
 
-    let message_bytes = crypto::decrypt(&encrypted_bytes, key)?;
+/// Marks a message appended behind the others in an image
+const SLOT_MAGIC: &[u8; 4] = b"lKsl";
 
-    if let Some(parent) = Path::new(output_file).parent() {
-        fs::create_dir_all(parent)
-            .map_err(|e| format!("Failed to create output directory: {}", e))?;
+/// Channel values the slot magic takes, at one bit each
+const SLOT_MAGIC_VALUES: usize = SLOT_MAGIC.len() * 8;
+
+/// One of the messages chained in an image, as far as its header tells
+/// without the key. The first is an ordinary sequential message; each one
+/// appended behind it (see `append_to_file`) starts at the next pixel with
+/// the slot magic, in its own channels, and has a header and key of its own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Slot {
+    pub version: u8,
+    /// Encrypted payload length
+    pub length: u64,
+    /// Message bits per channel value after the header
+    pub depth: u8,
+    /// Channels that carry the magic, the header and the message
+    pub channels: Channels,
+    /// Whether the message starts with file metadata
+    pub file: bool,
+    /// Whether the message is an archive of several files
+    pub archive: bool,
+    /// Whether the message was compressed before encryption
+    pub compressed: bool,
+    /// Whether the message is encrypted to age recipients instead of the key
+    pub recipients: bool,
+    /// Whether the message is not encrypted at all
+    pub plain: bool,
+    /// Whether the key given to `list_slots` opens the message; `None`
+    /// without a key, or for a message not encrypted with one
+    pub opens: Option<bool>,
+    /// Index in the RGBA buffer of the pixel the slot starts at
+    start: usize,
+    /// Index in the RGBA buffer of the value after the last one it takes
+    end: usize,
+}
+
+impl Slot {
+    /// Reader at the header of the slot's message.
+    fn reader<'a>(&self, img: &'a RgbaImage) -> impl Iterator<Item = u8> + 'a {
+        let magic = if self.start == 0 {
+            0
+        } else {
+            SLOT_MAGIC_VALUES
+        };
+        slot_values(img, self.start, self.channels).skip(magic)
     }
+}
 
-    let mut file = File::create(output_file)
-        .map_err(|e| format!("Failed to create output file '{}': {}", output_file, e))?;
-    file.write_all(&message_bytes)
-        .map_err(|e| format!("Failed to write to output file: {}", e))?;
+/// Values of `channels` of `img` from the pixel at index `start` of its RGBA
+/// buffer on.
+fn slot_values(img: &RgbaImage, start: usize, channels: Channels) -> impl Iterator<Item = u8> + '_ {
+    img.as_raw()[start..]
+        .iter()
+        .enumerate()
+        .filter(move |&(i, _)| channels.contains(i))
+        .map(|(_, &value)| value)
+}
 
-    Ok(())
+/// The messages chained in `img`, in order: none if it does not start with
+/// a sequential message of the whole image.
+fn image_slots(img: &RgbaImage) -> Vec<Slot> {
+    let mut slots: Vec<Slot> = Vec::new();
+    let mut start = 0;
+    while start < img.len() {
+        let magic = if start == 0 { 0 } else { SLOT_MAGIC_VALUES };
+        let found = Channels::candidates().find_map(|channels| {
+            let mut reader = slot_values(img, start, channels);
+            if magic > 0 && read_bits(&mut reader, magic).ok()?.into_vec() != SLOT_MAGIC {
+                return None;
+            }
+            let header = read_message_header(&mut reader).ok()?;
+            if header.check_length().is_err()
+                || header.channels != channels
+                || !header.allows_layout(LAYOUT_SEQUENTIAL)
+                || header.selection() != Selection::WHOLE
+            {
+                return None;
+            }
+            let values = magic
+                + header_bytes(header.version) * 8
+                + (header.length as usize)
+                    .saturating_mul(8)
+                    .div_ceil(header.depth as usize);
+            if values > (img.len() - start) / 4 * channels.count() {
+                return None;
+            }
+            Some(Slot {
+                version: header.version,
+                length: header.length,
+                depth: header.depth,
+                channels,
+                file: header.file,
+                archive: header.archive,
+                compressed: header.compressed,
+                recipients: header.recipients,
+                plain: header.plain,
+                opens: None,
+                start,
+                end: start + channels.index_of(values),
+            })
+        });
+        match found {
+            Some(slot) => {
+                start = slot.end.next_multiple_of(4);
+                slots.push(slot);
+            }
+            None => break,
+        }
+    }
+    slots
 }
 
-fn get_message_header_bytes(body_bytes: &[u8]) -> [u8; 5] {
-    let message_len = body_bytes.len() as u32;
-    let message_len_bytes = message_len.to_be_bytes();
+/// What can be learned about a stego image without the key.
+pub struct Inspection {
+    /// Protocol version from the header, `None` if the image is not the first of a sequence
+    pub version: Option<u8>,
+    /// Encrypted payload length from the header, `None` if the image is not the first of a sequence
+    pub payload_length: Option<u64>,
+    /// Message bits per channel value from the header, `None` if the image is not the first of a sequence
+    pub depth: Option<u8>,
+    /// Channels carrying the message, from the header, `None` if the image is not the first of a sequence
+    pub channels: Option<Channels>,
+    /// Whether the message was compressed, from the header, `None` if the image is not the first of a sequence
+    pub compressed: Option<bool>,
+    /// Whether the message is error-corrected, from the header, `None` if the image is not the first of a sequence
+    pub ecc: Option<bool>,
+    /// Whether the message carries file metadata, from the header, `None` if the image is not the first of a sequence
+    pub file_info: Option<bool>,
+    /// Whether the message is an archive of several files, from the header, `None` if the image is not the first of a sequence
+    pub archive: Option<bool>,
+    /// Whether the message is encrypted, from the header, `None` if the image is not the first of a sequence
+    pub encrypted: Option<bool>,
+    /// Cipher the message is encrypted with, from the header, `None` if it is not encrypted with the key or the image is not the first of a sequence
+    pub cipher: Option<Cipher>,
+    /// Whether the message is encrypted to age recipients, from the header, `None` if the image is not the first of a sequence
+    pub recipients: Option<bool>,
+    /// Whether the message was padded to a fixed size, from the header, `None` if the image is not the first of a sequence
+    pub padded: Option<bool>,
+    /// How the values were changed, from the header, `None` if the image is not the first of a sequence
+    pub method: Option<Method>,
+    /// Whether transparent pixels were left out, from the header, `None` if the image is not the first of a sequence
+    pub transparent_skipped: Option<bool>,
+    /// Argon2id parameters stored with the encrypted payload, `None` before
+    /// version 1 or if the image is not the first of a sequence
+    pub kdf: Option<KdfParams>,
+    /// Ways in which the header and payload do not fit together, empty for
+    /// what looks like a genuine message
+    pub problems: Vec<String>,
+    /// `(index, total)` from the sequence metadata or, without it, the
+    /// in-band sequence header, if present
+    pub sequence_info: Option<(u32, u32)>,
+    /// For a shard of an erasure-coded set, how many of the set's images
+    /// rebuild the message
+    pub shards_needed: Option<u8>,
+    /// Maximum plaintext message size the image can hold, in bytes
+    pub capacity: usize,
+}
 
-    let mut head = [0u8; 5];
-    head[0] = PROTOCOL_VERSION;
-    head[1..5].copy_from_slice(&message_len_bytes);
+/// What an encode wrote, with how detectable each image is.
+#[derive(Default, Serialize)]
+pub struct EncodeReport {
+    pub images: Vec<EncodedImage>,
+    /// Covers passed through untouched: too small (see `skip_small` of
+    /// `encode_from_files`) or not needed for the message
+    pub unused: Vec<String>,
+}
 
-    head
+#[derive(Serialize)]
+pub struct EncodedImage {
+    pub path: String,
+    /// The (local copy of the) cover it was made from
+    #[serde(skip)]
+    pub cover: String,
+    /// Visual difference from the cover
+    pub quality: Quality,
+    pub detectability: Detectability,
 }
 
-fn get_message_body_bytes(message_bytes: &[u8], key: &str) -> Result<Vec<u8>, String> {
-    let encrypted_bytes = crypto::encrypt(message_bytes, key)?;
-    Ok(encrypted_bytes)
+impl EncodeReport {
+    /// Detectability of every image, one block per image, and the covers
+    /// left untouched.
+    pub fn summary(&self) -> String {
+        let mut lines = self
+            .images
+            .iter()
+            .map(|image| {
+                format!(
+                    "{}: {}; {}",
+                    image.path,
+                    image.quality.summary(),
+                    image.detectability.summary()
+                )
+            })
+            .collect::<Vec<_>>();
+        if !self.unused.is_empty() {
+            lines.push(format!("Not used: {}", self.unused.join(", ")));
+        }
+        lines.join("\n")
+    }
 }
 
-fn get_message_bits(message_bytes: &[u8], key: &str) -> Result<BitVec<u8, Lsb0>, String> {
-    let body_bytes = get_message_body_bytes(message_bytes, key)?;
-    let header_bytes = get_message_header_bytes(&body_bytes);
+pub fn encode_from_file(
+    input_image: &str,
+    message_bytes: &[u8],
+    output_image: &str,
+    auto_resize: bool,
+    layout: Layout,
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<EncodeReport, LowkeyError> {
+    check_image_png(output_image)?;
+
+    // A 16-bit cover stays 16-bit in a PNG, with the message in the low bytes
+    let keep_depth = OutputFormat::from_path(output_image) == OutputFormat::Png;
+    let (mut img, mut wide) = metrics::time_stage("read", || {
+        read_cover(input_image, keep_depth, payload.convert_srgb)
+    })?;
+
+    let (depth, channels) = (layout.depth(), layout.channels());
+    check_depth(depth)?;
+    if payload.method == Method::LsbMatch && layout.by_texture() {
+        return Err(LowkeyError::InvalidInput(
+            "LSB matching cannot be combined with noise matching or adaptive embedding".to_string(),
+        ));
+    }
+    if payload.skip_transparent && layout.by_texture() {
+        return Err(LowkeyError::InvalidInput(
+            "Transparent pixels cannot be skipped with noise matching or adaptive embedding"
+                .to_string(),
+        ));
+    }
+    if payload.region.is_some() {
+        if layout.by_texture() {
+            return Err(LowkeyError::InvalidInput(
+                "A region cannot be combined with noise matching or adaptive embedding".to_string(),
+            ));
+        }
+        if auto_resize {
+            return Err(LowkeyError::InvalidInput(
+                "A region cannot be combined with resizing the image".to_string(),
+            ));
+        }
+        if payload.decoy.is_some() {
+            return Err(LowkeyError::InvalidInput(
+                "A region cannot be combined with a decoy".to_string(),
+            ));
+        }
+    }
+
+    let decoy = match &payload.decoy {
+        Some(decoy) => Some(get_decoy_bits(decoy, key, payload, layout)?),
+        None => None,
+    };
+    let decoy_len = decoy
+        .as_ref()
+        .map_or(0, |(header, body)| header.len() + body.len());
+    // Values the decoy takes, which the message goes after
+    let reserved = decoy.as_ref().map_or(0, |(header, body)| {
+        header.len() + body.len().div_ceil(depth as usize)
+    });
+
+    let filled;
+    let payload = match payload.pad_to {
+        Some(PadTo::Full) => {
+            if auto_resize {
+                return Err(LowkeyError::InvalidInput(
+                    "Padding to the full image cannot be combined with resizing it".to_string(),
+                ));
+            }
+            if layout == Layout::NoiseMatched {
+                return Err(LowkeyError::InvalidInput(
+                    "Padding to the full image is not supported with noise matching".to_string(),
+                ));
+            }
+            let values = match layout {
+                Layout::Adaptive { depth, channels } => adaptive_capacity(
+                    &textured_values(&img, depth),
+                    channels,
+                    channels.index_of(reserved),
+                ),
+                _ => match selected_pixels(&img, key, payload)? {
+                    Some((pixels, _)) => (pixels.len() * channels.count()).saturating_sub(reserved),
+                    None => channel_values(&img, channels).saturating_sub(reserved),
+                },
+            };
+            filled = PayloadOptions {
+                pad_to: Some(PadTo::Bytes(body_capacity(values, depth))),
+                ..payload.clone()
+            };
+            &filled
+        }
+        _ => payload,
+    };
+    let (header, body) = metrics::time_stage("encrypt", || {
+        get_message_bits(message_bytes, key, payload, layout)
+    })?;
+
+    if auto_resize {
+        // Sized for the message as embedded, after compression and with the
+        // encryption overhead, and for all four channels, so scaled up for fewer
+        let message_len =
+            ((header.len() + body.len() + decoy_len).div_ceil(8) * 4).div_ceil(channels.count());
+        match wide.take() {
+            Some(full) => {
+                let resized = metrics::time_stage("resize", || {
+                    resize_image(full, message_len, MIN_DIMENSION)
+                });
+                img = low_bytes(&resized);
+                wide = Some(resized);
+            }
+            None => {
+                img =
+                    metrics::time_stage("resize", || resize_image(img, message_len, MIN_DIMENSION));
+            }
+        }
+    }
 
-    let mut data = Vec::new();
-    data.extend_from_slice(&header_bytes);
-    data.extend_from_slice(&body_bytes);
+    let cover = img.clone();
+    // Without its transparent pixels or kept to a region, the image is
+    // embedded in as a row of the pixels left, and the whole of it waits
+    // alongside for the row to be put back
+    let selected = selected_pixels(&cover, key, payload)?.map(|(pixels, record_bits)| {
+        let row = take_pixels(&cover, &pixels);
+        (pixels, record_bits, std::mem::replace(&mut img, row))
+    });
+    // Against the capacity at one bit per value, so deeper embedding is not
+    // reported as less detectable
+    let fill_ratio =
+        (header.len() + body.len() + decoy_len) as f64 / channel_values(&cover, channels) as f64;
+    if let Some(limit) = payload.max_fill {
+        limit.check(input_image, fill_ratio)?;
+    }
+    let message_values = header.len() + body.len().div_ceil(depth as usize);
+    metrics::time_stage("embed", || match layout {
+        Layout::Sequential { depth, channels } => {
+            embed_sequential(&mut img, &header, &body, depth, channels)?;
+            if payload.noise_fill {
+                fill_noise_at(&mut img, message_values, depth, channels);
+            }
+            Ok(())
+        }
+        Layout::NoiseMatched => {
+            let mut bits = header.clone();
+            bits.extend_from_bitslice(&body);
+            set_bits_image_noise_matched(&mut img, &bits, key)
+        }
+        Layout::Scattered { depth, channels } => {
+            // The decoy goes first, and the message into the values after it
+            if let Some((decoy_header, decoy_body)) = &decoy {
+                embed_sequential(&mut img, decoy_header, decoy_body, depth, channels)?;
+            }
+            embed_scattered(&mut img, &header, &body, depth, channels, key, reserved)?;
+            if payload.noise_fill {
+                let start = channels.index_of(reserved);
+                let unused = scattered_positions(&img, key, channels, start).skip(message_values);
+                fill_noise_positions(&mut img, unused, depth);
+            }
+            Ok(())
+        }
+        Layout::Adaptive { depth, channels } => {
+            if let Some((decoy_header, decoy_body)) = &decoy {
+                embed_sequential(&mut img, decoy_header, decoy_body, depth, channels)?;
+            }
+            let textured =
+                embed_adaptive(&mut img, &header, &body, depth, channels, key, reserved)?;
+            if payload.noise_fill {
+                let start = channels.index_of(reserved);
+                let unused =
+                    adaptive_positions(&img, key, channels, start, &textured).skip(message_values);
+                fill_noise_positions(&mut img, unused, depth);
+            }
+            Ok(())
+        }
+    })?;
+    if let Some((pixels, record_bits, whole)) = selected {
+        let row = std::mem::replace(&mut img, whole);
+        put_pixels(&mut img, &pixels, &row);
+        let values = img.as_mut();
+        for (position, bit) in record_bits {
+            values[position] = (values[position] & !1) | bit as u8;
+        }
+    }
+    if payload.method == Method::LsbMatch {
+        metrics::time_stage("embed", || match_changes(&cover, &mut img, depth));
+    }
 
-    Ok(convert_bytes_to_bits(&data))
+    if let Some(parent) = Path::new(output_image).parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| LowkeyError::Io(format!("Failed to create output directory: {}", e)))?;
+    }
+
+    metrics::time_stage("write", || match &mut wide {
+        Some(wide) => {
+            set_low_bytes(wide, &img);
+            save_rgba16_with_metadata(
+                wide,
+                output_image,
+                input_image,
+                None,
+                payload.keep_metadata(),
+                payload.png_encoding,
+            )
+        }
+        None => save_rgba_with_metadata(
+            &img,
+            output_image,
+            input_image,
+            None,
+            payload.keep_metadata(),
+            payload.png_encoding,
+        ),
+    })?;
+    metrics::record_bytes_embedded(message_bytes.len());
+
+    Ok(EncodeReport {
+        images: vec![EncodedImage {
+            path: output_image.to_string(),
+            cover: input_image.to_string(),
+            quality: quality(&cover, &img)?,
+            detectability: detectability(&img, fill_ratio, layout, payload.method),
+        }],
+        unused: Vec::new(),
+    })
+}
+
+/// Hide `message_bytes` in the image at `input_image` behind the messages it
+/// already holds, which are left as they are, each with its own key, and
+/// write the result to `output_image`. The new message is embedded in the
+/// slot after the last one (see `Slot`), so there must be a sequential
+/// message at the start of the image. A message scattered behind a decoy
+/// can't be told from the values it leaves unused, and is lost.
+pub fn append_to_file(
+    input_image: &str,
+    message_bytes: &[u8],
+    output_image: &str,
+    depth: u8,
+    channels: Channels,
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<EncodeReport, LowkeyError> {
+    check_image_png(output_image)?;
+    check_depth(depth)?;
+    if payload.decoy.is_some()
+        || payload.skip_transparent
+        || payload.region.is_some()
+        || payload.pad_to == Some(PadTo::Full)
+        || payload.convert_srgb
+    {
+        return Err(LowkeyError::InvalidInput(
+            "An appended message cannot have a decoy, skip transparent pixels, be kept to a region, be padded to the full image or convert the image to sRGB"
+                .to_string(),
+        ));
+    }
+
+    let keep_depth = OutputFormat::from_path(output_image) == OutputFormat::Png;
+    let (mut img, mut wide) =
+        metrics::time_stage("read", || read_cover(input_image, keep_depth, false))?;
+    let slots = image_slots(&img);
+    let last = slots.last().ok_or_else(|| {
+        LowkeyError::NoMessage(format!(
+            "'{}' holds no sequential lowkey message to append to",
+            input_image
+        ))
+    })?;
+    let start = last.end.next_multiple_of(4);
+    // Values of `channels` before the new slot, which it leaves alone
+    let before = start / 4 * channels.count();
+
+    let (header, body) = metrics::time_stage("encrypt", || {
+        get_message_bits(
+            message_bytes,
+            key,
+            payload,
+            Layout::Sequential { depth, channels },
+        )
+    })?;
+    let mut slot_header = convert_bytes_to_bits(SLOT_MAGIC);
+    slot_header.extend_from_bitslice(&header);
+    check_capacity_values(
+        channel_values(&img, channels).saturating_sub(before),
+        &slot_header,
+        &body,
+        depth,
+    )
+    .map_err(|e| {
+        e.map_message(|e| format!("{} (after the {} message(s) already there)", e, slots.len()))
+    })?;
+
+    let cover = img.clone();
+    let fill_ratio =
+        (before + slot_header.len() + body.len()) as f64 / channel_values(&cover, channels) as f64;
+    if let Some(limit) = payload.max_fill {
+        limit.check(input_image, fill_ratio)?;
+    }
+    metrics::time_stage("embed", || {
+        set_bits_image_at(&mut img, &slot_header, before, 1, channels)?;
+        set_bits_image_at(&mut img, &body, before + slot_header.len(), depth, channels)?;
+        if payload.noise_fill {
+            let end = before + slot_header.len() + body.len().div_ceil(depth as usize);
+            fill_noise_at(&mut img, end, depth, channels);
+        }
+        if payload.method == Method::LsbMatch {
+            match_changes(&cover, &mut img, depth);
+        }
+        Ok::<_, LowkeyError>(())
+    })?;
+
+    if let Some(parent) = Path::new(output_image).parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| LowkeyError::Io(format!("Failed to create output directory: {}", e)))?;
+    }
+    metrics::time_stage("write", || match &mut wide {
+        Some(wide) => {
+            set_low_bytes(wide, &img);
+            save_rgba16_with_metadata(
+                wide,
+                output_image,
+                input_image,
+                None,
+                payload.keep_metadata(),
+                payload.png_encoding,
+            )
+        }
+        None => save_rgba_with_metadata(
+            &img,
+            output_image,
+            input_image,
+            None,
+            payload.keep_metadata(),
+            payload.png_encoding,
+        ),
+    })?;
+    metrics::record_bytes_embedded(message_bytes.len());
+
+    Ok(EncodeReport {
+        images: vec![EncodedImage {
+            path: output_image.to_string(),
+            cover: input_image.to_string(),
+            quality: quality(&cover, &img)?,
+            detectability: detectability(
+                &img,
+                fill_ratio,
+                Layout::Sequential { depth, channels },
+                payload.method,
+            ),
+        }],
+        unused: Vec::new(),
+    })
+}
+
+/// Encode a message over several covers, laid out as `set` says, writing
+/// one output per cover used into `output_dir`.
+pub fn encode_from_files(
+    input_images: &[String],
+    message_bytes: &[u8],
+    output_dir: &str,
+    set: &SetOptions,
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<EncodeReport, LowkeyError> {
+    let SetOptions {
+        depth,
+        channels,
+        auto_resize,
+        skip_small,
+        redundancy,
+        distribute,
+        ..
+    } = *set;
+    let output_template = set.output_template.as_ref();
+    if input_images.is_empty() {
+        return Err(LowkeyError::InvalidInput(
+            "No input images provided".to_string(),
+        ));
+    }
+    if payload.decoy.is_some() {
+        return Err(LowkeyError::InvalidInput(
+            "A decoy is only supported with a single cover".to_string(),
+        ));
+    }
+    if payload.region.is_some() {
+        return Err(LowkeyError::InvalidInput(
+            "A region is only supported with a single cover".to_string(),
+        ));
+    }
+    if payload.skip_transparent {
+        return Err(LowkeyError::InvalidInput(
+            "Skipping transparent pixels is only supported with a single cover".to_string(),
+        ));
+    }
+    if distribute == Distribute::Even && redundancy != Redundancy::None {
+        return Err(LowkeyError::InvalidInput(
+            "An even distribution is only supported without redundancy".to_string(),
+        ));
+    }
+
+    // Covers from different subdirectories can share a name, and would
+    // overwrite each other in the output directory
+    let mut output_names = HashMap::new();
+    for (i, image_path) in input_images.iter().enumerate() {
+        let vars = NameVars {
+            cover: image_path,
+            index: i + 1,
+            total: input_images.len(),
+            set_id: &[0; 16],
+        };
+        let name = output_name(output_template, &vars)?;
+        if let Some(other) = output_names.get(&name) {
+            return Err(LowkeyError::InvalidInput(format!(
+                "'{}' and '{}' would both be written to '{}' in the output directory",
+                other, image_path, name
+            )));
+        }
+        output_names.insert(name, image_path);
+    }
+
+    // Only the size of each cover is read up front; its pixels are read when
+    // the message gets to it, and let go of once it is written
+    let mut covers = metrics::time_stage("read", || {
+        input_images
+            .iter()
+            .map(|image_path| PlannedCover::probe(image_path))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    fs::create_dir_all(output_dir)
+        .map_err(|e| LowkeyError::Io(format!("Failed to create output directory: {}", e)))?;
+
+    check_depth(depth)?;
+    // Shards carry the message as bytes, which are read back a bit at a time
+    let message_depth = match redundancy {
+        Redundancy::Shards { .. } => 1,
+        _ => depth,
+    };
+    let (header, body) = metrics::time_stage("encrypt", || {
+        get_message_bits(
+            message_bytes,
+            key,
+            payload,
+            Layout::Sequential {
+                depth: message_depth,
+                channels,
+            },
+        )
+    })?;
+
+    // Evenly spread or kept under a fill limit, every image says how much of
+    // it is the message's
+    let spread = distribute == Distribute::Even || payload.max_fill.is_some();
+    let reserved = match spread {
+        true => SEQUENCE_HEADER_VALUES + SPREAD_HEADER_VALUES,
+        false => SEQUENCE_HEADER_VALUES,
+    };
+
+    // Covers that can't even hold the headers would only fail the encode
+    if skip_small {
+        let needed = reserved + header.len();
+        covers.retain(|cover| cover.values(channels) > needed);
+        if covers.is_empty() {
+            return Err(LowkeyError::Capacity(
+                "No cover is large enough to hold the message headers".to_string(),
+            ));
+        }
+    }
+
+    if let Redundancy::Shards { needed, total } = redundancy {
+        let mut report = encode_shards(
+            &mut covers,
+            &header,
+            &body,
+            output_dir,
+            set,
+            (needed, total),
+            payload,
+        )?;
+        metrics::record_bytes_embedded(message_bytes.len());
+        report.unused = unused_covers(input_images, &report);
+        return Ok(report);
+    }
+
+    if redundancy == Redundancy::Full {
+        let mut report = encode_copies(&mut covers, &header, &body, output_dir, set, payload)?;
+        metrics::record_bytes_embedded(message_bytes.len());
+        report.unused = unused_covers(input_images, &report);
+        return Ok(report);
+    }
+
+    if auto_resize {
+        // The headers take a value per bit, and a fill limit leaves room
+        let values = covers.len() * reserved + header.len() + body.len().div_ceil(depth as usize);
+        let values = payload.max_fill.map_or(values, |limit| {
+            (values as f64 / limit.ratio).ceil() as usize
+        });
+        resize_covers(&mut covers, values, channels);
+    }
+
+    check_capacity_values(
+        covers
+            .iter()
+            .map(|cover| cover.values(channels).saturating_sub(reserved))
+            .sum(),
+        &header,
+        &body,
+        depth,
+    )?;
+
+    let total_bits = body.len();
+    // Filled only up to the limit, the message goes on to more covers
+    let mut max_fill = payload.max_fill;
+    if let (Distribute::Fill, Some(limit)) = (distribute, max_fill) {
+        let room: usize = covers
+            .iter()
+            .enumerate()
+            .map(|(i, cover)| {
+                let start = reserved + if i == 0 { header.len() } else { 0 };
+                body_room(cover.values(channels), start, depth, max_fill)
+            })
+            .sum();
+        if room < total_bits {
+            let problem = format!(
+                "The message does not fit in the covers filled up to {:.1}% each",
+                limit.ratio * 100.0
+            );
+            if !limit.warn_only {
+                return Err(LowkeyError::Capacity(format!(
+                    "{}; use larger covers or more of them",
+                    problem
+                )));
+            }
+            eprintln!("Warning: {}", problem);
+            max_fill = None;
+        }
+    }
+    let shares = match (distribute, spread) {
+        (Distribute::Even, _) => Some(spread_shares(
+            &covers,
+            header.len(),
+            total_bits,
+            depth,
+            channels,
+        )),
+        (Distribute::Fill, true) => Some(filled_shares(
+            &covers,
+            header.len(),
+            total_bits,
+            depth,
+            channels,
+            max_fill,
+        )),
+        (Distribute::Fill, false) => None,
+    };
+    if let (Some(shares), Some(limit)) = (&shares, max_fill) {
+        for (cover, share) in covers.iter().zip(shares) {
+            let fill = (reserved + share) as f64 / cover.values(channels) as f64;
+            limit.check(&cover.path, fill)?;
+        }
+    }
+    // The images after the end of the message are left out of the sequence
+    let images_count = match &shares {
+        Some(shares) => shares.len(),
+        None => images_used(&covers, header.len(), total_bits, depth, channels),
+    };
+    let set_id = crypto::random_id();
+    let mut cursor = 0usize;
+    let mut report = EncodeReport::default();
+
+    let names = covers
+        .iter()
+        .map(|cover| cover.path.clone())
+        .collect::<Vec<_>>();
+    let progress = Progress::new("Encoding", file_names(&names));
+    progress.set_total(header.len() + total_bits.div_ceil(depth as usize));
+    let saved = |(i, path): (usize, String)| {
+        progress.message(&format!(
+            "Saved encoded image {}/{}: {}",
+            i + 1,
+            images_count,
+            path
+        ))
+    };
+    // Each image is compressed and written on a worker while the next is embedded
+    let mut writes = WritePool::new();
+
+    for (i, planned) in covers.iter().take(images_count).enumerate() {
+        let (mut img, wide) = planned.read(payload.convert_srgb)?;
+        let (image_path, img) = (&planned.path, &mut img);
+        let cover = img.clone();
+        // Every image opens with its sequence header and the first then with
+        // the message header, both at one bit per value
+        let sequence = SequenceHeader {
+            index: i as u32,
+            total: images_count as u32,
+            set_id,
+        };
+        let start = metrics::time_stage("embed", || {
+            set_bits_image_at(img, &sequence.to_bits(), 0, 1, channels)?;
+            if i == 0 {
+                set_bits_image_at(img, &header, reserved, 1, channels)?;
+                Ok::<_, LowkeyError>(reserved + header.len())
+            } else {
+                Ok(reserved)
+            }
+        })?;
+        let header_values = start - reserved;
+
+        let image_capacity_bits = match &shares {
+            Some(shares) => (shares[i] - header_values) * depth as usize,
+            None => channel_values(img, channels).saturating_sub(start) * depth as usize,
+        };
+        let bits_to_encode = std::cmp::min(image_capacity_bits, total_bits - cursor);
+        let next_cursor = cursor + bits_to_encode;
+        if spread {
+            let values = (header_values + bits_to_encode.div_ceil(depth as usize)) as u32;
+            let bits = SpreadHeader { values }.to_bits();
+            set_bits_image_at(img, &bits, SEQUENCE_HEADER_VALUES, 1, channels)?;
+        }
+
+        // Progress is counted in values of the message, without sequence headers
+        progress.start_image(i, header_values + bits_to_encode.div_ceil(depth as usize));
+        progress.advance(header_values);
+        metrics::time_stage("embed", || {
+            set_bits_image_at_tracked(
+                img,
+                &body[cursor..next_cursor],
+                start,
+                depth,
+                channels,
+                &progress,
+            )
+        })?;
+        let used = start + bits_to_encode.div_ceil(depth as usize);
+        if payload.noise_fill {
+            fill_noise_at(img, used, depth, channels);
+        }
+        if payload.method == Method::LsbMatch {
+            metrics::time_stage("embed", || match_changes(&cover, img, depth));
+        }
+        cursor = next_cursor;
+
+        let vars = NameVars {
+            cover: image_path,
+            index: i + 1,
+            total: images_count,
+            set_id: &set_id,
+        };
+        let output_path = Path::new(output_dir).join(output_name(output_template, &vars)?);
+        let output_path_str = output_path.to_string_lossy().to_string();
+
+        let (index, total) = (i as u32, images_count as u32);
+        let sequence_info = match payload.sequence_chunk {
+            SequenceChunk::Plain => Some(SequenceInfo::Plain(index, total)),
+            SequenceChunk::Encrypted => {
+                let mut plain = index.to_be_bytes().to_vec();
+                plain.extend_from_slice(&total.to_be_bytes());
+                Some(SequenceInfo::Sealed(crypto::encrypt(&plain, key)?))
+            }
+            SequenceChunk::Off => None,
+        };
+
+        let fill_ratio = used as f64 / channel_values(img, channels) as f64;
+        report.images.push(EncodedImage {
+            path: output_path_str.clone(),
+            cover: image_path.to_string(),
+            quality: quality(&cover, img)?,
+            detectability: detectability(
+                img,
+                fill_ratio,
+                Layout::Sequential { depth, channels },
+                payload.method,
+            ),
+        });
+        let job = write_output(
+            std::mem::take(img),
+            wide,
+            output_path_str.clone(),
+            image_path.clone(),
+            sequence_info,
+            payload,
+        );
+        writes
+            .submit((i, output_path_str), job)?
+            .into_iter()
+            .for_each(saved);
+    }
+    writes.finish()?.into_iter().for_each(saved);
+
+    metrics::record_bytes_embedded(message_bytes.len());
+    report.unused = unused_covers(input_images, &report);
+
+    Ok(report)
+}
+
+/// The covers among `input_images` that `report` wrote no output for.
+fn unused_covers(input_images: &[String], report: &EncodeReport) -> Vec<String> {
+    input_images
+        .iter()
+        .filter(|path| !report.images.iter().any(|image| &image.cover == *path))
+        .cloned()
+        .collect()
+}
+
+/// A `WritePool` job that writes `img` to `output_path` with the metadata of
+/// `cover`, as a 16-bit PNG if the cover had 16-bit values and the output is
+/// a PNG.
+fn write_output(
+    img: RgbaImage,
+    wide: Option<Rgba16Image>,
+    output_path: String,
+    cover: String,
+    sequence_info: Option<SequenceInfo>,
+    payload: &PayloadOptions,
+) -> impl FnOnce() -> Result<(), LowkeyError> + Send + 'static {
+    let (keep, encoding) = (payload.keep_metadata(), payload.png_encoding);
+    move || {
+        metrics::time_stage("write", || match wide {
+            // A template can name a 16-bit cover's output as another format
+            Some(mut wide) if OutputFormat::from_path(&output_path) == OutputFormat::Png => {
+                set_low_bytes(&mut wide, &img);
+                save_rgba16_with_metadata(
+                    &wide,
+                    &output_path,
+                    &cover,
+                    sequence_info.as_ref(),
+                    keep,
+                    encoding,
+                )
+            }
+            _ => save_rgba_with_metadata(
+                &img,
+                &output_path,
+                &cover,
+                sequence_info.as_ref(),
+                keep,
+                encoding,
+            ),
+        })
+    }
+}
+
+/// Embed a complete copy of the message in each of `images` that can hold
+/// one, as image 1 of 1 of a set they all share, and leave out the others.
+fn encode_copies(
+    covers: &mut [PlannedCover],
+    header: &BitSlice<u8, Lsb0>,
+    body: &BitSlice<u8, Lsb0>,
+    output_dir: &str,
+    set: &SetOptions,
+    payload: &PayloadOptions,
+) -> Result<EncodeReport, LowkeyError> {
+    let (depth, channels) = (set.depth, set.channels);
+    let output_template = set.output_template.as_ref();
+    // The headers take a value per bit
+    let values = SEQUENCE_HEADER_VALUES + header.len() + body.len().div_ceil(depth as usize);
+    if set.auto_resize {
+        for cover in covers.iter_mut() {
+            resize_covers(std::slice::from_mut(cover), values, channels);
+        }
+    }
+
+    let mut copies = Vec::new();
+    for (i, cover) in covers.iter().enumerate() {
+        let fill = values as f64 / cover.values(channels) as f64;
+        match check_capacity_values(
+            cover
+                .values(channels)
+                .saturating_sub(SEQUENCE_HEADER_VALUES),
+            header,
+            body,
+            depth,
+        )
+        .and_then(|()| match payload.max_fill {
+            Some(limit) => limit.check(&cover.path, fill),
+            None => Ok(()),
+        }) {
+            Ok(()) => copies.push(i),
+            Err(e) => eprintln!("Skipping {}: {}", cover.path, e),
+        }
+    }
+    if copies.is_empty() {
+        return Err(LowkeyError::Capacity(
+            "No cover is large enough for a complete copy of the message".to_string(),
+        ));
+    }
+
+    let set_id = crypto::random_id();
+    let sequence = SequenceHeader {
+        index: 0,
+        total: 1,
+        set_id,
+    }
+    .to_bits();
+    let mut report = EncodeReport::default();
+    let names = copies
+        .iter()
+        .map(|&i| covers[i].path.clone())
+        .collect::<Vec<_>>();
+    let progress = Progress::new("Encoding", file_names(&names));
+    let message_values = header.len() + body.len().div_ceil(depth as usize);
+    progress.set_total(copies.len() * message_values);
+    let saved = |(copy, path): (usize, String)| {
+        progress.message(&format!(
+            "Saved copy {}/{}: {}",
+            copy + 1,
+            copies.len(),
+            path
+        ))
+    };
+    let mut writes = WritePool::new();
+
+    for (copy, &i) in copies.iter().enumerate() {
+        let planned = &covers[i];
+        let (mut img, wide) = planned.read(payload.convert_srgb)?;
+        let (image_path, img) = (&planned.path, &mut img);
+        let cover = img.clone();
+        let start = SEQUENCE_HEADER_VALUES + header.len();
+
+        progress.start_image(copy, message_values);
+        progress.advance(header.len());
+        metrics::time_stage("embed", || {
+            set_bits_image_at(img, &sequence, 0, 1, channels)?;
+            set_bits_image_at(img, header, SEQUENCE_HEADER_VALUES, 1, channels)?;
+            set_bits_image_at_tracked(img, body, start, depth, channels, &progress)
+        })?;
+        if payload.noise_fill {
+            fill_noise_at(img, values, depth, channels);
+        }
+        if payload.method == Method::LsbMatch {
+            metrics::time_stage("embed", || match_changes(&cover, img, depth));
+        }
+
+        let vars = NameVars {
+            cover: image_path,
+            index: copy + 1,
+            total: copies.len(),
+            set_id: &set_id,
+        };
+        let output_path = Path::new(output_dir).join(output_name(output_template, &vars)?);
+        let output_path_str = output_path.to_string_lossy().to_string();
+
+        let fill_ratio = values as f64 / channel_values(img, channels) as f64;
+        report.images.push(EncodedImage {
+            path: output_path_str.clone(),
+            cover: image_path.to_string(),
+            quality: quality(&cover, img)?,
+            detectability: detectability(
+                img,
+                fill_ratio,
+                Layout::Sequential { depth, channels },
+                payload.method,
+            ),
+        });
+        // Each copy stands alone, so there is no sequence to note in the metadata
+        let job = write_output(
+            std::mem::take(img),
+            wide,
+            output_path_str.clone(),
+            image_path.clone(),
+            None,
+            payload,
+        );
+        writes
+            .submit((copy, output_path_str), job)?
+            .into_iter()
+            .for_each(saved);
+    }
+    writes.finish()?.into_iter().for_each(saved);
+
+    Ok(report)
+}
+
+/// Erasure-code the message into one shard per cover, for the first `total`
+/// of `images`, so that any `needed` of the outputs rebuild it.
+fn encode_shards(
+    covers: &mut [PlannedCover],
+    header: &BitSlice<u8, Lsb0>,
+    body: &BitSlice<u8, Lsb0>,
+    output_dir: &str,
+    set: &SetOptions,
+    (needed, total): (u8, u8),
+    payload: &PayloadOptions,
+) -> Result<EncodeReport, LowkeyError> {
+    let (depth, channels) = (set.depth, set.channels);
+    let output_template = set.output_template.as_ref();
+    let count = total as usize;
+    if covers.len() < count {
+        return Err(LowkeyError::InvalidInput(format!(
+            "--redundancy {}-of-{} needs {} covers, only {} given",
+            needed,
+            total,
+            total,
+            covers.len()
+        )));
+    }
+    if covers.len() > count {
+        eprintln!("Using the first {} of {} covers", count, covers.len());
+    }
+    let covers = &mut covers[..count];
+
+    let mut message = header.to_bitvec().into_vec();
+    message.extend(body.to_bitvec().into_vec());
+    let shards = metrics::time_stage("shard", || shard::encode(&message, needed as usize, count));
+    let shard_bits = shards[0].len() * 8;
+    let reserved = SEQUENCE_HEADER_VALUES + SHARD_HEADER_VALUES;
+    let shard_values = shard_bits.div_ceil(depth as usize);
+
+    if set.auto_resize {
+        for cover in covers.iter_mut() {
+            resize_covers(
+                std::slice::from_mut(cover),
+                reserved + shard_values,
+                channels,
+            );
+        }
+    }
+    for cover in covers.iter() {
+        check_capacity_values(
+            cover.values(channels).saturating_sub(reserved),
+            BitSlice::empty(),
+            &convert_bytes_to_bits(&shards[0]),
+            depth,
+        )
+        .map_err(|e| e.map_message(|e| format!("{} can't hold a shard: {}", cover.path, e)))?;
+        if let Some(limit) = payload.max_fill {
+            let fill = (reserved + shard_values) as f64 / cover.values(channels) as f64;
+            limit.check(&cover.path, fill)?;
+        }
+    }
+
+    let set_id = crypto::random_id();
+    let mut report = EncodeReport::default();
+    let progress = Progress::new(
+        "Encoding",
+        file_names(
+            &covers
+                .iter()
+                .map(|cover| cover.path.clone())
+                .collect::<Vec<_>>(),
+        ),
+    );
+    progress.set_total(count * shard_values);
+    let saved = |(i, path): (usize, String)| {
+        progress.message(&format!("Saved shard {}/{}: {}", i + 1, count, path))
+    };
+    let mut writes = WritePool::new();
+
+    for (i, (planned, shard)) in covers.iter().zip(&shards).enumerate() {
+        let (mut img, wide) = planned.read(payload.convert_srgb)?;
+        let (image_path, img) = (&planned.path, &mut img);
+        let cover = img.clone();
+        let sequence = SequenceHeader {
+            index: i as u32,
+            total: count as u32,
+            set_id,
+        };
+        let shard_header = ShardHeader::new(needed, depth, shard);
+
+        progress.start_image(i, shard_values);
+        metrics::time_stage("embed", || {
+            set_bits_image_at(img, &sequence.to_bits(), 0, 1, channels)?;
+            set_bits_image_at(
+                img,
+                &shard_header.to_bits(),
+                SEQUENCE_HEADER_VALUES,
+                1,
+                channels,
+            )?;
+            set_bits_image_at_tracked(
+                img,
+                &convert_bytes_to_bits(shard),
+                reserved,
+                depth,
+                channels,
+                &progress,
+            )
+        })?;
+        if payload.noise_fill {
+            fill_noise_at(img, reserved + shard_values, depth, channels);
+        }
+        if payload.method == Method::LsbMatch {
+            metrics::time_stage("embed", || match_changes(&cover, img, depth));
+        }
+
+        let vars = NameVars {
+            cover: image_path,
+            index: i + 1,
+            total: count,
+            set_id: &set_id,
+        };
+        let output_path = Path::new(output_dir).join(output_name(output_template, &vars)?);
+        let output_path_str = output_path.to_string_lossy().to_string();
+
+        let fill_ratio = (reserved + shard_values) as f64 / channel_values(img, channels) as f64;
+        report.images.push(EncodedImage {
+            path: output_path_str.clone(),
+            cover: image_path.to_string(),
+            quality: quality(&cover, img)?,
+            detectability: detectability(
+                img,
+                fill_ratio,
+                Layout::Sequential { depth, channels },
+                payload.method,
+            ),
+        });
+        // Sequence metadata would make a complete set look like a plain
+        // sequence; the in-band headers are enough to put shards in order
+        let job = write_output(
+            std::mem::take(img),
+            wide,
+            output_path_str.clone(),
+            image_path.clone(),
+            None,
+            payload,
+        );
+        writes
+            .submit((i, output_path_str), job)?
+            .into_iter()
+            .for_each(saved);
+    }
+    writes.finish()?.into_iter().for_each(saved);
+
+    Ok(report)
+}
+
+/// Write `input_image` to `output_image` with its `depth` low bit planes
+/// randomized, or zeroed, and without lowkey's sequence info or payload
+/// chunk, so that no
+/// message, whatever its key, can be read from it. At `MAX_DEPTH` this covers
+/// every bit any layout embeds in.
+pub fn wipe_file(
+    input_image: &str,
+    output_image: &str,
+    depth: u8,
+    zero: bool,
+) -> Result<(), LowkeyError> {
+    check_image_png(output_image)?;
+    check_depth(depth)?;
+
+    let keep_depth = OutputFormat::from_path(output_image) == OutputFormat::Png;
+    let (mut img, mut wide) = read_cover(input_image, keep_depth, false)?;
+    wipe_low_bits(&mut img, depth, zero);
+
+    if let Some(parent) = Path::new(output_image).parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| LowkeyError::Io(format!("Failed to create output directory: {}", e)))?;
+    }
+    match &mut wide {
+        Some(wide) => {
+            set_low_bytes(wide, &img);
+            save_rgba16_with_metadata(
+                wide,
+                output_image,
+                input_image,
+                None,
+                KeepMetadata::All,
+                PngEncoding::default(),
+            )
+        }
+        None => save_rgba_with_metadata(
+            &img,
+            output_image,
+            input_image,
+            None,
+            KeepMetadata::All,
+            PngEncoding::default(),
+        ),
+    }
+}
+
+/// Encode into the DCT coefficients of the baseline JPEG at `input_image`,
+/// one bit in each that can carry one (see `Jpeg`), in the order of the
+/// file, and write it to `output_image` with nothing else changed.
+pub fn encode_jpeg_file(
+    input_image: &str,
+    message_bytes: &[u8],
+    output_image: &str,
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<EncodeReport, LowkeyError> {
+    check_carrier_payload(payload, "JPEG")?;
+    let mut jpeg = metrics::time_stage("read", || Jpeg::read(input_image))?;
+    let message_values = embed_in_carrier(&mut jpeg, message_bytes, key, payload)?;
+
+    let stego_bytes = jpeg.to_bytes();
+    write_carrier(output_image, &stego_bytes)?;
+    metrics::record_bytes_embedded(message_bytes.len());
+
+    // As seen by a viewer, which decodes the coefficients to pixels; both
+    // as stored, since the stego JPEG keeps the cover's EXIF orientation
+    let cover = read_carrier(input_image)?;
+    let img = image::load_from_memory(&stego_bytes)
+        .map(image::DynamicImage::into_rgba8)
+        .map_err(|e| LowkeyError::Format(format!("Failed to read '{}': {}", output_image, e)))?;
+    Ok(EncodeReport {
+        images: vec![EncodedImage {
+            path: output_image.to_string(),
+            cover: input_image.to_string(),
+            quality: quality(&cover, &img)?,
+            detectability: detectability(
+                &img,
+                message_values as f64 / jpeg.value_count().max(1) as f64,
+                Layout::default(),
+                payload.method,
+            ),
+        }],
+        unused: Vec::new(),
+    })
+}
+
+/// Encode into the lowest bit of the 16-bit samples of the WAV or FLAC file
+/// at `input_audio`, one bit in each, in the order of the file, and write it
+/// to `output_audio` as a WAV (see `Audio`).
+pub fn encode_audio_file(
+    input_audio: &str,
+    message_bytes: &[u8],
+    output_audio: &str,
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<(), LowkeyError> {
+    check_carrier_payload(payload, "audio")?;
+    if !output_audio.to_lowercase().ends_with(".wav") {
+        return Err(LowkeyError::InvalidInput(format!(
+            "Audio is written as WAV, so the output must end in .wav, not '{}'",
+            output_audio
+        )));
+    }
+    let mut audio = metrics::time_stage("read", || Audio::read(input_audio))?;
+    embed_in_carrier(&mut audio, message_bytes, key, payload)?;
+
+    write_carrier(output_audio, &audio.to_wav()?)?;
+    metrics::record_bytes_embedded(message_bytes.len());
+    Ok(())
+}
+
+fn check_carrier_payload(payload: &PayloadOptions, carrier: &str) -> Result<(), LowkeyError> {
+    if payload.decoy.is_some()
+        || payload.skip_transparent
+        || payload.region.is_some()
+        || payload.method == Method::LsbMatch
+        || payload.convert_srgb
+    {
+        return Err(LowkeyError::InvalidInput(format!(
+            "A decoy, skipping transparent pixels, a region, LSB matching and converting to sRGB are not supported with {} embedding",
+            carrier
+        )));
+    }
+    Ok(())
+}
+
+/// Embed the message in `carrier` from its first value on, in order, and
+/// return how many values it takes.
+fn embed_in_carrier(
+    carrier: &mut impl Carrier,
+    message_bytes: &[u8],
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<usize, LowkeyError> {
+    let values = carrier.value_count();
+
+    let filled;
+    let payload = match payload.pad_to {
+        Some(PadTo::Full) => {
+            filled = PayloadOptions {
+                pad_to: Some(PadTo::Bytes(body_capacity(values, 1))),
+                ..payload.clone()
+            };
+            &filled
+        }
+        _ => payload,
+    };
+    let (header, body) = metrics::time_stage("encrypt", || {
+        get_message_bits(message_bytes, key, payload, Layout::default())
+    })?;
+    check_capacity_values(values, &header, &body, 1)?;
+
+    let message_values = header.len() + body.len();
+    metrics::time_stage("embed", || {
+        for (index, bit) in header
+            .iter()
+            .by_vals()
+            .chain(body.iter().by_vals())
+            .enumerate()
+        {
+            carrier.set_value(index, bit);
+        }
+        if payload.noise_fill {
+            let unused = message_values..values;
+            let noise = crypto::random_bytes(unused.len().div_ceil(8));
+            for (index, bit) in unused.zip(BitVec::<u8, Lsb0>::from_vec(noise)) {
+                carrier.set_value(index, bit);
+            }
+        }
+    });
+    Ok(message_values)
+}
+
+fn write_carrier(output: &str, bytes: &[u8]) -> Result<(), LowkeyError> {
+    if let Some(parent) = Path::new(output).parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| LowkeyError::Io(format!("Failed to create output directory: {}", e)))?;
+    }
+    metrics::time_stage("write", || {
+        write_atomically(output, |writer| {
+            writer
+                .write_all(bytes)
+                .map_err(|e| LowkeyError::Io(format!("Failed to write '{}': {}", output, e)))
+        })
+    })
+}
+
+/// Encode into a lowkey payload chunk of a copy of the PNG at `input_image`
+/// (see `Format::Chunk`), written to `output_image` with the pixels and
+/// metadata as they are.
+pub fn encode_chunk_file(
+    input_image: &str,
+    message_bytes: &[u8],
+    output_image: &str,
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<EncodeReport, LowkeyError> {
+    if payload.decoy.is_some()
+        || payload.skip_transparent
+        || payload.region.is_some()
+        || payload.method == Method::LsbMatch
+        || payload.pad_to == Some(PadTo::Full)
+    {
+        return Err(LowkeyError::InvalidInput(
+            "A decoy, skipping transparent pixels, a region, LSB matching and padding to full are not supported with chunk embedding"
+                .to_string(),
+        ));
+    }
+    if OutputFormat::from_path(output_image) != OutputFormat::Png {
+        return Err(LowkeyError::InvalidInput(format!(
+            "Chunk embedding needs a PNG output, not '{}'",
+            output_image
+        )));
+    }
+    check_image_png(output_image)?;
+    let (img, wide) = metrics::time_stage("read", || {
+        read_cover(input_image, true, payload.convert_srgb)
+    })?;
+
+    let (header, body) = metrics::time_stage("encrypt", || {
+        get_message_bits(message_bytes, key, payload, Layout::default())
+    })?;
+    let mut chunk = header;
+    chunk.extend_from_bitslice(&body);
+    let chunk = chunk.into_vec();
+
+    if let Some(parent) = Path::new(output_image).parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| LowkeyError::Io(format!("Failed to create output directory: {}", e)))?;
+    }
+    metrics::time_stage("write", || match &wide {
+        Some(wide) => save_rgba16_with_payload(
+            wide,
+            output_image,
+            input_image,
+            &chunk,
+            payload.keep_metadata(),
+            payload.png_encoding,
+        ),
+        None => save_rgba_with_payload(
+            &img,
+            output_image,
+            input_image,
+            &chunk,
+            payload.keep_metadata(),
+            payload.png_encoding,
+        ),
+    })?;
+    metrics::record_bytes_embedded(message_bytes.len());
+
+    // The pixels say nothing, but anything that lists the chunks finds it
+    let mut detectability = detectability(&img, 0.0, Layout::default(), payload.method);
+    detectability.score = 100.0;
+    detectability.level = "high";
+    detectability.recommendations.push(
+        "The message is in a PNG chunk that any chunk listing shows; use the pixels format to hide it"
+            .to_string(),
+    );
+    Ok(EncodeReport {
+        images: vec![EncodedImage {
+            path: output_image.to_string(),
+            cover: input_image.to_string(),
+            quality: quality(&img, &img)?,
+            detectability,
+        }],
+        unused: Vec::new(),
+    })
+}
+
+pub fn decode_from_files(image_paths: &[String], key: &str) -> Result<Vec<u8>, LowkeyError> {
+    decode_message_from_files(image_paths, key)?
+        .open(&[])
+        .map(|message| message.bytes)
+}
+
+/// The index and image count sealed under `key` in the metadata of the image
+/// at `path` by a multi-image encode with `SequenceChunk::Encrypted`, if any.
+fn read_sealed_sequence(path: &str, key: &str) -> Option<(u32, u32)> {
+    let sealed = read_sealed_sequence_info(path).ok().flatten()?;
+    let plain = crypto::decrypt(&sealed, key).ok()?;
+    let (index, total) = plain.split_first_chunk::<4>()?;
+    let total: [u8; 4] = total.try_into().ok()?;
+    Some((u32::from_be_bytes(*index), u32::from_be_bytes(total)))
+}
+
+/// Like `decode_from_files`, but keep the metadata of an embedded file.
+pub fn decode_message_from_files(
+    image_paths: &[String],
+    key: &str,
+) -> Result<DecodedMessage, LowkeyError> {
+    if image_paths.is_empty() {
+        return Err(LowkeyError::InvalidInput(
+            "No input images provided".to_string(),
+        ));
+    }
+    // A JPEG or audio file can only have been encoded on its own, and a
+    // payload chunk only ever holds a whole message
+    if let [path] = image_paths {
+        if is_jpeg_file(path)? {
+            return decode_carrier(&Jpeg::read(path)?, key, "the JPEG's DCT coefficients");
+        }
+        if is_audio_file(path)? {
+            return decode_carrier(&Audio::read(path)?, key, "the audio samples");
+        }
+        if let Some(chunk) = read_payload_chunk(path)? {
+            return decode_chunk(&chunk, key);
+        }
+    }
+
+    let mut paths_with_sequence: Vec<(String, Option<(u32, u32)>)> = image_paths
+        .iter()
+        .map(|path| {
+            let seq_info = read_sequence_info(path)
+                .unwrap_or(None)
+                .or_else(|| read_sealed_sequence(path, key));
+            (path.clone(), seq_info)
+        })
+        .collect();
+
+    // Only a set that is complete by its metadata can be decoded without
+    // opening every image first; otherwise the images are grouped by the set
+    // IDs of their sequence headers
+    if image_paths.len() > 1
+        && !is_complete_sequence(&paths_with_sequence)
+        && let Some(sets) = group_by_sequence_headers(image_paths)?
+    {
+        return decode_sets(&sets, key);
+    }
+
+    let all_have_sequence = paths_with_sequence
+        .iter()
+        .all(|(_, seq_info)| seq_info.is_some());
+
+    if all_have_sequence {
+        paths_with_sequence.sort_by_key(|(_, seq_info)| seq_info.unwrap().0);
+        eprintln!("Detected sequence information in PNG metadata, using automatic ordering");
+    }
+
+    let sorted_paths: Vec<String> = paths_with_sequence
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    decode_paths(&sorted_paths, key)
+}
+
+/// Decode the message in slot `slot` (from 1) of the image at
+/// `image_path` (see `Slot`).
+pub fn decode_slot_from_file(
+    image_path: &str,
+    slot: usize,
+    key: &str,
+) -> Result<DecodedMessage, LowkeyError> {
+    let img = metrics::time_stage("read", || {
+        check_image_png(image_path)?;
+        check_image_lossless(image_path)?;
+        read_carrier(image_path)
+    })?;
+    let slots = image_slots(&img);
+    let found = slot
+        .checked_sub(1)
+        .and_then(|i| slots.get(i))
+        .ok_or_else(|| {
+            LowkeyError::NoMessage(format!(
+                "'{}' has no slot {}: it holds {} message(s)",
+                image_path,
+                slot,
+                slots.len()
+            ))
+        })?;
+    decode_reader(&mut found.reader(&img), key)
+        .map_err(|e| e.map_message(|e| format!("No lowkey message found in slot {} ({})", slot, e)))
+}
+
+/// The messages chained in the image at `image_path`, in slot order (see
+/// `Slot`). With a `key`, each message encrypted with a key is decrypted in
+/// memory to tell whether it is the right one.
+pub fn list_slots(image_path: &str, key: Option<&str>) -> Result<Vec<Slot>, LowkeyError> {
+    let img = read_carrier(image_path)?;
+    let mut slots = image_slots(&img);
+    if let Some(key) = key {
+        for slot in slots
+            .iter_mut()
+            .filter(|slot| !slot.plain && !slot.recipients)
+        {
+            slot.opens = Some(decode_reader(&mut slot.reader(&img), key).is_ok());
+        }
+    }
+    Ok(slots)
+}
+
+/// Whether the sequence metadata names every image of one sequence once.
+fn is_complete_sequence(paths_with_sequence: &[(String, Option<(u32, u32)>)]) -> bool {
+    let count = paths_with_sequence.len();
+    let mut seen = vec![false; count];
+    paths_with_sequence
+        .iter()
+        .all(|(_, seq_info)| match seq_info {
+            Some((index, total)) if *total as usize == count && (*index as usize) < count => {
+                !std::mem::replace(&mut seen[*index as usize], true)
+            }
+            _ => false,
+        })
+}
+
+/// Decode the message from the images at `sorted_paths`, in that order.
+fn decode_paths(sorted_paths: &[String], key: &str) -> Result<DecodedMessage, LowkeyError> {
+    // Images are opened as the message reaches them, so the ones after its
+    // end never are and only one is held at a time (see `LazyImagesReader`)
+    let read = |image_path: &String| {
+        metrics::time_stage("read", || {
+            check_image_png(image_path)?;
+            check_image_lossless(image_path)?;
+            read_carrier(image_path)
+        })
+    };
+    let progress = Progress::new("Decoding", file_names(sorted_paths));
+    let (first_path, rest_paths) = sorted_paths.split_first().expect("checked above");
+    let first = read(first_path)?;
+
+    // With sequence headers, each image must be the next of the first one's set
+    let first_sequence = SequenceHeader::find(&first);
+    if let Some((sequence, _)) = first_sequence
+        && sequence.index != 0
+    {
+        return Err(LowkeyError::InvalidInput(format!(
+            "'{}' is image {} of {}, and the first image of its set is missing",
+            first_path,
+            sequence.index + 1,
+            sequence.total
+        )));
+    }
+    let read_next = |position: usize, image_path: &String| {
+        let img = read(image_path)?;
+        if let Some((first_sequence, channels)) = first_sequence {
+            let mut reader = get_bits_reader_images(std::slice::from_ref(&img), channels);
+            match SequenceHeader::read(&mut reader) {
+                Some(sequence)
+                    if sequence.set_id == first_sequence.set_id
+                        && sequence.index as usize == position => {}
+                _ => {
+                    return Err(LowkeyError::InvalidInput(format!(
+                        "'{}' is not image {} of the set that '{}' starts",
+                        image_path,
+                        position + 1,
+                        first_path
+                    )));
+                }
+            }
+        }
+        Ok(Cow::Owned(img))
+    };
+
+    decode_image_sequence(
+        &first,
+        || {
+            rest_paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| read_next(i + 1, path))
+        },
+        rest_paths.is_empty(),
+        key,
+        Some(&progress),
+    )
+}
+
+/// The file name of an output: rendered from `template` if there is one,
+/// else the cover's (see `output_file_name`).
+fn output_name(template: Option<&OutputTemplate>, vars: &NameVars) -> Result<String, LowkeyError> {
+    match template {
+        Some(template) => Ok(template.render(vars)),
+        None => output_file_name(vars.cover),
+    }
+}
+
+/// Name of the stego image written for the cover at `image_path`: the same,
+/// but PNG for a JPEG.
+fn output_file_name(image_path: &str) -> Result<String, LowkeyError> {
+    let filename = Path::new(image_path)
+        .file_name()
+        .ok_or_else(|| LowkeyError::InvalidInput(format!("Invalid input path: {}", image_path)))?;
+
+    let mut output_filename = filename.to_string_lossy().to_string();
+    if (output_filename.to_lowercase().ends_with(".jpg")
+        || output_filename.to_lowercase().ends_with(".jpeg"))
+        && let Some(pos) = output_filename.rfind('.')
+    {
+        output_filename = format!("{}.png", &output_filename[..pos]);
+    }
+    Ok(output_filename)
+}
+
+/// Images of one encode, found by the set ID of their sequence headers.
+struct ImageSet {
+    set_id: [u8; 16],
+    total: u32,
+    /// Index and path of each image, in order, with copies in the order given
+    images: Vec<(u32, String)>,
+    /// For a set of erasure-coded shards, how many of them rebuild the message
+    needed: Option<u8>,
+}
+
+impl ImageSet {
+    /// Start of the set ID, to tell sets apart in messages
+    fn name(&self) -> String {
+        self.set_id[..4]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Most copies given of any one image
+    fn copies(&self) -> usize {
+        self.images
+            .chunk_by(|(a, _), (b, _)| a == b)
+            .map(<[_]>::len)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Path of each image, taking its `copy`th copy where there is one and
+    /// its first otherwise
+    fn paths(&self, copy: usize) -> Vec<String> {
+        self.images
+            .chunk_by(|(a, _), (b, _)| a == b)
+            .map(|copies| copies[copy.min(copies.len() - 1)].1.clone())
+            .collect()
+    }
+
+    /// Number of different images of the set given
+    fn present(&self) -> usize {
+        self.images.chunk_by(|(a, _), (b, _)| a == b).count()
+    }
+
+    /// Indices of the images of the set that were not given
+    fn missing(&self) -> Vec<u32> {
+        (0..self.total)
+            .filter(|index| {
+                self.images
+                    .binary_search_by_key(index, |(i, _)| *i)
+                    .is_err()
+            })
+            .collect()
+    }
+}
+
+/// Group `paths` by the sets their in-band sequence headers name, or `None`
+/// if no image has one. Images without a header are left out.
+fn group_by_sequence_headers(paths: &[String]) -> Result<Option<Vec<ImageSet>>, LowkeyError> {
+    let mut sets: Vec<ImageSet> = Vec::new();
+    let mut unmarked = 0;
+    for path in paths {
+        let img = metrics::time_stage("read", || read_carrier(path))?;
+        let Some((sequence, channels)) = SequenceHeader::find(&img) else {
+            unmarked += 1;
+            continue;
+        };
+        let image = (sequence.index, path.clone());
+        match sets.iter_mut().find(|set| set.set_id == sequence.set_id) {
+            Some(set) => set.images.push(image),
+            None => sets.push(ImageSet {
+                set_id: sequence.set_id,
+                total: sequence.total,
+                images: vec![image],
+                needed: ShardHeader::find(&img, channels).map(|(shard, _)| shard.needed),
+            }),
+        }
+    }
+    if sets.is_empty() {
+        return Ok(None);
+    }
+
+    if unmarked > 0 {
+        eprintln!("Ignoring {} image(s) without a sequence header", unmarked);
+    }
+    for set in &mut sets {
+        // Stable, so copies stay in the order given
+        set.images.sort_by_key(|(index, _)| *index);
+    }
+    Ok(Some(sets))
+}
+
+/// Decode the first complete set that holds a message for `key`, or report
+/// what is wrong with each. Where images are given more than once, such as
+/// the copies `--redundancy full` writes, each copy is tried in turn.
+fn decode_sets(sets: &[ImageSet], key: &str) -> Result<DecodedMessage, LowkeyError> {
+    eprintln!("Detected in-band sequence headers, using automatic ordering");
+    if sets.len() > 1 {
+        eprintln!("Found {} sets of images", sets.len());
+    }
+
+    let mut errors = Vec::new();
+    for set in sets {
+        let missing = set.missing();
+        let result = match set.needed {
+            Some(needed) => decode_shards(set, needed as usize, key),
+            None if missing.is_empty() => decode_copies(set, key),
+            None => Err(LowkeyError::NoMessage(describe_missing(
+                &missing, set.total,
+            ))),
+        };
+        match result {
+            Ok(message) => {
+                if sets.len() > 1 {
+                    eprintln!("Decoded set {}", set.name());
+                }
+                return Ok(message);
+            }
+            Err(e) if sets.len() == 1 => return Err(e),
+            Err(e) => errors.push(format!(
+                "Set {} ({} of {} images): {}",
+                set.name(),
+                set.present(),
+                set.total,
+                e
+            )),
+        }
+    }
+    Err(LowkeyError::NoMessage(errors.join("\n")))
+}
+
+/// Decode a complete set, moving on to the next copy of its images while
+/// one fails.
+fn decode_copies(set: &ImageSet, key: &str) -> Result<DecodedMessage, LowkeyError> {
+    let copies = set.copies();
+    if copies > 1 {
+        if set.total == 1 {
+            eprintln!("Found {} copies of the message", copies);
+        } else {
+            eprintln!("Some images are given more than once, trying the copies in turn");
+        }
+    }
+
+    let mut last_error = LowkeyError::Other(String::new());
+    for copy in 0..copies {
+        let paths = set.paths(copy);
+        match decode_paths(&paths, key) {
+            Ok(message) => {
+                if copy > 0 {
+                    eprintln!("Decoded copy {} of {}", copy + 1, copies);
+                }
+                return Ok(message);
+            }
+            Err(e) if copies > 1 => {
+                eprintln!("Copy {} of {} failed: {}", copy + 1, copies, e);
+                last_error = e;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error.map_message(|e| format!("No copy of the message could be decoded: {}", e)))
+}
+
+/// Rebuild the message of an erasure-coded set from the first `needed` of
+/// its shards that are intact, and decode it.
+fn decode_shards(set: &ImageSet, needed: usize, key: &str) -> Result<DecodedMessage, LowkeyError> {
+    let mut shards: Vec<(usize, Vec<u8>)> = Vec::new();
+    for copies in set.images.chunk_by(|(a, _), (b, _)| a == b) {
+        if shards.len() == needed {
+            break;
+        }
+        for (index, path) in copies {
+            match read_shard(path) {
+                Ok(shard) => {
+                    shards.push((*index as usize, shard));
+                    break;
+                }
+                Err(e) => eprintln!(
+                    "Skipping shard {} of {} in '{}': {}",
+                    index + 1,
+                    set.total,
+                    path,
+                    e
+                ),
+            }
+        }
+    }
+
+    if shards.len() < needed {
+        let mut error = format!(
+            "Only {} of the {} shards needed are intact (any {} of the {} images rebuild the message)",
+            shards.len(),
+            needed,
+            needed,
+            set.total
+        );
+        let missing = set.missing();
+        if !missing.is_empty() {
+            error.push_str(&format!("; {}", describe_missing(&missing, set.total)));
+        }
+        return Err(LowkeyError::NoMessage(error));
+    }
+
+    let used = shards
+        .iter()
+        .map(|(index, _)| (index + 1).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    eprintln!(
+        "Rebuilding the message from shards {} of {} (any {} suffice)",
+        used, set.total, needed
+    );
+    let message = metrics::time_stage("reconstruct", || shard::reconstruct(&shards, needed))?;
+    let bits = convert_bytes_to_bits(&message);
+    decode_reader(&mut bits.iter().map(|bit| *bit as u8), key)
+}
+
+/// The shard in the image at `path`, if it has one and it is intact.
+fn read_shard(path: &str) -> Result<Vec<u8>, LowkeyError> {
+    let img = metrics::time_stage("read", || read_carrier(path))?;
+    let (_, channels) = SequenceHeader::find(&img)
+        .ok_or_else(|| LowkeyError::NoMessage("No sequence header found".to_string()))?;
+    let (header, mut reader) = ShardHeader::find(&img, channels)
+        .ok_or_else(|| LowkeyError::NoMessage("No shard header found".to_string()))?;
+    let shard = metrics::time_stage("extract", || {
+        read_bits_at_depth(&mut reader, header.length as usize * 8, header.depth)
+    })?
+    .into_vec();
+    if shard_checksum(&shard) != header.checksum {
+        return Err(LowkeyError::Crypto("the shard is damaged".to_string()));
+    }
+    Ok(shard)
+}
+
+/// Which of `total` images are missing, numbered from 1.
+fn describe_missing(missing: &[u32], total: u32) -> String {
+    let numbers = missing
+        .iter()
+        .map(|index| (index + 1).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if missing.len() == 1 {
+        format!("Image {} of {} is missing", numbers, total)
+    } else {
+        format!("Images {} of {} are missing", numbers, total)
+    }
+}
+
+/// Names of the files at `paths`, to show in progress.
+fn file_names(paths: &[String]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| {
+            Path::new(path)
+                .file_name()
+                .map_or_else(|| path.clone(), |name| name.to_string_lossy().to_string())
+        })
+        .collect()
+}
+
+/// Decode a message from a single PNG image held in memory.
+pub fn decode_from_memory(image_bytes: &[u8], key: &str) -> Result<Vec<u8>, LowkeyError> {
+    if is_jpeg(image_bytes) {
+        return decode_carrier(
+            &Jpeg::parse(image_bytes.to_vec())?,
+            key,
+            "the JPEG's DCT coefficients",
+        )?
+        .open(&[])
+        .map(|message| message.bytes);
+    }
+    if is_audio(image_bytes) {
+        return decode_carrier(&Audio::parse(image_bytes)?, key, "the audio samples")?
+            .open(&[])
+            .map(|message| message.bytes);
+    }
+    if let Some(chunk) = payload_chunk_from(image_bytes) {
+        return decode_chunk(&chunk, key)?
+            .open(&[])
+            .map(|message| message.bytes);
+    }
+    let img = image::load_from_memory(image_bytes)
+        .map(carrier_from)
+        .map_err(|e| LowkeyError::Format(format!("Failed to read image: {}", e)))?;
+
+    decode_images(&[img], key)?
+        .open(&[])
+        .map(|message| message.bytes)
+}
+
+/// Encode a message into a single image held in memory and return the PNG.
+///
+/// Metadata chunks of a PNG cover are carried over, as with `encode_from_file`.
+pub fn encode_to_memory(
+    cover_bytes: &[u8],
+    message_bytes: &[u8],
+    key: &str,
+) -> Result<Vec<u8>, LowkeyError> {
+    let mut img = metrics::time_stage("read", || {
+        image::load_from_memory(cover_bytes)
+            .map(image::DynamicImage::into_rgba8)
+            .map_err(|e| LowkeyError::Format(format!("Failed to read image: {}", e)))
+    })?;
+
+    let (header, body) = metrics::time_stage("encrypt", || {
+        get_message_bits(
+            message_bytes,
+            key,
+            &PayloadOptions::default(),
+            Layout::default(),
+        )
+    })?;
+    metrics::time_stage("embed", || {
+        embed_sequential(&mut img, &header, &body, 1, Channels::ALL)
+    })?;
+
+    let mut output = Vec::new();
+    metrics::time_stage("write", || {
+        write_rgba_with_metadata_from(&img, &mut output, cover_bytes, None)
+    })?;
+    metrics::record_bytes_embedded(message_bytes.len());
+
+    Ok(output)
+}
+
+/// Encode a message into a single image and write the PNG to `writer`.
+///
+/// The salt and nonce are derived from the key and message (see
+/// `crypto::encrypt_deterministic_with_kdf`), so encoding the same message into the
+/// same cover always produces byte-identical output. There is no key check
+/// and no chunking, so that the files the git filter has already stored don't
+/// all change.
+pub fn encode_deterministic<W: Write>(
+    input_image: &str,
+    message_bytes: &[u8],
+    key: &str,
+    writer: W,
+) -> Result<(), LowkeyError> {
+    let mut img = read_image(input_image)?;
+
+    let compressed = compress::compress(message_bytes);
+    let body_bytes = crypto::encrypt_deterministic_with_kdf(
+        compressed.as_deref().unwrap_or(message_bytes),
+        key,
+        &KdfParams::default(),
+    )?;
+    let flags = if compressed.is_some() {
+        COMPRESSED_FLAG
+    } else {
+        0
+    };
+    // Kept at version 5, whose length takes four bytes, for the same reason
+    let header = get_message_header_bytes(
+        FLAGS_PROTOCOL_VERSION,
+        &body_bytes,
+        Layout::default(),
+        flags,
+        Cipher::default(),
+    );
+    embed_sequential(
+        &mut img,
+        &convert_bytes_to_bits(&header),
+        &convert_bytes_to_bits(&body_bytes),
+        1,
+        Channels::ALL,
+    )?;
+
+    write_rgba_with_metadata(&img, writer, input_image, None)
+}
+
+fn decode_images(images: &[RgbaImage], key: &str) -> Result<DecodedMessage, LowkeyError> {
+    let (first, rest) = images
+        .split_first()
+        .ok_or_else(|| LowkeyError::InvalidInput("No input images provided".to_string()))?;
+    decode_image_sequence(
+        first,
+        || rest.iter().map(|img| Ok(Cow::Borrowed(img))),
+        rest.is_empty(),
+        key,
+        None,
+    )
+}
+
+/// Decode a message from `first` and the images after it, which `rest`
+/// yields anew for each set of channels tried. Only the first image is
+/// needed to find the header, so the others are taken from `rest` as the
+/// message body reaches them.
+fn decode_image_sequence<'a, I>(
+    first: &'a RgbaImage,
+    rest: impl Fn() -> I,
+    single: bool,
+    key: &str,
+    progress: Option<&'a Progress>,
+) -> Result<DecodedMessage, LowkeyError>
+where
+    I: Iterator<Item = Result<Cow<'a, RgbaImage>, LowkeyError>>,
+{
+    let result = decode_sequential(
+        || iter::once(Ok(Cow::Borrowed(first))).chain(rest()),
+        key,
+        progress,
+        Selection::WHOLE,
+    );
+
+    // A single image may have been encoded with --scatter, --adaptive or
+    // --match-noise instead, or without its transparent pixels or in a region
+    match result {
+        Err(e) if single => decode_scattered(first, key, Selection::WHOLE)
+            .or_else(|_| decode_adaptive(first, key))
+            .or_else(|_| decode_noise_matched(first, key))
+            .or_else(|_| decode_opaque(first, key))
+            .or_else(|_| decode_region(first, key))
+            .map_err(|_| e),
+        result => result,
+    }
+}
+
+/// The header is written in the channels the message uses, so try each set
+/// of channels whose header names that set. All four channels come first,
+/// which also covers versions before the channel mask.
+///
+/// Once a header is found, the body is counted in `progress`. Only a header
+/// that says the message is in the pixels of `selection` is taken.
+fn decode_sequential<'a, I>(
+    images: impl Fn() -> I,
+    key: &str,
+    progress: Option<&'a Progress>,
+    selection: Selection,
+) -> Result<DecodedMessage, LowkeyError>
+where
+    I: Iterator<Item = Result<Cow<'a, RgbaImage>, LowkeyError>>,
+{
+    let mut header_error = None;
+    let mut body_error = None;
+    for channels in Channels::candidates() {
+        let mut reader = sequence_reader(&images, channels);
+        match read_message_header(&mut reader) {
+            // Blank images read as a legacy header with nothing after it
+            Ok(header) if header.check_length().is_err() => {
+                header_error.get_or_insert(header.check_length().unwrap_err());
+            }
+            Ok(header)
+                if header.channels == channels
+                    && header.allows_layout(LAYOUT_SEQUENTIAL)
+                    && header.selection() == selection =>
+            {
+                if let Some(progress) = progress {
+                    progress.set_total(
+                        header_bytes(header.version) * 8
+                            + (header.length as usize * 8).div_ceil(header.depth as usize),
+                    );
+                    reader.track(progress);
+                }
+                match decode_body(&header, &mut reader, key) {
+                    Ok(message) => return Ok(message),
+                    Err(e) => {
+                        // An image that failed to open explains a short read
+                        body_error.get_or_insert(reader.take_error().unwrap_or(e));
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                header_error.get_or_insert(e);
+            }
+        }
+    }
+
+    // A header that names its channels is more telling than noise read as
+    // one; without one, the images hold no message at all
+    Err(body_error.unwrap_or_else(|| match header_error {
+        Some(e) => LowkeyError::NoMessage(format!("No lowkey message found ({})", e)),
+        None => LowkeyError::NoMessage("No lowkey message found".to_string()),
+    }))
+}
+
+/// Reader over the values of `channels` of `images`, past the sequence
+/// header of each if the first image starts with one.
+fn sequence_reader<'a, I>(images: &impl Fn() -> I, channels: Channels) -> LazyImagesReader<'a, I>
+where
+    I: Iterator<Item = Result<Cow<'a, RgbaImage>, LowkeyError>>,
+{
+    let mut reader = LazyImagesReader::new(images(), channels);
+    if SequenceHeader::read(&mut reader).is_none() {
+        return LazyImagesReader::new(images(), channels);
+    }
+    if SpreadHeader::read(&mut reader).is_some() {
+        // Evenly spread, each image says where its part of the message ends
+        let reserved = SEQUENCE_HEADER_VALUES + SPREAD_HEADER_VALUES;
+        reader.skip_each(reserved);
+        reader.take_each(move |img| {
+            SpreadHeader::find(img, channels).map_or(0, |spread| reserved + spread.values as usize)
+        });
+        return reader;
+    }
+    let mut reader = LazyImagesReader::new(images(), channels);
+    reader.skip_each(SEQUENCE_HEADER_VALUES);
+    reader
+}
+
+fn decode_noise_matched(img: &RgbaImage, key: &str) -> Result<DecodedMessage, LowkeyError> {
+    // Positions are taken in the same order whatever the count, so reading
+    // the longest header also reads the shorter ones
+    let header = read_message_header(&mut get_bits_reader_noise_matched(
+        img,
+        key,
+        HEADER_BYTES * 8,
+    )?)?;
+    if header.depth != 1
+        || header.channels != Channels::ALL
+        || !header.allows_layout(LAYOUT_NOISE_MATCHED)
+        || header.selection() != Selection::WHOLE
+    {
+        return Err(LowkeyError::NoMessage(format!(
+            "Invalid bit depth {} or channels {} for a noise-matched message",
+            header.depth, header.channels
+        )));
+    }
+
+    let count = (header_bytes(header.version) + header.length as usize) * 8;
+    let mut reader = get_bits_reader_noise_matched(img, key, count)?;
+    decode_reader(&mut reader, key)
+}
+
+/// Like `decode_sequential`, but each set of channels is read in its keyed
+/// order, and only a header that says it was scattered is taken.
+fn decode_scattered(
+    img: &RgbaImage,
+    key: &str,
+    selection: Selection,
+) -> Result<DecodedMessage, LowkeyError> {
+    // Behind a decoy, the message is in the values after the decoy's
+    for start in iter::once(0).chain(decoy_end(img, selection)) {
+        for channels in Channels::candidates() {
+            let mut reader = get_bits_reader_scattered(img, key, channels, start);
+            if let Ok(header) = read_message_header(&mut reader)
+                && header.channels == channels
+                && header.layout == Some(LAYOUT_SCATTERED)
+                && header.selection() == selection
+            {
+                return decode_body(&header, &mut reader, key);
+            }
+        }
+    }
+
+    Err(LowkeyError::NoMessage(
+        "No scattered message header found".to_string(),
+    ))
+}
+
+/// Like `decode_scattered`, over the textured values only. Which values
+/// are textured depends on the bit depth, so each depth is tried too.
+fn decode_adaptive(img: &RgbaImage, key: &str) -> Result<DecodedMessage, LowkeyError> {
+    for start in iter::once(0).chain(decoy_end(img, Selection::WHOLE)) {
+        for depth in 1..=MAX_DEPTH {
+            let textured = textured_values(img, depth);
+            let mut per_channel = [0usize; 4];
+            for (i, _) in textured.iter().enumerate().skip(start).filter(|(_, t)| **t) {
+                per_channel[i % 4] += 1;
+            }
+            for channels in Channels::candidates() {
+                // Without room for a header, the reader would go through the
+                // whole image to come up short
+                let values: usize = (0..4)
+                    .filter(|&channel| channels.contains(channel))
+                    .map(|channel| per_channel[channel])
+                    .sum();
+                if values < HEADER_BYTES * 8 {
+                    continue;
+                }
+                let mut reader = get_bits_reader_adaptive(img, key, channels, start, &textured);
+                if let Ok(header) = read_message_header(&mut reader)
+                    && header.depth == depth
+                    && header.channels == channels
+                    && header.layout == Some(LAYOUT_ADAPTIVE)
+                    && header.selection() == Selection::WHOLE
+                {
+                    return decode_body(&header, &mut reader, key);
+                }
+            }
+        }
+    }
+
+    Err(LowkeyError::NoMessage(
+        "No adaptive message header found".to_string(),
+    ))
+}
+
+/// Index in the RGBA buffer of `img` where the values of a sequential
+/// message in it end, which a scattered message may be hidden behind. The
+/// header of the sequential message tells how long it is without the key.
+fn decoy_end(img: &RgbaImage, selection: Selection) -> Option<usize> {
+    Channels::candidates().find_map(|channels| {
+        let mut reader = get_bits_reader_images(slice::from_ref(img), channels);
+        let header = read_message_header(&mut reader).ok()?;
+        if header.check_length().is_err()
+            || header.channels != channels
+            || !header.allows_layout(LAYOUT_SEQUENTIAL)
+            || header.selection() != selection
+        {
+            return None;
+        }
+        let values = header_bytes(header.version) * 8
+            + (header.length as usize)
+                .saturating_mul(8)
+                .div_ceil(header.depth as usize);
+        Some(channels.index_of(values))
+    })
+}
+
+/// Like the single-image layouts without noise matching, over the pixels
+/// that are not transparent only, taken as a row of their own.
+fn decode_opaque(img: &RgbaImage, key: &str) -> Result<DecodedMessage, LowkeyError> {
+    let row = take_pixels(img, &opaque_pixels(img));
+    decode_sequential(
+        || iter::once(Ok(Cow::Borrowed(&row))),
+        key,
+        None,
+        Selection::OPAQUE,
+    )
+    .or_else(|_| decode_scattered(&row, key, Selection::OPAQUE))
+}
+
+/// Like `decode_opaque`, over the pixels of the region that the record
+/// embedded with the key tells.
+fn decode_region(img: &RgbaImage, key: &str) -> Result<DecodedMessage, LowkeyError> {
+    let opaque = opaque_pixels(img);
+    let values = img.as_raw();
+    let mut reader = region_record_positions(key, &opaque).map(|position| values[position]);
+    let record = RegionRecord::read(&mut reader, key, img.width(), img.height())
+        .ok_or_else(|| LowkeyError::NoMessage("No region record found".to_string()))?;
+
+    let (pixels, _) = region_pixels(img, key, &record);
+    let row = take_pixels(img, &pixels);
+    let selection = Selection {
+        transparent_skipped: record.transparent_skipped,
+        region: true,
+    };
+    decode_sequential(|| iter::once(Ok(Cow::Borrowed(&row))), key, None, selection)
+        .or_else(|_| decode_scattered(&row, key, selection))
+}
+
+/// Decode a message that `encode_jpeg_file` embedded in `jpeg`.
+/// Decode the message `carrier` holds from its first value on, with `place`
+/// saying where for the error.
+fn decode_carrier(
+    carrier: &impl Carrier,
+    key: &str,
+    place: &str,
+) -> Result<DecodedMessage, LowkeyError> {
+    decode_reader(&mut carrier.values(), key)
+        .map_err(|e| e.map_message(|e| format!("No lowkey message found in {} ({})", place, e)))
+}
+
+fn decode_chunk(chunk: &[u8], key: &str) -> Result<DecodedMessage, LowkeyError> {
+    let bits = convert_bytes_to_bits(chunk);
+    decode_reader(&mut bits.iter().by_vals().map(u8::from), key).map_err(|e| {
+        e.map_message(|e| format!("No lowkey message found in the payload chunk ({})", e))
+    })
+}
+
+/// Bits of a region record with the values they go in
+type RecordBits = Vec<(usize, bool)>;
+
+/// Pixels of `img` that a message kept to the region of `record` is in,
+/// and the bits of the record with the values they go in (see
+/// `region_record_positions`), whose pixels are left out.
+fn region_pixels(img: &RgbaImage, key: &str, record: &RegionRecord) -> (Vec<usize>, RecordBits) {
+    let bits: BitVec<u8, Lsb0> = BitVec::from_vec(record.to_bytes(key));
+    let record_bits: RecordBits = region_record_positions(key, &opaque_pixels(img))
+        .zip(bits)
+        .collect();
+    let taken: HashSet<usize> = record_bits.iter().map(|&(value, _)| value / 4).collect();
+    let pixels = record
+        .pixels(img.width())
+        .into_iter()
+        .filter(|pixel| {
+            !taken.contains(pixel)
+                && (!record.transparent_skipped || img.as_raw()[pixel * 4 + 3] >= TRANSPARENT_ALPHA)
+        })
+        .collect();
+    (pixels, record_bits)
+}
+
+/// Pixels of `img` the message is kept to, to be embedded in as a row of
+/// their own, and the bits of the region record with the values they go
+/// in, or `None` if the message goes in the whole image.
+fn selected_pixels(
+    img: &RgbaImage,
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<Option<(Vec<usize>, RecordBits)>, LowkeyError> {
+    match &payload.region {
+        Some(region) => {
+            let record = region.record(img.width(), img.height(), payload.skip_transparent)?;
+            let (pixels, record_bits) = region_pixels(img, key, &record);
+            if record_bits.len() < record.bits() {
+                return Err(LowkeyError::NoMessage(format!(
+                    "Not enough pixels for the region record ({} bits)",
+                    record.bits()
+                )));
+            }
+            Ok(Some((pixels, record_bits)))
+        }
+        None if payload.skip_transparent => Ok(Some((opaque_pixels(img), Vec::new()))),
+        None => Ok(None),
+    }
+}
+
+fn decode_reader(
+    reader: &mut impl Iterator<Item = u8>,
+    key: &str,
+) -> Result<DecodedMessage, LowkeyError> {
+    let header = read_message_header(reader)?;
+    decode_body(&header, reader, key)
+}
+
+/// Read and decrypt the message that follows `header`.
+fn decode_body(
+    header: &MessageHeader,
+    reader: &mut impl Iterator<Item = u8>,
+    key: &str,
+) -> Result<DecodedMessage, LowkeyError> {
+    header.check_length()?;
+    let length = usize::try_from(header.length).map_err(|_| {
+        LowkeyError::Other(format!(
+            "Payload of {} bytes is too long for this system",
+            header.length
+        ))
+    })?;
+
+    // Error-corrected blocks are interleaved over the whole payload
+    let message_bytes = if header.chunked && !header.ecc {
+        decrypt_chunks(header, reader, length, key)?
+    } else {
+        decrypt_whole(header, reader, length, key)?
+    };
+    let message_bytes = if header.padded {
+        unpad_message(message_bytes)?
+    } else {
+        message_bytes
+    };
+
+    let sealed = Sealed {
+        compressed: header.compressed,
+        file: header.file,
+    };
+    if header.recipients {
+        return Ok(DecodedMessage {
+            bytes: message_bytes,
+            file: None,
+            archive: header.archive,
+            sealed: Some(sealed),
+        });
+    }
+    unpack_message(message_bytes, sealed, header.archive)
+}
+
+/// Decompress the decrypted message and split off its file metadata, as
+/// `sealed` says.
+fn unpack_message(
+    message_bytes: Vec<u8>,
+    sealed: Sealed,
+    archive: bool,
+) -> Result<DecodedMessage, LowkeyError> {
+    let message_bytes = if sealed.compressed {
+        metrics::time_stage("decompress", || compress::decompress(&message_bytes))?
+    } else {
+        message_bytes
+    };
+
+    if sealed.file {
+        let (file, bytes) = FileInfo::split(&message_bytes)?;
+        Ok(DecodedMessage {
+            bytes: bytes.to_vec(),
+            file: Some(file),
+            archive,
+            sealed: None,
+        })
+    } else {
+        Ok(DecodedMessage {
+            bytes: message_bytes,
+            file: None,
+            archive,
+            sealed: None,
+        })
+    }
+}
+
+/// Read a chunked payload of `length` bytes and decrypt it a chunk at a
+/// time, so that it is never held encrypted as a whole.
+fn decrypt_chunks(
+    header: &MessageHeader,
+    reader: &mut impl Iterator<Item = u8>,
+    length: usize,
+    key: &str,
+) -> Result<Vec<u8>, LowkeyError> {
+    // Chunks need not end on a value, so the bits of the last value read
+    // that a chunk leaves over are kept for the next one
+    let depth = header.depth;
+    let mut left_over: BitVec<u8, Lsb0> = BitVec::new();
+    let mut read_bytes = |count: usize| {
+        metrics::time_stage("extract", || {
+            let values = (count * 8 - left_over.len()).div_ceil(depth as usize);
+            let mut bits = std::mem::take(&mut left_over);
+            bits.extend_from_bitslice(&read_bits_at_depth(reader, values * depth as usize, depth)?);
+            left_over = bits.split_off(count * 8);
+            Ok::<_, LowkeyError>(bits.into_vec())
+        })
+    };
+
+    let cipher = header.cipher;
+    let mut decryptor =
+        ChunkDecryptor::new(&read_bytes(cipher.chunked_header_bytes())?, key, cipher)?;
+    let mut left = length - cipher.chunked_header_bytes();
+    let mut message_bytes = Vec::with_capacity(crypto::max_chunked_plaintext_len(length, cipher));
+    while left > 0 {
+        let count = left.min(CHUNK_BYTES + TAG_BYTES);
+        left -= count;
+        let chunk = read_bytes(count)?;
+        message_bytes.extend(metrics::time_stage("decrypt", || {
+            decryptor.decrypt(&chunk, left == 0)
+        })?);
+    }
+    Ok(message_bytes)
+}
+
+/// Read the whole payload of `length` bytes, correct it if it has parity,
+/// and decrypt it.
+fn decrypt_whole(
+    header: &MessageHeader,
+    reader: &mut impl Iterator<Item = u8>,
+    length: usize,
+    key: &str,
+) -> Result<Vec<u8>, LowkeyError> {
+    let encrypted_bytes = metrics::time_stage("extract", || {
+        read_bits_at_depth(reader, length * 8, header.depth).map(BitVec::into_vec)
+    })?;
+
+    let encrypted_bytes = if header.ecc {
+        let (encrypted_bytes, corrected) = metrics::time_stage("correct", || {
+            ecc::decode(&encrypted_bytes).map_err(|e| {
+                LowkeyError::Crypto(format!("The message is damaged beyond repair: {}", e))
+            })
+        })?;
+        if corrected > 0 {
+            eprintln!("Corrected {} damaged bytes of the message", corrected);
+        }
+        encrypted_bytes
+    } else {
+        encrypted_bytes
+    };
+
+    // Age files are decrypted by `DecodedMessage::open`
+    if header.plain || header.recipients {
+        return Ok(encrypted_bytes);
+    }
+    metrics::time_stage("decrypt", || {
+        if header.version == LEGACY_PROTOCOL_VERSION {
+            crypto::decrypt(&encrypted_bytes, key)
+        } else if header.chunked {
+            crypto::decrypt_chunked(&encrypted_bytes, key, header.cipher)
+        } else if header.key_check {
+            crypto::decrypt_with_key_check(&encrypted_bytes, key)
+        } else {
+            crypto::decrypt_with_kdf(&encrypted_bytes, key)
+        }
+    })
+}
+
+/// Maximum plaintext message size, in bytes, that fits into the given images.
+pub fn message_capacity_from_files(image_paths: &[String]) -> Result<usize, LowkeyError> {
+    Ok(capacity_report(image_paths, 1, Channels::ALL, false, false, None)?.total)
+}
+
+/// How much plaintext each image carries on its own and all of them together.
+#[derive(Serialize)]
+pub struct CapacityReport {
+    pub images: Vec<ImageCapacity>,
+    /// Plaintext bytes the images carry as one sequence, in bytes
+    pub total: usize,
+    /// Header and encryption bytes each message takes on top of the plaintext
+    pub overhead: usize,
+}
+
+#[derive(Serialize)]
+pub struct ImageCapacity {
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    /// Plaintext bytes the image carries on its own
+    pub capacity: usize,
+}
+
+/// Capacity of the images at `depth` bits per value of `channels`, with
+/// error correction if `ecc`, without the transparent pixels if
+/// `skip_transparent` and in `region` only, if given.
+pub fn capacity_report(
+    image_paths: &[String],
+    depth: u8,
+    channels: Channels,
+    ecc: bool,
+    skip_transparent: bool,
+    region: Option<&Region>,
+) -> Result<CapacityReport, LowkeyError> {
+    check_depth(depth)?;
+    if region.is_some() && image_paths.len() > 1 {
+        return Err(LowkeyError::InvalidInput(
+            "A region is only supported with a single image".to_string(),
+        ));
+    }
+
+    let mut images = Vec::new();
+    let mut total_values = 0usize;
+    for image_path in image_paths {
+        let ImageHeader {
+            width,
+            height,
+            color_type,
+        } = probe_image(image_path)?;
+        // The pixels are only read to find the transparent ones, which an
+        // image without alpha has none of
+        let img = match skip_transparent && color_type.has_alpha() {
+            true => Some(read_image(image_path)?),
+            false => None,
+        };
+        let pixels = match (region, &img) {
+            // Each value of the record may take a pixel of the region
+            (Some(region), _) => {
+                let record = region.record(width, height, skip_transparent)?;
+                record
+                    .pixels(width)
+                    .into_iter()
+                    .filter(|pixel| {
+                        img.as_ref()
+                            .is_none_or(|img| img.as_raw()[pixel * 4 + 3] >= TRANSPARENT_ALPHA)
+                    })
+                    .count()
+                    .saturating_sub(record.bits())
+            }
+            (None, Some(img)) => opaque_pixels(img).len(),
+            (None, None) => width as usize * height as usize,
+        };
+        let values = pixels * channels.count();
+        total_values += values;
+        images.push(ImageCapacity {
+            path: image_path.clone(),
+            width,
+            height,
+            capacity: message_capacity(values, depth, ecc),
+        });
+    }
+
+    // Split over several images, each starts with a sequence header
+    if image_paths.len() > 1 {
+        total_values = total_values.saturating_sub(image_paths.len() * SEQUENCE_HEADER_VALUES);
+    }
+
+    Ok(CapacityReport {
+        images,
+        total: message_capacity(total_values, depth, ecc),
+        overhead: MESSAGE_OVERHEAD_BYTES,
+    })
+}
+
+/// Maximum plaintext message size, in bytes, that fits into an image of
+/// `width`x`height` with the default settings.
+pub fn message_capacity_for_size(width: u32, height: u32) -> usize {
+    message_capacity(width as usize * height as usize * 4, 1, false)
+}
+
+/// Maximum plaintext message size, in bytes, that fits into an image held in memory.
+pub fn message_capacity_from_memory(image_bytes: &[u8]) -> Result<usize, LowkeyError> {
+    let header = probe_image_from_memory(image_bytes)?;
+    Ok(message_capacity_for_size(header.width, header.height))
+}
+
+/// Read the message header and sequence information of a single image.
+///
+/// The header is only present in the first image of a multi-image sequence,
+/// so it is not read from the others.
+pub fn inspect_file(image_path: &str) -> Result<Inspection, LowkeyError> {
+    let metadata_info = read_sequence_info(image_path)?;
+    let img = read_carrier(image_path)?;
+    let (width, height) = img.dimensions();
+    let capacity = message_capacity(width as usize * height as usize * 4, 1, false);
+
+    let mut problems = Vec::new();
+    if is_lossy_webp(image_path)? {
+        problems.push("Lossy WebP compression destroys hidden data".to_string());
+    }
+
+    let in_band = SequenceHeader::find(&img);
+    let in_band_info = in_band.map(|(sequence, _)| (sequence.index, sequence.total));
+    let shards_needed = in_band
+        .and_then(|(_, channels)| ShardHeader::find(&img, channels))
+        .map(|(shard, _)| shard.needed);
+    if let (Some((index, total)), Some(in_band)) = (metadata_info, in_band_info)
+        && (index, total) != in_band
+    {
+        problems.push(format!(
+            "Sequence metadata says image {} of {}, but the in-band header image {} of {}",
+            index + 1,
+            total,
+            in_band.0 + 1,
+            in_band.1
+        ));
+    }
+    let sequence_info = metadata_info.or(in_band_info);
+    if let Some((index, total)) = sequence_info
+        && index >= total
+    {
+        problems.push(format!(
+            "Sequence index {} is out of range for {} images",
+            index, total
+        ));
+    }
+
+    // A shard has no message header of its own
+    let header = match sequence_info {
+        _ if shards_needed.is_some() => None,
+        Some((index, _)) if index != 0 => None,
+        _ => Some(find_inspected_header(&img).map_err(|e| {
+            LowkeyError::NoMessage(format!("No lowkey message header found: {}", e))
+        })?),
+    };
+    let kdf = match &header {
+        Some((header, carrier)) => {
+            let single = sequence_info.is_none_or(|(_, total)| total == 1);
+            check_payload(carrier, header, single, &mut problems)
+        }
+        None => None,
+    };
+    let header = header.map(|(header, _)| header);
+
+    Ok(Inspection {
+        version: header.as_ref().map(|header| header.version),
+        payload_length: header.as_ref().map(|header| header.length),
+        depth: header.as_ref().map(|header| header.depth),
+        channels: header.as_ref().map(|header| header.channels),
+        compressed: header.as_ref().map(|header| header.compressed),
+        ecc: header.as_ref().map(|header| header.ecc),
+        file_info: header.as_ref().map(|header| header.file),
+        archive: header.as_ref().map(|header| header.archive),
+        encrypted: header.as_ref().map(|header| !header.plain),
+        cipher: header
+            .as_ref()
+            .filter(|header| !header.plain && !header.recipients)
+            .map(|header| header.cipher),
+        recipients: header.as_ref().map(|header| header.recipients),
+        padded: header.as_ref().map(|header| header.padded),
+        method: header.as_ref().map(|header| header.method),
+        transparent_skipped: header.as_ref().map(|header| header.transparent_skipped),
+        kdf,
+        problems,
+        sequence_info,
+        shards_needed,
+        capacity,
+    })
+}
+
+/// What lowkey payload the image at `image_path` already holds, as far as
+/// can be told without the key: a payload chunk, or a message or sequence
+/// header that checks out. `None` for an image without one, or one that
+/// can't be read as a cover.
+pub fn find_existing_payload(image_path: &str) -> Option<String> {
+    if read_payload_chunk(image_path).ok().flatten().is_some() {
+        return Some("a lowkey message in a PNG chunk".to_string());
+    }
+    if is_jpeg_file(image_path).ok()? {
+        return carrier_holds_message(&Jpeg::read(image_path).ok()?)
+            .then(|| "a lowkey message in its DCT coefficients".to_string());
+    }
+    if is_audio_file(image_path).ok()? {
+        return carrier_holds_message(&Audio::read(image_path).ok()?)
+            .then(|| "a lowkey message in its samples".to_string());
+    }
+    let inspection = inspect_file(image_path).ok()?;
+    if !inspection.problems.is_empty() {
+        return None;
+    }
+    Some(match inspection.sequence_info {
+        Some((index, total)) if total > 1 => {
+            format!("image {} of {} of a lowkey message", index + 1, total)
+        }
+        _ => "a lowkey message".to_string(),
+    })
+}
+
+/// Whether the values of `carrier` start with a message header that checks
+/// out and announces a payload they have room for.
+fn carrier_holds_message(carrier: &impl Carrier) -> bool {
+    read_message_header(&mut carrier.values()).is_ok_and(|header| {
+        header.check_length().is_ok()
+            && header.length.saturating_mul(8) <= carrier.value_count() as u64
+    })
+}
+
+/// The header of a sequentially embedded message in `img`, or failing that,
+/// of one embedded without the transparent pixels, with the pixels it is in.
+fn find_inspected_header(
+    img: &RgbaImage,
+) -> Result<(MessageHeader, Cow<'_, RgbaImage>), LowkeyError> {
+    let found = find_message_header(slice::from_ref(img), Selection::WHOLE);
+    if let Ok(header) = &found
+        && header.check_length().is_ok()
+    {
+        return found.map(|header| (header, Cow::Borrowed(img)));
+    }
+    let row = take_pixels(img, &opaque_pixels(img));
+    match find_message_header(slice::from_ref(&row), Selection::OPAQUE) {
+        Ok(header) => Ok((header, Cow::Owned(row))),
+        Err(_) => found.map(|header| (header, Cow::Borrowed(img))),
+    }
+}
+
+/// Check that the payload announced by `header` can be a message: long enough
+/// for the encryption overhead, within the image if it is not split over a
+/// sequence, with intact error correction if any, and with sane KDF
+/// parameters, which are returned.
+fn check_payload(
+    img: &RgbaImage,
+    header: &MessageHeader,
+    single: bool,
+    problems: &mut Vec<String>,
+) -> Option<KdfParams> {
+    let length = header.length as usize;
+    let kdf_bytes =
+        if header.version == LEGACY_PROTOCOL_VERSION || header.plain || header.recipients {
+            0
+        } else {
+            KDF_HEADER_BYTES
+        };
+    let min_length = header.min_length();
+    let data_length = if header.ecc {
+        ecc::max_data_len(length)
+    } else {
+        length
+    };
+    if data_length < min_length {
+        problems.push(format!(
+            "Payload of {} bytes is shorter than the {} bytes of encryption overhead",
+            length, min_length
+        ));
+    }
+
+    // A sequence header in the same channels comes first, and a spread
+    // header after it if the set is evenly spread
+    let sequence_values = match SequenceHeader::find(img) {
+        Some((_, channels)) if channels == header.channels => {
+            match SpreadHeader::find(img, channels) {
+                Some(_) => SEQUENCE_HEADER_VALUES + SPREAD_HEADER_VALUES,
+                None => SEQUENCE_HEADER_VALUES,
+            }
+        }
+        _ => 0,
+    };
+    let header_values = sequence_values + header_bytes(header.version) * 8;
+    let values = header_values + (length * 8).div_ceil(header.depth as usize);
+    let fits = values <= channel_values(img, header.channels);
+    if single && !fits {
+        problems.push(format!(
+            "Payload of {} bytes does not fit in the image",
+            length
+        ));
+    }
+
+    if kdf_bytes == 0 || data_length < kdf_bytes {
+        return None;
+    }
+    // Error-corrected blocks are interleaved, so all of them are needed to
+    // get at the KDF parameters
+    let read_length = match header.ecc {
+        true if single && fits => length,
+        true => return None,
+        false => kdf_bytes,
+    };
+    let mut reader =
+        get_bits_reader_images(std::slice::from_ref(img), header.channels).skip(header_values);
+    let kdf = read_bits_at_depth(&mut reader, read_length * 8, header.depth)
+        .and_then(|bits| {
+            let bytes = bits.into_vec();
+            if header.ecc {
+                ecc::decode(&bytes)
+                    .map(|(data, _)| data)
+                    .map_err(LowkeyError::Crypto)
+            } else {
+                Ok(bytes)
+            }
+        })
+        .and_then(|bytes| crypto::read_kdf_params(&bytes));
+    match kdf {
+        Ok(kdf) => Some(kdf),
+        Err(e) => {
+            problems.push(e.to_string());
+            None
+        }
+    }
+}
+
+/// The header of a sequentially embedded message, in whichever channels it is
+/// (see `decode_sequential`, also for `selection`).
+fn find_message_header(
+    images: &[RgbaImage],
+    selection: Selection,
+) -> Result<MessageHeader, LowkeyError> {
+    let images = || images.iter().map(|img| Ok(Cow::Borrowed(img)));
+    let mut first_error = None;
+    for channels in Channels::candidates() {
+        match read_message_header(&mut sequence_reader(&images, channels)) {
+            Ok(header)
+                if header.channels == channels
+                    && header.allows_layout(LAYOUT_SEQUENTIAL)
+                    && header.selection() == selection =>
+            {
+                return Ok(header);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    Err(first_error
+        .unwrap_or_else(|| LowkeyError::NoMessage("No message header found".to_string())))
+}
+
+/// Read the protocol version, encrypted payload length and, from version 2,
+/// the bit depth, from version 3, the channels, from version 4, the layout
+/// and, from version 5, the flags.
+fn read_message_header(
+    reader: &mut impl Iterator<Item = u8>,
+) -> Result<MessageHeader, LowkeyError> {
+    let mut read_byte = || {
+        let bits = read_bits(reader, 8)?;
+        Ok::<_, LowkeyError>(
+            bits.iter()
+                .enumerate()
+                .fold(0u8, |acc, (i, bit)| acc | ((*bit as u8) << i)),
+        )
+    };
+
+    let version = read_byte()?;
+    let mut len_bytes = [0u8; 8];
+    for byte in &mut len_bytes[4..] {
+        *byte = read_byte()?;
+    }
+    if version > PROTOCOL_VERSION {
+        return Err(LowkeyError::NoMessage(format!(
+            "Unsupported protocol version {}. Expected version {} to {}",
+            version, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION
+        )));
+    }
+    if version >= WIDE_LENGTH_PROTOCOL_VERSION {
+        // The four bytes read so far were the high ones
+        len_bytes.copy_within(4.., 0);
+        for byte in &mut len_bytes[4..] {
+            *byte = read_byte()?;
+        }
+    }
+
+    let depth = if version >= DEPTH_PROTOCOL_VERSION {
+        let depth = read_byte()?;
+        check_depth(depth)?;
+        depth
+    } else {
+        1
+    };
+
+    let channels = if version >= CHANNELS_PROTOCOL_VERSION {
+        Channels::from_mask(read_byte()?)?
+    } else {
+        Channels::ALL
+    };
+
+    let layout = if version >= LAYOUT_PROTOCOL_VERSION {
+        let layout = read_byte()?;
+        if layout > LAYOUT_ADAPTIVE {
+            return Err(LowkeyError::NoMessage(format!("Unknown layout {}", layout)));
+        }
+        Some(layout)
+    } else {
+        None
+    };
+
+    let mut flags = if version >= FLAGS_PROTOCOL_VERSION {
+        read_byte()? as u16
+    } else {
+        0
+    };
+
+    let cipher = if version >= CIPHER_PROTOCOL_VERSION {
+        Cipher::from_id(read_byte()?)?
+    } else {
+        Cipher::default()
+    };
+
+    if version >= MORE_FLAGS_PROTOCOL_VERSION {
+        flags |= (read_byte()? as u16) << 8;
+    }
+    let known = COMPRESSED_FLAG
+        | ECC_FLAG
+        | FILE_FLAG
+        | ARCHIVE_FLAG
+        | KEY_CHECK_FLAG
+        | CHUNKED_FLAG
+        | PLAIN_FLAG
+        | RECIPIENTS_FLAG
+        | PADDED_FLAG
+        | LSB_MATCH_FLAG
+        | TRANSPARENT_SKIPPED_FLAG
+        | REGION_FLAG;
+    if flags & !known != 0 {
+        return Err(LowkeyError::NoMessage(format!(
+            "Unknown header flags {:#018b}",
+            flags
+        )));
+    }
+    // At most one way of encrypting, and padding only inside the key's
+    let encryption = flags & (KEY_CHECK_FLAG | CHUNKED_FLAG | PLAIN_FLAG | RECIPIENTS_FLAG);
+    if (encryption & (PLAIN_FLAG | RECIPIENTS_FLAG) != 0 && !encryption.is_power_of_two())
+        || (flags & PADDED_FLAG != 0 && flags & CHUNKED_FLAG == 0)
+    {
+        return Err(LowkeyError::NoMessage(format!(
+            "Contradictory header flags {:#018b}",
+            flags
+        )));
+    }
+
+    Ok(MessageHeader {
+        version,
+        length: u64::from_be_bytes(len_bytes),
+        depth,
+        channels,
+        layout,
+        compressed: flags & COMPRESSED_FLAG != 0,
+        ecc: flags & ECC_FLAG != 0,
+        file: flags & FILE_FLAG != 0,
+        archive: flags & ARCHIVE_FLAG != 0,
+        key_check: flags & KEY_CHECK_FLAG != 0,
+        chunked: flags & CHUNKED_FLAG != 0,
+        plain: flags & PLAIN_FLAG != 0,
+        cipher,
+        recipients: flags & RECIPIENTS_FLAG != 0,
+        padded: flags & PADDED_FLAG != 0,
+        method: if flags & LSB_MATCH_FLAG != 0 {
+            Method::LsbMatch
+        } else {
+            Method::LsbReplace
+        },
+        transparent_skipped: flags & TRANSPARENT_SKIPPED_FLAG != 0,
+        region: flags & REGION_FLAG != 0,
+    })
+}
+
+/// Size of the header written by `version`
+fn header_bytes(version: u8) -> usize {
+    if version >= MORE_FLAGS_PROTOCOL_VERSION {
+        HEADER_BYTES
+    } else if version >= CIPHER_PROTOCOL_VERSION {
+        BASE_HEADER_BYTES + 9
+    } else if version >= WIDE_LENGTH_PROTOCOL_VERSION {
+        BASE_HEADER_BYTES + 8
+    } else if version >= FLAGS_PROTOCOL_VERSION {
+        BASE_HEADER_BYTES + 4
+    } else if version >= LAYOUT_PROTOCOL_VERSION {
+        BASE_HEADER_BYTES + 3
+    } else if version >= CHANNELS_PROTOCOL_VERSION {
+        BASE_HEADER_BYTES + 2
+    } else if version >= DEPTH_PROTOCOL_VERSION {
+        BASE_HEADER_BYTES + 1
+    } else {
+        BASE_HEADER_BYTES
+    }
+}
+
+/// Header of `version`, 5 or later, for `body_bytes`.
+fn get_message_header_bytes(
+    version: u8,
+    body_bytes: &[u8],
+    layout: Layout,
+    flags: u16,
+    cipher: Cipher,
+) -> Vec<u8> {
+    let mut head = vec![version];
+    if version >= WIDE_LENGTH_PROTOCOL_VERSION {
+        head.extend_from_slice(&(body_bytes.len() as u64).to_be_bytes());
+    } else {
+        head.extend_from_slice(&(body_bytes.len() as u32).to_be_bytes());
+    }
+    head.push(layout.depth());
+    head.push(layout.channels().mask());
+    head.push(layout.id());
+    head.push(flags as u8);
+    if version >= CIPHER_PROTOCOL_VERSION {
+        head.push(cipher.id());
+    }
+    if version >= MORE_FLAGS_PROTOCOL_VERSION {
+        head.push((flags >> 8) as u8);
+    }
+
+    head
+}
+
+/// The encrypted message, after any file metadata, compressed first if that
+/// makes it smaller and with error correction if asked for, and the header
+/// flags saying which.
+fn get_message_body_bytes(
+    message_bytes: &[u8],
+    key: &str,
+    payload: &PayloadOptions,
+) -> Result<(Vec<u8>, u16), LowkeyError> {
+    let mut flags = 0;
+    let with_file;
+    let message_bytes = match &payload.file {
+        Some(file) => {
+            flags |= FILE_FLAG;
+            with_file = file.prepend(message_bytes)?;
+            &with_file[..]
+        }
+        None => message_bytes,
+    };
+    if payload.archive {
+        flags |= ARCHIVE_FLAG;
+    }
+    if payload.method == Method::LsbMatch {
+        flags |= LSB_MATCH_FLAG;
+    }
+    if payload.skip_transparent {
+        flags |= TRANSPARENT_SKIPPED_FLAG;
+    }
+    if payload.region.is_some() {
+        flags |= REGION_FLAG;
+    }
+    let compressed = payload
+        .compress
+        .then(|| compress::compress(message_bytes))
+        .flatten();
+    if compressed.is_some() {
+        flags |= COMPRESSED_FLAG;
+    }
+    let compressed = compressed.as_deref().unwrap_or(message_bytes);
+    if payload.pad_to.is_some() && (payload.plain || !payload.recipients.is_empty()) {
+        return Err(LowkeyError::InvalidInput(
+            "Only messages encrypted with the key can be padded".to_string(),
+        ));
+    }
+    let mut body_bytes = if !payload.recipients.is_empty() {
+        flags |= RECIPIENTS_FLAG;
+        crypto::encrypt_to_recipients(compressed, &payload.recipients)?
+    } else if payload.plain {
+        flags |= PLAIN_FLAG;
+        compressed.to_vec()
+    } else {
+        flags |= KEY_CHECK_FLAG | CHUNKED_FLAG;
+        let padded;
+        let pad_to = match payload.pad_to {
+            Some(PadTo::PowerOfTwo) => Some(PadTo::Bytes(power_of_two_pad(
+                compressed.len(),
+                payload.ecc,
+                payload.cipher,
+            ))),
+            pad_to => pad_to,
+        };
+        let plaintext = match pad_to {
+            Some(PadTo::Bytes(size)) => {
+                flags |= PADDED_FLAG;
+                let len = plaintext_capacity(size, payload.ecc, payload.cipher);
+                padded = pad_message(compressed, len).ok_or_else(|| {
+                    LowkeyError::Capacity(format!(
+                        "The message is too long to be padded to {} bytes",
+                        size
+                    ))
+                })?;
+                &padded[..]
+            }
+            Some(PadTo::Full) => {
+                return Err(LowkeyError::InvalidInput(
+                    "Padding to the full image needs a single cover".to_string(),
+                ));
+            }
+            Some(PadTo::PowerOfTwo) | None => compressed,
+        };
+        crypto::encrypt_chunked(plaintext, key, &payload.kdf, payload.cipher)?
+    };
+    if payload.ecc {
+        body_bytes = ecc::encode(&body_bytes);
+        flags |= ECC_FLAG;
+    }
+    Ok((body_bytes, flags))
+}
+
+/// Header and body bits of a message, embedded at different depths
+type MessageBits = (BitVec<u8, Lsb0>, BitVec<u8, Lsb0>);
+
+fn get_message_bits(
+    message_bytes: &[u8],
+    key: &str,
+    payload: &PayloadOptions,
+    layout: Layout,
+) -> Result<MessageBits, LowkeyError> {
+    let (body_bytes, flags) = get_message_body_bytes(message_bytes, key, payload)?;
+    Ok(frame_message_body(
+        &body_bytes,
+        layout,
+        flags,
+        payload.cipher,
+    ))
+}
+
+/// Bits of `decoy`, encrypted with its own key at the cost and with the
+/// cipher of the message, to go where a sequential message would.
+fn get_decoy_bits(
+    decoy: &Decoy,
+    key: &str,
+    payload: &PayloadOptions,
+    layout: Layout,
+) -> Result<MessageBits, LowkeyError> {
+    let (Layout::Scattered { depth, channels } | Layout::Adaptive { depth, channels }) = layout
+    else {
+        return Err(LowkeyError::InvalidInput(
+            "A decoy needs the message to be scattered".to_string(),
+        ));
+    };
+    if decoy.key == key {
+        return Err(LowkeyError::InvalidInput(
+            "The decoy key must differ from the key".to_string(),
+        ));
+    }
+    let decoy_payload = PayloadOptions {
+        kdf: payload.kdf,
+        ecc: payload.ecc,
+        cipher: payload.cipher,
+        skip_transparent: payload.skip_transparent,
+        ..PayloadOptions::default()
+    };
+    get_message_bits(
+        &decoy.message,
+        &decoy.key,
+        &decoy_payload,
+        Layout::Sequential { depth, channels },
+    )
+}
+
+fn frame_message_body(
+    body_bytes: &[u8],
+    layout: Layout,
+    flags: u16,
+    cipher: Cipher,
+) -> MessageBits {
+    let header_bytes =
+        get_message_header_bytes(PROTOCOL_VERSION, body_bytes, layout, flags, cipher);
+
+    (
+        convert_bytes_to_bits(&header_bytes),
+        convert_bytes_to_bits(body_bytes),
+    )
+}
+
+/// Embed the header at one bit per value of `channels` from the start of the
+/// image and the body after it at `depth` bits per value.
+fn embed_sequential(
+    img: &mut RgbaImage,
+    header: &BitSlice<u8, Lsb0>,
+    body: &BitSlice<u8, Lsb0>,
+    depth: u8,
+    channels: Channels,
+) -> Result<(), LowkeyError> {
+    check_capacity_images(&[img], 0, header, body, depth, channels)?;
+    set_bits_image_at(img, header, 0, 1, channels)?;
+    set_bits_image_at(img, body, header.len(), depth, channels)
+}
+
+/// Like `embed_sequential`, with the values of `channels` in keyed order,
+/// leaving out the first `reserved` of them, which a decoy takes.
+fn embed_scattered(
+    img: &mut RgbaImage,
+    header: &BitSlice<u8, Lsb0>,
+    body: &BitSlice<u8, Lsb0>,
+    depth: u8,
+    channels: Channels,
+    key: &str,
+    reserved: usize,
+) -> Result<(), LowkeyError> {
+    check_capacity_images(&[img], reserved, header, body, depth, channels)?;
+    let start = channels.index_of(reserved);
+    let mut positions = scattered_positions(img, key, channels, start);
+    set_bits_positions(img, positions.by_ref().take(header.len()), header, 1);
+    set_bits_positions(img, positions, body, depth);
+    Ok(())
+}
+
+/// Like `embed_scattered`, over the textured values at `depth` (see
+/// `textured_values`), which it returns.
+fn embed_adaptive(
+    img: &mut RgbaImage,
+    header: &BitSlice<u8, Lsb0>,
+    body: &BitSlice<u8, Lsb0>,
+    depth: u8,
+    channels: Channels,
+    key: &str,
+    reserved: usize,
+) -> Result<Vec<bool>, LowkeyError> {
+    let textured = textured_values(img, depth);
+    let start = channels.index_of(reserved);
+    check_capacity_values(
+        adaptive_capacity(&textured, channels, start),
+        header,
+        body,
+        depth,
+    )
+    .map_err(|e| e.map_message(|e| format!("{} (in the textured areas only)", e)))?;
+    let mut positions = adaptive_positions(img, key, channels, start, &textured);
+    set_bits_positions(img, positions.by_ref().take(header.len()), header, 1);
+    set_bits_positions(img, positions, body, depth);
+    Ok(textured)
+}
+
+/// Plaintext bytes that fit in `values` channel values: the header at one bit
+/// per value, the encrypted message after it at `depth` bits per value, with
+/// its parity if `ecc`.
+fn message_capacity(values: usize, depth: u8, ecc: bool) -> usize {
+    plaintext_capacity(body_capacity(values, depth), ecc, Cipher::default())
+}
+
+/// Encrypted bytes that fit in `values` channel values after the header.
+fn body_capacity(values: usize, depth: u8) -> usize {
+    values.saturating_sub(HEADER_BYTES * 8) * depth as usize / 8
+}
+
+/// Plaintext bytes that `cipher` encrypts to at most `body_bytes` bytes,
+/// with parity if `ecc`.
+fn plaintext_capacity(body_bytes: usize, ecc: bool, cipher: Cipher) -> usize {
+    let encrypted_bytes = if ecc {
+        ecc::max_data_len(body_bytes)
+    } else {
+        body_bytes
+    };
+    crypto::max_chunked_plaintext_len(encrypted_bytes, cipher)
+}
+
+/// Bytes in front of a padded message that give its length
+const PAD_LENGTH_BYTES: usize = 8;
+
+/// `message` with its length in front and zeros after it, `len` bytes in
+/// all. Once encrypted, the zeros cannot be told from the message.
+fn pad_message(message: &[u8], len: usize) -> Option<Vec<u8>> {
+    if PAD_LENGTH_BYTES + message.len() > len {
+        return None;
+    }
+    let mut padded = Vec::with_capacity(len);
+    padded.extend_from_slice(&(message.len() as u64).to_be_bytes());
+    padded.extend_from_slice(message);
+    padded.resize(len, 0);
+    Some(padded)
+}
+
+/// Embedded size `PadTo::PowerOfTwo` pads a message of `len` bytes to.
+fn power_of_two_pad(len: usize, ecc: bool, cipher: Cipher) -> usize {
+    let mut size = MIN_POWER_OF_TWO_PAD;
+    while plaintext_capacity(size, ecc, cipher) < PAD_LENGTH_BYTES + len {
+        size *= 2;
+    }
+    size
+}
+
+/// The message `pad_message` padded.
+fn unpad_message(mut padded: Vec<u8>) -> Result<Vec<u8>, LowkeyError> {
+    let (len, rest) = padded
+        .split_first_chunk::<PAD_LENGTH_BYTES>()
+        .ok_or_else(|| LowkeyError::Crypto("Padded message is too short".to_string()))?;
+    let len = u64::from_be_bytes(*len);
+    if len > rest.len() as u64 {
+        return Err(LowkeyError::Crypto(format!(
+            "Padded message length {} is longer than its {} bytes",
+            len,
+            rest.len()
+        )));
+    }
+    padded.drain(..PAD_LENGTH_BYTES);
+    padded.truncate(len as usize);
+    Ok(padded)
+}
+
+/// Number of values of `channels` in the image
+/// How many of `images` a message fills, with `header_len` values of message
+/// header and `body_bits` bits of body.
+fn images_used(
+    covers: &[PlannedCover],
+    header_len: usize,
+    body_bits: usize,
+    depth: u8,
+    channels: Channels,
+) -> usize {
+    let mut left = body_bits;
+    for (i, cover) in covers.iter().enumerate() {
+        let start = SEQUENCE_HEADER_VALUES + if i == 0 { header_len } else { 0 };
+        let capacity = cover.values(channels).saturating_sub(start) * depth as usize;
+        if left <= capacity {
+            return i + 1;
+        }
+        left -= capacity;
+    }
+    covers.len()
+}
+
+/// Message bits an image of `values` channel values has room for after its
+/// first `start` values, up to `max_fill` of it if there is a limit.
+fn body_room(values: usize, start: usize, depth: u8, max_fill: Option<FillLimit>) -> usize {
+    let end = max_fill.map_or(values, |limit| limit.values(values).min(values));
+    end.saturating_sub(start) * depth as usize
+}
+
+/// Values of the message after the sequence and spread headers of each of
+/// `images` when each is filled in turn, up to `max_fill` if there is a
+/// limit, leaving out the images after the end of the message.
+fn filled_shares(
+    covers: &[PlannedCover],
+    header_len: usize,
+    body_bits: usize,
+    depth: u8,
+    channels: Channels,
+    max_fill: Option<FillLimit>,
+) -> Vec<usize> {
+    let reserved = SEQUENCE_HEADER_VALUES + SPREAD_HEADER_VALUES;
+    let mut left = body_bits.div_ceil(depth as usize);
+    let mut shares = Vec::new();
+    for (i, cover) in covers.iter().enumerate() {
+        let header = if i == 0 { header_len } else { 0 };
+        let body = body_room(cover.values(channels), reserved + header, 1, max_fill).min(left);
+        left -= body;
+        shares.push(header + body);
+        if left == 0 {
+            break;
+        }
+    }
+    shares
+}
+
+/// Values of the message after the sequence and spread headers of each of
+/// `images`, the message header's in the first included, for all of them to
+/// be about equally full, headers and all.
+fn spread_shares(
+    covers: &[PlannedCover],
+    header_len: usize,
+    body_bits: usize,
+    depth: u8,
+    channels: Channels,
+) -> Vec<usize> {
+    let reserved = SEQUENCE_HEADER_VALUES + SPREAD_HEADER_VALUES;
+    let mut left = body_bits.div_ceil(depth as usize);
+    let needed = covers.len() * reserved + header_len + left;
+    let available: usize = covers.iter().map(|cover| cover.values(channels)).sum();
+    covers
+        .iter()
+        .enumerate()
+        .map(|(i, cover)| {
+            let header = if i == 0 { header_len } else { 0 };
+            // Rounded up, so that the shares add up to at least the message
+            let share = (cover.values(channels) as u128 * needed as u128)
+                .div_ceil(available.max(1) as u128) as usize;
+            let body = share.saturating_sub(reserved + header).min(left);
+            left -= body;
+            header + body
+        })
+        .collect()
+}
+
+/// A cover of a multi-image encode, known by its size until the message
+/// gets to it, so that the message can be planned over hundreds of photos
+/// without holding more than a few of them in memory at once.
+struct PlannedCover {
+    path: String,
+    /// Size of the cover as read, upright
+    original: (u32, u32),
+    /// Size the message is embedded at, smaller if `resize_covers` shrank it
+    dimensions: (u32, u32),
+}
+
+impl PlannedCover {
+    /// The cover at `path`, from the header of the file alone.
+    fn probe(path: &str) -> Result<Self, LowkeyError> {
+        let header = probe_image(path)?;
+        let dimensions = (header.width, header.height);
+        Ok(Self {
+            path: path.to_string(),
+            original: dimensions,
+            dimensions,
+        })
+    }
+
+    /// Number of values of `channels` in the cover
+    fn values(&self, channels: Channels) -> usize {
+        let (width, height) = self.dimensions;
+        width as usize * height as usize * channels.count()
+    }
+
+    /// Read the pixels of the cover, shrunk to `dimensions`. Outputs keep the
+    /// format of their cover (JPEG aside, which is never 16-bit).
+    fn read(&self, srgb: bool) -> Result<(RgbaImage, Option<Rgba16Image>), LowkeyError> {
+        let keep_depth = OutputFormat::from_path(&self.path) == OutputFormat::Png;
+        let (img, wide) = metrics::time_stage("read", || read_cover(&self.path, keep_depth, srgb))?;
+        if img.dimensions() != self.original {
+            return Err(LowkeyError::InvalidInput(format!(
+                "'{}' changed while the message was being embedded",
+                self.path
+            )));
+        }
+        if self.dimensions == self.original {
+            return Ok((img, wide));
+        }
+
+        let (width, height) = self.dimensions;
+        Ok(metrics::time_stage("resize", || match wide {
+            Some(wide) => {
+                // Only the full-depth image is resized; its low bytes are
+                // taken again after
+                drop(img);
+                let wide = resize_to(wide, width, height);
+                (low_bytes(&wide), Some(wide))
+            }
+            None => (resize_to(img, width, height), None),
+        }))
+    }
+}
+
+/// Shrink every cover by the same factor, so that together they have about
+/// the `values` channel values of `channels` the message takes. Only their
+/// planned size changes; `PlannedCover::read` resizes the pixels.
+///
+/// Each side is rounded up and the covers are kept to the minimum size of
+/// `resize_image`, so they still hold the message afterwards. Covers never
+/// grow: if the message does not fit as they are, they are left alone for the
+/// capacity check to report.
+fn resize_covers(covers: &mut [PlannedCover], values: usize, channels: Channels) {
+    let available: usize = covers.iter().map(|cover| cover.values(channels)).sum();
+    let scale = (values as f64 / available as f64).sqrt().min(1.0);
+
+    for cover in covers {
+        let (width, height) = cover.dimensions;
+        let (new_width, new_height) = scaled_dimensions(width, height, scale, MIN_DIMENSION);
+        if (new_width, new_height) == (width, height) {
+            continue;
+        }
+
+        eprintln!(
+            "Resizing {} from {}x{} to {}x{} to optimize for message size",
+            cover.path, width, height, new_width, new_height
+        );
+        cover.dimensions = (new_width, new_height);
+    }
+}
+
+fn channel_values(img: &RgbaImage, channels: Channels) -> usize {
+    img.len() / 4 * channels.count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::pixel::{set_bits_image, splitmix64};
+    use std::path::{Path, PathBuf};
+
+    /// Argon2 parameters cheap enough for tests
+    const CHEAP_KDF: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    /// A scratch directory, removed when the test ends or panics
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(label: &str) -> TestDir {
+            let path =
+                std::env::temp_dir().join(format!("lowkey-{}-{}", label, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TestDir(path)
+        }
+    }
+
+    impl std::ops::Deref for TestDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A cover with a different value in every pixel
+    fn gradient_cover(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([x as u8, y as u8, 200, 255])
+        })
+    }
+
+    /// Saves a flat cover of each size as cover0.<extension>, cover1...
+    fn save_covers(dir: &Path, extension: &str, sizes: &[(u32, u32)]) -> Vec<String> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &(width, height))| {
+                let path = dir
+                    .join(format!("cover{}.{}", i, extension))
+                    .to_string_lossy()
+                    .to_string();
+                RgbaImage::from_pixel(width, height, image::Rgba([i as u8 * 40, 100, 150, 255]))
+                    .save(&path)
+                    .unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_legacy_version() {
+        let body_bytes = crypto::encrypt(b"Hello, World!", "default-key").unwrap();
+        let mut header = get_message_header_bytes(
+            FLAGS_PROTOCOL_VERSION,
+            &body_bytes,
+            Layout::default(),
+            0,
+            Cipher::default(),
+        );
+        header[0] = LEGACY_PROTOCOL_VERSION;
+        let mut data = header[..BASE_HEADER_BYTES].to_vec();
+        data.extend_from_slice(&body_bytes);
+
+        let mut img = RgbaImage::new(32, 32);
+        set_bits_image(&mut img, &convert_bytes_to_bits(&data)).unwrap();
+
+        assert_eq!(
+            decode_images(&[img], "default-key").unwrap().bytes,
+            b"Hello, World!"
+        );
+    }
+
+    #[test]
+    fn test_depth_round_trip() {
+        let (header, body) = frame_message_body(
+            &[0xa5; 100],
+            Layout::Sequential {
+                depth: 3,
+                channels: Channels::ALL,
+            },
+            0,
+            Cipher::default(),
+        );
+        let cover = gradient_cover(20, 20);
+        let mut img = cover.clone();
+        embed_sequential(&mut img, &header, &body, 3, Channels::ALL).unwrap();
+
+        let images = [img];
+        let mut reader = get_bits_reader_images(&images, Channels::ALL);
+        let header = read_message_header(&mut reader).unwrap();
+        assert_eq!(
+            (header.version, header.length, header.depth),
+            (PROTOCOL_VERSION, 100, 3)
+        );
+        let bits = read_bits_at_depth(&mut reader, 800, 3).unwrap();
+        assert_eq!(bits, convert_bytes_to_bits(&[0xa5; 100]));
+        // Header values and 267 body values, nothing beyond them changed
+        let used = HEADER_BYTES * 8 + 267;
+        assert_eq!(images[0].as_raw()[used..], cover.as_raw()[used..]);
+    }
+
+    #[test]
+    fn test_depth_capacity() {
+        let (header, body) = frame_message_body(
+            &[0; 100],
+            Layout::Sequential {
+                depth: 2,
+                channels: Channels::ALL,
+            },
+            0,
+            Cipher::default(),
+        );
+        // Header values + 400 values at 2 bits each, four per pixel
+        let pixels = (HEADER_BYTES * 8 + 400) as u32 / 4;
+        let mut img = RgbaImage::new(pixels - 1, 1);
+        assert!(embed_sequential(&mut img, &header, &body, 2, Channels::ALL).is_err());
+        let mut img = RgbaImage::new(pixels, 1);
+        assert!(embed_sequential(&mut img, &header, &body, 2, Channels::ALL).is_ok());
+    }
+
+    #[test]
+    fn test_message_capacity() {
+        // One bit per value: a byte per eight values, less the overhead
+        assert_eq!(
+            message_capacity(8000, 1, false),
+            1000 - MESSAGE_OVERHEAD_BYTES
+        );
+        assert_eq!(message_capacity(10, 1, false), 0);
+
+        // Exactly as much as fits, at a depth where the header and the
+        // message take different bits per value
+        let mut img = RgbaImage::new(30, 7);
+        let capacity = message_capacity(img.len(), 3, false);
+        let payload = |plaintext: usize| {
+            let body = vec![0; plaintext + MESSAGE_OVERHEAD_BYTES - HEADER_BYTES];
+            frame_message_body(
+                &body,
+                Layout::Sequential {
+                    depth: 3,
+                    channels: Channels::ALL,
+                },
+                0,
+                Cipher::default(),
+            )
+        };
+        let (header, body) = payload(capacity);
+        assert!(embed_sequential(&mut img, &header, &body, 3, Channels::ALL).is_ok());
+        let (header, body) = payload(capacity + 1);
+        assert!(embed_sequential(&mut img, &header, &body, 3, Channels::ALL).is_err());
+
+        // With parity, the largest message whose error-corrected form fits
+        let capacity = message_capacity(img.len(), 3, true);
+        let body_bits = |plaintext: usize| {
+            ecc::encoded_len(plaintext + MESSAGE_OVERHEAD_BYTES - HEADER_BYTES) * 8
+        };
+        let available = (img.len() - HEADER_BYTES * 8) * 3;
+        assert!(body_bits(capacity) <= available);
+        assert!(body_bits(capacity + 1) > available);
+    }
+
+    #[test]
+    fn test_capacity_report() {
+        let dir = TestDir::new("capacity");
+        let opaque = dir.join("opaque.png").to_string_lossy().to_string();
+        let half = dir.join("half.png").to_string_lossy().to_string();
+        image::RgbImage::from_pixel(40, 20, image::Rgb([1, 2, 3]))
+            .save(&opaque)
+            .unwrap();
+        RgbaImage::from_fn(40, 20, |x, _| image::Rgba([1, 2, 3, (x % 2 * 255) as u8]))
+            .save(&half)
+            .unwrap();
+        assert_eq!(
+            probe_image(&opaque).unwrap().color_type,
+            image::ColorType::Rgb8
+        );
+
+        let paths = [opaque.clone(), half.clone()];
+        let all = capacity_report(&paths, 1, Channels::ALL, false, false, None).unwrap();
+        let opaque_only = capacity_report(&paths, 1, Channels::ALL, false, true, None).unwrap();
+        assert_eq!(
+            all.images[0].capacity,
+            message_capacity(40 * 20 * 4, 1, false)
+        );
+        assert_eq!(opaque_only.images[0].capacity, all.images[0].capacity);
+        assert_eq!(
+            opaque_only.images[1].capacity,
+            message_capacity(20 * 20 * 4, 1, false)
+        );
+        assert_eq!(
+            message_capacity_from_memory(&fs::read(&opaque).unwrap()).unwrap(),
+            all.images[0].capacity
+        );
+    }
+
+    #[test]
+    fn test_channels_round_trip() {
+        let cover = gradient_cover(20, 20);
+        let channels: Channels = "rgb".parse().unwrap();
+        let body_bytes =
+            crypto::encrypt_with_kdf(b"Hello, World!", "default-key", &CHEAP_KDF).unwrap();
+        let (header, body) = frame_message_body(
+            &body_bytes,
+            Layout::Sequential { depth: 2, channels },
+            0,
+            Cipher::default(),
+        );
+        let mut img = cover.clone();
+        embed_sequential(&mut img, &header, &body, 2, channels).unwrap();
+
+        assert!(
+            img.pixels().zip(cover.pixels()).all(|(a, b)| a[3] == b[3]),
+            "alpha is left untouched"
+        );
+        let header = find_message_header(std::slice::from_ref(&img), Selection::WHOLE).unwrap();
+        assert_eq!((header.depth, header.channels), (2, channels));
+        assert_eq!(
+            decode_images(&[img], "default-key").unwrap().bytes,
+            b"Hello, World!"
+        );
+    }
+
+    #[test]
+    fn test_scattered_round_trip() {
+        let cover = gradient_cover(20, 20);
+        let channels: Channels = "gb".parse().unwrap();
+        let body_bytes =
+            crypto::encrypt_with_kdf(b"Hello, World!", "scatter-key", &CHEAP_KDF).unwrap();
+        let layout = Layout::Scattered { depth: 2, channels };
+        let (header, body) = frame_message_body(&body_bytes, layout, 0, Cipher::default());
+        let mut img = cover.clone();
+        embed_scattered(&mut img, &header, &body, 2, channels, "scatter-key", 0).unwrap();
+
+        assert!(
+            find_message_header(std::slice::from_ref(&img), Selection::WHOLE).is_err(),
+            "no header at the start of the image"
+        );
+        assert!(decode_images(std::slice::from_ref(&img), "other-key").is_err());
+        assert_eq!(
+            decode_images(&[img], "scatter-key").unwrap().bytes,
+            b"Hello, World!"
+        );
+    }
+
+    #[test]
+    fn test_decoy_round_trip() {
+        let dir = TestDir::new("decoy");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        gradient_cover(64, 64).save(&cover_path).unwrap();
+
+        let layout = Layout::Scattered {
+            depth: 2,
+            channels: "rgb".parse().unwrap(),
+        };
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            decoy: Some(Decoy {
+                message: b"shopping list".to_vec(),
+                key: "decoy-key".to_string(),
+            }),
+            ..Default::default()
+        };
+        let message = "the real message\n".repeat(50);
+        encode_from_file(
+            &cover_path,
+            message.as_bytes(),
+            &output_path,
+            false,
+            layout,
+            "real-key",
+            &payload,
+        )
+        .unwrap();
+
+        let stego = image::open(&output_path).unwrap().to_rgba8();
+        assert!(decoy_end(&stego, Selection::WHOLE).is_some());
+        assert_eq!(
+            decode_images(std::slice::from_ref(&stego), "decoy-key")
+                .unwrap()
+                .bytes,
+            b"shopping list"
+        );
+        assert_eq!(
+            decode_images(std::slice::from_ref(&stego), "real-key")
+                .unwrap()
+                .bytes,
+            message.as_bytes()
+        );
+        assert!(decode_images(&[stego], "other-key").is_err());
+
+        let Err(error) = encode_from_file(
+            &cover_path,
+            message.as_bytes(),
+            &output_path,
+            false,
+            Layout::default(),
+            "real-key",
+            &payload,
+        ) else {
+            panic!("a decoy needs the message scattered");
+        };
+        assert!(error.message().contains("scattered"), "{}", error);
+    }
+
+    #[test]
+    fn test_noise_fill() {
+        let dir = TestDir::new("noise");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        // Flat alpha is left out of the fill
+        let cover = RgbaImage::from_fn(40, 40, |x, y| {
+            image::Rgba([x as u8 * 4, y as u8 * 4, (x + y) as u8 * 2, 254])
+        });
+        cover.save(&cover_path).unwrap();
+
+        let scattered = Layout::Scattered {
+            depth: 1,
+            channels: Channels::ALL,
+        };
+        for layout in [Layout::default(), scattered] {
+            for noise_fill in [false, true] {
+                let payload = PayloadOptions {
+                    kdf: CHEAP_KDF,
+                    noise_fill,
+                    ..Default::default()
+                };
+                encode_from_file(
+                    &cover_path,
+                    b"Hello, World!",
+                    &output_path,
+                    false,
+                    layout,
+                    "default-key",
+                    &payload,
+                )
+                .unwrap();
+
+                let stego = image::open(&output_path).unwrap().to_rgba8();
+                let changed = cover.iter().zip(stego.iter()).filter(|(a, b)| a != b);
+                // The message takes well under a tenth of the values
+                if noise_fill {
+                    assert!(changed.count() > cover.len() / 3);
+                } else {
+                    assert!(changed.count() < cover.len() / 10);
+                }
+                assert_eq!(
+                    decode_images(&[stego], "default-key").unwrap().bytes,
+                    b"Hello, World!"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_lsb_match() {
+        let dir = TestDir::new("match");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        let cover = RgbaImage::from_pixel(40, 40, image::Rgba([100, 150, 200, 254]));
+        cover.save(&cover_path).unwrap();
+
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            method: Method::LsbMatch,
+            ..Default::default()
+        };
+        encode_from_file(
+            &cover_path,
+            b"Hello, World!",
+            &output_path,
+            false,
+            Layout::default(),
+            "default-key",
+            &payload,
+        )
+        .unwrap();
+
+        let stego = image::open(&output_path).unwrap().to_rgba8();
+        assert!(
+            cover
+                .iter()
+                .zip(stego.iter())
+                .all(|(&a, &b)| (a as i16 - b as i16).abs() <= 1)
+        );
+        // Replacing would only ever make the even values odd
+        assert!(stego.contains(&99));
+        assert_eq!(
+            find_message_header(std::slice::from_ref(&stego), Selection::WHOLE)
+                .unwrap()
+                .method,
+            Method::LsbMatch
+        );
+        assert_eq!(
+            decode_images(&[stego], "default-key").unwrap().bytes,
+            b"Hello, World!"
+        );
+    }
+
+    #[test]
+    fn test_adaptive_round_trip() {
+        let dir = TestDir::new("adaptive");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        // A flat sky over a textured ground
+        let cover = RgbaImage::from_fn(48, 48, |x, y| {
+            let value = if y < 24 { 180 } else { (x * 37 + y * 91) as u8 };
+            image::Rgba([value, value / 2, 90, 255])
+        });
+        cover.save(&cover_path).unwrap();
+
+        let layout = Layout::Adaptive {
+            depth: 2,
+            channels: "rg".parse().unwrap(),
+        };
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            ..Default::default()
+        };
+        encode_from_file(
+            &cover_path,
+            b"Hello, World!",
+            &output_path,
+            false,
+            layout,
+            "adaptive-key",
+            &payload,
+        )
+        .unwrap();
+
+        let stego = image::open(&output_path).unwrap().to_rgba8();
+        let (sky, ground) = (23 * 48 * 4, 25 * 48 * 4);
+        assert_eq!(
+            stego.as_raw()[..sky],
+            cover.as_raw()[..sky],
+            "sky untouched"
+        );
+        assert_ne!(stego.as_raw()[ground..], cover.as_raw()[ground..]);
+        assert!(decode_images(std::slice::from_ref(&stego), "other-key").is_err());
+        assert_eq!(
+            decode_images(&[stego], "adaptive-key").unwrap().bytes,
+            b"Hello, World!"
+        );
+
+        // Moving a value by one changes the bits its texture is measured on
+        let matched = PayloadOptions {
+            method: Method::LsbMatch,
+            ..payload.clone()
+        };
+        let Err(error) = encode_from_file(
+            &cover_path,
+            b"Hello, World!",
+            &output_path,
+            false,
+            layout,
+            "adaptive-key",
+            &matched,
+        ) else {
+            panic!("LSB matching would move the textured values");
+        };
+        assert!(error.message().contains("LSB matching"), "{}", error);
+    }
+
+    #[test]
+    fn test_skip_transparent() {
+        let dir = TestDir::new("transparent");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        // Transparent on the left, barely opaque on the right
+        let cover = RgbaImage::from_fn(40, 40, |x, y| {
+            let alpha = if x < 20 {
+                (x % 16) as u8
+            } else {
+                16 + (y % 4) as u8
+            };
+            image::Rgba([0, x as u8 * 3, y as u8 * 5, alpha])
+        });
+        cover.save(&cover_path).unwrap();
+
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            skip_transparent: true,
+            ..Default::default()
+        };
+        let scattered = Layout::Scattered {
+            depth: 2,
+            channels: Channels::ALL,
+        };
+        for (layout, method) in [
+            (Layout::default(), Method::LsbReplace),
+            (scattered, Method::LsbMatch),
+        ] {
+            let payload = PayloadOptions {
+                method,
+                ..payload.clone()
+            };
+            encode_from_file(
+                &cover_path,
+                b"Hello, World!",
+                &output_path,
+                false,
+                layout,
+                "default-key",
+                &payload,
+            )
+            .unwrap();
+
+            let stego = image::open(&output_path).unwrap().to_rgba8();
+            assert!(
+                cover
+                    .enumerate_pixels()
+                    .filter(|(x, _, _)| *x < 20)
+                    .all(|(x, y, pixel)| stego.get_pixel(x, y) == pixel),
+                "transparent pixels untouched"
+            );
+            assert_eq!(opaque_pixels(&stego), opaque_pixels(&cover));
+            assert!(find_message_header(slice::from_ref(&stego), Selection::WHOLE).is_err());
+            assert_eq!(
+                decode_images(&[stego], "default-key").unwrap().bytes,
+                b"Hello, World!"
+            );
+        }
+        assert!(
+            inspect_file(&output_path)
+                .map(|inspection| inspection.transparent_skipped)
+                .is_err(),
+            "a scattered header is not found without the key"
+        );
+
+        encode_from_file(
+            &cover_path,
+            b"Hello, World!",
+            &output_path,
+            false,
+            Layout::default(),
+            "default-key",
+            &payload,
+        )
+        .unwrap();
+        assert_eq!(
+            inspect_file(&output_path).unwrap().transparent_skipped,
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_region() {
+        let dir = TestDir::new("region");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        let cover = RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([x as u8 * 4, y as u8 * 4, (x + y) as u8, 255])
+        });
+        cover.save(&cover_path).unwrap();
+
+        let rect = Region::Rect("10,20,30,16".parse().unwrap());
+        let mut mask = image::GrayImage::new(64, 64);
+        for (x, y, pixel) in mask.enumerate_pixels_mut() {
+            if (x as i32 - 40).pow(2) + (y as i32 - 30).pow(2) < 15 * 15 {
+                *pixel = image::Luma([255]);
+            }
+        }
+        let scattered = Layout::Scattered {
+            depth: 2,
+            channels: Channels::ALL,
+        };
+        for (region, layout, method) in [
+            (rect, Layout::default(), Method::LsbReplace),
+            (Region::Mask(mask), scattered, Method::LsbMatch),
+        ] {
+            let payload = PayloadOptions {
+                kdf: CHEAP_KDF,
+                method,
+                region: Some(region.clone()),
+                ..Default::default()
+            };
+            encode_from_file(
+                &cover_path,
+                b"Hello, World!",
+                &output_path,
+                false,
+                layout,
+                "default-key",
+                &payload,
+            )
+            .unwrap();
+
+            let stego = image::open(&output_path).unwrap().to_rgba8();
+            let record = region.record(64, 64, false).unwrap();
+            let inside: HashSet<usize> = record.pixels(64).into_iter().collect();
+            let outside: Vec<(u8, u8)> = cover
+                .as_raw()
+                .iter()
+                .zip(stego.as_raw())
+                .enumerate()
+                .filter(|(i, (from, to))| !inside.contains(&(i / 4)) && from != to)
+                .map(|(_, (&from, &to))| (from, to))
+                .collect();
+            assert!(
+                outside.len() <= record.bits() && outside.iter().all(|(from, to)| from ^ to == 1),
+                "only the record outside the region"
+            );
+            assert!(find_message_header(slice::from_ref(&stego), Selection::WHOLE).is_err());
+            assert_eq!(
+                decode_images(slice::from_ref(&stego), "default-key")
+                    .unwrap()
+                    .bytes,
+                b"Hello, World!"
+            );
+            assert!(decode_images(&[stego], "other-key").is_err());
+        }
+
+        let payload = PayloadOptions {
+            region: Some(Region::Rect("60,0,10,10".parse().unwrap())),
+            ..Default::default()
+        };
+        let encode = |layout| {
+            encode_from_file(
+                &cover_path,
+                b"Hello, World!",
+                &output_path,
+                false,
+                layout,
+                "default-key",
+                &payload,
+            )
+        };
+        assert!(
+            encode(Layout::default()).is_err(),
+            "region outside the image"
+        );
+        assert!(
+            encode(Layout::Adaptive {
+                depth: 1,
+                channels: Channels::ALL
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_jpeg() {
+        let dir = TestDir::new("jpeg");
+        let cover_path = dir.join("cover.jpg").to_string_lossy().to_string();
+        let output_path = dir.join("output.jpg").to_string_lossy().to_string();
+        let cover = image::RgbImage::from_fn(96, 64, |x, y| {
+            image::Rgb([(x * y) as u8, (x * 5 + y * 3) as u8, ((x ^ y) * 7) as u8])
+        });
+        cover.save(&cover_path).unwrap();
+
+        let kdf = CHEAP_KDF;
+        for pad_to in [None, Some(PadTo::Full)] {
+            let payload = PayloadOptions {
+                kdf,
+                pad_to,
+                ..Default::default()
+            };
+            let report = encode_jpeg_file(
+                &cover_path,
+                b"Hello, World!",
+                &output_path,
+                "default-key",
+                &payload,
+            )
+            .unwrap();
+            assert!(report.images[0].quality.psnr > 30.0);
+
+            let paths = [output_path.clone()];
+            assert_eq!(
+                decode_from_files(&paths, "default-key").unwrap(),
+                b"Hello, World!"
+            );
+            assert!(decode_from_files(&paths, "other-key").is_err());
+            let bytes = fs::read(&output_path).unwrap();
+            assert_eq!(
+                decode_from_memory(&bytes, "default-key").unwrap(),
+                b"Hello, World!"
+            );
+        }
+
+        let payload = PayloadOptions {
+            kdf,
+            ..Default::default()
+        };
+        let encode = |message: &[u8], payload: &PayloadOptions| {
+            encode_jpeg_file(&cover_path, message, &output_path, "default-key", payload)
+        };
+        assert!(
+            encode(&crypto::random_bytes(4096), &payload).is_err(),
+            "too long"
+        );
+        let lsb_match = PayloadOptions {
+            method: Method::LsbMatch,
+            ..payload.clone()
+        };
+        assert!(encode(b"Hello", &lsb_match).is_err());
+        let png_path = dir.join("cover.png").to_string_lossy().to_string();
+        cover.save(&png_path).unwrap();
+        assert!(
+            encode_jpeg_file(&png_path, b"Hello", &output_path, "default-key", &payload).is_err()
+        );
+    }
+
+    #[test]
+    fn test_chunk() {
+        let dir = TestDir::new("chunk");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        let cover = RgbaImage::from_fn(4, 4, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        cover.save(&cover_path).unwrap();
+
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            ..Default::default()
+        };
+        // Far more than the pixels could hold
+        let message = crypto::random_bytes(4096);
+        let report =
+            encode_chunk_file(&cover_path, &message, &output_path, "default-key", &payload)
+                .unwrap();
+        assert_eq!(report.images[0].detectability.level, "high");
+        assert_eq!(read_image(&output_path).unwrap(), cover);
+
+        let paths = [output_path.clone()];
+        assert_eq!(decode_from_files(&paths, "default-key").unwrap(), message);
+        assert!(decode_from_files(&paths, "other-key").is_err());
+        let bytes = fs::read(&output_path).unwrap();
+        assert_eq!(decode_from_memory(&bytes, "default-key").unwrap(), message);
+
+        let webp_path = dir.join("output.webp").to_string_lossy().to_string();
+        assert!(
+            encode_chunk_file(&cover_path, b"Hello", &webp_path, "default-key", &payload).is_err()
+        );
+        let full = PayloadOptions {
+            pad_to: Some(PadTo::Full),
+            ..payload.clone()
+        };
+        assert!(
+            encode_chunk_file(&cover_path, b"Hello", &output_path, "default-key", &full).is_err()
+        );
+    }
+
+    #[test]
+    fn test_audio() {
+        let dir = TestDir::new("audio");
+        let cover_path = dir.join("cover.wav").to_string_lossy().to_string();
+        let output_path = dir.join("output.wav").to_string_lossy().to_string();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&cover_path, spec).unwrap();
+        for i in 0..4000i32 {
+            writer.write_sample((i * 37 % 2000 - 1000) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            ..Default::default()
+        };
+        encode_audio_file(
+            &cover_path,
+            b"Hello, World!",
+            &output_path,
+            "default-key",
+            &payload,
+        )
+        .unwrap();
+        let cover = Audio::read(&cover_path).unwrap();
+        let stego = Audio::read(&output_path).unwrap();
+        assert!(
+            cover
+                .samples()
+                .iter()
+                .zip(stego.samples())
+                .all(|(a, b)| (a - b).abs() <= 1)
+        );
+
+        let paths = [output_path.clone()];
+        assert_eq!(
+            decode_from_files(&paths, "default-key").unwrap(),
+            b"Hello, World!"
+        );
+        assert!(decode_from_files(&paths, "other-key").is_err());
+        let bytes = fs::read(&output_path).unwrap();
+        assert_eq!(
+            decode_from_memory(&bytes, "default-key").unwrap(),
+            b"Hello, World!"
+        );
+
+        let encode = |message: &[u8], output: &str| {
+            encode_audio_file(&cover_path, message, output, "default-key", &payload)
+        };
+        assert!(encode(&crypto::random_bytes(1024), &output_path).is_err());
+        let png_path = dir.join("output.png").to_string_lossy().to_string();
+        assert!(encode(b"Hello", &png_path).is_err());
+    }
+
+    #[test]
+    fn test_wipe_file() {
+        let dir = TestDir::new("wipe");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let stego_path = dir.join("stego.png").to_string_lossy().to_string();
+        let wiped_path = dir.join("wiped.png").to_string_lossy().to_string();
+        let cover = RgbaImage::from_fn(32, 32, |x, y| {
+            image::Rgba([x as u8 * 8, y as u8 * 8, (x * y) as u8, 255])
+        });
+        cover.save(&cover_path).unwrap();
+
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            ..Default::default()
+        };
+        let layout = Layout::Sequential {
+            depth: 2,
+            channels: Channels::ALL,
+        };
+        encode_from_file(
+            &cover_path,
+            b"Hello, World!",
+            &stego_path,
+            false,
+            layout,
+            "default-key",
+            &payload,
+        )
+        .unwrap();
+        assert!(decode_from_files(slice::from_ref(&stego_path), "default-key").is_ok());
+
+        for zero in [false, true] {
+            wipe_file(&stego_path, &wiped_path, MAX_DEPTH, zero).unwrap();
+            let wiped = read_image(&wiped_path).unwrap();
+            assert!(decode_from_files(slice::from_ref(&wiped_path), "default-key").is_err());
+            assert_eq!(read_sequence_info(&wiped_path).unwrap(), None);
+            for (i, (&from, &to)) in cover.as_raw().iter().zip(wiped.as_raw()).enumerate() {
+                if i % 4 == 3 {
+                    assert_eq!(to, 255, "opaque alpha is made opaque again");
+                } else {
+                    assert_eq!(from >> MAX_DEPTH, to >> MAX_DEPTH);
+                    if zero {
+                        assert_eq!(to & 0x0F, 0);
+                    }
+                }
+            }
+        }
+        assert!(wipe_file(&stego_path, &wiped_path, MAX_DEPTH + 1, false).is_err());
+    }
+
+    #[test]
+    fn test_check_payload() {
+        let kdf = CHEAP_KDF;
+        let body_bytes = crypto::encrypt_with_kdf(b"Hello, World!", "default-key", &kdf).unwrap();
+        let (header_bits, body) =
+            frame_message_body(&body_bytes, Layout::default(), 0, Cipher::default());
+        let mut img = RgbaImage::new(20, 20);
+        embed_sequential(&mut img, &header_bits, &body, 1, Channels::ALL).unwrap();
+
+        let mut header = find_message_header(std::slice::from_ref(&img), Selection::WHOLE).unwrap();
+        let mut problems = Vec::new();
+        assert_eq!(check_payload(&img, &header, true, &mut problems), Some(kdf));
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        header.length = 10;
+        check_payload(&img, &header, true, &mut problems);
+        assert_eq!(problems.len(), 1, "too short: {:?}", problems);
+
+        problems.clear();
+        header.length = 1000;
+        check_payload(&img, &header, true, &mut problems);
+        assert_eq!(problems.len(), 1, "too long: {:?}", problems);
+        problems.clear();
+        check_payload(&img, &header, false, &mut problems);
+        assert!(problems.is_empty(), "may continue in the next image");
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let message = "all work and no play makes jack a dull boy\n".repeat(200);
+        let (header, body) = get_message_bits(
+            message.as_bytes(),
+            "default-key",
+            &PayloadOptions {
+                kdf: CHEAP_KDF,
+                ecc: false,
+                file: None,
+                archive: false,
+                plain: false,
+                compress: true,
+                cipher: Cipher::default(),
+                recipients: Vec::new(),
+                pad_to: None,
+                decoy: None,
+                noise_fill: true,
+                method: Method::default(),
+                skip_transparent: false,
+                region: None,
+                max_fill: None,
+                convert_srgb: false,
+                strip_metadata: false,
+                sequence_chunk: SequenceChunk::default(),
+                png_encoding: PngEncoding::default(),
+            },
+            Layout::default(),
+        )
+        .unwrap();
+        assert!(body.len() / 8 < message.len() / 5, "compressed");
+
+        let mut img = RgbaImage::new(40, 40);
+        embed_sequential(&mut img, &header, &body, 1, Channels::ALL).unwrap();
+        let header = find_message_header(std::slice::from_ref(&img), Selection::WHOLE).unwrap();
+        assert!(header.compressed);
+        assert_eq!(
+            decode_images(&[img], "default-key").unwrap().bytes,
+            message.as_bytes()
+        );
+
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            compress: false,
+            ..Default::default()
+        };
+        let (_, body) = get_message_bits(
+            message.as_bytes(),
+            "default-key",
+            &payload,
+            Layout::default(),
+        )
+        .unwrap();
+        assert!(body.len() / 8 > message.len(), "not compressed");
+    }
+
+    #[test]
+    fn test_file_info_round_trip() {
+        let file = FileInfo {
+            name: "notes.txt".to_string(),
+            mime: "text/plain".to_string(),
+            modified: Some(1_700_000_000),
+        };
+        let (header, body) = get_message_bits(
+            b"Hello, World!",
+            "default-key",
+            &PayloadOptions {
+                kdf: CHEAP_KDF,
+                ecc: false,
+                file: Some(file.clone()),
+                archive: false,
+                plain: false,
+                compress: true,
+                cipher: Cipher::default(),
+                recipients: Vec::new(),
+                pad_to: None,
+                decoy: None,
+                noise_fill: true,
+                method: Method::default(),
+                skip_transparent: false,
+                region: None,
+                max_fill: None,
+                convert_srgb: false,
+                strip_metadata: false,
+                sequence_chunk: SequenceChunk::default(),
+                png_encoding: PngEncoding::default(),
+            },
+            Layout::default(),
+        )
+        .unwrap();
+
+        let mut img = RgbaImage::new(40, 40);
+        embed_sequential(&mut img, &header, &body, 1, Channels::ALL).unwrap();
+        assert!(
+            find_message_header(std::slice::from_ref(&img), Selection::WHOLE)
+                .unwrap()
+                .file
+        );
+        assert_eq!(
+            decode_images(&[img], "default-key").unwrap(),
+            DecodedMessage {
+                bytes: b"Hello, World!".to_vec(),
+                file: Some(file),
+                archive: false,
+                sealed: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_ecc_corrects_damage() {
+        let encode = |ecc| {
+            let payload = PayloadOptions {
+                kdf: CHEAP_KDF,
+                ecc,
+                file: None,
+                archive: false,
+                plain: false,
+                compress: true,
+                cipher: Cipher::default(),
+                recipients: Vec::new(),
+                pad_to: None,
+                decoy: None,
+                noise_fill: true,
+                method: Method::default(),
+                skip_transparent: false,
+                region: None,
+                max_fill: None,
+                convert_srgb: false,
+                strip_metadata: false,
+                sequence_chunk: SequenceChunk::default(),
+                png_encoding: PngEncoding::default(),
+            };
+            let (header, body) =
+                get_message_bits(&[0x42; 300], "default-key", &payload, Layout::default()).unwrap();
+            let mut img = RgbaImage::new(60, 60);
+            embed_sequential(&mut img, &header, &body, 1, Channels::ALL).unwrap();
+            // Twelve damaged bytes just after the header
+            for value in &mut img.as_mut()[HEADER_BYTES * 8..HEADER_BYTES * 8 + 96] {
+                *value ^= 1;
+            }
+            img
+        };
+
+        assert_eq!(
+            decode_images(&[encode(true)], "default-key").unwrap().bytes,
+            [0x42; 300]
+        );
+        assert!(decode_images(&[encode(false)], "default-key").is_err());
+    }
+
+    #[test]
+    fn test_key_check_errors() {
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            ..Default::default()
+        };
+        let (header, body) =
+            get_message_bits(&[0x42; 100], "default-key", &payload, Layout::default()).unwrap();
+        let mut img = RgbaImage::new(40, 40);
+        embed_sequential(&mut img, &header, &body, 1, Channels::ALL).unwrap();
+        assert!(
+            find_message_header(std::slice::from_ref(&img), Selection::WHOLE)
+                .unwrap()
+                .key_check
+        );
+
+        let error = decode_images(std::slice::from_ref(&img), "wrong-key").unwrap_err();
+        assert!(error.message().starts_with("Wrong key"), "{}", error);
+        assert!(matches!(error, LowkeyError::Crypto(_)));
+
+        // Damage past the key check, in the ciphertext
+        let start = (HEADER_BYTES + KDF_HEADER_BYTES + KEY_CHECK_BYTES + 12) * 8;
+        for value in &mut img.as_mut()[start..start + 16] {
+            *value ^= 1;
+        }
+        let error = decode_images(&[img], "default-key").unwrap_err();
+        assert!(
+            error.message().starts_with("The message is damaged"),
+            "{}",
+            error
+        );
+        assert!(matches!(error, LowkeyError::Crypto(_)));
+
+        let error = decode_images(&[RgbaImage::new(40, 40)], "default-key").unwrap_err();
+        assert!(
+            error.message().starts_with("No lowkey message found"),
+            "{}",
+            error
+        );
+        assert!(matches!(error, LowkeyError::NoMessage(_)));
+    }
+
+    #[test]
+    fn test_cipher_round_trip() {
+        let kdf = CHEAP_KDF;
+        for cipher in [
+            Cipher::ChaCha20Poly1305,
+            Cipher::XChaCha20Poly1305,
+            Cipher::Aes256Gcm,
+        ] {
+            let payload = PayloadOptions {
+                kdf,
+                cipher,
+                ..Default::default()
+            };
+            let (header, body) =
+                get_message_bits(b"Hello, World!", "default-key", &payload, Layout::default())
+                    .unwrap();
+            let mut img = RgbaImage::new(20, 20);
+            embed_sequential(&mut img, &header, &body, 1, Channels::ALL).unwrap();
+            assert_eq!(
+                find_message_header(std::slice::from_ref(&img), Selection::WHOLE)
+                    .unwrap()
+                    .cipher,
+                cipher
+            );
+            assert_eq!(
+                decode_images(&[img], "default-key").unwrap().bytes,
+                b"Hello, World!"
+            );
+        }
+
+        // Version 6 has no cipher byte and is always ChaCha20-Poly1305
+        let body_bytes =
+            crypto::encrypt_chunked(b"Hello, World!", "default-key", &kdf, Cipher::default())
+                .unwrap();
+        let header = get_message_header_bytes(
+            WIDE_LENGTH_PROTOCOL_VERSION,
+            &body_bytes,
+            Layout::default(),
+            KEY_CHECK_FLAG | CHUNKED_FLAG,
+            Cipher::Aes256Gcm,
+        );
+        assert_eq!(header.len(), header_bytes(WIDE_LENGTH_PROTOCOL_VERSION));
+        let mut img = RgbaImage::new(20, 20);
+        embed_sequential(
+            &mut img,
+            &convert_bytes_to_bits(&header),
+            &convert_bytes_to_bits(&body_bytes),
+            1,
+            Channels::ALL,
+        )
+        .unwrap();
+        assert_eq!(
+            decode_images(&[img], "default-key").unwrap().bytes,
+            b"Hello, World!"
+        );
+    }
+
+    #[test]
+    fn test_recipients_round_trip() {
+        use age::secrecy::ExposeSecret;
+
+        let identity = age::x25519::Identity::generate();
+        let dir = TestDir::new("identity");
+        let identity_path = dir.join("identity.txt").to_string_lossy().to_string();
+        std::fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+
+        let file = FileInfo {
+            name: "notes.txt".to_string(),
+            mime: "text/plain".to_string(),
+            modified: None,
+        };
+        let payload = PayloadOptions {
+            file: Some(file.clone()),
+            recipients: vec![identity.to_public().to_string()],
+            ..Default::default()
+        };
+        let message = "all work and no play makes jack a dull boy\n".repeat(20);
+        let (header, body) = get_message_bits(
+            message.as_bytes(),
+            "default-key",
+            &payload,
+            Layout::default(),
+        )
+        .unwrap();
+        let mut img = RgbaImage::new(40, 40);
+        embed_sequential(&mut img, &header, &body, 1, Channels::ALL).unwrap();
+        let found = find_message_header(std::slice::from_ref(&img), Selection::WHOLE).unwrap();
+        assert!(found.recipients && found.compressed && !found.chunked);
+
+        // The key only places the bits; the identity decrypts
+        let sealed = decode_images(&[img], "other-key").unwrap();
+        assert!(sealed.sealed.is_some());
+        let error = sealed.clone().open(&[]).unwrap_err();
+        assert!(error.message().contains("identity"), "{}", error);
+        let opened = sealed.open(std::slice::from_ref(&identity_path)).unwrap();
+        assert_eq!(opened.bytes, message.as_bytes());
+        assert_eq!(opened.file, Some(file));
+    }
+
+    #[test]
+    fn test_plain_message() {
+        let payload = PayloadOptions {
+            plain: true,
+            ..Default::default()
+        };
+        let message = b"-----BEGIN AGE ENCRYPTED FILE-----";
+        let (header, body) =
+            get_message_bits(message, "default-key", &payload, Layout::default()).unwrap();
+        // No encryption overhead, at most compressed
+        assert!(body.len() <= message.len() * 8);
+        let mut img = RgbaImage::new(20, 20);
+        embed_sequential(&mut img, &header, &body, 1, Channels::ALL).unwrap();
+
+        let found = find_message_header(std::slice::from_ref(&img), Selection::WHOLE).unwrap();
+        assert!(found.plain && !found.chunked && !found.key_check);
+        let mut problems = Vec::new();
+        assert_eq!(check_payload(&img, &found, true, &mut problems), None);
+        assert!(problems.is_empty(), "{:?}", problems);
+        // The key only places the bits, which a sequential message doesn't use
+        assert_eq!(decode_images(&[img], "other-key").unwrap().bytes, message);
+    }
+
+    #[test]
+    fn test_padded_message() {
+        let kdf = CHEAP_KDF;
+        for ecc in [false, true] {
+            let payload = PayloadOptions {
+                kdf,
+                ecc,
+                pad_to: Some(PadTo::Bytes(1000)),
+                ..Default::default()
+            };
+            // Same size as embedded, however long the message
+            let (header, short) =
+                get_message_bits(b"hi", "default-key", &payload, Layout::default()).unwrap();
+            let (_, long) =
+                get_message_bits(&[7; 500], "default-key", &payload, Layout::default()).unwrap();
+            assert_eq!(short.len(), long.len());
+            assert!(short.len() <= 1000 * 8 && short.len() > 900 * 8);
+
+            let mut img = RgbaImage::new(50, 50);
+            embed_sequential(&mut img, &header, &short, 1, Channels::ALL).unwrap();
+            assert!(
+                find_message_header(std::slice::from_ref(&img), Selection::WHOLE)
+                    .unwrap()
+                    .padded
+            );
+            assert_eq!(decode_images(&[img], "default-key").unwrap().bytes, b"hi");
+        }
+
+        let payload = PayloadOptions {
+            kdf,
+            pad_to: Some(PadTo::Bytes(50)),
+            ..Default::default()
+        };
+        assert!(get_message_bits(&[7; 100], "default-key", &payload, Layout::default()).is_err());
+
+        // Rounded up to a power of two
+        let payload = PayloadOptions {
+            kdf,
+            pad_to: Some(PadTo::PowerOfTwo),
+            ..Default::default()
+        };
+        let body_bits = |message: &[u8]| {
+            get_message_bits(message, "default-key", &payload, Layout::default())
+                .unwrap()
+                .1
+                .len()
+        };
+        assert_eq!(body_bits(b"hi"), body_bits(&[7; 500]));
+        assert!(body_bits(b"hi") <= 1024 * 8);
+        let noise: Vec<u8> = (0..1500).map(|i| splitmix64(i) as u8).collect();
+        assert!(body_bits(&noise) > 1024 * 8 && body_bits(&noise) <= 2048 * 8);
+
+        assert_eq!("full".parse::<PadTo>().unwrap(), PadTo::Full);
+        assert_eq!("pow2".parse::<PadTo>().unwrap(), PadTo::PowerOfTwo);
+        assert_eq!("4096".parse::<PadTo>().unwrap(), PadTo::Bytes(4096));
+        assert!("4k".parse::<PadTo>().is_err());
+        assert_eq!(
+            unpad_message(pad_message(b"abc", 20).unwrap()).unwrap(),
+            b"abc"
+        );
+        assert!(unpad_message(vec![0, 0, 0, 0, 0, 0, 0, 9, 1]).is_err());
+    }
+
+    #[test]
+    fn test_pad_to_full() {
+        let dir = TestDir::new("pad");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        gradient_cover(40, 40).save(&cover_path).unwrap();
+
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            pad_to: Some(PadTo::Full),
+            ..Default::default()
+        };
+        let report = encode_from_file(
+            &cover_path,
+            b"Hello, World!",
+            &output_path,
+            false,
+            Layout::default(),
+            "default-key",
+            &payload,
+        )
+        .unwrap();
+        // Filled to within the last chunk's rounding
+        assert!(report.images[0].detectability.fill_ratio > 0.99);
+        assert_eq!(
+            decode_from_files(&[output_path], "default-key").unwrap(),
+            b"Hello, World!"
+        );
+    }
+
+    #[test]
+    fn test_chunked_round_trip() {
+        let payload = PayloadOptions {
+            kdf: CHEAP_KDF,
+            ..Default::default()
+        };
+        // Incompressible, and a second chunk that doesn't end on a value at
+        // three bits per value
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let message: Vec<u8> = (0..CHUNK_BYTES + 1001)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let layout = Layout::Sequential {
+            depth: 3,
+            channels: Channels::ALL,
+        };
+        let (header, body) = get_message_bits(&message, "default-key", &payload, layout).unwrap();
+        let mut img = RgbaImage::new(1024, 720);
+        embed_sequential(&mut img, &header, &body, 3, Channels::ALL).unwrap();
+        let found = find_message_header(std::slice::from_ref(&img), Selection::WHOLE).unwrap();
+        assert_eq!(found.version, PROTOCOL_VERSION);
+        assert!(found.chunked);
+        assert_eq!(
+            found.length,
+            crypto::chunked_len(message.len(), Cipher::default()) as u64
+        );
+        assert_eq!(decode_images(&[img], "default-key").unwrap().bytes, message);
+
+        // Version 5 messages, with their 4-byte length, still decode
+        let body_bytes =
+            crypto::encrypt_with_key_check(b"Hello, World!", "default-key", &payload.kdf).unwrap();
+        let header = get_message_header_bytes(
+            FLAGS_PROTOCOL_VERSION,
+            &body_bytes,
+            Layout::default(),
+            KEY_CHECK_FLAG,
+            Cipher::default(),
+        );
+        let mut img = RgbaImage::new(32, 32);
+        embed_sequential(
+            &mut img,
+            &convert_bytes_to_bits(&header),
+            &convert_bytes_to_bits(&body_bytes),
+            1,
+            Channels::ALL,
+        )
+        .unwrap();
+        assert_eq!(
+            decode_images(&[img], "default-key").unwrap().bytes,
+            b"Hello, World!"
+        );
+    }
+
+    #[test]
+    fn test_decode_unknown_version() {
+        let mut img = RgbaImage::new(32, 32);
+        let header = [9u8, 0, 0, 0, 28];
+        set_bits_image(&mut img, &convert_bytes_to_bits(&header)).unwrap();
+
+        let error = decode_images(&[img], "default-key").unwrap_err();
+        assert!(
+            error.message().contains("Unsupported protocol version 9"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_16_bit_round_trip() {
+        let dir = TestDir::new("16-bit");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+
+        let cover = Rgba16Image::from_fn(40, 40, |x, y| {
+            image::Rgba([x as u16 * 1500, y as u16 * 1500, 40000, 65535])
+        });
+        cover.save(&cover_path).unwrap();
+
+        encode_from_file(
+            &cover_path,
+            b"Hello, World!",
+            &output_path,
+            false,
+            Layout::default(),
+            "default-key",
+            &PayloadOptions::default(),
+        )
+        .unwrap();
+
+        let stego = image::open(&output_path).unwrap();
+        assert_eq!(stego.color(), image::ColorType::Rgba16);
+        // Only the low bit of each value may change
+        let stego = stego.to_rgba16();
+        assert!(
+            cover
+                .as_raw()
+                .iter()
+                .zip(stego.as_raw())
+                .all(|(a, b)| a & !1 == b & !1)
+        );
+        assert_eq!(
+            decode_from_files(&[output_path], "default-key").unwrap(),
+            b"Hello, World!"
+        );
+    }
+
+    #[test]
+    fn test_sequence_headers() {
+        let dir = TestDir::new("sequence");
+        // BMPs have no room for sequence metadata
+        let covers = save_covers(&dir, "bmp", &[(16, 8); 4]);
+        let message: Vec<u8> = (0..32u8).map(|i| i.wrapping_mul(37)).collect();
+        let encode = |output_dir: &str| {
+            let output_dir = dir.join(output_dir).to_string_lossy().to_string();
+            let report = encode_from_files(
+                &covers,
+                &message,
+                &output_dir,
+                &SetOptions::default(),
+                "default-key",
+                &PayloadOptions::default(),
+            )
+            .unwrap();
+            report
+                .images
+                .into_iter()
+                .map(|image| image.path)
+                .collect::<Vec<_>>()
+        };
+        let outputs = encode("a");
+        // The message ends in the third
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(read_sequence_info(&outputs[2]).unwrap(), None);
+
+        let shuffled = [&outputs[2], &outputs[0], &outputs[1]].map(String::clone);
+        assert_eq!(
+            decode_from_files(&shuffled, "default-key").unwrap(),
+            message
+        );
+
+        // Neither set is complete, and each says what it lacks
+        let other = encode("b");
+        let mixed = [&outputs[0], &other[1], &outputs[2]].map(String::clone);
+        let error = decode_from_files(&mixed, "default-key").unwrap_err();
+        assert!(
+            error
+                .message()
+                .contains("(2 of 3 images): Image 2 of 3 is missing")
+        );
+        assert!(
+            error
+                .message()
+                .contains("(1 of 3 images): Images 1, 3 of 3 are missing")
+        );
+
+        // A complete set is found among others, and copies don't get in the way
+        let mut mixed = other.clone();
+        mixed.extend([&outputs[1], &outputs[1], &outputs[2], &outputs[0]].map(String::clone));
+        mixed.remove(0);
+        assert_eq!(decode_from_files(&mixed, "default-key").unwrap(), message);
+
+        let gap = [outputs[0].clone(), outputs[2].clone()];
+        assert_eq!(
+            decode_from_files(&gap, "default-key").unwrap_err(),
+            LowkeyError::NoMessage("Image 2 of 3 is missing".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sequence_chunk() {
+        let dir = TestDir::new("sequence-chunk");
+        let covers = save_covers(&dir, "png", &[(16, 8); 3]);
+        let message: Vec<u8> = (0..32u8).map(|i| i.wrapping_mul(37)).collect();
+        for (name, sequence_chunk) in [
+            ("encrypted", SequenceChunk::Encrypted),
+            ("off", SequenceChunk::Off),
+        ] {
+            let output_dir = dir.join(name).to_string_lossy().to_string();
+            let options = PayloadOptions {
+                sequence_chunk,
+                ..PayloadOptions::default()
+            };
+            let report = encode_from_files(
+                &covers,
+                &message,
+                &output_dir,
+                &SetOptions::default(),
+                "default-key",
+                &options,
+            )
+            .unwrap();
+            let outputs: Vec<String> = report.images.into_iter().map(|image| image.path).collect();
+            assert_eq!(outputs.len(), 3);
+            for output in &outputs {
+                assert_eq!(read_sequence_info(output).unwrap(), None);
+            }
+            let expected = (sequence_chunk == SequenceChunk::Encrypted).then_some((1, 3));
+            assert_eq!(read_sealed_sequence(&outputs[1], "default-key"), expected);
+            assert_eq!(read_sealed_sequence(&outputs[1], "other-key"), None);
+
+            let shuffled = [&outputs[2], &outputs[0], &outputs[1]].map(String::clone);
+            assert_eq!(
+                decode_from_files(&shuffled, "default-key").unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_redundancy_full() {
+        let dir = TestDir::new("redundancy");
+        // The last cover is too small for a copy and is skipped
+        let covers = save_covers(&dir, "bmp", &[(32, 16), (32, 16), (32, 16), (4, 4)]);
+        let message: Vec<u8> = (0..40u8).map(|i| i.wrapping_mul(37)).collect();
+        let output_dir = dir.join("out").to_string_lossy().to_string();
+        let report = encode_from_files(
+            &covers,
+            &message,
+            &output_dir,
+            &SetOptions {
+                redundancy: Redundancy::Full,
+                ..SetOptions::default()
+            },
+            "default-key",
+            &PayloadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.unused, covers[3..]);
+        let outputs: Vec<String> = report.images.into_iter().map(|image| image.path).collect();
+        assert_eq!(outputs.len(), 3);
+
+        for output in &outputs {
+            assert_eq!(
+                decode_from_files(std::slice::from_ref(output), "default-key").unwrap(),
+                message
+            );
+        }
+
+        // A damaged copy is passed over for the next
+        let mut damaged = image::open(&outputs[0]).unwrap().to_rgba8();
+        for pixel in damaged.pixels_mut().skip(70).take(200) {
+            pixel.0[0] ^= 1;
+        }
+        damaged.save(&outputs[0]).unwrap();
+        assert!(decode_from_files(&outputs[..1], "default-key").is_err());
+        assert_eq!(decode_from_files(&outputs, "default-key").unwrap(), message);
+    }
+
+    #[test]
+    fn test_distribute_even() {
+        let dir = TestDir::new("distribute");
+        let covers = save_covers(&dir, "png", &[(40, 20), (24, 24), (60, 30)]);
+        let message: Vec<u8> = (0..300u16).map(|i| (i * 37) as u8).collect();
+        let output_dir = dir.join("out").to_string_lossy().to_string();
+        let report = encode_from_files(
+            &covers,
+            &message,
+            &output_dir,
+            &SetOptions {
+                distribute: Distribute::Even,
+                depth: 2,
+                ..SetOptions::default()
+            },
+            "default-key",
+            &PayloadOptions::default(),
+        )
+        .unwrap();
+        // Every cover takes a part, and about the same share of itself
+        assert_eq!(report.images.len(), 3);
+        assert!(report.unused.is_empty());
+        let fills: Vec<f64> = report
+            .images
+            .iter()
+            .map(|image| image.detectability.fill_ratio)
+            .collect();
+        assert!(
+            fills.iter().all(|fill| (fill - fills[0]).abs() < 0.05),
+            "{:?}",
+            fills
+        );
+
+        let mut outputs: Vec<String> = report.images.into_iter().map(|image| image.path).collect();
+        outputs.reverse();
+        assert_eq!(decode_from_files(&outputs, "default-key").unwrap(), message);
+    }
+
+    #[test]
+    fn test_max_fill() {
+        let dir = TestDir::new("max-fill");
+        let covers = save_covers(&dir, "png", &[(32, 32); 3]);
+        let message: Vec<u8> = (0..600u16).map(|i| (i * 37) as u8).collect();
+        let payload = |ratio| PayloadOptions {
+            max_fill: Some(FillLimit {
+                ratio,
+                warn_only: false,
+            }),
+            compress: false,
+            ..PayloadOptions::default()
+        };
+        let encode = |output_dir: &str, payload: &PayloadOptions| {
+            encode_from_files(
+                &covers,
+                &message,
+                &dir.join(output_dir).to_string_lossy(),
+                &SetOptions::default(),
+                "default-key",
+                payload,
+            )
+        };
+
+        // Two covers would hold it, but only three stay under the limit
+        let report = encode("out", &payload(0.6)).unwrap();
+        assert_eq!(report.images.len(), 3);
+        assert!(
+            report
+                .images
+                .iter()
+                .all(|image| image.detectability.fill_ratio <= 0.6)
+        );
+        let outputs: Vec<String> = report.images.into_iter().map(|image| image.path).collect();
+        assert_eq!(decode_from_files(&outputs, "default-key").unwrap(), message);
+
+        assert!(matches!(
+            encode("low", &payload(0.3)),
+            Err(LowkeyError::Capacity(_))
+        ));
+        let output = dir.join("single.png").to_string_lossy().to_string();
+        assert!(
+            encode_from_file(
+                &covers[0],
+                &message,
+                &output,
+                false,
+                Layout::default(),
+                "default-key",
+                &payload(0.3),
+            )
+            .is_err()
+        );
+        assert!(!Path::new(&output).exists());
+    }
+
+    #[test]
+    fn test_find_existing_payload() {
+        let dir = TestDir::new("existing");
+        let cover_path = dir.join("cover.png").to_string_lossy().to_string();
+        let output_path = dir.join("output.png").to_string_lossy().to_string();
+        let mut cover = RgbaImage::new(40, 40);
+        for (i, value) in cover.iter_mut().enumerate() {
+            *value = splitmix64(i as u64) as u8;
+        }
+        cover.save(&cover_path).unwrap();
+        assert_eq!(find_existing_payload(&cover_path), None);
+
+        encode_from_file(
+            &cover_path,
+            b"Hello, World!",
+            &output_path,
+            false,
+            Layout::default(),
+            "default-key",
+            &PayloadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            find_existing_payload(&output_path).as_deref(),
+            Some("a lowkey message")
+        );
+        assert_eq!(
+            find_existing_payload(&dir.join("missing.png").to_string_lossy()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_append_slots() {
+        let dir = TestDir::new("slots");
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        RgbaImage::from_pixel(40, 40, image::Rgba([100, 150, 200, 255]))
+            .save(path("cover.png"))
+            .unwrap();
+        let payload = PayloadOptions::default();
+        assert!(
+            append_to_file(
+                &path("cover.png"),
+                b"first",
+                &path("none.png"),
+                1,
+                Channels::ALL,
+                "key-a",
+                &payload,
+            )
+            .is_err()
+        );
+
+        encode_from_file(
+            &path("cover.png"),
+            b"first",
+            &path("one.png"),
+            false,
+            Layout::default(),
+            "key-a",
+            &payload,
+        )
+        .unwrap();
+        append_to_file(
+            &path("one.png"),
+            b"second",
+            &path("two.png"),
+            1,
+            Channels::ALL,
+            "key-b",
+            &payload,
+        )
+        .unwrap();
+        let rgb = "rgb".parse().unwrap();
+        append_to_file(
+            &path("two.png"),
+            b"third",
+            &path("three.png"),
+            2,
+            rgb,
+            "key-c",
+            &payload,
+        )
+        .unwrap();
+
+        let slots = list_slots(&path("three.png"), Some("key-b")).unwrap();
+        assert_eq!(slots.len(), 3);
+        let opens: Vec<Option<bool>> = slots.iter().map(|slot| slot.opens).collect();
+        assert_eq!(opens, [Some(false), Some(true), Some(false)]);
+        assert_eq!((slots[2].depth, slots[2].channels), (2, rgb));
+        assert!(slots.windows(2).all(|pair| pair[0].end <= pair[1].start));
+        for (slot, (key, message)) in [("key-a", "first"), ("key-b", "second"), ("key-c", "third")]
+            .into_iter()
+            .enumerate()
+        {
+            let decoded = decode_slot_from_file(&path("three.png"), slot + 1, key).unwrap();
+            assert_eq!(decoded.bytes, message.as_bytes());
+        }
+        assert!(decode_slot_from_file(&path("three.png"), 2, "key-a").is_err());
+        assert!(decode_slot_from_file(&path("three.png"), 4, "key-a").is_err());
+        assert_eq!(
+            decode_from_files(&[path("three.png")], "key-a").unwrap(),
+            b"first"
+        );
+    }
+
+    #[test]
+    fn test_redundancy_shards() {
+        assert_eq!(
+            "3-of-5".parse::<Redundancy>().unwrap(),
+            Redundancy::Shards {
+                needed: 3,
+                total: 5
+            }
+        );
+        for invalid in ["1-of-3", "3-of-3", "2-of-256", "3of5", "a-of-5"] {
+            assert!(invalid.parse::<Redundancy>().is_err());
+        }
+
+        let dir = TestDir::new("shards");
+        let covers = save_covers(&dir, "bmp", &[(16, 16); 6]);
+        // Too long for any one cover
+        let message: Vec<u8> = (0..120u8).map(|i| i.wrapping_mul(37)).collect();
+        let output_dir = dir.join("out").to_string_lossy().to_string();
+        let report = encode_from_files(
+            &covers,
+            &message,
+            &output_dir,
+            &SetOptions {
+                redundancy: Redundancy::Shards {
+                    needed: 3,
+                    total: 5,
+                },
+                depth: 2,
+                ..SetOptions::default()
+            },
+            "default-key",
+            &PayloadOptions::default(),
+        )
+        .unwrap();
+        let outputs: Vec<String> = report.images.into_iter().map(|image| image.path).collect();
+        assert_eq!(outputs.len(), 5);
+
+        // Any three do, in any order
+        let picked = [&outputs[4], &outputs[1], &outputs[3]].map(String::clone);
+        assert_eq!(decode_from_files(&picked, "default-key").unwrap(), message);
+
+        // A damaged shard is passed over, until too few are left
+        let mut damaged = image::open(&outputs[1]).unwrap().to_rgba8();
+        for pixel in damaged.pixels_mut().skip(100).take(20) {
+            pixel.0[1] ^= 3;
+        }
+        damaged.save(&outputs[1]).unwrap();
+        assert_eq!(decode_from_files(&outputs, "default-key").unwrap(), message);
+        let error = decode_from_files(&picked, "default-key").unwrap_err();
+        assert!(
+            error
+                .message()
+                .starts_with("Only 2 of the 3 shards needed are intact")
+        );
+        assert!(error.message().ends_with("Images 1, 3 of 5 are missing"));
+    }
+
+    #[test]
+    fn test_resize_covers() {
+        let cover = |path: &str, dimensions| PlannedCover {
+            path: path.to_string(),
+            original: dimensions,
+            dimensions,
+        };
+        let mut covers = vec![cover("a.png", (1800, 900)), cover("b.png", (900, 900))];
+        // A ninth of the values, so a third of each side, but no side
+        // shorter than the minimum
+        let values = (1800 * 900 + 900 * 900) * 4 / 9;
+        resize_covers(&mut covers, values, Channels::ALL);
+        assert_eq!(covers[0].dimensions, (1200, 600));
+        assert_eq!(covers[1].dimensions, (600, 600));
+
+        let mut covers = vec![cover("c.png", (1200, 900))];
+        resize_covers(&mut covers, 800 * 600 * 4, Channels::ALL);
+        assert_eq!(covers[0].dimensions, (800, 600));
+
+        // Too much to fit: left alone
+        resize_covers(&mut covers, usize::MAX / 2, Channels::ALL);
+        assert_eq!(covers[0].dimensions, (800, 600));
+        assert_eq!(covers[0].original, (1200, 900));
+    }
 }
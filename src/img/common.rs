@@ -1,18 +1,25 @@
 use bitvec::prelude::{BitSlice, BitVec, Lsb0};
 use image::RgbaImage;
 
-fn check_capacity(capacity_bit_count: usize, bit_count: usize) -> Result<(), String> {
+use super::io::is_lossy_webp;
+use super::pixel::Channels;
+use crate::error::LowkeyError;
+
+fn check_capacity(capacity_bit_count: usize, bit_count: usize) -> Result<(), LowkeyError> {
     if bit_count > capacity_bit_count {
-        return Err(format!(
+        return Err(LowkeyError::Capacity(format!(
             "Message is too long for the image. Capacity: {} bits, required: {} bits",
             capacity_bit_count, bit_count
-        ));
+        )));
     }
 
     Ok(())
 }
 
-pub fn check_capacity_image(img: &mut RgbaImage, bits: &BitSlice<u8, Lsb0>) -> Result<(), String> {
+pub fn check_capacity_image(
+    img: &mut RgbaImage,
+    bits: &BitSlice<u8, Lsb0>,
+) -> Result<(), LowkeyError> {
     let (width, height) = img.dimensions();
     let capacity_bit_count = width as usize * height as usize * 4;
 
@@ -21,18 +28,60 @@ pub fn check_capacity_image(img: &mut RgbaImage, bits: &BitSlice<u8, Lsb0>) -> R
     Ok(())
 }
 
-pub fn check_capacity_images(imgs: &[&RgbaImage], bits: &BitSlice<u8, Lsb0>) -> Result<(), String> {
-    let capacity_bit_count: u32 = imgs
+/// Check that `bits` fit in the values of `channels` from the `start`th on
+/// at `depth` bits per value.
+pub fn check_capacity_image_at(
+    img: &RgbaImage,
+    start: usize,
+    bits: &BitSlice<u8, Lsb0>,
+    depth: u8,
+    channels: Channels,
+) -> Result<(), LowkeyError> {
+    let (width, height) = img.dimensions();
+    let values = (width as usize * height as usize * channels.count()).saturating_sub(start);
+
+    check_capacity(values * depth as usize, bits.len())
+}
+
+pub fn check_capacity_positions(img: &RgbaImage, count: usize) -> Result<(), LowkeyError> {
+    let (width, height) = img.dimensions();
+    check_capacity(width as usize * height as usize * 4, count)
+}
+
+/// Check that a message fits in the values of `channels` of a sequence of
+/// images: `header` at one bit per value from the start, then `body` at
+/// `depth` bits per value, with the first `reserved` values of each image
+/// left for its sequence header.
+pub fn check_capacity_images(
+    imgs: &[&RgbaImage],
+    reserved: usize,
+    header: &BitSlice<u8, Lsb0>,
+    body: &BitSlice<u8, Lsb0>,
+    depth: u8,
+    channels: Channels,
+) -> Result<(), LowkeyError> {
+    let values: usize = imgs
         .iter()
         .map(|img| {
             let (width, height) = img.dimensions();
-            width * height * 4
+            (width as usize * height as usize * channels.count()).saturating_sub(reserved)
         })
         .sum();
 
-    check_capacity(capacity_bit_count as usize, bits.len())?;
+    check_capacity_values(values, header, body, depth)
+}
 
-    Ok(())
+/// Check that a message fits in `values` channel values: `header` at one
+/// bit per value, then `body` at `depth` bits per value.
+pub fn check_capacity_values(
+    values: usize,
+    header: &BitSlice<u8, Lsb0>,
+    body: &BitSlice<u8, Lsb0>,
+    depth: u8,
+) -> Result<(), LowkeyError> {
+    let capacity_bit_count = header.len() + values.saturating_sub(header.len()) * depth as usize;
+
+    check_capacity(capacity_bit_count, header.len() + body.len())
 }
 
 pub fn convert_bytes_to_bits(bytes: &[u8]) -> BitVec<u8, Lsb0> {
@@ -42,10 +91,22 @@ pub fn convert_bytes_to_bits(bytes: &[u8]) -> BitVec<u8, Lsb0> {
         .collect()
 }
 
-pub fn check_image_png(path: &str) -> Result<(), String> {
+pub fn check_image_png(path: &str) -> Result<(), LowkeyError> {
     let path_lower = path.to_lowercase();
     if path_lower.ends_with(".jpg") || path_lower.ends_with(".jpeg") {
-        return Err("JPEG format is not supported. JPEG's lossy compression will destroy the hidden data. Please use PNG format instead.".to_string());
+        return Err(LowkeyError::Format("JPEG format is not supported. JPEG's lossy compression will destroy the hidden data. Please use PNG, lossless WebP, BMP or TIFF format instead, or --format jpeg to embed in the DCT coefficients of a JPEG cover.".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Check that an image a message is read from was stored losslessly.
+pub fn check_image_lossless(path: &str) -> Result<(), LowkeyError> {
+    if is_lossy_webp(path)? {
+        return Err(LowkeyError::Format(format!(
+            "'{}' is lossy WebP, whose compression destroys hidden data. Only lossless WebP can carry a message.",
+            path
+        )));
     }
 
     Ok(())
@@ -0,0 +1,197 @@
+// Synthetic cover images, for when there is no photo at hand to hide a
+// message in, and for test fixtures: the same seed always gives the same
+// image.
+
+use image::{Rgba, RgbaImage};
+use std::str::FromStr;
+
+use super::codec::message_capacity_for_size;
+use super::pixel::splitmix64;
+use crate::error::LowkeyError;
+
+/// Longest side of a generated cover
+pub const MAX_SIDE: u32 = 16384;
+
+/// Octaves of value noise in the brightness of a photo-like cover
+const OCTAVES: u32 = 7;
+
+/// What a generated cover looks like.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoverStyle {
+    /// Every value at random. Hides anything, but looks like nothing.
+    Noise,
+    /// A smooth blend of two colors with a light grain, so that the low
+    /// bits are as random as in a photo
+    Gradient,
+    /// Clouds of color at several scales with a grain on top, about as
+    /// textured as a photo
+    #[default]
+    PhotoLike,
+}
+
+impl FromStr for CoverStyle {
+    type Err = LowkeyError;
+
+    fn from_str(s: &str) -> Result<Self, LowkeyError> {
+        match s {
+            "noise" => Ok(Self::Noise),
+            "gradient" => Ok(Self::Gradient),
+            "photo-like" => Ok(Self::PhotoLike),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown cover style '{}' (expected noise, gradient or photo-like)",
+                s
+            ))),
+        }
+    }
+}
+
+/// `WxH` in pixels, as `--size` takes it.
+pub fn parse_size(s: &str) -> Result<(u32, u32), LowkeyError> {
+    let invalid =
+        || LowkeyError::InvalidInput(format!("Invalid size '{}': expected WxH, e.g. 1024x768", s));
+    let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+    let width: u32 = width.trim().parse().map_err(|_| invalid())?;
+    let height: u32 = height.trim().parse().map_err(|_| invalid())?;
+    if width == 0 || height == 0 || width > MAX_SIDE || height > MAX_SIDE {
+        return Err(LowkeyError::InvalidInput(format!(
+            "Size '{}' is out of range: each side must be 1 to {} pixels",
+            s, MAX_SIDE
+        )));
+    }
+    Ok((width, height))
+}
+
+/// The smallest 4:3 size whose cover carries `message_bytes` with the
+/// default settings.
+pub fn size_for_message(message_bytes: usize) -> Result<(u32, u32), LowkeyError> {
+    (4..=MAX_SIDE)
+        .step_by(4)
+        .map(|width| (width, width / 4 * 3))
+        .find(|&(width, height)| message_capacity_for_size(width, height) >= message_bytes)
+        .ok_or_else(|| {
+            LowkeyError::Capacity(format!(
+                "{} bytes do not fit in a cover of at most {} pixels on a side",
+                message_bytes, MAX_SIDE
+            ))
+        })
+}
+
+/// Whether a cover of `width`x`height` carries `message_bytes`, and if not
+/// why.
+pub fn check_fits(width: u32, height: u32, message_bytes: usize) -> Result<(), LowkeyError> {
+    let capacity = message_capacity_for_size(width, height);
+    if capacity < message_bytes {
+        return Err(LowkeyError::Capacity(format!(
+            "A {}x{} cover carries {} bytes, fewer than the {} needed",
+            width, height, capacity, message_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// An opaque cover of `width`x`height` in `style`, the same for the same
+/// `seed`.
+pub fn generate_cover(width: u32, height: u32, style: CoverStyle, seed: u64) -> RgbaImage {
+    let colors = [0, 1].map(|salt| {
+        let bits = random(seed, salt, 0).to_le_bytes();
+        [bits[0], bits[1], bits[2]]
+    });
+    let span = (width.max(height) as f32).max(2.0);
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let index = y as u64 * width as u64 + x as u64;
+        let grain = |channel: u64| (random(seed, 10 + channel, index) % 7) as f32 - 3.0;
+        let rgb: [f32; 3] = match style {
+            CoverStyle::Noise => {
+                let bits = random(seed, 2, index).to_le_bytes();
+                [bits[0], bits[1], bits[2]].map(f32::from)
+            }
+            CoverStyle::Gradient => {
+                let t = (x as f32 + y as f32) / (width + height).max(2) as f32;
+                let [from, to] = colors;
+                [0, 1, 2]
+                    .map(|c| from[c] as f32 + (to[c] as f32 - from[c] as f32) * t + grain(c as u64))
+            }
+            CoverStyle::PhotoLike => {
+                let (x, y) = (x as f32 / span, y as f32 / span);
+                let brightness = fractal_noise(seed, 3, x, y, OCTAVES);
+                [0, 1, 2].map(|c| {
+                    let tint = fractal_noise(seed, 4 + c as u64, x, y, 3);
+                    (0.7 * brightness + 0.3 * tint) * 255.0 + grain(c as u64)
+                })
+            }
+        };
+        let [r, g, b] = rgb.map(|value| value.round().clamp(0.0, 255.0) as u8);
+        Rgba([r, g, b, 255])
+    })
+}
+
+fn random(seed: u64, salt: u64, i: u64) -> u64 {
+    splitmix64(splitmix64(seed ^ salt) ^ i)
+}
+
+/// Sum of `octaves` layers of value noise at `x`, `y` (0 to 1 across the
+/// image), each twice as fine as the last and a third weaker, from 0 to 1.
+fn fractal_noise(seed: u64, salt: u64, x: f32, y: f32, octaves: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    for octave in 0..octaves {
+        let (cells, weight) = ((2 << octave) as f32, 0.65f32.powi(octave as i32));
+        sum += weight * value_noise(random(seed, salt, octave as u64), x * cells, y * cells);
+        total += weight;
+    }
+    sum / total
+}
+
+/// Random values at whole coordinates, smoothly blended in between.
+fn value_noise(seed: u64, x: f32, y: f32) -> f32 {
+    let lattice = |x: u64, y: u64| (random(seed, x, y) >> 40) as f32 / (1u64 << 24) as f32;
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (smooth(x - x0), smooth(y - y0));
+    let (x0, y0) = (x0 as u64, y0 as u64);
+    let top = lattice(x0, y0) + (lattice(x0 + 1, y0) - lattice(x0, y0)) * tx;
+    let bottom = lattice(x0, y0 + 1) + (lattice(x0 + 1, y0 + 1) - lattice(x0, y0 + 1)) * tx;
+    top + (bottom - top) * ty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024x768").unwrap(), (1024, 768));
+        assert!(parse_size("1024").is_err());
+        assert!(parse_size("0x10").is_err());
+        assert!(parse_size("100000x10").is_err());
+        assert!("clouds".parse::<CoverStyle>().is_err());
+    }
+
+    #[test]
+    fn test_generate_cover() {
+        for style in [
+            CoverStyle::Noise,
+            CoverStyle::Gradient,
+            CoverStyle::PhotoLike,
+        ] {
+            let cover = generate_cover(40, 30, style, 7);
+            assert_eq!(cover.dimensions(), (40, 30));
+            assert_eq!(cover, generate_cover(40, 30, style, 7));
+            assert_ne!(cover, generate_cover(40, 30, style, 8));
+            assert!(cover.pixels().all(|pixel| pixel[3] == 255));
+            // Low bits as varied as a photo's
+            let odd = cover.pixels().filter(|pixel| pixel[0] & 1 == 1).count();
+            assert!((300..900).contains(&odd), "{:?}: {}", style, odd);
+        }
+    }
+
+    #[test]
+    fn test_size_for_message() {
+        let (width, height) = size_for_message(5000).unwrap();
+        assert_eq!(width / 4 * 3, height);
+        assert!(check_fits(width, height, 5000).is_ok());
+        assert!(check_fits(width - 4, height - 3, 5000).is_err());
+        assert!(size_for_message(usize::MAX).is_err());
+    }
+}
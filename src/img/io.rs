@@ -1,49 +1,610 @@
-use image::ImageBuffer;
-use image::RgbaImage;
-use png::{BitDepth, ColorType, Encoder};
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::metadata::Orientation;
+use image::{
+    DynamicImage, ExtendedColorType, GenericImageView, ImageBuffer, ImageDecoder, ImageReader,
+    RgbaImage,
+};
+use moxcms::{ColorProfile, DataColorSpace, TransformOptions};
+use png::{AdaptiveFilterType, BitDepth, ColorType, Encoder, FilterType};
+use std::collections::HashSet;
 use std::fs::File;
 use std::fs::{self};
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use tiff::encoder::{Compression, TiffEncoder, colortype};
+use tiff::tags::Tag;
 
-pub fn read_image(path: &str) -> Result<ImageBuffer<image::Rgba<u8>, Vec<u8>>, String> {
-    let img = image::open(path)
-        .map_err(|e| format!("Failed to open image '{}': {}", path, e))?
-        .to_rgba8();
+use crate::crypto;
+use crate::error::LowkeyError;
 
-    Ok(img)
+pub fn read_image(path: &str) -> Result<ImageBuffer<image::Rgba<u8>, Vec<u8>>, LowkeyError> {
+    Ok(open_upright(path, false)?.into_rgba8())
 }
 
-pub fn collect_images_from_dir(dir: &str) -> Result<Vec<String>, String> {
+/// Open the image at `path` the way it is shown: turned and flipped as its
+/// EXIF orientation says, as a rotated phone photo is. Outputs then carry
+/// the orientation as the identity (see `clear_orientation`), so that they
+/// are not turned a second time.
+///
+/// With `srgb`, the colours are also converted from the image's ICC profile,
+/// if it has one, to sRGB (see `convert_to_srgb`).
+fn open_upright(path: &str, srgb: bool) -> Result<DynamicImage, LowkeyError> {
+    let mut decoder = ImageReader::open(path)
+        .map_err(|e| LowkeyError::Io(format!("Failed to open image '{}': {}", path, e)))?
+        .into_decoder()
+        .map_err(|e| open_error(path, e))?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let icc_profile = match srgb {
+        true => decoder.icc_profile().map_err(|e| open_error(path, e))?,
+        false => None,
+    };
+    let mut img = DynamicImage::from_decoder(decoder).map_err(|e| open_error(path, e))?;
+    img.apply_orientation(orientation);
+    match icc_profile {
+        Some(icc_profile) => convert_to_srgb(&img, &icc_profile, path),
+        None => Ok(img),
+    }
+}
+
+/// The colours of `img` under its ICC profile `icc_profile`, as an RGBA
+/// image in sRGB with the depth of `img`, so that it looks the same with
+/// the profile left out. Alpha is kept as it is.
+fn convert_to_srgb(
+    img: &DynamicImage,
+    icc_profile: &[u8],
+    path: &str,
+) -> Result<DynamicImage, LowkeyError> {
+    let cms_error = |e: moxcms::CmsError| {
+        LowkeyError::Format(format!(
+            "Failed to convert '{}' to sRGB with its ICC profile: {}",
+            path, e
+        ))
+    };
+    let profile = ColorProfile::new_from_slice(icc_profile).map_err(cms_error)?;
+    let layout = match profile.color_space {
+        DataColorSpace::Rgb => moxcms::Layout::Rgba,
+        DataColorSpace::Gray => moxcms::Layout::GrayAlpha,
+        _ => {
+            return Err(LowkeyError::Format(format!(
+                "Cannot convert '{}' to sRGB: its ICC profile is for {:?} colours",
+                path, profile.color_space
+            )));
+        }
+    };
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+    let (width, height) = img.dimensions();
+    let values = width as usize * height as usize * 4;
+
+    if is_16_bit(img) {
+        let source = match layout {
+            moxcms::Layout::Rgba => img.to_rgba16().into_raw(),
+            _ => img.to_luma_alpha16().into_raw(),
+        };
+        let mut converted = vec![0u16; values];
+        profile
+            .create_transform_16bit(layout, &srgb, moxcms::Layout::Rgba, options)
+            .and_then(|transform| transform.transform(&source, &mut converted))
+            .map_err(cms_error)?;
+        let converted = Rgba16Image::from_raw(width, height, converted)
+            .expect("same size as the image converted");
+        Ok(DynamicImage::ImageRgba16(converted))
+    } else {
+        let source = match layout {
+            moxcms::Layout::Rgba => img.to_rgba8().into_raw(),
+            _ => img.to_luma_alpha8().into_raw(),
+        };
+        let mut converted = vec![0u8; values];
+        profile
+            .create_transform_8bit(layout, &srgb, moxcms::Layout::Rgba, options)
+            .and_then(|transform| transform.transform(&source, &mut converted))
+            .map_err(cms_error)?;
+        let converted = RgbaImage::from_raw(width, height, converted)
+            .expect("same size as the image converted");
+        Ok(DynamicImage::ImageRgba8(converted))
+    }
+}
+
+/// Set the orientation in `exif` to the identity, for metadata copied to
+/// an image whose pixels were read upright.
+fn clear_orientation(exif: &mut [u8]) {
+    let _ = Orientation::remove_from_exif_chunk(exif);
+}
+
+/// An image that can't be opened is missing or unreadable, or else not an
+/// image lowkey can read.
+fn open_error(path: &str, error: image::ImageError) -> LowkeyError {
+    let message = format!("Failed to open image '{}': {}", path, error);
+    match error {
+        image::ImageError::IoError(_) => LowkeyError::Io(message),
+        _ => LowkeyError::Format(message),
+    }
+}
+
+/// RGBA image with 16 bits per channel value
+pub type Rgba16Image = ImageBuffer<image::Rgba<u16>, Vec<u16>>;
+
+/// Read a cover to embed a message into.
+///
+/// With `keep_depth`, a cover with 16-bit values is also returned at full
+/// depth, and the RGBA8 image holds the low byte of each value (see
+/// `low_bytes`) for the message to go in. Otherwise it is reduced to 8 bits
+/// as with `read_image`. Either way, it is turned upright.
+///
+/// With `srgb`, a cover with an ICC profile has it applied to its pixels,
+/// for an output saved with `srgb` too, which leaves the profile out.
+pub fn read_cover(
+    path: &str,
+    keep_depth: bool,
+    srgb: bool,
+) -> Result<(RgbaImage, Option<Rgba16Image>), LowkeyError> {
+    let img = open_upright(path, srgb)?;
+    if keep_depth && is_16_bit(&img) {
+        let wide = img.into_rgba16();
+        Ok((low_bytes(&wide), Some(wide)))
+    } else {
+        Ok((img.into_rgba8(), None))
+    }
+}
+
+/// What the header of an image says, read without decoding its pixels, for
+/// capacity checks over many or large images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Width as `read_cover` returns the image, turned upright
+    pub width: u32,
+    /// Height as `read_cover` returns the image, turned upright
+    pub height: u32,
+    /// How the pixels are stored, e.g. with alpha or 16-bit values
+    pub color_type: image::ColorType,
+}
+
+/// Read the header of the image at `path`.
+pub fn probe_image(path: &str) -> Result<ImageHeader, LowkeyError> {
+    let decoder = ImageReader::open(path)
+        .map_err(|e| LowkeyError::Io(format!("Failed to open image '{}': {}", path, e)))?
+        .into_decoder()
+        .map_err(|e| open_error(path, e))?;
+    Ok(header_of(decoder))
+}
+
+/// Same as `probe_image` for an image held in memory.
+pub fn probe_image_from_memory(bytes: &[u8]) -> Result<ImageHeader, LowkeyError> {
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| LowkeyError::Io(e.to_string()))?
+        .into_decoder()
+        .map(header_of)
+        .map_err(|e| LowkeyError::Format(format!("Failed to read image: {}", e)))
+}
+
+fn header_of(mut decoder: impl ImageDecoder) -> ImageHeader {
+    let (width, height) = decoder.dimensions();
+    let color_type = decoder.color_type();
+    let (width, height) = match decoder.orientation().unwrap_or(Orientation::NoTransforms) {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    };
+    ImageHeader {
+        width,
+        height,
+        color_type,
+    }
+}
+
+/// Read an image to decode a message from: the low byte of each value for a
+/// 16-bit image, where `read_cover` put the message. The pixels are taken as
+/// stored, whatever the EXIF orientation.
+pub fn read_carrier(path: &str) -> Result<RgbaImage, LowkeyError> {
+    image::open(path)
+        .map(carrier_from)
+        .map_err(|e| open_error(path, e))
+}
+
+/// Same as `read_carrier` for an image already decoded.
+pub fn carrier_from(img: DynamicImage) -> RgbaImage {
+    if is_16_bit(&img) {
+        low_bytes(&img.into_rgba16())
+    } else {
+        img.into_rgba8()
+    }
+}
+
+fn is_16_bit(img: &DynamicImage) -> bool {
+    matches!(
+        img,
+        DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_)
+    )
+}
+
+/// The low byte of each value of a 16-bit image, as an RGBA8 image of the
+/// same size. Message bits embedded there change the 16-bit values by at
+/// most the same amounts as in an 8-bit image, 256 times less visibly.
+pub fn low_bytes(img: &Rgba16Image) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let data = img.as_raw().iter().map(|&v| v as u8).collect();
+    RgbaImage::from_raw(width, height, data).expect("same size as the 16-bit image")
+}
+
+/// Replace the low byte of each value of `img` with the values of `low`.
+pub fn set_low_bytes(img: &mut Rgba16Image, low: &RgbaImage) {
+    for (value, &byte) in img.iter_mut().zip(low.as_raw()) {
+        *value = (*value & 0xFF00) | byte as u16;
+    }
+}
+
+pub fn read_message_file(path: &str) -> Result<Vec<u8>, LowkeyError> {
+    let mut file = File::open(path)
+        .map_err(|e| LowkeyError::Io(format!("Failed to open message file '{}': {}", path, e)))?;
+    let mut message_bytes = Vec::new();
+    file.read_to_end(&mut message_bytes)
+        .map_err(|e| LowkeyError::Io(format!("Failed to read message file: {}", e)))?;
+
+    Ok(message_bytes)
+}
+
+pub fn write_message_file(path: &str, message_bytes: &[u8]) -> Result<(), LowkeyError> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| LowkeyError::Io(format!("Failed to create output directory: {}", e)))?;
+    }
+
+    let mut file = File::create(path)
+        .map_err(|e| LowkeyError::Io(format!("Failed to create output file '{}': {}", path, e)))?;
+    file.write_all(message_bytes)
+        .map_err(|e| LowkeyError::Io(format!("Failed to write to output file: {}", e)))?;
+
+    Ok(())
+}
+
+/// Access and modification times and permission bits of a file, to give
+/// to another one.
+pub struct FileAttributes(fs::Metadata);
+
+impl FileAttributes {
+    pub fn read(path: &str) -> Result<Self, LowkeyError> {
+        fs::metadata(path)
+            .map(Self)
+            .map_err(|e| LowkeyError::Io(format!("Failed to read '{}': {}", path, e)))
+    }
+
+    /// Give the file at `path` these times and permissions.
+    pub fn apply(&self, path: &str) -> Result<(), LowkeyError> {
+        let io_error = |e: std::io::Error| {
+            LowkeyError::Io(format!("Failed to set the attributes of '{}': {}", path, e))
+        };
+        let mut times = fs::FileTimes::new();
+        if let Ok(accessed) = self.0.accessed() {
+            times = times.set_accessed(accessed);
+        }
+        if let Ok(modified) = self.0.modified() {
+            times = times.set_modified(modified);
+        }
+        File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_times(times))
+            .map_err(io_error)?;
+        fs::set_permissions(path, self.0.permissions()).map_err(io_error)
+    }
+}
+
+/// What to do with symbolic links met while collecting images from a directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Leave out every link
+    Skip,
+    /// Use links to files, but don't descend into linked directories
+    #[default]
+    Files,
+    /// Use links to files and descend into linked directories, each at most once
+    Follow,
+}
+
+impl std::str::FromStr for Symlinks {
+    type Err = LowkeyError;
+
+    fn from_str(name: &str) -> Result<Self, LowkeyError> {
+        match name {
+            "skip" => Ok(Self::Skip),
+            "files" => Ok(Self::Files),
+            "follow" => Ok(Self::Follow),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown symlink mode '{}' (expected skip, files or follow)",
+                name
+            ))),
+        }
+    }
+}
+
+/// Order of the images `collect_images_from_dir` returns, which is the order
+/// a message is split over them in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    /// By path, with runs of digits compared as numbers (`img2` before `img10`)
+    Natural,
+    /// By path, character by character
+    #[default]
+    Name,
+    /// Oldest modification time first
+    Mtime,
+    /// Smallest file first
+    Size,
+    /// Most pixels first, so that the message takes as few covers as it can
+    Capacity,
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = LowkeyError;
+
+    fn from_str(name: &str) -> Result<Self, LowkeyError> {
+        match name {
+            "natural" => Ok(Self::Natural),
+            "name" => Ok(Self::Name),
+            "mtime" => Ok(Self::Mtime),
+            "size" => Ok(Self::Size),
+            "capacity" => Ok(Self::Capacity),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown sort order '{}' (expected natural, name, mtime, size or capacity)",
+                name
+            ))),
+        }
+    }
+}
+
+/// Which images `collect_images_from_dir` picks up.
+///
+/// Patterns take `*` and `?` within a path component and `**` across them.
+/// A pattern without a `/` is matched against the file name, one with a `/`
+/// against the path relative to the directory. Exclude patterns also prune
+/// the subdirectories they match.
+#[derive(Debug, Default)]
+pub struct DirScan {
+    /// Also look in subdirectories
+    pub recursive: bool,
+    /// Keep only images matching one of these, if any are given
+    pub include: Vec<String>,
+    /// Leave out images and subdirectories matching any of these
+    pub exclude: Vec<String>,
+    pub symlinks: Symlinks,
+    pub sort: SortOrder,
+}
+
+impl DirScan {
+    fn excludes(&self, relative: &str) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| path_matches(pattern, relative))
+    }
+
+    fn includes(&self, relative: &str) -> bool {
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| path_matches(pattern, relative))
+    }
+}
+
+pub fn collect_images_from_dir(dir: &str, scan: &DirScan) -> Result<Vec<String>, LowkeyError> {
     let path = Path::new(dir);
     if !path.is_dir() {
-        return Err(format!("'{}' is not a directory", dir));
+        return Err(LowkeyError::InvalidInput(format!(
+            "'{}' is not a directory",
+            dir
+        )));
     }
 
-    let entries =
-        fs::read_dir(path).map_err(|e| format!("Failed to read directory '{}': {}", dir, e))?;
+    let mut visited = HashSet::new();
+    if scan.symlinks == Symlinks::Follow
+        && let Ok(canonical) = path.canonicalize()
+    {
+        visited.insert(canonical);
+    }
 
     let mut image_files = Vec::new();
+    collect_images(path, "", scan, &mut visited, &mut image_files)?;
+
+    if image_files.is_empty() {
+        return Err(LowkeyError::InvalidInput(format!(
+            "No image files found in directory '{}'",
+            dir
+        )));
+    }
+
+    sort_images(&mut image_files, scan.sort)?;
+    Ok(image_files)
+}
+
+/// Sort `paths` by `order`. Paths that tie, such as files of the same size,
+/// stay in name order so that the result is the same on every run.
+fn sort_images(paths: &mut [String], order: SortOrder) -> Result<(), LowkeyError> {
+    paths.sort();
+    match order {
+        SortOrder::Name => {}
+        SortOrder::Natural => paths.sort_by(|a, b| natural_cmp(a, b)),
+        SortOrder::Mtime => sort_by_metadata(paths, |path, metadata| {
+            metadata.modified().map_err(|e| {
+                LowkeyError::Io(format!(
+                    "Failed to read modification time of '{}': {}",
+                    path, e
+                ))
+            })
+        })?,
+        SortOrder::Size => sort_by_metadata(paths, |_, metadata| Ok(metadata.len()))?,
+        // Only the image headers are read for the dimensions
+        SortOrder::Capacity => sort_by_metadata(paths, |path, _| {
+            probe_image(path)
+                .map(|header| std::cmp::Reverse(header.width as u64 * header.height as u64))
+        })?,
+    }
+    Ok(())
+}
+
+fn sort_by_metadata<K: Ord>(
+    paths: &mut [String],
+    key: impl Fn(&str, &fs::Metadata) -> Result<K, LowkeyError>,
+) -> Result<(), LowkeyError> {
+    let mut keyed = paths
+        .iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).map_err(|e| {
+                LowkeyError::Io(format!("Failed to read metadata of '{}': {}", path, e))
+            })?;
+            Ok((key(path, &metadata)?, path.clone()))
+        })
+        .collect::<Result<Vec<_>, LowkeyError>>()?;
+    // Stable, so ties keep their name order
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, (_, sorted)) in paths.iter_mut().zip(keyed) {
+        *path = sorted;
+    }
+    Ok(())
+}
+
+/// Compare `a` and `b` character by character, but runs of digits by their
+/// value. Equal values with different zero padding fall back to plain order.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut a_rest, mut b_rest) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a_rest.first(), b_rest.first()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_len = a_rest.iter().take_while(|c| c.is_ascii_digit()).count();
+                let b_len = b_rest.iter().take_while(|c| c.is_ascii_digit()).count();
+                let a_digits = trim_zeros(&a_rest[..a_len]);
+                let b_digits = trim_zeros(&b_rest[..b_len]);
+                let ordering = a_digits
+                    .len()
+                    .cmp(&b_digits.len())
+                    .then_with(|| a_digits.cmp(b_digits));
+                if ordering.is_ne() {
+                    return ordering;
+                }
+                a_rest = &a_rest[a_len..];
+                b_rest = &b_rest[b_len..];
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a_rest = &a_rest[1..];
+                b_rest = &b_rest[1..];
+            }
+        }
+    }
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|&&c| c == b'0').count();
+    &digits[zeros..]
+}
+
+fn collect_images(
+    dir: &Path,
+    relative: &str,
+    scan: &DirScan,
+    visited: &mut HashSet<PathBuf>,
+    image_files: &mut Vec<String>,
+) -> Result<(), LowkeyError> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        LowkeyError::Io(format!(
+            "Failed to read directory '{}': {}",
+            dir.display(),
+            e
+        ))
+    })?;
+
     for entry in entries {
-        let entry = entry.map_err(|e| format!("Failed to read directory entry: {}", e))?;
+        let entry =
+            entry.map_err(|e| LowkeyError::Io(format!("Failed to read directory entry: {}", e)))?;
         let path = entry.path();
+        let is_link = entry
+            .file_type()
+            .is_ok_and(|file_type| file_type.is_symlink());
+        if is_link && scan.symlinks == Symlinks::Skip {
+            continue;
+        }
 
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                let ext_str = ext.to_string_lossy().to_lowercase();
-                if ext_str == "png" || ext_str == "jpg" || ext_str == "jpeg" {
-                    image_files.push(path.to_string_lossy().to_string());
+        let name = entry.file_name().to_string_lossy().to_string();
+        let entry_relative = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+
+        // is_dir and is_file follow links, and are both false for a broken one
+        if path.is_dir() {
+            if !scan.recursive
+                || (is_link && scan.symlinks != Symlinks::Follow)
+                || scan.excludes(&entry_relative)
+            {
+                continue;
+            }
+            if scan.symlinks == Symlinks::Follow {
+                let canonical = path.canonicalize().map_err(|e| {
+                    LowkeyError::Io(format!("Failed to resolve '{}': {}", path.display(), e))
+                })?;
+                if !visited.insert(canonical) {
+                    continue;
                 }
             }
+            collect_images(&path, &entry_relative, scan, visited, image_files)?;
+        } else if path.is_file()
+            && let Some(ext) = path.extension()
+        {
+            let ext_str = ext.to_string_lossy().to_lowercase();
+            if matches!(
+                ext_str.as_str(),
+                "png" | "webp" | "bmp" | "tif" | "tiff" | "jpg" | "jpeg"
+            ) && scan.includes(&entry_relative)
+                && !scan.excludes(&entry_relative)
+            {
+                image_files.push(path.to_string_lossy().to_string());
+            }
         }
     }
 
-    if image_files.is_empty() {
-        return Err(format!("No image files found in directory '{}'", dir));
-    }
+    Ok(())
+}
 
-    image_files.sort();
-    Ok(image_files)
+/// Match `pattern` against the file name of `relative`, or against all of it
+/// if the pattern has a `/`.
+fn path_matches(pattern: &str, relative: &str) -> bool {
+    let text = if pattern.contains('/') {
+        relative
+    } else {
+        relative.rsplit('/').next().unwrap_or(relative)
+    };
+    glob_matches(pattern.as_bytes(), text.as_bytes())
+}
+
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // "**/" also matches no directories at all
+            (rest.first() == Some(&b'/') && glob_matches(&rest[1..], text))
+                || (0..=text.len()).any(|i| glob_matches(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let component = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=component).any(|i| glob_matches(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(text, [c, ..] if *c != b'/') && glob_matches(rest, &text[1..])
+        }
+        [c, rest @ ..] => text.first() == Some(c) && glob_matches(rest, &text[1..]),
+    }
 }
 
 fn crc32(data: &[u8]) -> u32 {
@@ -83,6 +644,113 @@ impl PngChunk {
     }
 }
 
+/// Where an output stands in a multi-image set, as recorded in its metadata
+/// besides the in-band sequence header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SequenceInfo {
+    /// Index and image count, in the clear in an lKsq chunk
+    Plain(u32, u32),
+    /// Index and image count encrypted under the key, which looks like
+    /// random data, in a chunk with a generic name
+    Sealed(Vec<u8>),
+}
+
+impl SequenceInfo {
+    /// Name and data of the PNG or WebP chunk holding it
+    fn chunk(&self) -> (&'static [u8; 4], Vec<u8>) {
+        match self {
+            Self::Plain(index, total) => {
+                let mut data = index.to_be_bytes().to_vec();
+                data.extend_from_slice(&total.to_be_bytes());
+                (SEQUENCE_CHUNK, data)
+            }
+            Self::Sealed(sealed) => (SEALED_SEQUENCE_CHUNK, sealed.clone()),
+        }
+    }
+}
+
+/// Which of a cover's metadata its output is given. The output's own
+/// sequence info and payload chunks are written whatever it is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeepMetadata {
+    /// Everything that still holds for the output
+    #[default]
+    All,
+    /// All but the colour profile (the ICC profile, and in a PNG the gAMA,
+    /// cHRM, sRGB, cICP, mDCV and cLLI chunks), for pixels `read_cover`
+    /// converted to sRGB, so that the output is plain sRGB
+    AllButColorProfile,
+    /// Nothing, as with `--strip-metadata`
+    Nothing,
+}
+
+/// How hard the PNG encoder works at making the output small. The pixels
+/// are the same whatever it is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PngCompression {
+    /// Quickest, for large batches, at the cost of larger files
+    Fast,
+    #[default]
+    Default,
+    /// Smallest files, and the slowest
+    Best,
+}
+
+impl std::str::FromStr for PngCompression {
+    type Err = LowkeyError;
+
+    fn from_str(name: &str) -> Result<Self, LowkeyError> {
+        match name {
+            "fast" => Ok(Self::Fast),
+            "default" => Ok(Self::Default),
+            "best" => Ok(Self::Best),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown PNG compression '{}' (expected fast, default or best)",
+                name
+            ))),
+        }
+    }
+}
+
+/// Which filter the PNG encoder puts each row through before compressing it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PngFilter {
+    /// The best of the filters for each row; the smallest files, but slower
+    Adaptive,
+    /// No filter, the quickest
+    None,
+    /// The difference from the pixel to the left, on every row
+    #[default]
+    Sub,
+    /// The Paeth predictor on every row, which suits photos
+    Paeth,
+}
+
+impl std::str::FromStr for PngFilter {
+    type Err = LowkeyError;
+
+    fn from_str(name: &str) -> Result<Self, LowkeyError> {
+        match name {
+            "adaptive" => Ok(Self::Adaptive),
+            "none" => Ok(Self::None),
+            "sub" => Ok(Self::Sub),
+            "paeth" => Ok(Self::Paeth),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown PNG filter '{}' (expected adaptive, none, sub or paeth)",
+                name
+            ))),
+        }
+    }
+}
+
+/// Settings for the PNG encoder, which trade the size of a PNG output
+/// against the time it takes to write. Other formats ignore them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PngEncoding {
+    pub compression: PngCompression,
+    pub filter: PngFilter,
+}
+
 /// Save RGBA image with all PNG metadata chunks preserved from the original file.
 ///
 /// # Why preserve metadata?
@@ -113,48 +781,366 @@ impl PngChunk {
 ///
 /// ## Implementation Strategy:
 ///
-/// 1. **Extract**: Read all ancillary chunks from the input PNG file
+/// 1. **Extract**: Read the intact ancillary chunks that still hold for the output (see `keeps_chunk`)
 /// 2. **Generate**: Use `png` crate to encode the pixel data (IHDR + IDAT + IEND)
 /// 3. **Inject**: Insert the extracted metadata chunks between IHDR and IDAT
 /// 4. **Output**: Write the complete PNG with metadata preserved
 ///
+/// A cover in another format, such as a JPEG, has no chunks to copy, so its
+/// EXIF and XMP are written into eXIf and iTXt chunks instead.
+///
 /// This ensures the steganography process is truly "invisible" - not just in terms
 /// of the hidden data, but also in maintaining the exact visual appearance of the
 /// original image.
+///
+/// An output path ending in `.webp`, `.bmp` or `.tif`/`.tiff` is written in
+/// that format instead (see `OutputFormat`).
+///
+/// `keep` says which of the metadata is carried over (see `KeepMetadata`),
+/// and `encoding` how a PNG is compressed.
 pub fn save_rgba_with_metadata(
     img: &RgbaImage,
     output_path: &str,
     input_path: &str,
-    sequence_info: Option<(u32, u32)>,
-) -> Result<(), String> {
-    use std::io::{Read, Write};
+    sequence_info: Option<&SequenceInfo>,
+    keep: KeepMetadata,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
+    write_atomically(output_path, |output_writer| {
+        match OutputFormat::from_path(output_path) {
+            OutputFormat::Png => {
+                let png = PngImage {
+                    data: img.as_raw(),
+                    dimensions: img.dimensions(),
+                    depth: BitDepth::Eight,
+                };
+                write_png_with_metadata(
+                    &png,
+                    output_writer,
+                    input_path,
+                    sequence_info,
+                    None,
+                    keep,
+                    encoding,
+                )
+            }
+            OutputFormat::WebP => {
+                write_webp_with_metadata(img, output_writer, input_path, sequence_info, keep)
+            }
+            OutputFormat::Bmp => write_bmp(img, output_writer),
+            OutputFormat::Tiff => {
+                write_tiff_with_metadata(img, output_writer, input_path, sequence_info, keep)
+            }
+        }
+    })
+}
 
-    // Check if input file is PNG by reading signature
-    let input_file = File::open(input_path).map_err(|e| e.to_string())?;
-    let mut input_reader = BufReader::new(input_file);
+/// Write `path` through a temporary file in the same directory, renamed into
+/// place once `write` has succeeded, so that a crash or a full disk never
+/// leaves a truncated file behind (and an existing file is only replaced by a
+/// complete one, with the same mode). Writes running at once on other
+/// threads, even to the same path, each get a temporary file of their own,
+/// under a random name that is never an existing file or link.
+pub fn write_atomically(
+    path: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), LowkeyError>,
+) -> Result<(), LowkeyError> {
+    let path = Path::new(path);
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let suffix: String = crypto::random_bytes(8)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let temp = path.with_file_name(format!(".{}.{}.tmp", file_name, suffix));
+    let io_error =
+        |e: std::io::Error| LowkeyError::Io(format!("Failed to write '{}': {}", path.display(), e));
 
-    let mut signature = [0u8; 8];
-    let png_signature: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+    let file = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .map_err(io_error)?;
+    let result = (|| {
+        #[cfg(unix)]
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())
+                .map_err(io_error)?;
+        }
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| io_error(e.into_error()))?;
+        file.sync_all().map_err(io_error)?;
+        fs::rename(&temp, path).map_err(io_error)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
 
-    // Try to read signature
-    if input_reader.read_exact(&mut signature).is_err() || signature != png_signature {
-        // Not a PNG file, just save without metadata preservation
-        drop(input_reader);
-        return save_rgba_simple(img, output_path);
+    // The rename itself is only durable once the directory is
+    #[cfg(unix)]
+    {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(io_error)?;
+    }
+    Ok(())
+}
+
+/// The lossless formats a stego image can be written in, chosen by the
+/// extension of the output path. Anything unknown is written as PNG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    /// Lossless WebP, with ICC profile, EXIF and sequence info
+    WebP,
+    /// 32-bit BMP, which has no room for metadata
+    Bmp,
+    /// LZW-compressed TIFF, with ICC profile and sequence info
+    Tiff,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &str) -> Self {
+        let path_lower = path.to_lowercase();
+        if path_lower.ends_with(".webp") {
+            Self::WebP
+        } else if path_lower.ends_with(".bmp") {
+            Self::Bmp
+        } else if path_lower.ends_with(".tif") || path_lower.ends_with(".tiff") {
+            Self::Tiff
+        } else {
+            Self::Png
+        }
     }
+}
+
+/// Same as `save_rgba_with_metadata`, but writes the PNG to any writer.
+pub fn write_rgba_with_metadata<W: Write>(
+    img: &RgbaImage,
+    output_writer: W,
+    input_path: &str,
+    sequence_info: Option<&SequenceInfo>,
+) -> Result<(), LowkeyError> {
+    let png = PngImage {
+        data: img.as_raw(),
+        dimensions: img.dimensions(),
+        depth: BitDepth::Eight,
+    };
+    write_png_with_metadata(
+        &png,
+        output_writer,
+        input_path,
+        sequence_info,
+        None,
+        KeepMetadata::All,
+        PngEncoding::default(),
+    )
+}
+
+/// Same as `write_rgba_with_metadata`, but reads the original image's metadata
+/// chunks from `input_reader` instead of a file.
+pub fn write_rgba_with_metadata_from<R: Read, W: Write>(
+    img: &RgbaImage,
+    output_writer: W,
+    input_reader: R,
+    sequence_info: Option<&SequenceInfo>,
+) -> Result<(), LowkeyError> {
+    let png = PngImage {
+        data: img.as_raw(),
+        dimensions: img.dimensions(),
+        depth: BitDepth::Eight,
+    };
+    write_png_with_metadata_from(
+        &png,
+        output_writer,
+        input_reader,
+        sequence_info,
+        None,
+        KeepMetadata::All,
+        PngEncoding::default(),
+    )
+}
+
+/// Same as `save_rgba_with_metadata` for an image with 16-bit values, which
+/// is always written as a 16-bit PNG.
+pub fn save_rgba16_with_metadata(
+    img: &Rgba16Image,
+    output_path: &str,
+    input_path: &str,
+    sequence_info: Option<&SequenceInfo>,
+    keep: KeepMetadata,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
+    let data = png_data16(img);
+    let png = PngImage {
+        data: &data,
+        dimensions: img.dimensions(),
+        depth: BitDepth::Sixteen,
+    };
+    save_png(
+        &png,
+        output_path,
+        input_path,
+        sequence_info,
+        None,
+        keep,
+        encoding,
+    )
+}
+
+/// Save `img` as a PNG with the metadata of `input_path`, like
+/// `save_rgba_with_metadata`, and `payload` in a lowkey payload chunk.
+/// The output is PNG whatever its extension.
+pub fn save_rgba_with_payload(
+    img: &RgbaImage,
+    output_path: &str,
+    input_path: &str,
+    payload: &[u8],
+    keep: KeepMetadata,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
+    let png = PngImage {
+        data: img.as_raw(),
+        dimensions: img.dimensions(),
+        depth: BitDepth::Eight,
+    };
+    save_png(
+        &png,
+        output_path,
+        input_path,
+        None,
+        Some(payload),
+        keep,
+        encoding,
+    )
+}
+
+/// Same as `save_rgba_with_payload` for an image with 16-bit values.
+pub fn save_rgba16_with_payload(
+    img: &Rgba16Image,
+    output_path: &str,
+    input_path: &str,
+    payload: &[u8],
+    keep: KeepMetadata,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
+    let data = png_data16(img);
+    let png = PngImage {
+        data: &data,
+        dimensions: img.dimensions(),
+        depth: BitDepth::Sixteen,
+    };
+    save_png(
+        &png,
+        output_path,
+        input_path,
+        None,
+        Some(payload),
+        keep,
+        encoding,
+    )
+}
 
-    // Step 1: Extract metadata chunks from original PNG file
-    let input_file = File::open(input_path).map_err(|e| e.to_string())?;
-    let mut input_reader = BufReader::new(input_file);
+fn save_png(
+    png: &PngImage,
+    output_path: &str,
+    input_path: &str,
+    sequence_info: Option<&SequenceInfo>,
+    payload: Option<&[u8]>,
+    keep: KeepMetadata,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
+    write_atomically(output_path, |output_writer| {
+        write_png_with_metadata(
+            png,
+            output_writer,
+            input_path,
+            sequence_info,
+            payload,
+            keep,
+            encoding,
+        )
+    })
+}
+
+fn write_png_with_metadata<W: Write>(
+    png: &PngImage,
+    output_writer: W,
+    input_path: &str,
+    sequence_info: Option<&SequenceInfo>,
+    payload: Option<&[u8]>,
+    keep: KeepMetadata,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
+    let input_file = File::open(input_path).map_err(|e| LowkeyError::Io(e.to_string()))?;
+    write_png_with_metadata_from(
+        png,
+        output_writer,
+        BufReader::new(input_file),
+        sequence_info,
+        payload,
+        keep,
+        encoding,
+    )
+}
 
-    // Skip PNG signature
+/// PNG stores 16-bit samples big-endian
+fn png_data16(img: &Rgba16Image) -> Vec<u8> {
+    img.as_raw().iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/// RGBA pixel data ready for the PNG encoder, with multi-byte samples big-endian
+struct PngImage<'a> {
+    data: &'a [u8],
+    dimensions: (u32, u32),
+    depth: BitDepth,
+}
+
+fn write_png_with_metadata_from<R: Read, W: Write>(
+    png: &PngImage,
+    output_writer: W,
+    mut input_reader: R,
+    sequence_info: Option<&SequenceInfo>,
+    payload: Option<&[u8]>,
+    keep: KeepMetadata,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
     let mut signature = [0u8; 8];
-    input_reader
-        .read_exact(&mut signature)
-        .map_err(|e| e.to_string())?;
+    let png_signature: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+    // Check if input is PNG by reading signature
+    if input_reader.read_exact(&mut signature).is_err() {
+        // Too short to be an image, just save without metadata preservation
+        return write_png_simple(png, output_writer, encoding);
+    }
+    if signature != png_signature {
+        // Another format, such as a JPEG: keep what of its metadata a PNG
+        // has chunks for
+        let mut input = signature.to_vec();
+        input_reader
+            .read_to_end(&mut input)
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
+        let metadata_chunks = match keep {
+            KeepMetadata::Nothing => Vec::new(),
+            _ => foreign_metadata_chunks(&input),
+        };
+        return write_png_with_chunks(
+            png,
+            output_writer,
+            metadata_chunks,
+            sequence_info,
+            payload,
+            encoding,
+        );
+    }
 
-    // Collect metadata chunks
-    let mut metadata_chunks = Vec::new();
+    // Step 1: Extract metadata chunks from original PNG
+    let mut metadata_chunks: Vec<PngChunk> = Vec::new();
     loop {
         let mut length_bytes = [0u8; 4];
         if input_reader.read_exact(&mut length_bytes).is_err() {
@@ -165,58 +1151,155 @@ pub fn save_rgba_with_metadata(
         let mut chunk_type = [0u8; 4];
         input_reader
             .read_exact(&mut chunk_type)
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
 
         let mut chunk_data = vec![0u8; length];
         input_reader
             .read_exact(&mut chunk_data)
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
 
-        let mut _crc = [0u8; 4];
+        let mut crc = [0u8; 4];
         input_reader
-            .read_exact(&mut _crc)
-            .map_err(|e| e.to_string())?;
-
-        // Save ancillary chunks (not IHDR, IDAT, IEND, PLTE)
-        let chunk_type_str = std::str::from_utf8(&chunk_type).unwrap_or("");
-        match chunk_type_str {
-            "IHDR" | "IDAT" | "IEND" | "PLTE" => {
-                if chunk_type_str == "IEND" {
-                    break;
-                }
-            }
-            _ => {
-                metadata_chunks.push(PngChunk {
-                    chunk_type,
-                    data: chunk_data,
-                });
-            }
+            .read_exact(&mut crc)
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
+
+        if &chunk_type == b"IEND" {
+            break;
+        }
+        let mut chunk = PngChunk {
+            chunk_type,
+            data: chunk_data,
+        };
+        // A damaged chunk is dropped rather than passed on as if it were whole
+        if chunk.calculate_crc() != u32::from_be_bytes(crc)
+            || !keeps_chunk(&chunk_type, keep)
+            || (!repeatable_chunk(&chunk_type)
+                && metadata_chunks
+                    .iter()
+                    .any(|kept| kept.chunk_type == chunk_type))
+        {
+            continue;
+        }
+        if &chunk_type == b"eXIf" {
+            clear_orientation(&mut chunk.data);
         }
+        metadata_chunks.push(chunk);
     }
 
+    write_png_with_chunks(
+        png,
+        output_writer,
+        metadata_chunks,
+        sequence_info,
+        payload,
+        encoding,
+    )
+}
+
+/// Whether a chunk of the cover is copied to the output, which is written
+/// anew as 8- or 16-bit RGBA without a palette, with `keep`. The chunks kept
+/// all go between IHDR and IDAT, which is a valid place for each of them.
+///
+/// The image chunks are written anew, and so are the original's sequence
+/// info and payload, if at all. Chunks that only make sense with the
+/// cover's colour type, palette or animation are dropped, as are unknown
+/// chunks unless the PNG specification says they are safe to copy into an
+/// image whose pixels have changed (an ancillary chunk with a lowercase
+/// fourth letter).
+fn keeps_chunk(chunk_type: &[u8; 4], keep: KeepMetadata) -> bool {
+    match chunk_type {
+        b"IHDR" | b"PLTE" | b"IDAT" | b"IEND" => false,
+        _ if chunk_type == SEQUENCE_CHUNK
+            || chunk_type == SEALED_SEQUENCE_CHUNK
+            || chunk_type == PAYLOAD_CHUNK =>
+        {
+            false
+        }
+        _ if keep == KeepMetadata::Nothing => false,
+        b"iCCP" | b"gAMA" | b"cHRM" | b"sRGB" | b"cICP" | b"mDCV" | b"cLLI" => {
+            keep == KeepMetadata::All
+        }
+        b"tRNS" | b"bKGD" | b"sBIT" | b"hIST" | b"acTL" | b"fcTL" | b"fdAT" => false,
+        b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" | b"pHYs" | b"sPLT" | b"oFFs" | b"sCAL" => {
+            true
+        }
+        _ => chunk_type[0].is_ascii_lowercase() && chunk_type[3].is_ascii_lowercase(),
+    }
+}
+
+/// Whether a PNG may hold more than one chunk of this type. Of the others,
+/// only the first is kept.
+fn repeatable_chunk(chunk_type: &[u8; 4]) -> bool {
+    !matches!(
+        chunk_type,
+        b"iCCP"
+            | b"gAMA"
+            | b"cHRM"
+            | b"sRGB"
+            | b"cICP"
+            | b"mDCV"
+            | b"cLLI"
+            | b"eXIf"
+            | b"tIME"
+            | b"pHYs"
+            | b"oFFs"
+            | b"sCAL"
+    )
+}
+
+/// The EXIF and XMP of a cover in another format than PNG, as the eXIf and
+/// iTXt chunks a PNG keeps them in. Metadata is best effort, as in
+/// `read_icc_and_exif`, so an input the decoder can't read has none.
+fn foreign_metadata_chunks(input: &[u8]) -> Vec<PngChunk> {
+    let Some(mut decoder) = ImageReader::new(Cursor::new(input))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+    else {
+        return Vec::new();
+    };
+    let mut chunks = Vec::new();
+    if let Some(mut exif) = decoder.exif_metadata().ok().flatten() {
+        clear_orientation(&mut exif);
+        chunks.push(PngChunk {
+            chunk_type: *b"eXIf",
+            data: exif,
+        });
+    }
+    if let Some(xmp) = decoder.xmp_metadata().ok().flatten() {
+        // Keyword, then uncompressed, with no language or translated keyword
+        let mut data = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+        data.extend_from_slice(&xmp);
+        chunks.push(PngChunk {
+            chunk_type: *b"iTXt",
+            data,
+        });
+    }
+    chunks
+}
+
+/// Write `png` with `metadata_chunks` after its IHDR, and the sequence info
+/// and payload chunks if given.
+fn write_png_with_chunks<W: Write>(
+    png: &PngImage,
+    mut output_writer: W,
+    metadata_chunks: Vec<PngChunk>,
+    sequence_info: Option<&SequenceInfo>,
+    payload: Option<&[u8]>,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
     // Step 2: Write new PNG with metadata using temp buffer
     let mut temp_buffer = Vec::new();
     {
-        let temp_writer = BufWriter::new(&mut temp_buffer);
-        let (width, height) = img.dimensions();
-        let mut encoder = Encoder::new(temp_writer, width, height);
-        encoder.set_color(ColorType::Rgba);
-        encoder.set_depth(BitDepth::Eight);
-
-        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
-        writer
-            .write_image_data(img.as_raw())
-            .map_err(|e| e.to_string())?;
+        write_png_simple(png, BufWriter::new(&mut temp_buffer), encoding)?;
     }
 
     // Step 3: Parse temp buffer and inject metadata chunks after IHDR
-    let output_file = File::create(output_path).map_err(|e| e.to_string())?;
-    let mut output_writer = BufWriter::new(output_file);
 
     // Write PNG signature
     output_writer
         .write_all(&temp_buffer[0..8])
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| LowkeyError::Io(e.to_string()))?;
 
     let mut pos = 8;
     // Read and write IHDR
@@ -229,108 +1312,1079 @@ pub fn save_rgba_with_metadata(
     let ihdr_end = pos + 4 + 4 + ihdr_length + 4; // length + type + data + crc
     output_writer
         .write_all(&temp_buffer[pos..ihdr_end])
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| LowkeyError::Io(e.to_string()))?;
     pos = ihdr_end;
 
     // Write metadata chunks after IHDR
     for chunk in metadata_chunks {
-        chunk.write(&mut output_writer).map_err(|e| e.to_string())?;
+        chunk
+            .write(&mut output_writer)
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
     }
 
-    if let Some((index, total)) = sequence_info {
-        let mut sequence_data = Vec::new();
-        sequence_data.extend_from_slice(&index.to_be_bytes());
-        sequence_data.extend_from_slice(&total.to_be_bytes());
-
+    if let Some(sequence_info) = sequence_info {
+        let (chunk_type, data) = sequence_info.chunk();
         let sequence_chunk = PngChunk {
-            chunk_type: *b"lKsq", // lowkey sequence chunk
-            data: sequence_data,
+            chunk_type: *chunk_type,
+            data,
         };
         sequence_chunk
             .write(&mut output_writer)
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
     }
 
-    // Write remaining chunks (IDAT and IEND)
+    // Write IDAT, then the payload after the image data, then IEND, which
+    // is always the last 12 bytes
+    let iend_start = temp_buffer.len() - 12;
     output_writer
-        .write_all(&temp_buffer[pos..])
-        .map_err(|e| e.to_string())?;
+        .write_all(&temp_buffer[pos..iend_start])
+        .map_err(|e| LowkeyError::Io(e.to_string()))?;
+    if let Some(payload) = payload {
+        let payload_chunk = PngChunk {
+            chunk_type: *PAYLOAD_CHUNK,
+            data: payload.to_vec(),
+        };
+        payload_chunk
+            .write(&mut output_writer)
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
+    }
+    output_writer
+        .write_all(&temp_buffer[iend_start..])
+        .map_err(|e| LowkeyError::Io(e.to_string()))?;
+    output_writer
+        .flush()
+        .map_err(|e| LowkeyError::Io(e.to_string()))?;
 
     Ok(())
 }
 
-/// Read sequence information from PNG metadata.
+/// Read sequence information from PNG, WebP or TIFF metadata.
 ///
-/// Returns Some((current_index, total_count)) if the lKsq chunk exists,
-/// otherwise returns None.
-pub fn read_sequence_info(image_path: &str) -> Result<Option<(u32, u32)>, String> {
-    use std::io::Read;
+/// Returns Some((current_index, total_count)) if the lKsq chunk (or TIFF
+/// tag) exists, otherwise returns None.
+pub fn read_sequence_info(image_path: &str) -> Result<Option<(u32, u32)>, LowkeyError> {
+    if let Some(chunks) = read_webp_chunks(image_path)? {
+        return Ok(chunks
+            .iter()
+            .find(|(fourcc, data)| fourcc == SEQUENCE_CHUNK && data.len() == 8)
+            .map(|(_, data)| parse_sequence_chunk(data)));
+    }
+    if is_tiff_file(image_path)? {
+        return read_tiff_sequence_info(image_path);
+    }
+
+    let input_file = File::open(image_path).map_err(|e| LowkeyError::Io(e.to_string()))?;
+    Ok(find_png_chunk(BufReader::new(input_file), SEQUENCE_CHUNK)
+        .filter(|data| data.len() == 8)
+        .map(|data| parse_sequence_chunk(&data)))
+}
+
+/// The sealed sequence info of the PNG, WebP or TIFF at `image_path`, as
+/// `SequenceInfo::Sealed` holds it, or `None` if it has none.
+pub fn read_sealed_sequence_info(image_path: &str) -> Result<Option<Vec<u8>>, LowkeyError> {
+    if let Some(chunks) = read_webp_chunks(image_path)? {
+        return Ok(chunks
+            .into_iter()
+            .find(|(fourcc, _)| fourcc == SEALED_SEQUENCE_CHUNK)
+            .map(|(_, data)| data));
+    }
+    if is_tiff_file(image_path)? {
+        let file = File::open(image_path).map_err(|e| LowkeyError::Io(e.to_string()))?;
+        let mut decoder = tiff::decoder::Decoder::new(BufReader::new(file)).map_err(|e| {
+            LowkeyError::Format(format!("Failed to read TIFF '{}': {}", image_path, e))
+        })?;
+        // Sealed info is stored as bytes, plain as two LONGs
+        return Ok(decoder
+            .find_tag(SEQUENCE_TAG)
+            .ok()
+            .flatten()
+            .and_then(|value| value.into_u8_vec().ok()));
+    }
 
-    let input_file = File::open(image_path).map_err(|e| e.to_string())?;
-    let mut input_reader = BufReader::new(input_file);
+    let input_file = File::open(image_path).map_err(|e| LowkeyError::Io(e.to_string()))?;
+    Ok(find_png_chunk(
+        BufReader::new(input_file),
+        SEALED_SEQUENCE_CHUNK,
+    ))
+}
+
+/// The lowkey payload chunk of the PNG at `image_path`, written by
+/// `save_rgba_with_payload`, or `None` if there is none.
+pub fn read_payload_chunk(image_path: &str) -> Result<Option<Vec<u8>>, LowkeyError> {
+    let input_file = File::open(image_path).map_err(|e| LowkeyError::Io(e.to_string()))?;
+    Ok(find_png_chunk(BufReader::new(input_file), PAYLOAD_CHUNK))
+}
 
+/// Same as `read_payload_chunk` for a PNG in memory.
+pub fn payload_chunk_from(bytes: &[u8]) -> Option<Vec<u8>> {
+    find_png_chunk(bytes, PAYLOAD_CHUNK)
+}
+
+/// The data of the first `chunk_type` chunk of the PNG in `input_reader`,
+/// or `None` if it is not a PNG or has no such chunk.
+fn find_png_chunk<R: Read>(mut input_reader: R, chunk_type: &[u8; 4]) -> Option<Vec<u8>> {
     let mut signature = [0u8; 8];
     let png_signature: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
     // Check if it's a PNG file
     if input_reader.read_exact(&mut signature).is_err() || signature != png_signature {
-        return Ok(None);
+        return None;
     }
 
-    // Read chunks to find lKsq
     loop {
         let mut length_bytes = [0u8; 4];
-        if input_reader.read_exact(&mut length_bytes).is_err() {
-            break;
-        }
+        input_reader.read_exact(&mut length_bytes).ok()?;
         let length = u32::from_be_bytes(length_bytes) as usize;
 
-        let mut chunk_type = [0u8; 4];
-        if input_reader.read_exact(&mut chunk_type).is_err() {
-            break;
-        }
+        let mut this_type = [0u8; 4];
+        input_reader.read_exact(&mut this_type).ok()?;
 
         let mut chunk_data = vec![0u8; length];
-        if input_reader.read_exact(&mut chunk_data).is_err() {
-            break;
-        }
+        input_reader.read_exact(&mut chunk_data).ok()?;
 
         let mut _crc = [0u8; 4];
-        if input_reader.read_exact(&mut _crc).is_err() {
-            break;
-        }
+        input_reader.read_exact(&mut _crc).ok()?;
 
-        // Check for lKsq chunk
-        if &chunk_type == b"lKsq" && chunk_data.len() == 8 {
-            let index =
-                u32::from_be_bytes([chunk_data[0], chunk_data[1], chunk_data[2], chunk_data[3]]);
-            let total =
-                u32::from_be_bytes([chunk_data[4], chunk_data[5], chunk_data[6], chunk_data[7]]);
-            return Ok(Some((index, total)));
+        if &this_type == chunk_type {
+            return Some(chunk_data);
         }
 
         // Stop at IEND
-        if &chunk_type == b"IEND" {
-            break;
+        if &this_type == b"IEND" {
+            return None;
         }
     }
-
-    Ok(None)
 }
 
-/// Save RGBA image as PNG without metadata preservation.
-fn save_rgba_simple(img: &RgbaImage, output_path: &str) -> Result<(), String> {
-    let output_file = File::create(output_path).map_err(|e| e.to_string())?;
-    let output_writer = BufWriter::new(output_file);
-    let (width, height) = img.dimensions();
+/// Write RGBA image as PNG without metadata preservation.
+fn write_png_simple<W: Write>(
+    png: &PngImage,
+    output_writer: W,
+    encoding: PngEncoding,
+) -> Result<(), LowkeyError> {
+    let (width, height) = png.dimensions;
     let mut encoder = Encoder::new(output_writer, width, height);
     encoder.set_color(ColorType::Rgba);
-    encoder.set_depth(BitDepth::Eight);
+    encoder.set_depth(png.depth);
+    encoder.set_compression(match encoding.compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    let (filter, adaptive) = match encoding.filter {
+        PngFilter::Adaptive => (FilterType::Sub, AdaptiveFilterType::Adaptive),
+        PngFilter::None => (FilterType::NoFilter, AdaptiveFilterType::NonAdaptive),
+        PngFilter::Sub => (FilterType::Sub, AdaptiveFilterType::NonAdaptive),
+        PngFilter::Paeth => (FilterType::Paeth, AdaptiveFilterType::NonAdaptive),
+    };
+    encoder.set_filter(filter);
+    encoder.set_adaptive_filter(adaptive);
 
-    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| LowkeyError::Io(e.to_string()))?;
     writer
-        .write_image_data(img.as_raw())
-        .map_err(|e| e.to_string())?;
+        .write_image_data(png.data)
+        .map_err(|e| LowkeyError::Io(e.to_string()))?;
 
     Ok(())
 }
+
+/// lowkey sequence chunk, in PNG and WebP files: [4 bytes index] + [4 bytes total], big-endian
+const SEQUENCE_CHUNK: &[u8; 4] = b"lKsq";
+
+/// Chunk holding sealed sequence info, in PNG and WebP files, with a name
+/// that does not give lowkey away
+const SEALED_SEQUENCE_CHUNK: &[u8; 4] = b"daTa";
+
+/// lowkey payload chunk, in PNG files: a message as it would be embedded in
+/// the pixels, header and body, for the chunk format
+const PAYLOAD_CHUNK: &[u8; 4] = b"lKms";
+
+fn parse_sequence_chunk(data: &[u8]) -> (u32, u32) {
+    let index = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    let total = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    (index, total)
+}
+
+/// Whether the WebP file at `path` holds lossy (VP8) image data, which
+/// would have destroyed any hidden message. `false` for other files.
+pub fn is_lossy_webp(path: &str) -> Result<bool, LowkeyError> {
+    Ok(read_webp_chunks(path)?.is_some_and(|chunks| {
+        chunks
+            .iter()
+            .any(|(fourcc, _)| matches!(fourcc, b"VP8 " | b"ALPH" | b"ANMF"))
+    }))
+}
+
+/// A RIFF chunk as (FourCC, data).
+type WebPChunk = ([u8; 4], Vec<u8>);
+
+/// The chunks of the RIFF container at `path`, or `None` if the file is not
+/// WebP.
+fn read_webp_chunks(path: &str) -> Result<Option<Vec<WebPChunk>>, LowkeyError> {
+    let mut signature = [0u8; 12];
+    let mut file = File::open(path).map_err(|e| LowkeyError::Io(e.to_string()))?;
+    if file.read_exact(&mut signature).is_err()
+        || &signature[..4] != b"RIFF"
+        || &signature[8..] != b"WEBP"
+    {
+        return Ok(None);
+    }
+
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .map_err(|e| LowkeyError::Io(e.to_string()))?;
+
+    let mut chunks = Vec::new();
+    let mut rest = &bytes[..];
+    while rest.len() >= 8 {
+        let fourcc = [rest[0], rest[1], rest[2], rest[3]];
+        let size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let data = rest
+            .get(8..8 + size)
+            .ok_or_else(|| LowkeyError::Format(format!("Truncated WebP chunk in '{}'", path)))?;
+        chunks.push((fourcc, data.to_vec()));
+        // Chunks are padded to an even size
+        rest = rest.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    Ok(Some(chunks))
+}
+
+fn write_webp_chunk(bytes: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(fourcc);
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        bytes.push(0);
+    }
+}
+
+/// Save RGBA image as lossless WebP, keeping the ICC profile and EXIF data of
+/// the original image (whatever its format) and recording `sequence_info`
+/// in an lKsq chunk.
+///
+/// Metadata needs the extended WebP container, so the lossless bitstream from
+/// the encoder is rewrapped as VP8X + ICCP + VP8L + EXIF + lKsq when there is
+/// any; otherwise the encoder's simple container is written as is.
+pub fn write_webp_with_metadata<W: Write>(
+    img: &RgbaImage,
+    mut output_writer: W,
+    input_path: &str,
+    sequence_info: Option<&SequenceInfo>,
+    keep: KeepMetadata,
+) -> Result<(), LowkeyError> {
+    let (width, height) = img.dimensions();
+    let mut simple = Vec::new();
+    WebPEncoder::new_lossless(&mut simple)
+        .encode(img.as_raw(), width, height, ExtendedColorType::Rgba8)
+        .map_err(|e| LowkeyError::Io(format!("Failed to encode WebP: {}", e)))?;
+
+    let (icc_profile, exif) = match keep {
+        KeepMetadata::Nothing => (None, None),
+        _ => read_icc_and_exif(input_path),
+    };
+    let icc_profile = icc_profile.filter(|_| keep == KeepMetadata::All);
+
+    if icc_profile.is_none() && exif.is_none() && sequence_info.is_none() {
+        output_writer
+            .write_all(&simple)
+            .and_then(|_| output_writer.flush())
+            .map_err(|e| LowkeyError::Io(e.to_string()))?;
+        return Ok(());
+    }
+
+    // The encoder's output is RIFF + size + WEBP + one VP8L chunk
+    let vp8l = simple
+        .get(20..)
+        .filter(|_| &simple[12..16] == b"VP8L")
+        .ok_or_else(|| LowkeyError::Format("Unexpected WebP encoder output".to_string()))?;
+    let vp8l = &vp8l[..u32::from_le_bytes(simple[16..20].try_into().unwrap()) as usize];
+
+    // Alpha, EXIF and ICC flags, then the canvas size minus one in 24 bits each
+    let mut flags = 1 << 4;
+    if exif.is_some() {
+        flags |= 1 << 3;
+    }
+    if icc_profile.is_some() {
+        flags |= 1 << 5;
+    }
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let mut chunks = Vec::new();
+    write_webp_chunk(&mut chunks, b"VP8X", &vp8x);
+    if let Some(icc_profile) = &icc_profile {
+        write_webp_chunk(&mut chunks, b"ICCP", icc_profile);
+    }
+    write_webp_chunk(&mut chunks, b"VP8L", vp8l);
+    if let Some(exif) = &exif {
+        write_webp_chunk(&mut chunks, b"EXIF", exif);
+    }
+    if let Some(sequence_info) = sequence_info {
+        let (fourcc, data) = sequence_info.chunk();
+        write_webp_chunk(&mut chunks, fourcc, &data);
+    }
+
+    output_writer
+        .write_all(b"RIFF")
+        .and_then(|_| output_writer.write_all(&(chunks.len() as u32 + 4).to_le_bytes()))
+        .and_then(|_| output_writer.write_all(b"WEBP"))
+        .and_then(|_| output_writer.write_all(&chunks))
+        .and_then(|_| output_writer.flush())
+        .map_err(|e| LowkeyError::Io(e.to_string()))
+}
+
+/// The ICC profile and EXIF data of the image at `path`, whatever its format.
+///
+/// Metadata is best effort, as with a PNG cover without chunks, so a file
+/// that cannot be read has neither.
+fn read_icc_and_exif(path: &str) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    ImageReader::open(path)
+        .ok()
+        .and_then(|reader| reader.with_guessed_format().ok())
+        .and_then(|reader| reader.into_decoder().ok())
+        .map(|mut decoder| {
+            let mut exif = decoder.exif_metadata().ok().flatten();
+            if let Some(exif) = &mut exif {
+                clear_orientation(exif);
+            }
+            (decoder.icc_profile().ok().flatten(), exif)
+        })
+        .unwrap_or_default()
+}
+
+/// Save RGBA image as 32-bit BMP. BMP has nowhere to keep metadata, so a
+/// multi-image message in BMPs is ordered by filename on decode.
+pub fn write_bmp<W: Write>(img: &RgbaImage, mut output_writer: W) -> Result<(), LowkeyError> {
+    let (width, height) = img.dimensions();
+    BmpEncoder::new(&mut output_writer)
+        .encode(img.as_raw(), width, height, ExtendedColorType::Rgba8)
+        .map_err(|e| LowkeyError::Io(format!("Failed to encode BMP: {}", e)))?;
+    output_writer
+        .flush()
+        .map_err(|e| LowkeyError::Io(e.to_string()))
+}
+
+/// Private TIFF tag holding the sequence info as two LONGs: [index, total]
+const SEQUENCE_TAG: Tag = Tag::Unknown(65000);
+
+/// Save RGBA image as LZW-compressed TIFF, keeping the ICC profile of the
+/// original image (whatever its format) and recording `sequence_info` in a
+/// private tag.
+///
+/// EXIF would need its own IFD and is dropped.
+pub fn write_tiff_with_metadata<W: Write + std::io::Seek>(
+    img: &RgbaImage,
+    output_writer: W,
+    input_path: &str,
+    sequence_info: Option<&SequenceInfo>,
+    keep: KeepMetadata,
+) -> Result<(), LowkeyError> {
+    let (width, height) = img.dimensions();
+    let (icc_profile, _) = read_icc_and_exif(input_path);
+    let icc_profile = icc_profile.filter(|_| keep == KeepMetadata::All);
+
+    let mut encoder = TiffEncoder::new(output_writer)
+        .map_err(|e| LowkeyError::Io(format!("Failed to encode TIFF: {}", e)))?
+        .with_compression(Compression::Lzw);
+    let mut image = encoder
+        .new_image::<colortype::RGBA8>(width, height)
+        .map_err(|e| LowkeyError::Io(format!("Failed to encode TIFF: {}", e)))?;
+    if let Some(icc_profile) = &icc_profile {
+        image
+            .encoder()
+            .write_tag(Tag::IccProfile, &icc_profile[..])
+            .map_err(|e| LowkeyError::Io(format!("Failed to encode TIFF: {}", e)))?;
+    }
+    let written = match sequence_info {
+        Some(SequenceInfo::Plain(index, total)) => image
+            .encoder()
+            .write_tag(SEQUENCE_TAG, &[*index, *total][..]),
+        Some(SequenceInfo::Sealed(sealed)) => image.encoder().write_tag(SEQUENCE_TAG, &sealed[..]),
+        None => Ok(()),
+    };
+    written.map_err(|e| LowkeyError::Io(format!("Failed to encode TIFF: {}", e)))?;
+    image
+        .write_data(img.as_raw())
+        .map_err(|e| LowkeyError::Io(format!("Failed to encode TIFF: {}", e)))
+}
+
+/// Whether the file at `path` starts with a TIFF signature, in either byte order.
+fn is_tiff_file(path: &str) -> Result<bool, LowkeyError> {
+    let mut signature = [0u8; 4];
+    let mut file = File::open(path).map_err(|e| LowkeyError::Io(e.to_string()))?;
+    Ok(file.read_exact(&mut signature).is_ok()
+        && (signature == *b"II*\0" || signature == *b"MM\0*"))
+}
+
+fn read_tiff_sequence_info(path: &str) -> Result<Option<(u32, u32)>, LowkeyError> {
+    let file = File::open(path).map_err(|e| LowkeyError::Io(e.to_string()))?;
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(file))
+        .map_err(|e| LowkeyError::Format(format!("Failed to read TIFF '{}': {}", path, e)))?;
+    let values = decoder
+        .find_tag_unsigned_vec::<u32>(SEQUENCE_TAG)
+        .map_err(|e| LowkeyError::Format(format!("Failed to read TIFF '{}': {}", path, e)))?;
+    Ok(values.and_then(|values| match values[..] {
+        [index, total] => Some((index, total)),
+        _ => None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webp_round_trip() {
+        let dir = std::env::temp_dir().join(format!("lowkey-webp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png").to_string_lossy().to_string();
+        let output = dir.join("output.webp").to_string_lossy().to_string();
+
+        let img = RgbaImage::from_fn(33, 17, |x, y| {
+            image::Rgba([
+                x as u8 * 7,
+                y as u8 * 13,
+                (x ^ y) as u8,
+                200 + (x % 3) as u8,
+            ])
+        });
+        img.save(&cover).unwrap();
+
+        for sequence_info in [None, Some((2, 5))] {
+            let info = sequence_info.map(|(index, total)| SequenceInfo::Plain(index, total));
+            save_rgba_with_metadata(
+                &img,
+                &output,
+                &cover,
+                info.as_ref(),
+                KeepMetadata::All,
+                PngEncoding::default(),
+            )
+            .unwrap();
+            assert_eq!(read_image(&output).unwrap(), img);
+            assert_eq!(read_sequence_info(&output).unwrap(), sequence_info);
+            assert!(!is_lossy_webp(&output).unwrap());
+        }
+        let sealed = SequenceInfo::Sealed(vec![9; 40]);
+        save_rgba_with_metadata(
+            &img,
+            &output,
+            &cover,
+            Some(&sealed),
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(read_sequence_info(&output).unwrap(), None);
+        assert_eq!(
+            read_sealed_sequence_info(&output).unwrap(),
+            Some(vec![9; 40])
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sequence_info_not_copied() {
+        let dir = std::env::temp_dir().join(format!("lowkey-sequence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.png").to_string_lossy().to_string();
+        let second = dir.join("second.png").to_string_lossy().to_string();
+
+        let img = RgbaImage::from_fn(8, 8, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        img.save(&first).unwrap();
+        save_rgba_with_metadata(
+            &img,
+            &second,
+            &first,
+            Some(&SequenceInfo::Plain(1, 2)),
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(read_sequence_info(&second).unwrap(), Some((1, 2)));
+
+        save_rgba_with_metadata(
+            &img,
+            &first,
+            &second,
+            None,
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(read_sequence_info(&first).unwrap(), None);
+        save_rgba_with_metadata(
+            &img,
+            &first,
+            &second,
+            Some(&SequenceInfo::Plain(0, 3)),
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(read_sequence_info(&first).unwrap(), Some((0, 3)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_payload_chunk() {
+        let dir = std::env::temp_dir().join(format!("lowkey-payload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png").to_string_lossy().to_string();
+        let output = dir.join("output.png").to_string_lossy().to_string();
+
+        let img = RgbaImage::from_fn(8, 8, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        img.save(&cover).unwrap();
+        assert_eq!(read_payload_chunk(&cover).unwrap(), None);
+
+        let payload: Vec<u8> = (0..=255).collect();
+        save_rgba_with_payload(
+            &img,
+            &output,
+            &cover,
+            &payload,
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(read_image(&output).unwrap(), img);
+        assert_eq!(read_payload_chunk(&output).unwrap(), Some(payload.clone()));
+        assert_eq!(
+            payload_chunk_from(&fs::read(&output).unwrap()),
+            Some(payload)
+        );
+
+        // Saving over it, as wipe does, drops it
+        save_rgba_with_metadata(
+            &img,
+            &cover,
+            &output,
+            None,
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(read_payload_chunk(&cover).unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bmp_and_tiff_round_trip() {
+        let dir = std::env::temp_dir().join(format!("lowkey-bmp-tiff-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png").to_string_lossy().to_string();
+
+        let img = RgbaImage::from_fn(21, 9, |x, y| {
+            image::Rgba([x as u8 * 11, y as u8 * 5, (x + y) as u8, 255 - x as u8])
+        });
+        img.save(&cover).unwrap();
+
+        let bmp = dir.join("output.bmp").to_string_lossy().to_string();
+        save_rgba_with_metadata(
+            &img,
+            &bmp,
+            &cover,
+            Some(&SequenceInfo::Plain(0, 2)),
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(read_image(&bmp).unwrap(), img);
+        assert_eq!(read_sequence_info(&bmp).unwrap(), None);
+
+        let tiff = dir.join("output.tiff").to_string_lossy().to_string();
+        for sequence_info in [None, Some((1, 3))] {
+            let info = sequence_info.map(|(index, total)| SequenceInfo::Plain(index, total));
+            save_rgba_with_metadata(
+                &img,
+                &tiff,
+                &cover,
+                info.as_ref(),
+                KeepMetadata::All,
+                PngEncoding::default(),
+            )
+            .unwrap();
+            assert_eq!(read_image(&tiff).unwrap(), img);
+            assert_eq!(read_sequence_info(&tiff).unwrap(), sequence_info);
+        }
+        let sealed = SequenceInfo::Sealed(vec![7; 40]);
+        save_rgba_with_metadata(
+            &img,
+            &tiff,
+            &cover,
+            Some(&sealed),
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(read_sequence_info(&tiff).unwrap(), None);
+        assert_eq!(read_sealed_sequence_info(&tiff).unwrap(), Some(vec![7; 40]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        let dir = std::env::temp_dir().join(format!("lowkey-orientation-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.jpg").to_string_lossy().to_string();
+
+        // A 6x2 JPEG whose EXIF says to turn it 90 degrees clockwise
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .encode(&[128; 6 * 2 * 3], 6, 2, ExtendedColorType::Rgb8)
+            .unwrap();
+        let tiff = [
+            b"II*\0".as_slice(),
+            &8u32.to_le_bytes(),
+            &1u16.to_le_bytes(),
+            &0x0112u16.to_le_bytes(),
+            &3u16.to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &[6, 0, 0, 0],
+            &0u32.to_le_bytes(),
+        ]
+        .concat();
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&(2 + 6 + tiff.len() as u16).to_be_bytes());
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(&tiff);
+        jpeg.splice(2..2, app1);
+        let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+        let mut xmp_app1 = vec![0xFF, 0xE1];
+        xmp_app1.extend_from_slice(&(2 + 29 + xmp.len() as u16).to_be_bytes());
+        xmp_app1.extend_from_slice(b"http://ns.adobe.com/xap/1.0/\0");
+        xmp_app1.extend_from_slice(xmp);
+        jpeg.splice(2..2, xmp_app1);
+        fs::write(&cover, &jpeg).unwrap();
+
+        let img = read_image(&cover).unwrap();
+        assert_eq!(img.dimensions(), (2, 6));
+        assert_eq!(
+            read_cover(&cover, true, false).unwrap().0.dimensions(),
+            (2, 6)
+        );
+        let header = probe_image(&cover).unwrap();
+        assert_eq!((header.width, header.height), (2, 6));
+        assert_eq!(read_carrier(&cover).unwrap().dimensions(), (6, 2));
+
+        // The EXIF kept in the output no longer turns it
+        let output = dir.join("output.webp").to_string_lossy().to_string();
+        save_rgba_with_metadata(
+            &img,
+            &output,
+            &cover,
+            None,
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        let exif = read_icc_and_exif(&output).1.unwrap();
+        assert_eq!(
+            Orientation::from_exif_chunk(&exif),
+            Some(Orientation::NoTransforms)
+        );
+        assert_eq!(read_image(&output).unwrap().dimensions(), (2, 6));
+
+        // A PNG made from it keeps its EXIF and XMP too
+        let output = dir.join("output.png").to_string_lossy().to_string();
+        save_rgba_with_metadata(
+            &img,
+            &output,
+            &cover,
+            None,
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        let mut decoder = ImageReader::open(&output).unwrap().into_decoder().unwrap();
+        let exif = decoder.exif_metadata().unwrap().unwrap();
+        assert_eq!(
+            Orientation::from_exif_chunk(&exif),
+            Some(Orientation::NoTransforms)
+        );
+        assert_eq!(decoder.xmp_metadata().unwrap().unwrap(), xmp);
+        assert_eq!(read_image(&output).unwrap().dimensions(), (2, 6));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_convert_srgb() {
+        use image::ImageEncoder;
+
+        let dir = std::env::temp_dir().join(format!("lowkey-srgb-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png").to_string_lossy().to_string();
+        let output = dir.join("output.png").to_string_lossy().to_string();
+
+        // A Display P3 cover, whose colours are more saturated than the same
+        // values in sRGB
+        let pixels = [100, 150, 200, 255, 200, 60, 40, 128];
+        let mut encoder = image::codecs::png::PngEncoder::new(File::create(&cover).unwrap());
+        encoder
+            .set_icc_profile(ColorProfile::new_display_p3().encode().unwrap())
+            .unwrap();
+        encoder
+            .write_image(&pixels, 2, 1, ExtendedColorType::Rgba8)
+            .unwrap();
+
+        let (raw, _) = read_cover(&cover, false, false).unwrap();
+        assert_eq!(raw.as_raw(), &pixels);
+        let (img, _) = read_cover(&cover, false, true).unwrap();
+        let [r, g, b, a] = img.get_pixel(0, 0).0;
+        assert!(r < 100 && g > 150 && b > 200 && a == 255, "{:?}", img);
+        assert!(img.get_pixel(1, 0)[0] > 200 && img.get_pixel(1, 0)[3] == 128);
+
+        let icc_chunk =
+            |path: &str| find_png_chunk(BufReader::new(File::open(path).unwrap()), b"iCCP");
+        save_rgba_with_metadata(
+            &img,
+            &output,
+            &cover,
+            None,
+            KeepMetadata::AllButColorProfile,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert!(icc_chunk(&output).is_none());
+        assert_eq!(read_cover(&output, false, true).unwrap().0, img);
+        save_rgba_with_metadata(
+            &raw,
+            &output,
+            &cover,
+            None,
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert!(icc_chunk(&output).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_rules() {
+        let dir = std::env::temp_dir().join(format!("lowkey-chunks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png").to_string_lossy().to_string();
+        let output = dir.join("output.png").to_string_lossy().to_string();
+
+        let img = RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 255]));
+        let png = PngImage {
+            data: img.as_raw(),
+            dimensions: img.dimensions(),
+            depth: BitDepth::Eight,
+        };
+        let mut bytes = Vec::new();
+        write_png_simple(&png, &mut bytes, PngEncoding::default()).unwrap();
+        let mut chunks = Vec::new();
+        for (chunk_type, data) in [
+            (b"gAMA", &[0, 0, 0xB1, 0x8F][..]),
+            (b"gAMA", &[0, 1, 0, 0]),
+            (b"tRNS", &[0, 0, 0, 0, 0, 0]),
+            (b"tEXt", b"Title\0first"),
+            (b"tEXt", b"Title\0second"),
+            (b"prVt", b"safe to copy"),
+            (b"prVT", b"tied to the pixels"),
+        ] {
+            PngChunk {
+                chunk_type: *chunk_type,
+                data: data.to_vec(),
+            }
+            .write(&mut chunks)
+            .unwrap();
+        }
+        // The second text chunk is damaged
+        let damaged = chunks
+            .windows(6)
+            .position(|window| window == b"second")
+            .unwrap();
+        chunks[damaged] = b'S';
+        bytes.splice(33..33, chunks);
+        fs::write(&cover, &bytes).unwrap();
+
+        let chunk_types = |path: &str| {
+            let bytes = fs::read(path).unwrap();
+            let mut types = Vec::new();
+            let mut pos = 8;
+            while pos < bytes.len() {
+                let length = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+                types.push(String::from_utf8_lossy(&bytes[pos + 4..pos + 8]).to_string());
+                pos += 12 + length;
+            }
+            types.join(" ")
+        };
+        save_rgba_with_metadata(
+            &img,
+            &output,
+            &cover,
+            Some(&SequenceInfo::Plain(0, 2)),
+            KeepMetadata::All,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(chunk_types(&output), "IHDR gAMA tEXt prVt lKsq IDAT IEND");
+        let gamma = find_png_chunk(BufReader::new(File::open(&output).unwrap()), b"gAMA");
+        assert_eq!(gamma.unwrap(), [0, 0, 0xB1, 0x8F]);
+
+        save_rgba_with_metadata(
+            &img,
+            &output,
+            &cover,
+            None,
+            KeepMetadata::AllButColorProfile,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(chunk_types(&output), "IHDR tEXt prVt IDAT IEND");
+        save_rgba_with_metadata(
+            &img,
+            &output,
+            &cover,
+            Some(&SequenceInfo::Plain(0, 2)),
+            KeepMetadata::Nothing,
+            PngEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(chunk_types(&output), "IHDR lKsq IDAT IEND");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_png_encoding() {
+        let dir = std::env::temp_dir().join(format!("lowkey-png-encoding-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png").to_string_lossy().to_string();
+        let output = dir.join("output.png").to_string_lossy().to_string();
+
+        let img = RgbaImage::from_fn(64, 48, |x, y| {
+            image::Rgba([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8, 255])
+        });
+        img.save(&cover).unwrap();
+
+        let mut sizes = Vec::new();
+        for compression in ["fast", "default", "best"] {
+            for filter in ["adaptive", "none", "sub", "paeth"] {
+                let encoding = PngEncoding {
+                    compression: compression.parse().unwrap(),
+                    filter: filter.parse().unwrap(),
+                };
+                save_rgba_with_metadata(&img, &output, &cover, None, KeepMetadata::All, encoding)
+                    .unwrap();
+                assert_eq!(read_image(&output).unwrap(), img);
+                sizes.push((compression, fs::metadata(&output).unwrap().len()));
+            }
+        }
+        let smallest = |level: &str| {
+            sizes
+                .iter()
+                .filter(|(compression, _)| *compression == level)
+                .map(|(_, size)| *size)
+                .min()
+                .unwrap()
+        };
+        assert!(smallest("best") <= smallest("fast"));
+        assert!("zopfli".parse::<PngCompression>().is_err());
+        assert!("up".parse::<PngFilter>().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = std::env::temp_dir().join(format!("lowkey-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.png").to_string_lossy().to_string();
+
+        write_atomically(&path, |writer| {
+            writer.write_all(b"complete").unwrap();
+            Ok(())
+        })
+        .unwrap();
+        let failed = write_atomically(&path, |writer| {
+            writer.write_all(b"trunc").unwrap();
+            Err(LowkeyError::Io("disk full".to_string()))
+        });
+        assert!(failed.is_err());
+        // The old file is untouched and no temporary file is left behind
+        assert_eq!(fs::read(&path).unwrap(), b"complete");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            // A replaced file keeps its mode
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+            write_atomically(&path, |writer| {
+                writer.write_all(b"replaced").unwrap();
+                Ok(())
+            })
+            .unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_attributes() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let dir = std::env::temp_dir().join(format!("lowkey-attributes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png").to_string_lossy().to_string();
+        let output = dir.join("output.png").to_string_lossy().to_string();
+        fs::write(&cover, b"cover").unwrap();
+        fs::write(&output, b"output").unwrap();
+        let old = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        File::options()
+            .write(true)
+            .open(&cover)
+            .unwrap()
+            .set_times(fs::FileTimes::new().set_accessed(old).set_modified(old))
+            .unwrap();
+        let mut permissions = fs::metadata(&cover).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&cover, permissions).unwrap();
+
+        FileAttributes::read(&cover)
+            .unwrap()
+            .apply(&output)
+            .unwrap();
+        let metadata = fs::metadata(&output).unwrap();
+        assert_eq!(metadata.modified().unwrap(), old);
+        assert!(metadata.permissions().readonly());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_images_from_dir() {
+        let dir = std::env::temp_dir().join(format!("lowkey-scan-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in [
+            "a.png",
+            "notes.txt",
+            "2024/b.jpg",
+            "2024/thumbs/c.png",
+            "2025/d.PNG",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let names = |scan: &DirScan| {
+            let images = collect_images_from_dir(dir.to_str().unwrap(), scan).unwrap_or_default();
+            images
+                .iter()
+                .map(|image| {
+                    Path::new(image)
+                        .strip_prefix(&dir)
+                        .unwrap()
+                        .to_string_lossy()
+                        .replace('\\', "/")
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(&DirScan::default()), ["a.png"]);
+        let recursive = DirScan {
+            recursive: true,
+            ..DirScan::default()
+        };
+        assert_eq!(
+            names(&recursive),
+            ["2024/b.jpg", "2024/thumbs/c.png", "2025/d.PNG", "a.png"]
+        );
+        let filtered = DirScan {
+            recursive: true,
+            include: vec!["*.png".into(), "2025/*".into()],
+            exclude: vec!["thumbs".into()],
+            ..DirScan::default()
+        };
+        assert_eq!(names(&filtered), ["2025/d.PNG", "a.png"]);
+        let nested = DirScan {
+            recursive: true,
+            include: vec!["2024/**/*.png".into()],
+            ..DirScan::default()
+        };
+        assert_eq!(names(&nested), ["2024/thumbs/c.png"]);
+
+        #[cfg(unix)]
+        {
+            // A link back up must not send the walk round in circles
+            std::os::unix::fs::symlink(&dir, dir.join("2025/loop")).unwrap();
+            let follow = DirScan {
+                recursive: true,
+                symlinks: Symlinks::Follow,
+                ..DirScan::default()
+            };
+            assert_eq!(names(&follow).len(), 4);
+            std::os::unix::fs::symlink(dir.join("a.png"), dir.join("2025/e.png")).unwrap();
+            assert_eq!(names(&recursive).len(), 5);
+            let skip = DirScan {
+                recursive: true,
+                symlinks: Symlinks::Skip,
+                ..DirScan::default()
+            };
+            assert_eq!(names(&skip).len(), 4);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sort_images() {
+        let mut names = [
+            "img10.png",
+            "img2.png",
+            "img02.png",
+            "a/img1.png",
+            "img1b.png",
+        ]
+        .map(String::from);
+        sort_images(&mut names, SortOrder::Natural).unwrap();
+        assert_eq!(
+            names,
+            [
+                "a/img1.png",
+                "img1b.png",
+                "img02.png",
+                "img2.png",
+                "img10.png"
+            ]
+        );
+
+        let dir = std::env::temp_dir().join(format!("lowkey-sort-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut paths =
+            [("a.png", 3), ("b.png", 1), ("c.png", 2), ("d.png", 1)].map(|(name, len)| {
+                let path = dir.join(name);
+                fs::write(&path, vec![0; len]).unwrap();
+                path.to_string_lossy().to_string()
+            });
+        sort_images(&mut paths, SortOrder::Size).unwrap();
+        let names = paths.map(|path| path[path.len() - 5..].to_string());
+        assert_eq!(names, ["b.png", "d.png", "c.png", "a.png"]);
+
+        let mut paths = [("e.png", 4), ("f.png", 9), ("g.png", 6)].map(|(name, side)| {
+            let path = dir.join(name).to_string_lossy().to_string();
+            RgbaImage::new(side, side).save(&path).unwrap();
+            path
+        });
+        sort_images(&mut paths, SortOrder::Capacity).unwrap();
+        let names = paths.map(|path| path[path.len() - 5..].to_string());
+        assert_eq!(names, ["f.png", "g.png", "e.png"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,670 @@
+// Embedding in the quantized DCT coefficients of a baseline JPEG. The
+// entropy-coded data is Huffman-decoded only as far as needed to find the
+// coefficients, and the file is written back with just their bits changed,
+// without decoding the image or compressing it again.
+//
+// A coefficient carries a bit in the lowest bit of its magnitude. Only AC
+// coefficients of magnitude 2 or more are used: changing that bit then
+// keeps the coefficient's size category, so its Huffman symbol and the
+// length of the scan stay the same, and no coefficient is turned into a 0
+// or a 1, which are too common for a change to go unnoticed.
+
+use std::fs;
+
+use crate::carrier::Carrier;
+use crate::error::LowkeyError;
+
+/// Start of image, which every JPEG begins with
+const SOI: [u8; 2] = [0xFF, 0xD8];
+
+/// Coefficients in an 8x8 block, the first of them DC
+const BLOCK_COEFFICIENTS: usize = 64;
+
+/// Whether `bytes` start like a JPEG.
+pub fn is_jpeg(bytes: &[u8]) -> bool {
+    bytes.starts_with(&SOI)
+}
+
+/// Whether the file at `path` is a JPEG, by its first bytes.
+pub fn is_jpeg_file(path: &str) -> Result<bool, LowkeyError> {
+    use std::io::Read;
+
+    let mut start = [0u8; 2];
+    let mut file = fs::File::open(path)
+        .map_err(|e| LowkeyError::Io(format!("Failed to open '{}': {}", path, e)))?;
+    Ok(file.read_exact(&mut start).is_ok() && is_jpeg(&start))
+}
+
+/// A baseline JPEG with the coefficients that can carry message bits.
+pub struct Jpeg {
+    bytes: Vec<u8>,
+    /// Entropy-coded data between restart markers, in file order
+    segments: Vec<Segment>,
+    /// Coefficients that carry a bit, in file order
+    slots: Vec<Slot>,
+}
+
+/// Stretch of entropy-coded data, with byte stuffing undone.
+struct Segment {
+    /// Range of the stuffed data in the file
+    start: usize,
+    end: usize,
+    data: Vec<u8>,
+}
+
+/// Where the lowest magnitude bit of a coefficient is: the last of the
+/// extra bits after its Huffman symbol. Those bits are the ones' complement
+/// of the magnitude for a negative coefficient.
+struct Slot {
+    segment: usize,
+    bit: usize,
+    negative: bool,
+}
+
+struct Component {
+    id: u8,
+    horizontal: usize,
+    vertical: usize,
+}
+
+struct Frame {
+    width: usize,
+    height: usize,
+    components: Vec<Component>,
+}
+
+impl Jpeg {
+    /// Read the JPEG at `path`.
+    pub fn read(path: &str) -> Result<Self, LowkeyError> {
+        let bytes = fs::read(path)
+            .map_err(|e| LowkeyError::Io(format!("Failed to read '{}': {}", path, e)))?;
+        Self::parse(bytes).map_err(|e| e.map_message(|e| format!("'{}': {}", path, e)))
+    }
+
+    /// Find the coefficients of the JPEG in `bytes`, which must be baseline
+    /// (sequential and Huffman-coded).
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, LowkeyError> {
+        Self::find_coefficients(bytes)
+    }
+
+    fn find_coefficients(bytes: Vec<u8>) -> Result<Self, LowkeyError> {
+        if !is_jpeg(&bytes) {
+            return Err(malformed("Not a JPEG"));
+        }
+
+        let mut frame = None;
+        let mut dc_tables: [Option<Huffman>; 4] = Default::default();
+        let mut ac_tables: [Option<Huffman>; 4] = Default::default();
+        let mut restart_interval = 0;
+        let mut segments = Vec::new();
+        let mut slots = Vec::new();
+
+        let mut pos = SOI.len();
+        loop {
+            if bytes.get(pos) != Some(&0xFF) {
+                return Err(LowkeyError::Format(format!(
+                    "Invalid JPEG marker at byte {}",
+                    pos
+                )));
+            }
+            // Markers may be preceded by any number of fill bytes
+            while bytes.get(pos) == Some(&0xFF) {
+                pos += 1;
+            }
+            let marker = *bytes.get(pos).ok_or_else(|| malformed("Truncated JPEG"))?;
+            pos += 1;
+            match marker {
+                // End of image
+                0xD9 => break,
+                // Markers without a segment
+                0x01 | 0xD0..=0xD7 => continue,
+                _ => {}
+            }
+
+            let length = match bytes.get(pos..pos + 2) {
+                Some(length) => u16::from_be_bytes([length[0], length[1]]) as usize,
+                None => return Err(malformed("Truncated JPEG")),
+            };
+            let data = bytes
+                .get(pos + 2..pos + length)
+                .filter(|_| length >= 2)
+                .ok_or_else(|| malformed("Truncated JPEG"))?;
+            pos += length;
+
+            match marker {
+                0xC0 | 0xC1 => frame = Some(parse_frame(data)?),
+                0xC2..=0xCF if marker != 0xC4 && marker != 0xC8 => {
+                    return Err(malformed(
+                        "Only baseline JPEGs are supported, not progressive, lossless or arithmetic-coded ones",
+                    ));
+                }
+                0xC4 => parse_huffman_tables(data, &mut dc_tables, &mut ac_tables)?,
+                0xDD => {
+                    let interval = data
+                        .get(..2)
+                        .ok_or_else(|| malformed("Invalid JPEG restart interval"))?;
+                    restart_interval = u16::from_be_bytes([interval[0], interval[1]]) as usize;
+                }
+                0xDA => {
+                    let frame = frame
+                        .as_ref()
+                        .ok_or_else(|| malformed("JPEG scan before its frame header"))?;
+                    let scan = parse_scan_header(data, frame, &dc_tables, &ac_tables)?;
+                    let first = segments.len();
+                    pos = read_segments(&bytes, pos, &mut segments)?;
+                    let mut decoder = ScanDecoder {
+                        segments: &segments[first..],
+                        first,
+                        segment: 0,
+                        reader: BitReader::new(&segments[first].data),
+                        slots: &mut slots,
+                    };
+                    decoder.decode(frame, &scan, restart_interval)?;
+                }
+                _ => {}
+            }
+        }
+
+        if frame.is_none() {
+            return Err(malformed("JPEG has no frame header"));
+        }
+        Ok(Self {
+            bytes,
+            segments,
+            slots,
+        })
+    }
+
+    /// The JPEG with the coefficients as they are now. Everything but the
+    /// entropy-coded data is kept byte for byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.bytes.len() + self.bytes.len() / 64);
+        let mut copied = 0;
+        for segment in &self.segments {
+            bytes.extend_from_slice(&self.bytes[copied..segment.start]);
+            for &byte in &segment.data {
+                bytes.push(byte);
+                if byte == 0xFF {
+                    bytes.push(0x00);
+                }
+            }
+            copied = segment.end;
+        }
+        bytes.extend_from_slice(&self.bytes[copied..]);
+        bytes
+    }
+}
+
+impl Carrier for Jpeg {
+    /// Coefficients that can carry a bit each.
+    fn value_count(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Lowest magnitude bit of each coefficient that can carry one, as 0 or
+    /// 1, in file order.
+    fn values(&self) -> impl Iterator<Item = u8> + '_ {
+        self.slots.iter().map(|slot| {
+            let data = &self.segments[slot.segment].data;
+            let bit = (data[slot.bit / 8] >> (7 - slot.bit % 8)) & 1;
+            bit ^ slot.negative as u8
+        })
+    }
+
+    /// Set the lowest magnitude bit of the `index`th coefficient that can
+    /// carry one.
+    fn set_value(&mut self, index: usize, bit: bool) {
+        let slot = &self.slots[index];
+        let data = &mut self.segments[slot.segment].data;
+        let mask = 1 << (7 - slot.bit % 8);
+        if bit ^ slot.negative {
+            data[slot.bit / 8] |= mask;
+        } else {
+            data[slot.bit / 8] &= !mask;
+        }
+    }
+}
+
+/// Error for a JPEG that is damaged or that lowkey can't embed in.
+fn malformed(message: &str) -> LowkeyError {
+    LowkeyError::Format(message.to_string())
+}
+
+fn parse_frame(data: &[u8]) -> Result<Frame, LowkeyError> {
+    let invalid = || malformed("Invalid JPEG frame header");
+    let header = data.get(..6).ok_or_else(invalid)?;
+    if header[0] != 8 {
+        return Err(LowkeyError::Format(format!(
+            "Only 8-bit JPEGs are supported, not {}-bit ones",
+            header[0]
+        )));
+    }
+    let height = u16::from_be_bytes([header[1], header[2]]) as usize;
+    let width = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if width == 0 || height == 0 {
+        return Err(malformed(
+            "JPEGs without their height in the frame header are not supported",
+        ));
+    }
+    let components = data[6..]
+        .chunks_exact(3)
+        .take(header[5] as usize)
+        .map(|component| Component {
+            id: component[0],
+            horizontal: (component[1] >> 4) as usize,
+            vertical: (component[1] & 0x0F) as usize,
+        })
+        .collect::<Vec<_>>();
+    if components.is_empty()
+        || components.len() != header[5] as usize
+        || components
+            .iter()
+            .any(|c| !(1..=4).contains(&c.horizontal) || !(1..=4).contains(&c.vertical))
+    {
+        return Err(invalid());
+    }
+    Ok(Frame {
+        width,
+        height,
+        components,
+    })
+}
+
+fn parse_huffman_tables(
+    mut data: &[u8],
+    dc_tables: &mut [Option<Huffman>; 4],
+    ac_tables: &mut [Option<Huffman>; 4],
+) -> Result<(), LowkeyError> {
+    let invalid = || malformed("Invalid JPEG Huffman table");
+    while let Some((&class_id, rest)) = data.split_first() {
+        let counts: [u8; 16] = rest
+            .get(..16)
+            .ok_or_else(invalid)?
+            .try_into()
+            .expect("16 bytes");
+        let total: usize = counts.iter().map(|&count| count as usize).sum();
+        let values = rest.get(16..16 + total).ok_or_else(invalid)?.to_vec();
+        let (class, id) = (class_id >> 4, (class_id & 0x0F) as usize);
+        let tables = match class {
+            0 => &mut *dc_tables,
+            1 => &mut *ac_tables,
+            _ => return Err(invalid()),
+        };
+        *tables.get_mut(id).ok_or_else(invalid)? = Some(Huffman::new(&counts, values));
+        data = &rest[16 + total..];
+    }
+    Ok(())
+}
+
+/// Components of a scan, as indices into the frame's, with their tables.
+struct Scan<'a> {
+    components: Vec<(usize, &'a Huffman, &'a Huffman)>,
+}
+
+fn parse_scan_header<'a>(
+    data: &[u8],
+    frame: &Frame,
+    dc_tables: &'a [Option<Huffman>; 4],
+    ac_tables: &'a [Option<Huffman>; 4],
+) -> Result<Scan<'a>, LowkeyError> {
+    let invalid = || malformed("Invalid JPEG scan header");
+    let count = *data.first().ok_or_else(invalid)? as usize;
+    let selectors = data.get(1..1 + count * 2).ok_or_else(invalid)?;
+    let components = selectors
+        .chunks_exact(2)
+        .map(|selector| {
+            let index = frame
+                .components
+                .iter()
+                .position(|component| component.id == selector[0])
+                .ok_or_else(invalid)?;
+            let table = |tables: &'a [Option<Huffman>; 4], id: u8| {
+                tables
+                    .get(id as usize)
+                    .and_then(Option::as_ref)
+                    .ok_or_else(|| malformed("JPEG scan uses an undefined Huffman table"))
+            };
+            Ok((
+                index,
+                table(dc_tables, selector[1] >> 4)?,
+                table(ac_tables, selector[1] & 0x0F)?,
+            ))
+        })
+        .collect::<Result<Vec<_>, LowkeyError>>()?;
+    // Spectral selection and successive approximation are only for
+    // progressive JPEGs
+    if count == 0 || data.get(1 + count * 2..) != Some(&[0, 63, 0][..]) {
+        return Err(invalid());
+    }
+    Ok(Scan { components })
+}
+
+/// Read the entropy-coded data from `pos` up to the marker after it, as one
+/// segment per restart interval, and return where that marker is.
+fn read_segments(
+    bytes: &[u8],
+    mut pos: usize,
+    segments: &mut Vec<Segment>,
+) -> Result<usize, LowkeyError> {
+    let mut start = pos;
+    let mut data = Vec::new();
+    loop {
+        let byte = *bytes
+            .get(pos)
+            .ok_or_else(|| malformed("Truncated JPEG scan"))?;
+        if byte != 0xFF {
+            data.push(byte);
+            pos += 1;
+            continue;
+        }
+
+        let mut next = pos + 1;
+        while bytes.get(next) == Some(&0xFF) {
+            next += 1;
+        }
+        match bytes.get(next) {
+            // A stuffed 0xFF data byte
+            Some(0x00) if next == pos + 1 => {
+                data.push(0xFF);
+                pos += 2;
+            }
+            Some(0xD0..=0xD7) => {
+                segments.push(Segment {
+                    start,
+                    end: pos,
+                    data: std::mem::take(&mut data),
+                });
+                pos = next + 1;
+                start = pos;
+            }
+            Some(_) => {
+                segments.push(Segment {
+                    start,
+                    end: pos,
+                    data,
+                });
+                return Ok(pos);
+            }
+            None => return Err(malformed("Truncated JPEG scan")),
+        }
+    }
+}
+
+/// Canonical Huffman code of a JPEG table (see ITU T.81, F.2.2.3).
+struct Huffman {
+    /// Largest code of each length, or -1 for none
+    max_code: [i32; 17],
+    min_code: [i32; 17],
+    /// Index in `values` of the first symbol of each length
+    first_value: [usize; 17],
+    values: Vec<u8>,
+}
+
+impl Huffman {
+    fn new(counts: &[u8; 16], values: Vec<u8>) -> Self {
+        let mut table = Huffman {
+            max_code: [-1; 17],
+            min_code: [0; 17],
+            first_value: [0; 17],
+            values,
+        };
+        let (mut code, mut index) = (0i32, 0usize);
+        for length in 1..=16 {
+            let count = counts[length - 1] as usize;
+            table.first_value[length] = index;
+            table.min_code[length] = code;
+            if count > 0 {
+                code += count as i32;
+                index += count;
+                table.max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, LowkeyError> {
+        let mut code = 0;
+        for length in 1..=16 {
+            code = (code << 1) | reader.bit()? as i32;
+            if code <= self.max_code[length] {
+                let index = self.first_value[length] + (code - self.min_code[length]) as usize;
+                return Ok(self.values[index]);
+            }
+        }
+        Err(malformed("Invalid Huffman code in JPEG scan"))
+    }
+}
+
+/// Reads entropy-coded data a bit at a time, most significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Result<u8, LowkeyError> {
+        let byte = self
+            .data
+            .get(self.pos / 8)
+            .ok_or_else(|| malformed("Truncated JPEG scan"))?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), LowkeyError> {
+        if self.pos + count > self.data.len() * 8 {
+            return Err(malformed("Truncated JPEG scan"));
+        }
+        self.pos += count;
+        Ok(())
+    }
+}
+
+/// Walks the blocks of a scan and notes the coefficients that can carry a bit.
+struct ScanDecoder<'a, 'b> {
+    segments: &'a [Segment],
+    /// Index of the scan's first segment among those of the file
+    first: usize,
+    segment: usize,
+    reader: BitReader<'a>,
+    slots: &'b mut Vec<Slot>,
+}
+
+impl ScanDecoder<'_, '_> {
+    fn decode(
+        &mut self,
+        frame: &Frame,
+        scan: &Scan,
+        restart_interval: usize,
+    ) -> Result<(), LowkeyError> {
+        let max_horizontal = frame
+            .components
+            .iter()
+            .map(|c| c.horizontal)
+            .max()
+            .unwrap_or(1);
+        let max_vertical = frame
+            .components
+            .iter()
+            .map(|c| c.vertical)
+            .max()
+            .unwrap_or(1);
+
+        // An interleaved scan has each component's sampling factors' worth
+        // of blocks per MCU; a scan of one component has one block per MCU
+        let (mcus, blocks_per_mcu): (usize, Vec<usize>) = if scan.components.len() == 1 {
+            let component = &frame.components[scan.components[0].0];
+            let width = (frame.width * component.horizontal).div_ceil(max_horizontal);
+            let height = (frame.height * component.vertical).div_ceil(max_vertical);
+            (width.div_ceil(8) * height.div_ceil(8), vec![1])
+        } else {
+            let columns = frame.width.div_ceil(8 * max_horizontal);
+            let rows = frame.height.div_ceil(8 * max_vertical);
+            let blocks = scan
+                .components
+                .iter()
+                .map(|&(index, _, _)| {
+                    let component = &frame.components[index];
+                    component.horizontal * component.vertical
+                })
+                .collect();
+            (columns * rows, blocks)
+        };
+
+        for mcu in 0..mcus {
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                self.segment += 1;
+                let segment = self
+                    .segments
+                    .get(self.segment)
+                    .ok_or_else(|| malformed("JPEG scan is missing a restart marker"))?;
+                self.reader = BitReader::new(&segment.data);
+            }
+            for (&(_, dc, ac), &blocks) in scan.components.iter().zip(&blocks_per_mcu) {
+                for _ in 0..blocks {
+                    self.decode_block(dc, ac)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_block(&mut self, dc: &Huffman, ac: &Huffman) -> Result<(), LowkeyError> {
+        let size = dc.decode(&mut self.reader)?;
+        self.reader.skip(size as usize)?;
+
+        let mut k = 1;
+        while k < BLOCK_COEFFICIENTS {
+            let symbol = ac.decode(&mut self.reader)?;
+            let (run, size) = ((symbol >> 4) as usize, (symbol & 0x0F) as usize);
+            if size == 0 {
+                // Sixteen zeros, or the end of the block
+                if run == 15 {
+                    k += 16;
+                    continue;
+                }
+                break;
+            }
+            k += run;
+            if k >= BLOCK_COEFFICIENTS {
+                return Err(malformed("Corrupt JPEG block"));
+            }
+            let start = self.reader.pos;
+            self.reader.skip(size)?;
+            if size >= 2 {
+                let data = self.reader.data;
+                self.slots.push(Slot {
+                    segment: self.first + self.segment,
+                    bit: start + size - 1,
+                    // A leading 0 makes the coefficient negative
+                    negative: (data[start / 8] >> (7 - start % 8)) & 1 == 0,
+                });
+            }
+            k += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{DynamicImage, RgbImage};
+
+    fn encode_jpeg(img: &RgbImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        JpegEncoder::new_with_quality(&mut bytes, 90)
+            .encode_image(&DynamicImage::ImageRgb8(img.clone()))
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let img = RgbImage::from_fn(50, 37, |x, y| {
+            image::Rgb([(x * y) as u8, (x * 7 + y * 3) as u8, ((x ^ y) * 5) as u8])
+        });
+        let bytes = encode_jpeg(&img);
+
+        let mut jpeg = Jpeg::parse(bytes.clone()).unwrap();
+        assert_eq!(
+            jpeg.to_bytes(),
+            bytes,
+            "unchanged coefficients, unchanged file"
+        );
+        let count = jpeg.value_count();
+        assert!(count > 1000, "{} coefficients", count);
+
+        let bits: Vec<bool> = (0..count).map(|i| (i * 7 + i / 3) % 5 < 2).collect();
+        for (i, &bit) in bits.iter().enumerate() {
+            jpeg.set_value(i, bit);
+        }
+        let stego = jpeg.to_bytes();
+        let read = Jpeg::parse(stego.clone()).unwrap();
+        assert_eq!(read.value_count(), count);
+        assert!(
+            read.values()
+                .zip(&bits)
+                .all(|(value, &bit)| value == bit as u8)
+        );
+
+        // Still a JPEG that decodes, and looks the same
+        let decoded = image::load_from_memory(&stego).unwrap().to_rgb8();
+        let original = image::load_from_memory(&bytes).unwrap().to_rgb8();
+        let difference: u64 = decoded
+            .as_raw()
+            .iter()
+            .zip(original.as_raw())
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum();
+        assert_eq!(decoded.dimensions(), (50, 37));
+        assert!(difference < decoded.as_raw().len() as u64 * 4);
+    }
+
+    #[test]
+    fn test_restart_markers() {
+        // 16x8 greyscale, two blocks with a restart marker between them,
+        // each with one AC coefficient of 3: a DC symbol of size 0 (0), the
+        // AC symbol of size 2 (01) with 11, and the end of block (00),
+        // padded with ones
+        let mut bytes = vec![0xFF, 0xD8, 0xFF, 0xDB, 0x00, 0x43, 0x00];
+        bytes.extend([1; 64]);
+        bytes.extend([
+            0xFF, 0xC0, 0x00, 0x0B, 0x08, 0x00, 0x08, 0x00, 0x10, 0x01, 0x01, 0x11, 0x00,
+        ]);
+        bytes.extend([0xFF, 0xC4, 0x00, 0x27, 0x00, 0x01]);
+        bytes.extend([0; 15]);
+        bytes.extend([0x00, 0x10, 0x00, 0x02]);
+        bytes.extend([0; 14]);
+        bytes.extend([0x00, 0x02]);
+        bytes.extend([0xFF, 0xDD, 0x00, 0x04, 0x00, 0x01]);
+        bytes.extend([0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00]);
+        bytes.extend([0x39, 0xFF, 0xD0, 0x39, 0xFF, 0xD9]);
+        assert!(image::load_from_memory(&bytes).is_ok());
+
+        let mut jpeg = Jpeg::parse(bytes.clone()).unwrap();
+        assert_eq!(jpeg.values().collect::<Vec<_>>(), vec![1, 1]);
+        jpeg.set_value(1, false);
+        let stego = jpeg.to_bytes();
+        let len = stego.len();
+        assert_eq!(stego[..len - 6], bytes[..len - 6]);
+        assert_eq!(stego[len - 6..], [0x39, 0xFF, 0xD0, 0x31, 0xFF, 0xD9]);
+        assert_eq!(
+            Jpeg::parse(stego).unwrap().values().collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+
+        // Without the restart marker, the second block is missing
+        let mut truncated = bytes.clone();
+        truncated.drain(truncated.len() - 5..truncated.len() - 2);
+        assert!(Jpeg::parse(truncated).is_err());
+        assert!(Jpeg::parse(b"not a jpeg".to_vec()).is_err());
+    }
+}
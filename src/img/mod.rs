@@ -1,5 +1,11 @@
+pub mod analysis;
 pub mod codec;
 pub mod common;
+pub mod generate;
 pub mod io;
+pub mod jpeg;
+pub mod naming;
 pub mod pixel;
+pub mod pool;
+pub mod region;
 pub mod resize;
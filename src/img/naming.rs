@@ -0,0 +1,228 @@
+// File names for the outputs of a multi-image encode, from a template such
+// as "{stem}_{index:03}.png". The extension of the rendered name picks the
+// output format, as with --output.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::LowkeyError;
+
+/// What an output is named after
+pub struct NameVars<'a> {
+    /// Path of the cover it is made from
+    pub cover: &'a str,
+    /// Position in the set, from 1
+    pub index: usize,
+    /// Number of images in the set
+    pub total: usize,
+    /// ID shared by the images of the set
+    pub set_id: &'a [u8; 16],
+}
+
+/// A parsed `--output-template`. Placeholders are `{stem}` (the cover's file
+/// name without its extension), `{index}` and `{total}` (which take a width,
+/// e.g. `{index:03}`), `{date}` (today in UTC, as YYYY-MM-DD) and `{set}` (the
+/// set ID in hex); `{{` and `}}` stand for braces.
+#[derive(Clone, Debug)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+    date: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Stem,
+    Index { width: usize, zero: bool },
+    Total { width: usize, zero: bool },
+    Date,
+    Set,
+}
+
+impl std::str::FromStr for OutputTemplate {
+    type Err = LowkeyError;
+
+    fn from_str(template: &str) -> Result<Self, LowkeyError> {
+        let invalid = |reason: &str| {
+            LowkeyError::InvalidInput(format!(
+                "Invalid output template '{}': {}",
+                template, reason
+            ))
+        };
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(invalid("unmatched '{'")),
+                        }
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(parse_placeholder(&placeholder).map_err(|e| invalid(&e))?);
+                }
+                '}' => return Err(invalid("unmatched '}'")),
+                '/' | '\\' | '\0' => {
+                    return Err(invalid("outputs are named, not placed in folders"));
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        if !parts
+            .iter()
+            .any(|part| matches!(part, Part::Stem | Part::Index { .. }))
+        {
+            return Err(invalid(
+                "it needs {stem} or {index} to tell the outputs apart",
+            ));
+        }
+
+        Ok(Self {
+            parts,
+            date: today(),
+        })
+    }
+}
+
+fn parse_placeholder(placeholder: &str) -> Result<Part, String> {
+    let (name, spec) = match placeholder.split_once(':') {
+        Some((name, spec)) => (name, Some(spec)),
+        None => (placeholder, None),
+    };
+    let width = |spec: &str| {
+        spec.parse::<usize>()
+            .ok()
+            .filter(|&width| width <= 20)
+            .map(|width| (width, spec.starts_with('0')))
+            .ok_or_else(|| format!("invalid width '{}' in {{{}}}", spec, placeholder))
+    };
+    match (name, spec) {
+        ("stem", None) => Ok(Part::Stem),
+        ("date", None) => Ok(Part::Date),
+        ("set", None) => Ok(Part::Set),
+        ("index", None) => Ok(Part::Index {
+            width: 0,
+            zero: false,
+        }),
+        ("total", None) => Ok(Part::Total {
+            width: 0,
+            zero: false,
+        }),
+        ("index", Some(spec)) => width(spec).map(|(width, zero)| Part::Index { width, zero }),
+        ("total", Some(spec)) => width(spec).map(|(width, zero)| Part::Total { width, zero }),
+        _ => Err(format!(
+            "unknown placeholder {{{}}} (expected stem, index, total, date or set)",
+            placeholder
+        )),
+    }
+}
+
+impl OutputTemplate {
+    /// The file name of the output described by `vars`.
+    pub fn render(&self, vars: &NameVars) -> String {
+        let number = |n: usize, width: usize, zero: bool| match zero {
+            true => format!("{:0width$}", n, width = width),
+            false => format!("{:width$}", n, width = width),
+        };
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Stem => std::path::Path::new(vars.cover)
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                Part::Index { width, zero } => number(vars.index, *width, *zero),
+                Part::Total { width, zero } => number(vars.total, *width, *zero),
+                Part::Date => self.date.clone(),
+                Part::Set => vars.set_id.iter().map(|b| format!("{:02x}", b)).collect(),
+            })
+            .collect()
+    }
+}
+
+/// Today's date in UTC as YYYY-MM-DD.
+fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_date((secs / 86400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Year, month and day of the day `days` after 1970-01-01 (Howard Hinnant's
+/// algorithm).
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let template: OutputTemplate = "{stem}_{index:03}-of-{total}_{{{set}}}.png"
+            .parse()
+            .unwrap();
+        let vars = NameVars {
+            cover: "photos/beach.jpg",
+            index: 7,
+            total: 12,
+            set_id: &[0xab; 16],
+        };
+        assert_eq!(
+            template.render(&vars),
+            format!("beach_007-of-12_{{{}}}.png", "ab".repeat(16))
+        );
+
+        let dated: OutputTemplate = "IMG_{date}_{index:2}.png".parse().unwrap();
+        assert!(dated.render(&vars).starts_with("IMG_20"));
+        assert!(dated.render(&vars).ends_with("_ 7.png"));
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for template in [
+            "out.png",
+            "{date}.png",
+            "{name}_{index}.png",
+            "{index:x}.png",
+            "{index}}.png",
+            "{index.png",
+            "dir/{index}.png",
+        ] {
+            assert!(template.parse::<OutputTemplate>().is_err(), "{}", template);
+        }
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(19_723), (2024, 1, 1));
+    }
+}
@@ -1,39 +1,1153 @@
+use bitvec::field::BitField;
 use bitvec::prelude::{BitSlice, BitVec, Lsb0};
 use image::RgbaImage;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
-use super::common::check_capacity_image;
+use super::common::{check_capacity_image, check_capacity_image_at, check_capacity_positions};
+use crate::crypto;
+use crate::error::LowkeyError;
+use crate::progress::Progress;
 
-pub fn set_bits_image(img: &mut RgbaImage, bits: &BitSlice<u8, Lsb0>) -> Result<(), String> {
-    check_capacity_image(img, bits)?;
+/// Weight of channel values without any local noise, relative to a noise
+/// level of 1. They are used last, once textured areas are full.
+const FLAT_WEIGHT: f32 = 0.01;
+
+/// Texture, in steps of the lowest bit above the embedded ones, from which
+/// adaptive embedding takes a value (see `textured_values`)
+const TEXTURED_LEVEL: i32 = 2;
+
+/// Most low bits of a channel value that can carry message bits
+pub const MAX_DEPTH: u8 = 4;
+
+/// Alpha below which a pixel counts as transparent, where transparent
+/// pixels are skipped. Nearly transparent ones count too, so that embedding
+/// in the low bits of alpha never turns a pixel from one into the other.
+pub const TRANSPARENT_ALPHA: u8 = 1 << MAX_DEPTH;
+
+/// Values embedded between progress updates
+const PROGRESS_STEP: usize = 1 << 16;
+
+/// Values filled with noise per batch of random bytes
+const NOISE_STEP: usize = 1 << 16;
+
+/// Values `LazyImagesReader` copies out of an image at a time
+const READ_BATCH: usize = 1 << 12;
+
+// Keeps the scatter order independent of the noise-matched sampling, which
+// is drawn from the same seed.
+const SCATTER_STREAM: u64 = 0x5ca7_7e25_0000_0000;
+
+// Keeps the region record apart from the message scattered in the same image
+const REGION_STREAM: u64 = 0x7e61_0a00_0000_0000;
+
+/// Steps of the shuffle drawn with the swapped values kept in a map, before
+/// the whole order is laid out; reading a header takes far fewer
+const SPARSE_SHUFFLE_STEPS: usize = 1 << 12;
+
+const CHANNEL_LETTERS: [char; 4] = ['r', 'g', 'b', 'a'];
+
+/// Set of RGBA channels that carry message bits, as a bit mask with red in
+/// the lowest bit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Channels(u8);
+
+impl Channels {
+    pub const ALL: Channels = Channels(0b1111);
+    pub const RGB: Channels = Channels(0b0111);
+
+    pub fn from_mask(mask: u8) -> Result<Self, LowkeyError> {
+        if mask == 0 || mask > Self::ALL.0 {
+            return Err(LowkeyError::InvalidInput(format!(
+                "Invalid channel mask {:#06b}",
+                mask
+            )));
+        }
+        Ok(Channels(mask))
+    }
+
+    pub fn mask(self) -> u8 {
+        self.0
+    }
+
+    /// Whether the channel of the value at `index` of an RGBA buffer is in the set
+    pub fn contains(self, index: usize) -> bool {
+        self.0 & (1 << (index % 4)) != 0
+    }
+
+    pub fn count(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Index in an RGBA buffer of the `n`th value of the set, from zero
+    pub fn index_of(self, n: usize) -> usize {
+        let channel = (0..4)
+            .filter(|&i| self.contains(i))
+            .nth(n % self.count())
+            .unwrap_or(0);
+        n / self.count() * 4 + channel
+    }
+
+    /// Every set, all four channels first
+    pub fn candidates() -> impl Iterator<Item = Channels> {
+        (1..=Self::ALL.0).rev().map(Channels)
+    }
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl fmt::Display for Channels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, letter) in CHANNEL_LETTERS.iter().enumerate() {
+            if self.contains(i) {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse letters of "rgba" in any order, e.g. "rgb" or "b".
+impl FromStr for Channels {
+    type Err = LowkeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mask = 0u8;
+        for c in s.to_lowercase().chars() {
+            let bit = CHANNEL_LETTERS
+                .iter()
+                .position(|&letter| letter == c)
+                .ok_or_else(|| {
+                    LowkeyError::InvalidInput(format!(
+                        "Invalid channels '{}' (use letters of 'rgba', e.g. 'rgb' or 'b')",
+                        s
+                    ))
+                })?;
+            mask |= 1 << bit;
+        }
+        Channels::from_mask(mask).map_err(|_| {
+            LowkeyError::InvalidInput(
+                "Channels cannot be empty (use letters of 'rgba')".to_string(),
+            )
+        })
+    }
+}
+
+/// How a value is changed to carry message bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Method {
+    /// Overwrite the low bits
+    #[default]
+    LsbReplace,
+    /// Move the value up or down to the nearest one with the low bits
+    /// wanted (see `match_changes`)
+    LsbMatch,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Method::LsbReplace => "lsb-replace",
+            Method::LsbMatch => "lsb-match",
+        })
+    }
+}
+
+impl FromStr for Method {
+    type Err = LowkeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lsb-replace" => Ok(Method::LsbReplace),
+            "lsb-match" => Ok(Method::LsbMatch),
+            _ => Err(LowkeyError::InvalidInput(format!(
+                "Unknown embedding method '{}' (expected lsb-replace or lsb-match)",
+                s
+            ))),
+        }
+    }
+}
+
+pub fn set_bits_image(img: &mut RgbaImage, bits: &BitSlice<u8, Lsb0>) -> Result<(), LowkeyError> {
+    set_bits_image_at(img, bits, 0, 1, Channels::ALL)
+}
+
+/// Embed `bits` in the values of `channels` from the `start`th on, in the
+/// `depth` low bits of each value, least significant first.
+pub fn set_bits_image_at(
+    img: &mut RgbaImage,
+    bits: &BitSlice<u8, Lsb0>,
+    start: usize,
+    depth: u8,
+    channels: Channels,
+) -> Result<(), LowkeyError> {
+    check_capacity_image_at(img, start, bits, depth, channels)?;
+    write_values(img.as_mut(), bits, start, depth, channels);
 
-    let mut iter = img.iter_mut();
-    for bit in bits {
-        if let Some(channel) = iter.next() {
-            *channel = (*channel & 0xFE) | (*bit as u8);
+    Ok(())
+}
+
+/// Same as `set_bits_image_at`, counting the values written in `progress`
+/// as it goes.
+pub fn set_bits_image_at_tracked(
+    img: &mut RgbaImage,
+    bits: &BitSlice<u8, Lsb0>,
+    start: usize,
+    depth: u8,
+    channels: Channels,
+    progress: &Progress,
+) -> Result<(), LowkeyError> {
+    check_capacity_image_at(img, start, bits, depth, channels)?;
+
+    let mut start = start;
+    for step in bits.chunks(PROGRESS_STEP * depth as usize) {
+        write_values(img.as_mut(), step, start, depth, channels);
+        start += PROGRESS_STEP;
+        progress.advance(step.len().div_ceil(depth as usize));
+    }
+
+    Ok(())
+}
+
+/// Embed `bits` at the values at `positions`, in the `depth` low bits of
+/// each value. The caller checks that there are enough positions.
+pub fn set_bits_positions(
+    img: &mut RgbaImage,
+    positions: impl IntoIterator<Item = usize>,
+    bits: &BitSlice<u8, Lsb0>,
+    depth: u8,
+) {
+    let values = img.as_mut();
+    for (position, chunk) in positions.into_iter().zip(bits.chunks(depth as usize)) {
+        write_chunks(std::iter::once(&mut values[position]), chunk, depth);
+    }
+}
+
+/// Set the `depth` low bits of the values at `positions` at random, so that
+/// the values a message left unused look like the ones it took.
+pub fn fill_noise_positions(
+    img: &mut RgbaImage,
+    positions: impl IntoIterator<Item = usize>,
+    depth: u8,
+) {
+    let mask = (1u8 << depth) - 1;
+    let values = img.as_mut();
+    let mut positions = positions.into_iter().peekable();
+    while positions.peek().is_some() {
+        // Noise first, so that no position is taken past the last byte
+        for (noise, position) in crypto::random_bytes(NOISE_STEP)
+            .into_iter()
+            .zip(positions.by_ref())
+        {
+            values[position] = (values[position] & !mask) | (noise & mask);
+        }
+    }
+}
+
+/// `fill_noise_positions` for the values of `channels` from the `start`th on.
+///
+/// Channels whose values differ in the low `depth` bits only, such as the
+/// alpha of an opaque cover, are left alone: noise there would stand out
+/// against the one value the cover has.
+pub fn fill_noise_at(img: &mut RgbaImage, start: usize, depth: u8, channels: Channels) {
+    let flat = flat_channels(img, depth);
+    let len = img.len();
+    let positions = (0..len)
+        .filter(move |&i| channels.contains(i))
+        .skip(start)
+        .filter(|&i| flat[i % 4].is_none());
+    fill_noise_positions(img, positions, depth);
+}
+
+/// For each channel whose values only differ in the `planes` low bits, its
+/// highest value; `None` for the others.
+fn flat_channels(img: &RgbaImage, planes: u8) -> [Option<u8>; 4] {
+    let mask = !((1u8 << planes) - 1);
+    std::array::from_fn(|channel| {
+        let mut values = img.as_raw().iter().skip(channel).step_by(4);
+        let first = *values.next()?;
+        values.try_fold(first, |highest, &value| {
+            (value & mask == first & mask).then_some(highest.max(value))
+        })
+    })
+}
+
+/// Replace the `planes` low bits of every value with noise, or with zeros,
+/// so that nothing embedded there is left. A channel whose values differ in
+/// those bits only, such as the alpha of an opaque cover, is set to its
+/// highest value everywhere instead, which carries nothing either.
+pub fn wipe_low_bits(img: &mut RgbaImage, planes: u8, zero: bool) {
+    let mask = !((1u8 << planes) - 1);
+    let flat = flat_channels(img, planes);
+
+    if !zero {
+        let positions = (0..img.len()).filter(|&i| flat[i % 4].is_none());
+        fill_noise_positions(img, positions, planes);
+    }
+    for (i, value) in img.as_mut().iter_mut().enumerate() {
+        match flat[i % 4] {
+            Some(highest) => *value = highest,
+            None if zero => *value &= mask,
+            None => {}
+        }
+    }
+}
+
+/// Turn the low bits `img` replaced in `cover` into LSB matching (±1
+/// embedding at one bit per value). Replacing only ever moves a value
+/// within its pair, 2k and 2k + 1, which evens out the pair counts that the
+/// chi-square attack looks for. Each changed value keeps its new `depth` low
+/// bits but takes whichever value with those bits is nearest the cover's,
+/// up or down at random when both are as near. Alpha is not moved across
+/// `TRANSPARENT_ALPHA`, which would change the pixels that are skipped.
+pub fn match_changes(cover: &RgbaImage, img: &mut RgbaImage, depth: u8) {
+    let step = 1i16 << depth;
+    let changed: Vec<usize> = cover
+        .iter()
+        .zip(img.iter())
+        .enumerate()
+        .filter(|(_, (from, to))| from != to)
+        .map(|(i, _)| i)
+        .collect();
+    let coins = crypto::random_bytes(changed.len().div_ceil(8));
+    let values = img.as_mut();
+    for (n, &i) in changed.iter().enumerate() {
+        let (from, to) = (cover.as_raw()[i] as i16, values[i] as i16);
+        // The other value with the same low bits on the cover's side
+        let other = if to > from { to - step } else { to + step };
+        let coin = (coins[n / 8] >> (n % 8)) & 1 == 1;
+        let (near, far) = ((other - from).abs(), (to - from).abs());
+        let threshold = TRANSPARENT_ALPHA as i16;
+        let same_side = i % 4 != 3 || (other < threshold) == (from < threshold);
+        if (0..=255).contains(&other) && same_side && (near < far || (near == far && coin)) {
+            values[i] = other as u8;
         }
     }
+}
+
+/// Indices of the pixels of `img` that are not transparent (see
+/// `TRANSPARENT_ALPHA`), which is the same before and after embedding.
+pub fn opaque_pixels(img: &RgbaImage) -> Vec<usize> {
+    img.pixels()
+        .enumerate()
+        .filter(|(_, pixel)| pixel[3] >= TRANSPARENT_ALPHA)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// The pixels of `img` at `pixels`, in one row, for a message to be
+/// embedded in or read from as if they were the whole image.
+pub fn take_pixels(img: &RgbaImage, pixels: &[usize]) -> RgbaImage {
+    let values = img.as_raw();
+    let row = pixels
+        .iter()
+        .flat_map(|&pixel| values[pixel * 4..pixel * 4 + 4].iter().copied())
+        .collect();
+    RgbaImage::from_raw(pixels.len() as u32, 1, row).unwrap_or_default()
+}
+
+/// Put the pixels of `row`, from `take_pixels`, back at `pixels` of `img`.
+pub fn put_pixels(img: &mut RgbaImage, pixels: &[usize], row: &RgbaImage) {
+    let values = img.as_mut();
+    for (&pixel, taken) in pixels.iter().zip(row.as_raw().chunks_exact(4)) {
+        values[pixel * 4..pixel * 4 + 4].copy_from_slice(taken);
+    }
+}
+
+/// Embed `bits` in the values of `channels` of the RGBA buffer `values`,
+/// from the `start`th value of the set on, `depth` bits per value.
+///
+/// The bits are taken a 64-bit word at a time and spread over the low bits
+/// of as many values as the word fills, which are then written in one pass,
+/// rather than a bit and a value at a time through iterators. With all four
+/// channels that pass runs over contiguous bytes and vectorizes, so large
+/// images embed at about the speed of memory.
+fn write_values(
+    values: &mut [u8],
+    bits: &BitSlice<u8, Lsb0>,
+    start: usize,
+    depth: u8,
+    channels: Channels,
+) {
+    let mask = (1u8 << depth) - 1;
+    let lanes: Vec<usize> = (0..4).filter(|&i| channels.contains(i)).collect();
+    let whole = bits.len() / depth as usize;
+    let (body, tail) = bits.split_at(whole * depth as usize);
+
+    let mut digits = [0u8; 64];
+    let (mut pixel, mut lane) = (start / lanes.len() * 4, start % lanes.len());
+    for word in body.chunks(values_per_word(depth) * depth as usize) {
+        let digits = spread_word(word, depth, &mut digits);
+        if channels == Channels::ALL {
+            let values = &mut values[pixel + lane..pixel + lane + digits.len()];
+            for (value, &digit) in values.iter_mut().zip(digits) {
+                *value = (*value & !mask) | digit;
+            }
+            pixel += (lane + digits.len()) / 4 * 4;
+            lane = (lane + digits.len()) % 4;
+        } else {
+            for &digit in digits {
+                let value = &mut values[pixel + lanes[lane]];
+                *value = (*value & !mask) | digit;
+                lane += 1;
+                if lane == lanes.len() {
+                    (pixel, lane) = (pixel + 4, 0);
+                }
+            }
+        }
+    }
+    // A last value short of `depth` bits keeps its others
+    if !tail.is_empty() {
+        let value = &mut values[channels.index_of(start + whole)];
+        write_chunks(std::iter::once(value), tail, depth);
+    }
+}
+
+/// Values whose `depth` low bits fill a 64-bit word, or nearly
+fn values_per_word(depth: u8) -> usize {
+    64 / depth as usize
+}
+
+/// The `depth`-bit values that make up `word`, up to 64 bits of a message,
+/// least significant first, in `digits`.
+fn spread_word<'a>(word: &BitSlice<u8, Lsb0>, depth: u8, digits: &'a mut [u8; 64]) -> &'a [u8] {
+    let mask = (1u8 << depth) - 1;
+    let bits: u64 = word.load_le();
+    let digits = &mut digits[..word.len() / depth as usize];
+    for (i, digit) in digits.iter_mut().enumerate() {
+        *digit = (bits >> (i * depth as usize)) as u8 & mask;
+    }
+    digits
+}
+
+fn write_chunks<'a>(
+    values: impl Iterator<Item = &'a mut u8>,
+    bits: &BitSlice<u8, Lsb0>,
+    depth: u8,
+) {
+    // Bits first, so that no value is taken past the last chunk
+    for (chunk, channel) in bits.chunks(depth as usize).zip(values) {
+        let mask = ((1u16 << chunk.len()) - 1) as u8;
+        let value = chunk
+            .iter()
+            .enumerate()
+            .fold(0u8, |acc, (i, bit)| acc | ((*bit as u8) << i));
+        *channel = (*channel & !mask) | value;
+    }
+}
+
+/// Embed `bits` at the channel values chosen by `noise_matched_positions`.
+pub fn set_bits_image_noise_matched(
+    img: &mut RgbaImage,
+    bits: &BitSlice<u8, Lsb0>,
+    key: &str,
+) -> Result<(), LowkeyError> {
+    check_capacity_image(img, bits)?;
+
+    let positions = noise_matched_positions(img, key, bits.len());
+    for (position, bit) in positions.into_iter().zip(bits) {
+        let channel = &mut img.as_mut()[position];
+        *channel = (*channel & 0xFE) | (*bit as u8);
+    }
 
     Ok(())
 }
 
-pub fn get_bits_reader_images<'a>(imgs: &'a [RgbaImage]) -> impl Iterator<Item = &'a u8> + 'a {
-    imgs.iter().flat_map(|img| img.iter())
+/// The first `count` channel values, in the order message bits are placed
+/// in them when matching the image's noise.
+///
+/// Rather than filling the image from the start, values are picked across
+/// the whole image by keyed weighted sampling, more often where the image is
+/// noisy. Noisy areas already have random-looking LSBs, while smooth areas
+/// and flat channels (like an opaque alpha channel) have structured ones
+/// that random bits would stand out against. Noise is measured on the upper
+/// seven bits only, so the order is the same before and after embedding.
+pub fn noise_matched_positions(img: &RgbaImage, key: &str, count: usize) -> Vec<usize> {
+    let seed = crypto::layout_seed(key);
+
+    // Weighted sampling without replacement (Efraimidis & Spirakis): the
+    // values with the smallest -ln(r) / weight win.
+    let mut priorities: Vec<(f32, u32)> = (0..img.len())
+        .map(|i| {
+            let weight = texture(img, i, 1) as f32 + FLAT_WEIGHT;
+            let r = (splitmix64(seed ^ i as u64) >> 40) as f32 / (1u64 << 24) as f32;
+            (-(1.0 - r).ln() / weight, i as u32)
+        })
+        .collect();
+
+    let by_priority = |a: &(f32, u32), b: &(f32, u32)| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1));
+    if count < priorities.len() {
+        priorities.select_nth_unstable_by(count, by_priority);
+        priorities.truncate(count);
+    }
+    priorities.sort_unstable_by(by_priority);
+
+    priorities.into_iter().map(|(_, i)| i as usize).collect()
+}
+
+/// Local texture at the value at `index` of `img`: how far it and its four
+/// neighbours in the same channel are from a straight line, with the `shift`
+/// low bits left out. Smooth gradients and flat areas come out as zero.
+fn texture(img: &RgbaImage, index: usize, shift: u8) -> i32 {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let values = img.as_raw();
+    let upper = |x: usize, y: usize, channel: usize| {
+        (values[(y * width + x) * 4 + channel] >> shift) as i32
+    };
+
+    let (pixel, channel) = (index / 4, index % 4);
+    let (x, y) = (pixel % width, pixel / width);
+    let center = 2 * upper(x, y, channel);
+    let horizontal =
+        center - upper(x.saturating_sub(1), y, channel) - upper((x + 1).min(width - 1), y, channel);
+    let vertical = center
+        - upper(x, y.saturating_sub(1), channel)
+        - upper(x, (y + 1).min(height - 1), channel);
+    horizontal.abs() + vertical.abs()
+}
+
+/// Reader over the LSBs of the first `count` noise-matched positions.
+pub fn get_bits_reader_noise_matched<'a>(
+    img: &'a RgbaImage,
+    key: &str,
+    count: usize,
+) -> Result<impl Iterator<Item = u8> + 'a, LowkeyError> {
+    check_capacity_positions(img, count)?;
+    let values = img.as_raw();
+    Ok(noise_matched_positions(img, key, count)
+        .into_iter()
+        .map(move |position| values[position]))
+}
+
+/// The values of `channels`, in an order shuffled by the key.
+///
+/// Scattering spreads a message evenly over the whole image instead of
+/// packing it into the top rows, and without the key the bits cannot be
+/// found, let alone put back in order. The shuffle (Fisher-Yates) is drawn
+/// lazily, so reading a short prefix such as the header is cheap and the
+/// prefix does not depend on how much is read after it. The first steps
+/// keep only the swapped values, so reading the header does not lay out the
+/// whole image. The seed is derived from the key with Argon2id (see
+/// `crypto::layout_seed`), so each guessed key still costs a derivation.
+///
+/// Positions before `start`, where a decoy message is, are left out.
+pub fn scattered_positions(
+    img: &RgbaImage,
+    key: &str,
+    channels: Channels,
+    start: usize,
+) -> impl Iterator<Item = usize> + use<> {
+    let seed = crypto::layout_seed(key) ^ SCATTER_STREAM;
+    shuffled(img.as_raw().len(), seed)
+        .filter(move |&position| position >= start && channels.contains(position))
+}
+
+/// The values of the pixels at `pixels` (such as those from
+/// `opaque_pixels`), one at a time in an order shuffled by the key apart
+/// from the message's, for a region record to go in at one bit each.
+pub fn region_record_positions<'a>(
+    key: &str,
+    pixels: &'a [usize],
+) -> impl Iterator<Item = usize> + use<'a> {
+    let seed = crypto::layout_seed(key) ^ REGION_STREAM;
+    shuffled(pixels.len() * 4, seed).map(|k| pixels[k / 4] * 4 + k % 4)
+}
+
+/// 0 to `len` - 1 in the lazily drawn Fisher-Yates order of `seed` (see
+/// `scattered_positions`).
+fn shuffled(len: usize, seed: u64) -> impl Iterator<Item = usize> {
+    let mut swapped: HashMap<usize, u32> = HashMap::new();
+    let mut order: Vec<u32> = Vec::new();
+    (0..len).map(move |i| {
+        let j = i + (splitmix64(seed ^ i as u64) % (len - i) as u64) as usize;
+        if i < SPARSE_SHUFFLE_STEPS {
+            let at = |k: usize| swapped.get(&k).copied().unwrap_or(k as u32);
+            let (taken, left) = (at(j), at(i));
+            // Index i is never looked at again
+            swapped.remove(&i);
+            swapped.insert(j, left);
+            taken as usize
+        } else {
+            if order.is_empty() {
+                order = (0..len as u32).collect();
+                for (k, value) in swapped.drain() {
+                    order[k] = value;
+                }
+            }
+            order.swap(i, j);
+            order[i] as usize
+        }
+    })
+}
+
+/// Reader over the values of `channels` from `start` on in scattered order.
+pub fn get_bits_reader_scattered<'a>(
+    img: &'a RgbaImage,
+    key: &str,
+    channels: Channels,
+    start: usize,
+) -> impl Iterator<Item = u8> + 'a {
+    let values = img.as_raw();
+    scattered_positions(img, key, channels, start).map(move |position| values[position])
+}
+
+/// Whether each value of `img` lies in a textured area, where changed low
+/// bits blend in, rather than a flat one such as a sky or a solid background,
+/// where they stand out.
+///
+/// Texture is measured on the bits above the `depth` low ones, which
+/// embedding at that depth leaves alone, so that decode finds the same map.
+pub fn textured_values(img: &RgbaImage, depth: u8) -> Vec<bool> {
+    (0..img.len())
+        .map(|i| texture(img, i, depth) >= TEXTURED_LEVEL)
+        .collect()
+}
+
+/// The values of `channels` from `start` on that `textured` (from
+/// `textured_values`) marks, in the keyed order of `scattered_positions`.
+/// Flat areas are left out altogether, however long the message.
+pub fn adaptive_positions<'a>(
+    img: &RgbaImage,
+    key: &str,
+    channels: Channels,
+    start: usize,
+    textured: &'a [bool],
+) -> impl Iterator<Item = usize> + use<'a> {
+    scattered_positions(img, key, channels, start).filter(move |&position| textured[position])
+}
+
+/// How many values `adaptive_positions` yields.
+pub fn adaptive_capacity(textured: &[bool], channels: Channels, start: usize) -> usize {
+    textured
+        .iter()
+        .enumerate()
+        .filter(|&(i, &textured)| textured && i >= start && channels.contains(i))
+        .count()
+}
+
+/// Reader over the values `adaptive_positions` yields.
+pub fn get_bits_reader_adaptive<'a>(
+    img: &'a RgbaImage,
+    key: &str,
+    channels: Channels,
+    start: usize,
+    textured: &'a [bool],
+) -> impl Iterator<Item = u8> + 'a {
+    let values = img.as_raw();
+    adaptive_positions(img, key, channels, start, textured).map(move |position| values[position])
+}
+
+pub(crate) fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Reader over the values of `channels` of each image in turn.
+pub fn get_bits_reader_images(
+    imgs: &[RgbaImage],
+    channels: Channels,
+) -> LazyImagesReader<'_, impl Iterator<Item = Result<Cow<'_, RgbaImage>, LowkeyError>>> {
+    LazyImagesReader::new(imgs.iter().map(|img| Ok(Cow::Borrowed(img))), channels)
+}
+
+/// Values of an image for `LazyImagesReader` to take (see `take_each`)
+type ImageLimit<'a> = Box<dyn Fn(&RgbaImage) -> usize + 'a>;
+
+/// Reader over the values of `channels` of each image in turn, taking an
+/// image from `images` only once the values before it are used up and
+/// dropping it once its own are.
+///
+/// With images loaded on demand, reading a short message from a folder of
+/// large photos only ever holds one of them, and the photos after the end
+/// of the message are never opened. An image that fails to load ends the
+/// reader, and the error is kept for `take_error`.
+///
+/// Values are copied out of the current image a batch at a time, so that
+/// `next` is mostly a read from a slice and decoding a large image is not
+/// held up by per-value bookkeeping.
+pub struct LazyImagesReader<'a, I> {
+    images: I,
+    current: Option<Cow<'a, RgbaImage>>,
+    /// Values of the current image read ahead, and how many were returned
+    batch: Vec<u8>,
+    returned: usize,
+    /// Images taken so far
+    loaded: usize,
+    position: usize,
+    channels: Channels,
+    /// Values of `channels` passed over at the start of each image
+    skip: usize,
+    /// Values of `channels` read or passed over in the current image
+    taken: usize,
+    /// Values of `channels` of each image to take, passed over ones included
+    limit: Option<ImageLimit<'a>>,
+    /// `limit` of the current image
+    end: usize,
+    error: Option<LowkeyError>,
+    progress: Option<&'a Progress>,
+}
+
+impl<'a, I> LazyImagesReader<'a, I>
+where
+    I: Iterator<Item = Result<Cow<'a, RgbaImage>, LowkeyError>>,
+{
+    pub fn new(images: I, channels: Channels) -> Self {
+        LazyImagesReader {
+            images,
+            current: None,
+            batch: Vec::with_capacity(READ_BATCH),
+            returned: 0,
+            loaded: 0,
+            position: 0,
+            channels,
+            skip: 0,
+            taken: 0,
+            limit: None,
+            end: usize::MAX,
+            error: None,
+            progress: None,
+        }
+    }
+
+    /// Pass over the first `values` values of `channels` of every image, such
+    /// as their in-band sequence headers. Values already read from the
+    /// current image count towards its share.
+    pub fn skip_each(&mut self, values: usize) {
+        self.unread();
+        self.skip = values;
+    }
+
+    /// Take no more than the first `values(img)` values of `channels` of
+    /// every image, the passed over ones included, and go on with the next
+    /// image after them. The current image is held to it too.
+    pub fn take_each(&mut self, values: impl Fn(&RgbaImage) -> usize + 'a) {
+        self.unread();
+        if let Some(img) = &self.current {
+            self.end = values(img);
+        }
+        self.limit = Some(Box::new(values));
+    }
+
+    /// Count the values read from here on in `progress`, along with those
+    /// already read from the current image.
+    pub fn track(&mut self, progress: &'a Progress) {
+        self.unread();
+        if let Some(img) = &self.current {
+            let values = self.channel_values(img).min(self.end);
+            progress.start_image(self.loaded - 1, values.saturating_sub(self.skip));
+            progress.advance(self.taken.saturating_sub(self.skip));
+        }
+        self.progress = Some(progress);
+    }
+
+    fn channel_values(&self, img: &RgbaImage) -> usize {
+        img.len() / 4 * self.channels.count()
+    }
+
+    /// Put the values read ahead and not returned back in the current image.
+    fn unread(&mut self) {
+        let ahead = self.batch.len() - self.returned;
+        if ahead > 0 {
+            self.taken -= ahead;
+            self.position = self.channels.index_of(self.taken);
+        }
+        self.batch.clear();
+        self.returned = 0;
+    }
+
+    /// Read the next batch of values of the current image ahead, past the
+    /// ones passed over, and whether there were any left to read.
+    fn fill(&mut self) -> bool {
+        self.batch.clear();
+        self.returned = 0;
+        let Some(img) = &self.current else {
+            return false;
+        };
+        let values = img.as_raw();
+        while self.taken < self.skip.min(self.end) && self.position < values.len() {
+            if self.channels.contains(self.position) {
+                self.taken += 1;
+            }
+            self.position += 1;
+        }
+
+        let wanted = READ_BATCH.min(self.end.saturating_sub(self.taken));
+        if self.channels == Channels::ALL {
+            let count = wanted.min(values.len() - self.position);
+            self.batch
+                .extend_from_slice(&values[self.position..self.position + count]);
+            self.position += count;
+        } else {
+            while self.batch.len() < wanted && self.position < values.len() {
+                if self.channels.contains(self.position) {
+                    self.batch.push(values[self.position]);
+                }
+                self.position += 1;
+            }
+        }
+        self.taken += self.batch.len();
+        if let Some(progress) = self.progress {
+            progress.advance(self.batch.len());
+        }
+        !self.batch.is_empty()
+    }
+
+    /// Why the reader ended early, if an image failed to load.
+    pub fn take_error(&mut self) -> Option<LowkeyError> {
+        self.error.take()
+    }
+}
+
+impl<'a, I> Iterator for LazyImagesReader<'a, I>
+where
+    I: Iterator<Item = Result<Cow<'a, RgbaImage>, LowkeyError>>,
+{
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            if let Some(&value) = self.batch.get(self.returned) {
+                self.returned += 1;
+                return Some(value);
+            }
+            if self.fill() {
+                continue;
+            }
+
+            match self.images.next()? {
+                Ok(img) => {
+                    self.end = self.limit.as_ref().map_or(usize::MAX, |limit| limit(&img));
+                    if let Some(progress) = self.progress {
+                        let values = self.channel_values(&img).min(self.end);
+                        progress.start_image(self.loaded, values.saturating_sub(self.skip));
+                    }
+                    self.current = Some(img);
+                    self.loaded += 1;
+                    self.position = 0;
+                    self.taken = 0;
+                }
+                Err(e) => {
+                    self.current = None;
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+pub fn read_bits(
+    reader: &mut impl Iterator<Item = u8>,
+    length: usize,
+) -> Result<BitVec<u8, Lsb0>, LowkeyError> {
+    read_bits_at_depth(reader, length, 1)
 }
 
-pub fn read_bits<'a>(
-    reader: &mut impl Iterator<Item = &'a u8>,
+/// Read `length` bits from the `depth` low bits of the next channel values.
+pub fn read_bits_at_depth(
+    reader: &mut impl Iterator<Item = u8>,
     length: usize,
-) -> Result<BitVec<u8, Lsb0>, String> {
-    let bytes: Vec<u8> = reader.take(length).map(|&v| v).collect();
+    depth: u8,
+) -> Result<BitVec<u8, Lsb0>, LowkeyError> {
+    let count = length.div_ceil(depth as usize);
+    let bytes: Vec<u8> = reader.take(count).collect();
 
     let batch_len = bytes.len();
-    if batch_len < length {
-        Err(format!(
+    if batch_len < count {
+        Err(LowkeyError::NoMessage(format!(
             "Count of channels ({}) is fewer than length ({})",
-            batch_len, length
-        ))
+            batch_len, count
+        )))
     } else {
-        let bits: BitVec<u8, Lsb0> = bytes.into_iter().map(|c| (c & 1) == 1).collect();
+        // The low bits of a word's worth of values at a time
+        let mask = (1u64 << depth) - 1;
+        let per_word = values_per_word(depth);
+        let mut bits: BitVec<u8, Lsb0> = BitVec::repeat(false, count * depth as usize);
+        for (values, word) in bytes
+            .chunks(per_word)
+            .zip(bits.chunks_mut(per_word * depth as usize))
+        {
+            let packed = values.iter().rev().fold(0u64, |packed, &value| {
+                packed << depth | (value as u64 & mask)
+            });
+            word.store_le(packed);
+        }
+        bits.truncate(length);
         Ok(bits)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_channels() {
+        assert_eq!("rgba".parse::<Channels>().unwrap(), Channels::ALL);
+        assert_eq!("BG".parse::<Channels>().unwrap().to_string(), "gb");
+        assert_eq!("b".parse::<Channels>().unwrap().mask(), 0b0100);
+        assert!("".parse::<Channels>().is_err());
+        assert!("rgbx".parse::<Channels>().is_err());
+        assert_eq!(Channels::candidates().count(), 15);
+        assert_eq!(Channels::candidates().next(), Some(Channels::ALL));
+    }
+
+    #[test]
+    fn test_scattered_positions() {
+        // Past the sparse steps of the shuffle
+        let img = RgbaImage::new(48, 48);
+        let channels: Channels = "rb".parse().unwrap();
+
+        let mut positions: Vec<usize> = scattered_positions(&img, "key", channels, 0).collect();
+        assert_ne!(
+            positions,
+            scattered_positions(&img, "other", channels, 0).collect::<Vec<_>>()
+        );
+        let behind: Vec<usize> = scattered_positions(&img, "key", channels, 100).collect();
+        assert_eq!(
+            behind,
+            positions
+                .iter()
+                .copied()
+                .filter(|&i| i >= 100)
+                .collect::<Vec<_>>(),
+            "same order behind a decoy"
+        );
+        positions.sort_unstable();
+        let expected: Vec<usize> = (0..img.len()).filter(|i| channels.contains(*i)).collect();
+        assert_eq!(positions, expected, "every value of the channels once");
+
+        // The same order as a plain Fisher-Yates shuffle
+        let seed = crypto::layout_seed("key") ^ SCATTER_STREAM;
+        let mut order: Vec<usize> = (0..img.len()).collect();
+        for i in 0..order.len() {
+            let j = i + (splitmix64(seed ^ i as u64) % (order.len() - i) as u64) as usize;
+            order.swap(i, j);
+        }
+        assert!(
+            scattered_positions(&img, "key", Channels::ALL, 0).eq(order),
+            "orders drawn before stay the same"
+        );
+        assert_eq!(channels.index_of(0), 0);
+        assert_eq!(channels.index_of(3), 6);
+        assert_eq!(Channels::ALL.index_of(5), 5);
+    }
+
+    #[test]
+    fn test_write_values() {
+        let cover = RgbaImage::from_fn(9, 7, |x, y| {
+            image::Rgba([x as u8 * 29, y as u8 * 37, 200, 255])
+        });
+        for (channels, depth, start) in
+            [("rgba", 1, 0), ("rgba", 2, 3), ("rgb", 3, 5), ("gb", 4, 1)]
+        {
+            let channels: Channels = channels.parse().unwrap();
+            // Not a whole number of values, nor of words
+            let length = (cover.len() / 4 * channels.count() - start) * depth as usize - 2;
+            let bits: BitVec<u8, Lsb0> = (0..length).map(|i| i % 7 < 3).collect();
+            let mut img = cover.clone();
+            set_bits_image_at(&mut img, &bits, start, depth, channels).unwrap();
+
+            // A bit at a time
+            let mut expected = cover.clone();
+            let positions = (0..expected.len())
+                .filter(|&i| channels.contains(i))
+                .skip(start);
+            for (k, bit) in bits.iter().enumerate() {
+                let position = positions.clone().nth(k / depth as usize).unwrap();
+                let value = &mut expected.as_mut()[position];
+                *value = (*value & !(1 << (k % depth as usize)))
+                    | ((*bit as u8) << (k % depth as usize));
+            }
+            assert_eq!(img, expected, "{} at depth {}", channels, depth);
+
+            let mut reader =
+                get_bits_reader_images(std::slice::from_ref(&img), channels).skip(start);
+            assert_eq!(
+                read_bits_at_depth(&mut reader, length, depth).unwrap(),
+                bits
+            );
+        }
+    }
+
+    #[test]
+    fn test_fill_noise_at() {
+        // Red and green vary above the fill depth, so they are filled
+        let cover = RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([
+                0x80 | (x as u8 & 1) << 4,
+                0x80 | (y as u8 & 1) << 4,
+                0x80,
+                0x80,
+            ])
+        });
+        let mut img = cover.clone();
+        let channels: Channels = "rg".parse().unwrap();
+        fill_noise_at(&mut img, 100, 2, channels);
+
+        let values: Vec<(u8, u8)> = cover
+            .iter()
+            .zip(img.iter())
+            .enumerate()
+            .filter(|(i, _)| channels.contains(*i))
+            .map(|(_, (&from, &to))| (from, to))
+            .collect();
+        assert!(values[..100].iter().all(|&(from, to)| from == to));
+        assert!(values.iter().all(|&(from, to)| from & !0b11 == to & !0b11));
+        let changed = values[100..].iter().filter(|&&(from, to)| from != to);
+        assert!(
+            changed.count() > (values.len() - 100) / 2,
+            "about 3 in 4 changed"
+        );
+        assert!(
+            cover
+                .iter()
+                .zip(img.iter())
+                .enumerate()
+                .all(|(i, (from, to))| channels.contains(i) || from == to),
+            "other channels untouched"
+        );
+    }
+
+    #[test]
+    fn test_fill_noise_at_keeps_opaque_alpha() {
+        let cover = RgbaImage::from_fn(64, 64, |x, y| image::Rgba([x as u8, y as u8, 0x80, 255]));
+        let mut img = cover.clone();
+        fill_noise_at(&mut img, 0, 1, "rgba".parse().unwrap());
+
+        assert!(
+            img.pixels().all(|pixel| pixel[3] == 255),
+            "alpha stays opaque"
+        );
+        assert_ne!(img, cover, "the other channels are filled");
+    }
+
+    #[test]
+    fn test_match_changes() {
+        // Away from 0 and 255, where values can only move one way
+        let cover = RgbaImage::from_fn(32, 32, |x, y| {
+            image::Rgba([x as u8 * 4 + 64, y as u8 + 64, 128, 200])
+        });
+        let mut img = cover.clone();
+        let bits: BitVec<u8, Lsb0> = (0..img.len() * 2).map(|i| i % 3 == 0).collect();
+        set_bits_image_at(&mut img, &bits, 0, 2, Channels::ALL).unwrap();
+        let replaced = img.clone();
+        match_changes(&cover, &mut img, 2);
+
+        let mut left_block = false;
+        for ((&from, &to), &wanted) in cover.iter().zip(img.iter()).zip(replaced.iter()) {
+            assert_eq!(to & 0b11, wanted & 0b11, "same low bits");
+            assert!((to as i16 - from as i16).abs() <= 2, "nearest");
+            left_block |= to >> 2 != from >> 2;
+        }
+        assert!(left_block, "values leave their block of four");
+        assert_eq!("lsb-match".parse::<Method>().unwrap(), Method::LsbMatch);
+        assert_eq!(Method::default().to_string(), "lsb-replace");
+        assert!("lsb".parse::<Method>().is_err());
+    }
+
+    #[test]
+    fn test_adaptive_positions() {
+        // Flat on the left, noisy on the right
+        let mut img = RgbaImage::from_fn(32, 32, |x, y| {
+            let value = if x < 16 {
+                100
+            } else {
+                (x * 37 + y * 91) as u8 % 64
+            };
+            image::Rgba([value, value, value, 255])
+        });
+        let channels: Channels = "rgb".parse().unwrap();
+        let textured = textured_values(&img, 2);
+        let positions: Vec<usize> =
+            adaptive_positions(&img, "key", channels, 0, &textured).collect();
+        assert_eq!(positions.len(), adaptive_capacity(&textured, channels, 0));
+        assert!(positions.len() > img.len() / 4, "the noisy half is used");
+        assert!(
+            positions.iter().all(|&i| i / 4 % 32 >= 15 && i % 4 != 3),
+            "flat values and other channels are left out"
+        );
+
+        // Embedding in the low bits leaves the map as it was
+        fill_noise_positions(&mut img, positions.iter().copied(), 2);
+        assert_eq!(textured_values(&img, 2), textured);
+    }
+
+    #[test]
+    fn test_opaque_pixels() {
+        let mut img = RgbaImage::from_fn(4, 4, |x, y| {
+            image::Rgba([x as u8, y as u8, 7, if x == y { 0 } else { 15 + x as u8 }])
+        });
+        let pixels = opaque_pixels(&img);
+        // The diagonal is transparent, and so is alpha 15
+        assert_eq!(pixels, [1, 2, 3, 6, 7, 9, 11, 13, 14]);
+
+        let mut row = take_pixels(&img, &pixels);
+        assert_eq!(row.dimensions(), (9, 1));
+        assert_eq!(row.get_pixel(1, 0), img.get_pixel(2, 0));
+        let before = img.clone();
+        for value in row.iter_mut() {
+            *value |= 0b1111;
+        }
+        put_pixels(&mut img, &pixels, &row);
+        assert_eq!(
+            opaque_pixels(&img),
+            pixels,
+            "the low bits of alpha do not count"
+        );
+        assert_eq!(img.get_pixel(0, 0), before.get_pixel(0, 0));
+        assert_eq!(img.get_pixel(2, 0).0, [0b1111, 0b1111, 0b1111, 0b11111]);
+    }
+
+    #[test]
+    fn test_lazy_images_reader() {
+        let imgs = [
+            RgbaImage::new(2, 2),
+            RgbaImage::from_pixel(2, 2, image::Rgba([1; 4])),
+        ];
+        let loaded = std::cell::Cell::new(0);
+        let images = || {
+            imgs.iter()
+                .map(|img| Ok(Cow::Borrowed(img)))
+                .chain([Err(LowkeyError::Io("missing".to_string()))])
+                .inspect(|_| loaded.set(loaded.get() + 1))
+        };
+
+        // Three values of each image, in turn
+        let mut reader = LazyImagesReader::new(images(), "rgb".parse().unwrap());
+        assert_eq!(reader.by_ref().take(12).collect::<Vec<_>>(), [0; 12]);
+        assert_eq!(loaded.get(), 1, "the second image is not loaded yet");
+        assert_eq!(reader.by_ref().take(12).collect::<Vec<_>>(), [1; 12]);
+        assert_eq!(reader.next(), None);
+        assert_eq!(
+            reader.take_error(),
+            Some(LowkeyError::Io("missing".to_string()))
+        );
+
+        // Past the first two values, up to the fifth of each image
+        let mut reader = LazyImagesReader::new(images(), "rgb".parse().unwrap());
+        reader.skip_each(2);
+        reader.take_each(|_| 5);
+        assert_eq!(
+            reader.by_ref().take(6).collect::<Vec<_>>(),
+            [0, 0, 0, 1, 1, 1]
+        );
+        assert_eq!(reader.next(), None);
+
+        // Limits set after reading apply from the next value, not from
+        // where the reader had read ahead to
+        let imgs = [RgbaImage::from_fn(64, 64, |x, _| image::Rgba([x as u8; 4]))];
+        let mut reader = get_bits_reader_images(&imgs, Channels::ALL);
+        assert_eq!(reader.by_ref().take(5).collect::<Vec<_>>(), [0, 0, 0, 0, 1]);
+        reader.take_each(|_| 7);
+        assert_eq!(reader.collect::<Vec<_>>(), [1, 1]);
+    }
+}
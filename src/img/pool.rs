@@ -0,0 +1,177 @@
+// Worker threads that write the outputs of a multi-image encode, so that
+// the compression of one image overlaps the embedding of the next instead of
+// everything happening in turn on one thread.
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::error::LowkeyError;
+
+/// Most writers at once. Each holds a whole image while it compresses it,
+/// so more would cost memory without going much faster.
+const MAX_WORKERS: usize = 4;
+
+type Job = Box<dyn FnOnce() -> Result<(), LowkeyError> + Send>;
+
+/// A pool of threads running write jobs in the order they are submitted,
+/// each job tagged with a `T` that says which output it was once it is done.
+///
+/// `submit` blocks while every worker is busy, so that no more images wait
+/// in memory than there are workers. After a job fails the jobs still
+/// queued are skipped, and the error comes back from the next `submit` or
+/// from `finish`. Dropping the pool waits for the jobs already running.
+pub struct WritePool<T: Send + 'static> {
+    jobs: Option<SyncSender<(T, Job)>>,
+    done: Receiver<(T, Result<(), LowkeyError>)>,
+    workers: Vec<JoinHandle<()>>,
+    pending: usize,
+}
+
+impl<T: Send + 'static> WritePool<T> {
+    /// A pool with a worker per spare core, up to `MAX_WORKERS`, leaving
+    /// one core to the thread that embeds.
+    pub fn new() -> Self {
+        let cores = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        Self::with_workers(cores.saturating_sub(1).clamp(1, MAX_WORKERS))
+    }
+
+    pub fn with_workers(count: usize) -> Self {
+        let (jobs, queue) = mpsc::sync_channel::<(T, Job)>(0);
+        let (finished, done) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let failed = Arc::new(AtomicBool::new(false));
+        let workers = (0..count.max(1))
+            .map(|_| {
+                let (queue, finished, failed) = (queue.clone(), finished.clone(), failed.clone());
+                thread::spawn(move || {
+                    loop {
+                        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                        let Ok((tag, job)) = next else {
+                            break;
+                        };
+                        if failed.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        let result = job();
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        let _ = finished.send((tag, result));
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            done,
+            workers,
+            pending: 0,
+        }
+    }
+
+    /// Queue `job`, once a worker is free to take it, and return the tags of
+    /// the jobs that have finished since the last call.
+    pub fn submit(
+        &mut self,
+        tag: T,
+        job: impl FnOnce() -> Result<(), LowkeyError> + Send + 'static,
+    ) -> Result<Vec<T>, LowkeyError> {
+        let finished = self.collect(false)?;
+        self.jobs
+            .as_ref()
+            .and_then(|jobs| jobs.send((tag, Box::new(job))).ok())
+            .ok_or_else(|| LowkeyError::Io("The output writers have stopped".to_string()))?;
+        self.pending += 1;
+        Ok(finished)
+    }
+
+    /// Wait for every job, and return the tags of those that had not been
+    /// returned yet.
+    pub fn finish(mut self) -> Result<Vec<T>, LowkeyError> {
+        self.jobs = None;
+        self.collect(true)
+    }
+
+    /// Tags of the finished jobs, waiting for all of them with `wait`, or
+    /// the first error.
+    fn collect(&mut self, wait: bool) -> Result<Vec<T>, LowkeyError> {
+        let mut finished = Vec::new();
+        while self.pending > 0 {
+            let next = match wait {
+                true => self.done.recv().ok(),
+                false => self.done.try_recv().ok(),
+            };
+            let Some((tag, result)) = next else {
+                break;
+            };
+            self.pending -= 1;
+            result?;
+            finished.push(tag);
+        }
+        if wait && self.pending > 0 {
+            // A worker panicked; its job never reported back
+            return Err(LowkeyError::Io(
+                "An output writer stopped unexpectedly".to_string(),
+            ));
+        }
+        Ok(finished)
+    }
+}
+
+impl<T: Send + 'static> Default for WritePool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send + 'static> Drop for WritePool<T> {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_write_pool() {
+        let mut pool = WritePool::with_workers(3);
+        let mut finished = Vec::new();
+        for i in 0..10u64 {
+            finished.extend(
+                pool.submit(i, move || {
+                    thread::sleep(Duration::from_millis(10 - i));
+                    Ok(())
+                })
+                .unwrap(),
+            );
+        }
+        finished.extend(pool.finish().unwrap());
+        finished.sort();
+        assert_eq!(finished, (0..10).collect::<Vec<_>>());
+
+        // An error comes back, and the jobs queued after it are skipped
+        let mut pool = WritePool::with_workers(1);
+        let ran = Arc::new(AtomicBool::new(false));
+        pool.submit(0, || Err(LowkeyError::Io("disk full".to_string())))
+            .unwrap();
+        let flag = ran.clone();
+        let result = pool
+            .submit(1, move || {
+                flag.store(true, Ordering::Relaxed);
+                Ok(())
+            })
+            .and_then(|_| pool.finish());
+        assert_eq!(result, Err(LowkeyError::Io("disk full".to_string())));
+        assert!(!ran.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,364 @@
+// Part of an image a message is kept to, as a rectangle or a mask, and the
+// record of it embedded with the key, so that decode finds the region
+// without being told.
+
+use bitvec::prelude::{BitVec, Lsb0};
+use image::GrayImage;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+use super::pixel::read_bits;
+use crate::error::LowkeyError;
+use crate::{compress, crypto};
+
+/// Mask values from this on are white, where the message may go
+const WHITE_LEVEL: u8 = 128;
+
+/// Size of the record up to the mask: flags byte + 4-byte x, y, width and
+/// height + 4-byte mask length
+const RECORD_START_BYTES: usize = 21;
+
+/// Size of the check at the end of the record
+const CHECK_BYTES: usize = 8;
+
+/// Record flag set when transparent pixels in the region are left out too
+const TRANSPARENT_SKIPPED_FLAG: u8 = 0b01;
+
+/// Record flag set when the mask is deflated
+const DEFLATED_FLAG: u8 = 0b10;
+
+/// Rectangle of pixels, from its top left corner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Rect {
+    type Err = LowkeyError;
+
+    /// `x,y,w,h` in pixels, as `--region` takes it
+    fn from_str(s: &str) -> Result<Self, LowkeyError> {
+        let invalid =
+            || LowkeyError::InvalidInput(format!("Invalid region '{}': expected x,y,w,h", s));
+        let numbers = s
+            .split(',')
+            .map(|number| number.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let [x, y, width, height] = numbers[..] else {
+            return Err(invalid());
+        };
+        if width == 0 || height == 0 {
+            return Err(LowkeyError::InvalidInput(format!(
+                "Region '{}' is empty",
+                s
+            )));
+        }
+        Ok(Self {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+impl Rect {
+    fn check_within(&self, width: u32, height: u32) -> Result<(), LowkeyError> {
+        if self.x as u64 + self.width as u64 > width as u64
+            || self.y as u64 + self.height as u64 > height as u64
+        {
+            return Err(LowkeyError::InvalidInput(format!(
+                "Region {},{},{},{} does not fit in the {}x{} image",
+                self.x, self.y, self.width, self.height, width, height
+            )));
+        }
+        Ok(())
+    }
+
+    fn area(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+/// Pixels of a cover the message is kept to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Region {
+    Rect(Rect),
+    /// Image the size of the cover, white where the message may go
+    Mask(GrayImage),
+}
+
+impl Region {
+    /// The mask in the image at `path`, white where the message may go.
+    pub fn from_mask_file(path: &str) -> Result<Self, LowkeyError> {
+        let mask = image::open(path)
+            .map_err(|e| LowkeyError::Io(format!("Failed to read region mask '{}': {}", path, e)))?
+            .to_luma8();
+        Ok(Region::Mask(mask))
+    }
+
+    /// The record of the region in an image of `width`x`height`.
+    pub(crate) fn record(
+        &self,
+        width: u32,
+        height: u32,
+        transparent_skipped: bool,
+    ) -> Result<RegionRecord, LowkeyError> {
+        let (rect, mask) = match self {
+            Region::Rect(rect) => {
+                rect.check_within(width, height)?;
+                (*rect, None)
+            }
+            Region::Mask(mask) => {
+                if mask.dimensions() != (width, height) {
+                    return Err(LowkeyError::InvalidInput(format!(
+                        "Region mask is {}x{}, but the image is {}x{}",
+                        mask.width(),
+                        mask.height(),
+                        width,
+                        height
+                    )));
+                }
+                let white: Vec<(u32, u32)> = mask
+                    .enumerate_pixels()
+                    .filter(|(_, _, pixel)| pixel[0] >= WHITE_LEVEL)
+                    .map(|(x, y, _)| (x, y))
+                    .collect();
+                let (Some(left), Some(right)) = (
+                    white.iter().map(|&(x, _)| x).min(),
+                    white.iter().map(|&(x, _)| x).max(),
+                ) else {
+                    return Err(LowkeyError::InvalidInput(
+                        "Region mask has no white pixels".to_string(),
+                    ));
+                };
+                let (top, bottom) = (white[0].1, white[white.len() - 1].1);
+                let rect = Rect {
+                    x: left,
+                    y: top,
+                    width: right - left + 1,
+                    height: bottom - top + 1,
+                };
+                let bits = (0..rect.area())
+                    .map(|i| {
+                        let (x, y) = (i as u32 % rect.width, i as u32 / rect.width);
+                        mask.get_pixel(rect.x + x, rect.y + y)[0] >= WHITE_LEVEL
+                    })
+                    .collect();
+                (rect, Some(bits))
+            }
+        };
+        Ok(RegionRecord {
+            rect,
+            mask,
+            transparent_skipped,
+        })
+    }
+}
+
+/// A region as it is embedded: its bounding rectangle and, for a mask,
+/// which pixels of the rectangle are in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RegionRecord {
+    rect: Rect,
+    mask: Option<BitVec<u8, Lsb0>>,
+    /// Whether transparent pixels in the region are left out too
+    pub transparent_skipped: bool,
+}
+
+impl RegionRecord {
+    /// Indices of the pixels in the region of an image `width` wide, in order.
+    pub fn pixels(&self, width: u32) -> Vec<usize> {
+        let rect = self.rect;
+        (0..rect.area())
+            .filter(|&i| self.mask.as_ref().is_none_or(|mask| mask[i]))
+            .map(|i| {
+                let (x, y) = (
+                    rect.x + i as u32 % rect.width,
+                    rect.y + i as u32 / rect.width,
+                );
+                y as usize * width as usize + x as usize
+            })
+            .collect()
+    }
+
+    /// [1 byte flags] + [4 bytes x] + [4 bytes y] + [4 bytes width] +
+    /// [4 bytes height] + [4 bytes mask length] + [mask] + [8 bytes check],
+    /// big-endian. The mask has a bit per pixel of the rectangle, row by
+    /// row, and is deflated if that makes it smaller; a rectangle has none.
+    /// The check is the start of SHA256 over the key's layout seed and the
+    /// rest, so that a record is only found with its key.
+    pub fn to_bytes(&self, key: &str) -> Vec<u8> {
+        let mut bytes = self.content();
+        let check = record_check(&bytes, key);
+        bytes.extend_from_slice(&check);
+        bytes
+    }
+
+    /// Bits the record takes, whatever the key.
+    pub fn bits(&self) -> usize {
+        (self.content().len() + CHECK_BYTES) * 8
+    }
+
+    /// The record up to the check
+    fn content(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.transparent_skipped {
+            flags |= TRANSPARENT_SKIPPED_FLAG;
+        }
+        let mask = match &self.mask {
+            Some(bits) => {
+                let raw = bits.as_raw_slice();
+                match compress::compress(raw) {
+                    Some(deflated) => {
+                        flags |= DEFLATED_FLAG;
+                        deflated
+                    }
+                    None => raw.to_vec(),
+                }
+            }
+            None => Vec::new(),
+        };
+
+        let mut bytes = vec![flags];
+        for number in [self.rect.x, self.rect.y, self.rect.width, self.rect.height] {
+            bytes.extend_from_slice(&number.to_be_bytes());
+        }
+        bytes.extend_from_slice(&(mask.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&mask);
+        bytes
+    }
+
+    /// The record for `key` at one bit per value of `reader`, for an image of
+    /// `width`x`height`, or `None` if there is none.
+    pub fn read(
+        reader: &mut impl Iterator<Item = u8>,
+        key: &str,
+        width: u32,
+        height: u32,
+    ) -> Option<Self> {
+        let mut bytes = read_bits(reader, RECORD_START_BYTES * 8).ok()?.into_vec();
+        let flags = bytes[0];
+        let number = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        let rect = Rect {
+            x: number(1),
+            y: number(5),
+            width: number(9),
+            height: number(13),
+        };
+        let mask_len = number(17) as usize;
+        let raw_len = rect.area().div_ceil(8);
+        if flags & !(TRANSPARENT_SKIPPED_FLAG | DEFLATED_FLAG) != 0
+            || rect.area() == 0
+            || rect.check_within(width, height).is_err()
+            || mask_len > raw_len
+        {
+            return None;
+        }
+
+        bytes.extend(
+            read_bits(reader, (mask_len + CHECK_BYTES) * 8)
+                .ok()?
+                .into_vec(),
+        );
+        let (content, check) = bytes.split_at(bytes.len() - CHECK_BYTES);
+        if check != record_check(content, key) {
+            return None;
+        }
+
+        let mask = &content[RECORD_START_BYTES..];
+        let mask = if mask.is_empty() {
+            None
+        } else {
+            let raw = if flags & DEFLATED_FLAG != 0 {
+                compress::decompress(mask).ok()?
+            } else {
+                mask.to_vec()
+            };
+            if raw.len() != raw_len {
+                return None;
+            }
+            let mut bits = BitVec::from_vec(raw);
+            bits.truncate(rect.area());
+            Some(bits)
+        };
+        Some(Self {
+            rect,
+            mask,
+            transparent_skipped: flags & TRANSPARENT_SKIPPED_FLAG != 0,
+        })
+    }
+}
+
+fn record_check(bytes: &[u8], key: &str) -> [u8; CHECK_BYTES] {
+    let mut hasher = Sha256::new();
+    hasher.update(crypto::layout_seed(key).to_le_bytes());
+    hasher.update(bytes);
+    hasher.finalize()[..CHECK_BYTES].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    #[test]
+    fn test_parse_rect() {
+        assert_eq!(
+            "10, 20,30,40".parse::<Rect>().unwrap(),
+            Rect {
+                x: 10,
+                y: 20,
+                width: 30,
+                height: 40
+            }
+        );
+        assert!("10,20,30".parse::<Rect>().is_err());
+        assert!("10,20,0,40".parse::<Rect>().is_err());
+        assert!("a,b,c,d".parse::<Rect>().is_err());
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let mut mask = GrayImage::new(20, 10);
+        for (x, y) in [(3, 2), (4, 2), (7, 5), (5, 8)] {
+            mask.put_pixel(x, y, Luma([255]));
+        }
+        let record = Region::Mask(mask).record(20, 10, true).unwrap();
+        assert_eq!(record.pixels(20), vec![43, 44, 107, 165]);
+
+        let bytes = record.to_bytes("key");
+        let values = |bytes: &[u8]| {
+            BitVec::<u8, Lsb0>::from_slice(bytes)
+                .into_iter()
+                .map(u8::from)
+                .collect::<Vec<_>>()
+        };
+        let read = RegionRecord::read(&mut values(&bytes).into_iter(), "key", 20, 10);
+        assert_eq!(read.as_ref(), Some(&record));
+        assert!(RegionRecord::read(&mut values(&bytes).into_iter(), "other", 20, 10).is_none());
+        // Nor in an image it does not fit in
+        assert!(RegionRecord::read(&mut values(&bytes).into_iter(), "key", 6, 10).is_none());
+
+        let rect = Region::Rect("2,1,3,2".parse().unwrap())
+            .record(20, 10, false)
+            .unwrap();
+        assert_eq!(rect.pixels(20), vec![22, 23, 24, 42, 43, 44]);
+        assert_eq!(rect.to_bytes("key").len(), RECORD_START_BYTES + CHECK_BYTES);
+        assert_eq!(rect.bits(), rect.to_bytes("other").len() * 8);
+        assert!(
+            Region::Rect("18,1,3,2".parse().unwrap())
+                .record(20, 10, false)
+                .is_err()
+        );
+        assert!(
+            Region::Mask(GrayImage::new(20, 10))
+                .record(20, 10, false)
+                .is_err()
+        );
+    }
+}
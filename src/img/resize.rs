@@ -1,33 +1,35 @@
-use image::RgbaImage;
+use image::{ImageBuffer, Pixel};
 
-pub fn resize_image(
-    img: &mut RgbaImage,
+/// Shortest side `--auto-resize` shrinks an image to, unless it was shorter
+pub const MIN_DIMENSION: u32 = 600;
+
+/// Shrink `img` to what a message of `message_bytes_len` bytes needs, or
+/// give it back as it is if it is no larger than that.
+///
+/// Works at any depth, so a 16-bit cover is resized before its low bytes
+/// are taken.
+pub fn resize_image<P: Pixel + 'static>(
+    img: ImageBuffer<P, Vec<P::Subpixel>>,
     message_bytes_len: usize,
     min_size: u32,
-) -> Result<RgbaImage, String> {
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
     let (original_width, original_height) = img.dimensions();
     let (new_width, new_height) =
         calculate_optimal_dimensions(message_bytes_len, original_width, original_height, min_size);
 
     if new_width < original_width || new_height < original_height {
-        println!(
+        eprintln!(
             "Resizing image from {}x{} to {}x{} to optimize for message size",
             original_width, original_height, new_width, new_height
         );
-        *img = image::imageops::resize(
-            img,
-            new_width,
-            new_height,
-            image::imageops::FilterType::Lanczos3,
-        );
+        resize_to(img, new_width, new_height)
     } else {
-        println!(
+        eprintln!(
             "Image size {}x{} is already optimal for message size",
             original_width, original_height
         );
+        img
     }
-
-    Ok(img.clone())
 }
 
 /// Calculate the optimal dimensions for an image to fit a message of given size.
@@ -83,3 +85,27 @@ fn calculate_optimal_dimensions(
 
     (new_width, new_height)
 }
+
+/// Dimensions of an image shrunk by `scale` on each side, keeping the aspect
+/// ratio. The shorter side stays at least `min_dimension` (or what it was, if
+/// shorter), so the result only ever holds more than asked for.
+pub fn scaled_dimensions(width: u32, height: u32, scale: f64, min_dimension: u32) -> (u32, u32) {
+    let shorter = std::cmp::min(width, height);
+    let effective_min = std::cmp::min(min_dimension, shorter);
+    let scale = scale.max(effective_min as f64 / shorter as f64).min(1.0);
+
+    (
+        (width as f64 * scale).ceil() as u32,
+        (height as f64 * scale).ceil() as u32,
+    )
+}
+
+/// Resize `img` to `width`x`height` with the filter `resize_image` uses. The
+/// original is freed as soon as the resized image is made.
+pub fn resize_to<P: Pixel + 'static>(
+    img: ImageBuffer<P, Vec<P::Subpixel>>,
+    width: u32,
+    height: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    image::imageops::resize(&img, width, height, image::imageops::FilterType::Lanczos3)
+}
@@ -0,0 +1,76 @@
+// `lowkey keygen`: new key material in a file that only the user can read.
+
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+
+use lowkey::crypto;
+use lowkey::error::LowkeyError;
+
+/// Write a new random key, or with `age` a new age identity, to `output`,
+/// which must not exist yet. The public key of an identity is printed, for
+/// handing out to senders.
+pub fn keygen(output: &str, age: bool) -> Result<String, LowkeyError> {
+    if age {
+        let (secret, public) = crypto::generate_identity();
+        write_private(output, &format!("# public key: {}\n{}\n", public, secret))?;
+        println!("Public key: {}", public);
+        Ok(format!(
+            "Wrote a new age identity to {}, decode with --identity {}",
+            output, output
+        ))
+    } else {
+        write_private(output, &format!("{}\n", crypto::generate_key()))?;
+        Ok(format!(
+            "Wrote a new key to {}, use it with --key-file {}",
+            output, output
+        ))
+    }
+}
+
+/// Create `path` readable and writable by the user only, refusing to replace
+/// an existing file, which may be the only copy of a key.
+fn write_private(path: &str, contents: &str) -> Result<(), LowkeyError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => {
+            LowkeyError::Io(format!("'{}' already exists, not overwriting it", path))
+        }
+        _ => LowkeyError::Io(format!("Failed to create key file '{}': {}", path, e)),
+    })?;
+    file.write_all(contents.as_bytes())
+        .map_err(|e| LowkeyError::Io(format!("Failed to write key file '{}': {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_private() {
+        let path = std::env::temp_dir()
+            .join(format!("lowkey-keygen-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        keygen(&path, false).unwrap();
+        let key = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(key.trim_end().len(), 64);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let error = keygen(&path, true).unwrap_err();
+        assert!(error.message().contains("already exists"), "{}", error);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), key);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+//! LSB steganography: hide encrypted messages in PNG images.
+//!
+//! ```no_run
+//! use lowkey::EncodeOptions;
+//!
+//! let options = EncodeOptions::with_key("my secret");
+//! lowkey::encode("cover.png", b"hello", "stego.png", &options)?;
+//! assert_eq!(lowkey::decode(&["stego.png"], "my secret")?, b"hello");
+//! # Ok::<(), lowkey::Error>(())
+//! ```
+
+mod api;
+pub mod archive;
+pub mod audio;
+pub mod carrier;
+mod compress;
+pub mod crypto;
+mod ecc;
+pub mod error;
+pub mod ffi;
+pub mod file_info;
+pub mod img;
+pub mod metrics;
+pub mod progress;
+mod shard;
+pub mod trace;
+
+pub use api::{
+    DEFAULT_KEY, EncodeOptions, Error, capacity, decode, decode_bytes, encode, encode_bytes,
+    encode_many,
+};
+pub use img::codec::{EncodeReport, EncodedImage};
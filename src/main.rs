@@ -1,9 +1,47 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
 
-mod crypto;
-mod img;
-use img::codec::{decode_from_files, encode_from_file, encode_from_files};
-use img::io::collect_images_from_dir;
+mod analyze;
+mod clipboard;
+mod config;
+mod control;
+mod cover;
+mod diff;
+mod fetch;
+mod filter;
+#[cfg(feature = "gui")]
+mod gui;
+mod hooks;
+mod keygen;
+mod notify;
+mod rpc;
+mod service;
+mod storage;
+mod sync;
+mod verify;
+use config::Defaults;
+use crypto::{Cipher, KdfParams};
+use fetch::ScratchDir;
+use file_info::{FileInfo, OnConflict};
+use img::codec::{
+    DecodedMessage, Decoy, Distribute, EncodeReport, FillLimit, Format, Inspection, Layout, PadTo,
+    PayloadOptions, Redundancy, SequenceChunk, SetOptions, append_to_file, capacity_report,
+    check_depth, decode_message_from_files, decode_slot_from_file, encode_audio_file,
+    encode_chunk_file, encode_from_file, encode_from_files, encode_jpeg_file,
+    find_existing_payload, inspect_file, list_slots, wipe_file,
+};
+use img::io::{
+    DirScan, FileAttributes, PngEncoding, collect_images_from_dir, read_message_file,
+    write_atomically, write_message_file,
+};
+use img::naming::OutputTemplate;
+use img::pixel::{Channels, Method};
+use img::region::Region;
+use lowkey::error::LowkeyError;
+use lowkey::{DEFAULT_KEY, archive, crypto, file_info, img, metrics, progress, trace};
 
 #[derive(Parser)]
 #[command(name = "lowkey")]
@@ -13,16 +51,26 @@ struct Cli {
     command: Commands,
 }
 
-const DEFAULT_KEY: &str = "lowkey is a steganography tool";
+/// `decode --output` value that writes the message to stdout
+const STDOUT_OUTPUT: &str = "-";
+
+fn default_key() -> String {
+    DEFAULT_KEY.to_string()
+}
 
 #[derive(Subcommand)]
 enum Commands {
-    Encode {
-        /// Single input image (mutually exclusive with --image-list and --image-dir)
+    Encode(Box<EncodeArgs>),
+    Decode(DecodeArgs),
+    /// Decode images and check that they give back a message, without writing it anywhere
+    Verify(VerifyArgs),
+    /// Run steganalysis detectors on images to see how detectable embedded data is
+    Analyze {
+        /// Single input image or HTTPS URL (mutually exclusive with --image-list and --image-dir)
         #[arg(long)]
         image: Option<String>,
 
-        /// Multiple input images (space-separated)
+        /// Multiple input images or HTTPS URLs (space-separated)
         #[arg(long, num_args = 1..)]
         image_list: Option<Vec<String>>,
 
@@ -30,30 +78,109 @@ enum Commands {
         #[arg(long)]
         image_dir: Option<String>,
 
+        /// Original cover of the image, to compare histograms with (used with --image)
         #[arg(long)]
-        message: String,
+        cover: Option<String>,
+
+        /// Also write the two lowest bit planes of each channel as images named <PREFIX>-<channel>-lsb.png / -lsb2.png
+        #[arg(long, value_name = "PREFIX")]
+        export_planes: Option<String>,
+
+        /// Print the report as JSON
+        #[arg(long, default_value = "false")]
+        json: bool,
+
+        /// Write every result, ranked by suspicion, to this JSON file and print only the most suspicious images
+        #[arg(long, value_name = "FILE")]
+        report: Option<String>,
+    },
+    /// Render one bit plane of an image as a black and white image, white where the bit is set
+    Bitplane {
+        /// Input image or HTTPS URL
+        #[arg(long)]
+        image: String,
+
+        /// Bit plane to render, from 0 (the least significant bit) to 7
+        #[arg(long, default_value_t = 0)]
+        plane: u8,
+
+        /// Render this channel only ("r", "g", "b" or "a") instead of all four in a grid (red and green on top, blue and alpha below)
+        #[arg(long)]
+        channel: Option<String>,
+
+        /// PNG to write the plane to
+        #[arg(long)]
+        output: String,
+    },
+    /// Compare a stego image with its original cover: changed values, PSNR and SSIM
+    Diff {
+        /// Original cover image
+        #[arg(long)]
+        original: String,
 
-        /// Single output image (used with --image)
+        /// Stego image encoded from it
         #[arg(long)]
+        stego: String,
+
+        /// Also write the differences, amplified, as an image to this PNG
+        #[arg(long, value_name = "FILE")]
         output: Option<String>,
 
-        /// Output directory (used with --image-list or --image-dir)
+        /// Factor each change is multiplied by in the --output image
+        #[arg(long, default_value_t = 128)]
+        amplify: u8,
+
+        /// Print the report as JSON
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
+    /// Destroy any message hidden in an image: randomize its low bit planes and drop lowkey's sequence info
+    Wipe {
+        /// Input image or HTTPS URL
+        #[arg(long)]
+        image: String,
+
+        /// Where to write the wiped image
         #[arg(long)]
-        output_dir: Option<String>,
+        output: String,
 
+        /// Low bit planes per channel value to wipe (1 to 4); the default covers every bit encode can use
+        #[arg(long, default_value_t = 4)]
+        bits: u8,
+
+        /// Set the wiped bits to zero instead of at random
         #[arg(long, default_value = "false")]
-        auto_resize: bool,
+        zero: bool,
+    },
+    /// Generate a synthetic cover image, sized for a message if asked
+    GenerateCover {
+        /// Size as WxH, e.g. 1024x768 [default: the smallest 4:3 size that carries --message-size]
+        #[arg(long)]
+        size: Option<String>,
+
+        /// What the cover looks like: "noise", "gradient" or "photo-like" [default: photo-like]
+        #[arg(long)]
+        style: Option<String>,
+
+        /// Bytes of message the cover must carry with the default encode settings
+        #[arg(long)]
+        message_size: Option<usize>,
+
+        /// Seed of the image, for the same cover every time [default: random]
+        #[arg(long)]
+        seed: Option<u64>,
 
-        /// Encryption key (any length, will be hashed with SHA256). If not specified, uses default key
-        #[arg(long, default_value = DEFAULT_KEY)]
-        key: String,
+        /// Where to write the cover
+        #[arg(long)]
+        output: String,
     },
-    Decode {
-        /// Single input image (mutually exclusive with --image-list and --image-dir)
+    /// Report how many bytes of message each image, and all of them together, can carry
+    Capacity {
+        /// Single input image or HTTPS URL (mutually exclusive with --image-list and --image-dir)
         #[arg(long)]
         image: Option<String>,
 
-        /// Multiple input images (space-separated)
+        /// Multiple input images or HTTPS URLs (space-separated)
         #[arg(long, num_args = 1..)]
         image_list: Option<Vec<String>>,
 
@@ -61,183 +188,2723 @@ enum Commands {
         #[arg(long)]
         image_dir: Option<String>,
 
+        /// Message bits per channel value (1 to 4), as for encode
+        #[arg(long, default_value_t = 1)]
+        bits: u8,
+
+        /// Channels that carry the message, as letters of "rgba", as for encode
+        #[arg(long, default_value = "rgba")]
+        channels: String,
+
+        /// Leave room for error correction, as for encode
+        #[arg(long, default_value = "false")]
+        ecc: bool,
+
+        /// Leave out transparent pixels, as for encode
+        #[arg(long, default_value = "false")]
+        skip_transparent: bool,
+
+        /// Count the pixels in this rectangle (x,y,w,h) only, as for encode
         #[arg(long)]
-        output: String,
+        region: Option<String>,
+
+        /// Count the white pixels of this mask image only, as for encode
+        #[arg(long)]
+        region_mask: Option<String>,
 
-        /// Encryption key (any length, will be hashed with SHA256). If not specified, uses default key
-        #[arg(long, default_value = DEFAULT_KEY)]
-        key: String,
+        /// Print the report as JSON
+        #[arg(long, default_value = "false")]
+        json: bool,
     },
-}
+    /// Read the header of an image and report whether it appears to hold a message, without the key
+    Inspect {
+        /// Input image or HTTPS URL
+        #[arg(long)]
+        image: String,
 
-fn main() {
-    let cli = Cli::parse();
+        /// Print the report as JSON
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
+    /// List the messages an image holds, the first and those appended behind it with `encode --append`, and which of them a key opens
+    List {
+        /// Input image or HTTPS URL
+        #[arg(long)]
+        image: String,
 
-    let result = match cli.command {
-        Commands::Encode {
-            image,
-            image_list,
-            image_dir,
-            message,
-            output,
-            output_dir,
-            auto_resize,
-            key,
-        } => encode(
-            image,
-            image_list,
-            image_dir,
-            message,
-            output,
-            output_dir,
-            auto_resize,
-            key,
-        ),
-        Commands::Decode {
-            image,
-            image_list,
-            image_dir,
-            output,
-            key,
-        } => decode(image, image_list, image_dir, output, key),
-    };
+        /// Print the list as JSON
+        #[arg(long, default_value = "false")]
+        json: bool,
 
-    match result {
-        Ok(m) => {
-            println!("OK: {}", m);
-        }
-        Err(m) => {
-            eprintln!("{}", m);
-            std::process::exit(1);
-        }
-    }
+        /// Key to try on each message, which is decrypted in memory only [default: none, the messages are listed without a key]
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Read the key to try from this inherited file descriptor instead of --key
+        #[arg(long)]
+        key_fd: Option<i32>,
+
+        /// Read the key to try from this file instead of --key
+        #[arg(long)]
+        key_file: Option<String>,
+    },
+    /// Serve line-delimited JSON-RPC 2.0 requests on stdin/stdout
+    Rpc {
+        /// Serve connections on this TCP address instead of stdin/stdout, e.g. 127.0.0.1:7878
+        /// (a socket passed by systemd socket activation takes precedence)
+        #[arg(long)]
+        listen: Option<String>,
+
+        /// Require every request to carry the secret in this file as its "token" member (sent in plaintext, as lowkey has no TLS)
+        #[arg(long)]
+        token_file: Option<String>,
+
+        /// Serve on an address other hosts can reach even without --token-file
+        #[arg(long)]
+        allow_remote: bool,
+
+        /// Directory that requests may read and write files in, and that relative paths
+        /// are taken from (defaults to the working directory when serving a socket)
+        #[arg(long)]
+        root: Option<String>,
+
+        /// Refuse images and requests whose Argon2id memory exceeds this many KiB
+        #[arg(long, default_value_t = crypto::SERVER_MAX_KDF_MEMORY_KIB)]
+        max_kdf_memory: u32,
+
+        /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9898
+        #[arg(long)]
+        metrics_addr: Option<String>,
+
+        /// OTLP/HTTP collector to export traces to, e.g. http://localhost:4318
+        /// (defaults to $OTEL_EXPORTER_OTLP_ENDPOINT)
+        #[arg(long)]
+        otlp_endpoint: Option<String>,
+    },
+    /// Serve encode/decode requests on a local Unix domain socket, holding the key for all clients
+    /// (not available on Windows, where named pipes are not implemented)
+    Control {
+        /// Path of the socket to listen on, e.g. $XDG_RUNTIME_DIR/lowkey.sock
+        #[arg(long)]
+        socket: String,
+
+        /// Encryption key (any length, stretched with Argon2id). If not specified, uses default key
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Read the encryption key from this inherited file descriptor instead of --key
+        #[arg(long)]
+        key_fd: Option<i32>,
+
+        /// Read the encryption key from this file instead of --key
+        #[arg(long)]
+        key_file: Option<String>,
+
+        /// Directory that requests may read and write files in, and that relative paths
+        /// are taken from (defaults to the working directory)
+        #[arg(long)]
+        root: Option<String>,
+
+        /// Refuse images and requests whose Argon2id memory exceeds this many KiB
+        #[arg(long, default_value_t = crypto::SERVER_MAX_KDF_MEMORY_KIB)]
+        max_kdf_memory: u32,
+    },
+    /// Git clean filter: hide the file on stdin in a cover image, write the PNG to stdout
+    FilterClean {
+        /// Cover image to hide the file in
+        #[arg(long)]
+        cover: String,
+
+        /// Encryption key (any length, stretched with Argon2id). If not specified, uses default key
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Read the encryption key from this inherited file descriptor instead of --key
+        #[arg(long)]
+        key_fd: Option<i32>,
+
+        /// Read the encryption key from this file instead of --key
+        #[arg(long)]
+        key_file: Option<String>,
+    },
+    /// Git smudge filter: recover the file hidden in the PNG on stdin, write it to stdout
+    FilterSmudge {
+        /// Encryption key (any length, stretched with Argon2id). If not specified, uses default key
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Read the encryption key from this inherited file descriptor instead of --key
+        #[arg(long)]
+        key_fd: Option<i32>,
+
+        /// Read the encryption key from this file instead of --key
+        #[arg(long)]
+        key_file: Option<String>,
+    },
+    /// Keep a directory of stego images in step with a directory of cover images
+    Sync {
+        /// Directory of cover images to mirror (searched recursively)
+        #[arg(long)]
+        source: String,
+
+        /// Directory to keep the encoded mirror in, as PNG images
+        #[arg(long)]
+        dest: String,
+
+        /// File or object-storage URI containing the message to hide in every image
+        #[arg(long)]
+        message: String,
+
+        /// File recording what was encoded by the last sync (defaults to <dest>/.lowkey-sync.json)
+        #[arg(long)]
+        state_file: Option<String>,
+
+        /// Encryption key (any length, stretched with Argon2id). If not specified, uses default key
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Read the encryption key from this inherited file descriptor instead of --key
+        #[arg(long)]
+        key_fd: Option<i32>,
+
+        /// Read the encryption key from this file instead of --key
+        #[arg(long)]
+        key_file: Option<String>,
+    },
+    /// Generate a random key for --key-file, or an age identity for --identity and print its public key
+    Keygen {
+        /// File to write the key to, readable by you only (it must not exist yet)
+        #[arg(long)]
+        output: String,
+
+        /// Generate an age X25519 identity, whose public key senders pass to --recipient, instead of a key
+        #[arg(long, default_value = "false")]
+        age: bool,
+    },
+    /// Open the desktop GUI
+    #[cfg(feature = "gui")]
+    Gui,
 }
 
-fn encode(
+// Encode and decode options double as JSON-RPC params, with the same names and defaults
+#[derive(Args, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EncodeArgs {
+    /// Single input image or HTTPS URL (mutually exclusive with --image-list and --image-dir)
+    #[arg(long)]
     image: Option<String>,
+
+    /// Multiple input images or HTTPS URLs (space-separated)
+    #[arg(long, num_args = 1..)]
     image_list: Option<Vec<String>>,
+
+    /// Directory containing input images
+    #[arg(long)]
     image_dir: Option<String>,
-    message: String,
+
+    /// 16-bit PCM WAV or FLAC file or HTTPS URL to hide the message in the sample LSBs of, written to --output as a WAV (instead of --image)
+    #[arg(long)]
+    audio: Option<String>,
+
+    /// Also collect images from the subdirectories of --image-dir
+    #[arg(long, default_value = "false")]
+    recursive: bool,
+
+    /// Only use --image-dir images matching this glob, against the file name or, with a "/", the relative path (repeatable)
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Leave out --image-dir images and subdirectories matching this glob (repeatable)
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Symbolic links under --image-dir: "skip" them, use linked "files" only, or "follow" linked directories too [default: files]
+    #[arg(long)]
+    symlinks: Option<String>,
+
+    /// Order of the --image-dir images, which the message is split over in that order: "natural" (img2 before img10), "name", "mtime", "size" or "capacity" (most pixels first) [default: name]
+    #[arg(long)]
+    sort: Option<String>,
+
+    /// File or object-storage URI containing the message to hide (mutually exclusive with --message-text and --from-clipboard)
+    #[arg(long)]
+    message: Option<String>,
+
+    /// Text to hide as it is, for a short message that needs no file (it may end up in the shell history)
+    #[arg(long)]
+    message_text: Option<String>,
+
+    /// File or object-storage URI to bundle into an archive with the other --message-file files (repeatable)
+    #[arg(long)]
+    message_file: Vec<String>,
+
+    /// Directory whose files are bundled into an archive, for `decode --output-dir` to unpack
+    #[arg(long)]
+    message_dir: Option<String>,
+
+    /// Use the text currently on the clipboard as the message
+    #[arg(long, default_value = "false")]
+    from_clipboard: bool,
+
+    /// Single output image or object-storage URI (used with --image)
+    #[arg(long)]
     output: Option<String>,
+
+    /// Output directory or object-storage prefix (used with --image-list or --image-dir)
+    #[arg(long)]
     output_dir: Option<String>,
+
+    /// Name the --output-dir images by this template instead of after their covers, e.g. "{stem}_{index:03}.png", with {stem}, {index} (from 1), {total}, {date} (UTC, YYYY-MM-DD) and {set} (the set ID in hex); the extension picks the format
+    #[arg(long, conflicts_with = "in_place")]
+    output_template: Option<String>,
+
+    /// Give each output the access and modification times and the permission bits of its (local) cover, so that it doesn't stand out with a fresh timestamp
+    #[arg(long, default_value = "false")]
+    preserve_attributes: bool,
+
+    /// Overwrite each cover with its stego version instead of writing to --output or --output-dir; asks for confirmation unless --backup-suffix keeps a copy
+    #[arg(long, default_value = "false", conflicts_with_all = ["output", "output_dir"])]
+    #[serde(skip)]
+    in_place: bool,
+
+    /// With --in-place, copy each cover to its name with this suffix (e.g. ".orig") before overwriting it
+    #[arg(long, requires = "in_place")]
+    #[serde(skip)]
+    backup_suffix: Option<String>,
+
+    /// Encode into (local) covers that already hold a lowkey message, which is lost, instead of refusing to
+    #[arg(long, default_value = "false")]
+    overwrite_payload: bool,
+
+    /// Hide the message behind the ones --image already holds, under its own key, instead of over them; `lowkey list` shows them and `decode --slot` picks one
+    #[arg(long, default_value = "false", conflicts_with = "overwrite_payload")]
+    #[serde(skip)]
+    append: bool,
+
+    /// Shrink the covers to about the size the message needs, all by the same factor, but no shorter than 600 pixels
+    #[arg(long, default_value = "false")]
     auto_resize: bool,
-    key: String,
-) -> Result<String, String> {
-    if key.is_empty() {
-        return Err("Encryption key cannot be empty".into());
-    }
 
-    let image_param_count = [image.is_some(), image_list.is_some(), image_dir.is_some()]
-        .iter()
-        .filter(|&&x| x)
-        .count();
+    /// Apply each cover's embedded ICC profile to its pixels before embedding and write the output as plain sRGB, without the profile (not with --format jpeg, --audio or --append)
+    #[arg(long, default_value = "false")]
+    convert_srgb: bool,
 
-    if image_param_count == 0 {
-        return Err("Must specify one of --image, --image-list, or --image-dir".into());
-    }
+    /// Write the outputs without any of the covers' metadata (ICC profile, EXIF, XMP, text), keeping only lowkey's own sequence information
+    #[arg(long, default_value = "false")]
+    strip_metadata: bool,
 
-    if image_param_count > 1 {
-        return Err("Only one of --image, --image-list, or --image-dir can be specified".into());
-    }
+    /// With --image-list or --image-dir, write no sequence information into the outputs; they must then be decoded in the right order
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with = "encrypt_sequence_chunk"
+    )]
+    no_sequence_chunk: bool,
 
-    if image.is_some() {
-        if output.is_none() {
-            return Err("--output is required when using --image".into());
-        }
-        if output_dir.is_some() {
-            return Err("--output-dir cannot be used with --image (use --output instead)".into());
-        }
-    } else {
-        if output_dir.is_none() {
-            return Err("--output-dir is required when using --image-list or --image-dir".into());
-        }
-        if output.is_some() {
-            return Err("--output cannot be used with --image-list or --image-dir (use --output-dir instead)".into());
-        }
+    /// With --image-list or --image-dir, encrypt the sequence information under the key and store it in a chunk with a generic name, so that the outputs do not show they belong to a set
+    #[arg(long, default_value = "false")]
+    encrypt_sequence_chunk: bool,
 
-        if auto_resize {
-            return Err("--auto-resize is not supported with multiple images yet".to_string());
-        }
-    }
+    /// How hard to compress PNG outputs: "fast" (larger files, for big batches), "default" or "best" (smallest, slowest); the pixels are the same [default: default]
+    #[arg(long)]
+    png_compression: Option<String>,
 
-    let result = if let Some(single_image) = &image {
-        encode_from_file(
-            single_image,
-            &message,
-            output.as_ref().unwrap(),
-            auto_resize,
-            &key,
-        )
-    } else if let Some(images) = &image_list {
-        encode_from_files(
-            images,
-            &message,
-            output_dir.as_ref().unwrap(),
-            &key,
-        )
-    } else if let Some(dir) = &image_dir {
-        match collect_images_from_dir(dir) {
-            Ok(images) => encode_from_files(
-                &images,
-                &message,
-                output_dir.as_ref().unwrap(),
-                &key,
-            ),
-            Err(e) => Err(e),
-        }
-    } else {
-        unreachable!()
-    };
+    /// Filter for the rows of PNG outputs: "adaptive" (the best for each row, smaller but slower), "none" (quickest), "sub" or "paeth" [default: sub]
+    #[arg(long)]
+    png_filter: Option<String>,
 
-    match result {
-        Ok(_) => {
-            if let Some(out) = &output {
-                Ok(format!("Encoded message into {}", out))
-            } else if let Some(out_dir) = &output_dir {
-                Ok(format!("Encoded message into output directory {}", out_dir))
-            } else {
-                unreachable!()
-            }
-        }
-        Err(e) => Err(format!("Failed to encode message: {}", e)),
-    }
+    /// With --image-list or --image-dir, leave out covers too small to hold even the headers instead of failing; the covers left untouched are reported
+    #[arg(long, default_value = "false")]
+    skip_small: bool,
+
+    /// With --image-list or --image-dir, "full" puts a complete copy of the message in every cover large enough for one, so that any one of them is enough to decode, and "K-of-N" erasure-codes it over the first N covers, so that any K of them are [default: none]
+    #[arg(long)]
+    redundancy: Option<String>,
+
+    /// With --image-list or --image-dir and no --redundancy, how the message is shared out: "fill" fills each cover before the next, "even" makes every cover about equally full, so that none stands out [default: fill]
+    #[arg(long)]
+    distribute: Option<String>,
+
+    /// Message bits per channel value (1 to 4): more capacity, more visible changes [default: 1]
+    #[arg(long)]
+    bits: Option<u8>,
+
+    /// Channels that carry the message, as letters of "rgba" (e.g. "rgb" to leave alpha untouched, or "b") [default: rgba]
+    #[arg(long)]
+    channels: Option<String>,
+
+    /// Spread the message over the image's noisy areas at keyed positions instead of filling it from the start (used with --image)
+    #[arg(long, default_value = "false")]
+    match_noise: bool,
+
+    /// Add Reed-Solomon parity (about 14% more data) so that the message survives a few damaged pixels
+    #[arg(long, default_value = "false")]
+    ecc: bool,
+
+    /// Carry the --message file's name, MIME type and modification time, so that `decode --output-dir` can restore it
+    #[arg(long, default_value = "false")]
+    file_info: bool,
+
+    /// Place the message bits in a random order derived from the key instead of filling the image from the start (used with --image)
+    #[arg(long, default_value = "false")]
+    scatter: bool,
+
+    /// Scatter the message over the textured areas only, leaving flat ones (skies, solid backgrounds) untouched; works with --bits and --channels (used with --image)
+    #[arg(long, default_value = "false")]
+    adaptive: bool,
+
+    /// What the message goes in: "pixels", written to a lossless image, "jpeg", the DCT coefficients of a baseline JPEG cover, written back to a JPEG without compressing it again, or "chunk", a private PNG chunk, which survives changes to the pixels but is not hidden (used with --image) [default: pixels]
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Most deniable settings in one go (--scatter over rgb, --method lsb-match, the noise fill and --pad-to pow2 unless given, with the key's Argon2id encryption), and fail if the detectability report has any recommendation (used with --image)
+    #[arg(long, default_value = "false")]
+    paranoid: bool,
+
+    /// Run steganalysis detectors on the output image and report how detectable it is (used with --image)
+    #[arg(long, default_value = "false")]
+    analyze: bool,
+
+    /// Fail, and remove the output, if the PSNR between a cover and its output image is below this many dB
+    #[arg(long, value_name = "DB")]
+    min_psnr: Option<f64>,
+
+    /// Fail, and remove the output, if the SSIM between a cover and its output image is below this (0 to 1)
+    #[arg(long)]
+    min_ssim: Option<f64>,
+
+    /// Fail if the message would fill more than this share (0 to 1) of an image, as the fill ratio of the report; several covers are each filled only up to it, so that the message reaches more of them
+    #[arg(long, value_name = "FRACTION")]
+    max_fill: Option<f64>,
+
+    /// With --max-fill, only warn about an image filled beyond it
+    #[arg(long, default_value = "false", requires = "max_fill")]
+    max_fill_warn: bool,
+
+    /// Decode the output right after encoding and fail, removing it, unless it gives back the message
+    #[arg(long, default_value = "false")]
+    verify: bool,
+
+    /// Memory for deriving the encryption key with Argon2id, in KiB [default: 19456]
+    #[arg(long)]
+    kdf_memory: Option<u32>,
+
+    /// Passes over that memory when deriving the encryption key [default: 2]
+    #[arg(long)]
+    kdf_iterations: Option<u32>,
+
+    /// AEAD cipher to encrypt the message with: "chacha20-poly1305", "xchacha20-poly1305" (24-byte nonces) or "aes-256-gcm" [default: chacha20-poly1305]
+    #[arg(long)]
+    cipher: Option<String>,
+
+    /// age recipient (an age1... public key) to encrypt the message to instead of with --key, which then only places the bits of --scatter and --match-noise (repeatable)
+    #[arg(long)]
+    recipient: Vec<String>,
+
+    /// age recipients file, one age1... public key per line, to encrypt the message to as with --recipient (repeatable)
+    #[arg(long)]
+    recipients_file: Vec<String>,
+
+    /// Embed the message without encrypting it, for payloads already encrypted with e.g. age or gpg (--key then only places the bits of --scatter and --match-noise)
+    #[arg(long, default_value = "false")]
+    no_encrypt: bool,
+
+    /// Embed the message as it is instead of deflating it first, so that the embedded size follows from the message's length alone and not from its contents
+    #[arg(long, default_value = "false")]
+    no_compress: bool,
+
+    /// Pad the message with encrypted zeros to this many bytes as embedded, "pow2" for the next power of two from 1024, or "full" to fill the image (used with --image), so that the length in the header and the changed values don't give its size away
+    #[arg(long)]
+    pad_to: Option<String>,
+
+    /// How values are changed to carry the bits: "lsb-replace" overwrites the low bits, "lsb-match" moves each value up or down by one at random instead, which the chi-square attack does not pick up [default: lsb-replace]
+    #[arg(long)]
+    method: Option<String>,
+
+    /// Leave pixels with (nearly) zero alpha untouched, whose colour is often undefined and would only show the bits (used with --image)
+    #[arg(long, default_value = "false")]
+    skip_transparent: bool,
+
+    /// Embed in this rectangle of the image only, as x,y,w,h in pixels; decode finds it with the key (used with --image)
+    #[arg(long)]
+    region: Option<String>,
+
+    /// Embed in the white pixels of this mask image only, which is the size of the cover; decode finds them with the key (used with --image)
+    #[arg(long)]
+    region_mask: Option<String>,
+
+    /// Leave the low bits after the message as they were in the cover, instead of setting them at random so that there is no edge where the message ends
+    #[arg(long, default_value = "false")]
+    no_noise_fill: bool,
+
+    /// File holding a harmless decoy message, embedded from the start of the image with --decoy-key, while the message is scattered behind it with --key; decoding with either key finds only its own message (used with --image)
+    #[arg(long, requires = "decoy_key")]
+    decoy: Option<String>,
+
+    /// Key of the --decoy message, to give up instead of --key
+    #[arg(long, requires = "decoy")]
+    decoy_key: Option<String>,
+
+    /// POST a JSON summary of the run (outcome, files, durations) to this URL when it finishes
+    #[arg(long)]
+    #[serde(skip)]
+    notify_url: Option<String>,
+
+    /// Shell command to run before encoding; paths are passed in LOWKEY_IMAGES, LOWKEY_MESSAGE and LOWKEY_OUTPUT
+    #[arg(long)]
+    #[serde(skip)]
+    pre_encode_cmd: Option<String>,
+
+    /// Shell command to run after encoding succeeded, with the same environment as --pre-encode-cmd
+    #[arg(long)]
+    #[serde(skip)]
+    post_encode_cmd: Option<String>,
+
+    /// Don't show progress bars while encoding into multiple images
+    #[arg(long, default_value = "false")]
+    #[serde(skip)]
+    quiet: bool,
+
+    /// Encryption key (any length, stretched with Argon2id). If not specified, uses default key
+    #[arg(long)]
+    #[serde(default)]
+    key: Option<String>,
+
+    /// Read the encryption key from this inherited file descriptor instead of --key
+    #[arg(long)]
+    #[serde(skip)]
+    key_fd: Option<i32>,
+
+    /// Read the encryption key from this file instead of --key
+    #[arg(long)]
+    #[serde(skip)]
+    key_file: Option<String>,
+
+    /// Take defaults from this [profiles.<name>] table of the config file instead of [defaults] alone
+    #[arg(long)]
+    #[serde(skip)]
+    profile: Option<String>,
 }
 
-fn decode(
+#[derive(Args)]
+struct VerifyArgs {
+    /// Single stego image or HTTPS URL (mutually exclusive with --image-list and --image-dir)
+    #[arg(long)]
     image: Option<String>,
+
+    /// Multiple stego images or HTTPS URLs (space-separated)
+    #[arg(long, num_args = 1..)]
     image_list: Option<Vec<String>>,
+
+    /// Directory containing the stego images
+    #[arg(long)]
     image_dir: Option<String>,
-    output: String,
-    key: String,
-) -> Result<String, String> {
-    if key.is_empty() {
-        return Err("Encryption key cannot be empty".into());
-    }
 
-    let image_param_count = [image.is_some(), image_list.is_some(), image_dir.is_some()]
-        .iter()
-        .filter(|&&x| x)
-        .count();
+    /// WAV file or HTTPS URL written by `encode --audio` (instead of --image)
+    #[arg(long)]
+    audio: Option<String>,
 
-    if image_param_count == 0 {
-        return Err("Must specify one of --image, --image-list, or --image-dir".into());
-    }
+    /// File or object-storage URI holding the message that should come back (mutually exclusive with --sha256)
+    #[arg(long)]
+    message: Option<String>,
 
-    if image_param_count > 1 {
-        return Err("Only one of --image, --image-list, or --image-dir can be specified".into());
-    }
+    /// SHA256 of the message that should come back, in hex
+    #[arg(long)]
+    sha256: Option<String>,
 
-    let images = if let Some(single_image) = image {
-        vec![single_image]
-    } else if let Some(images) = image_list {
-        images
-    } else if let Some(dir) = image_dir {
-        collect_images_from_dir(&dir)
-            .map_err(|e| format!("Failed to read image directory: {}", e))?
+    /// age identity file for a message encoded with --recipient or --recipients-file (repeatable)
+    #[arg(long)]
+    identity: Vec<String>,
+
+    /// Encryption key (any length, stretched with Argon2id). If not specified, uses default key
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Read the encryption key from this inherited file descriptor instead of --key
+    #[arg(long)]
+    key_fd: Option<i32>,
+
+    /// Read the encryption key from this file instead of --key
+    #[arg(long)]
+    key_file: Option<String>,
+
+    /// Take defaults from this [profiles.<name>] table of the config file instead of [defaults] alone
+    #[arg(long)]
+    profile: Option<String>,
+}
+
+#[derive(Args, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DecodeArgs {
+    /// Single input image or HTTPS URL (mutually exclusive with --image-list and --image-dir)
+    #[arg(long)]
+    image: Option<String>,
+
+    /// Multiple input images or HTTPS URLs (space-separated)
+    #[arg(long, num_args = 1..)]
+    image_list: Option<Vec<String>>,
+
+    /// Directory containing input images
+    #[arg(long)]
+    image_dir: Option<String>,
+
+    /// WAV file or HTTPS URL written by `encode --audio` (instead of --image)
+    #[arg(long)]
+    audio: Option<String>,
+
+    /// Also collect images from the subdirectories of --image-dir
+    #[arg(long, default_value = "false")]
+    recursive: bool,
+
+    /// Only use --image-dir images matching this glob, against the file name or, with a "/", the relative path (repeatable)
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Leave out --image-dir images and subdirectories matching this glob (repeatable)
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Symbolic links under --image-dir: "skip" them, use linked "files" only, or "follow" linked directories too [default: files]
+    #[arg(long)]
+    symlinks: Option<String>,
+
+    /// Order of the --image-dir images, which the message is split over in that order: "natural" (img2 before img10), "name", "mtime", "size" or "capacity" (most pixels first) [default: name]
+    #[arg(long)]
+    sort: Option<String>,
+
+    /// File or object-storage URI to write the recovered message to, or - for stdout (mutually exclusive with --to-clipboard)
+    #[arg(long)]
+    output: Option<String>,
+
+    /// Directory to restore a file encoded with --file-info to, under its original name and modification time
+    #[arg(long)]
+    output_dir: Option<String>,
+
+    /// Name to restore the file under in --output-dir instead of its original one (also names a message without file info)
+    #[arg(long)]
+    rename: Option<String>,
+
+    /// What to do when --output-dir already has a file of the restored name: "rename" to "name (1).ext", "overwrite" or "fail" [default: rename]
+    #[arg(long)]
+    on_conflict: Option<String>,
+
+    /// Place the recovered text (or image) onto the clipboard
+    #[arg(long, default_value = "false")]
+    to_clipboard: bool,
+
+    /// age identity file for a message encoded with --recipient or --recipients-file (repeatable)
+    #[arg(long)]
+    identity: Vec<String>,
+
+    /// Decode the message in this slot (from 1) of --image, which `encode --append` put behind the first; `lowkey list` shows them [default: 1]
+    #[arg(long)]
+    #[serde(skip)]
+    slot: Option<usize>,
+
+    /// POST a JSON summary of the run (outcome, files, durations) to this URL when it finishes
+    #[arg(long)]
+    #[serde(skip)]
+    notify_url: Option<String>,
+
+    /// Don't show progress bars while decoding from multiple images
+    #[arg(long, default_value = "false")]
+    #[serde(skip)]
+    quiet: bool,
+
+    /// Encryption key (any length, stretched with Argon2id). If not specified, uses default key
+    #[arg(long)]
+    #[serde(default)]
+    key: Option<String>,
+
+    /// Read the encryption key from this inherited file descriptor instead of --key
+    #[arg(long)]
+    #[serde(skip)]
+    key_fd: Option<i32>,
+
+    /// Read the encryption key from this file instead of --key
+    #[arg(long)]
+    #[serde(skip)]
+    key_file: Option<String>,
+
+    /// Take defaults from this [profiles.<name>] table of the config file instead of [defaults] alone
+    #[arg(long)]
+    #[serde(skip)]
+    profile: Option<String>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    match run(cli.command) {
+        Ok(m) => {
+            if !m.is_empty() {
+                println!("OK: {}", m);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
+
+/// Run `command`, returning the status line to print.
+fn run(command: Commands) -> Result<String, LowkeyError> {
+    Ok(match command {
+        Commands::Encode(mut args) => {
+            let files = count_images(
+                &args.image,
+                &args.image_list,
+                &args.image_dir,
+                &args.dir_scan(),
+            );
+            enable_progress(args.quiet);
+            let notify_url = args.notify_url.clone();
+            notify::run_and_notify(notify_url.as_deref(), "encode", files, || {
+                let config = config::load().map_err(LowkeyError::InvalidInput)?;
+                args.apply_defaults(
+                    &config
+                        .defaults(args.profile.as_deref())
+                        .map_err(LowkeyError::InvalidInput)?,
+                );
+                args.pre_encode_cmd = args.pre_encode_cmd.or(config.hooks.pre_encode);
+                args.post_encode_cmd = args.post_encode_cmd.or(config.hooks.post_encode);
+                if !args.no_encrypt && args.recipient.is_empty() && args.recipients_file.is_empty()
+                {
+                    args.key =
+                        prompt_key_if_interactive(args.key, args.key_fd, &args.key_file, true)?;
+                }
+                encode(*args)
+            })?
+        }
+        Commands::Decode(mut args) => {
+            let files = count_images(
+                &args.image,
+                &args.image_list,
+                &args.image_dir,
+                &args.dir_scan(),
+            );
+            enable_progress(args.quiet);
+            let notify_url = args.notify_url.clone();
+            notify::run_and_notify(notify_url.as_deref(), "decode", files, || {
+                args.apply_defaults(
+                    &config::load()
+                        .map_err(LowkeyError::InvalidInput)?
+                        .defaults(args.profile.as_deref())
+                        .map_err(LowkeyError::InvalidInput)?,
+                );
+                args.key = prompt_key_if_interactive(args.key, args.key_fd, &args.key_file, false)?;
+                decode(args)
+            })?
+        }
+        Commands::Verify(args) => verify(args)?,
+        Commands::Sync {
+            source,
+            dest,
+            message,
+            state_file,
+            key,
+            key_fd,
+            key_file,
+        } => resolve_key(key, key_fd, key_file).and_then(|key| {
+            let message_bytes = read_message(&message)?;
+            sync::sync(&source, &dest, &message_bytes, &key, state_file.as_deref())
+                .map_err(LowkeyError::Other)
+        })?,
+        Commands::Analyze {
+            image,
+            image_list,
+            image_dir,
+            cover,
+            export_planes,
+            json,
+            report,
+        } => resolve_images(image, image_list, image_dir, &DirScan::default())
+            .and_then(|images| fetch::fetch_images(images).map_err(LowkeyError::Io))
+            .and_then(|(images, _downloads)| {
+                let options = analyze::AnalyzeOptions {
+                    cover: cover.as_deref(),
+                    export_planes: export_planes.as_deref(),
+                    json,
+                    report: report.as_deref(),
+                };
+                analyze::analyze(&images, &options)
+            })?,
+        Commands::Bitplane {
+            image,
+            plane,
+            channel,
+            output,
+        } => fetch::fetch_images(vec![image.clone()])
+            .map_err(LowkeyError::Io)
+            .and_then(|(images, _downloads)| {
+                analyze::render_bit_plane(&images[0], plane, channel.as_deref(), &output)?;
+                Ok(format!(
+                    "Wrote bit plane {} of {} to {}",
+                    plane, image, output
+                ))
+            })?,
+        Commands::Diff {
+            original,
+            stego,
+            output,
+            amplify,
+            json,
+        } => {
+            let options = diff::DiffOptions {
+                output: output.as_deref(),
+                amplify,
+                json,
+            };
+            diff::diff(&original, &stego, &options).map_err(LowkeyError::Other)?
+        }
+        Commands::Wipe {
+            image,
+            output,
+            bits,
+            zero,
+        } => fetch::fetch_images(vec![image.clone()])
+            .map_err(LowkeyError::Io)
+            .and_then(|(images, _downloads)| {
+                wipe_file(&images[0], &output, bits, zero)?;
+                Ok(format!("Wiped {} into {}", image, output))
+            })?,
+        Commands::GenerateCover {
+            size,
+            style,
+            message_size,
+            seed,
+            output,
+        } => {
+            let options = cover::CoverOptions {
+                size: size.as_deref(),
+                style: style.as_deref(),
+                message_size,
+                seed,
+            };
+            cover::generate(&output, &options).map_err(LowkeyError::Other)?
+        }
+        Commands::Capacity {
+            image,
+            image_list,
+            image_dir,
+            bits,
+            channels,
+            ecc,
+            skip_transparent,
+            region,
+            region_mask,
+            json,
+        } => {
+            let images = resolve_images(image, image_list, image_dir, &DirScan::default())?;
+            let region = resolve_region(region, region_mask)?;
+            capacity(images, bits, &channels, ecc, skip_transparent, region, json)?
+        }
+        Commands::Inspect { image, json } => inspect(image, json)?,
+        Commands::List {
+            image,
+            json,
+            key,
+            key_fd,
+            key_file,
+        } => {
+            let key = match (key, key_fd, key_file) {
+                (None, None, None) => None,
+                (key, key_fd, key_file) => Some(resolve_key(key, key_fd, key_file)?),
+            };
+            list(image, key.as_deref(), json)?
+        }
+        Commands::Keygen { output, age } => keygen::keygen(&output, age)?,
+        // These commands own stdout, so they don't print a final status line
+        Commands::Rpc {
+            listen,
+            token_file,
+            allow_remote,
+            root,
+            max_kdf_memory,
+            metrics_addr,
+            otlp_endpoint,
+        } => token_file
+            .as_deref()
+            .map(read_token_file)
+            .transpose()
+            .and_then(|token| {
+                crypto::limit_kdf_memory(max_kdf_memory);
+                let policy = rpc::Policy {
+                    key: None,
+                    token,
+                    root: root.map(Into::into),
+                };
+                rpc::serve(
+                    listen.as_deref(),
+                    policy,
+                    allow_remote,
+                    metrics_addr.as_deref(),
+                    otlp_endpoint.as_deref(),
+                )
+                .map_err(LowkeyError::Other)
+            })
+            .map(|_| String::new())?,
+        Commands::Control {
+            socket,
+            key,
+            key_fd,
+            key_file,
+            root,
+            max_kdf_memory,
+        } => resolve_key(key, key_fd, key_file).and_then(|key| {
+            crypto::limit_kdf_memory(max_kdf_memory);
+            control::serve(&socket, key, root.as_deref())
+                .map(|_| String::new())
+                .map_err(LowkeyError::Other)
+        })?,
+        Commands::FilterClean {
+            cover,
+            key,
+            key_fd,
+            key_file,
+        } => resolve_key(key, key_fd, key_file)
+            .and_then(|key| filter::clean(&cover, &key).map(|_| String::new()))?,
+        Commands::FilterSmudge {
+            key,
+            key_fd,
+            key_file,
+        } => resolve_key(key, key_fd, key_file)
+            .and_then(|key| filter::smudge(&key).map(|_| String::new()))?,
+        #[cfg(feature = "gui")]
+        Commands::Gui => gui::run()
+            .map(|_| String::new())
+            .map_err(LowkeyError::Other)?,
+    })
+}
+
+fn check_key(key: &str) -> Result<(), LowkeyError> {
+    if key.is_empty() {
+        return Err(LowkeyError::InvalidInput(
+            "Encryption key cannot be empty".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Pick the key from --key, --key-fd or --key-file, or the default key
+/// without any, and check it.
+fn resolve_key(
+    key: Option<String>,
+    key_fd: Option<i32>,
+    key_file: Option<String>,
+) -> Result<String, LowkeyError> {
+    let given = [key.is_some(), key_fd.is_some(), key_file.is_some()];
+    if given.iter().filter(|&&given| given).count() > 1 {
+        return Err(LowkeyError::InvalidInput(
+            "Only one of --key, --key-fd or --key-file can be specified".into(),
+        ));
+    }
+
+    let key = match (key_fd, key_file) {
+        (Some(fd), _) => read_key_fd(fd)?,
+        (_, Some(path)) => read_key_file(&path)?,
+        _ => key.unwrap_or_else(default_key),
+    };
+
+    check_key(&key)?;
+    Ok(key)
+}
+
+/// Show progress bars for multi-image runs, unless `quiet` or stderr is not
+/// a terminal, where they would only clutter logs.
+fn enable_progress(quiet: bool) {
+    if !quiet && std::io::stderr().is_terminal() {
+        progress::enable();
+    }
+}
+
+/// Ask for the key on the terminal when none was given and stdin is a
+/// terminal; an empty answer keeps the default key. With `confirm`, the key
+/// has to be entered twice.
+fn prompt_key_if_interactive(
+    key: Option<String>,
+    key_fd: Option<i32>,
+    key_file: &Option<String>,
+    confirm: bool,
+) -> Result<Option<String>, LowkeyError> {
+    if key.is_some() || key_fd.is_some() || key_file.is_some() || !std::io::stdin().is_terminal() {
+        return Ok(key);
+    }
+
+    let entered = read_hidden("Key (leave empty for the default key): ")?;
+    if entered.is_empty() {
+        return Ok(None);
+    }
+    if confirm && read_hidden("Repeat key: ")? != entered {
+        return Err(LowkeyError::InvalidInput("Keys do not match".into()));
+    }
+
+    Ok(Some(entered))
+}
+
+/// Read a line from the terminal without echoing it.
+fn read_hidden(prompt: &str) -> Result<String, LowkeyError> {
+    eprint!("{}", prompt);
+
+    let mut line = String::new();
+    let result = {
+        let _echo_off = EchoOff::new();
+        std::io::stdin().read_line(&mut line)
+    };
+    eprintln!();
+
+    result.map_err(|e| LowkeyError::Io(format!("Failed to read key: {}", e)))?;
+    Ok(trim_newline(&line).to_string())
+}
+
+/// Terminal echo turned off while it lives. The saved settings come back
+/// when it is dropped, and from SIGINT, SIGTERM and SIGHUP handlers before
+/// the signal's default action, so an interrupted prompt does not leave the
+/// terminal silent.
+#[cfg(unix)]
+struct EchoOff {
+    saved: libc::termios,
+    handlers: Vec<signal_hook::SigId>,
+}
+
+#[cfg(unix)]
+impl EchoOff {
+    /// `None` when stdin is not a terminal.
+    fn new() -> Option<Self> {
+        let fd = libc::STDIN_FILENO;
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return None;
+        }
+
+        // Installed before echo goes off, so no signal can come in between
+        let handlers = [
+            signal_hook::consts::SIGINT,
+            signal_hook::consts::SIGTERM,
+            signal_hook::consts::SIGHUP,
+        ]
+        .into_iter()
+        .filter_map(|signal| {
+            // tcsetattr and emulate_default_handler are async-signal-safe
+            let restore = move || {
+                unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+                let _ = signal_hook::low_level::emulate_default_handler(signal);
+            };
+            unsafe { signal_hook::low_level::register(signal, restore) }.ok()
+        })
+        .collect();
+
+        let mut silent = saved;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &silent) };
+        Some(Self { saved, handlers })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+        for handler in self.handlers.drain(..) {
+            signal_hook::low_level::unregister(handler);
+        }
+    }
+}
+
+/// Echo stays on elsewhere.
+#[cfg(not(unix))]
+struct EchoOff;
+
+#[cfg(not(unix))]
+impl EchoOff {
+    fn new() -> Option<Self> {
+        None
+    }
+}
+
+/// Read a key from a file, e.g. `--key-file /run/secrets/lowkey`.
+///
+/// One trailing newline is dropped, since secret files usually end with one.
+fn read_key_file(path: &str) -> Result<String, LowkeyError> {
+    let key = std::fs::read_to_string(path)
+        .map_err(|e| LowkeyError::Io(format!("Failed to read key file '{}': {}", path, e)))?;
+    Ok(trim_newline(&key).to_string())
+}
+
+fn read_token_file(path: &str) -> Result<String, LowkeyError> {
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| LowkeyError::Io(format!("Failed to read token file '{}': {}", path, e)))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(LowkeyError::InvalidInput(format!(
+            "Token file '{}' is empty",
+            path
+        )));
+    }
+    Ok(token.to_string())
+}
+
+fn trim_newline(line: &str) -> &str {
+    line.strip_suffix("\r\n")
+        .or_else(|| line.strip_suffix('\n'))
+        .unwrap_or(line)
+}
+
+/// Read a key from an inherited file descriptor, e.g. `--key-fd 3 3</run/secrets/lowkey`.
+///
+/// One trailing newline is dropped, since secret files usually end with one.
+#[cfg(unix)]
+fn read_key_fd(fd: i32) -> Result<String, LowkeyError> {
+    // Going through /dev/fd turns a descriptor that was never passed in into
+    // an ordinary error instead of reading from whatever happens to use it
+    let path = format!("/dev/fd/{}", fd);
+    let key = std::fs::read_to_string(&path).map_err(|e| {
+        LowkeyError::Io(format!(
+            "Failed to read key from file descriptor {}: {}",
+            fd, e
+        ))
+    })?;
+
+    Ok(trim_newline(&key).to_string())
+}
+
+#[cfg(not(unix))]
+fn read_key_fd(_fd: i32) -> Result<String, LowkeyError> {
+    Err(LowkeyError::InvalidInput(
+        "--key-fd is only supported on Unix".into(),
+    ))
+}
+
+fn check_image_params(
+    image: &Option<String>,
+    image_list: &Option<Vec<String>>,
+    image_dir: &Option<String>,
+) -> Result<(), LowkeyError> {
+    let image_param_count = [image.is_some(), image_list.is_some(), image_dir.is_some()]
+        .iter()
+        .filter(|&&x| x)
+        .count();
+
+    if image_param_count == 0 {
+        return Err(LowkeyError::InvalidInput(
+            "Must specify one of --image, --image-list, or --image-dir".into(),
+        ));
+    }
+
+    if image_param_count > 1 {
+        return Err(LowkeyError::InvalidInput(
+            "Only one of --image, --image-list, or --image-dir can be specified".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn encode(args: EncodeArgs) -> Result<String, LowkeyError> {
+    let (status, report) = encode_with_report(args)?;
+    if !report.images.is_empty() {
+        eprintln!("{}", report.summary());
+    }
+    Ok(status)
+}
+
+/// Encode as described by `args`, returning the status line and how
+/// detectable each written image is.
+fn encode_with_report(args: EncodeArgs) -> Result<(String, EncodeReport), LowkeyError> {
+    let scan = args.dir_scan().map_err(LowkeyError::InvalidInput)?;
+    let EncodeArgs {
+        image,
+        image_list,
+        image_dir,
+        audio,
+        message,
+        message_text,
+        message_file,
+        message_dir,
+        from_clipboard,
+        output,
+        output_dir,
+        output_template,
+        preserve_attributes,
+        in_place,
+        backup_suffix,
+        overwrite_payload,
+        append,
+        auto_resize,
+        convert_srgb,
+        strip_metadata,
+        no_sequence_chunk,
+        encrypt_sequence_chunk,
+        png_compression,
+        png_filter,
+        skip_small,
+        redundancy,
+        distribute,
+        bits,
+        channels,
+        match_noise,
+        scatter,
+        adaptive,
+        format,
+        ecc,
+        file_info,
+        paranoid,
+        analyze,
+        min_psnr,
+        min_ssim,
+        max_fill,
+        max_fill_warn,
+        verify,
+        kdf_memory,
+        kdf_iterations,
+        cipher,
+        recipient,
+        recipients_file,
+        no_encrypt,
+        no_compress,
+        pad_to,
+        method,
+        skip_transparent,
+        region,
+        region_mask,
+        no_noise_fill,
+        decoy,
+        decoy_key,
+        pre_encode_cmd,
+        post_encode_cmd,
+        key,
+        key_fd,
+        key_file,
+        ..
+    } = args;
+
+    let key = resolve_key(key, key_fd, key_file)?;
+    if min_psnr.is_some_and(|min_psnr| min_psnr.is_nan() || min_psnr <= 0.0) {
+        return Err(LowkeyError::InvalidInput(
+            "--min-psnr must be a positive number of dB".to_string(),
+        ));
+    }
+    if min_ssim.is_some_and(|min_ssim| !(0.0..=1.0).contains(&min_ssim)) {
+        return Err(LowkeyError::InvalidInput(
+            "--min-ssim must be between 0 and 1".to_string(),
+        ));
+    }
+    if max_fill.is_some_and(|max_fill| max_fill.is_nan() || max_fill <= 0.0 || max_fill > 1.0) {
+        return Err(LowkeyError::InvalidInput(
+            "--max-fill must be above 0 and at most 1".to_string(),
+        ));
+    }
+    let max_fill = max_fill.map(|ratio| FillLimit {
+        ratio,
+        warn_only: max_fill_warn,
+    });
+    // The preset picks the layout, method, padding and encryption itself
+    if paranoid
+        && (match_noise
+            || adaptive
+            || bits.is_some()
+            || channels.is_some()
+            || method.is_some()
+            || no_noise_fill
+            || no_encrypt
+            || !recipient.is_empty()
+            || !recipients_file.is_empty())
+    {
+        return Err(LowkeyError::InvalidInput("--paranoid cannot be used with --match-noise, --adaptive, --bits, --channels, --method, --no-noise-fill, --no-encrypt or recipients".to_string()));
+    }
+    let scatter = scatter || paranoid;
+    let redundancy: Redundancy = redundancy
+        .as_deref()
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let distribute: Distribute = distribute
+        .as_deref()
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or_default();
+    if distribute != Distribute::Fill && redundancy != Redundancy::None {
+        return Err(LowkeyError::InvalidInput(
+            "--distribute cannot be used with --redundancy".to_string(),
+        ));
+    }
+
+    let depth = bits.unwrap_or(1);
+    check_depth(depth)?;
+    if depth != 1 && match_noise {
+        return Err(LowkeyError::InvalidInput(
+            "--bits cannot be used with --match-noise".to_string(),
+        ));
+    }
+    let channels = match &channels {
+        Some(channels) => channels.parse::<Channels>()?,
+        // An opaque cover's alpha is constant, so bits in it would stand out
+        None if paranoid => Channels::RGB,
+        None => Channels::ALL,
+    };
+    if channels != Channels::ALL && match_noise {
+        return Err(LowkeyError::InvalidInput(
+            "--channels cannot be used with --match-noise".to_string(),
+        ));
+    }
+    if scatter && match_noise {
+        return Err(LowkeyError::InvalidInput(
+            "--scatter cannot be used with --match-noise".to_string(),
+        ));
+    }
+    if adaptive && (scatter || match_noise) {
+        return Err(LowkeyError::InvalidInput(
+            "--adaptive cannot be used with --scatter, --match-noise or --paranoid".to_string(),
+        ));
+    }
+    if decoy.is_some() && match_noise {
+        return Err(LowkeyError::InvalidInput(
+            "--decoy cannot be used with --match-noise".to_string(),
+        ));
+    }
+    if skip_transparent && (adaptive || match_noise) {
+        return Err(LowkeyError::InvalidInput(
+            "--skip-transparent cannot be used with --adaptive or --match-noise".to_string(),
+        ));
+    }
+    let region = resolve_region(region, region_mask)?;
+    if region.is_some() && (adaptive || match_noise) {
+        return Err(LowkeyError::InvalidInput(
+            "--region and --region-mask cannot be used with --adaptive or --match-noise"
+                .to_string(),
+        ));
+    }
+    if region.is_some() && (auto_resize || decoy.is_some()) {
+        return Err(LowkeyError::InvalidInput(
+            "--region and --region-mask cannot be used with --auto-resize or --decoy".to_string(),
+        ));
+    }
+    // The message goes behind the decoy in scattered order
+    let layout = if match_noise {
+        Layout::NoiseMatched
+    } else if adaptive {
+        Layout::Adaptive { depth, channels }
+    } else if scatter || decoy.is_some() {
+        Layout::Scattered { depth, channels }
+    } else {
+        Layout::Sequential { depth, channels }
+    };
+
+    let format: Format = format
+        .as_deref()
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let output_template: Option<OutputTemplate> =
+        output_template.as_deref().map(str::parse).transpose()?;
+    if format != Format::Pixels
+        && (layout != Layout::default() || auto_resize || max_fill.is_some())
+    {
+        return Err(LowkeyError::InvalidInput(format!(
+            "--format {} cannot be used with --bits, --channels, --scatter, --adaptive, --match-noise, --paranoid, --decoy, --auto-resize or --max-fill",
+            format
+        )));
+    }
+    // An audio cover takes the place of --image
+    let audio_cover = audio.is_some();
+    if audio_cover {
+        if image.is_some() || image_list.is_some() || image_dir.is_some() {
+            return Err(LowkeyError::InvalidInput(
+                "--audio cannot be used with --image, --image-list or --image-dir".to_string(),
+            ));
+        }
+        if format != Format::Pixels
+            || layout != Layout::default()
+            || auto_resize
+            || analyze
+            || min_psnr.is_some()
+            || min_ssim.is_some()
+            || max_fill.is_some()
+        {
+            return Err(LowkeyError::InvalidInput("--audio cannot be used with --format, --bits, --channels, --scatter, --adaptive, --match-noise, --paranoid, --decoy, --auto-resize, --analyze, --min-psnr, --min-ssim or --max-fill".to_string()));
+        }
+    }
+    if append
+        && (image.is_none()
+            || format != Format::Pixels
+            || !matches!(layout, Layout::Sequential { .. })
+            || auto_resize)
+    {
+        return Err(LowkeyError::InvalidInput(
+            "--append is only used with --image, and cannot be used with --audio, --format, --scatter, --adaptive, --match-noise, --paranoid, --decoy or --auto-resize"
+                .to_string(),
+        ));
+    }
+    let image = image.or(audio);
+
+    let defaults = KdfParams::default();
+    let kdf = KdfParams {
+        memory_kib: kdf_memory.unwrap_or(defaults.memory_kib),
+        iterations: kdf_iterations.unwrap_or(defaults.iterations),
+        ..defaults
+    };
+    kdf.validate()?;
+    let mut recipients = recipient;
+    for path in &recipients_file {
+        recipients.extend(crypto::read_recipients_file(path)?);
+    }
+    if (no_encrypt || !recipients.is_empty())
+        && (kdf_memory.is_some() || kdf_iterations.is_some() || cipher.is_some())
+    {
+        return Err(LowkeyError::InvalidInput("--kdf-memory, --kdf-iterations and --cipher cannot be used with --no-encrypt or recipients".to_string()));
+    }
+    if no_encrypt && !recipients.is_empty() {
+        return Err(LowkeyError::InvalidInput(
+            "--no-encrypt cannot be used with recipients".to_string(),
+        ));
+    }
+    let pad_to: Option<PadTo> = pad_to
+        .as_deref()
+        .map(str::parse)
+        .transpose()?
+        .or(paranoid.then_some(PadTo::PowerOfTwo));
+    if pad_to.is_some() && (no_encrypt || !recipients.is_empty()) {
+        return Err(LowkeyError::InvalidInput(
+            "--pad-to cannot be used with --no-encrypt or recipients".to_string(),
+        ));
+    }
+    if verify && !recipients.is_empty() {
+        return Err(LowkeyError::InvalidInput(
+            "--verify cannot be used with recipients, whose messages only an identity decodes"
+                .to_string(),
+        ));
+    }
+    if pad_to == Some(PadTo::Full) && auto_resize {
+        return Err(LowkeyError::InvalidInput(
+            "--pad-to full cannot be used with --auto-resize".to_string(),
+        ));
+    }
+    if pad_to == Some(PadTo::Full) && match_noise {
+        return Err(LowkeyError::InvalidInput(
+            "--pad-to full cannot be used with --match-noise".to_string(),
+        ));
+    }
+    let cipher: Cipher = cipher
+        .as_deref()
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let file = match (&message, file_info) {
+        (Some(path), true) if !storage::is_object_uri(path) => Some(FileInfo::from_path(path)?),
+        (_, true) => {
+            return Err(LowkeyError::InvalidInput(
+                "--file-info needs --message to name a local file".to_string(),
+            ));
+        }
+        (_, false) => None,
+    };
+    let archive = !message_file.is_empty() || message_dir.is_some();
+    let payload = PayloadOptions {
+        kdf,
+        ecc,
+        file,
+        archive,
+        plain: no_encrypt,
+        compress: !no_compress,
+        cipher,
+        recipients,
+        pad_to,
+        decoy: match (decoy, decoy_key) {
+            (Some(path), Some(key)) => Some(Decoy {
+                message: read_message(&path)?,
+                key,
+            }),
+            _ => None,
+        },
+        noise_fill: !no_noise_fill,
+        method: match method {
+            Some(method) => method.parse()?,
+            None if paranoid => Method::LsbMatch,
+            None => Method::default(),
+        },
+        skip_transparent,
+        region,
+        max_fill,
+        convert_srgb,
+        strip_metadata,
+        sequence_chunk: if no_sequence_chunk {
+            SequenceChunk::Off
+        } else if encrypt_sequence_chunk {
+            SequenceChunk::Encrypted
+        } else {
+            SequenceChunk::Plain
+        },
+        png_encoding: PngEncoding {
+            compression: png_compression
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            filter: png_filter
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+        },
+    };
+
+    check_image_params(&image, &image_list, &image_dir)?;
+
+    let sources = [
+        message.is_some(),
+        message_text.is_some(),
+        !message_file.is_empty(),
+        message_dir.is_some(),
+        from_clipboard,
+    ];
+    if sources.iter().filter(|&&given| given).count() > 1 {
+        return Err(LowkeyError::InvalidInput(
+            "Only one of --message, --message-text, --message-file, --message-dir or --from-clipboard can be specified"
+                .to_string(),
+        ));
+    }
+
+    // Encoding over an earlier stego image loses its message
+    if !overwrite_payload && !append {
+        let covers = resolve_images(image.clone(), image_list.clone(), image_dir.clone(), &scan)?;
+        check_existing_payloads(&covers)?;
+    }
+
+    if in_place {
+        let covers = resolve_images(image.clone(), image_list.clone(), image_dir.clone(), &scan)?;
+        check_in_place(&covers, format, audio_cover)?;
+        if backup_suffix.is_none()
+            && !confirm(&format!(
+                "Overwrite {} cover(s) in place without a backup?",
+                covers.len()
+            ))?
+        {
+            return Err(LowkeyError::InvalidInput(
+                "--in-place overwrites the covers: pass --backup-suffix to keep a copy, or confirm at a terminal"
+                    .to_string(),
+            ));
+        }
+    }
+
+    if image.is_some() {
+        if output.is_none() && !in_place {
+            return Err(LowkeyError::InvalidInput(
+                "--output is required when using --image".to_string(),
+            ));
+        }
+        if output_dir.is_some() {
+            return Err(LowkeyError::InvalidInput(
+                "--output-dir cannot be used with --image (use --output instead)".to_string(),
+            ));
+        }
+        if redundancy != Redundancy::None {
+            return Err(LowkeyError::InvalidInput(
+                "--redundancy is only used with --image-list or --image-dir".to_string(),
+            ));
+        }
+        if output_template.is_some() {
+            return Err(LowkeyError::InvalidInput(
+                "--output-template is only used with --image-list or --image-dir".to_string(),
+            ));
+        }
+        if skip_small {
+            return Err(LowkeyError::InvalidInput(
+                "--skip-small is only used with --image-list or --image-dir".to_string(),
+            ));
+        }
+        if distribute != Distribute::Fill {
+            return Err(LowkeyError::InvalidInput(
+                "--distribute is only used with --image-list or --image-dir".to_string(),
+            ));
+        }
+        if no_sequence_chunk || encrypt_sequence_chunk {
+            return Err(LowkeyError::InvalidInput(
+                "--no-sequence-chunk and --encrypt-sequence-chunk are only used with --image-list or --image-dir".to_string(),
+            ));
+        }
+    } else {
+        if output_dir.is_none() && !in_place {
+            return Err(LowkeyError::InvalidInput(
+                "--output-dir is required when using --image-list or --image-dir".to_string(),
+            ));
+        }
+        if output.is_some() {
+            return Err(LowkeyError::InvalidInput("--output cannot be used with --image-list or --image-dir (use --output-dir instead)".to_string()));
+        }
+
+        if match_noise {
+            return Err(LowkeyError::InvalidInput(
+                "--match-noise is not supported with multiple images yet".to_string(),
+            ));
+        }
+
+        if scatter {
+            return Err(LowkeyError::InvalidInput(
+                "--scatter is not supported with multiple images yet".to_string(),
+            ));
+        }
+
+        if adaptive {
+            return Err(LowkeyError::InvalidInput(
+                "--adaptive is not supported with multiple images yet".to_string(),
+            ));
+        }
+
+        if payload.decoy.is_some() {
+            return Err(LowkeyError::InvalidInput(
+                "--decoy is only supported with --image".to_string(),
+            ));
+        }
+
+        if skip_transparent {
+            return Err(LowkeyError::InvalidInput(
+                "--skip-transparent is only supported with --image".to_string(),
+            ));
+        }
+
+        if payload.region.is_some() {
+            return Err(LowkeyError::InvalidInput(
+                "--region and --region-mask are only supported with --image".to_string(),
+            ));
+        }
+
+        if payload.pad_to == Some(PadTo::Full) {
+            return Err(LowkeyError::InvalidInput(
+                "--pad-to full is only supported with --image".to_string(),
+            ));
+        }
+
+        if format != Format::Pixels {
+            return Err(LowkeyError::InvalidInput(format!(
+                "--format {} is only supported with --image",
+                format
+            )));
+        }
+
+        if paranoid {
+            return Err(LowkeyError::InvalidInput(
+                "--paranoid is not supported with multiple images yet".to_string(),
+            ));
+        }
+
+        if analyze {
+            return Err(LowkeyError::InvalidInput(
+                "--analyze is not supported with multiple images yet (run `lowkey analyze --image-dir` on the output directory)"
+                    .to_string(),
+            ));
+        }
+    }
+
+    let images = resolve_images(image.clone(), image_list.clone(), image_dir.clone(), &scan)
+        .unwrap_or_default()
+        .join("\n");
+    let hook_vars = [
+        ("LOWKEY_IMAGES", images.clone()),
+        (
+            "LOWKEY_MESSAGE",
+            message
+                .clone()
+                .or(message_dir.clone())
+                .unwrap_or_else(|| message_file.join("\n")),
+        ),
+        (
+            "LOWKEY_OUTPUT",
+            match in_place {
+                true => images,
+                false => output.clone().or(output_dir.clone()).unwrap_or_default(),
+            },
+        ),
+    ];
+    // Taken up front, since the pre-encode hook and encoding touch the covers
+    let cover_attributes = if preserve_attributes {
+        resolve_images(image.clone(), image_list.clone(), image_dir.clone(), &scan)?
+            .into_iter()
+            .filter(|cover| !fetch::is_url(cover))
+            .map(|cover| FileAttributes::read(&cover).map(|attributes| (cover, attributes)))
+            .collect::<Result<HashMap<_, _>, _>>()?
+    } else {
+        HashMap::new()
+    };
+
+    if let Some(command) = &pre_encode_cmd {
+        hooks::run("pre-encode", command, &hook_vars).map_err(LowkeyError::Other)?;
+    }
+
+    let message_bytes = if let Some(message_file) = &message {
+        read_message(message_file)?
+    } else if let Some(text) = message_text {
+        text.into_bytes()
+    } else if !message_file.is_empty() {
+        archive::pack(&read_message_entries(&message_file)?)?
+    } else if let Some(dir) = &message_dir {
+        archive::pack(&archive::read_dir(dir)?)?
+    } else if from_clipboard {
+        clipboard::read_text().map_err(LowkeyError::Io)?
+    } else {
+        return Err(LowkeyError::InvalidInput(
+            "Must specify one of --message, --message-text, --message-file, --message-dir or --from-clipboard"
+                .to_string(),
+        ));
+    };
+
+    // Object-storage outputs are written to a local staging directory first
+    // and uploaded once encoding has succeeded.
+    let staging = if output
+        .iter()
+        .chain(output_dir.iter())
+        .any(|path| storage::is_object_uri(path))
+    {
+        Some(ScratchDir::create("upload").map_err(LowkeyError::Io)?)
+    } else {
+        None
+    };
+
+    let local_output = match (&output, &staging) {
+        (Some(out), Some(staging)) => Some(
+            staging
+                .path()
+                .join(storage::object_file_name(out).map_err(LowkeyError::InvalidInput)?)
+                .to_string_lossy()
+                .to_string(),
+        ),
+        (out, _) => out.clone(),
+    };
+    let local_output_dir = match (&output_dir, &staging) {
+        (Some(_), Some(staging)) => Some(staging.path().to_string_lossy().to_string()),
+        (out_dir, _) => out_dir.clone(),
+    };
+
+    // In place, the outputs are written aside and only replace the covers
+    // once every check below has passed
+    let in_place_scratch = match in_place {
+        true => Some(ScratchDir::create("in-place").map_err(LowkeyError::Io)?),
+        false => None,
+    };
+    let (local_output, local_output_dir) = match (&in_place_scratch, &image) {
+        (Some(scratch), Some(cover)) => {
+            let name = Path::new(cover).file_name().unwrap_or_default();
+            let local = scratch.path().join(name).to_string_lossy().to_string();
+            (Some(local), None)
+        }
+        (Some(scratch), None) => (None, Some(scratch.path().to_string_lossy().to_string())),
+        (None, _) => (local_output, local_output_dir),
+    };
+
+    let set = SetOptions {
+        depth,
+        channels,
+        auto_resize,
+        skip_small,
+        redundancy,
+        distribute,
+        output_template,
+    };
+    let result = if let Some(single_image) = image.clone() {
+        fetch::fetch_images(vec![single_image])
+            .map_err(LowkeyError::Io)
+            .and_then(|(images, _downloads)| match format {
+                // Nothing to say about the quality of an image
+                _ if audio_cover => encode_audio_file(
+                    &images[0],
+                    &message_bytes,
+                    local_output.as_ref().unwrap(),
+                    &key,
+                    &payload,
+                )
+                .map(|_| EncodeReport::default()),
+                Format::Pixels if append => append_to_file(
+                    &images[0],
+                    &message_bytes,
+                    local_output.as_ref().unwrap(),
+                    depth,
+                    channels,
+                    &key,
+                    &payload,
+                ),
+                Format::Pixels => encode_from_file(
+                    &images[0],
+                    &message_bytes,
+                    local_output.as_ref().unwrap(),
+                    auto_resize,
+                    layout,
+                    &key,
+                    &payload,
+                ),
+                Format::Jpeg => encode_jpeg_file(
+                    &images[0],
+                    &message_bytes,
+                    local_output.as_ref().unwrap(),
+                    &key,
+                    &payload,
+                ),
+                Format::Chunk => encode_chunk_file(
+                    &images[0],
+                    &message_bytes,
+                    local_output.as_ref().unwrap(),
+                    &key,
+                    &payload,
+                ),
+            })
+    } else if let Some(images) = image_list {
+        fetch::fetch_images(images)
+            .map_err(LowkeyError::Io)
+            .and_then(|(images, _downloads)| {
+                encode_from_files(
+                    &images,
+                    &message_bytes,
+                    local_output_dir.as_ref().unwrap(),
+                    &set,
+                    &key,
+                    &payload,
+                )
+            })
+    } else if let Some(dir) = &image_dir {
+        match collect_images_from_dir(dir, &scan) {
+            Ok(images) => encode_from_files(
+                &images,
+                &message_bytes,
+                local_output_dir.as_ref().unwrap(),
+                &set,
+                &key,
+                &payload,
+            ),
+            Err(e) => Err(e),
+        }
     } else {
         unreachable!()
     };
 
-    decode_from_files(&images, &output, &key)?;
-    Ok(format!("Successfully decoded message to {}", output))
+    if let (Ok(_), true, Some(local)) = (&result, analyze, &local_output) {
+        eprintln!("{}", analyze::report(local)?);
+    }
+
+    // Self-check: don't leave behind an image with anything left to improve.
+    // Every moderate or high score comes with recommendations.
+    let result = match result {
+        Ok(report) if paranoid => match report
+            .images
+            .iter()
+            .find(|image| !image.detectability.recommendations.is_empty())
+        {
+            Some(image) => {
+                if let Some(local) = &local_output {
+                    let _ = std::fs::remove_file(local);
+                }
+                Err(LowkeyError::Other(format!(
+                    "--paranoid self-check failed, output removed: {}",
+                    image.detectability.summary()
+                )))
+            }
+            None => Ok(report),
+        },
+        result => result,
+    };
+
+    // Quality floor: nothing written may have changed its cover more than allowed
+    let result = match result {
+        Ok(report) => match report.images.iter().find_map(|image| {
+            image
+                .quality
+                .check(min_psnr, min_ssim)
+                .err()
+                .map(|e| (image, e))
+        }) {
+            Some((image, e)) => {
+                for image in &report.images {
+                    let _ = std::fs::remove_file(&image.path);
+                }
+                Err(LowkeyError::Other(format!(
+                    "Quality check failed for {}, output removed: {}",
+                    image.path, e
+                )))
+            }
+            None => Ok(report),
+        },
+        result => result,
+    };
+
+    // Round trip: what was written must give back the message, before the
+    // plaintext is let go of
+    let result = match result {
+        Ok(report) if verify => {
+            let written: Vec<String> = match &local_output {
+                Some(local) => vec![local.clone()],
+                None => report
+                    .images
+                    .iter()
+                    .map(|image| image.path.clone())
+                    .collect(),
+            };
+            // An appended message is the last of its image
+            let decoded = match append {
+                true => list_slots(&written[0], None)
+                    .and_then(|slots| decode_slot_from_file(&written[0], slots.len(), &key)),
+                false => decode_message_from_files(&written, &key),
+            };
+            let checked = decoded
+                .and_then(|message| message.open(&[]))
+                .and_then(|message| {
+                    verify::check(
+                        &message.bytes,
+                        &verify::sha256_hex(&message_bytes),
+                        "the output",
+                    )
+                });
+            match checked {
+                Ok(()) => Ok(report),
+                Err(e) => {
+                    for path in &written {
+                        let _ = std::fs::remove_file(path);
+                    }
+                    Err(e.map_message(|e| format!("Verification failed, output removed: {}", e)))
+                }
+            }
+        }
+        result => result,
+    };
+
+    let result = match result {
+        Ok(mut report) if in_place => {
+            let outputs: Vec<(String, String)> = match (&image, &local_output) {
+                (Some(cover), Some(local)) => vec![(cover.clone(), local.clone())],
+                _ => report
+                    .images
+                    .iter()
+                    .map(|image| (image.cover.clone(), image.path.clone()))
+                    .collect(),
+            };
+            // Report the images where they ended up, not where they were staged
+            for image in &mut report.images {
+                image.path = image.cover.clone();
+            }
+            replace_covers(&outputs, backup_suffix.as_deref()).map(|_| report)
+        }
+        result => result,
+    };
+
+    // Last, as reading the outputs back may have touched their access times
+    let result = match result {
+        Ok(report) if preserve_attributes => {
+            let outputs: Vec<(&String, &String)> = match (&image, &local_output) {
+                (Some(cover), _) if in_place => vec![(cover, cover)],
+                (Some(cover), Some(local)) => vec![(cover, local)],
+                _ => report
+                    .images
+                    .iter()
+                    .map(|image| (&image.cover, &image.path))
+                    .collect(),
+            };
+            outputs
+                .into_iter()
+                .filter_map(|(cover, output)| {
+                    cover_attributes
+                        .get(cover)
+                        .map(|attributes| attributes.apply(output))
+                })
+                .collect::<Result<(), _>>()
+                .map(|_| report)
+        }
+        result => result,
+    };
+
+    let result = match (result, &staging) {
+        (Ok(mut report), Some(staging)) => {
+            if let (Some(local), Some(out)) = (&local_output, &output) {
+                // Report the images where they ended up, not where they were staged
+                report.images[0].path = out.clone();
+                storage::upload_file(local, out)
+                    .map(|_| report)
+                    .map_err(LowkeyError::Io)
+            } else {
+                let out_dir = output_dir.as_ref().unwrap();
+                for image in &mut report.images {
+                    let name = Path::new(&image.path).file_name().unwrap_or_default();
+                    image.path = format!(
+                        "{}/{}",
+                        out_dir.trim_end_matches('/'),
+                        name.to_string_lossy()
+                    );
+                }
+                storage::upload_dir(staging.path(), out_dir)
+                    .map(|_| report)
+                    .map_err(LowkeyError::Io)
+            }
+        }
+        (result, _) => result,
+    };
+
+    let result = match (result, &post_encode_cmd) {
+        (Ok(report), Some(command)) => hooks::run("post-encode", command, &hook_vars)
+            .map(|_| report)
+            .map_err(LowkeyError::Other),
+        (result, _) => result,
+    };
+
+    match result {
+        Ok(report) => {
+            let status = if in_place {
+                let backups = match &backup_suffix {
+                    Some(suffix) => format!(" (backups end in {})", suffix),
+                    None => String::new(),
+                };
+                match &image {
+                    Some(cover) => format!("Encoded message into {} in place{}", cover, backups),
+                    None => format!(
+                        "Encoded message into {} covers in place{}",
+                        report.images.len(),
+                        backups
+                    ),
+                }
+            } else if let Some(out) = &output {
+                format!("Encoded message into {}", out)
+            } else if let Some(out_dir) = &output_dir {
+                format!("Encoded message into output directory {}", out_dir)
+            } else {
+                unreachable!()
+            };
+            Ok((status, report))
+        }
+        Err(e) => Err(e.map_message(|e| format!("Failed to encode message: {}", e))),
+    }
+}
+
+fn decode(args: DecodeArgs) -> Result<String, LowkeyError> {
+    let destinations = [
+        args.output.is_some(),
+        args.output_dir.is_some(),
+        args.to_clipboard,
+    ];
+    match destinations.iter().filter(|&&given| given).count() {
+        0 => {
+            return Err(LowkeyError::InvalidInput(
+                "Must specify one of --output, --output-dir or --to-clipboard".to_string(),
+            ));
+        }
+        1 => {}
+        _ => {
+            return Err(LowkeyError::InvalidInput(
+                "Only one of --output, --output-dir or --to-clipboard can be specified".to_string(),
+            ));
+        }
+    }
+    if let Some(dir) = &args.output_dir
+        && storage::is_object_uri(dir)
+    {
+        return Err(LowkeyError::InvalidInput(
+            "--output-dir must be a local directory".to_string(),
+        ));
+    }
+
+    if args.output_dir.is_none() && (args.rename.is_some() || args.on_conflict.is_some()) {
+        return Err(LowkeyError::InvalidInput(
+            "--rename and --on-conflict are only used with --output-dir".to_string(),
+        ));
+    }
+    let on_conflict: OnConflict = args
+        .on_conflict
+        .as_deref()
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or_default();
+
+    let output = args.output.clone();
+    let output_dir = args.output_dir.clone();
+    let rename = args.rename.clone();
+    let DecodedMessage {
+        bytes: message_bytes,
+        file,
+        archive,
+        ..
+    } = decode_message(args)?;
+
+    if archive && output_dir.is_none() {
+        return Err(LowkeyError::InvalidInput(
+            "The message is an archive of several files, use --output-dir to unpack it".to_string(),
+        ));
+    }
+
+    if let Some(dir) = output_dir {
+        if archive {
+            if rename.is_some() {
+                return Err(LowkeyError::InvalidInput(
+                    "--rename cannot be used with an archive of several files".to_string(),
+                ));
+            }
+            let entries = archive::unpack(&message_bytes)?;
+            archive::write_dir(&entries, &dir)?;
+            return Ok(format!(
+                "Successfully decoded {} files to {}",
+                entries.len(),
+                dir
+            ));
+        }
+
+        let file = match (file, rename) {
+            (Some(file), Some(name)) => FileInfo { name, ..file },
+            (Some(file), None) => file,
+            (None, Some(name)) => FileInfo {
+                name,
+                mime: "application/octet-stream".to_string(),
+                modified: None,
+            },
+            (None, None) => {
+                return Err(LowkeyError::InvalidInput(
+                    "The message carries no file name (encode with --file-info or --message-dir), use --output or --rename instead"
+                        .to_string(),
+                ));
+            }
+        };
+        let path = file.restore(&dir, &message_bytes, on_conflict)?;
+        Ok(format!(
+            "Successfully decoded {} ({}) to {}",
+            file.name,
+            file.mime,
+            path.display()
+        ))
+    } else if let Some(output) = output {
+        if output == STDOUT_OUTPUT {
+            let mut stdout = std::io::stdout().lock();
+            stdout
+                .write_all(&message_bytes)
+                .and_then(|_| stdout.flush())
+                .map_err(|e| {
+                    LowkeyError::Io(format!("Failed to write message to stdout: {}", e))
+                })?;
+            // Keep stdout to the message alone
+            eprintln!("OK: Successfully decoded message to stdout");
+            return Ok(String::new());
+        }
+        if storage::is_object_uri(&output) {
+            storage::put(&output, &message_bytes).map_err(LowkeyError::Io)?;
+        } else {
+            write_message_file(&output, &message_bytes)?;
+        }
+        Ok(format!("Successfully decoded message to {}", output))
+    } else {
+        clipboard::write(&message_bytes).map_err(LowkeyError::Io)?;
+        Ok("Successfully decoded message to clipboard".to_string())
+    }
+}
+
+/// The region given by `--region` or `--region-mask`, if either is.
+fn resolve_region(
+    region: Option<String>,
+    region_mask: Option<String>,
+) -> Result<Option<Region>, LowkeyError> {
+    match (region, region_mask) {
+        (Some(_), Some(_)) => Err(LowkeyError::InvalidInput(
+            "Only one of --region and --region-mask can be specified".to_string(),
+        )),
+        (Some(rect), None) => Ok(Some(Region::Rect(rect.parse()?))),
+        (None, Some(path)) => Ok(Some(Region::from_mask_file(&path)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Print the capacity of each image and return the total.
+fn capacity(
+    images: Vec<String>,
+    bits: u8,
+    channels: &str,
+    ecc: bool,
+    skip_transparent: bool,
+    region: Option<Region>,
+    json: bool,
+) -> Result<String, LowkeyError> {
+    let channels = channels.parse::<Channels>()?;
+    let (paths, _downloads) = fetch::fetch_images(images.clone()).map_err(LowkeyError::Io)?;
+    let mut report = capacity_report(
+        &paths,
+        bits,
+        channels,
+        ecc,
+        skip_transparent,
+        region.as_ref(),
+    )?;
+    // Name remote images by their URL rather than the downloaded copy
+    for (image, name) in report.images.iter_mut().zip(images) {
+        image.path = name;
+    }
+
+    if json {
+        let json = serde_json::to_string_pretty(&report).map_err(|e| {
+            LowkeyError::Other(format!("Failed to serialize capacity report: {}", e))
+        })?;
+        println!("{}", json);
+        // Keep stdout valid JSON
+        return Ok(String::new());
+    }
+
+    for image in &report.images {
+        println!(
+            "{}: {}x{}, {} bytes",
+            image.path, image.width, image.height, image.capacity
+        );
+    }
+    Ok(format!(
+        "Total: {} bytes at {} bit(s) per value in {}, after {} bytes of header and encryption overhead{}",
+        report.total,
+        bits,
+        channels,
+        report.overhead,
+        if ecc {
+            " and the error-correction parity"
+        } else {
+            ""
+        }
+    ))
+}
+
+/// Print what the header of `image` says and whether it holds up.
+fn inspect(image: String, json: bool) -> Result<String, LowkeyError> {
+    let (paths, _downloads) = fetch::fetch_images(vec![image.clone()]).map_err(LowkeyError::Io)?;
+    let inspection = inspect_file(&paths[0])?;
+
+    if json {
+        println!("{}", inspection_json(&inspection));
+    } else {
+        if let Some(version) = inspection.version {
+            println!("Protocol version: {}", version);
+        }
+        if let Some(length) = inspection.payload_length {
+            println!("Payload length: {} bytes", length);
+        }
+        if let (Some(depth), Some(channels)) = (inspection.depth, inspection.channels) {
+            println!("Embedding: {} bit(s) per value in {}", depth, channels);
+        }
+        if let Some(compressed) = inspection.compressed {
+            println!("Compressed: {}", if compressed { "yes" } else { "no" });
+        }
+        if let Some(ecc) = inspection.ecc {
+            println!("Error correction: {}", if ecc { "yes" } else { "no" });
+        }
+        if let Some(file_info) = inspection.file_info {
+            println!("File info: {}", if file_info { "yes" } else { "no" });
+        }
+        if let Some(archive) = inspection.archive {
+            println!("Archive: {}", if archive { "yes" } else { "no" });
+        }
+        if let Some(encrypted) = inspection.encrypted {
+            println!("Encrypted: {}", if encrypted { "yes" } else { "no" });
+        }
+        if let Some(cipher) = inspection.cipher {
+            println!("Cipher: {}", cipher);
+        }
+        if let Some(recipients) = inspection.recipients {
+            println!("Age recipients: {}", if recipients { "yes" } else { "no" });
+        }
+        if let Some(padded) = inspection.padded {
+            println!("Padded: {}", if padded { "yes" } else { "no" });
+        }
+        if let Some(method) = inspection.method {
+            println!("Embedding method: {}", method);
+        }
+        if let Some(skipped) = inspection.transparent_skipped {
+            println!(
+                "Transparent pixels: {}",
+                if skipped { "skipped" } else { "used" }
+            );
+        }
+        if let Some(kdf) = inspection.kdf {
+            println!(
+                "Key derivation: Argon2id, {} KiB, {} iterations, parallelism {}",
+                kdf.memory_kib, kdf.iterations, kdf.parallelism
+            );
+        }
+        match inspection.sequence_info {
+            Some((index, total)) => println!("Sequence: image {} of {}", index + 1, total),
+            None => println!("Sequence: none"),
+        }
+        if let Some(needed) = inspection.shards_needed {
+            println!("Shards: any {} of the set rebuild the message", needed);
+        }
+        println!("Capacity: {} bytes", inspection.capacity);
+        for problem in &inspection.problems {
+            println!("Problem: {}", problem);
+        }
+    }
+
+    if inspection.problems.is_empty() {
+        // Keep stdout valid JSON
+        Ok(if json {
+            String::new()
+        } else {
+            format!("{} appears to contain a lowkey message", image)
+        })
+    } else {
+        Err(LowkeyError::NoMessage(format!(
+            "{} has a lowkey header that does not hold up, likely a chance match",
+            image
+        )))
+    }
+}
+
+fn list(image: String, key: Option<&str>, json: bool) -> Result<String, LowkeyError> {
+    let (paths, _downloads) = fetch::fetch_images(vec![image.clone()]).map_err(LowkeyError::Io)?;
+    let slots = list_slots(&paths[0], key)?;
+    if slots.is_empty() {
+        return Err(LowkeyError::NoMessage(format!(
+            "{} holds no lowkey message that can be listed",
+            image
+        )));
+    }
+
+    if json {
+        let slots: Vec<serde_json::Value> = slots
+            .iter()
+            .enumerate()
+            .map(|(i, slot)| {
+                serde_json::json!({
+                    "slot": i + 1,
+                    "version": slot.version,
+                    "payload_length": slot.length,
+                    "compressed": slot.compressed,
+                    "depth": slot.depth,
+                    "channels": slot.channels.to_string(),
+                    "file_info": slot.file,
+                    "archive": slot.archive,
+                    "recipients": slot.recipients,
+                    "encrypted": !slot.plain,
+                    "opens": slot.opens,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(slots));
+        return Ok(String::new());
+    }
+    for (i, slot) in slots.iter().enumerate() {
+        let content = if slot.archive {
+            ", an archive"
+        } else if slot.file {
+            ", a file"
+        } else {
+            ""
+        };
+        let encryption = if slot.plain {
+            "not encrypted"
+        } else if slot.recipients {
+            "encrypted to age recipients"
+        } else {
+            "encrypted with a key"
+        };
+        let compressed = if slot.compressed { ", compressed" } else { "" };
+        let opens = match slot.opens {
+            Some(true) => "; the key opens it",
+            Some(false) => "; the key does not open it",
+            None => "",
+        };
+        println!(
+            "Slot {}: protocol version {}, {} bytes{}{}, {} bit(s) per value in {}, {}{}",
+            i + 1,
+            slot.version,
+            slot.length,
+            compressed,
+            content,
+            slot.depth,
+            slot.channels,
+            encryption,
+            opens
+        );
+    }
+    Ok(format!("{} holds {} message(s)", image, slots.len()))
+}
+
+/// The inspect report as JSON, as printed by `inspect --json` and returned over RPC.
+fn inspection_json(inspection: &Inspection) -> serde_json::Value {
+    let sequence = inspection
+        .sequence_info
+        .map(|(index, total)| serde_json::json!({ "index": index, "total": total }));
+    let kdf = inspection.kdf.map(|kdf| {
+        serde_json::json!({
+            "memory_kib": kdf.memory_kib,
+            "iterations": kdf.iterations,
+            "parallelism": kdf.parallelism,
+        })
+    });
+    serde_json::json!({
+        "version": inspection.version,
+        "payload_length": inspection.payload_length,
+        "depth": inspection.depth,
+        "channels": inspection.channels.map(|channels| channels.to_string()),
+        "compressed": inspection.compressed,
+        "ecc": inspection.ecc,
+        "file_info": inspection.file_info,
+        "archive": inspection.archive,
+        "encrypted": inspection.encrypted,
+        "cipher": inspection.cipher.map(|cipher| cipher.to_string()),
+        "recipients": inspection.recipients,
+        "padded": inspection.padded,
+        "method": inspection.method.map(|method| method.to_string()),
+        "transparent_skipped": inspection.transparent_skipped,
+        "kdf": kdf,
+        "sequence": sequence,
+        "shards_needed": inspection.shards_needed,
+        "capacity": inspection.capacity,
+        "problems": inspection.problems,
+    })
+}
+
+/// Read a message from a local file or an object-storage URI.
+/// Read each --message-file into an archive entry named after the file.
+fn read_message_entries(paths: &[String]) -> Result<Vec<archive::Entry>, LowkeyError> {
+    let mut entries: Vec<archive::Entry> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = if storage::is_object_uri(path) {
+            storage::object_file_name(path).map_err(LowkeyError::InvalidInput)?
+        } else {
+            FileInfo::from_path(path)?.name
+        };
+        if entries.iter().any(|entry| entry.path == name) {
+            return Err(LowkeyError::InvalidInput(format!(
+                "More than one --message-file is named '{}'",
+                name
+            )));
+        }
+        entries.push(archive::Entry {
+            path: name,
+            data: read_message(path)?,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_message(path: &str) -> Result<Vec<u8>, LowkeyError> {
+    if storage::is_object_uri(path) {
+        storage::get(path).map_err(LowkeyError::Io)
+    } else {
+        read_message_file(path)
+    }
+}
+
+/// Decode the message described by `args` and check it against --message
+/// or --sha256.
+fn verify(args: VerifyArgs) -> Result<String, LowkeyError> {
+    let VerifyArgs {
+        image,
+        image_list,
+        image_dir,
+        audio,
+        message,
+        sha256,
+        identity,
+        key,
+        key_fd,
+        key_file,
+        profile,
+    } = args;
+    let key_file = match key_file {
+        None if key.is_none() && key_fd.is_none() => config::load()
+            .map_err(LowkeyError::InvalidInput)?
+            .defaults(profile.as_deref())
+            .map_err(LowkeyError::InvalidInput)?
+            .key_file(),
+        key_file => key_file,
+    };
+    let expected = match (&message, &sha256) {
+        (Some(message), None) => verify::sha256_hex(&read_message(message)?),
+        (None, Some(sha256)) => verify::parse_sha256(sha256)?,
+        _ => {
+            return Err(LowkeyError::InvalidInput(
+                "Must specify one of --message or --sha256".to_string(),
+            ));
+        }
+    };
+    let key = prompt_key_if_interactive(key, key_fd, &key_file, false)?;
+    let source = image
+        .clone()
+        .or(audio.clone())
+        .or(image_dir.clone())
+        .unwrap_or_else(|| "the images".to_string());
+
+    let decoded = decode_message(DecodeArgs {
+        image,
+        image_list,
+        image_dir,
+        audio,
+        identity,
+        key,
+        key_fd,
+        key_file,
+        ..Default::default()
+    })?;
+    verify::check(&decoded.bytes, &expected, &source)?;
+    Ok(format!(
+        "{} holds the message (SHA256 {})",
+        source, expected
+    ))
+}
+
+/// Recover the message described by `args` without writing it anywhere.
+fn decode_message(args: DecodeArgs) -> Result<DecodedMessage, LowkeyError> {
+    let scan = args.dir_scan().map_err(LowkeyError::InvalidInput)?;
+    let DecodeArgs {
+        image,
+        image_list,
+        image_dir,
+        audio,
+        identity,
+        slot,
+        key,
+        key_fd,
+        key_file,
+        ..
+    } = args;
+
+    let key = resolve_key(key, key_fd, key_file)?;
+    if slot.is_some() && (image.is_none() || audio.is_some()) {
+        return Err(LowkeyError::InvalidInput(
+            "--slot is only used with --image".to_string(),
+        ));
+    }
+
+    let images = match audio {
+        Some(_) if image.is_some() || image_list.is_some() || image_dir.is_some() => {
+            return Err(LowkeyError::InvalidInput(
+                "--audio cannot be used with --image, --image-list or --image-dir".to_string(),
+            ));
+        }
+        Some(audio) => vec![audio],
+        None => resolve_images(image, image_list, image_dir, &scan)?,
+    };
+    let (images, _downloads) = fetch::fetch_images(images).map_err(LowkeyError::Io)?;
+    let mut message = match slot {
+        Some(slot) => decode_slot_from_file(&images[0], slot, &key)?,
+        None => decode_message_from_files(&images, &key)?,
+    };
+
+    if message.sealed.is_some() {
+        message = message.open(&identity)?;
+    } else if !identity.is_empty() {
+        // Before the recipients flag, age files were encrypted with the key
+        // on top
+        message.bytes = crypto::decrypt_with_identities(&message.bytes, &identity)?;
+    }
+    Ok(message)
+}
+
+/// Number of images named by --image / --image-list / --image-dir, for reporting.
+fn count_images(
+    image: &Option<String>,
+    image_list: &Option<Vec<String>>,
+    image_dir: &Option<String>,
+    scan: &Result<DirScan, String>,
+) -> usize {
+    if let Some(images) = image_list {
+        images.len()
+    } else if let (Some(dir), Ok(scan)) = (image_dir, scan) {
+        collect_images_from_dir(dir, scan).map_or(0, |images| images.len())
+    } else {
+        usize::from(image.is_some())
+    }
+}
+
+/// The options that pick and order images from --image-dir.
+fn dir_scan(
+    recursive: bool,
+    include: &[String],
+    exclude: &[String],
+    symlinks: &Option<String>,
+    sort: &Option<String>,
+) -> Result<DirScan, String> {
+    Ok(DirScan {
+        recursive,
+        include: include.to_vec(),
+        exclude: exclude.to_vec(),
+        symlinks: symlinks
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        sort: sort
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+impl EncodeArgs {
+    fn dir_scan(&self) -> Result<DirScan, String> {
+        dir_scan(
+            self.recursive,
+            &self.include,
+            &self.exclude,
+            &self.symlinks,
+            &self.sort,
+        )
+    }
+
+    /// Fill in what the command line leaves open from the config file.
+    fn apply_defaults(&mut self, defaults: &Defaults) {
+        if self.key.is_none() && self.key_fd.is_none() && self.key_file.is_none() {
+            self.key_file = defaults.key_file();
+        }
+        // --bits and --channels only go with the plain pixel layout
+        let pixels = self
+            .format
+            .as_deref()
+            .is_none_or(|format| format == "pixels");
+        if pixels && self.audio.is_none() && !self.match_noise && !self.paranoid {
+            self.bits = self.bits.or(defaults.bits);
+            self.channels = self.channels.clone().or(defaults.channels.clone());
+        }
+        if self.output.is_none() && self.output_dir.is_none() {
+            self.output = self
+                .image
+                .as_ref()
+                .and_then(|cover| defaults.output_for(cover));
+        }
+        self.no_compress |= defaults.compress == Some(false);
+    }
+}
+
+impl DecodeArgs {
+    fn dir_scan(&self) -> Result<DirScan, String> {
+        dir_scan(
+            self.recursive,
+            &self.include,
+            &self.exclude,
+            &self.symlinks,
+            &self.sort,
+        )
+    }
+
+    /// Fill in what the command line leaves open from the config file.
+    fn apply_defaults(&mut self, defaults: &Defaults) {
+        if self.key.is_none() && self.key_fd.is_none() && self.key_file.is_none() {
+            self.key_file = defaults.key_file();
+        }
+    }
+}
+
+/// Refuse local covers that already hold a lowkey message (see
+/// `find_existing_payload`), naming each.
+fn check_existing_payloads(covers: &[String]) -> Result<(), LowkeyError> {
+    let found: Vec<String> = covers
+        .iter()
+        .filter(|cover| !fetch::is_url(cover) && !storage::is_object_uri(cover))
+        .filter_map(|cover| {
+            find_existing_payload(cover).map(|payload| format!("{} holds {}", cover, payload))
+        })
+        .collect();
+    if found.is_empty() {
+        return Ok(());
+    }
+    Err(LowkeyError::InvalidInput(format!(
+        "Encoding would destroy what is already hidden: {}; pass --overwrite-payload to encode anyway",
+        found.join(", ")
+    )))
+}
+
+/// Whether every one of `covers` can be overwritten with its stego version:
+/// a local file that is written back in its own format.
+fn check_in_place(covers: &[String], format: Format, audio: bool) -> Result<(), LowkeyError> {
+    let extensions: &[&str] = match format {
+        _ if audio => &["wav"],
+        Format::Pixels => &["png", "webp", "bmp", "tif", "tiff"],
+        Format::Jpeg => &["jpg", "jpeg"],
+        Format::Chunk => &["png"],
+    };
+    let mut names = HashSet::new();
+    for cover in covers {
+        if fetch::is_url(cover) || storage::is_object_uri(cover) {
+            return Err(LowkeyError::InvalidInput(format!(
+                "--in-place can only overwrite local covers, not {}",
+                cover
+            )));
+        }
+        let extension = Path::new(cover)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if !extensions.contains(&extension.as_str()) {
+            return Err(LowkeyError::InvalidInput(format!(
+                "--in-place would change the format of {} (it overwrites {} covers only)",
+                cover,
+                extensions.join(", ")
+            )));
+        }
+        // The outputs are staged under the covers' file names
+        if !names.insert(Path::new(cover).file_name()) {
+            return Err(LowkeyError::InvalidInput(format!(
+                "--in-place needs covers with distinct file names, but {} appears twice",
+                cover
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Replace each cover with its staged output, after copying it to its name
+/// with `backup_suffix` if given. No backup is overwritten.
+fn replace_covers(
+    outputs: &[(String, String)],
+    backup_suffix: Option<&str>,
+) -> Result<(), LowkeyError> {
+    if let Some(suffix) = backup_suffix {
+        for (cover, _) in outputs {
+            let backup = format!("{}{}", cover, suffix);
+            if Path::new(&backup).exists() {
+                return Err(LowkeyError::Io(format!(
+                    "Backup {} already exists, no cover was overwritten",
+                    backup
+                )));
+            }
+        }
+    }
+    for (cover, staged) in outputs {
+        if let Some(suffix) = backup_suffix {
+            let backup = format!("{}{}", cover, suffix);
+            std::fs::copy(cover, &backup).map_err(|e| {
+                LowkeyError::Io(format!("Failed to back up {} to {}: {}", cover, backup, e))
+            })?;
+        }
+        let mut staged_file = std::fs::File::open(staged)
+            .map_err(|e| LowkeyError::Io(format!("Failed to read {}: {}", staged, e)))?;
+        write_atomically(cover, |writer| {
+            std::io::copy(&mut staged_file, writer)
+                .map(|_| ())
+                .map_err(|e| LowkeyError::Io(format!("Failed to write {}: {}", cover, e)))
+        })?;
+    }
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal; without one, the answer is no.
+fn confirm(question: &str) -> Result<bool, LowkeyError> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("{} [y/N] ", question);
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| LowkeyError::Io(format!("Failed to read answer: {}", e)))?;
+    Ok(matches!(
+        trim_newline(&line).to_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// Turn the mutually exclusive --image / --image-list / --image-dir options into a list of paths.
+fn resolve_images(
+    image: Option<String>,
+    image_list: Option<Vec<String>>,
+    image_dir: Option<String>,
+    scan: &DirScan,
+) -> Result<Vec<String>, LowkeyError> {
+    check_image_params(&image, &image_list, &image_dir)?;
+
+    if let Some(single_image) = image {
+        Ok(vec![single_image])
+    } else if let Some(images) = image_list {
+        Ok(images)
+    } else if let Some(dir) = image_dir {
+        collect_images_from_dir(&dir, scan)
+            .map_err(|e| LowkeyError::Io(format!("Failed to read image directory: {}", e)))
+    } else {
+        unreachable!()
+    }
 }
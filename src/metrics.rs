@@ -0,0 +1,334 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::trace;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long a scrape may take to send its whole request, and to take each
+/// write of the response
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most of a request read; the rest of a longer one is ignored
+const MAX_REQUEST_BYTES: u64 = 8 * 1024;
+
+/// Scrapes served at once, each on its own thread; more are closed as soon
+/// as they are accepted
+const MAX_CONNECTIONS: usize = 16;
+
+/// Process-wide metrics.
+///
+/// Counters are always updated, which costs next to nothing for one-shot CLI
+/// runs; they are only exposed when a long-running mode starts `serve`.
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+struct Histogram {
+    /// Cumulative counts per bucket in `LATENCY_BUCKETS`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: [0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+struct Registry {
+    requests: BTreeMap<String, u64>,
+    failures: BTreeMap<(String, String), u64>,
+    bytes_embedded: u64,
+    stages: BTreeMap<String, Histogram>,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            requests: BTreeMap::new(),
+            failures: BTreeMap::new(),
+            bytes_embedded: 0,
+            stages: BTreeMap::new(),
+        }
+    }
+}
+
+fn with_registry<T>(f: impl FnOnce(&mut Registry) -> T) -> T {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut registry)
+}
+
+pub fn record_request(method: &str) {
+    with_registry(|r| *r.requests.entry(method.to_string()).or_insert(0) += 1);
+}
+
+pub fn record_failure(method: &str, class: &str) {
+    with_registry(|r| {
+        *r.failures
+            .entry((method.to_string(), class.to_string()))
+            .or_insert(0) += 1
+    });
+}
+
+pub fn record_bytes_embedded(count: usize) {
+    with_registry(|r| r.bytes_embedded += count as u64);
+}
+
+/// Run `f` and record how long it took under `stage`.
+///
+/// The stage is also recorded as a span when a trace is active.
+pub fn time_stage<T>(stage: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = trace::span(stage, f);
+    let seconds = start.elapsed().as_secs_f64();
+
+    with_registry(|r| {
+        r.stages
+            .entry(stage.to_string())
+            .or_insert_with(Histogram::new)
+            .observe(seconds)
+    });
+
+    result
+}
+
+/// Total time spent in each stage so far, in seconds.
+pub fn stage_totals() -> BTreeMap<String, f64> {
+    with_registry(|r| {
+        r.stages
+            .iter()
+            .map(|(stage, histogram)| (stage.clone(), histogram.sum))
+            .collect()
+    })
+}
+
+/// Render all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    with_registry(|r| {
+        let mut out = String::new();
+
+        out.push_str("# HELP lowkey_requests_total Requests received, by method.\n");
+        out.push_str("# TYPE lowkey_requests_total counter\n");
+        for (method, count) in &r.requests {
+            out.push_str(&format!(
+                "lowkey_requests_total{{method=\"{}\"}} {}\n",
+                escape(method),
+                count
+            ));
+        }
+
+        out.push_str(
+            "# HELP lowkey_request_failures_total Failed requests, by method and error class.\n",
+        );
+        out.push_str("# TYPE lowkey_request_failures_total counter\n");
+        for ((method, class), count) in &r.failures {
+            out.push_str(&format!(
+                "lowkey_request_failures_total{{method=\"{}\",class=\"{}\"}} {}\n",
+                escape(method),
+                escape(class),
+                count
+            ));
+        }
+
+        out.push_str("# HELP lowkey_bytes_embedded_total Message bytes embedded into images.\n");
+        out.push_str("# TYPE lowkey_bytes_embedded_total counter\n");
+        out.push_str(&format!(
+            "lowkey_bytes_embedded_total {}\n",
+            r.bytes_embedded
+        ));
+
+        out.push_str(
+            "# HELP lowkey_stage_duration_seconds Time spent in each encode/decode stage.\n",
+        );
+        out.push_str("# TYPE lowkey_stage_duration_seconds histogram\n");
+        for (stage, histogram) in &r.stages {
+            let stage = escape(stage);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                out.push_str(&format!(
+                    "lowkey_stage_duration_seconds_bucket{{stage=\"{}\",le=\"{}\"}} {}\n",
+                    stage, bound, count
+                ));
+            }
+            out.push_str(&format!(
+                "lowkey_stage_duration_seconds_bucket{{stage=\"{}\",le=\"+Inf\"}} {}\n",
+                stage, histogram.count
+            ));
+            out.push_str(&format!(
+                "lowkey_stage_duration_seconds_sum{{stage=\"{}\"}} {}\n",
+                stage, histogram.sum
+            ));
+            out.push_str(&format!(
+                "lowkey_stage_duration_seconds_count{{stage=\"{}\"}} {}\n",
+                stage, histogram.count
+            ));
+        }
+
+        out
+    })
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `GET /metrics` on `addr` from a background thread.
+pub fn serve(addr: &str) -> Result<(), String> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| format!("Failed to listen for metrics on '{}': {}", addr, e))?;
+    eprintln!("Serving metrics on http://{}/metrics", addr);
+
+    std::thread::spawn(move || {
+        let open = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming().flatten() {
+            if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                open.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            let open = Arc::clone(&open);
+            std::thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, CONNECTION_TIMEOUT) {
+                    eprintln!("Metrics request failed: {}", e);
+                }
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// A stream whose reads fail once `deadline` has passed, however slowly
+/// the bytes before it trickled in.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+fn handle_connection(stream: TcpStream, timeout: Duration) -> std::io::Result<()> {
+    stream.set_write_timeout(Some(timeout))?;
+    let stream = DeadlineStream {
+        stream,
+        deadline: Instant::now() + timeout,
+    };
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Drain the headers; the request body (if any) is ignored
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render()),
+        _ => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+    };
+
+    let mut stream = reader.into_inner().into_inner().stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new();
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        histogram.observe(60.0);
+
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[5], 2);
+        assert_eq!(histogram.buckets[LATENCY_BUCKETS.len() - 1], 2);
+        assert_eq!(histogram.count, 3);
+    }
+
+    #[test]
+    fn test_connection_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+            // A header that never ends, a byte at a time
+            for _ in 0..40 {
+                if stream.write_all(b"x").is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(25));
+            }
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        let error = handle_connection(stream, Duration::from_millis(200)).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+        ));
+        assert!(started.elapsed() < Duration::from_millis(600));
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_render_contains_recorded_metrics() {
+        record_request("test-method");
+        record_failure("test-method", "invalid_params");
+        time_stage("test-stage", || ());
+
+        let text = render();
+        assert!(text.contains("lowkey_requests_total{method=\"test-method\"}"));
+        assert!(text.contains(
+            "lowkey_request_failures_total{method=\"test-method\",class=\"invalid_params\"} 1"
+        ));
+        assert!(text.contains("lowkey_stage_duration_seconds_count{stage=\"test-stage\"} 1"));
+    }
+}
@@ -0,0 +1,101 @@
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use lowkey::error::LowkeyError;
+
+/// A slow webhook must not hold up the job that is reporting to it
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run `f` and, if `url` is set, POST a JSON summary of the run to it.
+///
+/// `files` is the number of images the run works on. Delivery problems are
+/// reported on stderr but never change the result of the run.
+pub fn run_and_notify(
+    url: Option<&str>,
+    command: &str,
+    files: usize,
+    f: impl FnOnce() -> Result<String, LowkeyError>,
+) -> Result<String, LowkeyError> {
+    let Some(url) = url else {
+        return f();
+    };
+
+    let start = Instant::now();
+    let result = f();
+    let summary = summary(
+        command,
+        files,
+        &result,
+        start.elapsed().as_secs_f64(),
+        metrics::stage_totals(),
+    );
+
+    if let Err(e) = post(url, &summary) {
+        eprintln!("Failed to send notification to '{}': {}", url, e);
+    }
+
+    result
+}
+
+fn summary(
+    command: &str,
+    files: usize,
+    result: &Result<String, LowkeyError>,
+    duration_seconds: f64,
+    stage_durations_seconds: BTreeMap<String, f64>,
+) -> Value {
+    let (message, error) = match result {
+        Ok(message) => (Some(message), None),
+        Err(e) => (None, Some(e.message())),
+    };
+
+    json!({
+        "command": command,
+        "success": result.is_ok(),
+        "message": message,
+        "error": error,
+        "files": files,
+        "duration_seconds": duration_seconds,
+        "stage_durations_seconds": stage_durations_seconds,
+    })
+}
+
+fn post(url: &str, summary: &Value) -> Result<(), String> {
+    ureq::post(url)
+        .config()
+        .timeout_global(Some(NOTIFY_TIMEOUT))
+        .build()
+        .header("Content-Type", "application/json")
+        .send(summary.to_string())
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let stages = BTreeMap::from([("embed".to_string(), 0.5)]);
+
+        let success = summary("encode", 3, &Ok("done".to_string()), 1.5, stages.clone());
+        assert_eq!(success["success"], true);
+        assert_eq!(success["message"], "done");
+        assert_eq!(success["error"], Value::Null);
+        assert_eq!(success["files"], 3);
+        assert_eq!(success["stage_durations_seconds"]["embed"], 0.5);
+
+        let failure = summary(
+            "decode",
+            1,
+            &Err(LowkeyError::Crypto("bad key".to_string())),
+            0.1,
+            stages,
+        );
+        assert_eq!(failure["success"], false);
+        assert_eq!(failure["error"], "bad key");
+    }
+}
@@ -0,0 +1,164 @@
+use std::cell::Cell;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Characters per bar
+const BAR_WIDTH: usize = 20;
+
+/// Redraw at most this often, so that progress costs nothing next to the work
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Values between looking at the clock, which per value would show in decoding
+const DRAW_STEP: usize = 1 << 14;
+
+/// Whether progress is drawn. Off until `enable` is called, so library users
+/// and the RPC and sync modes never see it.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Draw progress bars on stderr from now on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Progress of a multi-image encode or decode: an overall bar for the
+/// channel values of the whole message and one for the current image.
+///
+/// Units are channel values, which is what both the embedder and the bit
+/// reader step through; only the ratios are shown. Everything takes `&self`
+/// so that the closures loading images can share it. Without `enable` it
+/// only counts.
+pub struct Progress {
+    label: &'static str,
+    /// Name of each image, in the order they are used
+    names: Vec<String>,
+    total: Cell<usize>,
+    done: Cell<usize>,
+    /// Index, values done and values in the current image
+    image: Cell<Option<(usize, usize, usize)>>,
+    drawn_at: Cell<Option<Instant>>,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, names: Vec<String>) -> Self {
+        Self {
+            label,
+            names,
+            total: Cell::new(0),
+            done: Cell::new(0),
+            image: Cell::new(None),
+            drawn_at: Cell::new(None),
+        }
+    }
+
+    /// Set how many values the whole message takes.
+    pub fn set_total(&self, total: usize) {
+        self.total.set(total);
+        self.draw(false);
+    }
+
+    /// Move on to the `index`th image, of which `total` values will be used,
+    /// or fewer if the message ends in it.
+    pub fn start_image(&self, index: usize, total: usize) {
+        let left = self.total.get().saturating_sub(self.done.get());
+        self.image.set(Some((index, 0, total.min(left))));
+        self.draw(true);
+    }
+
+    /// Count `values` more values as done, in the message and the current image.
+    pub fn advance(&self, values: usize) {
+        let done = self.done.get();
+        self.done.set(done + values);
+        if let Some((index, done, total)) = self.image.get() {
+            self.image.set(Some((index, done + values, total)));
+        }
+        if done / DRAW_STEP != (done + values) / DRAW_STEP {
+            self.draw(false);
+        }
+    }
+
+    /// Print a line of its own above the bars.
+    pub fn message(&self, message: &str) {
+        if self.drawn_at.get().is_some() {
+            eprint!("\r\x1b[2K");
+        }
+        eprintln!("{}", message);
+        if self.drawn_at.get().is_some() {
+            self.draw(true);
+        }
+    }
+
+    fn draw(&self, force: bool) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let now = Instant::now();
+        if !force
+            && self
+                .drawn_at
+                .get()
+                .is_some_and(|drawn_at| now - drawn_at < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.drawn_at.set(Some(now));
+
+        let mut line = format!("{} {}", self.label, bar(self.done.get(), self.total.get()));
+        if let Some((index, done, total)) = self.image.get() {
+            let name = self.names.get(index).map_or("", String::as_str);
+            line.push_str(&format!(
+                "  image {}/{} {} {}",
+                index + 1,
+                self.names.len(),
+                name,
+                bar(done, total)
+            ));
+        }
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "\r\x1b[2K{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+/// Leave the last state on screen, also when the run failed half-way.
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.drawn_at.get().is_some() {
+            self.draw(true);
+            eprintln!();
+        }
+    }
+}
+
+fn bar(done: usize, total: usize) -> String {
+    let ratio = if total == 0 {
+        0.0
+    } else {
+        (done as f64 / total as f64).min(1.0)
+    };
+    let filled = (ratio * BAR_WIDTH as f64).round() as usize;
+    format!(
+        "[{}{}] {:>3}%",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        (ratio * 100.0).floor() as usize
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_total_capped_at_message_end() {
+        let progress = Progress::new("Decoding", vec!["a.png".into(), "b.png".into()]);
+        progress.set_total(100);
+        progress.start_image(0, 80);
+        progress.advance(80);
+        progress.start_image(1, 80);
+        assert_eq!(progress.image.get(), Some((1, 0, 20)));
+
+        assert_eq!(bar(5, 20), format!("[#####{}]  25%", "-".repeat(15)));
+        assert_eq!(bar(0, 0), format!("[{}]   0%", "-".repeat(20)));
+    }
+}
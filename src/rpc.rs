@@ -0,0 +1,708 @@
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::img::codec::{inspect_file, message_capacity_from_files};
+use crate::img::io::DirScan;
+use crate::service::{self, Listener};
+use crate::{
+    DecodeArgs, EncodeArgs, STDOUT_OUTPUT, decode, decode_message, encode_with_report, fetch,
+    inspection_json, resolve_images,
+};
+use crate::{metrics, trace};
+
+// Standard JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Server-defined error code for operations that were understood but failed
+const OPERATION_FAILED: i64 = -32000;
+/// Server-defined error code for requests without the server's token
+const UNAUTHORIZED: i64 = -32001;
+
+/// Longest request line read; the connection is closed after a longer one,
+/// which would otherwise be buffered whole before the token is checked
+const MAX_REQUEST_BYTES: usize = 1024 * 1024;
+
+/// Methods `dispatch` answers; the rest are counted as "unknown" so clients
+/// cannot add metric series
+const METHODS: [&str; 4] = ["encode", "decode", "capacity", "inspect"];
+
+/// Params that name files, which must be in the server's root
+const PATH_PARAMS: [&str; 13] = [
+    "image",
+    "image_list",
+    "image_dir",
+    "audio",
+    "message",
+    "message_file",
+    "message_dir",
+    "output",
+    "output_dir",
+    "region_mask",
+    "decoy",
+    "recipients_file",
+    "identity",
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+    /// W3C trace context of the caller, so lowkey's spans join its trace
+    #[serde(default)]
+    traceparent: Option<String>,
+    /// Secret shared with a server started with a token
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// What requests may do, for servers that take them from other processes
+/// or hosts.
+#[derive(Default)]
+pub struct Policy {
+    /// Key encode and decode use; requests may not pass their own
+    pub key: Option<String>,
+    /// Secret every request must carry as its `token` member
+    pub token: Option<String>,
+    /// Directory every file a request names must be in, with relative
+    /// paths taken from it
+    pub root: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ImagesParams {
+    image: Option<String>,
+    image_list: Option<Vec<String>>,
+    image_dir: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InspectParams {
+    image: String,
+}
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Serve line-delimited JSON-RPC 2.0 requests.
+///
+/// Every request is a single line of JSON and gets a single line of JSON back.
+/// Requests without an `id` are notifications and get no response. Progress
+/// messages keep going to stderr, so responses are the only thing written to
+/// the connection.
+///
+/// Requests are read from stdin unless a listening socket is available: one
+/// passed by systemd socket activation, or one bound to `listen`. Each socket
+/// connection is served on its own thread.
+///
+/// Requests only carry the client's say on which files are read and written,
+/// so a socket that other hosts can reach is refused unless `policy` has a
+/// token or `allow_remote` is set, and socket clients are held to the
+/// policy's root, or to the working directory without one.
+///
+/// On SIGTERM or SIGINT no new requests are accepted, in-flight requests are
+/// allowed to finish, and the process exits.
+///
+/// With `metrics_addr`, Prometheus metrics are served on `http://<addr>/metrics`.
+/// With `otlp_endpoint` (or `OTEL_EXPORTER_OTLP_ENDPOINT`), each request is
+/// exported as a trace, with a span per encode/decode stage.
+pub fn serve(
+    listen: Option<&str>,
+    mut policy: Policy,
+    allow_remote: bool,
+    metrics_addr: Option<&str>,
+    otlp_endpoint: Option<&str>,
+) -> Result<(), String> {
+    if let Some(addr) = metrics_addr {
+        metrics::serve(addr)?;
+    }
+
+    let otlp_endpoint = otlp_endpoint
+        .map(str::to_string)
+        .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok());
+    if let Some(endpoint) = otlp_endpoint {
+        trace::enable(&endpoint);
+        eprintln!("Exporting traces to {}", endpoint);
+    }
+
+    let shutdown = service::install_shutdown_handler()?;
+
+    let listener = match Listener::from_systemd()? {
+        Some(listener) => {
+            eprintln!("Using socket from systemd socket activation");
+            Some(listener)
+        }
+        None => listen.map(Listener::bind_tcp).transpose()?,
+    };
+    if listener
+        .as_ref()
+        .is_some_and(|listener| listener.is_remote() && policy.token.is_none() && !allow_remote)
+    {
+        return Err(
+            "Refusing to serve requests without a token on an address other hosts can reach (pass --token-file, or --allow-remote to do it anyway)"
+                .to_string(),
+        );
+    }
+    if listener.is_some() || policy.root.is_some() {
+        policy.root = Some(resolve_root(policy.root.take())?);
+    }
+    let policy = Arc::new(policy);
+
+    let result = match listener {
+        Some(listener) => {
+            let connection_shutdown = Arc::clone(&shutdown);
+            listener.serve(&shutdown, move |stream| {
+                let result = stream.try_clone().and_then(|reader| {
+                    serve_connection(
+                        BufReader::new(reader),
+                        stream,
+                        &policy,
+                        &connection_shutdown,
+                    )
+                });
+                if let Err(e) = result {
+                    eprintln!("Connection failed: {}", e);
+                }
+            })
+        }
+        None => {
+            service::spawn_drain_watcher(Arc::clone(&shutdown));
+            serve_connection(
+                std::io::stdin().lock(),
+                std::io::stdout(),
+                &policy,
+                &shutdown,
+            )
+            .map_err(|e| format!("Failed to serve requests: {}", e))
+        }
+    };
+
+    trace::flush();
+    result
+}
+
+/// Answer requests from `reader` on `writer` until EOF or shutdown.
+fn serve_connection<R: BufRead, W: Write>(
+    reader: R,
+    writer: W,
+    policy: &Policy,
+    shutdown: &AtomicBool,
+) -> std::io::Result<()> {
+    let mut reader = reader;
+    let mut writer = BufWriter::new(writer);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_REQUEST_BYTES as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 || shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        if line.len() > MAX_REQUEST_BYTES {
+            metrics::record_failure("", error_class(INVALID_REQUEST));
+            let error = RpcError::new(
+                INVALID_REQUEST,
+                format!("Request longer than {} bytes", MAX_REQUEST_BYTES),
+            );
+            writeln!(writer, "{}", error_response(Value::Null, error))?;
+            writer.flush()?;
+            break;
+        }
+        let line = std::str::from_utf8(&line)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let _request = service::begin_request();
+        if let Some(response) = handle_message(line, policy) {
+            writeln!(writer, "{}", response)?;
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer one JSON-RPC message under `policy`, or return `None` for a
+/// notification.
+pub fn handle_message(message: &str, policy: &Policy) -> Option<Value> {
+    let value: Value = match serde_json::from_str(message) {
+        Ok(value) => value,
+        Err(e) => {
+            metrics::record_failure("", error_class(PARSE_ERROR));
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, format!("Parse error: {}", e)),
+            ));
+        }
+    };
+
+    let request: Request = match serde_json::from_value(value) {
+        Ok(request) => request,
+        Err(e) => {
+            metrics::record_failure("", error_class(INVALID_REQUEST));
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, format!("Invalid request: {}", e)),
+            ));
+        }
+    };
+
+    let id = request.id.clone();
+    let authorized = match (&policy.token, &request.token) {
+        (Some(token), Some(given)) => same_secret(token, given),
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+    let result = if request.jsonrpc != "2.0" {
+        Err(RpcError::new(
+            INVALID_REQUEST,
+            "Invalid request: jsonrpc must be \"2.0\"",
+        ))
+    } else if !authorized {
+        Err(RpcError::new(
+            UNAUTHORIZED,
+            "Unauthorized: the request does not carry the server's token",
+        ))
+    } else {
+        trace::root_span(
+            &format!("lowkey/{}", request.method),
+            request.traceparent.as_deref(),
+            &[("rpc.system", "jsonrpc"), ("rpc.method", &request.method)],
+            |result: &Result<Value, RpcError>| result.as_ref().err().map(|e| e.message.clone()),
+            || {
+                let mut params = request.params;
+                if let Some(key) = &policy.key {
+                    params = with_server_key(&request.method, params, key)?;
+                }
+                if let Some(root) = &policy.root {
+                    params = confine(params, root)?;
+                }
+                dispatch(&request.method, params)
+            },
+        )
+    };
+
+    let method = METHODS
+        .into_iter()
+        .find(|&method| method == request.method)
+        .unwrap_or("unknown");
+    metrics::record_request(method);
+    if let Err(error) = &result {
+        metrics::record_failure(method, error_class(error.code));
+    }
+
+    // Notifications never get a response, not even for errors
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => error_response(id, error),
+    })
+}
+
+/// Short, stable name for an error code, used as a metrics label
+fn error_class(code: i64) -> &'static str {
+    match code {
+        PARSE_ERROR => "parse_error",
+        INVALID_REQUEST => "invalid_request",
+        METHOD_NOT_FOUND => "method_not_found",
+        INVALID_PARAMS => "invalid_params",
+        UNAUTHORIZED => "unauthorized",
+        _ => "operation_failed",
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+}
+
+fn dispatch(method: &str, params: Option<Value>) -> Result<Value, RpcError> {
+    fn failed(e: impl Into<String>) -> RpcError {
+        RpcError::new(OPERATION_FAILED, e)
+    }
+
+    match method {
+        "encode" => {
+            let args: EncodeArgs = parse_params(params)?;
+            let (status, report) = encode_with_report(args).map_err(failed)?;
+            Ok(json!({ "status": status, "images": report.images }))
+        }
+        "decode" => {
+            let args: DecodeArgs = parse_params(params)?;
+            if args.output.as_deref() == Some(STDOUT_OUTPUT) {
+                // stdout may be the RPC channel itself
+                return Err(failed(
+                    "\"output\" cannot be \"-\" here (leave it out to get the text inline)"
+                        .to_string(),
+                ));
+            }
+            if args.output.is_some() || args.output_dir.is_some() || args.to_clipboard {
+                let status = decode(args).map_err(failed)?;
+                return Ok(json!({ "status": status }));
+            }
+
+            // Without a destination the message is returned inline
+            let message = decode_message(args).map_err(failed)?;
+            if message.archive {
+                return Err(failed(
+                    "Decoded message is an archive (pass \"output_dir\" to unpack it)".to_string(),
+                ));
+            }
+            let message_bytes = message.bytes;
+            let text = String::from_utf8(message_bytes).map_err(|_| {
+                failed(
+                    "Decoded message is not UTF-8 text (pass \"output\" to write it to a file)"
+                        .to_string(),
+                )
+            })?;
+            Ok(json!({ "text": text }))
+        }
+        "capacity" => {
+            let ImagesParams {
+                image,
+                image_list,
+                image_dir,
+            } = parse_params(params)?;
+            let images = resolve_images(image, image_list, image_dir, &DirScan::default())
+                .map_err(failed)?;
+            let (images, _downloads) = fetch::fetch_images(images).map_err(failed)?;
+            let capacity = message_capacity_from_files(&images).map_err(failed)?;
+            Ok(json!({ "capacity": capacity }))
+        }
+        "inspect" => {
+            let InspectParams { image } = parse_params(params)?;
+            let (images, _downloads) = fetch::fetch_images(vec![image]).map_err(failed)?;
+            let inspection = inspect_file(&images[0]).map_err(failed)?;
+            Ok(inspection_json(&inspection))
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Method not found: {}", method),
+        )),
+    }
+}
+
+/// Fill in the key held by the server for the methods that take one.
+fn with_server_key(
+    method: &str,
+    params: Option<Value>,
+    key: &str,
+) -> Result<Option<Value>, RpcError> {
+    if method != "encode" && method != "decode" {
+        return Ok(params);
+    }
+
+    let mut params = params.unwrap_or_else(|| json!({}));
+    let Some(object) = params.as_object_mut() else {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            "Invalid params: expected an object",
+        ));
+    };
+    if object.contains_key("key") {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            "Invalid params: the key is held by the server and cannot be passed",
+        ));
+    }
+    object.insert("key".into(), json!(key));
+
+    Ok(Some(params))
+}
+
+/// `root` with its links resolved, or the working directory without one, for
+/// `Policy::root`.
+pub fn resolve_root(root: Option<PathBuf>) -> Result<PathBuf, String> {
+    let root = match root {
+        Some(root) => root,
+        None => std::env::current_dir()
+            .map_err(|e| format!("Failed to read the working directory: {}", e))?,
+    };
+    root.canonicalize()
+        .map_err(|e| format!("Invalid root '{}': {}", root.display(), e))
+}
+
+/// Turn every path in `params` into an absolute one in `root`, taking
+/// relative ones from it, or refuse the request if one leads out of it or
+/// it asks for the server's clipboard.
+fn confine(params: Option<Value>, root: &Path) -> Result<Option<Value>, RpcError> {
+    let Some(mut params) = params else {
+        return Ok(None);
+    };
+    let Some(object) = params.as_object_mut() else {
+        return Ok(Some(params));
+    };
+    for clipboard in ["from_clipboard", "to_clipboard"] {
+        if object
+            .get(clipboard)
+            .is_some_and(|value| value != &json!(false))
+        {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("Invalid params: \"{}\" is not available here", clipboard),
+            ));
+        }
+    }
+    for name in PATH_PARAMS {
+        let paths = match object.get_mut(name) {
+            Some(Value::String(path)) => vec![path],
+            Some(Value::Array(paths)) => paths
+                .iter_mut()
+                .filter_map(|path| match path {
+                    Value::String(path) => Some(path),
+                    _ => None,
+                })
+                .collect(),
+            _ => continue,
+        };
+        for path in paths {
+            *path = confine_path(path, root).map_err(|e| {
+                RpcError::new(
+                    INVALID_PARAMS,
+                    format!("Invalid params: \"{}\": {}", name, e),
+                )
+            })?;
+        }
+    }
+    Ok(Some(params))
+}
+
+/// `path` as an absolute path in `root`, with links resolved as far as it
+/// exists. HTTPS URLs are fetched rather than read, so they are let through;
+/// other URIs, such as object storage, are not.
+fn confine_path(path: &str, root: &Path) -> Result<String, String> {
+    if path.starts_with("https://") {
+        return Ok(path.to_string());
+    }
+    if path.contains("://") {
+        return Err(format!("'{}' is not a local path", path));
+    }
+    if Path::new(path)
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err(format!("'{}' goes up a directory", path));
+    }
+
+    // The part that exists, with its links resolved, then the rest
+    let joined = root.join(path);
+    let mut existing = joined.as_path();
+    let mut rest = Vec::new();
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            Err(_) => match (existing.file_name(), existing.parent()) {
+                (Some(name), Some(parent)) => {
+                    rest.push(name);
+                    existing = parent;
+                }
+                _ => return Err(format!("'{}' cannot be resolved", path)),
+            },
+        }
+    };
+    let resolved = rest
+        .iter()
+        .rev()
+        .fold(resolved, |path, name| path.join(name));
+    if !resolved.starts_with(root) {
+        return Err(format!("'{}' is outside {}", path, root.display()));
+    }
+    resolved
+        .into_os_string()
+        .into_string()
+        .map_err(|_| format!("'{}' is not valid UTF-8", path))
+}
+
+/// Whether `a` and `b` are the same, in a time that does not depend on
+/// where they differ.
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+fn parse_params<T: DeserializeOwned>(params: Option<Value>) -> Result<T, RpcError> {
+    serde_json::from_value(params.unwrap_or_else(|| json!({})))
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid params: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle_line(line: &str) -> Option<Value> {
+        handle_message(line, &Policy::default())
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let response = handle_line("{not json").unwrap();
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        assert_eq!(response["id"], Value::Null);
+    }
+
+    #[test]
+    fn test_method_not_found() {
+        let response = handle_line(r#"{"jsonrpc":"2.0","id":7,"method":"nope"}"#).unwrap();
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(response["id"], 7);
+
+        // Made-up methods share one metric label instead of each adding one
+        handle_line(r#"{"jsonrpc":"2.0","id":8,"method":"made-up-method"}"#);
+        let metrics = metrics::render();
+        assert!(!metrics.contains("made-up-method"));
+        assert!(metrics.contains("lowkey_requests_total{method=\"unknown\"}"));
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let response =
+            handle_line(r#"{"jsonrpc":"2.0","id":1,"method":"encode","params":{"bogus":1}}"#)
+                .unwrap();
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_server_key_cannot_be_overridden() {
+        let response = handle_message(
+            r#"{"jsonrpc":"2.0","id":1,"method":"decode","params":{"image":"x.png","key":"k"}}"#,
+            &Policy {
+                key: Some("server key".to_string()),
+                ..Policy::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_token() {
+        let policy = Policy {
+            token: Some("s3cret".to_string()),
+            ..Policy::default()
+        };
+        for request in [
+            r#"{"jsonrpc":"2.0","id":1,"method":"nope"}"#,
+            r#"{"jsonrpc":"2.0","id":1,"method":"nope","token":"s3creT"}"#,
+        ] {
+            let response = handle_message(request, &policy).unwrap();
+            assert_eq!(response["error"]["code"], UNAUTHORIZED);
+        }
+        let response = handle_message(
+            r#"{"jsonrpc":"2.0","id":1,"method":"nope","token":"s3cret"}"#,
+            &policy,
+        )
+        .unwrap();
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_confine() {
+        let dir = std::env::temp_dir().join(format!("lowkey-rpc-root-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("in")).unwrap();
+        let root = dir.join("in").canonicalize().unwrap();
+
+        let params = confine(
+            Some(json!({ "image": "a.png", "image_list": ["b.png"], "output": "new/out.png" })),
+            &root,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(params["image"], json!(root.join("a.png")));
+        assert_eq!(params["image_list"][0], json!(root.join("b.png")));
+        assert_eq!(params["output"], json!(root.join("new/out.png")));
+
+        for params in [
+            json!({ "message": "/etc/passwd" }),
+            json!({ "output": "../out.png" }),
+            json!({ "message_file": ["ok.txt", "s3://bucket/key"] }),
+            json!({ "from_clipboard": true }),
+        ] {
+            assert!(confine(Some(params.clone()), &root).is_err(), "{}", params);
+        }
+        #[cfg(unix)]
+        {
+            // A link out of the root counts as where it leads
+            std::os::unix::fs::symlink(&dir, root.join("up")).unwrap();
+            assert!(confine_path("up/x.png", &root).is_err());
+        }
+        assert_eq!(
+            confine_path("https://example.com/a.png", &root).unwrap(),
+            "https://example.com/a.png"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_notification_has_no_response() {
+        assert!(handle_line(r#"{"jsonrpc":"2.0","method":"nope"}"#).is_none());
+    }
+
+    #[test]
+    fn test_request_length() {
+        let request = r#"{"jsonrpc":"2.0","id":1,"method":"nope"}"#;
+        let long = format!(
+            "{}{}\n{}\n",
+            request,
+            " ".repeat(MAX_REQUEST_BYTES),
+            request
+        );
+        let mut output = Vec::new();
+        serve_connection(
+            long.as_bytes(),
+            &mut output,
+            &Policy::default(),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        // One error for the long line, and nothing read after it
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), 1);
+        let response: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+
+        let mut output = Vec::new();
+        let short = format!("{}\n{}\n", request, request);
+        serve_connection(
+            short.as_bytes(),
+            &mut output,
+            &Policy::default(),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 2);
+    }
+}
@@ -0,0 +1,301 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// First file descriptor passed by systemd socket activation (SD_LISTEN_FDS_START)
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// How often idle loops check for a shutdown request
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Connections one listener serves at once; more are closed as soon as
+/// they are accepted, so that clients cannot take a thread each without limit
+const MAX_CONNECTIONS: usize = 64;
+
+/// Requests currently being processed, across all connections
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks a request as in flight until dropped.
+pub struct RequestGuard;
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn begin_request() -> RequestGuard {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    RequestGuard
+}
+
+/// Counts a connection as open until dropped, even if its handler panics.
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Catch SIGTERM and SIGINT so in-flight requests can finish before exiting.
+///
+/// The returned flag is set once a signal arrives. A second signal while
+/// draining terminates the process immediately.
+pub fn install_shutdown_handler() -> Result<Arc<AtomicBool>, String> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        // Registered first so it sees the flag before the second handler sets it
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&shutdown))
+            .map_err(|e| format!("Failed to install signal handler: {}", e))?;
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))
+            .map_err(|e| format!("Failed to install signal handler: {}", e))?;
+    }
+
+    Ok(shutdown)
+}
+
+/// Block until no request is in flight.
+pub fn wait_for_drain() {
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Exit the process once `shutdown` is set and no request is in flight.
+///
+/// Used when the main thread is stuck in a blocking read (e.g. on stdin) and
+/// cannot notice the shutdown request itself.
+pub fn spawn_drain_watcher(shutdown: Arc<AtomicBool>) {
+    std::thread::spawn(move || {
+        while !shutdown.load(Ordering::SeqCst) {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        eprintln!("Shutting down after in-flight requests finish");
+        wait_for_drain();
+        std::process::exit(0);
+    });
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: &str) -> Result<Self, String> {
+        TcpListener::bind(addr)
+            .map(Listener::Tcp)
+            .map_err(|e| format!("Failed to listen on '{}': {}", addr, e))
+    }
+
+    /// Listen on a Unix domain socket at `path` that only this user can connect to.
+    ///
+    /// A socket left behind by an instance that is no longer running is
+    /// replaced; one that still accepts connections is an error.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &str) -> Result<Self, String> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!(
+                "Another process is already listening on '{}'",
+                path
+            ));
+        }
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)
+                .map_err(|e| format!("Failed to remove stale socket '{}': {}", path, e))?;
+        }
+
+        // The socket gets its mode when it is bound, so binding under a umask
+        // that leaves only 0600 gives no moment where others can connect.
+        // Called at startup, before any other thread creates files.
+        // SAFETY: umask only swaps the process's file mode mask
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = listener.map_err(|e| format!("Failed to listen on '{}': {}", path, e))?;
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Take over the listening socket passed by systemd, if any.
+    ///
+    /// Follows the sd_listen_fds(3) protocol: `LISTEN_PID` must match this
+    /// process and `LISTEN_FDS` gives the number of sockets starting at fd 3.
+    #[cfg(unix)]
+    pub fn from_systemd() -> Result<Option<Self>, String> {
+        use std::os::fd::FromRawFd;
+
+        let pid_matches = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            == Some(std::process::id());
+        if !pid_matches {
+            return Ok(None);
+        }
+
+        let fd_count = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse::<i32>().ok())
+            .unwrap_or(0);
+        match fd_count {
+            0 => return Ok(None),
+            1 => {}
+            n => {
+                return Err(format!(
+                    "Expected one socket from systemd socket activation, got {}",
+                    n
+                ));
+            }
+        }
+
+        // SAFETY: systemd hands this process ownership of the descriptor, and
+        // nothing else in lowkey uses it.
+        let unix = unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+        if unix.local_addr().is_ok() {
+            return Ok(Some(Listener::Unix(unix)));
+        }
+
+        // Not a Unix socket, so it must be TCP. Hand the same descriptor over.
+        let fd = std::os::fd::IntoRawFd::into_raw_fd(unix);
+        // SAFETY: same descriptor as above, ownership moves to the TcpListener.
+        let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+        tcp.local_addr()
+            .map_err(|e| format!("Unsupported socket from systemd: {}", e))?;
+        Ok(Some(Listener::Tcp(tcp)))
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_systemd() -> Result<Option<Self>, String> {
+        Ok(None)
+    }
+
+    /// Accept connections until `shutdown` is set, handing each one to
+    /// `handle` on its own thread, up to `MAX_CONNECTIONS` at a time.
+    pub fn serve<F>(&self, shutdown: &AtomicBool, handle: F) -> Result<(), String>
+    where
+        F: Fn(Stream) + Send + Sync + 'static,
+    {
+        self.set_nonblocking(true)
+            .map_err(|e| format!("Failed to configure listener: {}", e))?;
+
+        let handle = Arc::new(handle);
+        let open = Arc::new(AtomicUsize::new(0));
+        while !shutdown.load(Ordering::SeqCst) {
+            match self.accept() {
+                Ok(stream) => {
+                    if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        open.fetch_sub(1, Ordering::SeqCst);
+                        eprintln!("Refused a connection: {} are already open", MAX_CONNECTIONS);
+                        continue;
+                    }
+                    let handle = Arc::clone(&handle);
+                    let connection = OpenConnection(Arc::clone(&open));
+                    std::thread::spawn(move || {
+                        let _connection = connection;
+                        handle(stream)
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(POLL_INTERVAL)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(format!("Failed to accept connection: {}", e)),
+            }
+        }
+
+        eprintln!("Shutting down after in-flight requests finish");
+        wait_for_drain();
+
+        Ok(())
+    }
+
+    /// Whether other hosts can connect: a TCP socket not on a loopback address.
+    pub fn is_remote(&self) -> bool {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map_or(true, |addr| !addr.ip().is_loopback()),
+            #[cfg(unix)]
+            Listener::Unix(_) => false,
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Stream> {
+        // Accepted sockets may inherit non-blocking mode, so reset it
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok(Stream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                Ok(Stream::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// Erasure coding across the images of a set, so that any `k` of `n` shards
+// rebuild the message. The data is cut into `k` equal shards, the last one
+// padded with zeros, and byte `j` of every shard is the value at point `α^i`
+// of the polynomial of degree below `k` through byte `j` of the data shards.
+// The first `k` shards are the data itself, and any `k` shards pin down the
+// polynomial again (Lagrange interpolation over GF(256)).
+
+use crate::ecc::{alpha, div, mul};
+use crate::error::LowkeyError;
+
+/// Most shards a set can have, one per nonzero field element
+pub(crate) const MAX_SHARDS: usize = 255;
+
+/// Bytes in each shard of `data_len` bytes cut into `data_shards`.
+pub(crate) fn shard_len(data_len: usize, data_shards: usize) -> usize {
+    data_len.div_ceil(data_shards).max(1)
+}
+
+/// Weight of the value at each of `points` in the value at `x` of the
+/// polynomial through them.
+fn lagrange(points: &[u8], x: u8) -> Vec<u8> {
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, &point)| {
+            points
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .fold(1, |acc, (_, &other)| {
+                    mul(acc, div(x ^ other, point ^ other))
+                })
+        })
+        .collect()
+}
+
+/// Sum of `shards` weighted by `weights`, byte by byte.
+fn combine(shards: &[&[u8]], weights: &[u8], len: usize) -> Vec<u8> {
+    let mut combined = vec![0u8; len];
+    for (shard, &weight) in shards.iter().zip(weights) {
+        for (out, &byte) in combined.iter_mut().zip(shard.iter()) {
+            *out ^= mul(weight, byte);
+        }
+    }
+    combined
+}
+
+/// `data` cut into `data_shards` shards, followed by parity shards up to
+/// `total`.
+pub(crate) fn encode(data: &[u8], data_shards: usize, total: usize) -> Vec<Vec<u8>> {
+    let len = shard_len(data.len(), data_shards);
+    let mut shards: Vec<Vec<u8>> = (0..data_shards)
+        .map(|i| {
+            let mut shard = data
+                .get(i * len..((i + 1) * len).min(data.len()))
+                .unwrap_or_default()
+                .to_vec();
+            shard.resize(len, 0);
+            shard
+        })
+        .collect();
+
+    let points: Vec<u8> = (0..data_shards).map(alpha).collect();
+    let parity: Vec<Vec<u8>> = {
+        let data: Vec<&[u8]> = shards.iter().map(Vec::as_slice).collect();
+        (data_shards..total)
+            .map(|i| combine(&data, &lagrange(&points, alpha(i)), len))
+            .collect()
+    };
+    shards.extend(parity);
+    shards
+}
+
+/// The data shards, joined, from the first `data_shards` of `shards`, given
+/// with their index in the set. The padding of the last shard is kept.
+pub(crate) fn reconstruct(
+    shards: &[(usize, Vec<u8>)],
+    data_shards: usize,
+) -> Result<Vec<u8>, LowkeyError> {
+    if shards.len() < data_shards {
+        return Err(LowkeyError::NoMessage(format!(
+            "{} shards are needed, only {} given",
+            data_shards,
+            shards.len()
+        )));
+    }
+    let shards = &shards[..data_shards];
+    let len = shards[0].1.len();
+    if shards.iter().any(|(_, shard)| shard.len() != len) {
+        return Err(LowkeyError::Format("Shards differ in length".to_string()));
+    }
+
+    let points: Vec<u8> = shards.iter().map(|&(index, _)| alpha(index)).collect();
+    let values: Vec<&[u8]> = shards.iter().map(|(_, shard)| shard.as_slice()).collect();
+    let mut data = Vec::with_capacity(data_shards * len);
+    for i in 0..data_shards {
+        match shards.iter().find(|&&(index, _)| index == i) {
+            Some((_, shard)) => data.extend_from_slice(shard),
+            None => data.extend(combine(&values, &lagrange(&points, alpha(i)), len)),
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any_shards_rebuild_the_data() {
+        let data: Vec<u8> = (0..100).map(|i| (i * 13 + 5) as u8).collect();
+        let shards = encode(&data, 3, 5);
+        assert_eq!(shards.len(), 5);
+        assert!(shards.iter().all(|shard| shard.len() == 34));
+        assert_eq!(&shards[0][..], &data[..34]);
+
+        for picked in [[0, 1, 2], [4, 3, 1], [2, 4, 0], [3, 0, 4]] {
+            let given: Vec<(usize, Vec<u8>)> =
+                picked.iter().map(|&i| (i, shards[i].clone())).collect();
+            let rebuilt = reconstruct(&given, 3).unwrap();
+            assert_eq!(&rebuilt[..data.len()], &data[..]);
+            assert!(rebuilt[data.len()..].iter().all(|&byte| byte == 0));
+        }
+
+        let given = vec![(1, shards[1].clone()), (3, shards[3].clone())];
+        assert!(reconstruct(&given, 3).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::crypto;
+use crate::img::codec::{Layout, PayloadOptions, encode_from_file};
+use lowkey::error::LowkeyError;
+
+/// Default name of the state file, kept in the destination directory
+const STATE_FILE: &str = ".lowkey-sync.json";
+
+/// What the mirror looked like after the last sync
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    /// Fingerprint of the message and key the mirror is encoded with
+    message: String,
+    /// Source images by path relative to the source directory
+    files: BTreeMap<String, FileState>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct FileState {
+    size: u64,
+    mtime_nanos: u64,
+    sha256: String,
+}
+
+/// Keep `dest` as a stego-encoded mirror of the images under `source`, each
+/// carrying `message_bytes`.
+///
+/// Only images that are new, whose content changed, or whose mirror copy is
+/// missing are encoded again; a different message or key re-encodes
+/// everything. Mirror copies of deleted source images are removed. Images
+/// are compared by size and mtime first and by SHA256 when those differ, so
+/// touching a file does not cost a re-encode.
+pub fn sync(
+    source: &str,
+    dest: &str,
+    message_bytes: &[u8],
+    key: &str,
+    state_file: Option<&str>,
+) -> Result<String, String> {
+    let source_dir = Path::new(source);
+    if !source_dir.is_dir() {
+        return Err(format!("'{}' is not a directory", source));
+    }
+    let dest_dir = Path::new(dest);
+    // The mirror would otherwise be synced into itself on the next run
+    if is_within(dest_dir, source_dir)? {
+        return Err("--dest must not be --source or a directory inside it".into());
+    }
+    fs::create_dir_all(dest_dir)
+        .map_err(|e| format!("Failed to create directory '{}': {}", dest, e))?;
+
+    let state_path = state_file.map_or_else(|| dest_dir.join(STATE_FILE), PathBuf::from);
+    let previous = load_state(&state_path)?;
+
+    let message = hex(&crypto::fingerprint(message_bytes, key));
+    let message_changed = previous.message != message;
+    let mut state = State {
+        message,
+        files: BTreeMap::new(),
+    };
+
+    let sources = collect_images(source_dir)?;
+    check_output_names(&sources)?;
+
+    let (mut encoded, mut unchanged) = (0, 0);
+    let mut failed = Vec::new();
+    for relative in &sources {
+        let input = source_dir.join(relative);
+        let output = dest_dir.join(output_name(relative));
+
+        match sync_file(
+            &input,
+            &output,
+            previous.files.get(relative),
+            message_changed,
+            message_bytes,
+            key,
+        ) {
+            Ok((file_state, true)) => {
+                state.files.insert(relative.clone(), file_state);
+                encoded += 1;
+            }
+            Ok((file_state, false)) => {
+                state.files.insert(relative.clone(), file_state);
+                unchanged += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to sync '{}': {}", relative, e);
+                // Don't leave a copy carrying an outdated message behind
+                let _ = fs::remove_file(&output);
+                failed.push(relative.as_str());
+            }
+        }
+    }
+
+    let mut removed = 0;
+    let current: BTreeSet<&String> = sources.iter().collect();
+    for relative in previous.files.keys().filter(|r| !current.contains(r)) {
+        let output = dest_dir.join(output_name(relative));
+        match fs::remove_file(&output) {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Failed to remove '{}': {}", output.display(), e),
+        }
+    }
+
+    save_state(&state_path, &state)?;
+
+    if !failed.is_empty() {
+        return Err(format!(
+            "Failed to sync {} of {} images: {}",
+            failed.len(),
+            sources.len(),
+            failed.join(", ")
+        ));
+    }
+
+    Ok(format!(
+        "Synced {} images into {}: {} encoded, {} unchanged, {} removed",
+        sources.len(),
+        dest,
+        encoded,
+        unchanged,
+        removed
+    ))
+}
+
+/// Bring one mirror copy up to date. Returns the new state of the source
+/// image and whether it had to be encoded.
+fn sync_file(
+    input: &Path,
+    output: &Path,
+    previous: Option<&FileState>,
+    message_changed: bool,
+    message_bytes: &[u8],
+    key: &str,
+) -> Result<(FileState, bool), String> {
+    let metadata = fs::metadata(input)
+        .map_err(|e| format!("Failed to read metadata of '{}': {}", input.display(), e))?;
+    let size = metadata.len();
+    let mtime_nanos = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_nanos() as u64);
+
+    let previous = previous.filter(|_| !message_changed && output.is_file());
+    if let Some(previous) = previous
+        && previous.size == size
+        && previous.mtime_nanos == mtime_nanos
+    {
+        return Ok((previous.clone(), false));
+    }
+
+    let content =
+        fs::read(input).map_err(|e| format!("Failed to read '{}': {}", input.display(), e))?;
+    let file_state = FileState {
+        size,
+        mtime_nanos,
+        sha256: hex(&Sha256::digest(&content)),
+    };
+    if previous.is_some_and(|previous| previous.sha256 == file_state.sha256) {
+        return Ok((file_state, false));
+    }
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create directory '{}': {}", parent.display(), e))?;
+    }
+
+    // Encode next to the output and rename, so an interrupted sync never
+    // leaves a truncated image that looks up to date
+    let file_name = output.file_name().unwrap_or_default().to_string_lossy();
+    let partial = output.with_file_name(format!(".{}.partial.png", file_name));
+    let result = encode_from_file(
+        &input.to_string_lossy(),
+        message_bytes,
+        &partial.to_string_lossy(),
+        false,
+        Layout::default(),
+        key,
+        &PayloadOptions::default(),
+    )
+    .and_then(|_| {
+        fs::rename(&partial, output)
+            .map_err(|e| LowkeyError::Io(format!("Failed to write '{}': {}", output.display(), e)))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result?;
+    Ok((file_state, true))
+}
+
+/// Image files under `dir`, recursively, as sorted `/`-separated relative paths.
+fn collect_images(dir: &Path) -> Result<Vec<String>, String> {
+    let mut images = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        let path = dir.join(&relative);
+        let entries = fs::read_dir(&path)
+            .map_err(|e| format!("Failed to read directory '{}': {}", path.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read directory entry: {}", e))?;
+            let child = relative.join(entry.file_name());
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(child);
+            } else if path.is_file() && is_image(&path) {
+                let parts: Vec<_> = child
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().to_string())
+                    .collect();
+                images.push(parts.join("/"));
+            }
+        }
+    }
+
+    images.sort();
+    Ok(images)
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        let ext = ext.to_string_lossy().to_lowercase();
+        ext == "png" || ext == "jpg" || ext == "jpeg"
+    })
+}
+
+/// Mirror copies are always PNG, e.g. `2024/beach.jpg` becomes `2024/beach.png`.
+fn output_name(relative: &str) -> String {
+    Path::new(relative)
+        .with_extension("png")
+        .to_string_lossy()
+        .to_string()
+}
+
+fn check_output_names(sources: &[String]) -> Result<(), String> {
+    let mut seen = BTreeMap::new();
+    for relative in sources {
+        if let Some(other) = seen.insert(output_name(relative), relative) {
+            return Err(format!(
+                "'{}' and '{}' would both be mirrored to '{}'",
+                other,
+                relative,
+                output_name(relative)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path`, which need not exist yet, is `dir` or inside it once
+/// links are resolved.
+fn is_within(path: &Path, dir: &Path) -> Result<bool, String> {
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| format!("Failed to resolve '{}': {}", dir.display(), e))?;
+    let path = std::path::absolute(path)
+        .map_err(|e| format!("Failed to resolve '{}': {}", path.display(), e))?;
+
+    // Resolve the part that exists and append the rest
+    let mut existing = path.as_path();
+    let mut rest = Vec::new();
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            Err(e) => match (existing.file_name(), existing.parent()) {
+                (Some(name), Some(parent)) => {
+                    rest.push(name);
+                    existing = parent;
+                }
+                _ => return Err(format!("Failed to resolve '{}': {}", path.display(), e)),
+            },
+        }
+    };
+    let resolved = rest
+        .iter()
+        .rev()
+        .fold(resolved, |path, name| path.join(name));
+    Ok(resolved.starts_with(dir))
+}
+
+fn load_state(path: &Path) -> Result<State, String> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|e| format!("Failed to parse sync state '{}': {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+        Err(e) => Err(format!(
+            "Failed to read sync state '{}': {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+fn save_state(path: &Path, state: &State) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(state)
+        .map_err(|e| format!("Failed to serialize sync state: {}", e))?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("Failed to write sync state '{}': {}", path.display(), e))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn write_cover(path: &Path, seed: u8) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([x as u8 ^ seed, y as u8, seed.wrapping_mul(7)])
+        })
+        .save(path)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_sync_changes_and_removals() {
+        let dir = std::env::temp_dir().join(format!("lowkey-sync-{}", std::process::id()));
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        write_cover(&source.join("a.png"), 1);
+        write_cover(&source.join("sub/b.png"), 2);
+        let run = || {
+            sync(
+                &source.to_string_lossy(),
+                &dest.to_string_lossy(),
+                b"hi",
+                "sync key",
+                None,
+            )
+            .unwrap()
+        };
+
+        assert!(run().ends_with("2 encoded, 0 unchanged, 0 removed"));
+        assert!(dest.join("sub/b.png").is_file());
+        assert!(run().ends_with("0 encoded, 2 unchanged, 0 removed"));
+
+        // A new mtime alone is caught by the SHA256; new content is not
+        let a = fs::File::options()
+            .write(true)
+            .open(source.join("a.png"))
+            .unwrap();
+        a.set_modified(UNIX_EPOCH).unwrap();
+        drop(a);
+        write_cover(&source.join("sub/b.png"), 3);
+        assert!(run().ends_with("1 encoded, 1 unchanged, 0 removed"));
+
+        fs::remove_file(source.join("a.png")).unwrap();
+        assert!(run().ends_with("0 encoded, 1 unchanged, 1 removed"));
+        assert!(!dest.join("a.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync_refuses_dest_inside_source() {
+        let dir = std::env::temp_dir().join(format!("lowkey-sync-nested-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.to_string_lossy();
+        for dest in [dir.clone(), dir.join("mirror"), dir.join("./new/../mirror")] {
+            assert!(sync(&source, &dest.to_string_lossy(), b"hi", "key", None).is_err());
+        }
+        assert!(!dir.join("mirror").exists());
+        assert!(is_within(&dir.join("..").join("elsewhere"), &dir).is_ok_and(|within| !within));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use serde_json::{Value, json};
+use std::cell::RefCell;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// OTLP span kinds
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+/// OTLP status code for failed spans
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// A slow collector must not pile up export threads
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where finished traces are sent. Tracing is off until `enable` is called.
+static TRACES_URL: OnceLock<String> = OnceLock::new();
+
+/// Exports still running in the background
+static PENDING_EXPORTS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The trace being recorded on this thread, if any
+    static ACTIVE: RefCell<Option<ActiveTrace>> = const { RefCell::new(None) };
+}
+
+struct ActiveTrace {
+    trace_id: [u8; 16],
+    /// Span that new spans on this thread become children of
+    current_span_id: [u8; 8],
+    finished: Vec<Value>,
+}
+
+/// Export traces to the OTLP/HTTP collector at `endpoint`, e.g. `http://localhost:4318`.
+///
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is used as-is when set, following the
+/// OpenTelemetry conventions; otherwise `/v1/traces` is appended to `endpoint`.
+pub fn enable(endpoint: &str) {
+    let url = std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+        .unwrap_or_else(|_| format!("{}/v1/traces", endpoint.trim_end_matches('/')));
+    let _ = TRACES_URL.set(url);
+}
+
+/// Run `f` as the root span of a trace and export the trace once it finishes.
+///
+/// `traceparent` is a W3C trace context header from the caller; with a valid
+/// one the span joins the caller's trace instead of starting a new one.
+/// `error` extracts a failure message from the result to mark the span failed.
+pub fn root_span<T>(
+    name: &str,
+    traceparent: Option<&str>,
+    attributes: &[(&str, &str)],
+    error: impl FnOnce(&T) -> Option<String>,
+    f: impl FnOnce() -> T,
+) -> T {
+    let Some(url) = TRACES_URL.get() else {
+        return f();
+    };
+
+    let parent = traceparent.and_then(parse_traceparent);
+    let trace_id = parent.map_or_else(random_bytes, |(trace_id, _)| trace_id);
+    let span_id = random_bytes();
+
+    ACTIVE.with(|active| {
+        *active.borrow_mut() = Some(ActiveTrace {
+            trace_id,
+            current_span_id: span_id,
+            finished: Vec::new(),
+        })
+    });
+
+    let start = SystemTime::now();
+    let result = f();
+    let end = SystemTime::now();
+
+    let Some(mut trace) = ACTIVE.with(|active| active.borrow_mut().take()) else {
+        return result;
+    };
+    trace.finished.push(span_json(SpanRecord {
+        trace_id,
+        span_id,
+        parent_span_id: parent.map(|(_, parent_span_id)| parent_span_id),
+        name,
+        kind: SPAN_KIND_SERVER,
+        start,
+        end,
+        attributes,
+        error: error(&result),
+    }));
+
+    let body = export_json(trace.finished);
+    let url = url.clone();
+    PENDING_EXPORTS.fetch_add(1, Ordering::SeqCst);
+    std::thread::spawn(move || {
+        if let Err(e) = export(&url, &body) {
+            eprintln!("Failed to export trace to '{}': {}", url, e);
+        }
+        PENDING_EXPORTS.fetch_sub(1, Ordering::SeqCst);
+    });
+
+    result
+}
+
+/// Wait for background exports to finish, so traces are not lost on exit.
+pub fn flush() {
+    let deadline = Instant::now() + EXPORT_TIMEOUT;
+    while PENDING_EXPORTS.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Run `f` as a child of the current span on this thread.
+///
+/// Does nothing but call `f` when no trace is being recorded.
+pub fn span<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    let parent_span_id = ACTIVE.with(|active| {
+        active.borrow_mut().as_mut().map(|trace| {
+            let parent = trace.current_span_id;
+            trace.current_span_id = random_bytes();
+            (trace.trace_id, parent, trace.current_span_id)
+        })
+    });
+    let Some((trace_id, parent_span_id, span_id)) = parent_span_id else {
+        return f();
+    };
+
+    let start = SystemTime::now();
+    let result = f();
+    let end = SystemTime::now();
+
+    let span = span_json(SpanRecord {
+        trace_id,
+        span_id,
+        parent_span_id: Some(parent_span_id),
+        name,
+        kind: SPAN_KIND_INTERNAL,
+        start,
+        end,
+        attributes: &[],
+        error: None,
+    });
+    ACTIVE.with(|active| {
+        if let Some(trace) = active.borrow_mut().as_mut() {
+            trace.current_span_id = parent_span_id;
+            trace.finished.push(span);
+        }
+    });
+
+    result
+}
+
+struct SpanRecord<'a> {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'a str,
+    kind: u8,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: &'a [(&'a str, &'a str)],
+    error: Option<String>,
+}
+
+fn span_json(span: SpanRecord) -> Value {
+    let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect();
+    let status = match &span.error {
+        Some(message) => json!({ "code": STATUS_CODE_ERROR, "message": message }),
+        None => json!({}),
+    };
+
+    json!({
+        "traceId": hex(&span.trace_id),
+        "spanId": hex(&span.span_id),
+        "parentSpanId": span.parent_span_id.map(|id| hex(&id)).unwrap_or_default(),
+        "name": span.name,
+        "kind": span.kind,
+        // 64-bit integers are strings in OTLP/JSON
+        "startTimeUnixNano": unix_nanos(span.start).to_string(),
+        "endTimeUnixNano": unix_nanos(span.end).to_string(),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+fn export_json(spans: Vec<Value>) -> String {
+    let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "lowkey".into());
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service_name } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "lowkey", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+    .to_string()
+}
+
+fn export(url: &str, body: &str) -> Result<(), String> {
+    ureq::post(url)
+        .config()
+        .timeout_global(Some(EXPORT_TIMEOUT))
+        .build()
+        .header("Content-Type", "application/json")
+        .send(body)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Parse a W3C `traceparent` header: `00-<trace id>-<parent span id>-<flags>`.
+fn parse_traceparent(traceparent: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = traceparent.trim().split('-');
+    let (version, trace_id, span_id, _flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version != "00" || parts.next().is_some() {
+        return None;
+    }
+
+    let trace_id: [u8; 16] = unhex(trace_id)?.try_into().ok()?;
+    let span_id: [u8; 8] = unhex(span_id)?.try_into().ok()?;
+    // All-zero IDs are invalid
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+
+    Some((trace_id, span_id))
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let (trace_id, span_id) =
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(hex(&trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&span_id), "00f067aa0ba902b7");
+
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(parse_traceparent("not a header").is_none());
+    }
+
+    #[test]
+    fn test_span_json() {
+        let span = span_json(SpanRecord {
+            trace_id: [1; 16],
+            span_id: [2; 8],
+            parent_span_id: None,
+            name: "rpc.encode",
+            kind: SPAN_KIND_SERVER,
+            start: UNIX_EPOCH + Duration::from_secs(1),
+            end: UNIX_EPOCH + Duration::from_secs(2),
+            attributes: &[("rpc.method", "encode")],
+            error: Some("bad key".to_string()),
+        });
+
+        assert_eq!(span["traceId"], "01010101010101010101010101010101");
+        assert_eq!(span["parentSpanId"], "");
+        assert_eq!(span["startTimeUnixNano"], "1000000000");
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "encode");
+        assert_eq!(span["status"]["code"], STATUS_CODE_ERROR);
+    }
+}
@@ -0,0 +1,54 @@
+// `lowkey verify` and `encode --verify`: decode what was written and check
+// that it gives back the message, before the plaintext is deleted.
+
+use lowkey::error::LowkeyError;
+use sha2::{Digest, Sha256};
+
+/// SHA256 of `bytes`, as lowercase hex.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The SHA256 given to `--sha256`, checked and in lowercase.
+pub fn parse_sha256(hex: &str) -> Result<String, LowkeyError> {
+    let hex = hex.trim().to_lowercase();
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(LowkeyError::InvalidInput(format!(
+            "Invalid SHA256 '{}': expected 64 hexadecimal digits",
+            hex
+        )));
+    }
+    Ok(hex)
+}
+
+/// Check that `decoded`, recovered from `source`, is the message whose
+/// SHA256 is `expected`.
+pub fn check(decoded: &[u8], expected: &str, source: &str) -> Result<(), LowkeyError> {
+    let actual = sha256_hex(decoded);
+    if actual != expected {
+        return Err(LowkeyError::Other(format!(
+            "The message in {} does not match: SHA256 {} instead of {}",
+            source, actual, expected
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let expected = sha256_hex(b"hello");
+        assert_eq!(parse_sha256(&expected.to_uppercase()).unwrap(), expected);
+        assert!(parse_sha256("abc").is_err());
+        assert!(parse_sha256(&"g".repeat(64)).is_err());
+
+        assert!(check(b"hello", &expected, "a.png").is_ok());
+        assert!(check(b"hello!", &expected, "a.png").is_err());
+    }
+}
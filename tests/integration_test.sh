@@ -256,9 +256,33 @@ else
     print_fail "Missing --output not detected"
 fi
 
+print_test "Testing mutually exclusive --message and --from-clipboard"
+OUTPUT=$(cargo run --quiet -- encode \
+    --image test/tmp/images/01.png \
+    --message test/tmp/messages/short.txt \
+    --from-clipboard \
+    --output test/tmp/output_single/test.png 2>&1)
+
+if echo "$OUTPUT" | grep -q "Only one of --message, --message-text, --message-file, --message-dir or --from-clipboard"; then
+    print_pass "Mutually exclusive message sources rejected correctly"
+else
+    print_fail "Mutually exclusive message sources not rejected"
+fi
+
+print_test "Testing decode requires --output or --to-clipboard"
+OUTPUT=$(cargo run --quiet -- decode \
+    --image test/tmp/output_single/encoded.png 2>&1)
+
+if echo "$OUTPUT" | grep -q "Must specify one of --output, --output-dir or --to-clipboard"; then
+    print_pass "Missing decode destination detected correctly"
+else
+    print_fail "Missing decode destination not detected"
+fi
+
 print_section "Test 8: Error handling"
 print_test "Testing message too long for image capacity"
-dd if=/dev/zero of=test/tmp/messages/huge.txt bs=1M count=1 2>/dev/null
+# Random, so that compression does not make it fit
+dd if=/dev/urandom of=test/tmp/messages/huge.txt bs=1M count=1 2>/dev/null
 
 OUTPUT=$(cargo run --quiet -- encode \
     --image test/tmp/images/01.png \
@@ -369,7 +393,7 @@ OUTPUT=$(cargo run --quiet -- decode \
     --output test/tmp/output_single/wrong_key_decoded.txt \
     --key "wrong-password" 2>&1)
 
-if echo "$OUTPUT" | grep -q "Decryption failed"; then
+if echo "$OUTPUT" | grep -q "Wrong key"; then
     print_pass "Decoding with wrong key correctly rejected"
 else
     print_fail "Decoding with wrong key not rejected"
@@ -470,6 +494,1375 @@ else
     print_fail "Multi-image custom key encoding failed"
 fi
 
+print_test "Testing key from an inherited file descriptor"
+printf 'fd-secret\n' >test/tmp/messages/key_fd.txt
+cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/key_fd.png --key-fd 3 3<test/tmp/messages/key_fd.txt >/dev/null 2>&1
+
+if cargo run --quiet -- decode --image test/tmp/output_single/key_fd.png \
+    --output test/tmp/output_single/key_fd_decoded.txt --key "fd-secret" >/dev/null 2>&1 &&
+    diff -q test/tmp/messages/short.txt test/tmp/output_single/key_fd_decoded.txt >/dev/null 2>&1; then
+    print_pass "--key-fd reads the key without its trailing newline"
+else
+    print_fail "--key-fd did not produce the expected key"
+fi
+
+if cargo run --quiet -- decode --image test/tmp/output_single/key_fd.png \
+    --output test/tmp/output_single/key_fd_decoded.txt --key "other" --key-fd 3 3<test/tmp/messages/key_fd.txt >/dev/null 2>&1; then
+    print_fail "--key and --key-fd together should be rejected"
+else
+    print_pass "--key and --key-fd together are rejected"
+fi
+
+print_section "Test 11: JSON-RPC over stdio"
+print_test "Encoding and decoding through lowkey rpc"
+OUTPUT=$(printf '%s\n' \
+    '{"jsonrpc":"2.0","id":1,"method":"encode","params":{"image":"test/tmp/images/01.png","message":"test/tmp/messages/short.txt","output":"test/tmp/output_single/rpc.png"}}' \
+    '{"jsonrpc":"2.0","id":2,"method":"decode","params":{"image":"test/tmp/output_single/rpc.png"}}' |
+    cargo run --quiet -- rpc 2>/dev/null)
+
+if echo "$OUTPUT" | grep -q '"id":2,"jsonrpc":"2.0","result":{"text":"Hello, World!\\n"}'; then
+    print_pass "JSON-RPC encode/decode round trip works"
+else
+    print_fail "JSON-RPC encode/decode round trip failed"
+fi
+
+print_test "Unknown JSON-RPC method"
+OUTPUT=$(echo '{"jsonrpc":"2.0","id":3,"method":"bogus"}' | cargo run --quiet -- rpc 2>/dev/null)
+
+if echo "$OUTPUT" | grep -q '"code":-32601'; then
+    print_pass "Unknown method rejected with JSON-RPC error"
+else
+    print_fail "Unknown method not rejected"
+fi
+
+print_section "Test 12: Git clean/smudge filters"
+print_test "Round trip through filter-clean and filter-smudge"
+cargo run --quiet -- filter-clean --cover test/tmp/images/01.png --key "git-key" \
+    <test/tmp/messages/short.txt >test/tmp/output_single/filter1.png 2>/dev/null
+cargo run --quiet -- filter-clean --cover test/tmp/images/01.png --key "git-key" \
+    <test/tmp/messages/short.txt >test/tmp/output_single/filter2.png 2>/dev/null
+cargo run --quiet -- filter-smudge --key "git-key" \
+    <test/tmp/output_single/filter1.png >test/tmp/output_single/filter_decoded.txt 2>/dev/null
+
+if diff -q test/tmp/messages/short.txt test/tmp/output_single/filter_decoded.txt >/dev/null 2>&1; then
+    print_pass "Filter round trip works"
+else
+    print_fail "Filter round trip failed"
+fi
+
+if cmp -s test/tmp/output_single/filter1.png test/tmp/output_single/filter2.png; then
+    print_pass "Clean filter output is deterministic"
+else
+    print_fail "Clean filter output is not deterministic"
+fi
+
+print_section "Test 13: age recipients and identities"
+# Test key pair from the age reference implementation
+cat >test/tmp/messages/age_identity.txt <<'EOF'
+# public key: age1t7rxyev2z3rw82stdlrrepyc39nvn86l5078zqkf5uasdy86jp6svpy7pa
+AGE-SECRET-KEY-1GQ9778VQXMMJVE8SK7J6VT8UJ4HDQAJUVSFCWCM02D8GEWQ72PVQ2Y5J33
+EOF
+printf '# team\nage1t7rxyev2z3rw82stdlrrepyc39nvn86l5078zqkf5uasdy86jp6svpy7pa\n' >test/tmp/messages/age_recipients.txt
+
+print_test "Encoding to an age recipients file and decoding with the identity"
+cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/age.png --recipients-file test/tmp/messages/age_recipients.txt >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/age.png \
+    --output test/tmp/output_single/age_decoded.txt --identity test/tmp/messages/age_identity.txt >/dev/null 2>&1
+
+if diff -q test/tmp/messages/short.txt test/tmp/output_single/age_decoded.txt >/dev/null 2>&1; then
+    print_pass "age recipients round trip works"
+else
+    print_fail "age recipients round trip failed"
+fi
+
+print_test "Decoding without the identity does not reveal the message"
+if cargo run --quiet -- decode --image test/tmp/output_single/age.png \
+    --output test/tmp/output_single/age_raw.txt 2>&1 | grep -q "encrypted to age recipients"; then
+    print_pass "Decoding asks for an identity"
+else
+    print_fail "Message decoded without --identity"
+fi
+
+print_test "Encoding to a --recipient public key"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/age_key.png \
+    --recipient age1t7rxyev2z3rw82stdlrrepyc39nvn86l5078zqkf5uasdy86jp6svpy7pa >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/output_single/age_key.png \
+        --output test/tmp/output_single/age_key.txt --identity test/tmp/messages/age_identity.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/output_single/age_key.txt; then
+    print_pass "--recipient round trip works"
+else
+    print_fail "--recipient round trip failed"
+fi
+
+print_section "Test 14: Directory sync"
+mkdir -p test/tmp/sync_source/nested
+cp test/tmp/images/01.png test/tmp/sync_source/
+cp test/tmp/images/04.jpg test/tmp/sync_source/nested/
+
+print_test "Mirroring a directory"
+if cargo run --quiet -- sync --source test/tmp/sync_source --dest test/tmp/sync_dest \
+    --message test/tmp/messages/short.txt 2>&1 | grep -q "2 encoded" &&
+    cargo run --quiet -- decode --image test/tmp/sync_dest/nested/04.png \
+        --output test/tmp/output_single/sync_decoded.txt >/dev/null 2>&1 &&
+    diff -q test/tmp/messages/short.txt test/tmp/output_single/sync_decoded.txt >/dev/null 2>&1; then
+    print_pass "Sync encodes every image into the mirror"
+else
+    print_fail "Sync did not mirror the directory"
+fi
+
+print_test "Syncing again after touching and deleting images"
+touch test/tmp/sync_source/01.png
+rm test/tmp/sync_source/nested/04.jpg
+if cargo run --quiet -- sync --source test/tmp/sync_source --dest test/tmp/sync_dest \
+    --message test/tmp/messages/short.txt 2>&1 | grep -q "0 encoded, 1 unchanged, 1 removed" &&
+    [ ! -f test/tmp/sync_dest/nested/04.png ]; then
+    print_pass "Sync skips unchanged images and removes deleted ones"
+else
+    print_fail "Sync re-encoded unchanged images or kept deleted ones"
+fi
+
+print_section "Test 15: Encode hooks"
+print_test "Running pre- and post-encode commands"
+cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/hooked.png \
+    --pre-encode-cmd 'echo "$LOWKEY_MESSAGE" >test/tmp/output_single/pre_hook.txt' \
+    --post-encode-cmd 'test -f "$LOWKEY_OUTPUT" && echo done >test/tmp/output_single/post_hook.txt' >/dev/null 2>&1
+
+if grep -q "short.txt" test/tmp/output_single/pre_hook.txt 2>/dev/null &&
+    grep -q "done" test/tmp/output_single/post_hook.txt 2>/dev/null; then
+    print_pass "Hooks run with the paths in their environment"
+else
+    print_fail "Hooks did not run as expected"
+fi
+
+print_test "Failing pre-encode command stops the encode"
+if ! cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/not_hooked.png --pre-encode-cmd 'exit 1' >/dev/null 2>&1 &&
+    [ ! -f test/tmp/output_single/not_hooked.png ]; then
+    print_pass "Failing pre-encode command prevents encoding"
+else
+    print_fail "Encode ran despite failing pre-encode command"
+fi
+
+print_section "Test 16: Control socket"
+print_test "Decoding through the control socket with the server's key"
+if command -v python3 >/dev/null 2>&1; then
+    cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+        --output test/tmp/output_single/control.png --key "control key" >/dev/null 2>&1
+    cargo run --quiet -- control --socket test/tmp/control.sock --key "control key" 2>/dev/null &
+    control_pid=$!
+    for _ in $(seq 50); do [ -S test/tmp/control.sock ] && break; sleep 0.1; done
+
+    response=$(python3 - test/tmp/control.sock <<'PYEOF'
+import json, socket, struct, sys
+sock = socket.socket(socket.AF_UNIX)
+sock.connect(sys.argv[1])
+request = json.dumps({"jsonrpc": "2.0", "id": 1, "method": "decode",
+                      "params": {"image": "test/tmp/output_single/control.png"}}).encode()
+sock.sendall(struct.pack(">I", len(request)) + request)
+length = struct.unpack(">I", sock.recv(4, socket.MSG_WAITALL))[0]
+print(json.loads(sock.recv(length, socket.MSG_WAITALL))["result"]["text"], end="")
+PYEOF
+    )
+    kill "$control_pid" 2>/dev/null
+    wait "$control_pid" 2>/dev/null
+
+    if [ "$response" = "$(cat test/tmp/messages/short.txt)" ]; then
+        print_pass "Control socket decodes with the key held by the server"
+    else
+        print_fail "Control socket did not decode the message"
+    fi
+else
+    print_pass "Skipped (python3 not available)"
+fi
+
+print_section "Test 17: Steganalysis"
+print_test "Analyzing an encoded image"
+analysis=$(cargo run --quiet -- analyze --image test/tmp/output_single/encoded.png 2>/dev/null)
+if echo "$analysis" | grep -q "chi-square attack" && echo "$analysis" | grep -q "RS analysis" &&
+    echo "$analysis" | grep -q "sample-pairs analysis"; then
+    print_pass "analyze reports the chi-square attack, RS and sample-pairs analysis"
+else
+    print_fail "analyze did not report all detectors"
+fi
+
+print_test "Exporting bit planes"
+cargo run --quiet -- analyze --image test/tmp/output_single/encoded.png \
+    --export-planes test/tmp/planes/encoded >/dev/null 2>&1
+if [ "$(ls test/tmp/planes/encoded-*.png 2>/dev/null | wc -l)" -eq 8 ]; then
+    print_pass "LSB and 2nd LSB planes written for every channel"
+else
+    print_fail "Bit planes were not exported"
+fi
+
+print_test "Analyzing after encoding"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/analyzed.png --analyze 2>&1 | grep -q "chi-square attack"; then
+    print_pass "encode --analyze reports the chi-square attack"
+else
+    print_fail "encode --analyze did not report the chi-square attack"
+fi
+
+print_test "Comparing with the cover"
+comparison=$(cargo run --quiet -- analyze --image test/tmp/output_single/analyzed.png \
+    --cover test/tmp/images/01.png --json 2>/dev/null)
+if echo "$comparison" | python3 -c '
+import json, sys
+report = json.load(sys.stdin)[0]
+channels = report["cover_comparison"]["channels"]
+assert set(channels) == {"r", "g", "b", "a"}
+assert any(channel["changed"] > 0 for channel in channels.values())
+assert "rs" in report and "sample_pairs" in report
+' 2>/dev/null; then
+    print_pass "analyze --cover --json reports a per-channel histogram comparison"
+else
+    print_fail "analyze --cover --json did not report a histogram comparison"
+fi
+
+print_test "Detectability score after encoding"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/scored.png 2>&1 | grep -q "detectability [0-9]*/100"; then
+    print_pass "encode reports a detectability score"
+else
+    print_fail "encode did not report a detectability score"
+fi
+
+print_section "Test 18: Noise Matching"
+print_test "Round trip with --match-noise"
+cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/noise.png --match-noise --key "noise key" >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/noise.png \
+    --output test/tmp/output_single/noise_decoded.txt --key "noise key" >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt test/tmp/output_single/noise_decoded.txt; then
+    print_pass "Message encoded with --match-noise decodes without extra options"
+else
+    print_fail "Message encoded with --match-noise did not round-trip"
+fi
+
+print_section "Test 19: Quality Metrics"
+print_test "Quality metrics after encoding"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/quality.png 2>&1 | grep -q "PSNR [0-9.]* dB, SSIM [0-9.]*"; then
+    print_pass "encode reports PSNR and SSIM"
+else
+    print_fail "encode did not report PSNR and SSIM"
+fi
+
+print_section "Test 20: Corpus Scan"
+print_test "Ranking a directory by suspicion"
+ranking=$(cargo run --quiet -- analyze --image-dir test/tmp/output_single \
+    --report test/tmp/scan-report.json 2>/dev/null)
+if echo "$ranking" | grep -q "Most suspicious images" && python3 -c '
+import json
+images = json.load(open("test/tmp/scan-report.json"))["images"]
+scores = [image["suspicion"] for image in images]
+assert len(images) > 1 and scores == sorted(scores, reverse=True)
+' 2>/dev/null; then
+    print_pass "analyze --report writes a ranked report"
+else
+    print_fail "analyze --report did not write a ranked report"
+fi
+
+print_section "Test 21: Paranoid Mode"
+print_test "Self-check rejects a detectable result"
+if ! cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/long.txt \
+    --output test/tmp/output_single/paranoid.png --paranoid >/dev/null 2>&1 &&
+    [ ! -f test/tmp/output_single/paranoid.png ]; then
+    print_pass "--paranoid fails and removes an output that fills most of the image"
+else
+    print_fail "--paranoid kept a detectable output"
+fi
+
+print_test "Rejecting --paranoid with --match-noise"
+if ! cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/paranoid_noise.png --paranoid --match-noise >/dev/null 2>&1; then
+    print_pass "--paranoid refuses options it chooses itself"
+else
+    print_fail "--paranoid accepted --match-noise"
+fi
+
+print_section "Test 22: Key File"
+print_test "Encoding with --key-file and decoding with --key"
+printf 'key from a file\n' >test/tmp/key.txt
+cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/key_file.png --key-file test/tmp/key.txt >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/key_file.png \
+    --output test/tmp/output_single/key_file.txt --key "key from a file" >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt test/tmp/output_single/key_file.txt; then
+    print_pass "--key-file reads the key without its trailing newline"
+else
+    print_fail "--key-file round trip failed"
+fi
+
+print_section "Test 23: Key Derivation Cost"
+print_test "Encoding with a custom Argon2id cost and decoding without options"
+cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/kdf.png --key "kdf key" --kdf-memory 32768 --kdf-iterations 3 >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/kdf.png \
+    --output test/tmp/output_single/kdf.txt --key "kdf key" >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt test/tmp/output_single/kdf.txt; then
+    print_pass "KDF parameters are read from the image"
+else
+    print_fail "Custom KDF cost round trip failed"
+fi
+
+print_test "Rejecting an out-of-range KDF cost"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/kdf_bad.png --kdf-iterations 0 >/dev/null 2>&1; then
+    print_fail "--kdf-iterations 0 was accepted"
+else
+    print_pass "--kdf-iterations 0 is rejected"
+fi
+
+print_section "Test 24: Bit Depth"
+print_test "Encoding a message too long for one bit per value with --bits 4"
+dd if=/dev/urandom of=test/tmp/messages/deep.txt bs=1000 count=40 2>/dev/null
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/deep.txt \
+    --output test/tmp/output_single/deep1.png >/dev/null 2>&1; then
+    print_fail "A 40000-byte message fit at one bit per value"
+else
+    print_pass "A 40000-byte message does not fit at one bit per value"
+fi
+cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/deep.txt \
+    --output test/tmp/output_single/deep4.png --bits 4 >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/deep4.png \
+    --output test/tmp/output_single/deep4.txt >/dev/null 2>&1
+if cmp -s test/tmp/messages/deep.txt test/tmp/output_single/deep4.txt; then
+    print_pass "Decoding picks up the depth from the header"
+else
+    print_fail "--bits 4 round trip failed"
+fi
+
+print_section "Test 25: Channel Selection"
+print_test "Encoding into the RGB channels only"
+cargo run --quiet -- encode --image test/tmp/images/02.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/rgb.png --channels rgb >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/rgb.png \
+    --output test/tmp/output_single/rgb.txt >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt test/tmp/output_single/rgb.txt; then
+    print_pass "Decoding finds the channels from the header"
+else
+    print_fail "--channels rgb round trip failed"
+fi
+
+print_test "Leaving the alpha channel untouched"
+comparison=$(cargo run --quiet -- analyze --image test/tmp/output_single/rgb.png \
+    --cover test/tmp/images/02.png --json 2>/dev/null)
+if echo "$comparison" | python3 -c '
+import json, sys
+channels = json.load(sys.stdin)[0]["cover_comparison"]["channels"]
+assert channels["a"]["changed"] == 0 and channels["b"]["changed"] > 0
+' 2>/dev/null; then
+    print_pass "--channels rgb does not change alpha"
+else
+    print_fail "--channels rgb changed the alpha channel"
+fi
+
+print_section "Test 26: Scattering"
+print_test "Encoding in a keyed random order"
+cargo run --quiet -- encode --image test/tmp/images/03.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/scattered.png --scatter --key "scatter key" >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/scattered.png --key "scatter key" \
+    --output test/tmp/output_single/scattered.txt >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt test/tmp/output_single/scattered.txt; then
+    print_pass "Decoding finds the scattered message with the key"
+else
+    print_fail "--scatter round trip failed"
+fi
+
+print_test "Decoding a scattered message with the wrong key"
+if cargo run --quiet -- decode --image test/tmp/output_single/scattered.png --key "wrong key" \
+    --output test/tmp/output_single/scattered_wrong.txt >/dev/null 2>&1; then
+    print_fail "Decoding with the wrong key should fail"
+else
+    print_pass "The wrong key does not find the message"
+fi
+
+print_test "Rejecting --scatter with --match-noise"
+if cargo run --quiet -- encode --image test/tmp/images/03.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/scattered_noise.png --scatter --match-noise >/dev/null 2>&1; then
+    print_fail "--scatter with --match-noise should be rejected"
+else
+    print_pass "--scatter and --match-noise are exclusive"
+fi
+
+print_section "Test 27: Capacity"
+print_test "Reporting the capacity of a set of images"
+capacity=$(cargo run --quiet -- capacity --image-list test/tmp/images/01.png test/tmp/images/02.png --json 2>/dev/null)
+if echo "$capacity" | python3 -c '
+import json, sys
+report = json.load(sys.stdin)
+assert [image["capacity"] for image in report["images"]] == [20000 - report["overhead"]] * 2
+assert report["total"] == 40000 - report["overhead"]
+' 2>/dev/null; then
+    print_pass "Each image and the set report their capacity"
+else
+    print_fail "Unexpected capacity report: $capacity"
+fi
+
+print_test "Encoding a message of exactly the reported capacity"
+bytes=$(cargo run --quiet -- capacity --image test/tmp/images/01.png --bits 2 --channels rgb --json 2>/dev/null \
+    | python3 -c 'import json, sys; print(json.load(sys.stdin)["total"])')
+head -c "$bytes" /dev/urandom > test/tmp/messages/capacity.bin
+head -c $((bytes + 1)) /dev/urandom > test/tmp/messages/over_capacity.bin
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/capacity.bin \
+    --output test/tmp/output_single/capacity.png --bits 2 --channels rgb >/dev/null 2>&1 \
+    && ! cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/over_capacity.bin \
+    --output test/tmp/output_single/over_capacity.png --bits 2 --channels rgb >/dev/null 2>&1; then
+    print_pass "The reported capacity is exactly what fits"
+else
+    print_fail "The reported capacity ($bytes bytes) does not match what encode accepts"
+fi
+
+print_section "Test 28: Inspect"
+print_test "Inspecting a stego image without the key"
+inspection=$(cargo run --quiet -- inspect --image test/tmp/output_single/rgb.png --json 2>/dev/null)
+if echo "$inspection" | python3 -c '
+import json, sys
+inspection = json.load(sys.stdin)
+assert inspection["channels"] == "rgb" and inspection["depth"] == 1
+assert inspection["kdf"]["memory_kib"] == 19456 and inspection["problems"] == []
+' 2>/dev/null; then
+    print_pass "The header and KDF parameters are reported"
+else
+    print_fail "Unexpected inspection: $inspection"
+fi
+
+print_test "Inspecting a cover without a message"
+if cargo run --quiet -- inspect --image test/tmp/images/01.png >/dev/null 2>&1; then
+    print_fail "A cover should not appear to contain a message"
+else
+    print_pass "No message is reported in a cover"
+fi
+
+print_section "Test 29: Compression"
+print_test "Encoding text larger than the raw capacity"
+python3 -c 'print("All work and no play makes Jack a dull boy. " * 1500)' > test/tmp/messages/compressible.txt
+cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/compressible.txt \
+    --output test/tmp/output_single/compressed.png >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/compressed.png \
+    --output test/tmp/output_single/compressed.txt >/dev/null 2>&1
+if cmp -s test/tmp/messages/compressible.txt test/tmp/output_single/compressed.txt; then
+    print_pass "A 66 KB text round-trips through a 20 KB cover"
+else
+    print_fail "Compressed round trip failed"
+fi
+
+print_test "Recording compression in the header"
+if cargo run --quiet -- inspect --image test/tmp/output_single/compressed.png --json 2>/dev/null \
+    | python3 -c 'import json, sys; assert json.load(sys.stdin)["compressed"] is True' 2>/dev/null \
+    && cargo run --quiet -- inspect --image test/tmp/output_single/rgb.png --json 2>/dev/null \
+    | python3 -c 'import json, sys; assert json.load(sys.stdin)["compressed"] is False' 2>/dev/null; then
+    print_pass "Compressed and incompressible messages are flagged accordingly"
+else
+    print_fail "The compression flag is wrong"
+fi
+
+print_section "Test 30: Error Correction"
+print_test "Encoding with Reed-Solomon parity"
+cargo run --quiet -- encode --image test/tmp/images/02.png --message test/tmp/messages/long.txt \
+    --output test/tmp/output_single/ecc.png --ecc >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/ecc.png \
+    --output test/tmp/output_single/ecc.txt >/dev/null 2>&1
+if cmp -s test/tmp/messages/long.txt test/tmp/output_single/ecc.txt; then
+    print_pass "--ecc round trip works"
+else
+    print_fail "--ecc round trip failed"
+fi
+
+print_test "Checking the parity without the key"
+if cargo run --quiet -- inspect --image test/tmp/output_single/ecc.png --json 2>/dev/null \
+    | python3 -c '
+import json, sys
+inspection = json.load(sys.stdin)
+assert inspection["ecc"] is True and inspection["problems"] == []
+' 2>/dev/null; then
+    print_pass "inspect reports intact error correction"
+else
+    print_fail "inspect does not report error correction"
+fi
+
+print_test "Accounting for the parity in the capacity"
+plain=$(cargo run --quiet -- capacity --image test/tmp/images/02.png --json 2>/dev/null \
+    | python3 -c 'import json, sys; print(json.load(sys.stdin)["total"])')
+parity=$(cargo run --quiet -- capacity --image test/tmp/images/02.png --ecc --json 2>/dev/null \
+    | python3 -c 'import json, sys; print(json.load(sys.stdin)["total"])')
+if [ -n "$plain" ] && [ -n "$parity" ] && [ "$parity" -lt "$plain" ]; then
+    print_pass "Capacity with --ecc ($parity bytes) is below capacity without ($plain bytes)"
+else
+    print_fail "Capacity with --ecc is not reduced"
+fi
+
+print_section "Test 31: File Info"
+print_test "Restoring a file under its original name"
+touch -d "2020-01-02 03:04:05" test/tmp/messages/short.txt
+cargo run --quiet -- encode --image test/tmp/images/02.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/file_info.png --file-info >/dev/null 2>&1
+rm -rf test/tmp/output_single/restored
+cargo run --quiet -- decode --image test/tmp/output_single/file_info.png \
+    --output-dir test/tmp/output_single/restored >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt test/tmp/output_single/restored/short.txt \
+    && [ "$(stat -c %Y test/tmp/output_single/restored/short.txt)" = "$(stat -c %Y test/tmp/messages/short.txt)" ]; then
+    print_pass "decode --output-dir restores the name, contents and modification time"
+else
+    print_fail "decode --output-dir did not restore the file"
+fi
+
+print_test "Restoring next to an existing file and under another name"
+cargo run --quiet -- decode --image test/tmp/output_single/file_info.png \
+    --output-dir test/tmp/output_single/restored >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt "test/tmp/output_single/restored/short (1).txt" \
+    && ! cargo run --quiet -- decode --image test/tmp/output_single/file_info.png \
+        --output-dir test/tmp/output_single/restored --on-conflict fail >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/output_single/file_info.png \
+        --output-dir test/tmp/output_single/restored --rename renamed.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/output_single/restored/renamed.txt; then
+    print_pass "--on-conflict and --rename pick the restored file's name"
+else
+    print_fail "--on-conflict or --rename failed"
+fi
+
+print_test "Requiring file info for --output-dir"
+OUTPUT=$(cargo run --quiet -- decode --image test/tmp/output_single/ecc.png \
+    --output-dir test/tmp/output_single/restored 2>&1 || true)
+if echo "$OUTPUT" | grep -q "carries no file name"; then
+    print_pass "--output-dir is refused for a message without file info"
+else
+    print_fail "--output-dir accepted a message without file info"
+fi
+
+print_section "Test 32: Archives"
+print_test "Hiding a directory and unpacking it"
+rm -rf test/tmp/messages/folder test/tmp/output_single/unpacked
+mkdir -p test/tmp/messages/folder/sub
+cp test/tmp/messages/short.txt test/tmp/messages/folder/
+cp test/tmp/messages/empty.txt test/tmp/messages/folder/sub/
+cargo run --quiet -- encode --image test/tmp/images/02.png --message-dir test/tmp/messages/folder \
+    --output test/tmp/output_single/archive.png >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/archive.png \
+    --output-dir test/tmp/output_single/unpacked >/dev/null 2>&1
+if diff -r test/tmp/messages/folder test/tmp/output_single/unpacked >/dev/null 2>&1; then
+    print_pass "--message-dir round trip restores the directory tree"
+else
+    print_fail "--message-dir round trip failed"
+fi
+
+print_test "Bundling several --message-file files"
+rm -rf test/tmp/output_single/unpacked
+cargo run --quiet -- encode --image test/tmp/images/02.png --message-file test/tmp/messages/short.txt \
+    --message-file test/tmp/messages/long.txt --output test/tmp/output_single/archive.png >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/archive.png \
+    --output-dir test/tmp/output_single/unpacked >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt test/tmp/output_single/unpacked/short.txt \
+    && cmp -s test/tmp/messages/long.txt test/tmp/output_single/unpacked/long.txt; then
+    print_pass "--message-file files are unpacked under their names"
+else
+    print_fail "--message-file files were not unpacked"
+fi
+
+print_test "Refusing to write an archive as a single file"
+OUTPUT=$(cargo run --quiet -- decode --image test/tmp/output_single/archive.png \
+    --output test/tmp/output_single/archive.bin 2>&1 || true)
+if echo "$OUTPUT" | grep -q "use --output-dir to unpack it"; then
+    print_pass "--output points to --output-dir for an archive"
+else
+    print_fail "--output accepted an archive"
+fi
+
+print_section "Test 33: Decode to Stdout"
+print_test "Writing the message to stdout with --output -"
+cargo run --quiet -- decode --image test/tmp/output_single/ecc.png --output - \
+    >test/tmp/output_single/stdout.txt 2>/dev/null
+if cmp -s test/tmp/messages/long.txt test/tmp/output_single/stdout.txt; then
+    print_pass "stdout holds exactly the message"
+else
+    print_fail "stdout does not match the message"
+fi
+
+print_section "Test 34: WebP Carriers"
+print_test "Encoding into lossless WebP"
+cargo run --quiet -- encode --image test/tmp/images/02.png --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/stego.webp >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/stego.webp \
+    --output test/tmp/output_single/webp.txt >/dev/null 2>&1
+if [ "$(head -c 4 test/tmp/output_single/stego.webp 2>/dev/null)" = "RIFF" ] \
+    && cmp -s test/tmp/messages/short.txt test/tmp/output_single/webp.txt; then
+    print_pass "WebP output round trip works"
+else
+    print_fail "WebP output round trip failed"
+fi
+
+print_test "Using a WebP cover"
+cargo run --quiet -- encode --image test/tmp/output_single/stego.webp --message test/tmp/messages/short.txt \
+    --output test/tmp/output_single/from_webp.png --overwrite-payload >/dev/null 2>&1
+cargo run --quiet -- decode --image test/tmp/output_single/from_webp.png \
+    --output test/tmp/output_single/from_webp.txt >/dev/null 2>&1
+if cmp -s test/tmp/messages/short.txt test/tmp/output_single/from_webp.txt; then
+    print_pass "WebP covers are read"
+else
+    print_fail "WebP cover could not be used"
+fi
+
+print_section "Test 35: BMP and TIFF Carriers"
+for format in bmp tiff; do
+    print_test "Encoding into $format"
+    cargo run --quiet -- encode --image test/tmp/images/03.png --message test/tmp/messages/short.txt \
+        --output test/tmp/output_single/stego.$format >/dev/null 2>&1
+    cargo run --quiet -- decode --image test/tmp/output_single/stego.$format \
+        --output test/tmp/output_single/$format.txt >/dev/null 2>&1
+    if cmp -s test/tmp/messages/short.txt test/tmp/output_single/$format.txt; then
+        print_pass "$format output round trip works"
+    else
+        print_fail "$format output round trip failed"
+    fi
+done
+
+print_section "Test 36: Auto-Resize with Multiple Images"
+print_test "Encoding into a directory with --auto-resize"
+rm -rf test/tmp/output_resized
+if cargo run --quiet -- encode --image-dir test/tmp/images --message test/tmp/messages/long.txt \
+    --output-dir test/tmp/output_resized --auto-resize >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image-dir test/tmp/output_resized \
+        --output test/tmp/output_resized.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/long.txt test/tmp/output_resized.txt; then
+    print_pass "Auto-resized image set round trip works"
+else
+    print_fail "Auto-resized image set round trip failed"
+fi
+
+print_section "Test 37: Recursive Image Directory"
+print_test "Encoding from nested directories with --include and --exclude"
+rm -rf test/tmp/library test/tmp/output_library
+mkdir -p test/tmp/library/2024/thumbs test/tmp/library/2025
+cp test/tmp/images/01.png test/tmp/library/2024/01.png
+cp test/tmp/images/02.png test/tmp/library/2024/thumbs/02.png
+cp test/tmp/images/03.png test/tmp/library/2025/03.png
+cp test/tmp/images/04.jpg test/tmp/library/2025/04.jpg
+# Too long for one image, so that the second one picked is written too
+dd if=/dev/urandom of=test/tmp/messages/library.bin bs=1024 count=30 2>/dev/null
+if cargo run --quiet -- encode --image-dir test/tmp/library --recursive --include '*.png' \
+    --exclude thumbs --message test/tmp/messages/library.bin \
+    --output-dir test/tmp/output_library >/dev/null 2>&1 \
+    && [ "$(ls test/tmp/output_library)" = "$(printf '01.png\n03.png')" ] \
+    && cargo run --quiet -- decode --image-dir test/tmp/output_library \
+        --output test/tmp/output_library.bin >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/library.bin test/tmp/output_library.bin; then
+    print_pass "Only the included images were used and the message round trips"
+else
+    print_fail "Recursive image directory round trip failed"
+fi
+
+print_section "Test 38: Natural Sort Order"
+print_test "Encoding with --sort natural"
+rm -rf test/tmp/numbered test/tmp/output_numbered
+mkdir -p test/tmp/numbered
+cp test/tmp/images/01.png test/tmp/numbered/img2.png
+cp test/tmp/images/02.png test/tmp/numbered/img10.png
+if cargo run --quiet -- encode --image-dir test/tmp/numbered --sort natural \
+    --message test/tmp/messages/short.txt --output-dir test/tmp/output_numbered >/dev/null 2>&1 \
+    && cargo run --quiet -- inspect --image test/tmp/output_numbered/img2.png 2>/dev/null \
+        | grep -q "Sequence: image 1 of"; then
+    print_pass "img2.png comes before img10.png"
+else
+    print_fail "Natural sort order not used"
+fi
+
+print_section "Test 39: In-Band Sequence Headers"
+print_test "Decoding images whose metadata was stripped, under names in the wrong order"
+rm -rf test/tmp/stripped
+mkdir -p test/tmp/stripped
+# As a messaging app would: same pixels, no sequence metadata
+for pair in 01:b 03:a; do
+    convert "test/tmp/output_library/${pair%:*}.png" -strip "PNG32:test/tmp/stripped/${pair#*:}.png" 2>/dev/null \
+        || magick "test/tmp/output_library/${pair%:*}.png" -strip "PNG32:test/tmp/stripped/${pair#*:}.png"
+done
+if cargo run --quiet -- decode --image-dir test/tmp/stripped \
+    --output test/tmp/stripped.bin >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/library.bin test/tmp/stripped.bin; then
+    print_pass "Images put in order by their in-band sequence headers"
+else
+    print_fail "In-band sequence headers not used"
+fi
+
+print_section "Test 40: Full Redundancy"
+print_test "Encoding a complete copy into every image and decoding from any one"
+rm -rf test/tmp/output_redundant
+if cargo run --quiet -- encode --image-list test/tmp/images/01.png test/tmp/images/02.png \
+    --redundancy full --message test/tmp/messages/short.txt \
+    --output-dir test/tmp/output_redundant >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/output_redundant/02.png \
+        --output test/tmp/redundant.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/redundant.txt; then
+    print_pass "The second copy decodes on its own"
+else
+    print_fail "Full redundancy round trip failed"
+fi
+
+print_section "Test 41: Erasure-Coded Image Set"
+print_test "Encoding with --redundancy 2-of-3 and decoding without one image"
+rm -rf test/tmp/output_shards
+if cargo run --quiet -- encode --image-list test/tmp/images/01.png test/tmp/images/02.png \
+    test/tmp/images/03.png --redundancy 2-of-3 --message test/tmp/messages/library.bin \
+    --output-dir test/tmp/output_shards >/dev/null 2>&1 \
+    && rm test/tmp/output_shards/02.png \
+    && cargo run --quiet -- decode --image-dir test/tmp/output_shards \
+        --output test/tmp/shards.bin 2>&1 | grep -q "from shards 1, 3 of 3" \
+    && cmp -s test/tmp/messages/library.bin test/tmp/shards.bin; then
+    print_pass "Two of three shards rebuild the message"
+else
+    print_fail "Erasure-coded round trip failed"
+fi
+
+print_section "Test 42: No Encryption"
+print_test "Encoding an already encrypted payload with --no-encrypt"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --no-encrypt \
+    --message test/tmp/messages/short.txt --output test/tmp/plain.png >/dev/null 2>&1 \
+    && cargo run --quiet -- inspect --image test/tmp/plain.png 2>/dev/null | grep -q "Encrypted: no" \
+    && cargo run --quiet -- decode --image test/tmp/plain.png --key "any key" \
+        --output test/tmp/plain.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/plain.txt; then
+    print_pass "Plain message recorded in the header and decoded without its key"
+else
+    print_fail "No-encryption round trip failed"
+fi
+
+print_section "Test 43: Cipher Selection"
+print_test "Encoding with --cipher aes-256-gcm and decoding without naming it"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --cipher aes-256-gcm \
+    --key "cipher key" --message test/tmp/messages/short.txt --output test/tmp/aes.png >/dev/null 2>&1 \
+    && cargo run --quiet -- inspect --image test/tmp/aes.png 2>/dev/null | grep -q "Cipher: AES-256-GCM" \
+    && cargo run --quiet -- decode --image test/tmp/aes.png --key "cipher key" \
+        --output test/tmp/aes.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/aes.txt; then
+    print_pass "Cipher recorded in the header and used to decode"
+else
+    print_fail "AES-256-GCM round trip failed"
+fi
+
+print_section "Test 44: Key Generation"
+print_test "Generating a key and an age identity and encoding with them"
+rm -f test/tmp/generated_key.txt test/tmp/generated_identity.txt
+if cargo run --quiet -- keygen --output test/tmp/generated_key.txt >/dev/null 2>&1 \
+    && [ "$(stat -c %a test/tmp/generated_key.txt 2>/dev/null || stat -f %Lp test/tmp/generated_key.txt)" = "600" ] \
+    && ! cargo run --quiet -- keygen --output test/tmp/generated_key.txt >/dev/null 2>&1 \
+    && recipient=$(cargo run --quiet -- keygen --age --output test/tmp/generated_identity.txt 2>/dev/null \
+        | sed -n 's/^Public key: //p') \
+    && cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+        --output test/tmp/generated.png --key-file test/tmp/generated_key.txt --recipient "$recipient" >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/generated.png --key-file test/tmp/generated_key.txt \
+        --identity test/tmp/generated_identity.txt --output test/tmp/generated.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/generated.txt; then
+    print_pass "Generated keys are private, not overwritten and usable"
+else
+    print_fail "keygen round trip failed"
+fi
+
+print_section "Test 45: Decoy Messages"
+print_test "Encoding a decoy and a real message with different keys"
+echo "Nothing to see here" >test/tmp/decoy.txt
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/decoy.png --key "real key" --decoy test/tmp/decoy.txt --decoy-key "decoy key" >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/decoy.png --key "decoy key" \
+        --output test/tmp/decoy_decoded.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/decoy.txt test/tmp/decoy_decoded.txt \
+    && cargo run --quiet -- decode --image test/tmp/decoy.png --key "real key" \
+        --output test/tmp/decoy_real.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/decoy_real.txt; then
+    print_pass "Each key decodes its own message"
+else
+    print_fail "Decoy round trip failed"
+fi
+
+print_section "Test 46: Padding"
+print_test "Padding messages of different lengths to the same size"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/padded_short.png --pad-to 18000 >/dev/null 2>&1 \
+    && cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/long.txt \
+        --output test/tmp/padded_long.png --pad-to 18000 >/dev/null 2>&1 \
+    && short_length=$(cargo run --quiet -- inspect --image test/tmp/padded_short.png 2>/dev/null | grep "Payload length") \
+    && [ "$short_length" = "$(cargo run --quiet -- inspect --image test/tmp/padded_long.png 2>/dev/null | grep "Payload length")" ] \
+    && cargo run --quiet -- decode --image test/tmp/padded_short.png --output test/tmp/padded_short.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/padded_short.txt \
+    && cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+        --output test/tmp/padded_full.png --pad-to full >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/padded_full.png --output test/tmp/padded_full.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/padded_full.txt; then
+    print_pass "Padded messages have the same length and decode"
+else
+    print_fail "Padding failed"
+fi
+
+print_section "Test 47: Noise Fill"
+print_test "Filling the unused low bits at random"
+cargo run --quiet -- encode --image test/tmp/images/02.png --message test/tmp/messages/short.txt \
+    --output test/tmp/noise_filled.png >/dev/null 2>&1
+cargo run --quiet -- encode --image test/tmp/images/02.png --message test/tmp/messages/short.txt \
+    --output test/tmp/noise_unfilled.png --no-noise-fill >/dev/null 2>&1
+# Share of the values of each channel that changed, summed over the channels
+changed() {
+    cargo run --quiet -- analyze --image "$1" --cover test/tmp/images/02.png --json 2>/dev/null |
+        python3 -c 'import json, sys; print(sum(c["changed"] for c in json.load(sys.stdin)[0]["cover_comparison"]["channels"].values()))'
+}
+if python3 -c 'import sys; assert float(sys.argv[1]) > 1 and float(sys.argv[2]) < 0.1' \
+    "$(changed test/tmp/noise_filled.png)" "$(changed test/tmp/noise_unfilled.png)" 2>/dev/null \
+    && cargo run --quiet -- decode --image test/tmp/noise_filled.png --output test/tmp/noise_filled.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/noise_filled.txt; then
+    print_pass "Noise fill changes the whole image and still decodes"
+else
+    print_fail "Noise fill failed"
+fi
+
+print_section "Test 48: LSB Matching"
+print_test "Embedding by LSB matching"
+if cargo run --quiet -- encode --image test/tmp/images/03.png --message test/tmp/messages/short.txt \
+    --output test/tmp/lsb_match.png --method lsb-match >/dev/null 2>&1 \
+    && cargo run --quiet -- inspect --image test/tmp/lsb_match.png 2>/dev/null | grep -q "Embedding method: lsb-match" \
+    && cargo run --quiet -- decode --image test/tmp/lsb_match.png --output test/tmp/lsb_match.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/lsb_match.txt \
+    && ! cargo run --quiet -- encode --image test/tmp/images/03.png --message test/tmp/messages/short.txt \
+        --output test/tmp/lsb_match.png --method lsb-flip >/dev/null 2>&1; then
+    print_pass "--method lsb-match round trip, recorded in the header"
+else
+    print_fail "--method lsb-match failed"
+fi
+
+print_section "Test 49: Adaptive Embedding"
+convert -size 200x200 plasma:fractal test/tmp/images/textured.png 2>/dev/null \
+    || magick -size 200x200 plasma:fractal test/tmp/images/textured.png
+print_test "Embedding in the textured areas only"
+if cargo run --quiet -- encode --image test/tmp/images/textured.png --message test/tmp/messages/short.txt \
+    --output test/tmp/adaptive.png --adaptive --bits 2 --channels rgb --key "adaptive key" >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/adaptive.png --output test/tmp/adaptive.txt \
+        --key "adaptive key" >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/adaptive.txt \
+    && ! cargo run --quiet -- decode --image test/tmp/adaptive.png --output test/tmp/adaptive.txt \
+        --key "other key" >/dev/null 2>&1 \
+    && ! cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+        --output test/tmp/adaptive_flat.png --adaptive >/dev/null 2>&1; then
+    print_pass "--adaptive round trip, and a flat cover has no room"
+else
+    print_fail "--adaptive failed"
+fi
+
+print_section "Test 50: Transparent Pixels"
+convert -size 200x200 xc:none -fill red -draw "rectangle 100,0 199,199" PNG32:test/tmp/images/transparent.png 2>/dev/null \
+    || magick -size 200x200 xc:none -fill red -draw "rectangle 100,0 199,199" PNG32:test/tmp/images/transparent.png
+print_test "Skipping transparent pixels"
+FULL_CAPACITY=$(cargo run --quiet -- capacity --image test/tmp/images/transparent.png --json 2>/dev/null \
+    | python3 -c 'import json, sys; print(json.load(sys.stdin)["total"])')
+OPAQUE_CAPACITY=$(cargo run --quiet -- capacity --image test/tmp/images/transparent.png --skip-transparent --json 2>/dev/null \
+    | python3 -c 'import json, sys; print(json.load(sys.stdin)["total"])')
+if cargo run --quiet -- encode --image test/tmp/images/transparent.png --message test/tmp/messages/short.txt \
+    --output test/tmp/transparent.png --skip-transparent >/dev/null 2>&1 \
+    && cargo run --quiet -- inspect --image test/tmp/transparent.png 2>/dev/null | grep -q "Transparent pixels: skipped" \
+    && cargo run --quiet -- decode --image test/tmp/transparent.png --output test/tmp/transparent.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/transparent.txt \
+    && [ "$OPAQUE_CAPACITY" -lt "$FULL_CAPACITY" ]; then
+    print_pass "--skip-transparent round trip, recorded in the header and counted in the capacity"
+else
+    print_fail "--skip-transparent failed"
+fi
+
+print_section "Test 51: Regions"
+convert -size 200x200 xc:black -fill white -draw "circle 100,100 100,40" test/tmp/images/region_mask.png 2>/dev/null \
+    || magick -size 200x200 xc:black -fill white -draw "circle 100,100 100,40" test/tmp/images/region_mask.png
+print_test "Keeping the message to a region"
+REGION_CAPACITY=$(cargo run --quiet -- capacity --image test/tmp/images/textured.png --region 0,0,100,50 --json 2>/dev/null \
+    | python3 -c 'import json, sys; print(json.load(sys.stdin)["total"])')
+if cargo run --quiet -- encode --image test/tmp/images/textured.png --message test/tmp/messages/short.txt \
+    --output test/tmp/region.png --region 0,0,100,50 --key "region key" >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/region.png --output test/tmp/region.txt \
+        --key "region key" >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/region.txt \
+    && cargo run --quiet -- encode --image test/tmp/images/textured.png --message test/tmp/messages/short.txt \
+        --output test/tmp/region_mask.png --region-mask test/tmp/images/region_mask.png --scatter \
+        --key "region key" >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/region_mask.png --output test/tmp/region_mask.txt \
+        --key "region key" >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/region_mask.txt \
+    && ! cargo run --quiet -- encode --image test/tmp/images/textured.png --message test/tmp/messages/short.txt \
+        --output test/tmp/region_bad.png --region 150,0,100,50 >/dev/null 2>&1 \
+    && [ "$REGION_CAPACITY" -lt "$FULL_CAPACITY" ]; then
+    print_pass "--region and --region-mask round trips, found with the key and counted in the capacity"
+else
+    print_fail "--region failed"
+fi
+
+print_section "Test 52: Diff"
+print_test "Comparing a stego image with its cover"
+DIFF_CHANGED=$(cargo run --quiet -- diff --original test/tmp/images/textured.png --stego test/tmp/region.png --json 2>/dev/null \
+    | python3 -c 'import json, sys; print(json.load(sys.stdin)["changed_values"])')
+SAME_CHANGED=$(cargo run --quiet -- diff --original test/tmp/images/textured.png --stego test/tmp/images/textured.png --json 2>/dev/null \
+    | python3 -c 'import json, sys; print(json.load(sys.stdin)["changed_values"])')
+if cargo run --quiet -- diff --original test/tmp/images/textured.png --stego test/tmp/region.png \
+    --output test/tmp/diff/region.png 2>/dev/null | grep -q "Changed values" \
+    && [ -f test/tmp/diff/region.png ] \
+    && [ "$DIFF_CHANGED" -gt 0 ] && [ "$SAME_CHANGED" -eq 0 ] \
+    && ! cargo run --quiet -- diff --original test/tmp/images/textured.png --stego test/tmp/images/04.jpg >/dev/null 2>&1; then
+    print_pass "diff counts the changed values and writes the difference image"
+else
+    print_fail "diff failed"
+fi
+
+print_section "Test 53: Quality Floor"
+print_test "Failing an encode below a PSNR or SSIM floor"
+rm -f test/tmp/quality.png
+if ! cargo run --quiet -- encode --image test/tmp/images/textured.png --message test/tmp/messages/short.txt \
+    --output test/tmp/quality.png --bits 4 --min-psnr 80 >/dev/null 2>&1 \
+    && [ ! -f test/tmp/quality.png ] \
+    && cargo run --quiet -- encode --image test/tmp/images/textured.png --message test/tmp/messages/short.txt \
+        --output test/tmp/quality.png --min-psnr 40 --min-ssim 0.9 >/dev/null 2>&1 \
+    && [ -f test/tmp/quality.png ]; then
+    print_pass "--min-psnr removes the output below the floor and keeps it above"
+else
+    print_fail "--min-psnr and --min-ssim failed"
+fi
+
+print_section "Test 54: Bit Planes"
+print_test "Rendering a bit plane"
+if cargo run --quiet -- bitplane --image test/tmp/region.png --plane 0 --output test/tmp/planes/lsb.png >/dev/null 2>&1 \
+    && cargo run --quiet -- bitplane --image test/tmp/region.png --plane 7 --channel r \
+        --output test/tmp/planes/r-msb.png >/dev/null 2>&1 \
+    && [ "$(identify -format "%wx%h" test/tmp/planes/lsb.png 2>/dev/null || magick identify -format "%wx%h" test/tmp/planes/lsb.png)" = "400x400" ] \
+    && [ -f test/tmp/planes/r-msb.png ] \
+    && ! cargo run --quiet -- bitplane --image test/tmp/region.png --plane 8 --output test/tmp/planes/x.png >/dev/null 2>&1; then
+    print_pass "bitplane renders a grid of all channels or a single one"
+else
+    print_fail "bitplane failed"
+fi
+
+print_section "Test 55: Wipe"
+print_test "Wiping stego images"
+if cargo run --quiet -- inspect --image test/tmp/quality.png >/dev/null 2>&1 \
+    && cargo run --quiet -- wipe --image test/tmp/quality.png --output test/tmp/wipe/quality.png >/dev/null 2>&1 \
+    && ! cargo run --quiet -- decode --image test/tmp/wipe/quality.png --output test/tmp/wipe/quality.txt >/dev/null 2>&1 \
+    && ! cargo run --quiet -- inspect --image test/tmp/wipe/quality.png >/dev/null 2>&1 \
+    && cargo run --quiet -- wipe --image test/tmp/region.png --output test/tmp/wipe/region.png --zero >/dev/null 2>&1 \
+    && ! cargo run --quiet -- decode --image test/tmp/wipe/region.png --output test/tmp/wipe/region.txt \
+        --key "region key" >/dev/null 2>&1 \
+    && ! cargo run --quiet -- wipe --image test/tmp/region.png --output test/tmp/wipe/bad.png --bits 5 >/dev/null 2>&1; then
+    print_pass "wipe leaves no message to decode or inspect"
+else
+    print_fail "wipe failed"
+fi
+
+print_section "Test 56: JPEG Carriers"
+convert test/tmp/images/textured.png -quality 90 test/tmp/images/textured.jpg 2>/dev/null \
+    || magick test/tmp/images/textured.png -quality 90 test/tmp/images/textured.jpg
+print_test "Embedding in the DCT coefficients of a JPEG"
+if cargo run --quiet -- encode --image test/tmp/images/textured.jpg --message test/tmp/messages/short.txt \
+    --output test/tmp/jpeg/output.jpg --format jpeg --key "jpeg key" >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/jpeg/output.jpg --output test/tmp/jpeg/decoded.txt \
+        --key "jpeg key" >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/jpeg/decoded.txt \
+    && [ "$(head -c 2 test/tmp/jpeg/output.jpg | od -An -tx1 | tr -d ' ')" = "ffd8" ] \
+    && ! cargo run --quiet -- decode --image test/tmp/jpeg/output.jpg --output test/tmp/jpeg/wrong.txt \
+        --key "other key" >/dev/null 2>&1 \
+    && ! cargo run --quiet -- encode --image test/tmp/images/textured.jpg --message test/tmp/messages/short.txt \
+        --output test/tmp/jpeg/scattered.jpg --format jpeg --scatter >/dev/null 2>&1; then
+    print_pass "--format jpeg round trip, written as a JPEG"
+else
+    print_fail "--format jpeg failed"
+fi
+
+print_section "Test 57: Chunk Carrier"
+convert -size 20x20 xc:orange test/tmp/images/small.png 2>/dev/null \
+    || magick -size 20x20 xc:orange test/tmp/images/small.png
+print_test "Carrying a message too long for the pixels in a PNG chunk"
+if cargo run --quiet -- encode --image test/tmp/images/small.png --message test/tmp/messages/long.txt \
+    --output test/tmp/chunk/output.png --format chunk --key "chunk key" >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/chunk/output.png --output test/tmp/chunk/decoded.txt \
+        --key "chunk key" >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/long.txt test/tmp/chunk/decoded.txt \
+    && cargo run --quiet -- wipe --image test/tmp/chunk/output.png --output test/tmp/chunk/wiped.png >/dev/null 2>&1 \
+    && ! cargo run --quiet -- decode --image test/tmp/chunk/wiped.png --output test/tmp/chunk/wiped.txt \
+        --key "chunk key" >/dev/null 2>&1 \
+    && ! cargo run --quiet -- encode --image test/tmp/images/small.png --message test/tmp/messages/short.txt \
+        --output test/tmp/chunk/output.webp --format chunk >/dev/null 2>&1; then
+    print_pass "--format chunk round trip, removed by wipe"
+else
+    print_fail "--format chunk failed"
+fi
+
+print_section "Test 58: Audio Covers"
+# One second of 16-bit mono noise at 44.1 kHz: a 44-byte header and the samples
+mkdir -p test/tmp/audio
+{
+    printf 'RIFF\xac\x58\x01\x00WAVEfmt \x10\x00\x00\x00\x01\x00\x01\x00'
+    printf '\x44\xac\x00\x00\x88\x58\x01\x00\x02\x00\x10\x00data\x88\x58\x01\x00'
+    head -c 88200 /dev/urandom
+} > test/tmp/audio/cover.wav
+print_test "Hiding a message in the samples of a WAV file"
+if cargo run --quiet -- encode --audio test/tmp/audio/cover.wav --message test/tmp/messages/short.txt \
+    --output test/tmp/audio/output.wav --key "audio key" >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --audio test/tmp/audio/output.wav --output test/tmp/audio/decoded.txt \
+        --key "audio key" >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/audio/decoded.txt \
+    && [ "$(head -c 4 test/tmp/audio/output.wav)" = "RIFF" ] \
+    && ! cargo run --quiet -- decode --audio test/tmp/audio/output.wav --output test/tmp/audio/wrong.txt \
+        --key "other key" >/dev/null 2>&1 \
+    && ! cargo run --quiet -- encode --audio test/tmp/audio/cover.wav --message test/tmp/messages/short.txt \
+        --output test/tmp/audio/scattered.wav --scatter >/dev/null 2>&1; then
+    print_pass "--audio round trip, written as a WAV"
+else
+    print_fail "--audio failed"
+fi
+
+print_section "Test 59: Generated Covers"
+print_test "Generating a cover sized for a message"
+if cargo run --quiet -- generate-cover --message-size 16000 --output test/tmp/generated/cover.png --seed 7 >/dev/null 2>&1 \
+    && cargo run --quiet -- generate-cover --message-size 16000 --output test/tmp/generated/again.png --seed 7 >/dev/null 2>&1 \
+    && cmp -s test/tmp/generated/cover.png test/tmp/generated/again.png \
+    && cargo run --quiet -- encode --image test/tmp/generated/cover.png --message test/tmp/messages/long.txt \
+        --output test/tmp/generated/output.png >/dev/null 2>&1 \
+    && cargo run --quiet -- generate-cover --size 320x240 --style gradient --output test/tmp/generated/gradient.png >/dev/null 2>&1 \
+    && ! cargo run --quiet -- generate-cover --size 32x32 --message-size 16000 --output test/tmp/generated/small.png >/dev/null 2>&1; then
+    print_pass "generate-cover is reproducible and sized for the message"
+else
+    print_fail "generate-cover failed"
+fi
+
+print_section "Test 60: Verify"
+print_test "Checking that a stego image gives back the message"
+SHORT_SHA256=$(sha256sum test/tmp/messages/short.txt | cut -c1-64)
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/verify/output.png --key "verify key" --verify >/dev/null 2>&1 \
+    && cargo run --quiet -- verify --image test/tmp/verify/output.png --key "verify key" \
+        --message test/tmp/messages/short.txt >/dev/null 2>&1 \
+    && cargo run --quiet -- verify --image test/tmp/verify/output.png --key "verify key" \
+        --sha256 "$SHORT_SHA256" >/dev/null 2>&1 \
+    && ! cargo run --quiet -- verify --image test/tmp/verify/output.png --key "verify key" \
+        --message test/tmp/messages/long.txt >/dev/null 2>&1 \
+    && ! cargo run --quiet -- verify --image test/tmp/verify/output.png --key "other key" \
+        --message test/tmp/messages/short.txt >/dev/null 2>&1; then
+    print_pass "verify and encode --verify check the round trip"
+else
+    print_fail "verify failed"
+fi
+
+print_section "Test 61: Exit Codes"
+print_test "Checking that failures exit with the code of their kind"
+exit_code() {
+    cargo run --quiet -- "$@" >/dev/null 2>&1 </dev/null
+    echo $?
+}
+WRONG_KEY=$(exit_code decode --image test/tmp/verify/output.png --key "other key" --output -)
+NO_MESSAGE=$(exit_code decode --image test/tmp/images/01.png --key "verify key" --output -)
+MISSING=$(exit_code decode --image test/tmp/verify/missing.png --key "verify key" --output -)
+INVALID=$(exit_code encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+    --output test/tmp/verify/bits.png --bits 9)
+if [ "$WRONG_KEY" -eq 7 ] && [ "$NO_MESSAGE" -eq 6 ] && [ "$MISSING" -eq 3 ] && [ "$INVALID" -eq 2 ]; then
+    print_pass "Wrong key, no message, missing file and invalid options exit with 7, 6, 3 and 2"
+else
+    print_fail "Unexpected exit codes: $WRONG_KEY, $NO_MESSAGE, $MISSING, $INVALID"
+fi
+
+print_section "Test 62: Config Profiles"
+mkdir -p test/tmp/profiles
+cp test/tmp/images/01.png test/tmp/profiles/cover.png
+printf 'profile key' > test/tmp/profiles/work.key
+cat > test/tmp/profiles/config.toml <<EOF
+[defaults]
+output = "{stem}-lk.png"
+
+[profiles.work]
+key_file = "$PWD/test/tmp/profiles/work.key"
+bits = 2
+compress = false
+EOF
+print_test "Defaults and a named profile from the config file"
+if LOWKEY_CONFIG=test/tmp/profiles/config.toml cargo run --quiet -- encode --image test/tmp/profiles/cover.png \
+    --message test/tmp/messages/short.txt --profile work >/dev/null 2>&1 \
+    && cargo run --quiet -- inspect --image test/tmp/profiles/cover-lk.png 2>/dev/null | grep -q "Embedding: 2 bit(s)" \
+    && cargo run --quiet -- decode --image test/tmp/profiles/cover-lk.png --key-file test/tmp/profiles/work.key \
+        --output test/tmp/profiles/message.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/profiles/message.txt \
+    && ! cargo run --quiet -- decode --image test/tmp/profiles/cover-lk.png --output - >/dev/null 2>&1 \
+    && LOWKEY_CONFIG=test/tmp/profiles/config.toml cargo run --quiet -- verify --image test/tmp/profiles/cover-lk.png \
+        --message test/tmp/messages/short.txt --profile work >/dev/null 2>&1 \
+    && ! LOWKEY_CONFIG=test/tmp/profiles/config.toml cargo run --quiet -- decode \
+        --image test/tmp/profiles/cover-lk.png --profile home --output - >/dev/null 2>&1; then
+    print_pass "--profile supplies the key file, bit depth and output name"
+else
+    print_fail "Config profiles failed"
+fi
+
+print_section "Test 63: Inline Message Text"
+print_test "Encoding --message-text instead of a file"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message-text "meet at noon" \
+    --output test/tmp/message_text.png >/dev/null 2>&1 \
+    && [ "$(cargo run --quiet -- decode --image test/tmp/message_text.png --output - 2>/dev/null)" = "meet at noon" ] \
+    && ! cargo run --quiet -- encode --image test/tmp/images/01.png --message-text "meet at noon" \
+        --message test/tmp/messages/short.txt --output test/tmp/message_text.png >/dev/null 2>&1; then
+    print_pass "--message-text round trip, exclusive with --message"
+else
+    print_fail "--message-text failed"
+fi
+
+print_section "Test 64: File Attributes"
+print_test "Copying the cover's times and permissions to the output"
+cp test/tmp/images/01.png test/tmp/attributes_cover.png
+touch -d "2019-05-06 07:08:09" test/tmp/attributes_cover.png
+chmod 640 test/tmp/attributes_cover.png
+if cargo run --quiet -- encode --image test/tmp/attributes_cover.png --message test/tmp/messages/short.txt \
+    --output test/tmp/attributes.png --preserve-attributes >/dev/null 2>&1 \
+    && [ "$(stat -c '%a %Y' test/tmp/attributes.png)" = "$(stat -c '%a %Y' test/tmp/attributes_cover.png)" ]; then
+    print_pass "--preserve-attributes keeps the cover's modification time and permissions"
+else
+    print_fail "--preserve-attributes failed"
+fi
+
+print_section "Test 65: In-Place Encoding"
+print_test "Overwriting the covers with a backup"
+rm -rf test/tmp/in_place && mkdir -p test/tmp/in_place
+cp test/tmp/images/01.png test/tmp/images/02.png test/tmp/in_place/
+if ! cargo run --quiet -- encode --image test/tmp/in_place/01.png --message test/tmp/messages/short.txt \
+    --in-place </dev/null >/dev/null 2>&1 \
+    && cmp -s test/tmp/images/01.png test/tmp/in_place/01.png \
+    && cargo run --quiet -- encode --image-dir test/tmp/in_place --message test/tmp/messages/short.txt \
+        --in-place --backup-suffix .orig --redundancy full >/dev/null 2>&1 \
+    && cmp -s test/tmp/images/02.png test/tmp/in_place/02.png.orig \
+    && cargo run --quiet -- decode --image test/tmp/in_place/02.png --output test/tmp/in_place/message.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/in_place/message.txt \
+    && ! cargo run --quiet -- encode --image test/tmp/in_place/01.png --message test/tmp/messages/short.txt \
+        --in-place --backup-suffix .orig --overwrite-payload >/dev/null 2>&1; then
+    print_pass "--in-place needs a backup or confirmation, keeps backups and never overwrites one"
+else
+    print_fail "--in-place failed"
+fi
+
+print_section "Test 66: Output Templates"
+print_test "Naming the outputs of a multi-image encode"
+rm -rf test/tmp/templated
+if cargo run --quiet -- encode --image-list test/tmp/images/01.png test/tmp/images/02.png \
+    --message test/tmp/messages/long.txt --output-dir test/tmp/templated \
+    --output-template 'part_{index:03}_of_{total}.png' >/dev/null 2>&1 \
+    && [ -f test/tmp/templated/part_001_of_2.png ] \
+    && cargo run --quiet -- decode --image-dir test/tmp/templated --output test/tmp/templated.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/long.txt test/tmp/templated.txt \
+    && ! cargo run --quiet -- encode --image-list test/tmp/images/01.png test/tmp/images/02.png \
+        --message test/tmp/messages/long.txt --output-dir test/tmp/templated \
+        --output-template 'same.png' >/dev/null 2>&1; then
+    print_pass "--output-template names the outputs and decode still finds their order"
+else
+    print_fail "--output-template failed"
+fi
+
+print_section "Test 67: Capacity-Aware Image Selection"
+print_test "Skipping covers too small for the headers and reporting the unused ones"
+rm -rf test/tmp/small-dir test/tmp/small-out
+mkdir -p test/tmp/small-dir
+cp test/tmp/images/01.png test/tmp/images/02.png test/tmp/small-dir/
+cargo run --quiet -- generate-cover --size 4x4 --output test/tmp/small-dir/00-tiny.png >/dev/null 2>&1
+if ! cargo run --quiet -- encode --image-dir test/tmp/small-dir --message test/tmp/messages/short.txt \
+        --output-dir test/tmp/small-out >/dev/null 2>&1 \
+    && cargo run --quiet -- encode --image-dir test/tmp/small-dir --message test/tmp/messages/short.txt \
+        --output-dir test/tmp/small-out --sort capacity --skip-small 2>&1 | grep -q "Not used: .*00-tiny.png" \
+    && [ ! -f test/tmp/small-out/00-tiny.png ] \
+    && cargo run --quiet -- decode --image-dir test/tmp/small-out --output test/tmp/small.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/small.txt; then
+    print_pass "--skip-small leaves out the tiny cover and --sort capacity fills the largest first"
+else
+    print_fail "--skip-small or --sort capacity failed"
+fi
+
+print_section "Test 68: Even Distribution"
+print_test "Spreading a message so that every cover is about equally full"
+rm -rf test/tmp/even-out
+if cargo run --quiet -- encode --image-list test/tmp/images/01.png test/tmp/images/02.png test/tmp/images/03.png \
+        --message test/tmp/messages/short.txt --output-dir test/tmp/even-out --distribute even >/dev/null 2>&1 \
+    && [ -f test/tmp/even-out/03.png ] \
+    && cargo run --quiet -- decode --image-list test/tmp/even-out/03.png test/tmp/even-out/01.png test/tmp/even-out/02.png \
+        --output test/tmp/even.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/even.txt \
+    && ! cargo run --quiet -- encode --image-list test/tmp/images/01.png test/tmp/images/02.png \
+        --message test/tmp/messages/short.txt --output-dir test/tmp/even-out --distribute even \
+        --redundancy full >/dev/null 2>&1; then
+    print_pass "--distribute even writes every cover and decodes in any order"
+else
+    print_fail "--distribute even failed"
+fi
+
+print_section "Test 69: Maximum Fill Ratio"
+print_test "Refusing to fill an image beyond --max-fill and pulling in more covers"
+rm -rf test/tmp/max-fill-out test/tmp/max-fill.png
+if ! cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/long.txt \
+        --output test/tmp/max-fill.png --max-fill 0.05 >/dev/null 2>&1 \
+    && [ ! -f test/tmp/max-fill.png ] \
+    && cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/long.txt \
+        --output test/tmp/max-fill.png --max-fill 0.05 --max-fill-warn 2>&1 | grep -q "Warning: The message would fill" \
+    && cargo run --quiet -- encode --image-list test/tmp/images/01.png test/tmp/images/02.png test/tmp/images/03.png \
+        --message test/tmp/messages/long.txt --output-dir test/tmp/max-fill-out --max-fill 0.3 >/dev/null 2>&1 \
+    && [ -f test/tmp/max-fill-out/03.png ] \
+    && cargo run --quiet -- decode --image-dir test/tmp/max-fill-out --output test/tmp/max-fill.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/long.txt test/tmp/max-fill.txt; then
+    print_pass "--max-fill refuses full images, warns with --max-fill-warn and spreads over more covers"
+else
+    print_fail "--max-fill failed"
+fi
+
+print_section "Test 70: Existing Payloads"
+print_test "Refusing to encode over a cover that already holds a message"
+rm -f test/tmp/existing.png test/tmp/existing2.png test/tmp/existing.txt
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+        --output test/tmp/existing.png >/dev/null 2>&1 \
+    && ! cargo run --quiet -- encode --image test/tmp/existing.png --message test/tmp/messages/short.txt \
+        --output test/tmp/existing2.png >/dev/null 2>&1 \
+    && [ ! -f test/tmp/existing2.png ] \
+    && cargo run --quiet -- encode --image test/tmp/existing.png --message test/tmp/messages/short.txt \
+        --output test/tmp/existing2.png --overwrite-payload >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/existing2.png --output test/tmp/existing.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/existing.txt; then
+    print_pass "A stego image is only encoded over with --overwrite-payload"
+else
+    print_fail "Existing payload check failed"
+fi
+
+print_section "Test 71: Multiple Payloads"
+print_test "Appending a second message under its own key and decoding each slot"
+rm -f test/tmp/slots-1.png test/tmp/slots-2.png test/tmp/slot-1.txt test/tmp/slot-2.txt
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+        --output test/tmp/slots-1.png --key "first key" >/dev/null 2>&1 \
+    && cargo run --quiet -- encode --image test/tmp/slots-1.png --append --message-text "for the second key" \
+        --output test/tmp/slots-2.png --key "second key" --verify >/dev/null 2>&1 \
+    && cargo run --quiet -- list --image test/tmp/slots-2.png 2>/dev/null | grep -q "^Slot 2:" \
+    && cargo run --quiet -- decode --image test/tmp/slots-2.png --key "first key" \
+        --output test/tmp/slot-1.txt >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/slots-2.png --slot 2 --key "second key" \
+        --output test/tmp/slot-2.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/slot-1.txt \
+    && grep -qx "for the second key" test/tmp/slot-2.txt \
+    && ! cargo run --quiet -- encode --image test/tmp/images/01.png --append \
+        --message test/tmp/messages/short.txt --output test/tmp/slots-3.png >/dev/null 2>&1; then
+    print_pass "Each slot decodes with its own key, and a cover without a message is refused"
+else
+    print_fail "Multiple payloads failed"
+fi
+
+print_section "Test 72: Listing Payloads"
+print_test "Listing the slots of an image and which of them a key opens"
+if cargo run --quiet -- list --image test/tmp/slots-2.png --key "second key" 2>/dev/null \
+        | grep -q "^Slot 1: protocol version .*; the key does not open it$" \
+    && cargo run --quiet -- list --image test/tmp/slots-2.png --key "second key" 2>/dev/null \
+        | grep -q "^Slot 2: .*; the key opens it$" \
+    && cargo run --quiet -- list --image test/tmp/slots-2.png --json 2>/dev/null | grep -q '"opens":null' \
+    && ! cargo run --quiet -- list --image test/tmp/images/01.png >/dev/null 2>&1; then
+    print_pass "list reports each slot's header and whether --key opens it"
+else
+    print_fail "Listing payloads failed"
+fi
+
+print_section "Test 73: Converting to sRGB"
+print_test "Encoding with the cover's colors converted to sRGB"
+rm -f test/tmp/srgb.png test/tmp/srgb.txt test/tmp/srgb-2.png
+if cargo run --quiet -- encode --image test/tmp/images/04.jpg --message test/tmp/messages/short.txt \
+        --output test/tmp/srgb.png --convert-srgb >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/srgb.png --output test/tmp/srgb.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/srgb.txt \
+    && ! cargo run --quiet -- encode --image test/tmp/srgb.png --append --convert-srgb \
+        --message test/tmp/messages/short.txt --output test/tmp/srgb-2.png >/dev/null 2>&1; then
+    print_pass "--convert-srgb encodes as usual and is refused with --append"
+else
+    print_fail "Converting to sRGB failed"
+fi
+
+print_section "Test 74: Stripping Metadata"
+print_test "Encoding without the cover's metadata"
+rm -f test/tmp/stripped.png test/tmp/stripped.txt
+if cargo run --quiet -- encode --image test/tmp/images/04.jpg --message test/tmp/messages/short.txt \
+        --output test/tmp/stripped.png --strip-metadata >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/stripped.png --output test/tmp/stripped.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/stripped.txt \
+    && ! grep -q "eXIf\|iTXt\|iCCP" test/tmp/stripped.png; then
+    print_pass "--strip-metadata writes an output with no metadata chunks that still decodes"
+else
+    print_fail "Stripping metadata failed"
+fi
+
+print_section "Test 75: Encrypted Sequence Chunk"
+print_test "Encoding a set with the sequence information encrypted"
+rm -rf test/tmp/output_sealed test/tmp/sealed.txt
+if cargo run --quiet -- encode \
+        --image-list test/tmp/images/01.png test/tmp/images/02.png test/tmp/images/03.png \
+        --message test/tmp/messages/long.txt --output-dir test/tmp/output_sealed \
+        --encrypt-sequence-chunk >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image-dir test/tmp/output_sealed \
+        --output test/tmp/sealed.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/long.txt test/tmp/sealed.txt \
+    && ! grep -q "lKsq" test/tmp/output_sealed/01.png; then
+    print_pass "--encrypt-sequence-chunk leaves no lKsq chunk and the set still decodes"
+else
+    print_fail "Encrypting the sequence chunk failed"
+fi
+
+print_section "Test 76: PNG Compression"
+print_test "Encoding with fast compression and the Paeth filter"
+rm -f test/tmp/png_fast.png test/tmp/png_fast.txt
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+        --output test/tmp/png_fast.png --png-compression fast --png-filter paeth >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image test/tmp/png_fast.png --output test/tmp/png_fast.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/short.txt test/tmp/png_fast.txt; then
+    print_pass "--png-compression and --png-filter write an output that decodes"
+else
+    print_fail "Encoding with PNG compression settings failed"
+fi
+
+print_test "Rejecting an unknown PNG filter"
+if cargo run --quiet -- encode --image test/tmp/images/01.png --message test/tmp/messages/short.txt \
+        --output test/tmp/png_bad.png --png-filter up >/dev/null 2>&1; then
+    print_fail "An unknown --png-filter was accepted"
+else
+    print_pass "An unknown --png-filter is rejected"
+fi
+
+print_section "Test 77: Parallel Writes"
+print_test "Writing the outputs of a multi-image encode on worker threads"
+rm -rf test/tmp/output_parallel test/tmp/parallel.txt
+if cargo run --quiet -- encode \
+        --image-list test/tmp/images/01.png test/tmp/images/02.png test/tmp/images/03.png \
+        --message test/tmp/messages/long.txt --output-dir test/tmp/output_parallel \
+        --distribute even >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image-dir test/tmp/output_parallel \
+        --output test/tmp/parallel.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/long.txt test/tmp/parallel.txt \
+    && [ -z "$(find test/tmp/output_parallel -name '.*.tmp')" ]; then
+    print_pass "Every output is complete, decodes and leaves no temporary file"
+else
+    print_fail "Writing outputs in parallel failed"
+fi
+
+print_section "Test 78: One Cover at a Time"
+print_test "Encoding over covers of different sizes with --auto-resize"
+rm -rf test/tmp/output_streamed test/tmp/streamed.txt
+if cargo run --quiet -- encode \
+        --image-list test/tmp/images/01.png test/tmp/images/04.jpg test/tmp/images/02.png \
+        --message test/tmp/messages/long.txt --output-dir test/tmp/output_streamed \
+        --auto-resize >/dev/null 2>&1 \
+    && cargo run --quiet -- decode --image-dir test/tmp/output_streamed \
+        --output test/tmp/streamed.txt >/dev/null 2>&1 \
+    && cmp -s test/tmp/messages/long.txt test/tmp/streamed.txt; then
+    print_pass "Covers read one at a time still carry the whole message"
+else
+    print_fail "Encoding one cover at a time failed"
+fi
+
+print_section "Test 79: Capacity From Headers"
+print_test "Measuring images with and without an alpha channel"
+capacity=$(cargo run --quiet -- capacity --image-list test/tmp/images/01.png test/tmp/images/04.jpg \
+    --skip-transparent --json 2>/dev/null)
+if echo "$capacity" | python3 -c '
+import json, sys
+report = json.load(sys.stdin)
+assert [image["capacity"] for image in report["images"]] == [20000 - report["overhead"], 11250 - report["overhead"]]
+' 2>/dev/null; then
+    print_pass "Capacity is reported from the image headers"
+else
+    print_fail "Unexpected capacity report: $capacity"
+fi
+
 print_section "Test Summary"
 TOTAL_TESTS=$((TESTS_PASSED + TESTS_FAILED))
 echo "Total tests: $TOTAL_TESTS"